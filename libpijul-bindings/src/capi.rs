@@ -0,0 +1,261 @@
+//! A C interface to the core operations of libpijul, meant for
+//! editors and bindings to other languages, built on [`libpijul::api`].
+//!
+//! Repositories are exposed as opaque `PijulRepository` handles,
+//! and every function returns a `PijulStatus`. When a call fails,
+//! the error message can be retrieved with
+//! `pijul_last_error_message`, which is valid until the next call on
+//! the same thread. Strings returned by this module must be freed
+//! with `pijul_string_free`. Panics are caught before they reach the
+//! caller, and reported as `PijulStatus::Panic`.
+use libpijul::api::{ApiError, ChangeHash, Identity, Repository};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+
+/// Status codes returned by every function of this module.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PijulStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidUtf8 = 2,
+    NotFound = 3,
+    InvalidHash = 4,
+    Pristine = 5,
+    Record = 6,
+    Apply = 7,
+    Output = 8,
+    Changestore = 9,
+    Io = 10,
+    Panic = 11,
+}
+
+/// An opaque handle to a repository.
+pub struct PijulRepository(Repository);
+
+/// Callback called on each entry of the log, with the hash of the
+/// change, the state after that change, and the user data.
+pub type PijulLogCallback =
+    extern "C" fn(hash: *const c_char, state: *const c_char, data: *mut c_void);
+
+/// Callback called on each conflict found while outputting the
+/// repository, with the path and line of the conflict (0 for name
+/// conflicts), and the user data.
+pub type PijulConflictCallback = extern "C" fn(path: *const c_char, line: usize, data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// The status and message of a failed call.
+pub(crate) struct Failure(PijulStatus, String);
+
+impl From<ApiError> for Failure {
+    fn from(e: ApiError) -> Self {
+        let status = match e {
+            ApiError::NotFound(_) | ApiError::ChannelNotFound(_) | ApiError::ChannelDeleted(_) => {
+                PijulStatus::NotFound
+            }
+            ApiError::AlreadyInRepository(_) | ApiError::Io(_) => PijulStatus::Io,
+            ApiError::Pristine(_) => PijulStatus::Pristine,
+            ApiError::Changestore(_) => PijulStatus::Changestore,
+            ApiError::Record(_) => PijulStatus::Record,
+            ApiError::Apply(_) => PijulStatus::Apply,
+            ApiError::Output(_) => PijulStatus::Output,
+            _ => PijulStatus::Io,
+        };
+        Failure(status, e.to_string())
+    }
+}
+
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+fn set_error(status: PijulStatus, msg: &str) -> PijulStatus {
+    let msg = c_string(msg);
+    LAST_ERROR.with(|l| *l.borrow_mut() = Some(msg));
+    status
+}
+
+/// Run the body of an exported function, turning its errors and
+/// panics into a status, and setting the last error message.
+pub(crate) fn ffi<F: FnOnce() -> Result<(), Failure>>(f: F) -> PijulStatus {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => PijulStatus::Ok,
+        Ok(Err(Failure(status, msg))) => set_error(status, &msg),
+        Err(e) => {
+            let msg = if let Some(s) = e.downcast_ref::<&str>() {
+                s
+            } else if let Some(s) = e.downcast_ref::<String>() {
+                s.as_str()
+            } else {
+                "unknown panic"
+            };
+            set_error(PijulStatus::Panic, &format!("Panic: {}", msg))
+        }
+    }
+}
+
+fn null(name: &str) -> Failure {
+    Failure(PijulStatus::NullPointer, format!("Null pointer: {}", name))
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(null(name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| Failure(PijulStatus::InvalidUtf8, format!("{}: {}", name, e)))
+}
+
+unsafe fn repo_arg<'a>(repo: *mut PijulRepository) -> Result<&'a Repository, Failure> {
+    repo.as_ref().map(|r| &r.0).ok_or_else(|| null("repo"))
+}
+
+unsafe fn out_arg<'a, T>(out: *mut T, name: &str) -> Result<&'a mut T, Failure> {
+    out.as_mut().ok_or_else(|| null(name))
+}
+
+/// Return the message of the last error that happened on this
+/// thread, or a null pointer if there is none.
+#[no_mangle]
+pub extern "C" fn pijul_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|l| {
+        if let Some(ref e) = *l.borrow() {
+            e.as_ptr()
+        } else {
+            std::ptr::null()
+        }
+    })
+}
+
+/// Free a string returned by this module.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        std::mem::drop(CString::from_raw(s))
+    }
+}
+
+/// Open the repository rooted at `path` (the directory containing
+/// `.pijul`), and write a handle to it in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_repository_open(
+    path: *const c_char,
+    out: *mut *mut PijulRepository,
+) -> PijulStatus {
+    ffi(|| {
+        let path = str_arg(path, "path")?;
+        let out = out_arg(out, "out")?;
+        let repo = Repository::open(path)?;
+        *out = Box::into_raw(Box::new(PijulRepository(repo)));
+        Ok(())
+    })
+}
+
+/// Close a repository handle.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_repository_free(repo: *mut PijulRepository) {
+    if !repo.is_null() {
+        std::mem::drop(Box::from_raw(repo))
+    }
+}
+
+/// Record all the changes in the working copy on channel `channel`,
+/// with message `message`, authored by `author`. If there was
+/// something to record, the hash of the new change is written to
+/// `out_hash`, else a null pointer is written.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_record(
+    repo: *mut PijulRepository,
+    channel: *const c_char,
+    message: *const c_char,
+    author: *const c_char,
+    out_hash: *mut *mut c_char,
+) -> PijulStatus {
+    ffi(|| {
+        let repo = repo_arg(repo)?;
+        let channel = str_arg(channel, "channel")?;
+        let message = str_arg(message, "message")?;
+        let author = Identity::new(str_arg(author, "author")?);
+        let out_hash = out_arg(out_hash, "out_hash")?;
+        *out_hash = std::ptr::null_mut();
+        if let Some(hash) = repo.record(channel, message, &author)? {
+            *out_hash = c_string(&hash.to_base32()).into_raw();
+        }
+        Ok(())
+    })
+}
+
+/// Apply the change with hash `hash` (in base32) and its
+/// dependencies to channel `channel`. This does not touch the
+/// working copy, see `pijul_output`.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_apply(
+    repo: *mut PijulRepository,
+    channel: *const c_char,
+    hash: *const c_char,
+) -> PijulStatus {
+    ffi(|| {
+        let repo = repo_arg(repo)?;
+        let channel = str_arg(channel, "channel")?;
+        let hash = str_arg(hash, "hash")?;
+        let hash = ChangeHash::from_base32(hash).ok_or_else(|| {
+            Failure(
+                PijulStatus::InvalidHash,
+                format!("Invalid hash: {:?}", hash),
+            )
+        })?;
+        Ok(repo.apply(channel, &hash)?)
+    })
+}
+
+/// Output channel `channel` to the working copy, calling `callback`
+/// (if not null) on each conflict.
+///
+/// **WARNING:** This overwrites the working copy, cancelling any
+/// unrecorded change.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_output(
+    repo: *mut PijulRepository,
+    channel: *const c_char,
+    callback: Option<PijulConflictCallback>,
+    data: *mut c_void,
+) -> PijulStatus {
+    ffi(|| {
+        let repo = repo_arg(repo)?;
+        let channel = str_arg(channel, "channel")?;
+        let conflicts = repo.output(channel)?;
+        if let Some(callback) = callback {
+            for c in conflicts {
+                let path = c_string(&c.path);
+                callback(path.as_ptr(), c.line.unwrap_or(0), data)
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Call `callback` on each change of channel `channel`, in the order
+/// in which they were applied.
+#[no_mangle]
+pub unsafe extern "C" fn pijul_log(
+    repo: *mut PijulRepository,
+    channel: *const c_char,
+    callback: PijulLogCallback,
+    data: *mut c_void,
+) -> PijulStatus {
+    ffi(|| {
+        let repo = repo_arg(repo)?;
+        let channel = str_arg(channel, "channel")?;
+        for e in repo.log(channel)? {
+            let h = c_string(&e.hash.to_base32());
+            let m = c_string(&e.state.to_base32());
+            callback(h.as_ptr(), m.as_ptr(), data)
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,18 @@
+//! Bindings to [libpijul](libpijul) for other languages, built as a
+//! shared library, so that libpijul itself and its Rust dependents
+//! don't have to build one:
+//!
+//! - the `capi` feature (enabled by default) exports a C interface
+//!   ([`capi`]),
+//! - the `python` feature exports a Python module ([`python`]), and
+//!   `python-extension` builds it as an extension module, for
+//!   instance with `maturin build --features python-extension`.
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(feature = "python")]
+pub mod python;
+
+#[cfg(test)]
+mod tests;
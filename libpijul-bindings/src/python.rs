@@ -0,0 +1,167 @@
+//! Python bindings, exposing repositories, channels and changes to
+//! scripts, built on [`libpijul::api`]. The module is built as
+//! `libpijul` when the `python-extension` feature is enabled.
+use libpijul::api::{self, ApiError, ChangeHash};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+fn err(e: ApiError) -> PyErr {
+    match e {
+        ApiError::NotFound(_) | ApiError::ChannelNotFound(_) | ApiError::ChannelDeleted(_) => {
+            PyValueError::new_err(e.to_string())
+        }
+        e => PyRuntimeError::new_err(e.to_string()),
+    }
+}
+
+fn parse_hash(hash: &str) -> PyResult<ChangeHash> {
+    ChangeHash::from_base32(hash)
+        .ok_or_else(|| PyValueError::new_err(format!("Invalid hash: {:?}", hash)))
+}
+
+/// A repository on disk.
+#[pyclass(unsendable)]
+pub struct Repository(api::Repository);
+
+/// A channel of a repository, identified by its name.
+#[pyclass]
+#[derive(Clone)]
+pub struct Channel {
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub state: String,
+}
+
+/// The header and dependencies of a change.
+#[pyclass]
+#[derive(Clone)]
+pub struct Change {
+    #[pyo3(get)]
+    pub hash: String,
+    #[pyo3(get)]
+    pub message: String,
+    #[pyo3(get)]
+    pub description: Option<String>,
+    #[pyo3(get)]
+    pub timestamp: String,
+    #[pyo3(get)]
+    pub dependencies: Vec<String>,
+}
+
+/// A tracked file with unrecorded changes. `lines` are the ranges of
+/// lines of the channel version replaced by ranges of lines of the
+/// working copy, as `(kind, (old_start, old_end), (new_start,
+/// new_end))`, where `kind` is one of `"added"`, `"modified"` or
+/// `"removed"`.
+#[pyclass]
+#[derive(Clone)]
+pub struct FileDiff {
+    #[pyo3(get)]
+    pub path: String,
+    #[pyo3(get)]
+    pub deleted: bool,
+    #[pyo3(get)]
+    pub lines: Vec<(String, (usize, usize), (usize, usize))>,
+}
+
+#[pymethods]
+impl Repository {
+    /// Open the repository rooted at `path`.
+    #[new]
+    pub fn open(path: &str) -> PyResult<Self> {
+        Ok(Repository(api::Repository::open(path).map_err(err)?))
+    }
+
+    /// List the channels of this repository.
+    pub fn channels(&self) -> PyResult<Vec<Channel>> {
+        let mut result = Vec::new();
+        for name in self.0.channels().map_err(err)? {
+            let state = self.0.state(&name).map_err(err)?.to_base32();
+            result.push(Channel { name, state })
+        }
+        Ok(result)
+    }
+
+    /// Read the header of change `hash`.
+    pub fn change(&self, hash: &str) -> PyResult<Change> {
+        let c = self.0.change(&parse_hash(hash)?).map_err(err)?;
+        Ok(Change {
+            hash: c.hash.to_base32(),
+            message: c.message,
+            description: c.description,
+            timestamp: c.timestamp.to_rfc3339(),
+            dependencies: c.dependencies.iter().map(|d| d.to_base32()).collect(),
+        })
+    }
+
+    /// Return the hashes of the changes of `channel`, in the order
+    /// in which they were applied.
+    pub fn log(&self, channel: &str) -> PyResult<Vec<String>> {
+        Ok(self
+            .0
+            .log(channel)
+            .map_err(err)?
+            .into_iter()
+            .map(|e| e.hash.to_base32())
+            .collect())
+    }
+
+    /// Return the tracked files of the working copy that differ from
+    /// their version in `channel`. This doesn't write anything to the
+    /// repository.
+    pub fn diff(&self, channel: &str) -> PyResult<Vec<FileDiff>> {
+        Ok(self
+            .0
+            .diff(channel)
+            .map_err(err)?
+            .into_iter()
+            .map(|d| FileDiff {
+                path: d.path,
+                deleted: d.deleted,
+                lines: d
+                    .lines
+                    .into_iter()
+                    .map(|l| {
+                        let kind = match l.kind {
+                            api::LineChangeKind::Added => "added",
+                            api::LineChangeKind::Modified => "modified",
+                            api::LineChangeKind::Removed => "removed",
+                        };
+                        (
+                            kind.to_string(),
+                            (l.old.start, l.old.end),
+                            (l.new.start, l.new.end),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Record the working copy on `channel` as a change authored by
+    /// `author`, returning the hash of the new change, or `None` if
+    /// there was nothing to record.
+    pub fn record(&self, channel: &str, message: &str, author: &str) -> PyResult<Option<String>> {
+        Ok(self
+            .0
+            .record(channel, message, &api::Identity::new(author))
+            .map_err(err)?
+            .map(|h| h.to_base32()))
+    }
+
+    /// Apply change `hash` and its dependencies to `channel`, without
+    /// touching the working copy.
+    pub fn apply(&self, channel: &str, hash: &str) -> PyResult<()> {
+        self.0.apply(channel, &parse_hash(hash)?).map_err(err)
+    }
+}
+
+#[pymodule]
+fn libpijul(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Repository>()?;
+    m.add_class::<Channel>()?;
+    m.add_class::<Change>()?;
+    m.add_class::<FileDiff>()?;
+    Ok(())
+}
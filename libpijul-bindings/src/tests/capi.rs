@@ -0,0 +1,146 @@
+use crate::capi::*;
+use libpijul::api::{Identity, Repository};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+
+fn last_error() -> String {
+    let e = pijul_last_error_message();
+    assert!(!e.is_null());
+    unsafe { CStr::from_ptr(e) }.to_str().unwrap().to_string()
+}
+
+extern "C" fn push_hash(hash: *const c_char, _state: *const c_char, data: *mut c_void) {
+    let hashes = unsafe { &mut *(data as *mut Vec<String>) };
+    hashes.push(
+        unsafe { CStr::from_ptr(hash) }
+            .to_str()
+            .unwrap()
+            .to_string(),
+    )
+}
+
+/// Record, log and apply through the C interface.
+#[test]
+fn capi_record_log() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let dir = tempfile::tempdir()?;
+    let repo = Repository::init(dir.path())?;
+    std::fs::write(dir.path().join("file"), b"a\nb\n")?;
+    repo.add("file")?;
+    let h0 = repo
+        .record("main", "init", &Identity::new("alice"))?
+        .unwrap();
+    std::mem::drop(repo);
+
+    let path = CString::new(dir.path().to_str().unwrap())?;
+    let main = CString::new("main")?;
+    let other = CString::new("other")?;
+    let message = CString::new("edit")?;
+    let author = CString::new("bob")?;
+    unsafe {
+        let mut repo = std::ptr::null_mut();
+        assert_eq!(
+            pijul_repository_open(path.as_ptr(), &mut repo),
+            PijulStatus::Ok
+        );
+        std::fs::write(dir.path().join("file"), b"a\nx\nb\n")?;
+        let mut hash = std::ptr::null_mut();
+        assert_eq!(
+            pijul_record(
+                repo,
+                main.as_ptr(),
+                message.as_ptr(),
+                author.as_ptr(),
+                &mut hash
+            ),
+            PijulStatus::Ok
+        );
+        assert!(!hash.is_null());
+        let h1 = CStr::from_ptr(hash).to_str()?.to_string();
+
+        // Nothing left to record.
+        let mut empty = std::ptr::null_mut();
+        assert_eq!(
+            pijul_record(
+                repo,
+                main.as_ptr(),
+                message.as_ptr(),
+                author.as_ptr(),
+                &mut empty
+            ),
+            PijulStatus::Ok
+        );
+        assert!(empty.is_null());
+
+        assert_eq!(pijul_apply(repo, other.as_ptr(), hash), PijulStatus::Ok);
+        pijul_string_free(hash);
+        let mut hashes: Vec<String> = Vec::new();
+        assert_eq!(
+            pijul_log(
+                repo,
+                other.as_ptr(),
+                push_hash,
+                &mut hashes as *mut Vec<String> as *mut c_void
+            ),
+            PijulStatus::Ok
+        );
+        assert_eq!(hashes, vec![h0.to_base32(), h1]);
+        pijul_repository_free(repo);
+    }
+    Ok(())
+}
+
+/// Errors set the last error message, including null pointers.
+#[test]
+fn capi_errors() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let dir = tempfile::tempdir()?;
+    let path = CString::new(dir.path().to_str().unwrap())?;
+    unsafe {
+        let mut repo = std::ptr::null_mut();
+        assert_eq!(
+            pijul_repository_open(std::ptr::null(), &mut repo),
+            PijulStatus::NullPointer
+        );
+        assert_eq!(last_error(), "Null pointer: path");
+        assert_eq!(
+            pijul_repository_open(path.as_ptr(), std::ptr::null_mut()),
+            PijulStatus::NullPointer
+        );
+        assert_eq!(last_error(), "Null pointer: out");
+        assert_eq!(
+            pijul_repository_open(path.as_ptr(), &mut repo),
+            PijulStatus::NotFound
+        );
+        assert!(last_error().starts_with("No repository at"));
+
+        Repository::init(dir.path())?;
+        assert_eq!(
+            pijul_repository_open(path.as_ptr(), &mut repo),
+            PijulStatus::Ok
+        );
+        let channel = CString::new("main")?;
+        let hash = CString::new("not a hash")?;
+        assert_eq!(
+            pijul_apply(repo, channel.as_ptr(), hash.as_ptr()),
+            PijulStatus::InvalidHash
+        );
+        assert_eq!(
+            pijul_log(repo, channel.as_ptr(), push_hash, std::ptr::null_mut()),
+            PijulStatus::NotFound
+        );
+        assert_eq!(last_error(), "Channel not found: main");
+        pijul_repository_free(repo);
+    }
+    Ok(())
+}
+
+/// Panics are caught and reported as errors.
+#[test]
+fn capi_panic() {
+    let status = crate::capi::ffi(|| panic!("boom"));
+    assert_eq!(status, PijulStatus::Panic);
+    assert_eq!(last_error(), "Panic: boom");
+}
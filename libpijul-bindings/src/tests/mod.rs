@@ -0,0 +1,4 @@
+#[cfg(feature = "capi")]
+mod capi;
+#[cfg(feature = "python")]
+mod python;
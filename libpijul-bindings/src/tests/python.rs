@@ -0,0 +1,43 @@
+use crate::python::*;
+use libpijul::api::Identity;
+use pyo3::exceptions::PyValueError;
+use pyo3::Python;
+
+/// Record, apply, log and diff through the Python bindings.
+#[test]
+fn python_bindings() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+    pyo3::prepare_freethreaded_python();
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().to_str().unwrap();
+    let e = Repository::open(path).err().unwrap();
+    assert!(Python::with_gil(|py| e.is_instance::<PyValueError>(py)));
+
+    let api = libpijul::api::Repository::init(dir.path())?;
+    std::fs::write(dir.path().join("file"), b"a\nb\n")?;
+    api.add("file")?;
+    let h0 = api
+        .record("main", "init", &Identity::new("alice"))?
+        .unwrap();
+
+    let repo = Repository::open(path)?;
+    assert!(repo.diff("main")?.is_empty());
+    std::fs::write(dir.path().join("file"), b"a\nx\nb\n")?;
+    let diff = repo.diff("main")?;
+    assert_eq!(diff.len(), 1);
+    // Diffing doesn't record anything.
+    assert_eq!(repo.log("main")?, vec![h0.to_base32()]);
+
+    let h1 = repo.record("main", "edit", "bob")?.unwrap();
+    assert!(repo.record("main", "nothing", "bob")?.is_none());
+    assert_eq!(repo.change(&h1)?.dependencies, vec![h0.to_base32()]);
+    assert!(repo.change("not a hash").is_err());
+
+    repo.apply("other", &h1)?;
+    assert_eq!(repo.log("other")?, vec![h0.to_base32(), h1.clone()]);
+    let channels = repo.channels()?;
+    assert_eq!(channels.len(), 2);
+    assert!(repo.log("missing").is_err());
+    Ok(())
+}
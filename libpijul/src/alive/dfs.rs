@@ -135,7 +135,7 @@ fn test4165() {
             graph[j].scc = i
         }
     }
-    let mut f = std::fs::File::create("debug4165").unwrap();
+    let mut f = std::fs::File::create(std::env::temp_dir().join("debug4165")).unwrap();
     graph.debug_raw(&mut f).unwrap();
     println!("{:#?}", graph.dfs(&scc))
 }
@@ -1,10 +1,11 @@
 use super::dfs::{Path, PathElement};
 use super::{Flags, Graph, VertexId};
 use crate::changestore::ChangeStore;
-use crate::output::FileError;
+use crate::output::{ConflictId, ConflictOrder, FileError};
 use crate::pristine::*;
 use crate::vector2::Vector2;
 use crate::vertex_buffer::VertexBuffer;
+use crate::HashMap;
 
 #[derive(Debug)]
 struct ConflictStackElt {
@@ -21,7 +22,9 @@ fn output_conflict<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     graph: &Graph,
     sccs: &Vector2<VertexId>,
     conflict: Path,
+    order: ConflictOrder,
 ) -> Result<(), FileError<P::Error, T::GraphError>> {
+    let mut keys = HashMap::default();
     let mut stack = vec![ConflictStackElt {
         conflict: vec![conflict],
         side: 0,
@@ -31,22 +34,25 @@ fn output_conflict<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     while let Some(mut elt) = stack.pop() {
         let n_sides = elt.conflict.len();
         if n_sides > 1 && elt.side == 0 && elt.idx == 0 {
-            line_buf.begin_conflict()?;
-            elt.conflict.sort_by(|a, b| {
-                let a_ = a
-                    .path
-                    .iter()
-                    .map(|a| a.oldest_vertex(changes, txn, channel, graph, sccs).unwrap())
-                    .min()
-                    .unwrap();
-                let b_ = b
-                    .path
-                    .iter()
-                    .map(|b| b.oldest_vertex(changes, txn, channel, graph, sccs).unwrap())
-                    .min()
-                    .unwrap();
-                a_.cmp(&b_)
-            });
+            let mut starts = Vec::with_capacity(n_sides);
+            for side in elt.conflict.iter() {
+                if let Some(k) = first_vertex(txn, graph, sccs, &side.path)? {
+                    starts.push(k)
+                }
+            }
+            line_buf.begin_conflict(ConflictId::new(ConflictId::ORDER, &mut starts))?;
+            let mut sides = Vec::with_capacity(n_sides);
+            for side in elt.conflict.drain(..) {
+                let mut min = None;
+                for p in side.path.iter() {
+                    let k =
+                        p.oldest_vertex(changes, txn, channel, graph, sccs, order, &mut keys)?;
+                    min = Some(min.map_or(k, |m: SideKey| m.min(k)))
+                }
+                sides.push((min.unwrap(), side))
+            }
+            sides.sort_by(|a, b| a.0.cmp(&b.0));
+            elt.conflict = sides.into_iter().map(|(_, side)| side).collect();
         }
 
         let mut next = None;
@@ -103,6 +109,55 @@ fn output_conflict<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     Ok(())
 }
 
+/// The hash and position of the vertex starting `path`, or of the
+/// smallest one if `path` starts with a cycle or a conflict. Unlike
+/// vertices, these don't depend on the local numbering of changes.
+fn first_vertex<T: GraphTxnT>(
+    txn: &T,
+    graph: &Graph,
+    sccs: &Vector2<VertexId>,
+    path: &[PathElement],
+) -> Result<Option<(Hash, ChangePosition)>, TxnErr<T::GraphError>> {
+    match path.first() {
+        Some(PathElement::Scc { scc }) => {
+            let mut min = None;
+            for &v in sccs[*scc].iter() {
+                let k = vertex_key(txn, graph[v].vertex)?;
+                min = Some(min.map_or(k, |m: (Hash, ChangePosition)| m.min(k)))
+            }
+            Ok(min)
+        }
+        Some(PathElement::Conflict { sides }) => {
+            let mut min = None;
+            for side in sides.iter() {
+                if let Some(k) = first_vertex(txn, graph, sccs, &side.path)? {
+                    min = Some(min.map_or(k, |m: (Hash, ChangePosition)| m.min(k)))
+                }
+            }
+            Ok(min)
+        }
+        None => Ok(None),
+    }
+}
+
+fn vertex_key<T: GraphTxnT>(
+    txn: &T,
+    v: Vertex<ChangeId>,
+) -> Result<(Hash, ChangePosition), TxnErr<T::GraphError>> {
+    let h = match txn.get_external(&v.change)? {
+        Some(h) => h.into(),
+        None => Hash::None,
+    };
+    Ok((h, v.start))
+}
+
+/// The key of a conflict side, see [`ConflictOrder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SideKey {
+    Timestamp(chrono::DateTime<chrono::Utc>, Hash),
+    Application(u64),
+}
+
 impl PathElement {
     fn oldest_vertex<T: ChannelTxnT, C: ChangeStore>(
         &self,
@@ -111,41 +166,51 @@ impl PathElement {
         channel: &T::Channel,
         graph: &Graph,
         sccs: &Vector2<VertexId>,
-    ) -> Result<u64, TxnErr<T::GraphError>> {
+        order: ConflictOrder,
+        keys: &mut HashMap<ChangeId, SideKey>,
+    ) -> Result<SideKey, FileError<C::Error, T::GraphError>> {
         match *self {
             PathElement::Scc { ref scc } => {
-                let mut min: Option<L64> = None;
+                let mut min: Option<SideKey> = None;
                 for x in sccs[*scc].iter() {
-                    if let Some(t) =
-                        txn.get_changeset(txn.changes(&channel), &graph[*x].vertex.change)?
-                    {
-                        if let Some(ref mut m) = min {
-                            *m = (*m).min(*t)
-                        } else {
-                            min = Some(*t)
-                        }
+                    let change = graph[*x].vertex.change;
+                    let k = if let Some(k) = keys.get(&change) {
+                        *k
                     } else {
-                        if log_enabled!(log::Level::Debug) {
-                            let f = std::fs::File::create("debug_oldest").unwrap();
-                            graph
-                                .debug(changes, txn, txn.graph(channel), false, true, f)
-                                .unwrap();
-                        }
-                        panic!("vertex not in channel: {:?}", graph[*x].vertex)
-                    }
+                        let t =
+                            if let Some(t) = txn.get_changeset(txn.changes(&channel), &change)? {
+                                u64::from_le(t.0)
+                            } else {
+                                if log_enabled!(log::Level::Debug) {
+                                    let f = std::fs::File::create("debug_oldest").unwrap();
+                                    graph
+                                        .debug(changes, txn, txn.graph(channel), false, true, f)
+                                        .unwrap();
+                                }
+                                panic!("vertex not in channel: {:?}", graph[*x].vertex)
+                            };
+                        let k = match order {
+                            ConflictOrder::Application => SideKey::Application(t),
+                            ConflictOrder::Timestamp => {
+                                let h: Hash = txn.get_external(&change)?.unwrap().into();
+                                let header =
+                                    changes.get_header(&h).map_err(FileError::Changestore)?;
+                                SideKey::Timestamp(header.timestamp, h)
+                            }
+                        };
+                        keys.insert(change, k);
+                        k
+                    };
+                    min = Some(min.map_or(k, |m| m.min(k)))
                 }
-                Ok(u64::from_le(min.unwrap().0))
+                Ok(min.unwrap())
             }
             PathElement::Conflict { ref sides } => {
-                let mut min: Option<u64> = None;
+                let mut min: Option<SideKey> = None;
                 for x in sides.iter() {
                     for y in x.path.iter() {
-                        let t = y.oldest_vertex(changes, txn, channel, graph, sccs)?;
-                        if let Some(ref mut m) = min {
-                            *m = (*m).min(t)
-                        } else {
-                            min = Some(t)
-                        }
+                        let k = y.oldest_vertex(changes, txn, channel, graph, sccs, order, keys)?;
+                        min = Some(min.map_or(k, |m| m.min(k)))
                     }
                 }
                 Ok(min.unwrap())
@@ -163,14 +228,19 @@ fn output_scc<T: GraphTxnT, B: VertexBuffer, P: ChangeStore>(
     vbuf: &mut B,
 ) -> Result<(), FileError<P::Error, T::GraphError>> {
     if scc.len() > 1 {
-        vbuf.begin_cyclic_conflict()?;
+        let mut keys = Vec::with_capacity(scc.len());
+        for &v in scc.iter() {
+            keys.push(vertex_key(txn, graph[v].vertex)?)
+        }
+        vbuf.begin_cyclic_conflict(ConflictId::new(ConflictId::CYCLIC, &mut keys))?;
     }
     for &v in scc.iter() {
         let now = std::time::Instant::now();
         if graph[v].flags.contains(Flags::ZOMBIE) {
             if !*is_zombie {
                 *is_zombie = true;
-                vbuf.begin_zombie_conflict()?;
+                let mut key = [vertex_key(txn, graph[v].vertex)?];
+                vbuf.begin_zombie_conflict(ConflictId::new(ConflictId::ZOMBIE, &mut key))?;
             }
         } else if *is_zombie {
             *is_zombie = false;
@@ -214,6 +284,28 @@ pub fn output_graph<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     line_buf: &mut B,
     graph: &mut Graph,
     forward: &mut Vec<(Vertex<ChangeId>, SerializedEdge)>,
+) -> Result<(), crate::output::FileError<P::Error, T::GraphError>> {
+    output_graph_ordered(
+        changes,
+        txn,
+        channel,
+        line_buf,
+        graph,
+        forward,
+        ConflictOrder::default(),
+    )
+}
+
+/// Like [`output_graph`], with the sides of conflicts in `order`
+/// rather than in the default order.
+pub fn output_graph_ordered<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
+    changes: &P,
+    txn: &T,
+    channel: &T::Channel,
+    line_buf: &mut B,
+    graph: &mut Graph,
+    forward: &mut Vec<(Vertex<ChangeId>, SerializedEdge)>,
+    order: ConflictOrder,
 ) -> Result<(), crate::output::FileError<P::Error, T::GraphError>> {
     if graph.lines.len() <= 1 {
         return Ok(());
@@ -226,7 +318,16 @@ pub fn output_graph<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
     crate::TIMERS.lock().unwrap().alive_graph += now0.elapsed();
     let now1 = std::time::Instant::now();
     debug!("conflict_tree = {:?}", conflict_tree);
-    output_conflict(changes, txn, channel, line_buf, graph, &scc, conflict_tree)?;
+    output_conflict(
+        changes,
+        txn,
+        channel,
+        line_buf,
+        graph,
+        &scc,
+        conflict_tree,
+        order,
+    )?;
     crate::TIMERS.lock().unwrap().alive_output += now1.elapsed();
     Ok(())
 }
@@ -3,11 +3,80 @@ use crate::pristine::*;
 use crate::HashMap;
 use std::collections::hash_map::Entry;
 
+/// Limits on the size of the graphs retrieved to output or diff a
+/// file. Pathological files can have graphs so large that outputting
+/// them takes minutes; these limits turn that into a
+/// [`GraphTooLarge`] error. They are set by each caller: see
+/// [`crate::record::Builder::graph_limits`] for recordings, and
+/// [`crate::working_copy::WorkingCopy::graph_limits`] and
+/// [`crate::output::OutputSink::graph_limits`] for outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RetrieveLimits {
+    /// Maximal number of alive vertices.
+    pub vertices: Option<usize>,
+    /// Maximal number of edges between alive vertices.
+    pub edges: Option<usize>,
+}
+
+impl RetrieveLimits {
+    /// No limits, for callers that would rather wait.
+    pub const UNLIMITED: RetrieveLimits = RetrieveLimits {
+        vertices: None,
+        edges: None,
+    };
+}
+
+/// The graph of the file at `pos` exceeded the [`RetrieveLimits`].
+/// The statistics are those of the part of the graph retrieved when
+/// the limit was hit.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("Graph of file {pos:?} too large: {vertices} vertices, {edges} edges, {bytes} bytes retrieved, limits {limits:?}")]
+pub struct GraphTooLarge {
+    pub pos: Position<ChangeId>,
+    pub vertices: usize,
+    pub edges: usize,
+    pub bytes: usize,
+    pub limits: RetrieveLimits,
+}
+
+#[derive(Debug, Error)]
+pub enum RetrieveError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for RetrieveError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        RetrieveError::Txn(e.0)
+    }
+}
+
+/// Retrieve the graph of the file at `pos0`, however large it is.
 pub fn retrieve<T: GraphTxnT>(
     txn: &T,
     channel: &T::Graph,
     pos0: Position<ChangeId>,
 ) -> Result<Graph, TxnErr<T::GraphError>> {
+    match retrieve_limited(txn, channel, pos0, RetrieveLimits::UNLIMITED) {
+        Ok(graph) => Ok(graph),
+        Err(RetrieveError::Txn(e)) => Err(TxnErr(e)),
+        Err(RetrieveError::GraphTooLarge(_)) => unreachable!(),
+    }
+}
+
+/// Retrieve the graph of the file at `pos0`, stopping as soon as it
+/// exceeds `limits`.
+pub fn retrieve_limited<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
+    pos0: Position<ChangeId>,
+    limits: RetrieveLimits,
+) -> Result<Graph, RetrieveError<T::GraphError>> {
+    let max_vertices = limits.vertices.unwrap_or(usize::MAX);
+    let max_edges = limits.edges.unwrap_or(usize::MAX);
+    let mut edges = 0;
     let now = std::time::Instant::now();
     let mut graph = Graph {
         lines: Vec::new(),
@@ -59,6 +128,19 @@ pub fn retrieve<T: GraphTxnT>(
             trace!("child {:?}", dest_vid);
             graph.children.push((Some(*e), dest_vid));
             graph[vid].n_children += 1;
+            edges += 1;
+            // The first line is a dummy vertex.
+            if graph.lines.len() - 1 > max_vertices || edges > max_edges {
+                debug!("graph of {:?} too large", pos0);
+                return Err(GraphTooLarge {
+                    pos: pos0,
+                    vertices: graph.lines.len() - 1,
+                    edges,
+                    bytes: graph.total_bytes,
+                    limits,
+                }
+                .into());
+            }
         }
         graph.children.push((None, VertexId::DUMMY));
         graph[vid].n_children += 1;
@@ -105,16 +187,28 @@ fn new_vertex<T: GraphTxnT>(
     }))
 }
 
-pub(crate) fn remove_forward_edges<T: GraphMutTxnT>(
-    txn: &mut T,
-    channel: &mut T::Graph,
+/// Retrieve the graph of the file at `pos`, and collect its forward
+/// pseudo-edges, i.e. the pseudo-edges whose target is also reachable
+/// through another path.
+pub(crate) fn forward_edges<T: GraphTxnT>(
+    txn: &T,
+    channel: &T::Graph,
     pos: Position<ChangeId>,
-) -> Result<(), TxnErr<T::GraphError>> {
+) -> Result<(Graph, Vec<(Vertex<ChangeId>, SerializedEdge)>), TxnErr<T::GraphError>> {
     let mut graph = retrieve(txn, channel, pos)?;
     let scc = graph.tarjan(); // SCCs are given here in reverse order.
     let (_, forward_scc) = graph.dfs(&scc);
     let mut forward = Vec::new();
     graph.collect_forward_edges(txn, channel, &scc, &forward_scc, &mut forward)?;
+    Ok((graph, forward))
+}
+
+pub(crate) fn remove_forward_edges<T: GraphMutTxnT>(
+    txn: &mut T,
+    channel: &mut T::Graph,
+    pos: Position<ChangeId>,
+) -> Result<(), TxnErr<T::GraphError>> {
+    let (_, forward) = forward_edges(txn, channel, pos)?;
     for &(vertex, edge) in forward.iter() {
         let dest = *txn.find_block(channel, edge.dest()).unwrap();
         debug!(target:"libpijul::forward", "deleting forward edge {:?} {:?} {:?}", vertex, dest, edge);
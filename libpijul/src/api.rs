@@ -0,0 +1,660 @@
+//! A high-level interface to on-disk repositories.
+//!
+//! The rest of this crate is generic over the transactions of the
+//! pristine, the change stores and the working copies, and exposes
+//! each step of the core operations separately, which lets tools
+//! combine them in new ways, but changes often. This module offers
+//! the common operations (record, apply, log, diff, output,
+//! conflicts, clone, push and pull) on repositories stored on disk,
+//! with types that don't expose that plumbing. Its signatures only
+//! change when [`API_VERSION`] is bumped, and errors are reported as
+//! an [`ApiError`] carrying the message of the underlying error.
+//!
+//! ```ignore
+//! let repo = Repository::open("path/to/repo")?;
+//! let me = Identity::new("Alice");
+//! if let Some(hash) = repo.record("main", "Fix the build", &me)? {
+//!     println!("recorded {}", hash);
+//! }
+//! ```
+use crate::change::{Author, Change, ChangeHeader};
+use crate::changestore::{self, ChangeStore};
+use crate::pristine::sanakirja::{MutTxn, Pristine};
+use crate::pristine::*;
+use crate::record::{Algorithm, Builder};
+use crate::working_copy::{self, WorkingCopy};
+use crate::{MutTxnTExt, TxnTExt};
+use std::path::{Path, PathBuf};
+
+/// The version of this module, bumped on every incompatible change
+/// to its signatures.
+pub const API_VERSION: u32 = 1;
+
+/// An error of this module. The variants tell which step failed.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ApiError {
+    #[error("No repository at {0:?}")]
+    NotFound(PathBuf),
+    #[error("Already in a repository: {0:?}")]
+    AlreadyInRepository(PathBuf),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+    #[error("Channel {0} is deleted")]
+    ChannelDeleted(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Pristine error: {0}")]
+    Pristine(String),
+    #[error("Change store error: {0}")]
+    Changestore(String),
+    #[error("Record error: {0}")]
+    Record(String),
+    #[error("Apply error: {0}")]
+    Apply(String),
+    #[error("Output error: {0}")]
+    Output(String),
+}
+
+impl ApiError {
+    fn pristine<E: std::fmt::Display>(e: E) -> Self {
+        ApiError::Pristine(e.to_string())
+    }
+}
+
+/// The hash of a change, written in base32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChangeHash(Hash);
+
+impl ChangeHash {
+    pub fn to_base32(&self) -> String {
+        self.0.to_base32()
+    }
+
+    /// Parse a hash written by [`ChangeHash::to_base32`].
+    pub fn from_base32(s: &str) -> Option<Self> {
+        Hash::from_base32(s.as_bytes()).map(ChangeHash)
+    }
+}
+
+impl std::fmt::Display for ChangeHash {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(&self.0.to_base32())
+    }
+}
+
+/// The state of a channel, which identifies the set of its changes,
+/// written in base32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState(Merkle);
+
+impl ChannelState {
+    pub fn to_base32(&self) -> String {
+        self.0.to_base32()
+    }
+}
+
+impl std::fmt::Display for ChannelState {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(&self.0.to_base32())
+    }
+}
+
+/// An entry of the log of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub hash: ChangeHash,
+    /// The state of the channel after this change.
+    pub state: ChannelState,
+}
+
+/// The kind of a [`FileConflict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConflictKind {
+    /// Several files have the same name.
+    Name,
+    /// A file was deleted by one change and edited by another.
+    ZombieFile,
+    /// A file has several names.
+    MultipleNames,
+    /// Lines were deleted by one change and edited by another.
+    Zombie,
+    /// Lines are ordered in a cycle.
+    Cyclic,
+    /// Lines were inserted at the same place by different changes.
+    Order,
+}
+
+/// A conflict found by [`Repository::output`] or
+/// [`Repository::conflicts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileConflict {
+    pub kind: ConflictKind,
+    pub path: String,
+    /// The line where the conflict starts, for conflicts inside
+    /// files.
+    pub line: Option<usize>,
+}
+
+impl From<crate::Conflict> for FileConflict {
+    fn from(c: crate::Conflict) -> Self {
+        use crate::Conflict::*;
+        let (kind, path, line) = match c {
+            Name { path } => (ConflictKind::Name, path, None),
+            ZombieFile { path } => (ConflictKind::ZombieFile, path, None),
+            MultipleNames { path, .. } => (ConflictKind::MultipleNames, path, None),
+            Zombie { path, line } => (ConflictKind::Zombie, path, Some(line)),
+            Cyclic { path, line } => (ConflictKind::Cyclic, path, Some(line)),
+            Order { path, line } => (ConflictKind::Order, path, Some(line)),
+        };
+        FileConflict { kind, path, line }
+    }
+}
+
+/// The header and dependencies of a change, see
+/// [`Repository::change`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeInfo {
+    pub hash: ChangeHash,
+    pub message: String,
+    pub description: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub dependencies: Vec<ChangeHash>,
+}
+
+/// How the lines of a [`LineChange`] changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// Lines `old` of the version of a file in a channel were replaced
+/// with lines `new` of the working copy, numbered from 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineChange {
+    pub kind: LineChangeKind,
+    pub old: std::ops::Range<usize>,
+    pub new: std::ops::Range<usize>,
+}
+
+impl From<crate::LineChange> for LineChange {
+    fn from(c: crate::LineChange) -> Self {
+        LineChange {
+            kind: match c.kind {
+                crate::LineChangeKind::Added => LineChangeKind::Added,
+                crate::LineChangeKind::Modified => LineChangeKind::Modified,
+                crate::LineChangeKind::Removed => LineChangeKind::Removed,
+            },
+            old: c.old,
+            new: c.new,
+        }
+    }
+}
+
+/// A tracked file with unrecorded changes, see [`Repository::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub path: String,
+    /// Whether the file was deleted from the working copy, in which
+    /// case `lines` is empty.
+    pub deleted: bool,
+    pub lines: Vec<LineChange>,
+}
+
+/// The author of the changes recorded by [`Repository::record`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: Option<String>,
+    /// The public key of the author, as written by `pijul key`.
+    pub key: Option<String>,
+}
+
+impl Identity {
+    /// An author named `name`, without email or key.
+    pub fn new(name: &str) -> Self {
+        Identity {
+            name: name.to_string(),
+            email: None,
+            key: None,
+        }
+    }
+
+    fn author(&self) -> Author {
+        let mut a = std::collections::BTreeMap::new();
+        a.insert("name".to_string(), self.name.clone());
+        if let Some(ref email) = self.email {
+            a.insert("email".to_string(), email.clone());
+        }
+        if let Some(ref key) = self.key {
+            a.insert("key".to_string(), key.clone());
+        }
+        Author(a)
+    }
+}
+
+/// A repository stored on disk, i.e. a directory containing a
+/// `.pijul` directory.
+pub struct Repository {
+    root: PathBuf,
+    pristine: Pristine,
+    changes: changestore::filesystem::FileSystem,
+    working_copy: working_copy::filesystem::FileSystem,
+}
+
+impl Repository {
+    fn at(root: &Path) -> Result<Self, ApiError> {
+        let pristine_dir = root
+            .join(crate::DOT_DIR)
+            .join(crate::repository::PRISTINE_DIR);
+        std::fs::create_dir_all(&pristine_dir)?;
+        Ok(Repository {
+            root: root.to_path_buf(),
+            pristine: Pristine::new(&pristine_dir.join("db")).map_err(ApiError::pristine)?,
+            changes: changestore::filesystem::FileSystem::from_root(root, 256),
+            working_copy: working_copy::filesystem::FileSystem::from_root(root),
+        })
+    }
+
+    /// Open the repository rooted at `root`.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, ApiError> {
+        let root = root.as_ref();
+        if std::fs::metadata(root.join(crate::DOT_DIR)).is_err() {
+            return Err(ApiError::NotFound(root.to_path_buf()));
+        }
+        Self::at(root)
+    }
+
+    /// Create an empty repository at `root`, which is created if
+    /// needed.
+    pub fn init<P: AsRef<Path>>(root: P) -> Result<Self, ApiError> {
+        let root = root.as_ref();
+        let dot_dir = root.join(crate::DOT_DIR);
+        if std::fs::metadata(&dot_dir).is_ok() {
+            return Err(ApiError::AlreadyInRepository(dot_dir));
+        }
+        let repo = Self::at(root)?;
+        let txn = repo.pristine.mut_txn_begin().map_err(ApiError::pristine)?;
+        txn.commit().map_err(ApiError::pristine)?;
+        Ok(repo)
+    }
+
+    /// The root of this repository.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The names of the channels of this repository.
+    pub fn channels(&self) -> Result<Vec<String>, ApiError> {
+        let txn = self.pristine.txn_begin().map_err(ApiError::pristine)?;
+        let mut result = Vec::new();
+        for c in txn.iter_channels("").map_err(|e| ApiError::pristine(e.0))? {
+            let (name, channel) = c.map_err(|e| ApiError::pristine(e.0))?;
+            if !crate::channel::is_deleted(&txn, &*channel.read())
+                .map_err(|e| ApiError::pristine(e.0))?
+            {
+                result.push(name.as_str().to_string())
+            }
+        }
+        Ok(result)
+    }
+
+    fn load_channel(
+        txn: &ArcTxn<MutTxn<()>>,
+        name: &str,
+    ) -> Result<ChannelRef<MutTxn<()>>, ApiError> {
+        crate::channel::load_channel(&*txn.read(), name)
+            .map_err(ApiError::pristine)?
+            .ok_or_else(|| ApiError::ChannelNotFound(name.to_string()))
+    }
+
+    fn open_or_create_channel(
+        txn: &ArcTxn<MutTxn<()>>,
+        name: &str,
+    ) -> Result<ChannelRef<MutTxn<()>>, ApiError> {
+        crate::channel::open_or_create_channel(&mut *txn.write(), name).map_err(|e| match e {
+            crate::channel::SoftDeleteError::Deleted(name) => ApiError::ChannelDeleted(name),
+            e => ApiError::pristine(e),
+        })
+    }
+
+    /// Add `path` (relative to the root, with `/` as the separator)
+    /// to the tracked files, recursively if it is a directory.
+    pub fn add(&self, path: &str) -> Result<(), ApiError> {
+        let txn = self.pristine.arc_txn_begin().map_err(ApiError::pristine)?;
+        self.add_rec(&txn, path)?;
+        txn.commit().map_err(ApiError::pristine)
+    }
+
+    fn add_rec(&self, txn: &ArcTxn<MutTxn<()>>, path: &str) -> Result<(), ApiError> {
+        let full = self.root.join(path);
+        if std::fs::metadata(&full)?.is_dir() {
+            txn.write()
+                .add_dir(path, 0)
+                .map_err(|e| ApiError::Record(e.to_string()))?;
+            for entry in std::fs::read_dir(&full)? {
+                let name = entry?.file_name();
+                if name == crate::DOT_DIR {
+                    continue;
+                }
+                if let Some(name) = name.to_str() {
+                    self.add_rec(txn, &format!("{}/{}", path, name))?;
+                }
+            }
+            Ok(())
+        } else {
+            txn.write()
+                .add_file(path, 0)
+                .map_err(|e| ApiError::Record(e.to_string()))
+        }
+    }
+
+    /// Record all the changes of the tracked files on `channel`
+    /// (created if needed) as a change authored by `author`, and
+    /// return the hash of the new change, or `None` if there was
+    /// nothing to record.
+    pub fn record(
+        &self,
+        channel: &str,
+        message: &str,
+        author: &Identity,
+    ) -> Result<Option<ChangeHash>, ApiError> {
+        let txn = self.pristine.arc_txn_begin().map_err(ApiError::pristine)?;
+        let channel = Self::open_or_create_channel(&txn, channel)?;
+        let mut builder = Builder::new();
+        builder
+            .record(
+                txn.clone(),
+                Algorithm::default(),
+                channel.clone(),
+                &self.working_copy,
+                &self.changes,
+                "",
+                1,
+            )
+            .map_err(|e| ApiError::Record(e.to_string()))?;
+        let rec = builder
+            .finish()
+            .map_err(|e| ApiError::Record(e.to_string()))?;
+        if rec.actions.is_empty() {
+            return Ok(None);
+        }
+        let hash = {
+            let mut txn_ = txn.write();
+            let actions = rec
+                .actions
+                .into_iter()
+                .map(|rec| rec.globalize(&*txn_))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ApiError::pristine)?;
+            let change = Change::make_change(
+                &*txn_,
+                &channel,
+                actions,
+                std::mem::take(&mut *rec.contents.lock()),
+                ChangeHeader {
+                    message: message.to_string(),
+                    authors: vec![author.author()],
+                    description: None,
+                    timestamp: chrono::Utc::now(),
+                },
+                Vec::new(),
+            )
+            .map_err(|e| ApiError::Record(e.to_string()))?;
+            let hash = self
+                .changes
+                .save_change(&change)
+                .map_err(|e| ApiError::Changestore(e.to_string()))?;
+            txn_.apply_local_change(&channel, &change, &hash, &rec.updatables)
+                .map_err(|e| ApiError::Apply(e.to_string()))?;
+            hash
+        };
+        txn.commit().map_err(ApiError::pristine)?;
+        Ok(Some(ChangeHash(hash)))
+    }
+
+    /// Apply change `hash`, which must be in the change store of this
+    /// repository, and its dependencies to `channel` (created if
+    /// needed). This doesn't touch the working copy, see
+    /// [`Repository::output`].
+    pub fn apply(&self, channel: &str, hash: &ChangeHash) -> Result<(), ApiError> {
+        let txn = self.pristine.arc_txn_begin().map_err(ApiError::pristine)?;
+        let channel = Self::open_or_create_channel(&txn, channel)?;
+        txn.write()
+            .apply_change_rec(&self.changes, &mut *channel.write(), &hash.0)
+            .map_err(|e| ApiError::Apply(e.to_string()))?;
+        txn.commit().map_err(ApiError::pristine)
+    }
+
+    /// The changes of `channel`, in the order in which they were
+    /// applied.
+    pub fn log(&self, channel: &str) -> Result<Vec<LogEntry>, ApiError> {
+        let txn = self.pristine.txn_begin().map_err(ApiError::pristine)?;
+        let channel = txn
+            .load_channel(channel)
+            .map_err(ApiError::pristine)?
+            .ok_or_else(|| ApiError::ChannelNotFound(channel.to_string()))?;
+        let channel = channel.read();
+        let mut entries = Vec::new();
+        for x in txn.log(&*channel, 0).map_err(ApiError::pristine)? {
+            let (_, (h, m)) = x.map_err(ApiError::pristine)?;
+            entries.push(LogEntry {
+                hash: ChangeHash(h.into()),
+                state: ChannelState(m.into()),
+            })
+        }
+        Ok(entries)
+    }
+
+    /// The current state of `channel`.
+    pub fn state(&self, channel: &str) -> Result<ChannelState, ApiError> {
+        let txn = self.pristine.txn_begin().map_err(ApiError::pristine)?;
+        let channel = txn
+            .load_channel(channel)
+            .map_err(ApiError::pristine)?
+            .ok_or_else(|| ApiError::ChannelNotFound(channel.to_string()))?;
+        let state = txn
+            .current_state(&*channel.read())
+            .map_err(ApiError::pristine)?;
+        Ok(ChannelState(state))
+    }
+
+    /// The header and dependencies of change `hash`.
+    pub fn change(&self, hash: &ChangeHash) -> Result<ChangeInfo, ApiError> {
+        let c = self
+            .changes
+            .get_change(&hash.0)
+            .map_err(|e| ApiError::Changestore(e.to_string()))?;
+        Ok(ChangeInfo {
+            hash: *hash,
+            message: c.hashed.header.message,
+            description: c.hashed.header.description,
+            timestamp: c.hashed.header.timestamp,
+            dependencies: c.hashed.dependencies.into_iter().map(ChangeHash).collect(),
+        })
+    }
+
+    /// The tracked files whose contents in the working copy differ
+    /// from their version in `channel`, with the lines that changed.
+    /// Unlike [`Repository::record`], this only reads the pristine.
+    pub fn diff(&self, channel: &str) -> Result<Vec<FileDiff>, ApiError> {
+        let txn = self.pristine.txn_begin().map_err(ApiError::pristine)?;
+        let channel = txn
+            .load_channel(channel)
+            .map_err(ApiError::pristine)?
+            .ok_or_else(|| ApiError::ChannelNotFound(channel.to_string()))?;
+        let channel = channel.read();
+        let mut result = Vec::new();
+        for x in crate::fs::iter_working_copy(&txn, Inode::ROOT) {
+            let (_, path) = x.map_err(ApiError::pristine)?;
+            match self.working_copy.file_metadata(&path) {
+                Ok(meta) if meta.is_dir() => continue,
+                Ok(_) => {}
+                Err(_) => {
+                    result.push(FileDiff {
+                        path,
+                        deleted: true,
+                        lines: Vec::new(),
+                    });
+                    continue;
+                }
+            }
+            let lines = crate::working_copy_gutter(
+                &txn,
+                &*channel,
+                &self.changes,
+                &self.working_copy,
+                &path,
+            )
+            .map_err(|e| ApiError::Output(e.to_string()))?;
+            if !lines.is_empty() {
+                result.push(FileDiff {
+                    path,
+                    deleted: false,
+                    lines: lines.into_iter().map(LineChange::from).collect(),
+                })
+            }
+        }
+        Ok(result)
+    }
+
+    /// Output `channel` to the working copy, and return the conflicts.
+    ///
+    /// **WARNING:** This overwrites the working copy, cancelling any
+    /// unrecorded change.
+    pub fn output(&self, channel: &str) -> Result<Vec<FileConflict>, ApiError> {
+        let txn = self.pristine.arc_txn_begin().map_err(ApiError::pristine)?;
+        let channel = Self::load_channel(&txn, channel)?;
+        let conflicts = crate::output::output_repository_no_pending(
+            &self.working_copy,
+            &self.changes,
+            &txn,
+            &channel,
+            "",
+            true,
+            None,
+            1,
+            0,
+        )
+        .map_err(|e| ApiError::Output(e.to_string()))?;
+        txn.commit().map_err(ApiError::pristine)?;
+        Ok(conflicts.into_iter().map(FileConflict::from).collect())
+    }
+
+    /// The conflicts of `channel`, found by outputting it in memory.
+    /// Unlike [`Repository::output`], this leaves the working copy
+    /// and the pristine untouched.
+    pub fn conflicts(&self, channel: &str) -> Result<Vec<FileConflict>, ApiError> {
+        let txn = self.pristine.arc_txn_begin().map_err(ApiError::pristine)?;
+        let channel = Self::load_channel(&txn, channel)?;
+        let memory = working_copy::memory::Memory::new();
+        let conflicts = crate::output::output_repository_no_pending(
+            &memory,
+            &self.changes,
+            &txn,
+            &channel,
+            "",
+            true,
+            None,
+            1,
+            0,
+        )
+        .map_err(|e| ApiError::Output(e.to_string()))?;
+        Ok(conflicts.into_iter().map(FileConflict::from).collect())
+    }
+
+    /// Clone channel `channel` of `source` into a new repository at
+    /// `root`, and output it.
+    pub fn clone<P: AsRef<Path>>(
+        source: &Repository,
+        channel: &str,
+        root: P,
+    ) -> Result<Self, ApiError> {
+        let repo = Self::init(root)?;
+        source.push(channel, &repo, channel)?;
+        repo.output(channel)?;
+        Ok(repo)
+    }
+
+    /// Copy the changes of `channel` missing from `remote_channel` of
+    /// `remote` to `remote`, and apply them there, in the order of
+    /// `channel`. The working copy of `remote` isn't touched. Returns
+    /// the changes applied.
+    pub fn push(
+        &self,
+        channel: &str,
+        remote: &Repository,
+        remote_channel: &str,
+    ) -> Result<Vec<ChangeHash>, ApiError> {
+        let missing = {
+            let from_txn = self.pristine.txn_begin().map_err(ApiError::pristine)?;
+            let from = from_txn
+                .load_channel(channel)
+                .map_err(ApiError::pristine)?
+                .ok_or_else(|| ApiError::ChannelNotFound(channel.to_string()))?;
+            let to_txn = remote.pristine.txn_begin().map_err(ApiError::pristine)?;
+            let to = to_txn
+                .load_channel(remote_channel)
+                .map_err(ApiError::pristine)?;
+            if let Some(ref to) = to {
+                if crate::channel::is_deleted(&to_txn, &*to.read()).map_err(ApiError::pristine)? {
+                    return Err(ApiError::ChannelDeleted(remote_channel.to_string()));
+                }
+            }
+            let mut missing = Vec::new();
+            for x in from_txn.log(&*from.read(), 0).map_err(ApiError::pristine)? {
+                let (_, (h, _)) = x.map_err(ApiError::pristine)?;
+                let h: Hash = h.into();
+                let present = if let Some(ref to) = to {
+                    to_txn
+                        .has_change(to, &h)
+                        .map_err(ApiError::pristine)?
+                        .is_some()
+                } else {
+                    false
+                };
+                if !present {
+                    missing.push(h)
+                }
+            }
+            missing
+        };
+        for h in missing.iter() {
+            let dest = remote.changes.filename(h);
+            if std::fs::metadata(&dest).is_err() {
+                if let Some(parent) = dest.parent() {
+                    std::fs::create_dir_all(parent)?
+                }
+                std::fs::copy(self.changes.filename(h), &dest)?;
+            }
+        }
+        let txn = remote
+            .pristine
+            .arc_txn_begin()
+            .map_err(ApiError::pristine)?;
+        let to = Self::open_or_create_channel(&txn, remote_channel)?;
+        for h in missing.iter() {
+            txn.write()
+                .apply_change_rec(&remote.changes, &mut *to.write(), h)
+                .map_err(|e| ApiError::Apply(e.to_string()))?;
+        }
+        txn.commit().map_err(ApiError::pristine)?;
+        Ok(missing.into_iter().map(ChangeHash).collect())
+    }
+
+    /// Copy the changes of `remote_channel` of `remote` missing from
+    /// `channel` to this repository, and apply them, in the order of
+    /// `remote_channel`. The working copy isn't touched, see
+    /// [`Repository::output`]. Returns the changes applied.
+    pub fn pull(
+        &self,
+        channel: &str,
+        remote: &Repository,
+        remote_channel: &str,
+    ) -> Result<Vec<ChangeHash>, ApiError> {
+        remote.push(remote_channel, self, channel)
+    }
+}
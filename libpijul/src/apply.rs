@@ -10,6 +10,12 @@ pub(crate) mod edge;
 pub(crate) use edge::*;
 mod vertex;
 pub(crate) use vertex::*;
+mod empty;
+pub use empty::*;
+mod preview;
+pub use preview::*;
+mod report;
+pub use report::*;
 
 #[derive(Debug, Error)]
 pub enum ApplyError<ChangestoreError: std::error::Error, TxnError: std::error::Error + 'static> {
@@ -20,6 +26,119 @@ pub enum ApplyError<ChangestoreError: std::error::Error, TxnError: std::error::E
         #[from]
         err: LocalApplyError<TxnError>,
     },
+    #[error("Resource limit exceeded: {resource:?} (limit {limit})")]
+    ResourceExceeded { resource: Resource, limit: u64 },
+    #[error("Invalid timestamp in change {:?}: {reason:?}", hash)]
+    InvalidTimestamp {
+        hash: Hash,
+        reason: TimestampViolation,
+    },
+    #[error("Change {:?} rejected: {err}", hash)]
+    InvalidHeader {
+        hash: Hash,
+        err: crate::header::InvalidHeader,
+    },
+}
+
+/// Reasons for rejecting the timestamp of a change, see
+/// [TimestampPolicy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampViolation {
+    /// The change is dated too far in the future.
+    Future,
+    /// The change is dated before one of its dependencies.
+    NonMonotonic,
+}
+
+/// Resources that can be bounded by [ApplyOptions].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Memory,
+    PendingChanges,
+}
+
+/// Limits on the resources consumed by [apply_change_rec_opt], for
+/// servers applying changes on behalf of other users.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Maximal total size, in bytes, of the contents of the changes
+    /// applied, checked before loading each change.
+    pub max_memory: Option<u64>,
+    /// Pause between two consecutive changes.
+    pub io_throttle: Option<std::time::Duration>,
+    /// Maximal number of changes applied (including dependencies).
+    pub max_pending_changes: Option<usize>,
+    /// Checks on the timestamps of the changes applied.
+    pub timestamps: TimestampPolicy,
+    /// Rules on the headers of the changes applied, see
+    /// [`crate::header`].
+    pub headers: crate::header::HeaderPolicy,
+    /// Don't add the changes that are semantically empty on the
+    /// channel (see [is_semantically_empty]) to its log. Since the
+    /// dependencies of the changes of a channel must be on the
+    /// channel, the changes depending on a skipped change, directly
+    /// or not, are skipped too.
+    pub skip_empty: bool,
+    /// Take the channel in this registry while applying, see
+    /// [`crate::intent`].
+    pub intents: Option<crate::intent::Intents>,
+    /// How long to wait for the other operations on the channel in
+    /// `intents`. Fail immediately if `None`.
+    pub intent_timeout: Option<std::time::Duration>,
+}
+
+/// Checks on the timestamps of changes, so that servers can refuse
+/// changes that would make the history of a channel misleading. The
+/// default policy accepts all timestamps.
+#[derive(Debug, Clone, Default)]
+pub struct TimestampPolicy {
+    /// Reject changes dated more than this after the time at which
+    /// they are applied.
+    pub max_future: Option<std::time::Duration>,
+    /// Reject changes dated before one of their dependencies.
+    pub monotonic: bool,
+}
+
+/// Check the timestamp of `change` against `policy`, at time `now`.
+pub fn check_timestamp<P: ChangeStore>(
+    changes: &P,
+    change: &Change,
+    policy: &TimestampPolicy,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<(), CheckTimestampError<P::Error>> {
+    let timestamp = change.header.timestamp;
+    if let Some(max) = policy.max_future {
+        let max = chrono::Duration::from_std(max).unwrap_or_else(|_| chrono::Duration::max_value());
+        if let Some(limit) = now.checked_add_signed(max) {
+            if timestamp > limit {
+                return Err(CheckTimestampError::Violation(TimestampViolation::Future));
+            }
+        }
+    }
+    if policy.monotonic {
+        for dep in change.dependencies.iter() {
+            if let Hash::None = dep {
+                continue;
+            }
+            let header = changes
+                .get_header(dep)
+                .map_err(CheckTimestampError::Changestore)?;
+            if timestamp < header.timestamp {
+                return Err(CheckTimestampError::Violation(
+                    TimestampViolation::NonMonotonic,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum CheckTimestampError<ChangestoreError: std::error::Error> {
+    #[error("Changestore error: {0}")]
+    Changestore(ChangestoreError),
+    #[error("Invalid timestamp: {0:?}")]
+    Violation(TimestampViolation),
 }
 
 #[derive(Debug, Error)]
@@ -34,6 +153,15 @@ pub enum LocalApplyError<TxnError: std::error::Error + 'static> {
     Block { block: Position<ChangeId> },
     #[error("Invalid change")]
     InvalidChange,
+    #[error("Hunk kind {kind:?} is not registered")]
+    UnknownHunkKind { kind: String },
+    #[error("Hunk kind {kind:?}: {error}")]
+    HunkKind {
+        kind: String,
+        error: crate::hunk_kind::HunkKindError,
+    },
+    #[error(transparent)]
+    Intent(crate::intent::IntentError<TxnError>),
 }
 
 impl<TxnError: std::error::Error> LocalApplyError<TxnError> {
@@ -91,7 +219,9 @@ pub fn apply_change_ws<T: MutTxnT, P: ChangeStore>(
 ) -> Result<(u64, Merkle), ApplyError<P::Error, T::GraphError>> {
     debug!("apply_change {:?}", hash.to_base32());
     workspace.clear();
-    let change = changes.get_change(&hash).map_err(ApplyError::Changestore)?;
+    let change = changes
+        .get_change_without_contents(&hash)
+        .map_err(ApplyError::Changestore)?;
 
     for hash in change.dependencies.iter() {
         if let Hash::None = hash {
@@ -125,13 +255,49 @@ pub fn apply_change_rec_ws<T: TxnT + MutTxnT, P: ChangeStore>(
     hash: &Hash,
     workspace: &mut Workspace,
     deps_only: bool,
+) -> Result<(), ApplyError<P::Error, T::GraphError>> {
+    apply_change_rec_opt(
+        changes,
+        txn,
+        channel,
+        hash,
+        workspace,
+        deps_only,
+        &ApplyOptions::default(),
+    )
+}
+
+/// Same as [apply_change_rec_ws], but bounding the resources used
+/// according to `options`. When a limit is exceeded, the changes
+/// applied so far are left on the channel, and the caller is
+/// expected to abort the transaction.
+pub fn apply_change_rec_opt<T: TxnT + MutTxnT, P: ChangeStore>(
+    changes: &P,
+    txn: &mut T,
+    channel: &mut T::Channel,
+    hash: &Hash,
+    workspace: &mut Workspace,
+    deps_only: bool,
+    options: &ApplyOptions,
 ) -> Result<(), ApplyError<P::Error, T::GraphError>> {
     debug!("apply_change {:?}", hash.to_base32());
+    let _intent = if let Some(ref intents) = options.intents {
+        let intent = crate::intent::Intent::Apply;
+        Some(
+            intents
+                .begin_channels(&*txn, &[&*channel], intent, options.intent_timeout)
+                .map_err(LocalApplyError::Intent)?,
+        )
+    } else {
+        None
+    };
     workspace.clear();
     let mut dep_stack = vec![(*hash, true, !deps_only)];
     let mut visited = HashSet::default();
+    let mut memory = 0u64;
+    let mut n_applied = 0usize;
+    let mut skipped = HashSet::default();
     while let Some((hash, first, actually_apply)) = dep_stack.pop() {
-        let change = changes.get_change(&hash).map_err(ApplyError::Changestore)?;
         let shash: SerializedHash = (&hash).into();
         if first {
             if !visited.insert(hash) {
@@ -147,6 +313,9 @@ pub fn apply_change_rec_ws<T: TxnT + MutTxnT, P: ChangeStore>(
             }
 
             dep_stack.push((hash, false, actually_apply));
+            let change = changes
+                .get_change_without_contents(&hash)
+                .map_err(ApplyError::Changestore)?;
             for &hash in change.dependencies.iter() {
                 if let Hash::None = hash {
                     continue;
@@ -160,6 +329,41 @@ pub fn apply_change_rec_ws<T: TxnT + MutTxnT, P: ChangeStore>(
                 false
             };
             if !applied {
+                // Check the size of the contents before loading them.
+                if let Some(max) = options.max_memory {
+                    memory += changes
+                        .get_contents_len(&hash)
+                        .map_err(ApplyError::Changestore)?;
+                    if memory > max {
+                        return Err(ApplyError::ResourceExceeded {
+                            resource: Resource::Memory,
+                            limit: max,
+                        });
+                    }
+                }
+                let change = changes.get_change(&hash).map_err(ApplyError::Changestore)?;
+                check_timestamp(changes, &change, &options.timestamps, chrono::Utc::now())
+                    .map_err(|e| match e {
+                        CheckTimestampError::Changestore(e) => ApplyError::Changestore(e),
+                        CheckTimestampError::Violation(reason) => {
+                            ApplyError::InvalidTimestamp { hash, reason }
+                        }
+                    })?;
+                crate::header::check(&options.headers, &change.header)
+                    .map_err(|err| ApplyError::InvalidHeader { hash, err })?;
+                if options.skip_empty {
+                    // Dependencies are applied before their dependents.
+                    if change.dependencies.iter().any(|d| skipped.contains(d)) {
+                        debug!("skipping {:?}, depending on a skipped change", hash);
+                        skipped.insert(hash);
+                        continue;
+                    }
+                    if is_semantically_empty(&*txn, txn.graph(&*channel), &change)? {
+                        debug!("skipping empty change {:?}", hash);
+                        skipped.insert(hash);
+                        continue;
+                    }
+                }
                 let internal = if let Some(&p) = txn.get_internal(&shash)? {
                     p
                 } else {
@@ -168,6 +372,20 @@ pub fn apply_change_rec_ws<T: TxnT + MutTxnT, P: ChangeStore>(
                     internal
                 };
                 debug!("internal = {:?}", internal);
+                n_applied += 1;
+                if let Some(max) = options.max_pending_changes {
+                    if n_applied > max {
+                        return Err(ApplyError::ResourceExceeded {
+                            resource: Resource::PendingChanges,
+                            limit: max as u64,
+                        });
+                    }
+                }
+                if let Some(pause) = options.io_throttle {
+                    if n_applied > 1 {
+                        std::thread::sleep(pause)
+                    }
+                }
                 workspace.clear();
                 apply_change_to_channel(txn, channel, internal, &hash, &change, workspace)?;
             }
@@ -186,6 +404,24 @@ pub fn apply_change<T: MutTxnT, P: ChangeStore>(
     apply_change_ws(changes, txn, channel, hash, &mut Workspace::new())
 }
 
+/// Same as [apply_change], but also reports on the files affected by
+/// the change, and on whether it was semantically empty.
+pub fn apply_change_report<T: MutTxnT, P: ChangeStore>(
+    changes: &P,
+    txn: &mut T,
+    channel: &mut T::Channel,
+    hash: &Hash,
+) -> Result<(u64, Merkle, ApplyReport), ApplyError<P::Error, T::GraphError>> {
+    let change = changes
+        .get_change_without_contents(hash)
+        .map_err(ApplyError::Changestore)?;
+    let empty = is_semantically_empty(&*txn, txn.graph(&*channel), &change)?;
+    let (n, merkle) = apply_change(changes, txn, channel, hash)?;
+    let mut report = apply_report(changes, txn, channel, hash)?;
+    report.empty = empty;
+    Ok((n, merkle, report))
+}
+
 /// Same as [apply_change], but with a wrapped `txn` and `channel`.
 pub fn apply_change_arc<T: MutTxnT, P: ChangeStore>(
     changes: &P,
@@ -231,6 +467,7 @@ fn apply_change_to_channel<T: ChannelMutTxnT>(
     ws.assert_empty();
     let n = txn.apply_counter(channel);
     debug!("apply_change_to_channel {:?} {:?}", change_id, hash);
+    crate::hunk_kind::apply_hunks(txn, channel, change, false)?;
     let merkle =
         if let Some(m) = txn.put_changes(channel, change_id, txn.apply_counter(channel), hash)? {
             m
@@ -389,6 +626,7 @@ fn update_inode<T: ChannelTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::Graph
             {
                 debug!("Adding inodes: {:?} {:?}", inode, vertex);
                 put_inodes_with_rev(txn, &inode, &vertex)?;
+                crate::fs::settle_inode(txn, inode, vertex)?;
             } else {
                 debug!("Not adding inodes: {:?} {:?}", inode, vertex);
             }
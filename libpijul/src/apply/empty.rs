@@ -0,0 +1,86 @@
+//! Changes that would not modify a channel.
+//!
+//! A change is semantically empty on a channel if it introduces no
+//! vertex, and the vertices whose status it changes already have the
+//! status it gives them: for example, a change deleting lines already
+//! deleted by another change, as happens when a change is recorded
+//! again after a rewrite of the history and pushed a second time.
+//! Such changes can be skipped with [`ApplyOptions::skip_empty`] to
+//! keep them out of the log of the channel.
+//!
+//! Vertices with deleted parent edges are never considered to be in
+//! their final status, since applying an edge to them may resurrect
+//! them as zombies, or mark them as conflicting.
+use super::LocalApplyError;
+use crate::change::{Atom, Change, Hunk};
+use crate::pristine::*;
+
+/// Whether applying `change` to `graph` would leave it unchanged, as
+/// far as the alive vertices are concerned.
+pub fn is_semantically_empty<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    change: &Change,
+) -> Result<bool, LocalApplyError<T::GraphError>> {
+    for hunk in change.changes.iter() {
+        if let Hunk::Custom { .. } = hunk {
+            return Ok(false);
+        }
+        for atom in hunk.iter() {
+            let edges = match atom {
+                Atom::NewVertex(_) => return Ok(false),
+                Atom::EdgeMap(e) => &e.edges,
+            };
+            for edge in edges.iter() {
+                let change = if let Some(h) = edge.to.change {
+                    if let Some(&c) = txn.get_internal(&h.into())? {
+                        c
+                    } else {
+                        return Ok(false);
+                    }
+                } else {
+                    return Ok(false);
+                };
+                let deleted = edge.flag.contains(EdgeFlags::DELETED);
+                if edge.to.start == edge.to.end {
+                    let v = Vertex {
+                        change,
+                        start: edge.to.start,
+                        end: edge.to.end,
+                    };
+                    if !has_status(txn, graph, &v, deleted)? {
+                        return Ok(false);
+                    }
+                    continue;
+                }
+                // The vertex may have been split in the graph.
+                let mut pos = edge.to.start;
+                while pos < edge.to.end {
+                    let v = *txn.find_block(graph, Position { change, pos })?;
+                    if !has_status(txn, graph, &v, deleted)? {
+                        return Ok(false);
+                    }
+                    pos = v.end;
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Whether `v` is already deleted (if `deleted` is `true`) or alive
+/// (if `deleted` is `false`), without any deleted parent edge if it
+/// is alive, so that an edge with that status wouldn't resurrect it
+/// as a zombie.
+fn has_status<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    v: &Vertex<ChangeId>,
+    deleted: bool,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    if is_alive(txn, graph, v)? {
+        Ok(!deleted && iter_deleted_parents(txn, graph, *v)?.next().is_none())
+    } else {
+        Ok(deleted)
+    }
+}
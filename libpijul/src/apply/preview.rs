@@ -0,0 +1,248 @@
+//! Previews of what applying a change would do to a working copy,
+//! before applying it.
+use super::report::{edited_file, known_position};
+use crate::change::{Atom, Change, Hunk, Local};
+use crate::changestore::ChangeStore;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use crate::HashMap;
+
+/// The files of a working copy that applying a change, and then
+/// outputting the channel, would touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkingCopyPreview {
+    /// The touched files, sorted by path.
+    pub files: Vec<PreviewFile>,
+}
+
+impl WorkingCopyPreview {
+    /// The touched files that also have unrecorded changes in the
+    /// working copy.
+    pub fn conflicts(&self) -> impl Iterator<Item = &PreviewFile> {
+        self.files.iter().filter(|f| f.locally_modified)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewFile {
+    /// The path of the file in the working copy, or, for the files
+    /// added by the change, the path recorded in the change.
+    pub path: String,
+    pub action: PreviewAction,
+    /// Whether the working copy differs from the channel on this file:
+    /// the file was edited or deleted without recording, or, for a
+    /// file added by the change, an untracked file is in its way.
+    pub locally_modified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PreviewAction {
+    Add,
+    Edit,
+    Move,
+    Delete,
+}
+
+#[derive(Debug, Error)]
+pub enum PreviewError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + 'static,
+> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    File(#[from] FileError<C, T>),
+    #[error("Working copy error: {0}")]
+    WorkingCopy(W),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static, W: std::error::Error>
+    From<TxnErr<T>> for PreviewError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        PreviewError::Txn(e.0)
+    }
+}
+
+/// The file deleted, undeleted, moved or whose permissions are
+/// changed by `hunk`.
+fn moved_file(hunk: &Hunk<Option<Hash>, Local>) -> Option<(Position<Option<Hash>>, PreviewAction)> {
+    match hunk {
+        Hunk::FileDel { del, .. } => Some((del.inode(), PreviewAction::Delete)),
+        Hunk::FileUndel { undel, .. } => Some((undel.inode(), PreviewAction::Add)),
+        Hunk::FileMove {
+            add: Atom::NewVertex(ref add),
+            ..
+        } => Some((*add.down_context.first()?, PreviewAction::Move)),
+        Hunk::FileMeta {
+            add: Atom::NewVertex(ref add),
+            ..
+        } => Some((*add.down_context.first()?, PreviewAction::Edit)),
+        _ => None,
+    }
+}
+
+/// Preview the files of `working_copy` that applying `change` to
+/// `channel`, and then outputting `channel` to `working_copy`, would
+/// touch, without applying anything.
+///
+/// Touched files that differ from their version on `channel` are
+/// flagged as locally modified: their unrecorded edits could be
+/// overwritten, or conflict with the change. This is decided per file,
+/// even if the change and the local edits are on different lines.
+///
+/// Hunks on files unknown to `channel`, for instance because a
+/// dependency of `change` isn't applied yet, are ignored.
+pub fn preview_working_copy<T, P, W>(
+    changes: &P,
+    txn: &T,
+    channel: &T::Channel,
+    change: &Change,
+    working_copy: &W,
+) -> Result<WorkingCopyPreview, PreviewError<P::Error, T::GraphError, W::Error>>
+where
+    T: ChannelTxnT + TreeTxnT,
+    P: ChangeStore,
+    W: WorkingCopy,
+{
+    // Positions introduced by `change` itself aren't in the pristine:
+    // any change id works to translate the others.
+    let none = ChangeId::ROOT;
+    let mut touched: HashMap<Position<ChangeId>, PreviewAction> = HashMap::default();
+    let mut added = Vec::new();
+    for hunk in change.changes.iter() {
+        let (inode, action) = if let Some(inode) = edited_file(hunk) {
+            (inode, PreviewAction::Edit)
+        } else if let Some(m) = moved_file(hunk) {
+            m
+        } else {
+            if let Hunk::FileAdd { path, .. }
+            | Hunk::FileCopy { path, .. }
+            | Hunk::SubrepoPointer { path, .. } = hunk
+            {
+                added.push(path.clone())
+            }
+            continue;
+        };
+        if inode.change.is_none() {
+            // Added by `change`, already listed as an addition.
+            continue;
+        }
+        let inode = if let Some(inode) = known_position(txn, &inode, none)? {
+            inode
+        } else {
+            continue;
+        };
+        let a = touched.entry(inode).or_insert(action);
+        // Deletions and undeletions win over moves, moves over edits.
+        *a = (*a).max(action)
+    }
+
+    let mut preview = WorkingCopyPreview::default();
+    for (inode, action) in touched {
+        let path =
+            if let Some((path, _)) = crate::fs::find_path(changes, txn, channel, true, inode)? {
+                path
+            } else if action == PreviewAction::Add {
+                // An undeleted file doesn't have a path on the channel:
+                // take the one recorded in the change.
+                if let Some(path) = undeleted_path(change, inode, txn)? {
+                    path
+                } else {
+                    continue;
+                }
+            } else {
+                continue;
+            };
+        let locally_modified = match action {
+            PreviewAction::Add => working_copy.file_metadata(&path).is_ok(),
+            // Deleting a file already deleted locally is harmless.
+            PreviewAction::Delete if working_copy.file_metadata(&path).is_err() => false,
+            _ => locally_modified(changes, txn, channel, working_copy, inode, &path)?,
+        };
+        preview.files.push(PreviewFile {
+            path,
+            action,
+            locally_modified,
+        })
+    }
+    for path in added {
+        let locally_modified = working_copy.file_metadata(&path).is_ok();
+        preview.files.push(PreviewFile {
+            path,
+            action: PreviewAction::Add,
+            locally_modified,
+        })
+    }
+    preview
+        .files
+        .sort_by(|a, b| (&a.path, a.action).cmp(&(&b.path, b.action)));
+    Ok(preview)
+}
+
+/// The path at which `change` undeletes `inode`.
+fn undeleted_path<T: GraphTxnT>(
+    change: &Change,
+    inode: Position<ChangeId>,
+    txn: &T,
+) -> Result<Option<String>, TxnErr<T::GraphError>> {
+    for hunk in change.changes.iter() {
+        if let Hunk::FileUndel { undel, path, .. } = hunk {
+            if known_position(txn, &undel.inode(), ChangeId::ROOT)? == Some(inode) {
+                return Ok(Some(path.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the file at `path` in `working_copy` differs from `inode`
+/// on `channel`. Directories are never modified, missing files always
+/// are.
+fn locally_modified<T, P, W>(
+    changes: &P,
+    txn: &T,
+    channel: &T::Channel,
+    working_copy: &W,
+    inode: Position<ChangeId>,
+    path: &str,
+) -> Result<bool, PreviewError<P::Error, T::GraphError, W::Error>>
+where
+    T: ChannelTxnT + TreeTxnT,
+    P: ChangeStore,
+    W: WorkingCopy,
+{
+    match working_copy.file_metadata(path) {
+        Ok(meta) if meta.is_dir() => return Ok(false),
+        Ok(_) => {}
+        Err(_) => return Ok(true),
+    }
+    let mut local = Vec::new();
+    working_copy
+        .read_file(path, &mut local)
+        .map_err(PreviewError::WorkingCopy)?;
+    // Conflicts are rendered like in the working copy.
+    let options = working_copy.conflict_options();
+    let mut graph = crate::alive::retrieve::retrieve_limited(
+        txn,
+        txn.graph(channel),
+        inode,
+        working_copy.graph_limits(),
+    )
+    .map_err(FileError::from)?;
+    let mut conflicts = Vec::new();
+    let mut pristine = crate::vertex_buffer::ConflictsWriter::new(Vec::new(), path, &mut conflicts);
+    pristine.ids = options.ids;
+    crate::alive::output_graph_ordered(
+        changes,
+        txn,
+        channel,
+        &mut pristine,
+        &mut graph,
+        &mut Vec::new(),
+        options.order,
+    )?;
+    Ok(local != pristine.w)
+}
@@ -0,0 +1,247 @@
+//! Reports on what applying a change did to the files of a channel,
+//! in terms that make sense to users rather than in terms of edges.
+use super::{ApplyError, LocalApplyError};
+use crate::change::{Atom, Hunk, Local};
+use crate::changestore::ChangeStore;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::HashSet;
+
+/// What happened to the files of a channel when applying a change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    pub rename_edit_conflicts: Vec<RenameEditConflict>,
+    /// Whether the change didn't modify the channel, see
+    /// [`super::is_semantically_empty`]. Only set by
+    /// [`super::apply_change_report`], since this must be checked
+    /// before applying the change.
+    pub empty: bool,
+}
+
+/// A file edited under its old name by a change, and renamed by
+/// another change that the first one doesn't know about. The two
+/// changes commute, and the edit ends up in the renamed file, which
+/// may surprise the author of the edit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameEditConflict {
+    /// The name under which the file was edited.
+    pub edited_path: String,
+    /// The name of the file on the channel.
+    pub current_path: String,
+    /// The change editing the file.
+    pub edit: Hash,
+    /// The change renaming the file.
+    pub rename: Hash,
+}
+
+/// The file edited by `hunk`, if `hunk` edits the contents of a file.
+pub(super) fn edited_file(hunk: &Hunk<Option<Hash>, Local>) -> Option<Position<Option<Hash>>> {
+    let atom: &Atom<Option<Hash>> = match hunk {
+        Hunk::Edit { change, .. }
+        | Hunk::Replacement { change, .. }
+        | Hunk::SolveOrderConflict { change, .. }
+        | Hunk::UnsolveOrderConflict { change, .. }
+        | Hunk::ResurrectZombies { change, .. } => change,
+        Hunk::BinaryEdit { delete, insert, .. } => delete.as_ref().or(insert.as_ref())?,
+        _ => return None,
+    };
+    Some(atom.inode())
+}
+
+/// The internal version of `pos`, or `None` if its change isn't in
+/// the pristine.
+pub(super) fn known_position<T: GraphTxnT>(
+    txn: &T,
+    pos: &Position<Option<Hash>>,
+    change_id: ChangeId,
+) -> Result<Option<Position<ChangeId>>, TxnErr<T::GraphError>> {
+    let change = if let Some(h) = pos.change {
+        if let Some(&c) = txn.get_internal(&h.into())? {
+            c
+        } else {
+            return Ok(None);
+        }
+    } else {
+        change_id
+    };
+    Ok(Some(Position {
+        change,
+        pos: pos.pos,
+    }))
+}
+
+/// Does `a` depend on `b`, directly or not?
+fn depends_on<T: DepsTxnT>(
+    txn: &T,
+    a: ChangeId,
+    b: ChangeId,
+) -> Result<bool, TxnErr<T::DepsError>> {
+    let mut stack = vec![a];
+    let mut visited = HashSet::default();
+    while let Some(a) = stack.pop() {
+        if a == b {
+            return Ok(true);
+        }
+        if !visited.insert(a) {
+            continue;
+        }
+        for x in txn.iter_dep(&a)? {
+            let (a_, dep) = x?;
+            if *a_ < a {
+                continue;
+            } else if *a_ > a {
+                break;
+            }
+            stack.push(*dep)
+        }
+    }
+    Ok(false)
+}
+
+/// The changes that introduced the alive names of `inode`.
+fn name_changes<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    inode: Position<ChangeId>,
+) -> Result<Vec<ChangeId>, TxnErr<T::GraphError>> {
+    let mut result = Vec::new();
+    for e in iter_adjacent(
+        txn,
+        graph,
+        inode.inode_vertex(),
+        EdgeFlags::FOLDER | EdgeFlags::PARENT,
+        EdgeFlags::all(),
+    )? {
+        let e = e?;
+        if e.flag().contains(EdgeFlags::PARENT)
+            && !e.flag().intersects(EdgeFlags::DELETED | EdgeFlags::PSEUDO)
+        {
+            result.push(e.dest().change)
+        }
+    }
+    Ok(result)
+}
+
+fn current_path<T: ChannelTxnT, P: ChangeStore>(
+    changes: &P,
+    txn: &T,
+    channel: &T::Channel,
+    inode: Position<ChangeId>,
+) -> Result<Option<String>, ApplyError<P::Error, T::GraphError>> {
+    match crate::fs::find_path(changes, txn, channel, true, inode) {
+        Ok(p) => Ok(p.map(|(p, _)| p)),
+        Err(FileError::Changestore(e)) => Err(ApplyError::Changestore(e)),
+        Err(FileError::Txn(e)) => Err(LocalApplyError::Txn(e).into()),
+        Err(FileError::Io(_)) | Err(FileError::GraphTooLarge(_)) => Ok(None),
+    }
+}
+
+/// Report on the application of change `hash`, which must already be
+/// on `channel`.
+pub fn apply_report<
+    T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+>(
+    changes: &P,
+    txn: &T,
+    channel: &T::Channel,
+    hash: &Hash,
+) -> Result<ApplyReport, ApplyError<P::Error, T::GraphError>> {
+    let mut report = ApplyReport::default();
+    let change_id = if let Some(&c) = txn.get_internal(&hash.into())? {
+        c
+    } else {
+        return Ok(report);
+    };
+    let change = changes.get_change(hash).map_err(ApplyError::Changestore)?;
+    let mut seen = HashSet::default();
+    for hunk in change.changes.iter() {
+        // This change edits a file renamed by a change it doesn't
+        // know about.
+        if let Some(inode) = edited_file(hunk) {
+            if inode.change.is_none() {
+                // The file was added by this change.
+                continue;
+            }
+            let inode = if let Some(inode) = known_position(txn, &inode, change_id)? {
+                inode
+            } else {
+                continue;
+            };
+            for rename in name_changes(txn, txn.graph(channel), inode)? {
+                if rename == change_id
+                    || depends_on(txn, change_id, rename)?
+                    || !seen.insert((change_id, rename, inode))
+                {
+                    continue;
+                }
+                if let Some(current_path) = current_path(changes, txn, channel, inode)? {
+                    report.rename_edit_conflicts.push(RenameEditConflict {
+                        edited_path: hunk.path().to_string(),
+                        current_path,
+                        edit: *hash,
+                        rename: txn.get_external(&rename)?.unwrap().into(),
+                    })
+                }
+            }
+        }
+        // This change renames a file edited by changes it doesn't
+        // know about.
+        if let Hunk::FileMove {
+            add: Atom::NewVertex(ref add),
+            ..
+        } = hunk
+        {
+            for inode in add.down_context.iter() {
+                let inode = if let Some(inode) = known_position(txn, inode, change_id)? {
+                    inode
+                } else {
+                    continue;
+                };
+                let mut edits = Vec::new();
+                for x in txn.iter_touched(&inode)? {
+                    let (inode_, edit) = x?;
+                    if *inode_ < inode {
+                        continue;
+                    } else if *inode_ > inode {
+                        break;
+                    }
+                    edits.push(*edit)
+                }
+                for edit in edits {
+                    if edit == change_id
+                        || txn.get_changeset(txn.changes(channel), &edit)?.is_none()
+                        || depends_on(txn, change_id, edit)?
+                        || depends_on(txn, edit, change_id)?
+                        || seen.contains(&(edit, change_id, inode))
+                    {
+                        continue;
+                    }
+                    let edit_hash: Hash = txn.get_external(&edit)?.unwrap().into();
+                    let hunks = changes
+                        .get_changes(&edit_hash)
+                        .map_err(ApplyError::Changestore)?;
+                    for h in hunks.iter() {
+                        if let Some(i) = edited_file(h) {
+                            if known_position(txn, &i, edit)? != Some(inode) {
+                                continue;
+                            }
+                            seen.insert((edit, change_id, inode));
+                            if let Some(current_path) = current_path(changes, txn, channel, inode)?
+                            {
+                                report.rename_edit_conflicts.push(RenameEditConflict {
+                                    edited_path: h.path().to_string(),
+                                    current_path,
+                                    edit: edit_hash,
+                                    rename: *hash,
+                                })
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,87 @@
+//! Per-path recording policies, read from the attributes file.
+//!
+//! The `text` attribute of the [`ATTRIBUTES_FILE`] sets the
+//! [`TextPolicy`] of the files matched by a pattern, for instance
+//!
+//! ```text
+//! *.min.js text=hybrid
+//! dumps/ text=hybrid:1024
+//! ```
+//!
+//! so that minified sources or text dumps with a few NUL bytes are
+//! diffed as text, without changing how the other files are recorded.
+//! Since the attributes file is tracked like any other file, all
+//! clients recording the same tree use the same policies.
+use crate::channel_settings::TextPolicy;
+pub use crate::opaque::ATTRIBUTES_FILE;
+use crate::record::Glob;
+use crate::working_copy::WorkingCopy;
+
+/// The attribute setting the text policy, as in `text=hybrid`.
+pub const TEXT_ATTRIBUTE: &str = "text";
+
+/// The patterns of the `text` attributes.
+#[derive(Debug, Clone, Default)]
+pub struct TextRules(Vec<(Glob, TextPolicy)>);
+
+impl TextRules {
+    /// Read the attributes file of `working_copy`, if it has one.
+    pub fn load<W: WorkingCopy>(working_copy: &W) -> Result<Self, W::Error> {
+        let mut rules = TextRules::default();
+        if working_copy.file_metadata(ATTRIBUTES_FILE).is_ok() {
+            let mut buf = Vec::new();
+            working_copy.read_file(ATTRIBUTES_FILE, &mut buf)?;
+            rules.parse(&String::from_utf8_lossy(&buf));
+        }
+        Ok(rules)
+    }
+
+    /// Parse the contents of an attributes file, appending its rules
+    /// to these rules. Each line is a pattern followed by attributes
+    /// separated by whitespace, where `text=P` sets the policy of the
+    /// files matched by the pattern to `P`, parsed as a
+    /// [`TextPolicy`] (`lines`, `hybrid` or `hybrid:N`). Malformed
+    /// policies and other attributes are ignored, and patterns are
+    /// interpreted as in [`crate::opaque::OpaqueRules::parse`].
+    pub fn parse(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(p) if !p.starts_with('#') => p,
+                _ => continue,
+            };
+            let policy = words.fold(None, |policy, attr| match attr.split_once('=') {
+                Some((TEXT_ATTRIBUTE, p)) => p.parse().ok().or(policy),
+                _ => policy,
+            });
+            let policy = if let Some(policy) = policy {
+                policy
+            } else {
+                continue;
+            };
+            let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+            let glob = if pattern.contains('/') {
+                Glob::new(pattern)
+            } else {
+                Glob::new(&format!("**/{}", pattern))
+            };
+            self.0.push((glob, policy))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The policy of `path`, set by the last matching rule on `path`
+    /// or one of its parent directories, or `None` if no rule
+    /// matches.
+    pub fn policy(&self, path: &str) -> Option<TextPolicy> {
+        let path: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.0
+            .iter()
+            .rev()
+            .find(|(glob, _)| (1..=path.len()).any(|n| glob.matches(&path[..n], false)))
+            .map(|(_, policy)| *policy)
+    }
+}
@@ -0,0 +1,129 @@
+//! An append-only log of the operations performed on a repository,
+//! stored in the pristine.
+//!
+//! Entries are never modified or deleted by libpijul, and are
+//! numbered consecutively from 0.
+use crate::pristine::*;
+
+/// An operation recorded in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOperation {
+    Record { hash: Hash },
+    Apply { hash: Hash },
+    Unrecord { hash: Hash },
+    ForkChannel { from: String },
+    RenameChannel { from: String },
+    DropChannel,
+    SoftDeleteChannel,
+    UndeleteChannel,
+}
+
+/// An entry of the audit log: who did what, when, and the state of
+/// the channel after the operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub identity: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub operation: AuditOperation,
+    pub channel: String,
+    pub state: Merkle,
+}
+
+#[derive(Debug, Error)]
+pub enum AuditError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed audit log entry {0}")]
+    Malformed(u64),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for AuditError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        AuditError::Txn(e.0)
+    }
+}
+
+/// Append an entry for `operation` on `channel`, performed by
+/// `identity`, now. The state recorded is the current state of
+/// `channel`, or the zero state if the channel doesn't exist (for
+/// instance after it was dropped).
+pub fn append<T: MutTxnT>(
+    txn: &mut T,
+    identity: &str,
+    operation: AuditOperation,
+    channel: &str,
+) -> Result<u64, AuditError<T::GraphError>> {
+    let state = if let Some(c) = txn.load_channel(channel)? {
+        current_state(&*txn, &*c.read())?
+    } else {
+        Merkle::zero()
+    };
+    append_state(txn, identity, operation, channel, state)
+}
+
+/// Like [`append`], but recording `state` as the state of `channel`
+/// after the operation, for instance when several operations are
+/// performed before the entries are appended.
+pub fn append_state<T: MutTxnT>(
+    txn: &mut T,
+    identity: &str,
+    operation: AuditOperation,
+    channel: &str,
+    state: Merkle,
+) -> Result<u64, AuditError<T::GraphError>> {
+    let entry = AuditEntry {
+        identity: identity.to_string(),
+        timestamp: chrono::Utc::now(),
+        operation,
+        channel: channel.to_string(),
+        state,
+    };
+    let bytes = bincode::serialize(&entry).unwrap();
+    Ok(txn.put_audit(&bytes)?)
+}
+
+/// Iterator over the entries of the audit log.
+pub struct AuditLog<'txn, T: TxnT> {
+    txn: &'txn T,
+    n: u64,
+}
+
+/// Iterate over the audit log, starting from entry `from`.
+pub fn iter<T: TxnT>(txn: &T, from: u64) -> AuditLog<T> {
+    AuditLog { txn, n: from }
+}
+
+impl<'txn, T: TxnT> Iterator for AuditLog<'txn, T> {
+    type Item = Result<(u64, AuditEntry), AuditError<T::GraphError>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.n;
+        match self.txn.get_audit(n) {
+            Ok(Some(bytes)) => {
+                self.n += 1;
+                match bincode::deserialize(bytes) {
+                    Ok(e) => Some(Ok((n, e))),
+                    Err(_) => Some(Err(AuditError::Malformed(n))),
+                }
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Write the audit log to `w` as JSON lines, one entry per line.
+pub fn export_json<T: TxnT, W: std::io::Write>(
+    txn: &T,
+    mut w: W,
+) -> Result<(), AuditError<T::GraphError>> {
+    for e in iter(txn, 0) {
+        let (_, e) = e?;
+        serde_json::to_writer(&mut w, &e)?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
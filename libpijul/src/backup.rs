@@ -0,0 +1,171 @@
+//! Online backups of a repository.
+//!
+//! A snapshot is taken while the caller holds a read transaction on
+//! the pristine, so other readers and writers aren't blocked. The
+//! database file is copied again if it was modified during the copy,
+//! so that the copy is exactly a committed state. The changes listed
+//! are those of the read transaction, which are all in that state
+//! or an earlier one. Change files are immutable once written, and
+//! are hard-linked into the backup when possible.
+use crate::changestore::filesystem::push_filename;
+use crate::pristine::*;
+use crate::HashSet;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST: &str = "backup.json";
+
+/// A file of the backup, with its size and Blake3 hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub path: String,
+    pub len: u64,
+    pub blake3: String,
+}
+
+/// The list of files in a backup, used to verify it before restoring.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Change file missing: {0:?}")]
+    MissingChange(PathBuf),
+    #[error("The pristine kept changing while it was being copied")]
+    Busy,
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for BackupError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        BackupError::Txn(e.0)
+    }
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String), std::io::Error> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let len = std::io::copy(&mut f, &mut hasher)?;
+    Ok((len, hasher.finalize().to_hex().to_string()))
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if let Some(p) = to.parent() {
+        std::fs::create_dir_all(p)?
+    }
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Number of attempts at copying the database file while it is
+/// being written to.
+const COPY_ATTEMPTS: usize = 16;
+
+/// The modification time and length of `path`, which change with
+/// every commit.
+fn version(path: &Path) -> Result<(std::time::SystemTime, u64), std::io::Error> {
+    let meta = std::fs::metadata(path)?;
+    Ok((meta.modified()?, meta.len()))
+}
+
+/// Copy `from` to `to`, until no commit happens during the copy.
+fn copy_stable<T: std::error::Error + 'static>(
+    from: &Path,
+    to: &Path,
+) -> Result<(), BackupError<T>> {
+    for _ in 0..COPY_ATTEMPTS {
+        let before = version(from)?;
+        std::fs::copy(from, to)?;
+        if version(from)? == before {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10))
+    }
+    Err(BackupError::Busy)
+}
+
+/// Copy the pristine at `pristine` (the database file) and the
+/// files of all the changes on the channels of `txn` from
+/// `changes_dir`, into `dest`, which mirrors the layout of a `.pijul`
+/// directory. `txn` must be a transaction on that pristine, started
+/// before calling this function.
+pub fn snapshot<T: TxnT>(
+    txn: &T,
+    pristine: &Path,
+    changes_dir: &Path,
+    dest: &Path,
+) -> Result<BackupManifest, BackupError<T::GraphError>> {
+    let mut files = Vec::new();
+
+    let db = dest.join("pristine").join("db");
+    std::fs::create_dir_all(db.parent().unwrap())?;
+    copy_stable(pristine, &db)?;
+    let (len, blake3) = hash_file(&db)?;
+    files.push(BackupFile {
+        path: "pristine/db".to_string(),
+        len,
+        blake3,
+    });
+
+    let mut hashes = HashSet::default();
+    for c in txn.iter_channels("")? {
+        let (_, c) = c?;
+        let c = c.read();
+        for x in changeid_log(txn, &*c, L64(0))? {
+            let (_, p) = x?;
+            let h: Hash = txn.get_external(&p.a)?.unwrap().into();
+            hashes.insert(h);
+        }
+    }
+    let mut hashes: Vec<_> = hashes.into_iter().collect();
+    hashes.sort();
+    for h in hashes {
+        let mut from = changes_dir.to_path_buf();
+        push_filename(&mut from, &h);
+        let mut to = dest.join("changes");
+        push_filename(&mut to, &h);
+        if std::fs::metadata(&from).is_err() {
+            return Err(BackupError::MissingChange(from));
+        }
+        link_or_copy(&from, &to)?;
+        let (len, blake3) = hash_file(&to)?;
+        let path = to.strip_prefix(dest).unwrap();
+        files.push(BackupFile {
+            path: path.to_str().unwrap().replace('\\', "/"),
+            len,
+            blake3,
+        })
+    }
+
+    let manifest = BackupManifest {
+        timestamp: chrono::Utc::now(),
+        files,
+    };
+    let f = std::fs::File::create(dest.join(MANIFEST))?;
+    serde_json::to_writer_pretty(f, &manifest)?;
+    Ok(manifest)
+}
+
+/// Check the backup in `dest` against its manifest, returning the
+/// paths of the files that are missing or corrupt.
+pub fn verify(dest: &Path) -> Result<Vec<String>, BackupError<std::io::Error>> {
+    let manifest: BackupManifest =
+        serde_json::from_reader(std::fs::File::open(dest.join(MANIFEST))?)?;
+    let mut broken = Vec::new();
+    for f in manifest.files.iter() {
+        match hash_file(&dest.join(&f.path)) {
+            Ok((len, blake3)) if len == f.len && blake3 == f.blake3 => {}
+            _ => broken.push(f.path.clone()),
+        }
+    }
+    Ok(broken)
+}
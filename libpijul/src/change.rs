@@ -1,9 +1,9 @@
-use crate::HashSet;
+use crate::{HashMap, HashSet};
 use std::collections::BTreeSet;
 
 use crate::pristine::*;
 use crate::text_encoding::Encoding;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, Utc};
 
 #[cfg(feature = "zstd")]
 use std::io::Write;
@@ -15,8 +15,22 @@ pub use text_changes::{TextDeError, TextSerError, WriteChangeLine};
 mod change_file;
 pub use change_file::*;
 
+mod explain;
+pub use explain::*;
+
+mod moves;
+pub use moves::*;
+
 mod noenc;
 
+mod touched;
+pub use touched::*;
+
+#[cfg(feature = "zstd")]
+mod stream;
+#[cfg(feature = "zstd")]
+pub use stream::*;
+
 #[derive(Debug, Error)]
 pub enum ChangeError {
     #[error("Version mismatch: got {}", got)]
@@ -49,6 +63,8 @@ pub enum ChangeError {
         claimed: crate::pristine::Hash,
         computed: crate::pristine::Hash,
     },
+    #[error("Path index of change {:?} doesn't match its hunks", hash)]
+    PathIndexMismatch { hash: crate::pristine::Hash },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -122,12 +138,27 @@ impl Default for ChangeHeader {
         ChangeHeader {
             message: String::new(),
             description: None,
-            timestamp: Utc::now(),
+            timestamp: chrono::Utc::now(),
             authors: Vec::new(),
         }
     }
 }
 
+/// Aggregate metrics about a change. These are computed from the
+/// hunks alone, and written to the unhashed part of change files, so
+/// that they can be read without the contents of the change.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeMetrics {
+    /// Number of hunks.
+    pub hunks: u64,
+    /// Number of bytes added to files (names excluded).
+    pub added_bytes: u64,
+    /// Number of bytes deleted from files (names excluded).
+    pub deleted_bytes: u64,
+    /// Largest number of bytes added or deleted in a single file.
+    pub largest_file: u64,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct LocalChange<Hunk, Author> {
     pub offsets: Offsets,
@@ -158,6 +189,14 @@ pub struct Author(pub std::collections::BTreeMap<String, String>);
 pub const VERSION: u64 = 6;
 pub const VERSION_NOENC: u64 = 4;
 
+/// Key of the metrics in the unhashed part of change files.
+pub const METRICS_KEY: &str = "metrics";
+
+/// Key of the timezone offset of the author, in seconds east of UTC,
+/// in the unhashed part of change files. Timestamps in the header are
+/// in UTC, the offset is only used to display them.
+pub const TIMEZONE_KEY: &str = "timezone";
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Hashed<Hunk, Author> {
     /// Version, again (in order to hash it).
@@ -191,6 +230,20 @@ pub fn dependencies<
 ) -> Result<(Vec<Hash>, Vec<Hash>), TxnErr<T::DepsError>> {
     let mut deps = BTreeSet::new();
     let mut zombie_deps = BTreeSet::new();
+    let mut copy_sources = Vec::new();
+    let changes = changes.inspect(|h| {
+        if let Hunk::FileCopy {
+            source_inode:
+                Some(Position {
+                    change: Some(source),
+                    ..
+                }),
+            ..
+        } = h
+        {
+            copy_sources.push(*source)
+        }
+    });
     for ch in changes.flat_map(|r| r.iter()) {
         match *ch {
             Atom::NewVertex(NewVertex {
@@ -226,6 +279,11 @@ pub fn dependencies<
             }
         }
     }
+    for source in copy_sources {
+        if source != Hash::None {
+            deps.insert(source);
+        }
+    }
     let deps = minimize_deps(txn, &channel, &deps)?;
     for d in deps.iter() {
         zombie_deps.remove(d);
@@ -546,13 +604,20 @@ impl EdgeMap<Option<Hash>> {
 }
 
 impl<L: Clone> Hunk<Option<Hash>, L> {
-    pub fn inverse(&self, hash: &Hash) -> Self {
-        match self {
+    /// The inverse of this hunk. Fails if this is a custom hunk whose
+    /// kind isn't registered, or can't invert its payload.
+    pub fn inverse(&self, hash: &Hash) -> Result<Self, crate::hunk_kind::HunkKindError> {
+        Ok(match self {
             Hunk::FileMove { del, add, path } => Hunk::FileMove {
                 del: add.inverse(hash),
                 add: del.inverse(hash),
                 path: path.clone(),
             },
+            Hunk::FileMeta { del, add, path } => Hunk::FileMeta {
+                del: add.inverse(hash),
+                add: del.inverse(hash),
+                path: path.clone(),
+            },
             Hunk::FileDel {
                 del,
                 contents,
@@ -581,6 +646,22 @@ impl<L: Clone> Hunk<Option<Hash>, L> {
                 contents,
                 path,
                 encoding,
+            }
+            | Hunk::FileCopy {
+                add_name,
+                add_inode,
+                contents,
+                path,
+                encoding,
+                ..
+            }
+            | Hunk::SubrepoPointer {
+                add_name,
+                add_inode,
+                contents,
+                path,
+                encoding,
+                ..
             } => {
                 let del = match (add_name.inverse(hash), add_inode.inverse(hash)) {
                     (Atom::EdgeMap(e0), Atom::EdgeMap(e1)) => Atom::EdgeMap(e0.concat(e1)),
@@ -638,15 +719,222 @@ impl<L: Clone> Hunk<Option<Hash>, L> {
                 local: local.clone(),
                 encoding: encoding.clone(),
             },
+            Hunk::BinaryEdit {
+                delete,
+                insert,
+                local,
+                offset,
+                ..
+            } => Hunk::BinaryEdit {
+                delete: insert.as_ref().map(|i| i.inverse(hash)),
+                insert: delete.as_ref().map(|d| d.inverse(hash)),
+                local: local.clone(),
+                offset: *offset,
+                delete_len: match insert {
+                    Some(Atom::NewVertex(ref n)) => (n.end.us() - n.start.us()) as u64,
+                    _ => 0,
+                },
+            },
+            Hunk::Custom {
+                kind,
+                payload,
+                local,
+            } => Hunk::Custom {
+                kind: kind.clone(),
+                payload: crate::hunk_kind::invert(kind, payload)?,
+                local: local.clone(),
+            },
+        })
+    }
+}
+
+impl<A> Hashed<Hunk<Option<Hash>, Local>, A> {
+    pub fn metrics(&self) -> ChangeMetrics {
+        let mut metrics = ChangeMetrics::default();
+        let mut files = HashMap::default();
+        for hunk in self.changes.iter() {
+            metrics.add_hunk(&mut files, hunk)
         }
+        metrics
+    }
+}
+
+/// Serialize the unhashed section of a change, adding `metrics` and
+/// `paths` unless the change has no hunks. Changes without hunks and
+/// without an unhashed section still have an empty unhashed section.
+pub(crate) fn serialize_unhashed(
+    unhashed: Option<serde_json::Value>,
+    metrics: ChangeMetrics,
+    paths: Vec<TouchedPath>,
+) -> Result<Vec<u8>, ChangeError> {
+    if metrics.hunks == 0 {
+        return if let Some(un) = unhashed {
+            Ok(serde_json::to_vec(&un)?)
+        } else {
+            Ok(Vec::new())
+        };
+    }
+    let mut un = unhashed.unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+    if let serde_json::Value::Object(ref mut un) = un {
+        un.insert(METRICS_KEY.to_string(), serde_json::to_value(metrics)?);
+        un.insert(PATHS_KEY.to_string(), serde_json::to_value(paths)?);
+    }
+    Ok(serde_json::to_vec(&un)?)
+}
+
+impl ChangeMetrics {
+    /// Add `hunk` to these metrics, `files` being the number of bytes
+    /// touched in each file by the hunks added so far.
+    pub(crate) fn add_hunk(
+        &mut self,
+        files: &mut HashMap<String, u64>,
+        hunk: &Hunk<Option<Hash>, Local>,
+    ) {
+        self.hunks += 1;
+        let mut touched = 0;
+        let mut deleted = HashSet::default();
+        for atom in hunk.iter() {
+            match atom {
+                Atom::NewVertex(n) if !n.flag.contains(EdgeFlags::FOLDER) => {
+                    let len = (n.end.us() - n.start.us()) as u64;
+                    self.added_bytes += len;
+                    touched += len;
+                }
+                Atom::EdgeMap(e) => {
+                    for e in e.edges.iter() {
+                        if e.flag.contains(EdgeFlags::DELETED)
+                            && !e.flag.contains(EdgeFlags::FOLDER)
+                            && deleted.insert(e.to)
+                        {
+                            let len = (e.to.end.us() - e.to.start.us()) as u64;
+                            self.deleted_bytes += len;
+                            touched += len;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let f = files.entry(hunk.path().to_string()).or_insert(0);
+        *f += touched;
+        self.largest_file = self.largest_file.max(*f);
     }
 }
 
 impl Change {
-    pub fn inverse(&self, hash: &Hash, header: ChangeHeader, metadata: Vec<u8>) -> Self {
+    /// The timestamp of this change, in the timezone of its author if
+    /// it was recorded, and in UTC else.
+    pub fn local_timestamp(&self) -> DateTime<FixedOffset> {
+        let offset = self
+            .unhashed
+            .as_ref()
+            .and_then(|un| un.get(TIMEZONE_KEY))
+            .and_then(|o| o.as_i64())
+            .and_then(|o| FixedOffset::east_opt(o as i32))
+            .unwrap_or_else(|| FixedOffset::east(0));
+        self.hashed.header.timestamp.with_timezone(&offset)
+    }
+
+    /// Record the timezone offset of the author of this change. This
+    /// doesn't change the hash of the change.
+    pub fn set_timezone(&mut self, offset: FixedOffset) {
+        let mut un = self
+            .unhashed
+            .take()
+            .unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+        if let serde_json::Value::Object(ref mut un) = un {
+            un.insert(TIMEZONE_KEY.to_string(), offset.local_minus_utc().into());
+        }
+        self.unhashed = Some(un)
+    }
+
+    /// Apply the byte-range hunks of this change touching `path` to
+    /// `old`, the contents of that file before the change, without
+    /// going through a pristine. Returns `None` if some hunk doesn't
+    /// fit in `old`, or if its inserted bytes aren't in this change.
+    pub fn apply_binary_edits(&self, path: &str, old: &[u8]) -> Option<Vec<u8>> {
+        let mut edits = Vec::new();
+        for hunk in self.changes.iter() {
+            if let Hunk::BinaryEdit {
+                insert,
+                local,
+                offset,
+                delete_len,
+                ..
+            } = hunk
+            {
+                if local.path != path {
+                    continue;
+                }
+                let ins = match insert {
+                    Some(Atom::NewVertex(n)) => self.contents.get(n.start.us()..n.end.us())?,
+                    Some(Atom::EdgeMap(_)) => return None,
+                    None => &[][..],
+                };
+                edits.push((*offset as usize, *delete_len as usize, ins))
+            }
+        }
+        edits.sort_by_key(|&(offset, _, _)| offset);
+        let mut result = Vec::with_capacity(old.len());
+        let mut pos = 0;
+        for (offset, delete_len, ins) in edits {
+            if offset < pos || offset + delete_len > old.len() {
+                return None;
+            }
+            result.extend_from_slice(&old[pos..offset]);
+            result.extend_from_slice(ins);
+            pos = offset + delete_len;
+        }
+        result.extend_from_slice(&old[pos..]);
+        Some(result)
+    }
+
+    /// The offsets of byte-range hunks are offsets in the file before
+    /// the change. In the inverse change, they must be offsets in the
+    /// file after this change, i.e. shifted by the bytes inserted and
+    /// deleted by the previous hunks on the same file.
+    fn shift_inverse_binary_offsets(&self, inverse: &mut [Hunk<Option<Hash>, Local>]) {
+        let mut edits: HashMap<&str, Vec<(u64, usize)>> = HashMap::default();
+        for (i, hunk) in self.changes.iter().enumerate() {
+            if let Hunk::BinaryEdit { local, offset, .. } = hunk {
+                edits.entry(&local.path).or_default().push((*offset, i))
+            }
+        }
+        for (_, mut edits) in edits {
+            edits.sort();
+            let mut shift = 0i64;
+            for (_, i) in edits {
+                if let Hunk::BinaryEdit {
+                    ref mut offset,
+                    delete_len,
+                    ..
+                } = inverse[i]
+                {
+                    let inserted = delete_len as i64;
+                    *offset = (*offset as i64 + shift) as u64;
+                    if let Hunk::BinaryEdit { delete_len, .. } = self.changes[i] {
+                        shift += inserted - delete_len as i64
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn inverse(
+        &self,
+        hash: &Hash,
+        header: ChangeHeader,
+        metadata: Vec<u8>,
+    ) -> Result<Self, crate::hunk_kind::HunkKindError> {
         let dependencies = vec![*hash];
         let contents_hash = Hasher::default().finish();
-        Change {
+        let mut changes = self
+            .changes
+            .iter()
+            .map(|r| r.inverse(hash))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.shift_inverse_binary_offsets(&mut changes);
+        Ok(Change {
             offsets: Offsets::default(),
             hashed: Hashed {
                 version: VERSION,
@@ -654,12 +942,12 @@ impl Change {
                 dependencies,
                 extra_known: self.extra_known.clone(),
                 metadata,
-                changes: self.changes.iter().map(|r| r.inverse(hash)).collect(),
+                changes,
                 contents_hash,
             },
             contents: Vec::new(),
             unhashed: None,
-        }
+        })
     }
 }
 
@@ -727,6 +1015,63 @@ pub enum Hunk<Hash, Local> {
         local: Local,
         encoding: Option<Encoding>,
     },
+    /// An edit of a binary file, replacing `delete_len` bytes at
+    /// byte `offset` of the old version of the file by the contents
+    /// of `insert`.
+    BinaryEdit {
+        delete: Option<Atom<Hash>>,
+        insert: Option<Atom<Hash>>,
+        local: Local,
+        offset: u64,
+        delete_len: u64,
+    },
+    /// The addition of a file whose contents were identical to those
+    /// of `source`, a tracked file, when recorded. This is applied
+    /// exactly like a `FileAdd`, `source` and `source_inode` only
+    /// record provenance, see [`crate::fs::copy_source`]. The
+    /// contents are still stored in the change, since the vertices of
+    /// a change can only point to its own contents.
+    FileCopy {
+        add_name: Atom<Hash>,
+        add_inode: Atom<Hash>,
+        contents: Option<Atom<Hash>>,
+        path: String,
+        source: String,
+        /// The inode vertex of `source`. The change introducing it is
+        /// a dependency of the change.
+        source_inode: Option<Position<Hash>>,
+        encoding: Option<Encoding>,
+    },
+    /// A hunk of a kind registered by an embedder, see
+    /// [`crate::hunk_kind`]. These hunks don't touch the graph: the
+    /// handlers of their kind are called instead.
+    Custom {
+        kind: String,
+        payload: Vec<u8>,
+        local: Local,
+    },
+    /// A change of the permissions of a file, without moving it. This
+    /// is applied exactly like a `FileMove` to the same name: `del`
+    /// deletes the former name of the file, and `add` is its name
+    /// with the new metadata.
+    FileMeta {
+        del: Atom<Hash>,
+        add: Atom<Hash>,
+        path: String,
+    },
+    /// The addition of the pointer to a nested repository, whose
+    /// state is `channel` and `change` (see [`crate::subrepo`]). This
+    /// is applied exactly like a `FileAdd` of the pointer, whose
+    /// contents are that state.
+    SubrepoPointer {
+        add_name: Atom<Hash>,
+        add_inode: Atom<Hash>,
+        contents: Option<Atom<Hash>>,
+        path: String,
+        channel: String,
+        change: Option<crate::pristine::Hash>,
+        encoding: Option<Encoding>,
+    },
 }
 
 #[doc(hidden)]
@@ -774,7 +1119,7 @@ impl<Context, Local> Iterator for HunkIter<Hunk<Context, Local>, Atom<Context>>
             Some(extra)
         } else if let Some(rec) = self.rec.take() {
             match rec {
-                Hunk::FileMove { del, add, .. } => {
+                Hunk::FileMove { del, add, .. } | Hunk::FileMeta { del, add, .. } => {
                     self.extra = Some(add);
                     Some(del)
                 }
@@ -793,6 +1138,18 @@ impl<Context, Local> Iterator for HunkIter<Hunk<Context, Local>, Atom<Context>>
                     add_inode,
                     contents,
                     ..
+                }
+                | Hunk::FileCopy {
+                    add_name,
+                    add_inode,
+                    contents,
+                    ..
+                }
+                | Hunk::SubrepoPointer {
+                    add_name,
+                    add_inode,
+                    contents,
+                    ..
                 } => {
                     self.extra = Some(add_inode);
                     self.extra2 = contents;
@@ -812,6 +1169,15 @@ impl<Context, Local> Iterator for HunkIter<Hunk<Context, Local>, Atom<Context>>
                 Hunk::SolveOrderConflict { change, .. } => Some(change),
                 Hunk::UnsolveOrderConflict { change, .. } => Some(change),
                 Hunk::ResurrectZombies { change, .. } => Some(change),
+                Hunk::BinaryEdit { delete, insert, .. } => {
+                    if let Some(delete) = delete {
+                        self.extra = insert;
+                        Some(delete)
+                    } else {
+                        insert
+                    }
+                }
+                Hunk::Custom { .. } => None,
             }
         } else {
             None
@@ -830,6 +1196,9 @@ impl<'a, Context, Local> Iterator for HunkIter<&'a Hunk<Context, Local>, &'a Ato
             match *rec {
                 Hunk::FileMove {
                     ref del, ref add, ..
+                }
+                | Hunk::FileMeta {
+                    ref del, ref add, ..
                 } => {
                     self.extra = Some(add);
                     Some(del)
@@ -855,6 +1224,18 @@ impl<'a, Context, Local> Iterator for HunkIter<&'a Hunk<Context, Local>, &'a Ato
                     ref add_inode,
                     ref contents,
                     ..
+                }
+                | Hunk::FileCopy {
+                    ref add_name,
+                    ref add_inode,
+                    ref contents,
+                    ..
+                }
+                | Hunk::SubrepoPointer {
+                    ref add_name,
+                    ref add_inode,
+                    ref contents,
+                    ..
                 } => {
                     self.extra = Some(add_inode);
                     self.extra2 = contents.as_ref();
@@ -874,6 +1255,19 @@ impl<'a, Context, Local> Iterator for HunkIter<&'a Hunk<Context, Local>, &'a Ato
                 Hunk::SolveOrderConflict { ref change, .. } => Some(change),
                 Hunk::UnsolveOrderConflict { ref change, .. } => Some(change),
                 Hunk::ResurrectZombies { ref change, .. } => Some(change),
+                Hunk::BinaryEdit {
+                    ref delete,
+                    ref insert,
+                    ..
+                } => {
+                    if let Some(ref delete) = delete {
+                        self.extra = insert.as_ref();
+                        Some(delete)
+                    } else {
+                        insert.as_ref()
+                    }
+                }
+                Hunk::Custom { .. } => None,
             }
         } else {
             None
@@ -898,6 +1292,9 @@ impl<'a, Context, Local> Iterator for RevHunkIter<&'a Hunk<Context, Local>, &'a
             match *rec {
                 Hunk::FileMove {
                     ref del, ref add, ..
+                }
+                | Hunk::FileMeta {
+                    ref del, ref add, ..
                 } => {
                     self.extra = Some(del);
                     Some(add)
@@ -931,6 +1328,18 @@ impl<'a, Context, Local> Iterator for RevHunkIter<&'a Hunk<Context, Local>, &'a
                     ref add_inode,
                     ref contents,
                     ..
+                }
+                | Hunk::FileCopy {
+                    ref add_name,
+                    ref add_inode,
+                    ref contents,
+                    ..
+                }
+                | Hunk::SubrepoPointer {
+                    ref add_name,
+                    ref add_inode,
+                    ref contents,
+                    ..
                 } => {
                     if let Some(ref c) = contents {
                         self.extra = Some(add_inode);
@@ -955,6 +1364,19 @@ impl<'a, Context, Local> Iterator for RevHunkIter<&'a Hunk<Context, Local>, &'a
                 Hunk::SolveOrderConflict { ref change, .. } => Some(change),
                 Hunk::UnsolveOrderConflict { ref change, .. } => Some(change),
                 Hunk::ResurrectZombies { ref change, .. } => Some(change),
+                Hunk::BinaryEdit {
+                    ref delete,
+                    ref insert,
+                    ..
+                } => {
+                    if let Some(ref insert) = insert {
+                        self.extra = delete.as_ref();
+                        Some(insert)
+                    } else {
+                        delete.as_ref()
+                    }
+                }
+                Hunk::Custom { .. } => None,
             }
         } else {
             None
@@ -1052,32 +1474,42 @@ impl<H> Hunk<H, Local> {
     pub fn path(&self) -> &str {
         match self {
             Hunk::FileMove { ref path, .. }
+            | Hunk::FileMeta { ref path, .. }
             | Hunk::FileDel { ref path, .. }
             | Hunk::FileUndel { ref path, .. }
             | Hunk::SolveNameConflict { ref path, .. }
             | Hunk::UnsolveNameConflict { ref path, .. }
-            | Hunk::FileAdd { ref path, .. } => path,
+            | Hunk::FileAdd { ref path, .. }
+            | Hunk::FileCopy { ref path, .. }
+            | Hunk::SubrepoPointer { ref path, .. } => path,
             Hunk::Edit { ref local, .. }
             | Hunk::Replacement { ref local, .. }
             | Hunk::SolveOrderConflict { ref local, .. }
             | Hunk::UnsolveOrderConflict { ref local, .. }
-            | Hunk::ResurrectZombies { ref local, .. } => &local.path,
+            | Hunk::ResurrectZombies { ref local, .. }
+            | Hunk::BinaryEdit { ref local, .. }
+            | Hunk::Custom { ref local, .. } => &local.path,
         }
     }
 
     pub fn line(&self) -> Option<usize> {
         match self {
             Hunk::FileMove { .. }
+            | Hunk::FileMeta { .. }
             | Hunk::FileDel { .. }
             | Hunk::FileUndel { .. }
             | Hunk::SolveNameConflict { .. }
             | Hunk::UnsolveNameConflict { .. }
-            | Hunk::FileAdd { .. } => None,
+            | Hunk::FileAdd { .. }
+            | Hunk::FileCopy { .. }
+            | Hunk::SubrepoPointer { .. } => None,
             Hunk::Edit { ref local, .. }
             | Hunk::Replacement { ref local, .. }
             | Hunk::SolveOrderConflict { ref local, .. }
             | Hunk::UnsolveOrderConflict { ref local, .. }
-            | Hunk::ResurrectZombies { ref local, .. } => Some(local.line),
+            | Hunk::ResurrectZombies { ref local, .. }
+            | Hunk::BinaryEdit { ref local, .. }
+            | Hunk::Custom { ref local, .. } => Some(local.line),
         }
     }
 }
@@ -1093,6 +1525,11 @@ impl<Local> Hunk<Option<ChangeId>, Local> {
                 add: add.globalize(txn)?,
                 path,
             },
+            Hunk::FileMeta { del, add, path } => Hunk::FileMeta {
+                del: del.globalize(txn)?,
+                add: add.globalize(txn)?,
+                path,
+            },
             Hunk::FileDel {
                 del,
                 contents,
@@ -1136,6 +1573,52 @@ impl<Local> Hunk<Option<ChangeId>, Local> {
                 path,
                 encoding,
             },
+            Hunk::FileCopy {
+                add_inode,
+                add_name,
+                contents,
+                path,
+                source,
+                source_inode,
+                encoding,
+            } => Hunk::FileCopy {
+                add_name: add_name.globalize(txn)?,
+                add_inode: add_inode.globalize(txn)?,
+                contents: contents
+                    .as_ref()
+                    .map(|add| add.globalize(txn))
+                    .transpose()?,
+                path,
+                source,
+                source_inode: if let Some(p) = source_inode {
+                    let change = if let Some(ref a) = p.change {
+                        txn.get_external(a).map_err(|e| e.0)?.map(Into::into)
+                    } else {
+                        None
+                    };
+                    Some(Position { change, pos: p.pos })
+                } else {
+                    None
+                },
+                encoding,
+            },
+            Hunk::SubrepoPointer {
+                add_inode,
+                add_name,
+                contents,
+                path,
+                channel,
+                change,
+                encoding,
+            } => Hunk::SubrepoPointer {
+                add_name: add_name.globalize(txn)?,
+                add_inode: add_inode.globalize(txn)?,
+                contents: contents.as_ref().map(|add| add.globalize(txn).unwrap()),
+                path,
+                channel,
+                change,
+                encoding,
+            },
             Hunk::Edit {
                 change,
                 local,
@@ -1173,6 +1656,28 @@ impl<Local> Hunk<Option<ChangeId>, Local> {
                 local,
                 encoding,
             },
+            Hunk::BinaryEdit {
+                delete,
+                insert,
+                local,
+                offset,
+                delete_len,
+            } => Hunk::BinaryEdit {
+                delete: delete.as_ref().map(|d| d.globalize(txn).unwrap()),
+                insert: insert.as_ref().map(|i| i.globalize(txn).unwrap()),
+                local,
+                offset,
+                delete_len,
+            },
+            Hunk::Custom {
+                kind,
+                payload,
+                local,
+            } => Hunk::Custom {
+                kind,
+                payload,
+                local,
+            },
         })
     }
 }
@@ -1282,6 +1787,18 @@ impl Change {
         Ok(off.contents_off)
     }
 
+    /// The uncompressed size of the contents of the change in `r`,
+    /// read from its header only.
+    pub fn contents_len<R: std::io::Read>(r: &mut R) -> Result<u64, ChangeError> {
+        let mut off = [0u8; Self::OFFSETS_SIZE as usize];
+        r.read_exact(&mut off)?;
+        let off: Offsets = bincode::deserialize(&off)?;
+        if off.version != VERSION && off.version != VERSION_NOENC {
+            return Err(ChangeError::VersionMismatch { got: off.version });
+        }
+        Ok(off.contents_len)
+    }
+
     /// Serialise the change as a file named "<hash>.change" in
     /// directory `dir`, where "<hash>" is the actual hash of the
     /// change.
@@ -1296,13 +1813,12 @@ impl Change {
         let hash = hasher.finish();
         debug!("{:?}", hash);
 
-        // Unhashed part.
-        let unhashed = if let Some(ref un) = self.unhashed {
-            let s = serde_json::to_string(un).unwrap();
-            s.into()
-        } else {
-            Vec::new()
-        };
+        // Unhashed part, with the metrics and path index added.
+        let unhashed = serialize_unhashed(
+            self.unhashed.clone(),
+            self.hashed.metrics(),
+            self.hashed.touched_paths(),
+        )?;
 
         // Compress the change.
         let mut hashed_comp = Vec::new();
@@ -1341,25 +1857,38 @@ impl Change {
     /// Deserialise a change from the file given as input `file`.
     #[cfg(feature = "zstd")]
     pub fn check_from_buffer(buf: &[u8], hash: &Hash) -> Result<(), ChangeError> {
+        Self::check_buffer(buf, hash, true)
+    }
+
+    /// Check the change in `buf` against `hash`, and its contents
+    /// against its contents hash if `check_contents` is set.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn check_buffer(
+        buf: &[u8],
+        hash: &Hash,
+        check_contents: bool,
+    ) -> Result<(), ChangeError> {
         let offsets: Offsets = bincode::deserialize_from(&buf[..Self::OFFSETS_SIZE as usize])?;
         if offsets.version != VERSION && offsets.version != VERSION_NOENC {
             return Err(ChangeError::VersionMismatch {
                 got: offsets.version,
             });
         }
+        let sections = Sections::from_header(&offsets);
 
-        debug!("check_from_buffer, offsets = {:?}", offsets);
+        debug!("check_from_buffer, sections = {:?}", sections);
         let mut s = zstd_seekable::Seekable::init_buf(
-            &buf[Self::OFFSETS_SIZE as usize..offsets.unhashed_off as usize],
+            &buf[sections.hashed.0 as usize..sections.hashed.1 as usize],
         )?;
         let mut buf_ = Vec::new();
-        buf_.resize(offsets.hashed_len as usize, 0);
+        buf_.resize(sections.hashed_len as usize, 0);
         s.decompress(&mut buf_[..], 0)?;
         trace!("check_from_buffer, buf_ = {:?}", buf_);
         let mut hasher = Hasher::default();
         hasher.update(&buf_);
         let computed_hash = hasher.finish();
         debug!("{:?} {:?}", computed_hash, hash);
+        let unhashed = read_unhashed(&mut std::io::Cursor::new(buf), &sections)?;
         if &computed_hash != hash {
             return Err((ChangeError::ChangeHashMismatch {
                 claimed: *hash,
@@ -1368,17 +1897,23 @@ impl Change {
             .into());
         }
 
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
+        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version != VERSION_NOENC
+        {
             bincode::deserialize(&buf_)?
         } else {
             let h: Hashed<noenc::Hunk<Option<Hash>, Local>, noenc::Author> =
                 bincode::deserialize(&buf_)?;
             h.into()
         };
+        check_path_index(unhashed.as_ref(), &hashed, hash)?;
+        if !check_contents {
+            return Ok(());
+        }
         buf_.clear();
-        buf_.resize(offsets.contents_len as usize, 0);
-        let mut s = zstd_seekable::Seekable::init_buf(&buf[offsets.contents_off as usize..])?;
-        buf_.resize(offsets.contents_len as usize, 0);
+        let mut s = zstd_seekable::Seekable::init_buf(
+            &buf[sections.contents.0 as usize..(sections.contents.1 as usize).min(buf.len())],
+        )?;
+        buf_.resize(sections.contents_len as usize, 0);
         s.decompress(&mut buf_[..], 0)?;
         let mut hasher = Hasher::default();
         trace!("contents = {:?}", buf_);
@@ -1400,7 +1935,7 @@ impl Change {
     /// Deserialise a change from the file given as input `file`.
     #[cfg(feature = "zstd")]
     pub fn deserialize(file: &str, hash: Option<&Hash>) -> Result<Self, ChangeError> {
-        use std::io::Read;
+        use std::io::{Read, Seek};
         let mut r = std::fs::File::open(file)?;
         let mut buf = vec![0u8; Self::OFFSETS_SIZE as usize];
         r.read_exact(&mut buf)?;
@@ -1412,47 +1947,39 @@ impl Change {
                 got: offsets.version,
             });
         }
-        debug!("offsets = {:?}", offsets);
+        let sections = Sections::from_header(&offsets);
+        debug!("sections = {:?}", sections);
+        r.seek(std::io::SeekFrom::Start(sections.hashed.0))?;
         buf.clear();
-        buf.resize((offsets.unhashed_off - Self::OFFSETS_SIZE) as usize, 0);
+        buf.resize((sections.hashed.1 - sections.hashed.0) as usize, 0);
         r.read_exact(&mut buf)?;
 
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = {
+        let (hashed, computed_hash): (Hashed<Hunk<Option<Hash>, Local>, Author>, _) = {
             let mut s = zstd_seekable::Seekable::init_buf(&buf[..])?;
-            let mut out = vec![0u8; offsets.hashed_len as usize];
+            let mut out = vec![0u8; sections.hashed_len as usize];
             s.decompress(&mut out[..], 0)?;
             let mut hasher = Hasher::default();
             hasher.update(&out);
-            let computed_hash = hasher.finish();
-            if let Some(hash) = hash {
-                if &computed_hash != hash {
-                    return Err(ChangeError::ChangeHashMismatch {
-                        claimed: *hash,
-                        computed: computed_hash,
-                    });
-                }
-            }
-            bincode::deserialize_from(&out[..])?
-        };
-        buf.clear();
-        buf.resize((offsets.contents_off - offsets.unhashed_off) as usize, 0);
-        let unhashed = if buf.is_empty() {
-            None
-        } else {
-            r.read_exact(&mut buf)?;
-            let mut s = zstd_seekable::Seekable::init_buf(&buf[..])?;
-            let mut out = vec![0u8; offsets.unhashed_len as usize];
-            s.decompress(&mut out[..], 0)?;
-            debug!("parsing unhashed: {:?}", std::str::from_utf8(&out));
-            serde_json::from_slice(&out).ok()
+            (bincode::deserialize_from(&out[..])?, hasher.finish())
         };
+        let unhashed = read_unhashed(&mut r, &sections)?;
         debug!("unhashed = {:?}", unhashed);
+        if let Some(hash) = hash {
+            if &computed_hash != hash {
+                return Err(ChangeError::ChangeHashMismatch {
+                    claimed: *hash,
+                    computed: computed_hash,
+                });
+            }
+            check_path_index(unhashed.as_ref(), &hashed, hash)?
+        }
 
+        r.seek(std::io::SeekFrom::Start(sections.contents.0))?;
         buf.clear();
-        buf.resize((offsets.total - offsets.contents_off) as usize, 0);
+        buf.resize((sections.contents.1 - sections.contents.0) as usize, 0);
         let contents = if r.read_exact(&mut buf).is_ok() {
             let mut s = zstd_seekable::Seekable::init_buf(&buf[..])?;
-            let mut contents = vec![0u8; offsets.contents_len as usize];
+            let mut contents = vec![0u8; sections.contents_len as usize];
             s.decompress(&mut contents[..], 0)?;
             contents
         } else {
@@ -1461,7 +1988,7 @@ impl Change {
         debug!("contents = {:?}", contents);
 
         Ok(LocalChange {
-            offsets,
+            offsets: sections.offsets(),
             hashed,
             unhashed,
             contents,
@@ -9,9 +9,11 @@ pub struct ChangeFile<'a> {
     unhashed: Option<toml::Value>,
 }
 
-struct OffFile {
-    f: std::fs::File,
-    start: u64,
+/// A section of a file, from `start` to `end`.
+pub(super) struct OffFile {
+    pub(super) f: std::fs::File,
+    pub(super) start: u64,
+    pub(super) end: u64,
 }
 
 unsafe impl Send for OffFile {}
@@ -27,9 +29,10 @@ impl std::io::Seek for OffFile {
         use std::io::SeekFrom;
         let from = match from {
             SeekFrom::Start(s) => SeekFrom::Start(s + self.start),
+            SeekFrom::End(e) => SeekFrom::Start((self.end as i64 + e) as u64),
             c => c,
         };
-        self.f.seek(from)
+        Ok(self.f.seek(from)? - self.start)
     }
 }
 
@@ -37,7 +40,7 @@ impl std::io::Seek for OffFile {
 impl<'a> ChangeFile<'a> {
     /// Open a change file from a path.
     pub fn open(hash: Hash, path: &str) -> Result<Self, ChangeError> {
-        use std::io::Read;
+        use std::io::{Read, Seek};
         let mut r = std::fs::File::open(path)?;
         let mut buf = Vec::new();
         buf.resize(Change::OFFSETS_SIZE as usize, 0);
@@ -48,18 +51,20 @@ impl<'a> ChangeFile<'a> {
                 got: offsets.version,
             });
         }
+        let sections = Sections::from_header(&offsets);
 
+        r.seek(std::io::SeekFrom::Start(sections.hashed.0))?;
         buf.clear();
-        buf.resize((offsets.unhashed_off - Change::OFFSETS_SIZE) as usize, 0);
+        buf.resize((sections.hashed.1 - sections.hashed.0) as usize, 0);
         r.read_exact(&mut buf)?;
         let mut buf2 = vec![0u8; offsets.hashed_len as usize];
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
+        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version != VERSION_NOENC
+        {
             let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
             s.decompress(&mut buf2, 0)?;
             trace!("deserialize current version {:?}", buf2.len());
             bincode::deserialize(&buf2)?
         } else {
-            assert_eq!(offsets.version, VERSION_NOENC);
             let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
             s.decompress(&mut buf2, 0)?;
             trace!("deserialize noenc {:?}", buf2.len());
@@ -68,10 +73,11 @@ impl<'a> ChangeFile<'a> {
             h.into()
         };
 
-        buf.resize((offsets.contents_off - offsets.unhashed_off) as usize, 0);
+        buf.resize((sections.unhashed.1 - sections.unhashed.0) as usize, 0);
         let unhashed = if buf.is_empty() {
             None
         } else {
+            r.seek(std::io::SeekFrom::Start(sections.unhashed.0))?;
             r.read_exact(&mut buf)?;
             let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
             buf2.resize(offsets.unhashed_len as usize, 0);
@@ -81,12 +87,13 @@ impl<'a> ChangeFile<'a> {
         };
 
         let m = r.metadata()?;
-        let s = if offsets.contents_off >= m.len() {
+        let s = if sections.contents.0 >= m.len() {
             None
         } else {
             Some(zstd_seekable::Seekable::init(Box::new(OffFile {
                 f: r,
-                start: offsets.contents_off,
+                start: sections.contents.0,
+                end: sections.contents.1,
             }))?)
         };
         Ok(ChangeFile {
@@ -120,4 +127,14 @@ impl<'a> ChangeFile<'a> {
     pub fn unhashed(&self) -> &Option<toml::Value> {
         &self.unhashed
     }
+
+    /// The metrics of this change, read from the unhashed section if
+    /// they are there, and recomputed from the hunks otherwise.
+    pub fn metrics(&self) -> ChangeMetrics {
+        self.unhashed
+            .as_ref()
+            .and_then(|un| un.get(METRICS_KEY))
+            .and_then(|m| m.clone().try_into().ok())
+            .unwrap_or_else(|| self.hashed.metrics())
+    }
 }
@@ -0,0 +1,191 @@
+use super::*;
+use crate::changestore::ChangeStore;
+use std::collections::VecDeque;
+
+/// The way an atom of a hunk refers to another change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyReason {
+    /// A new vertex is inserted after this vertex.
+    UpContext,
+    /// A new vertex is inserted before this vertex.
+    DownContext,
+    /// An edge starting at this vertex is modified.
+    EdgeSource,
+    /// An edge pointing to this vertex is modified.
+    EdgeTarget,
+    /// An edge introduced by the change is modified, and this is the
+    /// target of that edge.
+    EdgeIntroducedBy,
+}
+
+/// An atom of a change referring to another change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyCause {
+    /// Index of the hunk in the change.
+    pub hunk: usize,
+    pub path: String,
+    pub line: Option<usize>,
+    pub reason: DependencyReason,
+    /// The vertex referred to.
+    pub vertex: Position<Option<Hash>>,
+}
+
+/// Why a change depends on another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyExplanation {
+    /// The chain of changes through which the dependency is
+    /// inherited, from a change the explained change depends on
+    /// directly, to a change depending directly on the dependency.
+    /// Empty if the dependency is direct.
+    pub via: Vec<Hash>,
+    /// The atoms causing the first step of the dependency, i.e. the
+    /// atoms of the explained change referring to the dependency, or
+    /// to the first change of `via`.
+    pub causes: Vec<DependencyCause>,
+}
+
+#[derive(Debug, Error)]
+pub enum ExplainError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Changestore(C),
+    #[error("Change {0:?} not found in the pristine")]
+    UnknownChange(Hash),
+    #[error("{0:?} doesn't depend on {1:?}")]
+    NotADependency(Hash, Hash),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for ExplainError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        ExplainError::Txn(e.0)
+    }
+}
+
+/// Explain why `change` depends on `dep`, directly or not.
+pub fn explain_dependency<
+    T: GraphTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    C: ChangeStore,
+>(
+    txn: &T,
+    changes: &C,
+    change: &Hash,
+    dep: &Hash,
+) -> Result<DependencyExplanation, ExplainError<C::Error, T::GraphError>> {
+    let c = changes
+        .get_change(change)
+        .map_err(ExplainError::Changestore)?;
+    let causes = causes(&c, dep);
+    if !causes.is_empty() {
+        return Ok(DependencyExplanation {
+            via: Vec::new(),
+            causes,
+        });
+    }
+    let target = if let Some(&id) = txn.get_internal(&dep.into())? {
+        id
+    } else {
+        return Err(ExplainError::UnknownChange(*dep));
+    };
+    let via = dependency_path(txn, &c.dependencies, target)?;
+    if let Some(first) = via.first() {
+        Ok(DependencyExplanation {
+            causes: self::causes(&c, first),
+            via,
+        })
+    } else {
+        Err(ExplainError::NotADependency(*change, *dep))
+    }
+}
+
+/// The atoms of `change` referring to `dep`.
+fn causes(change: &Change, dep: &Hash) -> Vec<DependencyCause> {
+    let mut causes = Vec::new();
+    for (hunk, h) in change.changes.iter().enumerate() {
+        let mut push = |reason, vertex: Position<Option<Hash>>| {
+            causes.push(DependencyCause {
+                hunk,
+                path: h.path().to_string(),
+                line: h.line(),
+                reason,
+                vertex,
+            })
+        };
+        for atom in h.iter() {
+            match *atom {
+                Atom::NewVertex(ref n) => {
+                    for p in n.up_context.iter() {
+                        if p.change.as_ref() == Some(dep) {
+                            push(DependencyReason::UpContext, *p)
+                        }
+                    }
+                    for p in n.down_context.iter() {
+                        if p.change.as_ref() == Some(dep) {
+                            push(DependencyReason::DownContext, *p)
+                        }
+                    }
+                }
+                Atom::EdgeMap(ref e) => {
+                    for e in e.edges.iter() {
+                        if e.from.change.as_ref() == Some(dep) {
+                            push(DependencyReason::EdgeSource, e.from)
+                        }
+                        if e.to.change.as_ref() == Some(dep) {
+                            push(DependencyReason::EdgeTarget, e.to.start_pos())
+                        }
+                        if e.introduced_by.as_ref() == Some(dep) {
+                            push(DependencyReason::EdgeIntroducedBy, e.to.start_pos())
+                        }
+                    }
+                }
+            }
+        }
+    }
+    causes
+}
+
+/// A shortest chain of dependencies from one of `roots` to a change
+/// depending directly on `target`, or an empty vector if there is
+/// none.
+fn dependency_path<T: GraphTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
+    txn: &T,
+    roots: &[Hash],
+    target: ChangeId,
+) -> Result<Vec<Hash>, TxnErr<T::GraphError>> {
+    let mut parent = HashMap::default();
+    let mut queue = VecDeque::new();
+    for h in roots {
+        if let Some(&id) = txn.get_internal(&h.into())? {
+            if parent.insert(id, None).is_none() {
+                queue.push_back(id)
+            }
+        }
+    }
+    while let Some(id) = queue.pop_front() {
+        for x in txn.iter_dep(&id)? {
+            let (id_, d) = x?;
+            if *id_ < id {
+                continue;
+            } else if *id_ > id {
+                break;
+            }
+            if *d == target {
+                let mut path = Vec::new();
+                let mut cur = Some(id);
+                while let Some(c) = cur {
+                    path.push(txn.get_external(&c)?.unwrap().into());
+                    cur = parent[&c];
+                }
+                path.reverse();
+                return Ok(path);
+            }
+            if !parent.contains_key(d) {
+                parent.insert(*d, Some(id));
+                queue.push_back(*d)
+            }
+        }
+    }
+    Ok(Vec::new())
+}
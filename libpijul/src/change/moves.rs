@@ -0,0 +1,228 @@
+use super::*;
+use crate::changestore::ChangeStore;
+
+/// Key of the moves in the unhashed part of change files.
+pub const MOVES_KEY: &str = "moves";
+
+/// A block of lines deleted or added by a hunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovedBlock {
+    /// Index of the hunk in the change.
+    pub hunk: usize,
+    pub path: String,
+    /// The line of the hunk, as in its [`Local`].
+    pub line: usize,
+    /// Number of lines of the block.
+    pub lines: usize,
+}
+
+/// A block deleted by a hunk, and added again (possibly with small
+/// modifications) by another hunk of the same change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Move {
+    pub from: MovedBlock,
+    pub to: MovedBlock,
+    /// Proportion of the lines of the largest of the two blocks
+    /// found in the other one, between 0 and 1.
+    pub similarity: f64,
+}
+
+/// Parameters of [`LocalChange::detect_moves`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MoveDetection {
+    /// Blocks with fewer non-blank lines than this are never
+    /// considered moved.
+    pub min_lines: usize,
+    /// Minimal similarity of the two blocks of a move.
+    pub min_similarity: f64,
+}
+
+impl Default for MoveDetection {
+    fn default() -> Self {
+        MoveDetection {
+            min_lines: 3,
+            min_similarity: 0.8,
+        }
+    }
+}
+
+struct Block {
+    block: MovedBlock,
+    /// The non-blank lines, trimmed and sorted.
+    lines: Vec<Vec<u8>>,
+}
+
+fn trim(mut l: &[u8]) -> &[u8] {
+    while let Some((c, rest)) = l.split_first() {
+        if !c.is_ascii_whitespace() {
+            break;
+        }
+        l = rest
+    }
+    while let Some((c, rest)) = l.split_last() {
+        if !c.is_ascii_whitespace() {
+            break;
+        }
+        l = rest
+    }
+    l
+}
+
+impl Block {
+    fn new(hunk: usize, h: &Hunk<Option<Hash>, Local>, contents: &[u8]) -> Self {
+        let mut lines: Vec<Vec<u8>> = contents
+            .split(|&c| c == b'\n')
+            .map(|l| trim(l).to_vec())
+            .filter(|l| !l.is_empty())
+            .collect();
+        let n =
+            contents.split(|&c| c == b'\n').count() - if contents.ends_with(b"\n") { 1 } else { 0 };
+        lines.sort_unstable();
+        Block {
+            block: MovedBlock {
+                hunk,
+                path: h.path().to_string(),
+                line: h.line().unwrap_or(1),
+                lines: n,
+            },
+            lines,
+        }
+    }
+
+    /// Size of the intersection of the lines of `self` and `other`,
+    /// as multisets, over the size of the largest one.
+    fn similarity(&self, other: &Block) -> f64 {
+        let (mut i, mut j, mut common) = (0, 0, 0);
+        while i < self.lines.len() && j < other.lines.len() {
+            match self.lines[i].cmp(&other.lines[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    common += 1;
+                    i += 1;
+                    j += 1
+                }
+            }
+        }
+        common as f64 / self.lines.len().max(other.lines.len()) as f64
+    }
+}
+
+impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
+    /// Find the blocks of text deleted by a hunk of this change, and
+    /// added again by another hunk, in the same file or in another
+    /// one. Each added block is attributed to at most one deleted
+    /// block, the most similar one. Binary hunks are ignored.
+    ///
+    /// The contents of the deleted blocks are read from `changes`.
+    pub fn detect_moves<C: ChangeStore>(
+        &self,
+        changes: &C,
+        params: &MoveDetection,
+    ) -> Result<Vec<Move>, C::Error> {
+        let mut deleted = Vec::new();
+        let mut added = Vec::new();
+        let mut buf = Vec::new();
+        for (i, h) in self.changes.iter().enumerate() {
+            let (del, add, encoding) = match h {
+                Hunk::Edit {
+                    change, encoding, ..
+                } => match change {
+                    Atom::NewVertex(_) => (None, Some(change), encoding),
+                    Atom::EdgeMap(_) => (Some(change), None, encoding),
+                },
+                Hunk::Replacement {
+                    change,
+                    replacement,
+                    encoding,
+                    ..
+                } => (Some(change), Some(replacement), encoding),
+                Hunk::FileDel {
+                    contents, encoding, ..
+                } => (contents.as_ref(), None, encoding),
+                Hunk::FileAdd {
+                    contents, encoding, ..
+                } => (None, contents.as_ref(), encoding),
+                _ => continue,
+            };
+            if encoding.is_none() {
+                continue;
+            }
+            if let Some(Atom::EdgeMap(e)) = del {
+                buf.clear();
+                let mut current = None;
+                let mut tmp = Vec::new();
+                for e in e.edges.iter() {
+                    if !e.flag.contains(EdgeFlags::DELETED) || Some(e.to) == current {
+                        continue;
+                    }
+                    tmp.clear();
+                    changes.get_contents_ext(e.to, &mut tmp)?;
+                    buf.extend_from_slice(&tmp);
+                    current = Some(e.to)
+                }
+                deleted.push(Block::new(i, h, &buf))
+            }
+            if let Some(Atom::NewVertex(n)) = add {
+                added.push(Block::new(i, h, &self.contents[n.start.us()..n.end.us()]))
+            }
+        }
+        deleted.retain(|b| b.lines.len() >= params.min_lines.max(1));
+        added.retain(|b| b.lines.len() >= params.min_lines.max(1));
+        debug!(
+            "detect_moves: {} deleted, {} added",
+            deleted.len(),
+            added.len()
+        );
+
+        let mut moves = Vec::new();
+        for to in added.iter() {
+            let mut best: Option<(&Block, f64)> = None;
+            for from in deleted.iter() {
+                // A replacement deleting and adding the same block
+                // isn't a move.
+                if from.block.hunk == to.block.hunk {
+                    continue;
+                }
+                let s = from.similarity(to);
+                if s >= params.min_similarity && best.map(|(_, b)| s > b).unwrap_or(true) {
+                    best = Some((from, s))
+                }
+            }
+            if let Some((from, similarity)) = best {
+                moves.push(Move {
+                    from: from.block.clone(),
+                    to: to.block.clone(),
+                    similarity,
+                })
+            }
+        }
+        Ok(moves)
+    }
+
+    /// Store `moves` in the unhashed part of this change, which
+    /// doesn't change its hash.
+    pub fn annotate_moves(&mut self, moves: &[Move]) {
+        let mut un = self
+            .unhashed
+            .take()
+            .unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+        if let serde_json::Value::Object(ref mut un) = un {
+            if moves.is_empty() {
+                un.remove(MOVES_KEY);
+            } else {
+                un.insert(MOVES_KEY.to_string(), serde_json::to_value(moves).unwrap());
+            }
+        }
+        self.unhashed = Some(un)
+    }
+
+    /// The moves stored by [`LocalChange::annotate_moves`].
+    pub fn moves(&self) -> Vec<Move> {
+        self.unhashed
+            .as_ref()
+            .and_then(|un| un.get(MOVES_KEY))
+            .and_then(|m| serde_json::from_value(m.clone()).ok())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,475 @@
+//! Streaming change files.
+//!
+//! [`Change::serialize`] needs the entire change in memory, including
+//! its contents. [`ChangeWriter`] instead compresses the contents as
+//! they are written, and spools the hunks and the compressed
+//! contents, so that only a bounded amount of memory is used. The
+//! files it writes are ordinary change files, which can be read by
+//! any version of Pijul reading [`VERSION`].
+//!
+//! [`ChangeReader`] reads the hunks of a change file one at a time,
+//! without reading its contents, and is what the file system change
+//! store uses to load changes to be applied. Applying a change still
+//! needs all its hunks in memory, but not its contents.
+use super::*;
+use crate::pristine::Hasher;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Size of the buffers used to read and spool hunks.
+const BUF_SIZE: usize = 1 << 16;
+
+/// Where the compressed sections of a change file are, as ranges of
+/// the file, along with their decompressed lengths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sections {
+    pub version: u64,
+    pub hashed: (u64, u64),
+    pub hashed_len: u64,
+    pub unhashed: (u64, u64),
+    pub unhashed_len: u64,
+    pub contents: (u64, u64),
+    pub contents_len: u64,
+    pub total: u64,
+}
+
+impl Sections {
+    pub(crate) fn from_header(offsets: &Offsets) -> Self {
+        Sections {
+            version: offsets.version,
+            hashed: (Change::OFFSETS_SIZE, offsets.unhashed_off),
+            hashed_len: offsets.hashed_len,
+            unhashed: (offsets.unhashed_off, offsets.contents_off),
+            unhashed_len: offsets.unhashed_len,
+            contents: (offsets.contents_off, offsets.total),
+            contents_len: offsets.contents_len,
+            total: offsets.total,
+        }
+    }
+
+    /// The table of contents of the file.
+    pub(crate) fn offsets(&self) -> Offsets {
+        Offsets {
+            version: self.version,
+            hashed_len: self.hashed_len,
+            unhashed_off: self.unhashed.0,
+            unhashed_len: self.unhashed_len,
+            contents_off: self.contents.0,
+            contents_len: self.contents_len,
+            total: self.total,
+        }
+    }
+}
+
+/// Read and parse the unhashed section of a change file.
+pub(crate) fn read_unhashed<R: Read + Seek>(
+    r: &mut R,
+    sections: &Sections,
+) -> Result<Option<serde_json::Value>, ChangeError> {
+    let len = sections.unhashed.1 - sections.unhashed.0;
+    if len == 0 {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::Start(sections.unhashed.0))?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    let mut s = zstd_seekable::Seekable::init_buf(&buf[..])?;
+    let mut out = vec![0u8; sections.unhashed_len as usize];
+    s.decompress(&mut out[..], 0)?;
+    debug!("parsing unhashed: {:?}", std::str::from_utf8(&out));
+    Ok(serde_json::from_slice(&out).ok())
+}
+
+fn compress_into<W: Write>(
+    cstream: &mut zstd_seekable::SeekableCStream,
+    mut input: &[u8],
+    w: &mut W,
+) -> Result<u64, ChangeError> {
+    let mut output = [0; 4096];
+    let mut written = 0;
+    while !input.is_empty() {
+        let (out_pos, in_pos) = cstream.compress(&mut output, input)?;
+        w.write_all(&output[..out_pos])?;
+        written += out_pos as u64;
+        input = &input[in_pos..];
+    }
+    Ok(written)
+}
+
+fn end_stream<W: Write>(
+    cstream: &mut zstd_seekable::SeekableCStream,
+    w: &mut W,
+) -> Result<u64, ChangeError> {
+    let mut output = [0; 4096];
+    let mut written = 0;
+    while let Ok(n) = cstream.end_stream(&mut output) {
+        if n == 0 {
+            break;
+        }
+        w.write_all(&output[..n])?;
+        written += n as u64;
+    }
+    Ok(written)
+}
+
+/// Writes a change file incrementally. The contents are compressed
+/// as they are written, and spooled along with the hunks to two
+/// [`Read`]` + `[`Write`]` + `[`Seek`] until the change is finished,
+/// since the hunks and the contents hash come before the contents in
+/// change files. By default, the spools are in memory, which is fine
+/// unless the hunks themselves don't fit in memory, in which case
+/// [`ChangeWriter::with_spools`] can be given temporary files.
+///
+/// The header, dependencies and metadata can be set at any time
+/// before calling [`ChangeWriter::finish`], which returns the hash
+/// of the change. The file written is the same as if the change had
+/// been built in memory and serialized with [`Change::serialize`].
+pub struct ChangeWriter<W: Write + Seek, S: Read + Write + Seek = std::io::Cursor<Vec<u8>>> {
+    pub header: ChangeHeader,
+    pub dependencies: Vec<Hash>,
+    pub extra_known: Vec<Hash>,
+    pub metadata: Vec<u8>,
+    pub unhashed: Option<serde_json::Value>,
+    w: W,
+    spool: S,
+    spool_len: u64,
+    hunks: u64,
+    metrics: ChangeMetrics,
+    files: HashMap<String, u64>,
+    paths: PathIndex,
+    contents: zstd_seekable::SeekableCStream,
+    contents_spool: S,
+    contents_comp: u64,
+    contents_len: u64,
+    contents_hasher: Hasher,
+}
+
+impl<W: Write + Seek> ChangeWriter<W> {
+    /// Start writing a change to `w`, spooling in memory.
+    pub fn new(w: W) -> Result<Self, ChangeError> {
+        Self::with_spools(
+            w,
+            std::io::Cursor::new(Vec::new()),
+            std::io::Cursor::new(Vec::new()),
+        )
+    }
+}
+
+impl<W: Write + Seek, S: Read + Write + Seek> ChangeWriter<W, S> {
+    /// Start writing a change to `w`, spooling the hunks to `spool`
+    /// and the compressed contents to `contents_spool`, which must
+    /// both be empty.
+    pub fn with_spools(w: W, spool: S, contents_spool: S) -> Result<Self, ChangeError> {
+        Ok(ChangeWriter {
+            header: ChangeHeader::default(),
+            dependencies: Vec::new(),
+            extra_known: Vec::new(),
+            metadata: Vec::new(),
+            unhashed: None,
+            w,
+            spool,
+            spool_len: 0,
+            hunks: 0,
+            metrics: ChangeMetrics::default(),
+            files: HashMap::default(),
+            paths: PathIndex::default(),
+            contents: zstd_seekable::SeekableCStream::new(LEVEL, FRAME_SIZE).unwrap(),
+            contents_spool,
+            contents_comp: 0,
+            contents_len: 0,
+            contents_hasher: Hasher::default(),
+        })
+    }
+
+    /// The length of the contents written so far, i.e. the position
+    /// at which the next call to
+    /// [`write_contents`](ChangeWriter::write_contents) will write.
+    pub fn contents_len(&self) -> ChangePosition {
+        ChangePosition(L64::from(self.contents_len))
+    }
+
+    /// Append `bytes` to the contents of the change, and return the
+    /// position of the first byte.
+    pub fn write_contents(&mut self, bytes: &[u8]) -> Result<ChangePosition, ChangeError> {
+        let start = self.contents_len();
+        self.contents_hasher.update(bytes);
+        self.contents_comp += compress_into(&mut self.contents, bytes, &mut self.contents_spool)?;
+        self.contents_len += bytes.len() as u64;
+        Ok(start)
+    }
+
+    /// Append a hunk to the change. Positions in the contents of this
+    /// change must have been returned by
+    /// [`write_contents`](ChangeWriter::write_contents).
+    pub fn push_hunk(&mut self, hunk: &Hunk<Option<Hash>, Local>) -> Result<(), ChangeError> {
+        bincode::serialize_into(&mut self.spool, hunk)?;
+        self.spool_len += bincode::serialized_size(hunk)?;
+        self.metrics.add_hunk(&mut self.files, hunk);
+        self.paths.add_hunk(hunk);
+        self.hunks += 1;
+        Ok(())
+    }
+
+    /// Write the change, and return its hash, along with the
+    /// underlying writer.
+    pub fn finish(mut self) -> Result<(Hash, W), ChangeError> {
+        self.contents_comp += end_stream(&mut self.contents, &mut self.contents_spool)?;
+        let contents_hash = self.contents_hasher.finish();
+
+        // The table of contents is written last, at the start.
+        let start = self.w.seek(SeekFrom::Current(0))?;
+        bincode::serialize_into(&mut self.w, &Offsets::default())?;
+
+        // Hashed part, in the same order as the fields of `Hashed`.
+        let mut cstream = zstd_seekable::SeekableCStream::new(LEVEL, FRAME_SIZE).unwrap();
+        let mut hasher = Hasher::default();
+        let mut hashed_len = 0;
+        let mut hashed_comp = 0;
+        let prefix = bincode::serialize(&(
+            VERSION,
+            &self.header,
+            &self.dependencies,
+            &self.extra_known,
+            &self.metadata,
+            self.hunks,
+        ))?;
+        hasher.update(&prefix);
+        hashed_len += prefix.len() as u64;
+        hashed_comp += compress_into(&mut cstream, &prefix, &mut self.w)?;
+        self.spool.seek(SeekFrom::Start(0))?;
+        let mut spool = (&mut self.spool).take(self.spool_len);
+        let mut buf = vec![0; BUF_SIZE];
+        loop {
+            let n = spool.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            hashed_len += n as u64;
+            hashed_comp += compress_into(&mut cstream, &buf[..n], &mut self.w)?;
+        }
+        let suffix = bincode::serialize(&contents_hash)?;
+        hasher.update(&suffix);
+        hashed_len += suffix.len() as u64;
+        hashed_comp += compress_into(&mut cstream, &suffix, &mut self.w)?;
+        hashed_comp += end_stream(&mut cstream, &mut self.w)?;
+        let hash = hasher.finish();
+        debug!("streamed change {:?}", hash);
+
+        // Unhashed part, with the metrics and path index added.
+        let unhashed =
+            serialize_unhashed(self.unhashed.take(), self.metrics, self.paths.to_paths())?;
+        let unhashed_off = Change::OFFSETS_SIZE + hashed_comp;
+        let mut cstream = zstd_seekable::SeekableCStream::new(LEVEL, FRAME_SIZE).unwrap();
+        let unhashed_comp = compress_into(&mut cstream, &unhashed, &mut self.w)?
+            + end_stream(&mut cstream, &mut self.w)?;
+
+        // Contents.
+        let contents_off = unhashed_off + unhashed_comp;
+        self.contents_spool.seek(SeekFrom::Start(0))?;
+        std::io::copy(
+            &mut (&mut self.contents_spool).take(self.contents_comp),
+            &mut self.w,
+        )?;
+
+        self.w.seek(SeekFrom::Start(start))?;
+        bincode::serialize_into(
+            &mut self.w,
+            &Offsets {
+                version: VERSION,
+                hashed_len,
+                unhashed_off,
+                unhashed_len: unhashed.len() as u64,
+                contents_off,
+                contents_len: self.contents_len,
+                total: contents_off + self.contents_comp,
+            },
+        )?;
+        self.w
+            .seek(SeekFrom::Start(start + contents_off + self.contents_comp))?;
+        self.w.flush()?;
+        Ok((hash, self.w))
+    }
+}
+
+/// A section of a change file, decompressed on the fly and hashed.
+struct Decompressed<'a> {
+    s: zstd_seekable::Seekable<'a, OffFile>,
+    pos: u64,
+    len: u64,
+    hasher: Hasher,
+}
+
+impl<'a> Read for Decompressed<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let n = ((self.len - self.pos) as usize).min(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self
+            .s
+            .decompress(&mut buf[..n], self.pos)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        self.hasher.update(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads the hunks of a change file one at a time, without reading
+/// its contents. The hash of the change is checked after the last
+/// hunk has been read.
+pub struct ChangeReader<'a> {
+    r: std::io::BufReader<Decompressed<'a>>,
+    hash: Option<Hash>,
+    sections: Sections,
+    pub version: u64,
+    pub header: ChangeHeader,
+    pub dependencies: Vec<Hash>,
+    pub extra_known: Vec<Hash>,
+    pub metadata: Vec<u8>,
+    len: u64,
+    remaining: u64,
+    done: bool,
+    contents_hash: Option<Hash>,
+}
+
+impl<'a> ChangeReader<'a> {
+    /// Open the change file at `path`, checking its hash against
+    /// `hash` if it is given.
+    pub fn open(path: &str, hash: Option<&Hash>) -> Result<Self, ChangeError> {
+        let mut f = std::fs::File::open(path)?;
+        let mut buf = [0u8; Change::OFFSETS_SIZE as usize];
+        f.read_exact(&mut buf)?;
+        let offsets: Offsets = bincode::deserialize(&buf)?;
+        if offsets.version != VERSION {
+            return Err(ChangeError::VersionMismatch {
+                got: offsets.version,
+            });
+        }
+        let sections = Sections::from_header(&offsets);
+        let s = zstd_seekable::Seekable::init(Box::new(OffFile {
+            f,
+            start: sections.hashed.0,
+            end: sections.hashed.1,
+        }))?;
+        let mut r = std::io::BufReader::with_capacity(
+            BUF_SIZE,
+            Decompressed {
+                s,
+                pos: 0,
+                len: sections.hashed_len,
+                hasher: Hasher::default(),
+            },
+        );
+        let (version, header, dependencies, extra_known, metadata, len): (
+            u64,
+            ChangeHeader,
+            Vec<Hash>,
+            Vec<Hash>,
+            Vec<u8>,
+            u64,
+        ) = bincode::deserialize_from(&mut r)?;
+        Ok(ChangeReader {
+            r,
+            hash: hash.cloned(),
+            sections,
+            version,
+            header,
+            dependencies,
+            extra_known,
+            metadata,
+            len,
+            remaining: len,
+            done: false,
+            contents_hash: None,
+        })
+    }
+
+    /// The number of hunks of the change.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The hash of the contents of the change, available once all
+    /// the hunks have been read.
+    pub fn contents_hash(&self) -> Option<Hash> {
+        self.contents_hash
+    }
+
+    /// The table of contents of the file.
+    pub fn offsets(&self) -> Offsets {
+        self.sections.offsets()
+    }
+
+    fn finish(&mut self) -> Result<(), ChangeError> {
+        let contents_hash: Hash = bincode::deserialize_from(&mut self.r)?;
+        self.contents_hash = Some(contents_hash);
+        let computed = self.r.get_ref().hasher.finish();
+        if let Some(hash) = self.hash {
+            if computed != hash {
+                return Err(ChangeError::ChangeHashMismatch {
+                    claimed: hash,
+                    computed,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ChangeReader<'a> {
+    type Item = Result<Hunk<Option<Hash>, Local>, ChangeError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            if self.done {
+                return None;
+            }
+            self.done = true;
+            return self.finish().err().map(Err);
+        }
+        self.remaining -= 1;
+        Some(bincode::deserialize_from(&mut self.r).map_err(ChangeError::from))
+    }
+}
+
+impl Change {
+    /// Read the change in file `file`, except its contents, which are
+    /// left empty. Unlike [`Change::deserialize`], this decompresses
+    /// the hunks incrementally, and never reads the contents.
+    pub fn read_without_contents(file: &str, hash: Option<&Hash>) -> Result<Self, ChangeError> {
+        let mut f = std::fs::File::open(file)?;
+        let mut buf = [0u8; Change::OFFSETS_SIZE as usize];
+        f.read_exact(&mut buf)?;
+        let offsets: Offsets = bincode::deserialize(&buf)?;
+        if offsets.version == VERSION_NOENC {
+            let mut change = Self::deserialize(file, hash)?;
+            change.contents.clear();
+            return Ok(change);
+        }
+        let mut r = ChangeReader::open(file, hash)?;
+        let mut changes = Vec::with_capacity(r.len() as usize);
+        for hunk in &mut r {
+            changes.push(hunk?)
+        }
+        let sections = r.sections;
+        let unhashed = read_unhashed(&mut f, &sections)?;
+        Ok(LocalChange {
+            offsets: sections.offsets(),
+            hashed: Hashed {
+                version: r.version,
+                header: r.header,
+                dependencies: r.dependencies,
+                extra_known: r.extra_known,
+                metadata: r.metadata,
+                changes,
+                contents_hash: r.contents_hash.unwrap(),
+            },
+            unhashed,
+            contents: Vec::new(),
+        })
+    }
+}
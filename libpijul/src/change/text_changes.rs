@@ -17,6 +17,12 @@ pub enum TextDeError {
     MissingChange(usize),
     #[error("Byte position {0} from this change missing")]
     MissingPosition(u64),
+    #[error(transparent)]
+    Base64(#[from] data_encoding::DecodeError),
+    #[error("Invalid hash {0}")]
+    InvalidHash(String),
+    #[error(transparent)]
+    ParseInt(#[from] std::num::ParseIntError),
 }
 
 #[derive(Debug, Error)]
@@ -44,6 +50,19 @@ impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
         mut f: F,
     ) -> Result<(), ChangeError> {
         for c in self.changes.iter() {
+            if let Hunk::FileCopy {
+                source_inode:
+                    Some(Position {
+                        change: Some(change),
+                        ..
+                    }),
+                ..
+            } = c
+            {
+                if *change != Hash::None {
+                    f(*change)?
+                }
+            }
             for c in c.iter() {
                 match *c {
                     Atom::NewVertex(ref n) => {
@@ -324,50 +343,54 @@ impl Hunk<Option<Hash>, Local> {
     ) -> Result<(), TextSerError<C::Error>> {
         use self::text_changes::*;
         match self {
-            Hunk::FileMove { del, add, path } => match add {
-                Atom::NewVertex(ref add) => {
-                    let FileMetadata {
-                        basename: name,
-                        metadata: perms,
-                        ..
-                    } = FileMetadata::read(&change_contents[add.start.0.into()..add.end.0.into()]);
-                    write!(
-                        w,
-                        "Moved: {:?} {:?} {}",
-                        path,
-                        name,
-                        if perms.0 & 0o1000 == 0o1000 {
-                            "+dx "
-                        } else if perms.0 & 0o100 == 0o100 {
-                            "+x "
-                        } else {
-                            ""
-                        }
-                    )?;
-                    write_pos(&mut w, hashes, del.inode())?;
-                    writeln!(w)?;
-                    write_atom(&mut w, hashes, &del)?;
+            Hunk::FileMove { del, add, path } | Hunk::FileMeta { del, add, path } => {
+                let label = if let Hunk::FileMeta { .. } = self {
+                    "Permissions"
+                } else {
+                    "Moved"
+                };
+                match add {
+                    Atom::NewVertex(ref add) => {
+                        let FileMetadata {
+                            basename: name,
+                            metadata: perms,
+                            ..
+                        } = FileMetadata::read(
+                            &change_contents[add.start.0.into()..add.end.0.into()],
+                        );
+                        write!(
+                            w,
+                            "{}: {:?} {:?} {}",
+                            label,
+                            path,
+                            name,
+                            perm_marker(perms, false)
+                        )?;
+                        write_pos(&mut w, hashes, del.inode())?;
+                        writeln!(w)?;
+                        write_atom(&mut w, hashes, &del)?;
 
-                    write!(w, "up")?;
-                    for c in add.up_context.iter() {
-                        write!(w, " ")?;
-                        write_pos(&mut w, hashes, *c)?
+                        write!(w, "up")?;
+                        for c in add.up_context.iter() {
+                            write!(w, " ")?;
+                            write_pos(&mut w, hashes, *c)?
+                        }
+                        write!(w, ", down")?;
+                        for c in add.down_context.iter() {
+                            write!(w, " ")?;
+                            write_pos(&mut w, hashes, *c)?
+                        }
+                        w.write_all(b"\n")?;
                     }
-                    write!(w, ", down")?;
-                    for c in add.down_context.iter() {
-                        write!(w, " ")?;
-                        write_pos(&mut w, hashes, *c)?
+                    Atom::EdgeMap(_) => {
+                        write!(w, "{}: {:?} ", label, path)?;
+                        write_pos(&mut w, hashes, del.inode())?;
+                        writeln!(w)?;
+                        write_atom(&mut w, hashes, &add)?;
+                        write_atom(&mut w, hashes, &del)?;
                     }
-                    w.write_all(b"\n")?;
-                }
-                Atom::EdgeMap(_) => {
-                    write!(w, "Moved: {:?} ", path)?;
-                    write_pos(&mut w, hashes, del.inode())?;
-                    writeln!(w)?;
-                    write_atom(&mut w, hashes, &add)?;
-                    write_atom(&mut w, hashes, &del)?;
                 }
-            },
+            }
             Hunk::FileDel {
                 del,
                 contents,
@@ -412,6 +435,20 @@ impl Hunk<Option<Hash>, Local> {
                 path,
                 encoding,
                 ..
+            }
+            | Hunk::FileCopy {
+                add_name,
+                contents,
+                path,
+                encoding,
+                ..
+            }
+            | Hunk::SubrepoPointer {
+                add_name,
+                contents,
+                path,
+                encoding,
+                ..
             } => {
                 if let Atom::NewVertex(ref n) = add_name {
                     debug!("add_name {:?}", n);
@@ -431,18 +468,39 @@ impl Hunk<Option<Hash>, Local> {
                     };
                     write!(
                         w,
-                        "File addition: {:?} in {:?}{} {:?}\n  up",
+                        "{}: {:?} in {:?}{} {:?}",
+                        match self {
+                            Hunk::FileCopy { .. } => "File copy",
+                            Hunk::SubrepoPointer { .. } => "Subrepository",
+                            _ => "File addition",
+                        },
                         name,
                         parent,
-                        if perms.0 & 0o1000 == 0o1000 {
-                            " +dx"
-                        } else if perms.0 & 0o100 == 0o100 {
-                            " +x"
-                        } else {
-                            ""
-                        },
+                        perm_marker(perms, true),
                         encoding_label(encoding)
                     )?;
+                    if let Hunk::FileCopy {
+                        source,
+                        source_inode,
+                        ..
+                    } = self
+                    {
+                        write!(w, " from {:?}", source)?;
+                        if let Some(inode) = source_inode {
+                            write!(w, " ")?;
+                            write_pos(&mut w, hashes, *inode)?
+                        }
+                    }
+                    if let Hunk::SubrepoPointer {
+                        channel, change, ..
+                    } = self
+                    {
+                        write!(w, " at {:?}", channel)?;
+                        if let Some(change) = change {
+                            write!(w, " {}", change.to_base32())?
+                        }
+                    }
+                    write!(w, "\n  up")?;
                     assert!(n.down_context.is_empty());
                     for c in n.up_context.iter() {
                         write!(w, " ")?;
@@ -544,11 +602,96 @@ impl Hunk<Option<Hash>, Local> {
                 write_atom(&mut w, hashes, &change)?;
                 print_change_contents(w, changes, change, change_contents, encoding)?;
             }
+            Hunk::BinaryEdit {
+                delete,
+                insert,
+                local,
+                offset,
+                delete_len,
+            } => {
+                debug!("binary edit");
+                let inode = if let Some(ref d) = delete.as_ref().or(insert.as_ref()) {
+                    d.inode()
+                } else {
+                    return Err(TextSerError::InvalidChange);
+                };
+                write!(w, "Binary edit in {} ", file_name(&local, inode))?;
+                write_pos(&mut w, hashes, inode)?;
+                writeln!(w, " at byte {}, -{}", offset, delete_len)?;
+                if let Some(ref delete) = delete {
+                    write_atom(&mut w, hashes, delete)?;
+                }
+                if let Some(ref insert) = insert {
+                    write_atom(&mut w, hashes, insert)?;
+                }
+                if let Some(ref delete) = delete {
+                    print_change_contents(w, changes, delete, change_contents, &None)?;
+                }
+                if let Some(ref insert) = insert {
+                    print_change_contents(w, changes, insert, change_contents, &None)?;
+                }
+            }
+            Hunk::Custom {
+                kind,
+                payload,
+                local,
+            } => {
+                debug!("custom");
+                writeln!(w, "Custom {:?} in {:?}:{}", kind, local.path, local.line)?;
+                // The rendering is only informative, the payload is
+                // read back from its encoding.
+                if let Some(k) = crate::hunk_kind::get(kind) {
+                    for l in k.render(payload).lines() {
+                        writeln!(w, "  {}", l)?;
+                    }
+                }
+                writeln!(w, "+b{}", data_encoding::BASE64.encode(payload))?;
+            }
         }
         Ok(())
     }
 }
 
+/// The marker of the permissions of a file in its addition or move,
+/// preceded by a space if `space_before`, or followed by one
+/// otherwise. Full permissions are written in octal, after `+m`.
+fn perm_marker(perms: InodeMetadata, space_before: bool) -> String {
+    let marker = if perms.0 & 0o2000 == 0o2000 {
+        "+l".to_string()
+    } else if perms.0 & 0o4000 == 0o4000 {
+        "+o".to_string()
+    } else if perms.is_subrepo() {
+        "+sub".to_string()
+    } else if InodeMetadata::is_full_permissions(perms.permissions()) {
+        format!("+m{:o}", perms.0)
+    } else if perms.0 & 0o1000 == 0o1000 {
+        "+dx".to_string()
+    } else if perms.0 & 0o100 == 0o100 {
+        "+x".to_string()
+    } else {
+        return String::new();
+    };
+    if space_before {
+        format!(" {}", marker)
+    } else {
+        format!("{} ", marker)
+    }
+}
+
+fn parse_perm_marker(perm: &str) -> u16 {
+    match perm.trim() {
+        "+dx" => 0o1100,
+        "+l" => 0o2000,
+        "+o" => 0o4100,
+        "+sub" => InodeMetadata::new_subrepo().0,
+        "+x" => 0o100,
+        perm => perm
+            .strip_prefix("+m")
+            .and_then(|m| u16::from_str_radix(m, 8).ok())
+            .unwrap_or(0),
+    }
+}
+
 fn encoding_label(encoding: &Option<Encoding>) -> &str {
     match encoding {
         Some(encoding) => encoding.label(),
@@ -569,9 +712,11 @@ impl Hunk<Option<Hash>, Local> {
         use regex::Regex;
         lazy_static! {
             static ref FILE_ADDITION: Regex =
-                Regex::new(r#"^(?P<n>\d+)\. File addition: "(?P<name>[^"]*)" in "(?P<parent>[^"]*)"(?P<perm> \S+)? "(?P<encoding>[^"]*)""#).unwrap();
+                Regex::new(r#"^(?P<n>\d+)\. (?P<kind>File addition|File copy|Subrepository): "(?P<name>[^"]*)" in "(?P<parent>[^"]*)"(?P<perm> \S+)? "(?P<encoding>[^"]*)"( from "(?P<source>[^"]*)"( (?P<source_inode>\d+\.\d+))?)?( at "(?P<channel>[^"]*)"( (?P<change>[A-Z0-9]+))?)?"#).unwrap();
             static ref EDIT: Regex =
                 Regex::new(r#"^([0-9]+)\. Edit in ([^:]+):(\d+) (\d+\.\d+) "(?P<encoding>[^"]*)""#).unwrap();
+            static ref BINARY_EDIT: Regex =
+                Regex::new(r#"^([0-9]+)\. Binary edit in ([^:]+):(\d+) (\d+\.\d+) at byte (?P<offset>\d+), -(?P<len>\d+)"#).unwrap();
             static ref REPLACEMENT: Regex =
                 Regex::new(r#"^([0-9]+)\. Replacement in ([^:]+):(\d+) (\d+\.\d+) "(?P<encoding>[^"]*)""#).unwrap();
             static ref FILE_DELETION: Regex =
@@ -579,8 +724,8 @@ impl Hunk<Option<Hash>, Local> {
             static ref FILE_UNDELETION: Regex =
                 Regex::new(r#"^([0-9]+)\. File un-deletion: "([^"]*)" (\d+\.\d+) "(?P<encoding>[^"]*)""#).unwrap();
             static ref MOVE: Regex =
-                Regex::new(r#"^([0-9]+)\. Moved: "(?P<former>[^"]*)" "(?P<new>[^"]*)" (?P<perm>[^ ]+ )?(?P<inode>.*)"#).unwrap();
-            static ref MOVE_: Regex = Regex::new(r#"^([0-9]+)\. Moved: "([^"]*)" (.*)"#).unwrap();
+                Regex::new(r#"^([0-9]+)\. (?P<label>Moved|Permissions): "(?P<former>[^"]*)" "(?P<new>[^"]*)" (?P<perm>[^ ]+ )?(?P<inode>.*)"#).unwrap();
+            static ref MOVE_: Regex = Regex::new(r#"^([0-9]+)\. (Moved|Permissions): "([^"]*)" (.*)"#).unwrap();
             static ref NAME_CONFLICT: Regex = Regex::new(
                 r#"^([0-9]+)\. ((Solving)|(Un-solving)) a name conflict in "([^"]*)" (.*): .*"#
             )
@@ -589,6 +734,8 @@ impl Hunk<Option<Hash>, Local> {
                 r#"^([0-9]+)\. ((Solving)|(Un-solving)) an order conflict in (.*):(\d+) (\d+\.\d+)"#
             )
             .unwrap();
+            static ref CUSTOM: Regex =
+                Regex::new(r#"^([0-9]+)\. Custom "(?P<kind>[^"]*)" in "(?P<path>[^"]*)":(?P<line>\d+)"#).unwrap();
             static ref ZOMBIE: Regex =
                 Regex::new(r#"^([0-9]+)\. Resurrecting zombie lines in (?P<path>"[^"]+"):(?P<line>\d+) (?P<inode>\d+\.\d+) "(?P<encoding>[^"]*)""#)
                     .unwrap();
@@ -614,19 +761,11 @@ impl Hunk<Option<Hash>, Local> {
                 }) + name
             };
             debug!("cap = {:?}", cap);
-            let meta = if let Some(perm) = cap.name("perm") {
-                if perm.as_str() == " +dx" {
-                    0o1100
-                } else if perm.as_str() == " +x" {
-                    0o100
-                } else {
-                    0
-                }
-            } else {
-                0
-            };
+            let meta = cap
+                .name("perm")
+                .map_or(0, |perm| parse_perm_marker(perm.as_str()));
             let n = cap.name("n").unwrap().as_str().parse().unwrap();
-            let encoding = encoding_from_label(cap);
+            let encoding = encoding_from_label(&cap);
             let meta = FileMetadata {
                 metadata: InodeMetadata(meta),
                 basename: name,
@@ -653,16 +792,49 @@ impl Hunk<Option<Hash>, Local> {
                     *pos = add_inode.start
                 }
             }
-            Ok(std::mem::replace(
-                current,
-                Some(Hunk::FileAdd {
+            let hunk = if let Some(channel) = cap.name("channel") {
+                let change = if let Some(change) = cap.name("change") {
+                    Some(
+                        Hash::from_base32(change.as_str().as_bytes())
+                            .ok_or_else(|| TextDeError::InvalidHash(change.as_str().to_string()))?,
+                    )
+                } else {
+                    None
+                };
+                Hunk::SubrepoPointer {
                     add_name: Atom::NewVertex(add_name),
                     add_inode: Atom::NewVertex(add_inode),
                     contents: None,
                     path,
+                    channel: channel.as_str().to_string(),
+                    change,
                     encoding,
-                }),
-            ))
+                }
+            } else if let Some(source) = cap.name("source") {
+                let source_inode = if let Some(inode) = cap.name("source_inode") {
+                    Some(parse_pos(changes, inode.as_str())?)
+                } else {
+                    None
+                };
+                Hunk::FileCopy {
+                    add_name: Atom::NewVertex(add_name),
+                    add_inode: Atom::NewVertex(add_inode),
+                    contents: None,
+                    path,
+                    source: source.as_str().to_string(),
+                    source_inode,
+                    encoding,
+                }
+            } else {
+                Hunk::FileAdd {
+                    add_name: Atom::NewVertex(add_name),
+                    add_inode: Atom::NewVertex(add_inode),
+                    contents: None,
+                    path,
+                    encoding,
+                }
+            };
+            Ok(std::mem::replace(current, Some(hunk)))
         } else if let Some(cap) = EDIT.captures(h) {
             if has_newvertices(current) {
                 contents_.push(0)
@@ -679,7 +851,40 @@ impl Hunk<Option<Hash>, Local> {
                         path: cap[2].to_string(),
                         line: cap[3].parse().unwrap(),
                     },
-                    encoding: encoding_from_label(cap),
+                    encoding: encoding_from_label(&cap),
+                }),
+            ))
+        } else if let Some(cap) = BINARY_EDIT.captures(h) {
+            if has_newvertices(current) {
+                contents_.push(0)
+            }
+            let inode = parse_pos(changes, &cap[4])?;
+            let delete_len = cap.name("len").unwrap().as_str().parse()?;
+            // A hunk without deleted bytes always inserts something,
+            // so there is always an atom to carry the inode.
+            let (delete, insert) = if delete_len > 0 {
+                let mut e = default_edgemap();
+                e.inode = inode;
+                (Some(Atom::EdgeMap(e)), None)
+            } else {
+                let mut v = default_newvertex();
+                v.inode = inode;
+                v.flag = EdgeFlags::BLOCK;
+                v.start = ChangePosition(contents_.len().into());
+                v.end = v.start;
+                (None, Some(Atom::NewVertex(v)))
+            };
+            Ok(std::mem::replace(
+                current,
+                Some(Hunk::BinaryEdit {
+                    delete,
+                    insert,
+                    local: Local {
+                        path: cap[2].to_string(),
+                        line: cap[3].parse()?,
+                    },
+                    offset: cap.name("offset").unwrap().as_str().parse()?,
+                    delete_len,
                 }),
             ))
         } else if let Some(cap) = REPLACEMENT.captures(h) {
@@ -698,7 +903,7 @@ impl Hunk<Option<Hash>, Local> {
                         path: cap[2].to_string(),
                         line: cap[3].parse().unwrap(),
                     },
-                    encoding: encoding_from_label(cap),
+                    encoding: encoding_from_label(&cap),
                 }),
             ))
         } else if let Some(cap) = FILE_DELETION.captures(h) {
@@ -713,7 +918,7 @@ impl Hunk<Option<Hash>, Local> {
                     del: Atom::EdgeMap(del),
                     contents: None,
                     path: cap[2].to_string(),
-                    encoding: encoding_from_label(cap),
+                    encoding: encoding_from_label(&cap),
                 }),
             ))
         } else if let Some(cap) = FILE_UNDELETION.captures(h) {
@@ -728,7 +933,7 @@ impl Hunk<Option<Hash>, Local> {
                     undel: Atom::EdgeMap(undel),
                     contents: None,
                     path: cap[2].to_string(),
-                    encoding: encoding_from_label(cap),
+                    encoding: encoding_from_label(&cap),
                 }),
             ))
         } else if let Some(cap) = NAME_CONFLICT.captures(h) {
@@ -760,18 +965,9 @@ impl Hunk<Option<Hash>, Local> {
             add.start = ChangePosition(contents_.len().into());
             add.flag = EdgeFlags::FOLDER | EdgeFlags::BLOCK;
             let name = cap.name("new").unwrap().as_str();
-            let meta = if let Some(perm) = cap.name("perm") {
-                debug!("perm = {:?}", perm.as_str());
-                if perm.as_str() == "+dx " {
-                    0o1100
-                } else if perm.as_str() == "+x " {
-                    0o100
-                } else {
-                    0
-                }
-            } else {
-                0
-            };
+            let meta = cap
+                .name("perm")
+                .map_or(0, |perm| parse_perm_marker(perm.as_str()));
             let meta = FileMetadata {
                 metadata: InodeMetadata(meta),
                 basename: name,
@@ -782,12 +978,15 @@ impl Hunk<Option<Hash>, Local> {
 
             let mut del = default_edgemap();
             del.inode = parse_pos(changes, cap.name("inode").unwrap().as_str())?;
+            let del = Atom::EdgeMap(del);
+            let add = Atom::NewVertex(add);
+            let path = cap.name("former").unwrap().as_str().to_string();
             Ok(std::mem::replace(
                 current,
-                Some(Hunk::FileMove {
-                    del: Atom::EdgeMap(del),
-                    add: Atom::NewVertex(add),
-                    path: cap[2].to_string(),
+                Some(if &cap["label"] == "Permissions" {
+                    Hunk::FileMeta { del, add, path }
+                } else {
+                    Hunk::FileMove { del, add, path }
                 }),
             ))
         } else if let Some(cap) = MOVE_.captures(h) {
@@ -796,14 +995,17 @@ impl Hunk<Option<Hash>, Local> {
             }
             let mut add = default_edgemap();
             let mut del = default_edgemap();
-            add.inode = parse_pos(changes, &cap[3])?;
+            add.inode = parse_pos(changes, &cap[4])?;
             del.inode = add.inode;
+            let del = Atom::EdgeMap(del);
+            let add = Atom::EdgeMap(add);
+            let path = cap[3].to_string();
             Ok(std::mem::replace(
                 current,
-                Some(Hunk::FileMove {
-                    del: Atom::EdgeMap(del),
-                    add: Atom::EdgeMap(add),
-                    path: cap[2].to_string(),
+                Some(if &cap[2] == "Permissions" {
+                    Hunk::FileMeta { del, add, path }
+                } else {
+                    Hunk::FileMove { del, add, path }
                 }),
             ))
         } else if let Some(cap) = ORDER_CONFLICT.captures(h) {
@@ -849,7 +1051,22 @@ impl Hunk<Option<Hash>, Local> {
                         path: cap.name("path").unwrap().as_str().parse().unwrap(),
                         line: cap.name("line").unwrap().as_str().parse().unwrap(),
                     },
-                    encoding: encoding_from_label(cap),
+                    encoding: encoding_from_label(&cap),
+                }),
+            ))
+        } else if let Some(cap) = CUSTOM.captures(h) {
+            if has_newvertices(current) {
+                contents_.push(0)
+            }
+            Ok(std::mem::replace(
+                current,
+                Some(Hunk::Custom {
+                    kind: cap.name("kind").unwrap().as_str().to_string(),
+                    payload: Vec::new(),
+                    local: Local {
+                        path: cap.name("path").unwrap().as_str().to_string(),
+                        line: cap.name("line").unwrap().as_str().parse()?,
+                    },
                 }),
             ))
         } else {
@@ -859,6 +1076,18 @@ impl Hunk<Option<Hash>, Local> {
                     ref mut add_name,
                     encoding,
                     ..
+                })
+                | Some(Hunk::FileCopy {
+                    ref mut contents,
+                    ref mut add_name,
+                    encoding,
+                    ..
+                })
+                | Some(Hunk::SubrepoPointer {
+                    ref mut contents,
+                    ref mut add_name,
+                    encoding,
+                    ..
                 }) => {
                     if h.starts_with('+') {
                         if contents.is_none() {
@@ -954,6 +1183,11 @@ impl Hunk<Option<Hash>, Local> {
                     ref mut del,
                     ref mut add,
                     ..
+                })
+                | Some(Hunk::FileMeta {
+                    ref mut del,
+                    ref mut add,
+                    ..
                 }) => {
                     if let Some(edges) = parse_edges(changes, h)? {
                         if edges[0].flag.contains(EdgeFlags::DELETED) {
@@ -1113,6 +1347,54 @@ impl Hunk<Option<Hash>, Local> {
                     }
                     Ok(None)
                 }
+                Some(Hunk::BinaryEdit {
+                    ref mut delete,
+                    ref mut insert,
+                    ..
+                }) => {
+                    let inode = if let Some(a) = delete.as_ref().or(insert.as_ref()) {
+                        a.inode()
+                    } else {
+                        return Ok(None);
+                    };
+                    if let Some(b) = h.strip_prefix("+b") {
+                        if let Some(Atom::NewVertex(ref mut v)) = insert {
+                            if v.start == v.end {
+                                v.start = ChangePosition(contents_.len().into());
+                            }
+                            contents_
+                                .extend(&data_encoding::BASE64.decode(b.trim_end().as_bytes())?);
+                            v.end = ChangePosition(contents_.len().into());
+                        }
+                    } else if let Some(cap) = CONTEXT.captures(h) {
+                        let mut v = default_newvertex();
+                        v.inode = inode;
+                        v.flag = EdgeFlags::BLOCK;
+                        v.start = ChangePosition(contents_.len().into());
+                        v.end = v.start;
+                        v.up_context = parse_pos_vec(changes, offsets, &cap[1])?;
+                        if let Some(cap) = cap.get(7) {
+                            v.down_context = parse_pos_vec(changes, offsets, cap.as_str())?;
+                        }
+                        *insert = Some(Atom::NewVertex(v))
+                    } else if let Some(edges) = parse_edges(changes, h)? {
+                        let edges = Atom::EdgeMap(EdgeMap { inode, edges });
+                        if edges_are_deleted(&edges) {
+                            *delete = Some(edges)
+                        } else {
+                            *insert = Some(edges)
+                        }
+                    }
+                    Ok(None)
+                }
+                Some(Hunk::Custom {
+                    ref mut payload, ..
+                }) => {
+                    if let Some(b) = h.strip_prefix("+b") {
+                        payload.extend(&data_encoding::BASE64.decode(b.trim_end().as_bytes())?);
+                    }
+                    Ok(None)
+                }
                 None => {
                     debug!("current = {:#?}", current);
                     debug!("h = {:?}", h);
@@ -1123,7 +1405,18 @@ impl Hunk<Option<Hash>, Local> {
     }
 }
 
-fn encoding_from_label(cap: Captures) -> Option<Encoding> {
+fn edges_are_deleted(atom: &Atom<Option<Hash>>) -> bool {
+    if let Atom::EdgeMap(ref e) = atom {
+        e.edges
+            .get(0)
+            .map(|e| e.flag.contains(EdgeFlags::DELETED))
+            .unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+fn encoding_from_label(cap: &Captures) -> Option<Encoding> {
     let encoding_label = cap.name("encoding").unwrap().as_str();
     if encoding_label != BINARY_LABEL {
         Some(Encoding::for_label(encoding_label))
@@ -1164,7 +1457,10 @@ pub fn default_edgemap() -> EdgeMap<Option<Hash>> {
 
 pub fn has_newvertices<L>(current: &Option<Hunk<Option<Hash>, L>>) -> bool {
     match current {
-        Some(Hunk::FileAdd { contents: None, .. }) | None => false,
+        Some(Hunk::FileAdd { contents: None, .. })
+        | Some(Hunk::FileCopy { contents: None, .. })
+        | Some(Hunk::SubrepoPointer { contents: None, .. })
+        | None => false,
         Some(rec) => rec.iter().any(|e| matches!(e, Atom::NewVertex(_))),
     }
 }
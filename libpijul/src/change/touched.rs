@@ -0,0 +1,151 @@
+//! Index of the paths touched by a change.
+//!
+//! Listing the files touched by a change normally requires
+//! decompressing and deserializing all its hunks. The paths touched,
+//! along with the kinds of hunks touching them, are also written to
+//! the unhashed section of change files under [`PATHS_KEY`], which
+//! [`Change::read_touched_paths`] reads without reading the other
+//! sections.
+//!
+//! Since the unhashed section isn't covered by the hash of the
+//! change, the index is checked against the hunks whenever a change
+//! is verified against its hash, i.e. when it is received from a
+//! remote.
+use super::*;
+use std::collections::BTreeMap;
+
+/// Key of the path index in the unhashed part of change files.
+pub const PATHS_KEY: &str = "paths";
+
+/// The kind of a [`Hunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum HunkKind {
+    FileMove,
+    FileDel,
+    FileUndel,
+    FileAdd,
+    FileCopy,
+    SolveNameConflict,
+    UnsolveNameConflict,
+    Edit,
+    Replacement,
+    BinaryEdit,
+    SolveOrderConflict,
+    UnsolveOrderConflict,
+    ResurrectZombies,
+    Custom,
+    FileMeta,
+    SubrepoPointer,
+}
+
+impl<H, L> Hunk<H, L> {
+    pub fn hunk_kind(&self) -> HunkKind {
+        match self {
+            Hunk::FileMove { .. } => HunkKind::FileMove,
+            Hunk::FileDel { .. } => HunkKind::FileDel,
+            Hunk::FileUndel { .. } => HunkKind::FileUndel,
+            Hunk::FileAdd { .. } => HunkKind::FileAdd,
+            Hunk::FileCopy { .. } => HunkKind::FileCopy,
+            Hunk::SolveNameConflict { .. } => HunkKind::SolveNameConflict,
+            Hunk::UnsolveNameConflict { .. } => HunkKind::UnsolveNameConflict,
+            Hunk::Edit { .. } => HunkKind::Edit,
+            Hunk::Replacement { .. } => HunkKind::Replacement,
+            Hunk::BinaryEdit { .. } => HunkKind::BinaryEdit,
+            Hunk::SolveOrderConflict { .. } => HunkKind::SolveOrderConflict,
+            Hunk::UnsolveOrderConflict { .. } => HunkKind::UnsolveOrderConflict,
+            Hunk::ResurrectZombies { .. } => HunkKind::ResurrectZombies,
+            Hunk::Custom { .. } => HunkKind::Custom,
+            Hunk::FileMeta { .. } => HunkKind::FileMeta,
+            Hunk::SubrepoPointer { .. } => HunkKind::SubrepoPointer,
+        }
+    }
+}
+
+/// A path touched by a change, and the kinds of the hunks touching
+/// it, sorted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TouchedPath {
+    pub path: String,
+    pub kinds: Vec<HunkKind>,
+}
+
+/// The paths touched by the hunks added so far, see
+/// [`Hashed::touched_paths`].
+#[derive(Debug, Default)]
+pub(crate) struct PathIndex(BTreeMap<String, BTreeSet<HunkKind>>);
+
+impl PathIndex {
+    pub(crate) fn add_hunk<H>(&mut self, hunk: &Hunk<H, Local>) {
+        if let Some(kinds) = self.0.get_mut(hunk.path()) {
+            kinds.insert(hunk.hunk_kind());
+        } else {
+            let mut kinds = BTreeSet::new();
+            kinds.insert(hunk.hunk_kind());
+            self.0.insert(hunk.path().to_string(), kinds);
+        }
+    }
+
+    pub(crate) fn to_paths(&self) -> Vec<TouchedPath> {
+        self.0
+            .iter()
+            .map(|(path, kinds)| TouchedPath {
+                path: path.clone(),
+                kinds: kinds.iter().cloned().collect(),
+            })
+            .collect()
+    }
+}
+
+impl<A> Hashed<Hunk<Option<Hash>, Local>, A> {
+    /// The paths touched by the hunks of this change, sorted by path.
+    pub fn touched_paths(&self) -> Vec<TouchedPath> {
+        let mut index = PathIndex::default();
+        for hunk in self.changes.iter() {
+            index.add_hunk(hunk)
+        }
+        index.to_paths()
+    }
+}
+
+/// Check that the path index of `unhashed`, if any, is the one of the
+/// hunks of `hashed`.
+#[cfg(feature = "zstd")]
+pub(crate) fn check_path_index<A>(
+    unhashed: Option<&serde_json::Value>,
+    hashed: &Hashed<Hunk<Option<Hash>, Local>, A>,
+    hash: &Hash,
+) -> Result<(), ChangeError> {
+    if let Some(paths) = unhashed.and_then(|un| un.get(PATHS_KEY)) {
+        let paths: Vec<TouchedPath> = serde_json::from_value(paths.clone())
+            .map_err(|_| ChangeError::PathIndexMismatch { hash: *hash })?;
+        if paths != hashed.touched_paths() {
+            return Err(ChangeError::PathIndexMismatch { hash: *hash });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+impl Change {
+    /// Read the paths touched by the change in `file` from its
+    /// unhashed section, without decompressing its hunks or contents.
+    /// Returns `None` if the file has no path index, for instance if
+    /// it was written by an older version of Pijul.
+    pub fn read_touched_paths(file: &str) -> Result<Option<Vec<TouchedPath>>, ChangeError> {
+        use std::io::Read;
+        let mut r = std::fs::File::open(file)?;
+        let mut buf = [0u8; Change::OFFSETS_SIZE as usize];
+        r.read_exact(&mut buf)?;
+        let offsets: Offsets = bincode::deserialize(&buf)?;
+        if offsets.version != VERSION && offsets.version != VERSION_NOENC {
+            return Err(ChangeError::VersionMismatch {
+                got: offsets.version,
+            });
+        }
+        let sections = Sections::from_header(&offsets);
+        Ok(read_unhashed(&mut r, &sections)?
+            .as_ref()
+            .and_then(|un| un.get(PATHS_KEY))
+            .and_then(|p| serde_json::from_value(p.clone()).ok()))
+    }
+}
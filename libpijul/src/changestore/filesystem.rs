@@ -1,21 +1,21 @@
 use super::*;
-use crate::change::{Change, ChangeFile};
+use crate::change::{Change, ChangeFile, ChangeMetrics, ChangeWriter, TouchedPath};
 use crate::pristine::{Base32, ChangeId, Hash, Vertex};
-use std::cell::RefCell;
+use parking_lot::Mutex;
 use std::path::{Path, PathBuf};
 
 /// A file system change store.
 pub struct FileSystem {
-    change_cache: RefCell<lru_cache::LruCache<ChangeId, ChangeFile<'static>>>,
+    change_cache: Mutex<lru_cache::LruCache<ChangeId, ChangeFile<'static>>>,
     changes_dir: PathBuf,
 }
 
 impl Clone for FileSystem {
     fn clone(&self) -> Self {
-        let len = self.change_cache.borrow().capacity();
+        let len = self.change_cache.lock().capacity();
         FileSystem {
             changes_dir: self.changes_dir.clone(),
-            change_cache: RefCell::new(lru_cache::LruCache::new(len)),
+            change_cache: Mutex::new(lru_cache::LruCache::new(len)),
         }
     }
 }
@@ -32,6 +32,10 @@ pub enum Error {
     Persist(#[from] tempfile::PersistError),
 }
 
+/// A [`ChangeWriter`] writing to a temporary file of a [`FileSystem`].
+pub type FileChangeWriter =
+    ChangeWriter<std::io::BufWriter<tempfile::NamedTempFile>, std::fs::File>;
+
 pub fn push_filename(changes_dir: &mut PathBuf, hash: &Hash) {
     let h32 = hash.to_base32();
     let (a, b) = h32.split_at(2);
@@ -40,6 +44,13 @@ pub fn push_filename(changes_dir: &mut PathBuf, hash: &Hash) {
     changes_dir.set_extension("change");
 }
 
+/// Like [`push_filename`], for the redacted version of a change (see
+/// [`crate::redact`]), which is never stored as the change itself.
+pub fn push_redacted_filename(changes_dir: &mut PathBuf, hash: &Hash) {
+    push_filename(changes_dir, hash);
+    changes_dir.set_extension("redacted");
+}
+
 pub fn pop_filename(changes_dir: &mut PathBuf) {
     changes_dir.pop();
     changes_dir.pop();
@@ -52,8 +63,27 @@ impl FileSystem {
         path
     }
 
+    pub fn redacted_filename(&self, hash: &Hash) -> PathBuf {
+        let mut path = self.changes_dir.clone();
+        push_redacted_filename(&mut path, hash);
+        path
+    }
+
+    /// The file of change `hash`, or of its redacted version if we
+    /// don't have the change itself.
+    fn path(&self, hash: &Hash) -> PathBuf {
+        let path = self.filename(hash);
+        if std::fs::metadata(&path).is_err() {
+            let redacted = self.redacted_filename(hash);
+            if std::fs::metadata(&redacted).is_ok() {
+                return redacted;
+            }
+        }
+        path
+    }
+
     pub fn has_change(&self, hash: &Hash) -> bool {
-        std::fs::metadata(&self.filename(hash)).is_ok()
+        std::fs::metadata(self.path(hash)).is_ok()
     }
 
     /// Construct a `FileSystem`, starting from the root of the
@@ -70,7 +100,7 @@ impl FileSystem {
         std::fs::create_dir_all(&changes_dir).unwrap();
         FileSystem {
             changes_dir,
-            change_cache: RefCell::new(lru_cache::LruCache::new(cap)),
+            change_cache: Mutex::new(lru_cache::LruCache::new(cap)),
         }
     }
 
@@ -79,13 +109,13 @@ impl FileSystem {
         hash: F,
         change: ChangeId,
     ) -> Result<
-        std::cell::RefMut<lru_cache::LruCache<ChangeId, ChangeFile<'static>>>,
+        parking_lot::MutexGuard<lru_cache::LruCache<ChangeId, ChangeFile<'static>>>,
         crate::change::ChangeError,
     > {
-        let mut change_cache = self.change_cache.borrow_mut();
+        let mut change_cache = self.change_cache.lock();
         if !change_cache.contains_key(&change) {
             let h = hash(change).unwrap();
-            let path = self.filename(&h);
+            let path = self.path(&h);
             debug!("changefile: {:?}", path);
             let p = crate::change::ChangeFile::open(h, &path.to_str().unwrap())?;
             debug!("patch done");
@@ -105,6 +135,33 @@ impl FileSystem {
         Ok(())
     }
 
+    /// Save the change file `buf`, which may be the redacted version
+    /// of change `hash`, checked with
+    /// [`check_redacted`](crate::redact::check_redacted). Redacted
+    /// versions are saved apart from the changes.
+    pub fn save_redacted_from_buf(
+        &self,
+        buf: &[u8],
+        hash: &Hash,
+        change_id: Option<ChangeId>,
+    ) -> Result<(), crate::change::ChangeError> {
+        crate::redact::check_redacted(buf, hash)?;
+        if !crate::redact::is_redacted_file(buf, hash)? {
+            self.save_from_buf_unchecked(buf, hash, change_id)?;
+            return Ok(());
+        }
+        let mut f = tempfile::NamedTempFile::new_in(&self.changes_dir)?;
+        let file_name = self.redacted_filename(hash);
+        use std::io::Write;
+        f.write_all(buf)?;
+        std::fs::create_dir_all(file_name.parent().unwrap())?;
+        f.persist(file_name).map_err(std::io::Error::from)?;
+        if let Some(ref change_id) = change_id {
+            self.change_cache.lock().remove(change_id);
+        }
+        Ok(())
+    }
+
     pub fn save_from_buf_unchecked(
         &self,
         buf: &[u8],
@@ -119,21 +176,48 @@ impl FileSystem {
         std::fs::create_dir_all(file_name.parent().unwrap())?;
         f.persist(file_name)?;
         if let Some(ref change_id) = change_id {
-            self.change_cache.borrow_mut().remove(change_id);
+            self.change_cache.lock().remove(change_id);
         }
         Ok(())
     }
+
+    /// Start writing a change directly into this store, without
+    /// building it in memory. The hunks and contents are spooled to
+    /// temporary files in the changes directory.
+    pub fn change_writer(&self) -> Result<FileChangeWriter, Error> {
+        let f = tempfile::NamedTempFile::new_in(&self.changes_dir)?;
+        let spool = tempfile::tempfile_in(&self.changes_dir)?;
+        let contents_spool = tempfile::tempfile_in(&self.changes_dir)?;
+        Ok(ChangeWriter::with_spools(
+            std::io::BufWriter::new(f),
+            spool,
+            contents_spool,
+        )?)
+    }
+
+    /// Finish writing a change started with
+    /// [`change_writer`](FileSystem::change_writer), and move it to
+    /// its place in the store.
+    pub fn save_change_writer(&self, w: FileChangeWriter) -> Result<Hash, Error> {
+        let (hash, w) = w.finish()?;
+        let f = w.into_inner().map_err(std::io::Error::from)?;
+        let file_name = self.filename(&hash);
+        std::fs::create_dir_all(file_name.parent().unwrap())?;
+        debug!("file_name = {:?}", file_name);
+        f.persist(file_name)?;
+        Ok(hash)
+    }
 }
 
 impl ChangeStore for FileSystem {
     type Error = Error;
     fn has_contents(&self, hash: Hash, change_id: Option<ChangeId>) -> bool {
         if let Some(ref change_id) = change_id {
-            if let Some(l) = self.change_cache.borrow_mut().get_mut(change_id) {
+            if let Some(l) = self.change_cache.lock().get_mut(change_id) {
                 return l.has_contents();
             }
         }
-        let path = self.filename(&hash);
+        let path = self.path(&hash);
         if let Ok(p) = crate::change::ChangeFile::open(hash, &path.to_str().unwrap()) {
             p.has_contents()
         } else {
@@ -142,11 +226,36 @@ impl ChangeStore for FileSystem {
     }
 
     fn get_header(&self, h: &Hash) -> Result<ChangeHeader, Self::Error> {
-        let path = self.filename(h);
+        let path = self.path(h);
         let p = crate::change::ChangeFile::open(*h, &path.to_str().unwrap())?;
         Ok(p.hashed().header.clone())
     }
 
+    fn get_metrics(&self, h: &Hash) -> Result<ChangeMetrics, Self::Error> {
+        let path = self.path(h);
+        let p = crate::change::ChangeFile::open(*h, &path.to_str().unwrap())?;
+        Ok(p.metrics())
+    }
+
+    fn touched_paths(&self, h: &Hash) -> Result<Vec<TouchedPath>, Self::Error> {
+        let path = self.path(h);
+        let path = path.to_str().unwrap();
+        if let Some(paths) = Change::read_touched_paths(path)? {
+            return Ok(paths);
+        }
+        let p = crate::change::ChangeFile::open(*h, path)?;
+        Ok(p.hashed().touched_paths())
+    }
+
+    fn get_size(&self, h: &Hash) -> Result<u64, Self::Error> {
+        Ok(std::fs::metadata(self.path(h))?.len())
+    }
+
+    fn get_contents_len(&self, h: &Hash) -> Result<u64, Self::Error> {
+        let mut f = std::fs::File::open(self.path(h))?;
+        Ok(Change::contents_len(&mut f)?)
+    }
+
     fn get_contents<F: Fn(ChangeId) -> Option<Hash>>(
         &self,
         hash: F,
@@ -175,7 +284,7 @@ impl ChangeStore for FileSystem {
             if key.end <= key.start {
                 return Ok(0);
             }
-            let path = self.filename(&change);
+            let path = self.path(&change);
             let mut p = crate::change::ChangeFile::open(change, &path.to_str().unwrap())?;
             let n = p.read_contents(key.start.into(), buf)?;
             Ok(n)
@@ -214,14 +323,21 @@ impl ChangeStore for FileSystem {
     fn del_change(&self, hash: &Hash) -> Result<bool, Self::Error> {
         let file_name = self.filename(hash);
         debug!("file_name = {:?}", file_name);
-        let result = std::fs::remove_file(&file_name).is_ok();
+        let redacted = std::fs::remove_file(self.redacted_filename(hash)).is_ok();
+        let result = std::fs::remove_file(&file_name).is_ok() || redacted;
         std::fs::remove_dir(file_name.parent().unwrap()).unwrap_or(()); // fails silently if there are still changes with the same 2-letter prefix.
         Ok(result)
     }
     fn get_change(&self, h: &Hash) -> Result<Change, Self::Error> {
-        let file_name = self.filename(h);
+        let file_name = self.path(h);
         let file_name = file_name.to_str().unwrap();
         debug!("file_name = {:?}", file_name);
         Ok(Change::deserialize(&file_name, Some(h))?)
     }
+    fn get_change_without_contents(&self, h: &Hash) -> Result<Change, Self::Error> {
+        let file_name = self.path(h);
+        let file_name = file_name.to_str().unwrap();
+        debug!("file_name = {:?}", file_name);
+        Ok(Change::read_without_contents(&file_name, Some(h))?)
+    }
 }
@@ -4,7 +4,7 @@
 //! database, or something else.
 use crate::pristine::{ChangeId, Hash, InodeMetadata, Position, Vertex};
 use crate::{
-    change::{Change, ChangeHeader},
+    change::{Change, ChangeHeader, ChangeMetrics, TouchedPath},
     text_encoding::Encoding,
 };
 
@@ -41,6 +41,28 @@ pub trait ChangeStore {
         key: Vertex<Option<Hash>>,
         buf: &mut Vec<u8>,
     ) -> Result<usize, Self::Error>;
+    fn get_metrics(&self, h: &Hash) -> Result<ChangeMetrics, Self::Error> {
+        Ok(self.get_change(h)?.hashed.metrics())
+    }
+    /// The paths touched by change `h`, with the kinds of hunks
+    /// touching them, sorted by path. Stores able to read these from
+    /// an index should avoid loading the entire change.
+    fn touched_paths(&self, h: &Hash) -> Result<Vec<TouchedPath>, Self::Error> {
+        Ok(self.get_change_without_contents(h)?.hashed.touched_paths())
+    }
+    /// Space used by change `h` in this store, in bytes. The default
+    /// implementation returns the uncompressed size of the change.
+    fn get_size(&self, h: &Hash) -> Result<u64, Self::Error> {
+        let change = self.get_change(h)?;
+        let hashed =
+            bincode::serialized_size(&change.hashed).map_err(crate::change::ChangeError::from)?;
+        Ok(hashed + change.contents.len() as u64)
+    }
+    /// Size in bytes of the contents of change `h` once loaded.
+    /// Stores should read it without loading the change.
+    fn get_contents_len(&self, h: &Hash) -> Result<u64, Self::Error> {
+        Ok(self.get_change(h)?.contents.len() as u64)
+    }
     fn get_dependencies(&self, hash: &Hash) -> Result<Vec<Hash>, Self::Error> {
         Ok(self.get_change(hash)?.hashed.dependencies)
     }
@@ -75,6 +97,12 @@ pub trait ChangeStore {
     fn save_change(&self, p: &Change) -> Result<Hash, Self::Error>;
     fn del_change(&self, h: &Hash) -> Result<bool, Self::Error>;
     fn get_change(&self, h: &Hash) -> Result<Change, Self::Error>;
+    /// Load a change without its contents, which is all that
+    /// applying a change needs. Stores keeping changes in memory can
+    /// just return the entire change.
+    fn get_change_without_contents(&self, h: &Hash) -> Result<Change, Self::Error> {
+        self.get_change(h)
+    }
     fn get_file_meta<'a, F: Fn(ChangeId) -> Option<Hash>>(
         &self,
         hash: F,
@@ -0,0 +1,227 @@
+//! Waiting for the state of a channel to change.
+//!
+//! Each commit of a mutable transaction notifies a registry shared by
+//! the whole process, with the names of the channels it wrote. A
+//! [`Watch`] returned by [`watch`] blocks ([`Watch::wait`]) or is
+//! awaited ([`Watch::changed`]) until its channel gets notified, and
+//! then reads the state of the channel in a fresh transaction to
+//! check that it actually changed. Notifications are therefore only
+//! hints, and channels with the same name in different pristines
+//! don't confuse watchers.
+//!
+//! Only commits made by the current process are noticed.
+//!
+//! Channels can also be soft-deleted and restored, see
+//! [`soft_delete`].
+use crate::pristine::*;
+use crate::HashMap;
+use parking_lot::{Condvar, Mutex};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+mod trash;
+pub use trash::*;
+
+#[derive(Default)]
+struct Entry {
+    /// Number of notifications of this channel so far.
+    generation: u64,
+    wakers: Vec<Waker>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::default());
+    static ref NOTIFIED: Condvar = Condvar::new();
+}
+
+/// Notify the watchers of `channels`, called after each commit.
+pub(crate) fn notify(channels: &[String]) {
+    if channels.is_empty() {
+        return;
+    }
+    let mut registry = REGISTRY.lock();
+    for c in channels {
+        let e = registry.entry(c.clone()).or_default();
+        e.generation += 1;
+        for w in e.wakers.drain(..) {
+            w.wake()
+        }
+    }
+    NOTIFIED.notify_all();
+}
+
+fn generation(registry: &HashMap<String, Entry>, channel: &str) -> u64 {
+    registry.get(channel).map(|e| e.generation).unwrap_or(0)
+}
+
+#[derive(Debug, Error)]
+pub enum WatchError<E: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Begin(E),
+    #[error(transparent)]
+    Txn(T),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+}
+
+impl<E: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for WatchError<E, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        WatchError::Txn(e.0)
+    }
+}
+
+/// A handle on a channel, remembering the last state seen.
+pub struct Watch<F> {
+    txn_factory: F,
+    channel: String,
+    state: Merkle,
+    generation: u64,
+}
+
+fn read_state<T: TxnT, E: std::error::Error + 'static, F: Fn() -> Result<T, E>>(
+    txn_factory: &F,
+    channel: &str,
+) -> Result<Merkle, WatchError<E, T::GraphError>> {
+    let txn = txn_factory().map_err(WatchError::Begin)?;
+    let c = if let Some(c) = txn.load_channel(channel)? {
+        c
+    } else {
+        return Err(WatchError::ChannelNotFound(channel.to_string()));
+    };
+    let state = current_state(&txn, &*c.read())?;
+    Ok(state)
+}
+
+/// Watch `channel`, starting from its current state. `txn_factory`
+/// is called to start a new read-only transaction each time the
+/// channel is notified.
+pub fn watch<T: TxnT, E: std::error::Error + 'static, F: Fn() -> Result<T, E>>(
+    txn_factory: F,
+    channel: &str,
+) -> Result<Watch<F>, WatchError<E, T::GraphError>> {
+    // Read the generation before the state, so that commits between
+    // the two are not missed.
+    let generation = generation(&REGISTRY.lock(), channel);
+    let state = read_state(&txn_factory, channel)?;
+    Ok(Watch {
+        txn_factory,
+        channel: channel.to_string(),
+        state,
+        generation,
+    })
+}
+
+impl<F> Watch<F> {
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// The last state seen.
+    pub fn state(&self) -> Merkle {
+        self.state
+    }
+}
+
+impl<T: TxnT, E: std::error::Error + 'static, F: Fn() -> Result<T, E>> Watch<F> {
+    /// Read the state of the channel after notification number
+    /// `generation`, returning it if it changed.
+    fn update(&mut self, generation: u64) -> Result<Option<Merkle>, WatchError<E, T::GraphError>> {
+        self.generation = generation;
+        let state = read_state(&self.txn_factory, &self.channel)?;
+        if state != self.state {
+            self.state = state;
+            Ok(Some(state))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Block until the state of the channel changes, and return the
+    /// new state.
+    pub fn wait(&mut self) -> Result<Merkle, WatchError<E, T::GraphError>> {
+        loop {
+            if let Some(state) = self.wait_until(None)? {
+                return Ok(state);
+            }
+        }
+    }
+
+    /// Like [`Watch::wait`], but return `None` if the state hasn't
+    /// changed after `timeout`.
+    pub fn wait_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Merkle>, WatchError<E, T::GraphError>> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Merkle>, WatchError<E, T::GraphError>> {
+        loop {
+            let generation = {
+                let mut registry = REGISTRY.lock();
+                loop {
+                    let g = generation(&registry, &self.channel);
+                    if g != self.generation {
+                        break g;
+                    }
+                    if let Some(deadline) = deadline {
+                        if NOTIFIED.wait_until(&mut registry, deadline).timed_out()
+                            && generation(&registry, &self.channel) == self.generation
+                        {
+                            return Ok(None);
+                        }
+                    } else {
+                        NOTIFIED.wait(&mut registry)
+                    }
+                }
+            };
+            if let Some(state) = self.update(generation)? {
+                return Ok(Some(state));
+            }
+        }
+    }
+
+    /// A future resolving to the new state of the channel when it
+    /// changes.
+    pub fn changed(&mut self) -> Changed<F> {
+        Changed { watch: self }
+    }
+}
+
+/// The future returned by [`Watch::changed`].
+pub struct Changed<'a, F> {
+    watch: &'a mut Watch<F>,
+}
+
+impl<'a, T: TxnT, E: std::error::Error + 'static, F: Fn() -> Result<T, E>> Future
+    for Changed<'a, F>
+{
+    type Output = Result<Merkle, WatchError<E, T::GraphError>>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            let generation = {
+                let mut registry = REGISTRY.lock();
+                let e = registry.entry(self.watch.channel.clone()).or_default();
+                if e.generation == self.watch.generation {
+                    if !e.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                        e.wakers.push(cx.waker().clone())
+                    }
+                    return Poll::Pending;
+                }
+                e.generation
+            };
+            match self.watch.update(generation) {
+                Ok(Some(state)) => return Poll::Ready(Ok(state)),
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Soft deletion of channels.
+//!
+//! [`soft_delete`] marks a channel as deleted in the pristine. The
+//! channel keeps its name and its state, but isn't listed anymore,
+//! and neither [`load_channel`] nor [`open_or_create_channel`] open
+//! it, so that it can't be written to. [`undelete`] removes the mark,
+//! and [`purge`] drops the channels that have been deleted for longer
+//! than a retention period.
+//!
+//! Since a soft-deleted channel keeps its name, no other channel can
+//! be created under that name until it is purged.
+use crate::pristine::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default time during which soft-deleted channels can be restored.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// A soft-deleted channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletedChannel {
+    /// Name of the channel.
+    pub name: String,
+    /// Time of the deletion, in seconds since the Unix epoch.
+    pub deleted_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Error)]
+pub enum SoftDeleteError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+    #[error("Channel {0} is deleted")]
+    Deleted(String),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for SoftDeleteError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        SoftDeleteError::Txn(e.0)
+    }
+}
+
+/// Whether `channel` is soft-deleted. Such channels should not be
+/// shown when listing channels.
+pub fn is_deleted<T: ChannelTxnT + TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    Ok(txn.get_deleted_channel(txn.id(channel))?.is_some())
+}
+
+/// Load channel `name`, returning `None` if it doesn't exist or is
+/// soft-deleted.
+pub fn load_channel<T: TxnT>(
+    txn: &T,
+    name: &str,
+) -> Result<Option<ChannelRef<T>>, TxnErr<T::GraphError>> {
+    if let Some(channel) = txn.load_channel(name)? {
+        if !is_deleted(txn, &*channel.read())? {
+            return Ok(Some(channel));
+        }
+    }
+    Ok(None)
+}
+
+/// Open channel `name`, creating it if it doesn't exist. Fails if
+/// the channel is soft-deleted, since its name is still taken.
+pub fn open_or_create_channel<T: MutTxnT>(
+    txn: &mut T,
+    name: &str,
+) -> Result<ChannelRef<T>, SoftDeleteError<T::GraphError>> {
+    let channel = txn
+        .open_or_create_channel(name)
+        .map_err(SoftDeleteError::Txn)?;
+    if is_deleted(txn, &*channel.read())? {
+        return Err(SoftDeleteError::Deleted(name.to_string()));
+    }
+    Ok(channel)
+}
+
+/// Hide channel `name`, which can then be restored with [`undelete`]
+/// until it gets purged.
+pub fn soft_delete<T: MutTxnT>(
+    txn: &mut T,
+    name: &str,
+) -> Result<DeletedChannel, SoftDeleteError<T::GraphError>> {
+    let channel = if let Some(c) = load_channel(txn, name)? {
+        c
+    } else {
+        return Err(SoftDeleteError::ChannelNotFound(name.to_string()));
+    };
+    let deleted_at = now();
+    let id = *txn.id(&*channel.read());
+    txn.put_deleted_channel(&id, deleted_at)?;
+    Ok(DeletedChannel {
+        name: name.to_string(),
+        deleted_at,
+    })
+}
+
+/// The soft-deleted channels, oldest deletions first.
+pub fn deleted<T: TxnT>(txn: &T) -> Result<Vec<DeletedChannel>, TxnErr<T::GraphError>> {
+    let mut result = Vec::new();
+    for c in txn.iter_channels("")? {
+        let (name, channel) = c?;
+        let id = *txn.id(&*channel.read());
+        if let Some(deleted_at) = txn.get_deleted_channel(&id)? {
+            result.push(DeletedChannel {
+                name: name.as_str().to_string(),
+                deleted_at,
+            })
+        }
+    }
+    result.sort_by_key(|d| d.deleted_at);
+    Ok(result)
+}
+
+/// Restore the soft-deleted channel `name`.
+pub fn undelete<T: MutTxnT>(
+    txn: &mut T,
+    name: &str,
+) -> Result<ChannelRef<T>, SoftDeleteError<T::GraphError>> {
+    if let Some(channel) = txn.load_channel(name)? {
+        let id = *txn.id(&*channel.read());
+        if txn.del_deleted_channel(&id)? {
+            return Ok(channel);
+        }
+    }
+    Err(SoftDeleteError::ChannelNotFound(name.to_string()))
+}
+
+/// Drop the channels soft-deleted more than `retention` ago, and
+/// return them.
+pub fn purge<T: MutTxnT>(
+    txn: &mut T,
+    retention: Duration,
+) -> Result<Vec<DeletedChannel>, SoftDeleteError<T::GraphError>> {
+    let limit = now().saturating_sub(retention.as_secs());
+    let mut purged = Vec::new();
+    for d in deleted(txn)? {
+        if d.deleted_at > limit {
+            break;
+        }
+        debug!("purging channel {:?}", d);
+        let id = *txn.id(&*txn.load_channel(&d.name)?.unwrap().read());
+        txn.del_deleted_channel(&id)?;
+        txn.drop_channel(&d.name).map_err(SoftDeleteError::Txn)?;
+        purged.push(d)
+    }
+    Ok(purged)
+}
@@ -0,0 +1,177 @@
+//! Per-channel defaults for recording changes, stored in the
+//! pristine.
+//!
+//! [`Builder::record`](crate::record::Builder::record) reads the
+//! settings of the channel it records on, and uses them for each
+//! option that wasn't set explicitly on the builder, so that all
+//! clients recording on a channel produce the same changes.
+use crate::diff::Algorithm;
+use crate::pristine::*;
+
+/// How changes to binary files are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryPolicy {
+    /// Diff by chunks, and record byte-range hunks.
+    ByteRanges,
+    /// Diff by chunks, and record regular edits.
+    Chunks,
+    /// Diff by lines, like text files.
+    Lines,
+}
+
+impl Default for BinaryPolicy {
+    fn default() -> Self {
+        BinaryPolicy::ByteRanges
+    }
+}
+
+/// How line endings of text files are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EolPolicy {
+    /// Record line endings as they are in the working copy.
+    Preserve,
+    /// Record CRLF line endings as LF.
+    Lf,
+}
+
+impl Default for EolPolicy {
+    fn default() -> Self {
+        EolPolicy::Preserve
+    }
+}
+
+/// How text files with very long lines or NUL bytes are recorded,
+/// set by the `text` attribute of the files (see
+/// [`crate::attributes`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextPolicy {
+    /// Diff text files by lines, and record files containing NUL
+    /// bytes as binary.
+    Lines,
+    /// Split the lines longer than `max_line` bytes into segments
+    /// before diffing them, and record the files containing a few NUL
+    /// bytes but otherwise valid UTF-8 as text.
+    Hybrid { max_line: usize },
+}
+
+impl Default for TextPolicy {
+    fn default() -> Self {
+        TextPolicy::Lines
+    }
+}
+
+/// Default maximal length of a segment in [`TextPolicy::Hybrid`].
+pub const DEFAULT_MAX_LINE: usize = 4096;
+
+/// The record settings of a channel. `None` fields fall back to the
+/// defaults of this crate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelSettings {
+    pub diff_algorithm: Option<Algorithm>,
+    pub binary: Option<BinaryPolicy>,
+    pub eol: Option<EolPolicy>,
+}
+
+#[derive(Debug, Error)]
+pub enum ChannelSettingsError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed settings for channel {0}")]
+    Malformed(String),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for ChannelSettingsError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        ChannelSettingsError::Txn(e.0)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown policy: {0}")]
+pub struct UnknownPolicy(String);
+
+impl std::str::FromStr for BinaryPolicy {
+    type Err = UnknownPolicy;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "byte-ranges" => Ok(BinaryPolicy::ByteRanges),
+            "chunks" => Ok(BinaryPolicy::Chunks),
+            "lines" => Ok(BinaryPolicy::Lines),
+            _ => Err(UnknownPolicy(s.to_string())),
+        }
+    }
+}
+
+impl std::str::FromStr for EolPolicy {
+    type Err = UnknownPolicy;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(EolPolicy::Preserve),
+            "lf" => Ok(EolPolicy::Lf),
+            _ => Err(UnknownPolicy(s.to_string())),
+        }
+    }
+}
+
+impl std::str::FromStr for TextPolicy {
+    type Err = UnknownPolicy;
+    /// Parse `lines`, `hybrid`, or `hybrid:N` where `N` is the maximal
+    /// length of a segment.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(TextPolicy::Lines),
+            "hybrid" => Ok(TextPolicy::Hybrid {
+                max_line: DEFAULT_MAX_LINE,
+            }),
+            _ => match s.strip_prefix("hybrid:").map(|n| n.parse()) {
+                Some(Ok(max_line)) if max_line > 0 => Ok(TextPolicy::Hybrid { max_line }),
+                _ => Err(UnknownPolicy(s.to_string())),
+            },
+        }
+    }
+}
+
+/// Load the settings of `channel`. Channels without settings get the
+/// default (empty) settings. Settings are stored by channel id, and
+/// are kept when the channel is renamed.
+pub fn load<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<ChannelSettings, ChannelSettingsError<T::GraphError>> {
+    if let Some(bytes) = txn.get_channel_settings(txn.id(channel))? {
+        bincode::deserialize(bytes)
+            .map_err(|_| ChannelSettingsError::Malformed(txn.name(channel).to_string()))
+    } else {
+        Ok(ChannelSettings::default())
+    }
+}
+
+/// Save `settings` as the settings of `channel`. Saving the default
+/// settings deletes the entry of `channel`.
+pub fn save<T: MutTxnT>(
+    txn: &mut T,
+    channel: &T::Channel,
+    settings: &ChannelSettings,
+) -> Result<(), ChannelSettingsError<T::GraphError>> {
+    let id = *txn.id(channel);
+    if *settings == ChannelSettings::default() {
+        txn.del_channel_settings(&id)?;
+    } else {
+        let bytes = bincode::serialize(settings).unwrap();
+        txn.put_channel_settings(&id, &bytes)?;
+    }
+    Ok(())
+}
+
+/// Replace CRLF line endings with LF in `contents`, in place.
+pub(crate) fn normalize_eol(contents: &mut Vec<u8>) {
+    let mut w = 0;
+    for r in 0..contents.len() {
+        if contents[r] == b'\r' && contents.get(r + 1) == Some(&b'\n') {
+            continue;
+        }
+        contents[w] = contents[r];
+        w += 1
+    }
+    contents.truncate(w)
+}
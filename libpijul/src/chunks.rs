@@ -0,0 +1,185 @@
+//! Content-defined chunking of change files, used to transfer only
+//! the parts of a change file that the receiving side doesn't
+//! already have.
+//!
+//! Chunk boundaries are placed where a rolling hash of the last
+//! [`WINDOW`] bytes matches a mask, so that two files sharing a long
+//! run of bytes, such as a partial and a full version of the same
+//! change, also share the chunks of that run. Chunks are identified
+//! by their hash.
+//!
+//! A transfer goes as follows: the sender lists the chunks of a file
+//! ([`split`], [`write_list`]), the receiver answers with the indices
+//! of the chunks missing from its [`ChunkIndex`]
+//! ([`ChunkIndex::missing`]), and reassembles the file from the
+//! missing chunks sent back ([`ChunkIndex::reassemble`]).
+use crate::pristine::{Base32, Hash, Hasher};
+use crate::HashMap;
+use adler32::RollingAdler32;
+
+/// Size of the window of the rolling hash.
+pub const WINDOW: usize = 64;
+/// Minimum size of a chunk, except for the last one of a file.
+pub const MIN_CHUNK: usize = 1 << 11;
+/// Maximum size of a chunk.
+pub const MAX_CHUNK: usize = 1 << 16;
+/// A boundary is placed where the bits of this mask are all set in
+/// the rolling hash, which gives chunks of about 8KiB on average.
+const MASK: u32 = (1 << 13) - 1;
+
+/// A chunk of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    pub hash: Hash,
+    pub len: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum ChunkError {
+    #[error("Malformed chunk list")]
+    MalformedList,
+    #[error("Not enough data to reassemble chunk {index}")]
+    Truncated { index: usize },
+    #[error("Chunk {index} doesn't match its hash")]
+    WrongHash { index: usize },
+    #[error("Unexpected data after the last chunk")]
+    TrailingData,
+}
+
+fn hash(data: &[u8]) -> Hash {
+    let mut h = Hasher::default();
+    h.update(data);
+    h.finish()
+}
+
+/// Length of the first chunk of `data`.
+fn boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let mut i = MIN_CHUNK;
+    let mut ad = RollingAdler32::from_buffer(&data[i - WINDOW..i]);
+    while i < end {
+        // The low half of Adler-32 is a plain sum of the window, the
+        // high half is better mixed.
+        if (ad.hash() >> 16) & MASK == MASK {
+            return i;
+        }
+        ad.remove(WINDOW, data[i - WINDOW]);
+        ad.update(data[i]);
+        i += 1;
+    }
+    end
+}
+
+/// Split `data` into chunks.
+pub fn split(data: &[u8]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let len = boundary(&data[start..]);
+        chunks.push(Chunk {
+            hash: hash(&data[start..start + len]),
+            len,
+        });
+        start += len;
+    }
+    chunks
+}
+
+/// Write a list of chunks, one `<hash> <length>` line per chunk.
+pub fn write_list<W: std::io::Write>(chunks: &[Chunk], mut w: W) -> Result<(), std::io::Error> {
+    for c in chunks {
+        writeln!(w, "{} {}", c.hash.to_base32(), c.len)?;
+    }
+    Ok(())
+}
+
+/// Parse a list of chunks written by [`write_list`].
+pub fn read_list(list: &[u8]) -> Result<Vec<Chunk>, ChunkError> {
+    let list = std::str::from_utf8(list).map_err(|_| ChunkError::MalformedList)?;
+    let mut chunks = Vec::new();
+    for l in list.lines() {
+        let mut l = l.split(' ');
+        if let (Some(h), Some(len), None) = (l.next(), l.next(), l.next()) {
+            if let (Some(hash), Ok(len)) = (Hash::from_base32(h.as_bytes()), len.parse()) {
+                chunks.push(Chunk { hash, len });
+                continue;
+            }
+        }
+        return Err(ChunkError::MalformedList);
+    }
+    Ok(chunks)
+}
+
+/// The chunks known to the receiving side of a transfer.
+#[derive(Default)]
+pub struct ChunkIndex {
+    chunks: HashMap<Hash, Vec<u8>>,
+}
+
+impl ChunkIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the chunks of `data` to the index.
+    pub fn add(&mut self, data: &[u8]) {
+        let mut start = 0;
+        for c in split(data) {
+            self.chunks
+                .entry(c.hash)
+                .or_insert_with(|| data[start..start + c.len].to_vec());
+            start += c.len;
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    /// The indices in `chunks` of the chunks to request from the
+    /// sender. Chunks appearing more than once are only requested
+    /// once, at their first occurrence.
+    pub fn missing(&self, chunks: &[Chunk]) -> Vec<usize> {
+        let mut requested = crate::HashSet::default();
+        let mut missing = Vec::new();
+        for (i, c) in chunks.iter().enumerate() {
+            if !self.chunks.contains_key(&c.hash) && requested.insert(c.hash) {
+                missing.push(i)
+            }
+        }
+        missing
+    }
+
+    /// Reassemble the file made of `chunks`, where `received` is the
+    /// concatenation of the chunks returned by [`ChunkIndex::missing`],
+    /// in order. The received chunks are checked against their hash
+    /// and added to the index.
+    pub fn reassemble(&mut self, chunks: &[Chunk], received: &[u8]) -> Result<Vec<u8>, ChunkError> {
+        let mut result = Vec::with_capacity(chunks.iter().map(|c| c.len).sum());
+        let mut received = received;
+        for (index, c) in chunks.iter().enumerate() {
+            if let Some(data) = self.chunks.get(&c.hash) {
+                result.extend_from_slice(data);
+                continue;
+            }
+            if received.len() < c.len {
+                return Err(ChunkError::Truncated { index });
+            }
+            let (data, rest) = received.split_at(c.len);
+            if hash(data) != c.hash {
+                return Err(ChunkError::WrongHash { index });
+            }
+            result.extend_from_slice(data);
+            self.chunks.insert(c.hash, data.to_vec());
+            received = rest;
+        }
+        if received.is_empty() {
+            Ok(result)
+        } else {
+            Err(ChunkError::TrailingData)
+        }
+    }
+}
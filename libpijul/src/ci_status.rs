@@ -0,0 +1,166 @@
+//! Continuous integration statuses of changes.
+//!
+//! A CI system reports the result of each of its jobs (`build`,
+//! `test`…) on a change as a named [`CiStatus`]. Statuses are stored
+//! in the pristine, by change, so that tools can tell whether a
+//! change is green ([`summary`]) without asking the CI system.
+//!
+//! A change has at most one status of each name: setting a status
+//! again replaces it if it is more recent, and between two statuses
+//! with the same date, the worse state wins. Setting statuses is thus
+//! commutative and idempotent, and statuses received from remotes
+//! are merged with [`import`] in any order, like
+//! [provenance links](crate::provenance).
+use crate::pristine::*;
+
+/// The state of a CI job, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CiState {
+    Success,
+    Pending,
+    /// The job couldn't run, for instance because of an
+    /// infrastructure problem.
+    Error,
+    Failure,
+}
+
+impl std::fmt::Display for CiState {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CiState::Success => write!(fmt, "success"),
+            CiState::Pending => write!(fmt, "pending"),
+            CiState::Error => write!(fmt, "error"),
+            CiState::Failure => write!(fmt, "failure"),
+        }
+    }
+}
+
+/// The status of a CI job on a change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CiStatus {
+    /// Name of the job.
+    pub name: String,
+    pub state: CiState,
+    /// Link to the details of the job.
+    pub url: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl CiStatus {
+    pub fn new(name: &str, state: CiState, url: Option<&str>) -> Self {
+        CiStatus {
+            name: name.to_string(),
+            state,
+            url: url.map(|u| u.to_string()),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+}
+
+/// The statuses of a change, as exchanged with other repositories.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeStatuses {
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub change: Hash,
+    pub statuses: Vec<CiStatus>,
+}
+
+#[derive(Debug, Error)]
+pub enum CiStatusError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed CI statuses for change {}", .0.to_base32())]
+    Malformed(Hash),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for CiStatusError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        CiStatusError::Txn(e.0)
+    }
+}
+
+/// The statuses of `change`, sorted by name.
+pub fn get<T: TxnT>(txn: &T, change: &Hash) -> Result<Vec<CiStatus>, CiStatusError<T::GraphError>> {
+    if let Some(bytes) = txn.get_ci_statuses(change)? {
+        Ok(bincode::deserialize(bytes).map_err(|_| CiStatusError::Malformed(*change))?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// The overall state of `change`: the worst state of its statuses, or
+/// `None` if no status was reported.
+pub fn summary<T: TxnT>(
+    txn: &T,
+    change: &Hash,
+) -> Result<Option<CiState>, CiStatusError<T::GraphError>> {
+    Ok(get(txn, change)?.into_iter().map(|s| s.state).max())
+}
+
+/// The statuses of all changes.
+pub fn list<T: TxnT>(txn: &T) -> Result<Vec<ChangeStatuses>, CiStatusError<T::GraphError>> {
+    let mut result = Vec::new();
+    for (change, bytes) in txn.iter_ci_statuses()? {
+        result.push(ChangeStatuses {
+            change,
+            statuses: bincode::deserialize(bytes).map_err(|_| CiStatusError::Malformed(change))?,
+        })
+    }
+    Ok(result)
+}
+
+/// Set a status of `change`, returning `false` if an equally recent
+/// or more recent status of the same name was kept instead.
+pub fn set<T: MutTxnT>(
+    txn: &mut T,
+    change: &Hash,
+    status: CiStatus,
+) -> Result<bool, CiStatusError<T::GraphError>> {
+    let mut statuses = get(txn, change)?;
+    match statuses.binary_search_by(|s| s.name.cmp(&status.name)) {
+        Ok(i) => {
+            let current = &statuses[i];
+            if (status.timestamp, status.state, &status.url)
+                <= (current.timestamp, current.state, &current.url)
+            {
+                return Ok(false);
+            }
+            statuses[i] = status
+        }
+        Err(i) => statuses.insert(i, status),
+    }
+    txn.put_ci_statuses(change, &bincode::serialize(&statuses).unwrap())?;
+    Ok(true)
+}
+
+/// Merge the statuses of another repository, returning the number of
+/// statuses that changed.
+pub fn import<T: MutTxnT>(
+    txn: &mut T,
+    statuses: Vec<ChangeStatuses>,
+) -> Result<usize, CiStatusError<T::GraphError>> {
+    let mut changed = 0;
+    for c in statuses {
+        for s in c.statuses {
+            if set(txn, &c.change, s)? {
+                changed += 1
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Write `statuses` in the format read by [`read_list`], to send
+/// them to another repository.
+pub fn write_list<W: std::io::Write>(
+    statuses: &[ChangeStatuses],
+    w: W,
+) -> Result<(), serde_json::Error> {
+    serde_json::to_writer(w, statuses)
+}
+
+/// Parse a list of statuses written by [`write_list`].
+pub fn read_list(list: &[u8]) -> Result<Vec<ChangeStatuses>, serde_json::Error> {
+    serde_json::from_slice(list)
+}
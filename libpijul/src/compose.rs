@@ -0,0 +1,457 @@
+//! Several repositories presented as a single tree.
+//!
+//! A [`Composition`] mounts channels of different repositories at
+//! disjoint prefixes of a working copy. Paths of the composed tree are
+//! routed to the component mounted at their longest prefix, so that
+//! recording the composed tree records one change in each component
+//! with pending changes, and outputting it outputs each component
+//! under its prefix. [`Composition::log`] merges the logs of all
+//! components into a single changelog.
+use crate::apply::LocalApplyError;
+use crate::change::{Change, ChangeHeader};
+use crate::changestore::ChangeStore;
+use crate::fs::{FsError, FsErrorC};
+use crate::output::{Conflict, OutputError};
+use crate::pristine::*;
+use crate::record::{Builder, RecordError};
+use crate::subrepo::SubrepoState;
+use crate::working_copy::WorkingCopy;
+
+#[derive(Debug, Error)]
+pub enum ComposeError<
+    C: std::error::Error + 'static,
+    W: std::error::Error + Send + 'static,
+    T: std::error::Error + 'static,
+> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Changestore error: {0}")]
+    Changestore(C),
+    #[error(transparent)]
+    Record(RecordError<C, W, T>),
+    #[error(transparent)]
+    Output(OutputError<C, T, W>),
+    #[error(transparent)]
+    Apply(LocalApplyError<T>),
+}
+
+impl<
+        C: std::error::Error + 'static,
+        W: std::error::Error + Send + 'static,
+        T: std::error::Error + 'static,
+    > From<TxnErr<T>> for ComposeError<C, W, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        ComposeError::Txn(e.0)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RouteError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Fs(#[from] FsError<T>),
+    #[error("Path not in any component: {0}")]
+    NotMounted(String),
+    #[error("Cannot move {from} to {to}: the paths are in different components")]
+    CrossComponent { from: String, to: String },
+}
+
+#[derive(Debug, Error)]
+#[error("Mount point {0:?} overlaps mount point {1:?}")]
+pub struct Overlap(String, String);
+
+/// A channel of a repository, mounted at `prefix` in the composed
+/// tree.
+pub struct Component<T: MutTxnT, P> {
+    pub prefix: String,
+    pub txn: ArcTxn<T>,
+    pub channel: ChannelRef<T>,
+    pub changes: P,
+}
+
+/// Repositories mounted at disjoint prefixes of a single tree.
+pub struct Composition<T: MutTxnT, P> {
+    components: Vec<Component<T, P>>,
+}
+
+/// An entry of the changelog of a [`Composition`].
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// The index of the component containing the change.
+    pub component: usize,
+    pub hash: Hash,
+    pub header: ChangeHeader,
+}
+
+/// `path`, relative to mount point `prefix`, or `None` if `path` is
+/// not under `prefix`.
+fn relative<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    if prefix.is_empty() {
+        Some(path)
+    } else if path == prefix {
+        Some("")
+    } else if path.starts_with(prefix) && path.as_bytes()[prefix.len()] == b'/' {
+        Some(&path[prefix.len() + 1..])
+    } else {
+        None
+    }
+}
+
+fn join(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_string()
+    } else if path.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{}/{}", prefix, path)
+    }
+}
+
+impl<T: MutTxnT, P> Default for Composition<T, P> {
+    fn default() -> Self {
+        Composition {
+            components: Vec::new(),
+        }
+    }
+}
+
+impl<T, P> Composition<T, P>
+where
+    T: MutTxnT + Send + Sync + 'static,
+    T::Channel: Send + Sync + 'static,
+    P: ChangeStore + Clone + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn components(&self) -> &[Component<T, P>] {
+        &self.components
+    }
+
+    /// Mount `component` at its prefix. Mount points can't be nested
+    /// in each other.
+    pub fn mount(&mut self, mut component: Component<T, P>) -> Result<usize, Overlap> {
+        component.prefix = component.prefix.trim_matches('/').to_string();
+        for c in self.components.iter() {
+            if relative(&c.prefix, &component.prefix).is_some()
+                || relative(&component.prefix, &c.prefix).is_some()
+            {
+                return Err(Overlap(component.prefix.clone(), c.prefix.clone()));
+            }
+        }
+        self.components.push(component);
+        Ok(self.components.len() - 1)
+    }
+
+    /// The index of the component containing `path`, and `path`
+    /// relative to that component.
+    pub fn route<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        let path = path.trim_matches('/');
+        self.components
+            .iter()
+            .enumerate()
+            .find_map(|(i, c)| relative(&c.prefix, path).map(|p| (i, p)))
+    }
+
+    fn route_or_err<'a>(
+        &self,
+        path: &'a str,
+    ) -> Result<(usize, &'a str), RouteError<T::GraphError>> {
+        self.route(path)
+            .ok_or_else(|| RouteError::NotMounted(path.to_string()))
+    }
+
+    /// The components with files under `prefix`, along with `prefix`
+    /// relative to each of them.
+    fn under<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        let prefix = prefix.trim_matches('/');
+        self.components
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, c)| {
+                if let Some(p) = relative(&c.prefix, prefix) {
+                    Some((i, p))
+                } else if relative(prefix, &c.prefix).is_some() {
+                    Some((i, ""))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Start tracking file `path` of the composed tree.
+    pub fn add_file(&self, path: &str, salt: u64) -> Result<(), RouteError<T::GraphError>> {
+        let (i, path) = self.route_or_err(path)?;
+        crate::fs::add_inode(
+            &mut *self.components[i].txn.write(),
+            None,
+            path,
+            false,
+            salt,
+        )?;
+        Ok(())
+    }
+
+    /// Start tracking directory `path` of the composed tree.
+    pub fn add_dir(&self, path: &str, salt: u64) -> Result<(), RouteError<T::GraphError>> {
+        let (i, path) = self.route_or_err(path)?;
+        crate::fs::add_inode(&mut *self.components[i].txn.write(), None, path, true, salt)?;
+        Ok(())
+    }
+
+    /// Move `from` to `to`, which must be in the same component.
+    pub fn move_file(
+        &self,
+        from: &str,
+        to: &str,
+        salt: u64,
+    ) -> Result<(), RouteError<T::GraphError>> {
+        let (i, from_) = self.route_or_err(from)?;
+        let (j, to_) = self.route_or_err(to)?;
+        if i != j {
+            return Err(RouteError::CrossComponent {
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+        crate::fs::move_file(&mut *self.components[i].txn.write(), from_, to_, salt)?;
+        Ok(())
+    }
+
+    /// Stop tracking `path`.
+    pub fn remove_file(&self, path: &str) -> Result<(), RouteError<T::GraphError>> {
+        let (i, path) = self.route_or_err(path)?;
+        crate::fs::remove_file(&mut *self.components[i].txn.write(), path)?;
+        Ok(())
+    }
+
+    /// Record the changes under `prefix` in `working_copy`, which
+    /// contains the composed tree. Each component with pending
+    /// changes gets its own change with header `header`, which is
+    /// saved and applied to its channel. Returns the index of these
+    /// components, along with the hash of their change.
+    pub fn record<W>(
+        &self,
+        working_copy: &W,
+        prefix: &str,
+        diff_algorithm: crate::diff::Algorithm,
+        header: &ChangeHeader,
+    ) -> Result<Vec<(usize, Hash)>, ComposeError<P::Error, W::Error, T::GraphError>>
+    where
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        W::Error: 'static,
+    {
+        let mut recorded = Vec::new();
+        for (i, prefix) in self.under(prefix) {
+            let c = &self.components[i];
+            let mut builder = Builder::new();
+            builder
+                .record(
+                    c.txn.clone(),
+                    diff_algorithm,
+                    c.channel.clone(),
+                    &Mounted::new(&c.prefix, working_copy.clone()),
+                    &c.changes,
+                    prefix,
+                    1,
+                )
+                .map_err(ComposeError::Record)?;
+            let rec = builder
+                .finish()
+                .map_err(|e| ComposeError::Record(e.into()))?;
+            if rec.actions.is_empty() {
+                continue;
+            }
+            let actions = {
+                let txn = c.txn.read();
+                rec.actions
+                    .into_iter()
+                    .map(|rec| rec.globalize(&*txn))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(ComposeError::Txn)?
+            };
+            let change = Change::make_change(
+                &*c.txn.read(),
+                &c.channel,
+                actions,
+                std::mem::take(&mut *rec.contents.lock()),
+                header.clone(),
+                Vec::new(),
+            )?;
+            let hash = c
+                .changes
+                .save_change(&change)
+                .map_err(ComposeError::Changestore)?;
+            crate::apply::apply_local_change(
+                &mut *c.txn.write(),
+                &c.channel,
+                &change,
+                &hash,
+                &rec.updatables,
+            )
+            .map_err(ComposeError::Apply)?;
+            recorded.push((i, hash))
+        }
+        Ok(recorded)
+    }
+
+    /// Output the files under `prefix` of all components to
+    /// `working_copy`. The paths of the conflicts are relative to
+    /// their component.
+    pub fn output<W>(
+        &self,
+        working_copy: &W,
+        prefix: &str,
+    ) -> Result<Vec<(usize, Conflict)>, ComposeError<P::Error, W::Error, T::GraphError>>
+    where
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        W::Error: 'static,
+    {
+        let mut conflicts = Vec::new();
+        for (i, prefix) in self.under(prefix) {
+            let c = &self.components[i];
+            for conflict in crate::output::output_repository_no_pending(
+                &Mounted::new(&c.prefix, working_copy.clone()),
+                &c.changes,
+                &c.txn,
+                &c.channel,
+                prefix,
+                true,
+                None,
+                1,
+                0,
+            )
+            .map_err(ComposeError::Output)?
+            {
+                conflicts.push((i, conflict))
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// The changes of all components, most recent first according to
+    /// their timestamps.
+    pub fn log(&self) -> Result<Vec<LogEntry>, FsErrorC<P::Error, T::GraphError>> {
+        let mut log = Vec::new();
+        for (i, c) in self.components.iter().enumerate() {
+            let txn = c.txn.read();
+            let channel = c.channel.read();
+            for x in changeid_log(&*txn, &*channel, L64(0))? {
+                let (_, p) = x?;
+                let hash: Hash = txn.get_external(&p.a)?.unwrap().into();
+                let header = c.changes.get_header(&hash).map_err(FsErrorC::Changestore)?;
+                log.push(LogEntry {
+                    component: i,
+                    hash,
+                    header,
+                })
+            }
+        }
+        // The sort is stable, so changes with the same timestamp stay
+        // in the order of their component's log.
+        log.sort_by(|a, b| b.header.timestamp.cmp(&a.header.timestamp));
+        Ok(log)
+    }
+}
+
+/// The part of a working copy under `prefix`, as seen by the
+/// component mounted there.
+#[derive(Clone)]
+pub struct Mounted<W> {
+    prefix: String,
+    inner: W,
+}
+
+impl<W> Mounted<W> {
+    pub fn new(prefix: &str, inner: W) -> Self {
+        Mounted {
+            prefix: prefix.trim_matches('/').to_string(),
+            inner,
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        join(&self.prefix, path)
+    }
+}
+
+impl<W: WorkingCopy> WorkingCopy for Mounted<W> {
+    type Error = W::Error;
+    type Writer = W::Writer;
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        self.inner.create_dir_all(&self.path(path))
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        self.inner.file_metadata(&self.path(file))
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.read_file(&self.path(file), buffer)
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        self.inner.modified_time(&self.path(file))
+    }
+    fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error> {
+        self.inner.remove_path(&self.path(name), rec)
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        self.inner.rename(&self.path(former), &self.path(new))
+    }
+    fn permission_mask(&self) -> u16 {
+        self.inner.permission_mask()
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.inner.conflict_options()
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        self.inner.set_permissions(&self.path(name), permissions)
+    }
+    fn output_hook(&self, name: &str, meta: InodeMetadata) -> Result<(), Self::Error> {
+        self.inner.output_hook(&self.path(name), meta)
+    }
+    fn create_symlink(&self, name: &str, target: &str) -> Result<bool, Self::Error> {
+        self.inner.create_symlink(&self.path(name), target)
+    }
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        self.inner.read_dir(&self.path(path))
+    }
+    fn subrepo_state(&self, path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        self.inner.subrepo_state(&self.path(path))
+    }
+    fn sync(&self) -> Result<(), Self::Error> {
+        self.inner.sync()
+    }
+    fn is_writable(&self, path: &str) -> Result<bool, Self::Error> {
+        self.inner.is_writable(&self.path(path))
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        self.inner.available_space()
+    }
+    fn detect_encoding(
+        &self,
+        file: &str,
+        contents: &[u8],
+        last: bool,
+    ) -> Option<crate::text_encoding::Encoding> {
+        self.inner.detect_encoding(&self.path(file), contents, last)
+    }
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        self.inner.write_file(&self.path(file))
+    }
+    fn decode_file(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<crate::text_encoding::Encoding>, Self::Error> {
+        self.inner.decode_file(&self.path(file), buffer)
+    }
+    fn decode_file_with_stats(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+        stats: Option<&crate::file_stats::FileStats>,
+    ) -> Result<Option<crate::text_encoding::Encoding>, Self::Error> {
+        self.inner
+            .decode_file_with_stats(&self.path(file), buffer, stats)
+    }
+}
@@ -0,0 +1,207 @@
+//! Duplicated contents across the files and changes of a repository.
+//!
+//! The contents added by the changes of all channels are split into
+//! the same fixed-size chunks as the diff of binary files, and
+//! indexed by chunk hash along with the change and file that added
+//! them. A large asset committed twice, under two names or in two
+//! changes, yields the same chunks.
+//!
+//! [`DedupIndex::stats`] tells how much of the contents is
+//! duplicated, which estimates what storing chunks only once would
+//! save, and [`DedupIndex::duplicates`] lists the duplicated chunks
+//! and where they occur.
+use crate::change::{Atom, Change, Hunk};
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+use crate::{HashMap, HashSet};
+
+#[derive(Debug, Error)]
+pub enum DedupError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Changestore error: {0}")]
+    Changestore(C),
+    #[error("Change {0:?} has no hash")]
+    MissingHash(ChangeId),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for DedupError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        DedupError::Txn(e.0)
+    }
+}
+
+/// A place where a chunk was added.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Occurrence {
+    pub change: Hash,
+    pub path: String,
+}
+
+/// A chunk found more than once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    pub hash: Hash,
+    pub len: usize,
+    pub occurrences: Vec<Occurrence>,
+}
+
+impl Duplicate {
+    /// Bytes that storing this chunk once would save.
+    pub fn wasted(&self) -> u64 {
+        (self.len * (self.occurrences.len() - 1)) as u64
+    }
+}
+
+/// Totals of a [`DedupIndex`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Number of chunks indexed, counting repetitions.
+    pub chunks: usize,
+    /// Number of distinct chunks.
+    pub unique_chunks: usize,
+    /// Size of the contents indexed, counting repetitions.
+    pub total_bytes: u64,
+    /// Size of the distinct chunks.
+    pub unique_bytes: u64,
+}
+
+impl DedupStats {
+    /// Bytes that storing each chunk once would save.
+    pub fn duplicated_bytes(&self) -> u64 {
+        self.total_bytes - self.unique_bytes
+    }
+}
+
+struct Entry {
+    len: usize,
+    occurrences: Vec<Occurrence>,
+}
+
+/// An index of chunks, by hash.
+#[derive(Default)]
+pub struct DedupIndex {
+    chunks: HashMap<Hash, Entry>,
+    stats: DedupStats,
+}
+
+impl DedupIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index `contents`, added to `path` by `change`.
+    pub fn add(&mut self, change: Hash, path: &str, contents: &[u8]) {
+        for c in crate::diff::binary_chunks(contents) {
+            let mut hasher = Hasher::default();
+            hasher.update(c);
+            self.stats.chunks += 1;
+            self.stats.total_bytes += c.len() as u64;
+            let e = self.chunks.entry(hasher.finish()).or_insert_with(|| Entry {
+                len: c.len(),
+                occurrences: Vec::new(),
+            });
+            e.occurrences.push(Occurrence {
+                change,
+                path: path.to_string(),
+            })
+        }
+    }
+
+    /// Index the contents added by the hunks of `change`, whose hash
+    /// is `hash`. File names and other metadata are not indexed.
+    pub fn add_change(&mut self, hash: Hash, change: &Change) {
+        for hunk in change.changes.iter() {
+            let atom = match hunk {
+                Hunk::FileAdd {
+                    contents: Some(c), ..
+                }
+                | Hunk::FileCopy {
+                    contents: Some(c), ..
+                }
+                | Hunk::SubrepoPointer {
+                    contents: Some(c), ..
+                } => c,
+                Hunk::Edit { change, .. } => change,
+                Hunk::Replacement { replacement, .. } => replacement,
+                Hunk::BinaryEdit {
+                    insert: Some(i), ..
+                } => i,
+                _ => continue,
+            };
+            if let Atom::NewVertex(ref n) = atom {
+                let start = n.start.0.as_usize();
+                let end = n.end.0.as_usize();
+                if start < end && end <= change.contents.len() {
+                    self.add(hash, hunk.path(), &change.contents[start..end])
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            unique_chunks: self.chunks.len(),
+            unique_bytes: self.chunks.values().map(|e| e.len as u64).sum(),
+            ..self.stats
+        }
+    }
+
+    /// The chunks of at least `min_len` bytes found more than once,
+    /// the ones wasting the most space first.
+    pub fn duplicates(&self, min_len: usize) -> Vec<Duplicate> {
+        let mut result: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|(_, e)| e.len >= min_len && e.occurrences.len() > 1)
+            .map(|(h, e)| {
+                let mut occurrences = e.occurrences.clone();
+                occurrences.sort();
+                Duplicate {
+                    hash: *h,
+                    len: e.len,
+                    occurrences,
+                }
+            })
+            .collect();
+        result.sort_by(|a, b| {
+            b.wasted()
+                .cmp(&a.wasted())
+                .then_with(|| a.occurrences.cmp(&b.occurrences))
+        });
+        result
+    }
+}
+
+/// Index the contents of the changes of all the channels, each
+/// change counted once.
+pub fn index<T: TxnT, P: ChangeStore>(
+    txn: &T,
+    changes: &P,
+) -> Result<DedupIndex, DedupError<P::Error, T::GraphError>> {
+    let mut hashes = Vec::new();
+    let mut seen = HashSet::default();
+    for c in txn.iter_channels("")? {
+        let (_, c) = c?;
+        let c = c.read();
+        for x in changeid_log(txn, &*c, L64(0))? {
+            let (_, p) = x?;
+            let h: Hash = txn
+                .get_external(&p.a)?
+                .ok_or(DedupError::MissingHash(p.a))?
+                .into();
+            if seen.insert(h) {
+                hashes.push(h)
+            }
+        }
+    }
+    let mut index = DedupIndex::new();
+    for h in hashes {
+        let change = changes.get_change(&h).map_err(DedupError::Changestore)?;
+        index.add_change(h, &change)
+    }
+    debug!("dedup stats: {:?}", index.stats());
+    Ok(index)
+}
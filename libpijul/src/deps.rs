@@ -0,0 +1,47 @@
+//! The dependency graph of the changes on a channel.
+use crate::pristine::*;
+use crate::HashMap;
+
+/// Group the changes of `channel` into layers, where each change is
+/// in the layer following the last layer containing one of its
+/// dependencies. Changes in the same layer don't depend on each
+/// other, and can be applied in parallel once the previous layers
+/// are applied.
+///
+/// Layers are sorted by hash, so that two channels with the same
+/// changes have the same layers, regardless of the order in which
+/// the changes were applied.
+pub fn topo_layers<T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<Vec<Vec<Hash>>, TxnErr<T::GraphError>> {
+    let mut layer_of = HashMap::default();
+    let mut layers: Vec<Vec<Hash>> = Vec::new();
+    // Changes are applied after their dependencies, hence the log
+    // visits dependencies first.
+    for x in changeid_log(txn, channel, L64(0))? {
+        let (_, p) = x?;
+        let id = p.a;
+        let mut layer = 0;
+        for y in txn.iter_dep(&id)? {
+            let (id_, dep) = y?;
+            if *id_ < id {
+                continue;
+            } else if *id_ > id {
+                break;
+            }
+            if let Some(&l) = layer_of.get(dep) {
+                layer = layer.max(l + 1)
+            }
+        }
+        layer_of.insert(id, layer);
+        if layer >= layers.len() {
+            layers.push(Vec::new())
+        }
+        layers[layer].push(txn.get_external(&id)?.unwrap().into())
+    }
+    for layer in layers.iter_mut() {
+        layer.sort_unstable()
+    }
+    Ok(layers)
+}
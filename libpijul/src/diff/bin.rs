@@ -2,6 +2,12 @@ use adler32::*;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
+/// The blocks of `a` matched by the diff, of `window` bytes each
+/// except the last one.
+pub(super) fn blocks(window: usize, a: &[u8]) -> std::slice::Chunks<u8> {
+    a.chunks(window)
+}
+
 pub(super) fn make_old_chunks(
     window: usize,
     a: &[u8],
@@ -9,7 +15,7 @@ pub(super) fn make_old_chunks(
     let mut a_ad = 0;
     let mut a_h = HashMap::with_capacity(a.len() / window + 1);
     let mut lines = Vec::new();
-    'outer: for ch in a.chunks(window) {
+    'outer: for ch in blocks(window, a) {
         debug!("chunk {:?}", ch.len());
         lines.push(super::Line {
             l: ch,
@@ -112,6 +118,126 @@ pub(super) fn make_new_chunks<'a>(
     (bb, lines)
 }
 
+/// An incremental version of [`make_new_chunks`], fed with the new
+/// version of a file in windows. Only the bytes not found in the old
+/// version, and the bytes of the current window, are kept.
+pub(super) struct NewChunks<'a, 'h> {
+    window: usize,
+    a_h: &'h HashMap<u32, Vec<(usize, &'a [u8])>>,
+    /// The bytes received and not yet assigned to a chunk, from
+    /// `buf[pos]` on.
+    buf: Vec<u8>,
+    pos: usize,
+    /// Hash of the current window, and its length. `None` when it
+    /// needs to be recomputed, after a chunk of the old version.
+    ad: Option<(RollingAdler32, usize)>,
+    chunks: Vec<NewChunk<'a>>,
+    /// Contents of the `NewChunk::New` chunks.
+    new: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(super) enum NewChunk<'a> {
+    Old(&'a [u8]),
+    New { start: usize, len: usize },
+}
+
+impl<'a, 'h> NewChunks<'a, 'h> {
+    pub fn new(window: usize, a_h: &'h HashMap<u32, Vec<(usize, &'a [u8])>>) -> Self {
+        NewChunks {
+            window,
+            a_h,
+            buf: Vec::new(),
+            pos: 0,
+            ad: None,
+            chunks: Vec::new(),
+            new: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        while self.pos + self.window < self.buf.len() {
+            self.step()
+        }
+        if self.pos >= self.buf.len() / 2 {
+            self.buf.drain(..self.pos);
+            self.pos = 0
+        }
+    }
+
+    /// The chunks of the new version, and the contents of the new
+    /// chunks.
+    pub fn finish(mut self) -> (Vec<NewChunk<'a>>, Vec<u8>) {
+        while self.pos < self.buf.len() {
+            self.step()
+        }
+        (self.chunks, self.new)
+    }
+
+    /// Assign either a whole window to a chunk of the old version, or
+    /// a single byte to a new chunk.
+    fn step(&mut self) {
+        let (mut ad, len) = if let Some(ad) = self.ad.take() {
+            ad
+        } else {
+            let len = self.window.min(self.buf.len() - self.pos);
+            let w = &self.buf[self.pos..self.pos + len];
+            (RollingAdler32::from_buffer(w), len)
+        };
+        let w = &self.buf[self.pos..self.pos + len];
+        if let Some(v) = self.a_h.get(&ad.hash()) {
+            if let Some(&(_, old)) = v.iter().find(|(_, old)| *old == w) {
+                self.chunks.push(NewChunk::Old(old));
+                self.pos += len;
+                return;
+            }
+        }
+        let c = self.buf[self.pos];
+        if let Some(NewChunk::New { ref mut len, .. }) = self.chunks.last_mut() {
+            *len += 1
+        } else {
+            self.chunks.push(NewChunk::New {
+                start: self.new.len(),
+                len: 1,
+            })
+        }
+        self.new.push(c);
+        ad.remove(len, c);
+        self.pos += 1;
+        let mut len = len - 1;
+        if self.pos + len < self.buf.len() {
+            ad.update(self.buf[self.pos + len]);
+            len += 1
+        }
+        self.ad = Some((ad, len))
+    }
+}
+
+/// The lines of the new version, from the result of [`NewChunks`]
+/// or [`super::split::NewLines`]. Unlike the lines returned by [`make_new_chunks`], these lines are
+/// not contiguous.
+pub(super) fn new_chunk_lines<'a>(chunks: &[NewChunk<'a>], new: &'a [u8]) -> Vec<super::Line<'a>> {
+    let mut lines: Vec<_> = chunks
+        .iter()
+        .map(|chunk| match *chunk {
+            NewChunk::Old(old) => super::Line {
+                l: old,
+                ptr: old.as_ptr(),
+                ..super::Line::default()
+            },
+            NewChunk::New { start, len } => super::Line {
+                l: &new[start..start + len],
+                ..super::Line::default()
+            },
+        })
+        .collect();
+    if let Some(l) = lines.last_mut() {
+        l.last = true
+    }
+    lines
+}
+
 #[derive(Debug)]
 pub(super) enum Chunk {
     Old {
@@ -0,0 +1,36 @@
+//! Coalescing of nearby replacements: diff algorithms sometimes find
+//! many small replacements separated by a few unchanged lines, for
+//! instance when most lines of a block were edited. Recording them as
+//! a single replacement gives fewer, larger hunks, each with its own
+//! context, and therefore fewer dependencies.
+use super::diff::{Replacement, D};
+use super::Line;
+
+/// Join the replacements of `dd` separated by at most `distance`
+/// unchanged lines of `lines_a`, which are then deleted and inserted
+/// again. Lines with conflict markers or in cyclic conflicts are never
+/// joined, since they can't be deleted like other lines.
+pub(super) fn coalesce(lines_a: &[Line], dd: D, distance: usize) -> D {
+    let mut result: Vec<Replacement> = Vec::with_capacity(dd.len());
+    for r in dd.0 {
+        if let Some(last) = result.last_mut() {
+            let gap = last.old + last.old_len..r.old;
+            if !last.is_cyclic
+                && !r.is_cyclic
+                && gap.len() <= distance
+                && lines_a[gap].iter().all(joinable)
+            {
+                last.old_len = r.old + r.old_len - last.old;
+                last.new_len = r.new + r.new_len - last.new;
+                continue;
+            }
+        }
+        result.push(r)
+    }
+    debug!("coalesced: {:?}", result);
+    D(result)
+}
+
+fn joinable(l: &Line) -> bool {
+    l.marker.is_none() && !l.cyclic && !l.before_end_marker
+}
@@ -237,10 +237,12 @@ impl Recorded {
         let up_context = super::replace::get_up_context(diff, conflict_contexts, lines_a, old);
 
         let mut contents = self.contents.lock();
+        let chunk_start = contents.len();
         contents.push(0);
         let pos = ChangePosition(contents.len().into());
         contents.push(0);
         let contents_len = contents.len();
+        self.chunks.push(chunk_start..contents_len);
         std::mem::drop(contents);
 
         let down_context = if is_replaced {
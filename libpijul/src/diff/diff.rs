@@ -1,5 +1,5 @@
 use super::Line;
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 /// Algorithm used to compute the diff.
 pub enum Algorithm {
     Myers,
@@ -12,32 +12,83 @@ impl Default for Algorithm {
     }
 }
 
-pub(super) fn diff(lines_a: &[Line], lines_b: &[Line], algorithm: Algorithm) -> D {
-    let mut dd = diffs::Replace::new(D(Vec::with_capacity(lines_a.len() + lines_b.len())));
-    match algorithm {
-        Algorithm::Patience => diffs::patience::diff(
-            &mut dd,
-            lines_a,
-            0,
-            lines_a.len(),
-            lines_b,
-            0,
-            lines_b.len(),
-        )
-        .unwrap(),
-        Algorithm::Myers => diffs::myers::diff(
-            &mut dd,
-            lines_a,
-            0,
-            lines_a.len(),
-            lines_b,
-            0,
-            lines_b.len(),
-        )
-        .unwrap(),
+#[derive(Debug, Error)]
+#[error("Unknown diff algorithm: {0}")]
+pub struct UnknownAlgorithm(String);
+
+impl std::str::FromStr for Algorithm {
+    type Err = UnknownAlgorithm;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "myers" => Ok(Algorithm::Myers),
+            "patience" => Ok(Algorithm::Patience),
+            _ => Err(UnknownAlgorithm(s.to_string())),
+        }
+    }
+}
+
+/// A strategy matching the lines of two versions of a file. This is
+/// implemented by [`Algorithm`], and can be implemented by other
+/// crates to record with their own strategies (for instance a
+/// histogram diff), by passing them to
+/// [`Builder::record`](crate::record::Builder::record).
+///
+/// Lines are compared with `==`, which takes conflict markers into
+/// account, and ignores the trailing newline of the lines printed
+/// just before an end marker. Implementations hashing lines should
+/// use the `Hash` implementation of [`Line`], which is consistent
+/// with `==`, rather than hash [`Line::contents`].
+pub trait DiffAlgorithm: Send + Sync {
+    /// The replacements turning `a` into `b`, ordered by position and
+    /// not overlapping. Lines outside the replacements must be equal
+    /// in both versions.
+    fn diff(&self, a: &[Line], b: &[Line]) -> Vec<Replacement>;
+}
+
+impl DiffAlgorithm for Algorithm {
+    fn diff(&self, a: &[Line], b: &[Line]) -> Vec<Replacement> {
+        let mut dd = diffs::Replace::new(D(Vec::with_capacity(a.len() + b.len())));
+        match *self {
+            Algorithm::Patience => {
+                diffs::patience::diff(&mut dd, a, 0, a.len(), b, 0, b.len()).unwrap()
+            }
+            Algorithm::Myers => diffs::myers::diff(&mut dd, a, 0, a.len(), b, 0, b.len()).unwrap(),
+        }
+        dd.into_inner().0
+    }
+}
+
+/// The diff algorithm named in the [settings](crate::channel_settings)
+/// of the channel being recorded, or [`Algorithm::default`] if the
+/// channel doesn't name one. Any other algorithm passed to
+/// [`Builder::record`](crate::record::Builder::record) takes
+/// precedence over the settings of the channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelAlgorithm;
+
+impl DiffAlgorithm for ChannelAlgorithm {
+    fn diff(&self, a: &[Line], b: &[Line]) -> Vec<Replacement> {
+        Algorithm::default().diff(a, b)
+    }
+}
+
+impl<A: DiffAlgorithm + ?Sized> DiffAlgorithm for std::sync::Arc<A> {
+    fn diff(&self, a: &[Line], b: &[Line]) -> Vec<Replacement> {
+        (**self).diff(a, b)
     }
-    dd.into_inner()
 }
+
+pub(super) fn diff(lines_a: &[Line], lines_b: &[Line], algorithm: &dyn DiffAlgorithm) -> D {
+    let dd = algorithm.diff(lines_a, lines_b);
+    debug_assert!(dd
+        .windows(2)
+        .all(|w| w[0].old + w[0].old_len <= w[1].old && w[0].new + w[0].new_len <= w[1].new));
+    debug_assert!(dd
+        .iter()
+        .all(|r| r.old + r.old_len <= lines_a.len() && r.new + r.new_len <= lines_b.len()));
+    D(dd)
+}
+
 #[derive(Debug)]
 pub struct D(pub Vec<Replacement>);
 
@@ -60,7 +111,10 @@ impl std::ops::IndexMut<usize> for D {
     }
 }
 
-#[derive(Debug)]
+/// Lines `old..old + old_len` of the old version replaced by lines
+/// `new..new + new_len` of the new version. One of the lengths may
+/// be 0, for insertions and deletions.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Replacement {
     pub old: usize,
     pub old_len: usize,
@@ -69,6 +123,18 @@ pub struct Replacement {
     pub is_cyclic: bool,
 }
 
+impl Replacement {
+    pub fn new(old: usize, old_len: usize, new: usize, new_len: usize) -> Self {
+        Replacement {
+            old,
+            old_len,
+            new,
+            new_len,
+            is_cyclic: false,
+        }
+    }
+}
+
 impl diffs::Diff for D {
     type Error = ();
     fn delete(&mut self, old: usize, old_len: usize, new: usize) -> std::result::Result<(), ()> {
@@ -0,0 +1,178 @@
+//! Changed lines of a file of the working copy, for the change
+//! gutters of editors.
+//!
+//! [`working_copy_gutter`] diffs a file of the working copy against
+//! its version in a channel. Editors call this after each save, which
+//! is usually far more often than the channel changes, so the
+//! versions output from the pristine are kept in a small process-wide
+//! cache, keyed by the inode vertex of the file, in terms of change
+//! hashes rather than of the internal identifiers of a pristine, and
+//! by the digest of the changes touching it (see
+//! [`crate::file_stats::history`]). The graph of the file is only
+//! retrieved again if one of these changes was applied or unrecorded.
+use super::diff::{self, Algorithm};
+use crate::changestore::ChangeStore;
+use crate::fs::FsError;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use crate::HashMap;
+use parking_lot::Mutex;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Maximal number of versions in the cache, which is cleared when
+/// full.
+const CACHE_SIZE: usize = 64;
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<(Position<Hash>, Hash), Arc<Vec<u8>>>> =
+        Mutex::new(HashMap::default());
+}
+
+/// How the lines of a [`LineChange`] changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// Lines `old` of the channel version were replaced with lines `new`
+/// of the working copy. Lines are numbered from 0. `old` is empty for
+/// added lines, and `new` is empty for removed lines, in which case
+/// its start is the line of the working copy before which they were
+/// removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineChange {
+    pub kind: LineChangeKind,
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+#[derive(Debug, Error)]
+pub enum GutterError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + 'static,
+> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Fs(#[from] FsError<T>),
+    #[error(transparent)]
+    File(#[from] FileError<C, T>),
+    #[error(transparent)]
+    WorkingCopy(W),
+}
+
+impl<
+        C: std::error::Error + 'static,
+        T: std::error::Error + 'static,
+        W: std::error::Error + 'static,
+    > From<TxnErr<T>> for GutterError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        GutterError::Txn(e.0)
+    }
+}
+
+/// The lines of `path` in `working_copy` that differ from its version
+/// in `channel`, ordered by position. Files that aren't in `channel`
+/// yet are entirely added, and binary files have no lines.
+pub fn working_copy_gutter<T, C, W>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    working_copy: &W,
+    path: &str,
+) -> Result<Vec<LineChange>, GutterError<C::Error, T::GraphError, W::Error>>
+where
+    T: TxnT,
+    C: ChangeStore,
+    W: WorkingCopy,
+    W::Error: 'static,
+{
+    let inode = match crate::fs::find_inode(txn, path) {
+        Ok(inode) => Some(inode),
+        Err(FsError::NotFound(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut new = Vec::new();
+    let encoding = working_copy
+        .decode_file(path, &mut new)
+        .map_err(GutterError::WorkingCopy)?;
+    if encoding.is_none() {
+        return Ok(Vec::new());
+    }
+    let pos = if let Some(inode) = inode {
+        crate::record::get_inodes(txn, channel, &inode)?.cloned()
+    } else {
+        None
+    };
+    let old = if let Some(pos) = pos {
+        channel_version(txn, channel, changes, pos, working_copy.graph_limits())?
+    } else {
+        Arc::new(Vec::new())
+    };
+    let lines_a = super::make_new_lines(&old, None);
+    let lines_b = super::make_new_lines(&new, None);
+    let dd = diff::diff(&lines_a, &lines_b, &Algorithm::default());
+    Ok(dd
+        .0
+        .iter()
+        .map(|r| LineChange {
+            kind: if r.old_len == 0 {
+                LineChangeKind::Added
+            } else if r.new_len == 0 {
+                LineChangeKind::Removed
+            } else {
+                LineChangeKind::Modified
+            },
+            old: r.old..r.old + r.old_len,
+            new: r.new..r.new + r.new_len,
+        })
+        .collect())
+}
+
+/// The contents of the file at `pos` in `channel`, from the cache if
+/// possible.
+fn channel_version<T: TxnT, C: ChangeStore>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    pos: Position<ChangeId>,
+    limits: crate::alive::retrieve::RetrieveLimits,
+) -> Result<Arc<Vec<u8>>, FileError<C::Error, T::GraphError>> {
+    let history = crate::file_stats::history(txn, channel, pos).map_err(|e| FileError::Txn(e.0))?;
+    let key = if let Some(h) = txn
+        .get_external(&pos.change)
+        .map_err(|e| FileError::Txn(e.0))?
+    {
+        let key = (
+            Position {
+                change: h.into(),
+                pos: pos.pos,
+            },
+            history,
+        );
+        if let Some(contents) = CACHE.lock().get(&key) {
+            debug!("gutter cache hit {:?}", pos);
+            return Ok(contents.clone());
+        }
+        Some(key)
+    } else {
+        None
+    };
+    let mut w = crate::vertex_buffer::Writer::new(Vec::new());
+    crate::output::output_file(changes, txn, channel, pos, &mut w, limits)?;
+    let contents = Arc::new(w.into_inner());
+    if let Some(key) = key {
+        let mut cache = CACHE.lock();
+        if cache.len() >= CACHE_SIZE {
+            cache.clear()
+        }
+        cache.insert(key, contents.clone());
+    }
+    Ok(contents)
+}
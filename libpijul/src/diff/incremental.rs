@@ -0,0 +1,209 @@
+//! Diffs of a buffer against a fixed old version, updated as the
+//! buffer is edited.
+//!
+//! Editors showing which lines of a file have pending changes usually
+//! diff their buffer against the recorded version of the file (for
+//! instance obtained with [`output_file`](crate::output::output_file))
+//! after each keystroke, which is too slow on large files. A
+//! [`BufferDiff`] remembers where the lines of both versions start,
+//! and [`incremental`] only diffs the lines around each edit, along
+//! with the hunks touching them.
+//!
+//! Since hunks are only recomputed locally, the result may differ
+//! from a full diff of the same buffer, although it is always a valid
+//! diff. Editors may call [`BufferDiff::new`] again when idle.
+use super::diff::{self, Algorithm};
+use std::ops::Range;
+
+/// A hunk of a [`BufferDiff`], replacing lines `old` of the old
+/// version with lines `new` of the buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingHunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+/// An edit of a buffer, replacing bytes `start..end` with `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: Vec<u8>,
+}
+
+/// The diff between an old version of a file and a buffer.
+#[derive(Debug, Clone)]
+pub struct BufferDiff {
+    algorithm: Algorithm,
+    old: Vec<u8>,
+    old_lines: Vec<usize>,
+    new: Vec<u8>,
+    new_lines: Vec<usize>,
+    hunks: Vec<PendingHunk>,
+}
+
+/// Push the offsets of the lines of `buf` to `starts`, shifted by
+/// `offset`. Lines are split like
+/// [`LineSplit`](super::split::LineSplit) does.
+fn line_starts(buf: &[u8], offset: usize, starts: &mut Vec<usize>) {
+    let mut start = 0;
+    while start < buf.len() {
+        starts.push(offset + start);
+        start = match buf[start..].iter().position(|&c| c == b'\n') {
+            Some(n) => start + n + 1,
+            None => buf.len(),
+        }
+    }
+}
+
+/// The index of the line containing byte `pos` of `buf`. The end of
+/// the buffer is on a new line if the buffer ends with a newline, and
+/// on the last line else.
+fn line_of(buf: &[u8], starts: &[usize], pos: usize) -> usize {
+    if pos < buf.len() {
+        match starts.binary_search(&pos) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    } else if buf.last().map(|&c| c == b'\n').unwrap_or(true) {
+        starts.len()
+    } else {
+        starts.len() - 1
+    }
+}
+
+/// The bytes of lines `lines`.
+fn bytes(len: usize, starts: &[usize], lines: &Range<usize>) -> Range<usize> {
+    let bound = |l: usize| starts.get(l).cloned().unwrap_or(len);
+    bound(lines.start)..bound(lines.end)
+}
+
+fn shift(x: usize, delta: isize) -> usize {
+    (x as isize + delta) as usize
+}
+
+impl BufferDiff {
+    /// Diff buffer `new` against `old`.
+    pub fn new(old: Vec<u8>, new: Vec<u8>, algorithm: Algorithm) -> Self {
+        let mut old_lines = Vec::new();
+        line_starts(&old, 0, &mut old_lines);
+        let mut new_lines = Vec::new();
+        line_starts(&new, 0, &mut new_lines);
+        let mut d = BufferDiff {
+            algorithm,
+            old,
+            old_lines,
+            new,
+            new_lines,
+            hunks: Vec::new(),
+        };
+        d.hunks = d.diff_window(0..d.old_lines.len(), 0..d.new_lines.len());
+        d
+    }
+
+    /// The old version of the file.
+    pub fn old(&self) -> &[u8] {
+        &self.old
+    }
+
+    /// The current contents of the buffer.
+    pub fn buffer(&self) -> &[u8] {
+        &self.new
+    }
+
+    /// The hunks between the old version and the buffer, ordered by
+    /// position.
+    pub fn hunks(&self) -> &[PendingHunk] {
+        &self.hunks
+    }
+
+    fn diff_window(&self, old: Range<usize>, new: Range<usize>) -> Vec<PendingHunk> {
+        let a = &self.old[bytes(self.old.len(), &self.old_lines, &old)];
+        let b = &self.new[bytes(self.new.len(), &self.new_lines, &new)];
+        let lines_a = super::make_new_lines(a, None);
+        let lines_b = super::make_new_lines(b, None);
+        let dd = diff::diff(&lines_a, &lines_b, &self.algorithm);
+        dd.0.iter()
+            .map(|r| PendingHunk {
+                old: old.start + r.old..old.start + r.old + r.old_len,
+                new: new.start + r.new..new.start + r.new + r.new_len,
+            })
+            .collect()
+    }
+
+    /// Apply `edit` to the buffer, and update the hunks.
+    ///
+    /// Panics if `edit` is out of the bounds of the buffer.
+    pub fn edit(&mut self, edit: &BufferEdit) {
+        assert!(edit.start <= edit.end && edit.end <= self.new.len());
+        let n = self.new_lines.len();
+        // Lines l0..l1 of the buffer are touched by the edit.
+        let l0 = line_of(&self.new, &self.new_lines, edit.start);
+        let l1 = (line_of(&self.new, &self.new_lines, edit.end) + 1).min(n);
+        let touched = bytes(self.new.len(), &self.new_lines, &(l0..l1));
+
+        let delta = edit.text.len() as isize - (edit.end - edit.start) as isize;
+        self.new
+            .splice(edit.start..edit.end, edit.text.iter().cloned());
+        let mut starts = Vec::new();
+        line_starts(
+            &self.new[touched.start..shift(touched.end, delta)],
+            touched.start,
+            &mut starts,
+        );
+        let added = starts.len();
+        self.new_lines.splice(l0..l1, starts);
+        for s in self.new_lines[l0 + added..].iter_mut() {
+            *s = shift(*s, delta)
+        }
+        let line_delta = added as isize - (l1 - l0) as isize;
+
+        // Hunks i..j touch lines l0..l1, possibly at their ends.
+        let i = self
+            .hunks
+            .iter()
+            .position(|h| h.new.end >= l0)
+            .unwrap_or(self.hunks.len());
+        let j = i + self.hunks[i..]
+            .iter()
+            .take_while(|h| h.new.start <= l1)
+            .count();
+        let (new_start, new_end) = if i < j {
+            (
+                self.hunks[i].new.start.min(l0),
+                self.hunks[j - 1].new.end.max(l1),
+            )
+        } else {
+            (l0, l1)
+        };
+        // Outside of hunks, lines of the old version and of the
+        // buffer are in one-to-one correspondence.
+        let offset = |h: Option<&PendingHunk>| {
+            h.map(|h| h.old.end as isize - h.new.end as isize)
+                .unwrap_or(0)
+        };
+        let before = offset(if i > 0 { self.hunks.get(i - 1) } else { None });
+        let after = if i < j {
+            offset(self.hunks.get(j - 1))
+        } else {
+            before
+        };
+        let hunks = self.diff_window(
+            shift(new_start, before)..shift(new_end, after),
+            new_start..shift(new_end, line_delta),
+        );
+        for h in self.hunks[j..].iter_mut() {
+            h.new = shift(h.new.start, line_delta)..shift(h.new.end, line_delta)
+        }
+        self.hunks.splice(i..j, hunks);
+    }
+}
+
+/// Update `prev` with `edits`, applied in order, so that offsets in
+/// each edit are relative to the buffer after the previous edits.
+pub fn incremental(mut prev: BufferDiff, edits: &[BufferEdit]) -> BufferDiff {
+    for edit in edits {
+        prev.edit(edit)
+    }
+    prev
+}
@@ -0,0 +1,180 @@
+//! A parser for the conflict markers written by
+//! [`output`](crate::output) in files.
+//!
+//! When recording a file that still contains conflicts, the marker
+//! lines of the working copy need to be matched with the markers of
+//! the same conflicts in the pristine, else edits inside a side of a
+//! conflict could be attributed to another side. Since all markers of
+//! the same kind are identical, this module identifies each marker by
+//! its kind and the side of the conflict it opens. Start markers may
+//! carry the identifier of their conflict.
+use crate::vertex_buffer::{is_start_marker, END_MARKER, SEPARATOR};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MarkerKind {
+    Begin,
+    Next,
+    End,
+}
+
+/// A marker line, identified by its role in the conflict rather than
+/// by its position in the file. `side` is the index of the side
+/// starting after this marker (for `End`, the index of the last
+/// side).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub side: usize,
+}
+
+/// A side of a conflict, between two markers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Side {
+    /// Offset of the first byte of the side.
+    pub start: usize,
+    /// Offset of the first byte of the next marker line.
+    pub end: usize,
+    /// Conflicts nested in this side.
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A conflict, from the beginning of its start marker to the end of
+/// its end marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub start: usize,
+    pub end: usize,
+    pub sides: Vec<Side>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MarkerError {
+    #[error("Conflict separator outside of a conflict at byte {pos}")]
+    UnexpectedSeparator { pos: usize },
+    #[error("End of conflict marker outside of a conflict at byte {pos}")]
+    UnexpectedEnd { pos: usize },
+    #[error("Conflict starting at byte {pos} is not terminated")]
+    Unterminated { pos: usize },
+}
+
+/// The markers of a file, and the tree of conflicts they delimit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Markers {
+    /// Offsets of the marker lines, in increasing order, with their
+    /// role.
+    pub markers: Vec<(usize, Marker)>,
+    /// Top-level conflicts.
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Markers {
+    /// The marker whose line starts at byte `pos`, if any.
+    pub fn get(&self, pos: usize) -> Option<Marker> {
+        self.markers
+            .binary_search_by(|(p, _)| p.cmp(&pos))
+            .ok()
+            .map(|i| self.markers[i].1)
+    }
+}
+
+struct Frame {
+    start: usize,
+    side_start: usize,
+    sides: Vec<Side>,
+    conflicts: Vec<Conflict>,
+}
+
+fn marker_kind(line: &[u8]) -> Option<MarkerKind> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let is = |m: &str| line == m.trim_matches('\n').as_bytes();
+    if is_start_marker(line) {
+        Some(MarkerKind::Begin)
+    } else if is(SEPARATOR) {
+        Some(MarkerKind::Next)
+    } else if is(END_MARKER) {
+        Some(MarkerKind::End)
+    } else {
+        None
+    }
+}
+
+/// Parse the conflict markers of `contents`.
+pub fn parse(contents: &[u8]) -> Result<Markers, MarkerError> {
+    let mut result = Markers::default();
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut pos = 0;
+    while pos < contents.len() {
+        let end = contents[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(contents.len());
+        match marker_kind(&contents[pos..end]) {
+            Some(MarkerKind::Begin) => {
+                stack.push(Frame {
+                    start: pos,
+                    side_start: end,
+                    sides: Vec::new(),
+                    conflicts: Vec::new(),
+                });
+                result.markers.push((
+                    pos,
+                    Marker {
+                        kind: MarkerKind::Begin,
+                        side: 0,
+                    },
+                ))
+            }
+            Some(MarkerKind::Next) => {
+                let top = stack
+                    .last_mut()
+                    .ok_or(MarkerError::UnexpectedSeparator { pos })?;
+                top.sides.push(Side {
+                    start: top.side_start,
+                    end: pos,
+                    conflicts: std::mem::take(&mut top.conflicts),
+                });
+                top.side_start = end;
+                result.markers.push((
+                    pos,
+                    Marker {
+                        kind: MarkerKind::Next,
+                        side: top.sides.len(),
+                    },
+                ))
+            }
+            Some(MarkerKind::End) => {
+                let mut top = stack.pop().ok_or(MarkerError::UnexpectedEnd { pos })?;
+                top.sides.push(Side {
+                    start: top.side_start,
+                    end: pos,
+                    conflicts: top.conflicts,
+                });
+                result.markers.push((
+                    pos,
+                    Marker {
+                        kind: MarkerKind::End,
+                        side: top.sides.len() - 1,
+                    },
+                ));
+                let conflict = Conflict {
+                    start: top.start,
+                    end,
+                    sides: top.sides,
+                };
+                if let Some(parent) = stack.last_mut() {
+                    parent.conflicts.push(conflict)
+                } else {
+                    result.conflicts.push(conflict)
+                }
+            }
+            None => {}
+        }
+        pos = end
+    }
+    if let Some(top) = stack.first() {
+        return Err(MarkerError::Unterminated { pos: top.start });
+    }
+    Ok(result)
+}
@@ -1,25 +1,54 @@
-use crate::alive::{output_graph, Graph};
+use crate::alive::{output_graph_ordered, Graph};
+use crate::change::{Atom, Hunk};
 use crate::changestore::*;
+use crate::channel_settings::{BinaryPolicy, TextPolicy};
 use crate::pristine::*;
 use crate::record::Recorded;
 use crate::text_encoding::Encoding;
+use crate::HashSet;
 
 mod bin;
 
+mod coalesce;
 mod diff;
+pub(crate) mod markers;
 mod split;
 mod vertex_buffer;
-pub use diff::Algorithm;
+pub use diff::{Algorithm, ChannelAlgorithm, DiffAlgorithm, Replacement, UnknownAlgorithm};
 mod delete;
+mod gutter;
+mod incremental;
 mod replace;
+mod segments;
+mod words;
+pub use gutter::*;
+pub use incremental::*;
 
-#[derive(Hash, Clone, Copy)]
-struct Line<'a> {
+/// Size of the chunks of binary files.
+const ROLLING_SIZE: usize = 8192;
+
+/// The chunks of `data` compared by the diff of binary files.
+pub(crate) fn binary_chunks(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bin::blocks(ROLLING_SIZE, data)
+}
+
+/// A line of a file, or a chunk of a binary file, as compared by a
+/// [`DiffAlgorithm`].
+#[derive(Clone, Copy)]
+pub struct Line<'a> {
     l: &'a [u8],
     cyclic: bool,
     before_end_marker: bool,
     last: bool,
     ptr: *const u8,
+    marker: Option<markers::Marker>,
+}
+
+impl<'a> Line<'a> {
+    /// The bytes of this line, including its line ending.
+    pub fn contents(&self) -> &'a [u8] {
+        self.l
+    }
 }
 
 impl<'a> std::fmt::Debug for Line<'a> {
@@ -36,6 +65,7 @@ impl<'a> Default for Line<'a> {
             before_end_marker: false,
             last: false,
             ptr: std::ptr::null(),
+            marker: None,
         }
     }
 }
@@ -50,16 +80,29 @@ impl<'a> PartialEq for Line<'a> {
         }
         ((self.ptr == b.ptr && self.l.len() == b.l.len()) || self.l == b.l)
             && self.cyclic == b.cyclic
+            && self.marker == b.marker
     }
 }
 impl<'a> Eq for Line<'a> {}
 
+/// Consistent with `==`: lines printed just before an end marker are
+/// equal to the same lines with a newline, so trailing newlines are
+/// not hashed.
+impl<'a> std::hash::Hash for Line<'a> {
+    fn hash<H: std::hash::Hasher>(&self, h: &mut H) {
+        let end = self.l.iter().rposition(|&c| c != b'\n').map_or(0, |e| e + 1);
+        self.l[..end].hash(h)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum DiffError<P: std::error::Error + 'static, T: std::error::Error + 'static> {
     #[error(transparent)]
     Output(#[from] crate::output::FileError<P, T>),
     #[error(transparent)]
     Txn(T),
+    #[error("Diff cancelled")]
+    Cancelled,
 }
 
 impl<T: std::error::Error + 'static, C: std::error::Error + 'static> std::convert::From<TxnErr<T>>
@@ -70,7 +113,17 @@ impl<T: std::error::Error + 'static, C: std::error::Error + 'static> std::conver
     }
 }
 
-fn make_old_lines(d: &vertex_buffer::Diff) -> Vec<Line> {
+/// Split the old version of the file into lines. `markers` are the
+/// parsed markers of `d.contents_a`, used to find the lines printed
+/// just before an end marker, and to identify the marker lines if
+/// `tag` is set (when the new version could be parsed too). If
+/// `markers` is `None`, marker lines are only compared by their
+/// contents.
+fn make_old_lines<'a>(
+    d: &'a vertex_buffer::Diff,
+    markers: Option<&markers::Markers>,
+    tag: bool,
+) -> Vec<Line<'a>> {
     d.lines()
         .map(|l| {
             let old_bytes = l.as_ptr() as usize - d.contents_a.as_ptr() as usize;
@@ -85,36 +138,36 @@ fn make_old_lines(d: &vertex_buffer::Diff) -> Vec<Line> {
             } else {
                 false
             };
-            let before_end_marker = if l.last() != Some(&b'\n') {
-                let next_index = l.as_ptr() as usize + l.len() - d.contents_a.as_ptr() as usize + 1;
-                d.marker.get(&next_index) == Some(&vertex_buffer::ConflictMarker::End)
-            } else {
-                false
-            };
+            let before_end_marker = l.last() != Some(&b'\n')
+                && markers
+                    .and_then(|m| m.get(old_bytes + l.len() + 1))
+                    .map(|m| m.kind)
+                    == Some(markers::MarkerKind::End);
             debug!("old = {:?}", l);
             Line {
                 l,
                 cyclic,
                 before_end_marker,
-                last: l.as_ptr() as usize + l.len() - d.contents_a.as_ptr() as usize
-                    >= d.contents_a.len(),
+                last: old_bytes + l.len() >= d.contents_a.len(),
                 ptr: l.as_ptr(),
+                marker: markers.filter(|_| tag).and_then(|m| m.get(old_bytes)),
             }
         })
         .collect()
 }
 
-fn make_new_lines(b: &[u8]) -> Vec<Line> {
+fn make_new_lines<'a>(b: &'a [u8], markers: Option<&markers::Markers>) -> Vec<Line<'a>> {
     split::LineSplit::from(b)
         .map(|l| {
             debug!("new: {:?}", l);
-            let next_index = l.as_ptr() as usize + l.len() - b.as_ptr() as usize;
+            let new_bytes = l.as_ptr() as usize - b.as_ptr() as usize;
             Line {
                 l,
                 cyclic: false,
                 before_end_marker: false,
-                last: next_index >= b.len(),
+                last: new_bytes + l.len() >= b.len(),
                 ptr: l.as_ptr(),
+                marker: markers.and_then(|m| m.get(new_bytes)),
             }
         })
         .collect()
@@ -126,27 +179,59 @@ impl Recorded {
         changes: &P,
         txn: &T,
         channel: &T::Channel,
-        algorithm: Algorithm,
+        algorithm: &dyn DiffAlgorithm,
         path: String,
         inode: Position<Option<ChangeId>>,
         a: &mut Graph,
         b: &[u8],
         encoding: &Option<Encoding>,
     ) -> Result<(), DiffError<P::Error, T::GraphError>> {
+        if self.cancellation.is_cancelled() {
+            return Err(DiffError::Cancelled);
+        }
         self.largest_file = self.largest_file.max(b.len() as u64);
-        let mut d = vertex_buffer::Diff::new(inode, path.clone(), a);
-        output_graph(changes, txn, channel, &mut d, a, &mut self.redundant)?;
+        if let Some(ref observer) = self.observer {
+            observer.bytes_diffed(&path, b.len() as u64)
+        }
+        let options = self.conflict_options;
+        let mut d = vertex_buffer::Diff::new(inode, path.clone(), a, options.ids);
+        output_graph_ordered(
+            changes,
+            txn,
+            channel,
+            &mut d,
+            a,
+            &mut self.redundant,
+            options.order,
+        )?;
         // TODO pass through both encodings and use that to decide
         debug!("encoding = {:?}", encoding);
-        let (lines_a, lines_b) = if encoding.is_none() {
-            const ROLLING_SIZE: usize = 8192;
+        let binary = encoding.is_none() && self.binary_policy != BinaryPolicy::Lines;
+        let (lines_a, lines_b) = if binary {
             debug!("contents_a: {:?}", d.contents_a.len());
             let (ah, old) = bin::make_old_chunks(ROLLING_SIZE, &d.contents_a);
             let (bb, new) = bin::make_new_chunks(ROLLING_SIZE, &ah, &b);
             debug!("bb = {:?}", bb);
             (old, new)
         } else {
-            (make_old_lines(&d), make_new_lines(&b))
+            let ma = markers::parse(&d.contents_a);
+            let mb = markers::parse(b);
+            if let (Err(e), _) | (_, Err(e)) = (&ma, &mb) {
+                debug!("markers: {:?}", e);
+            }
+            let tag = ma.is_ok() && mb.is_ok();
+            (
+                make_old_lines(&d, ma.as_ref().ok(), tag),
+                make_new_lines(&b, mb.as_ref().ok().filter(|_| tag)),
+            )
+        };
+        let (lines_a, lines_b, segments) = match self.text_policy(&path) {
+            TextPolicy::Hybrid { max_line } if !binary => {
+                let (lines_a, _) = segments::split(lines_a, max_line);
+                let (lines_b, segments) = segments::split(lines_b, max_line);
+                (lines_a, lines_b, Some(segments))
+            }
+            _ => (lines_a, lines_b, None),
         };
 
         trace!("pos = {:?}", d.pos_a);
@@ -159,37 +244,258 @@ impl Recorded {
             }
         }
         let dd = diff::diff(&lines_a, &lines_b, algorithm);
+        if self.cancellation.is_cancelled() {
+            return Err(DiffError::Cancelled);
+        }
+        let ascii_compatible = encoding
+            .as_ref()
+            .map_or(true, |e| e.0.is_ascii_compatible());
+        let (lines_a, lines_b, dd, words) = if self.word_diff && !binary && ascii_compatible {
+            let words::Words { a, b, dd, lines } =
+                words::refine(&lines_a, &lines_b, &dd, algorithm);
+            (a, b, dd, Some(lines))
+        } else {
+            (lines_a, lines_b, dd, None)
+        };
+        let dd = if let Some(distance) = self.join_distance {
+            coalesce::coalesce(&lines_a, dd, distance)
+        } else {
+            dd
+        };
+        let first_file_hunk = self.actions.len();
+        self.push_hunks(
+            txn,
+            txn.graph(channel),
+            &d,
+            &lines_a,
+            &lines_b,
+            &dd,
+            encoding,
+            binary,
+        )?;
+        if words.is_some() || segments.is_some() {
+            // Hunks are numbered by words or segments until now.
+            for hunk in &mut self.actions[first_file_hunk..] {
+                match hunk {
+                    Hunk::Edit { local, .. }
+                    | Hunk::Replacement { local, .. }
+                    | Hunk::ResurrectZombies { local, .. }
+                    | Hunk::SolveOrderConflict { local, .. } => {
+                        if let Some(ref words) = words {
+                            local.line = words.line(local.line)
+                        }
+                        if let Some(ref segments) = segments {
+                            local.line = segments.line(local.line)
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        debug!("Diff ended");
+        Ok(())
+    }
+
+    /// Diff a file whose new version is read in windows by `read`,
+    /// which calls its argument on each window, and returns `false`
+    /// if the file couldn't be read entirely (in which case no hunk
+    /// is produced). Unlike [`Recorded::diff`], which needs the whole
+    /// new version, this only keeps the parts of the new version not
+    /// found in the old one: chunks for binary files (`encoding` is
+    /// `None`), lines for text files. Conflict markers are only
+    /// compared by their contents, and words are not diffed.
+    pub(crate) fn diff_windows<T: ChannelTxnT, P: ChangeStore>(
+        &mut self,
+        changes: &P,
+        txn: &T,
+        channel: &T::Channel,
+        algorithm: &dyn DiffAlgorithm,
+        path: String,
+        inode: Position<Option<ChangeId>>,
+        a: &mut Graph,
+        encoding: &Option<Encoding>,
+        read: &mut dyn FnMut(&mut dyn FnMut(&[u8])) -> bool,
+    ) -> Result<bool, DiffError<P::Error, T::GraphError>> {
+        let options = self.conflict_options;
+        let mut d = vertex_buffer::Diff::new(inode, path, a, options.ids);
+        output_graph_ordered(
+            changes,
+            txn,
+            channel,
+            &mut d,
+            a,
+            &mut self.redundant,
+            options.order,
+        )?;
+        let binary = encoding.is_none();
+        let (ah, lines_a) = if binary {
+            bin::make_old_chunks(ROLLING_SIZE, &d.contents_a)
+        } else {
+            let markers = markers::parse(&d.contents_a).ok();
+            (
+                Default::default(),
+                make_old_lines(&d, markers.as_ref(), false),
+            )
+        };
+        let old_lines: HashSet<&[u8]> = if binary {
+            HashSet::default()
+        } else {
+            lines_a.iter().map(|l| l.l).collect()
+        };
+        let mut chunks = bin::NewChunks::new(ROLLING_SIZE, &ah);
+        let mut lines = split::NewLines::new(&old_lines);
+        let mut size = 0;
+        let observer = self.observer.clone();
+        let cancellation = self.cancellation.clone();
+        if !read(&mut |w| {
+            if cancellation.is_cancelled() {
+                return;
+            }
+            size += w.len() as u64;
+            if let Some(ref observer) = observer {
+                observer.bytes_diffed(&d.path, w.len() as u64)
+            }
+            if binary {
+                chunks.feed(w)
+            } else {
+                lines.feed(w)
+            }
+        }) {
+            return Ok(false);
+        }
+        if self.cancellation.is_cancelled() {
+            return Err(DiffError::Cancelled);
+        }
+        self.largest_file = self.largest_file.max(size);
+        let (chunks, new) = if binary {
+            chunks.finish()
+        } else {
+            lines.finish()
+        };
+        debug!("{:?} chunks, {:?} new bytes", chunks.len(), new.len());
+        let lines_b = bin::new_chunk_lines(&chunks, &new);
+        let dd = diff::diff(&lines_a, &lines_b, algorithm);
+        let dd = if let Some(distance) = self.join_distance {
+            coalesce::coalesce(&lines_a, dd, distance)
+        } else {
+            dd
+        };
+        self.push_hunks(
+            txn,
+            txn.graph(channel),
+            &d,
+            &lines_a,
+            &lines_b,
+            &dd,
+            encoding,
+            binary,
+        )?;
+        Ok(true)
+    }
+
+    /// Turn the replacements `dd` between `lines_a` and `lines_b`
+    /// into hunks, after splitting `lines_a` at the vertices of the
+    /// old version.
+    fn push_hunks<T: GraphTxnT>(
+        &mut self,
+        txn: &T,
+        graph: &T::Graph,
+        d: &vertex_buffer::Diff,
+        lines_a: &[Line],
+        lines_b: &[Line],
+        dd: &diff::D,
+        encoding: &Option<Encoding>,
+        binary: bool,
+    ) -> Result<(), TxnErr<T::GraphError>> {
+        let split = split::split_at_vertices(d, lines_a, dd);
+        let (lines_a, dd) = if let Some((ref lines_a, ref dd)) = split {
+            (&lines_a[..], dd)
+        } else {
+            (lines_a, dd)
+        };
         let mut conflict_contexts = replace::ConflictContexts::new();
+        let first_file_hunk = self.actions.len();
         for r in 0..dd.len() {
+            let first_hunk = self.actions.len();
             if dd[r].old_len > 0 {
                 self.delete(
                     txn,
-                    txn.graph(channel),
-                    &d,
-                    &dd,
+                    graph,
+                    d,
+                    dd,
                     &mut conflict_contexts,
-                    &lines_a,
-                    &lines_b,
+                    lines_a,
+                    lines_b,
                     r,
                     encoding,
                 )?;
             }
             if dd[r].new_len > 0 {
-                self.replace(
-                    &d,
-                    &mut conflict_contexts,
-                    &lines_a,
-                    &lines_b,
-                    &dd,
-                    r,
-                    encoding,
-                );
+                self.replace(d, &mut conflict_contexts, lines_a, lines_b, dd, r, encoding);
+            }
+            if binary && self.binary_policy == BinaryPolicy::ByteRanges {
+                self.binary_edits(first_hunk, lines_a, dd[r].old, dd[r].old_len)
             }
         }
-        debug!("Diff ended");
+        if let Some(ref observer) = self.observer {
+            observer.hunks_produced(&d.path, self.actions.len() - first_file_hunk)
+        }
         Ok(())
     }
 }
+
+impl Recorded {
+    /// Turn the hunks produced from `first_hunk` on, for a single
+    /// replacement of `old_len` chunks at chunk `old` in a binary
+    /// file, into byte-range hunks.
+    fn binary_edits(&mut self, first_hunk: usize, chunks_a: &[Line], old: usize, old_len: usize) {
+        let offset = if old < chunks_a.len() {
+            bytes_pos(chunks_a, old)
+        } else if !chunks_a.is_empty() {
+            bytes_len(chunks_a, 0, chunks_a.len())
+        } else {
+            0
+        };
+        let delete_len = if old_len > 0 {
+            bytes_len(chunks_a, old, old_len)
+        } else {
+            0
+        };
+        let hunks: Vec<_> = self.actions.drain(first_hunk..).collect();
+        for hunk in hunks {
+            let (delete, insert, local) = match hunk {
+                Hunk::Edit {
+                    change: change @ Atom::EdgeMap(_),
+                    local,
+                    ..
+                } => (Some(change), None, local),
+                Hunk::Edit { change, local, .. } => (None, Some(change), local),
+                Hunk::Replacement {
+                    change,
+                    replacement,
+                    local,
+                    ..
+                } => (Some(change), Some(replacement), local),
+                hunk => {
+                    self.actions.push(hunk);
+                    continue;
+                }
+            };
+            self.actions.push(Hunk::BinaryEdit {
+                delete_len: if delete.is_some() {
+                    delete_len as u64
+                } else {
+                    0
+                },
+                delete,
+                insert,
+                local,
+                offset: offset as u64,
+            })
+        }
+    }
+}
+
 fn bytes_pos(chunks: &[Line], old: usize) -> usize {
     debug!(
         "bytes pos {:?} {:?}",
@@ -202,6 +508,12 @@ fn bytes_pos(chunks: &[Line], old: usize) -> usize {
 
     chunks[old].l.as_ptr() as usize - chunks[0].l.as_ptr() as usize
 }
+/// Length in bytes of lines `new..new + len` of the new version,
+/// which may not be contiguous in memory, see [`Recorded::diff_windows`].
+fn new_bytes_len(lines_b: &[Line], new: usize, len: usize) -> usize {
+    lines_b[new..new + len].iter().map(|l| l.l.len()).sum()
+}
+
 fn bytes_len(chunks: &[Line], old: usize, len: usize) -> usize {
     if let Some(p) = chunks.get(old + len) {
         p.l.as_ptr() as usize - chunks[old].l.as_ptr() as usize
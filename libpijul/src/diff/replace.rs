@@ -1,6 +1,6 @@
 use super::diff::*;
 use super::vertex_buffer::{ConflictMarker, Diff};
-use super::{bytes_len, bytes_pos, Line};
+use super::{bytes_len, bytes_pos, new_bytes_len, Line};
 use crate::change::{Atom, Hunk, Local, NewVertex};
 use crate::pristine::{ChangeId, ChangePosition, EdgeFlags, Position};
 use crate::record::Recorded;
@@ -42,7 +42,20 @@ impl Recorded {
         let len = dd[r].new_len;
         let up_context = get_up_context(diff, conflict_contexts, lines_a, old);
 
-        let start = self.contents.lock().len();
+        // Other files may be diffed concurrently, so the new vertex
+        // is written before the down context is computed, to avoid
+        // holding the lock while reading the graph.
+        let mut contents = self.contents.lock();
+        let start = contents.len();
+        for &line in &lines_b[from_new..(from_new + len)] {
+            contents.extend(line.l);
+        }
+        let end = contents.len();
+        if start < end {
+            contents.push(0);
+            self.chunks.push(start..contents.len());
+        }
+        std::mem::drop(contents);
 
         let down_context = get_down_context(
             diff,
@@ -61,17 +74,9 @@ impl Recorded {
         trace!("old {:?}", &lines_a[old..(old + old_len)]);
         debug!("new {:?}..{:?}", from_new, from_new + len);
         trace!("new {:?}", &lines_b[from_new..(from_new + len)]);
-
-        let mut contents = self.contents.lock();
-        for &line in &lines_b[from_new..(from_new + len)] {
-            contents.extend(line.l);
-        }
-        let end = contents.len();
         if start >= end {
             return;
         }
-        contents.push(0);
-        std::mem::drop(contents);
 
         let change = NewVertex {
             up_context,
@@ -146,7 +151,14 @@ pub(super) fn get_up_context(
             None => {
                 let change = diff.pos_a[up_context_idx].vertex.change;
                 let pos = diff.pos_a[up_context_idx].vertex.start;
-                let offset = old_bytes - diff.pos_a[up_context_idx].pos;
+                // The line ending added before conflict markers isn't
+                // in the vertex.
+                let end = if diff.missing_eol.contains(&(old_bytes - 1)) {
+                    old_bytes - 1
+                } else {
+                    old_bytes
+                };
+                let offset = end - diff.pos_a[up_context_idx].pos;
                 debug!("offset {:?} {:?}", pos.0, offset);
                 return vec![Position {
                     change: Some(change),
@@ -266,7 +278,7 @@ pub(super) fn get_down_context(
                     .side_ends
                     .entry(down_context_idx)
                     .or_default();
-                let b_len_bytes = bytes_len(lines_b, from_new, new_len);
+                let b_len_bytes = new_bytes_len(lines_b, from_new, new_len);
                 e.push(ChangePosition((contents_len + b_len_bytes).into()));
                 down_context_idx += 1
             }
@@ -306,7 +318,7 @@ fn get_down_context_conflict(
     mut down_context_idx: usize,
 ) -> Vec<Position<Option<ChangeId>>> {
     let conflict = diff.pos_a[down_context_idx].conflict;
-    let len_bytes = bytes_len(lines_b, from_new, new_len);
+    let len_bytes = new_bytes_len(lines_b, from_new, new_len);
     conflict_contexts
         .up
         .insert(conflict, ChangePosition(len_bytes.into()));
@@ -0,0 +1,93 @@
+//! Splitting very long lines into segments, so that an edit in a line
+//! of several megabytes (minified code, generated data) neither costs
+//! a diff of the whole line against its old version, nor produces a
+//! hunk replacing all of it.
+//!
+//! The boundaries of segments are chosen from the contents of the
+//! line, using a rolling hash of the last bytes, as in the chunking of
+//! files by backup tools: an edit only moves the boundaries close to
+//! it, and the other segments of the line are the same in both
+//! versions.
+use super::Line;
+use adler32::RollingAdler32;
+
+/// Number of bytes hashed to choose the boundaries.
+const WINDOW: usize = 32;
+
+/// The line of each segment of the new version.
+pub(super) struct SegmentLines {
+    line_of: Vec<usize>,
+    lines_b: usize,
+}
+
+impl SegmentLines {
+    /// The line number (starting at 1) of the line containing segment
+    /// number `segment` (starting at 1) of the new version.
+    pub fn line(&self, segment: usize) -> usize {
+        if let Some(&l) = self.line_of.get(segment - 1) {
+            l + 1
+        } else {
+            self.lines_b + 1
+        }
+    }
+}
+
+/// Split the lines of `lines` longer than `max_line` bytes into
+/// segments of at most `max_line` bytes.
+pub(super) fn split<'a>(lines: Vec<Line<'a>>, max_line: usize) -> (Vec<Line<'a>>, SegmentLines) {
+    let max_line = max_line.max(2 * WINDOW);
+    let mut segments = Vec::with_capacity(lines.len());
+    let mut line_of = Vec::with_capacity(lines.len());
+    for (n, line) in lines.iter().enumerate() {
+        if line.l.len() <= max_line {
+            segments.push(*line);
+            line_of.push(n);
+            continue;
+        }
+        let mut start = 0;
+        for end in boundaries(line.l, max_line) {
+            let l = &line.l[start..end];
+            let first = start == 0;
+            let last = end == line.l.len();
+            segments.push(Line {
+                l,
+                ptr: l.as_ptr(),
+                cyclic: line.cyclic,
+                before_end_marker: last && line.before_end_marker,
+                last: last && line.last,
+                marker: if first { line.marker } else { None },
+            });
+            line_of.push(n);
+            start = end
+        }
+    }
+    let lines_b = lines.len();
+    (segments, SegmentLines { line_of, lines_b })
+}
+
+/// The ends of the segments of `l`.
+fn boundaries(l: &[u8], max_line: usize) -> Vec<usize> {
+    let min_line = max_line / 4;
+    // A boundary is found on average every `2^bits` bytes after the
+    // first `min_line` bytes of a segment.
+    let bits = (max_line / 4).next_power_of_two().trailing_zeros().min(31);
+    let mut ends = Vec::with_capacity(l.len() / min_line + 1);
+    let mut hash = RollingAdler32::new();
+    let mut start = 0;
+    for i in 0..l.len() {
+        if i >= WINDOW {
+            hash.remove(WINDOW, l[i - WINDOW])
+        }
+        hash.update(l[i]);
+        let len = i + 1 - start;
+        let boundary = i + 1 >= WINDOW && hash.hash().wrapping_mul(0x9e37_79b1) >> (32 - bits) == 0;
+        if len >= max_line || (len >= min_line && boundary) {
+            ends.push(i + 1);
+            start = i + 1
+        }
+    }
+    if start < l.len() {
+        ends.push(l.len())
+    }
+    ends
+}
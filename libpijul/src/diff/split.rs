@@ -1,3 +1,5 @@
+use super::diff::{Replacement, D};
+use super::Line;
 use crate::HashSet;
 
 pub struct LineSplit<'a> {
@@ -48,3 +50,126 @@ impl<'a> Iterator for LineSplit<'a> {
         Some(&self.buf[current..last])
     }
 }
+
+/// Split the lines of the old version at the vertices starting in
+/// the middle of a line, for instance after an intra-line diff, so
+/// that deletions and contexts only ever start at the beginning of a
+/// line. `dd` is translated to the split lines. Returns `None` if no
+/// vertex starts in the middle of a line.
+pub(super) fn split_at_vertices<'a>(
+    diff: &super::vertex_buffer::Diff,
+    lines_a: &[Line<'a>],
+    dd: &D,
+) -> Option<(Vec<Line<'a>>, D)> {
+    let base = lines_a.first()?.l.as_ptr() as usize;
+    let mut split = Vec::with_capacity(lines_a.len());
+    // Index in `split` of the first part of each line.
+    let mut first_part = Vec::with_capacity(lines_a.len() + 1);
+    let mut v = 0;
+    for l in lines_a {
+        first_part.push(split.len());
+        let start = l.l.as_ptr() as usize - base;
+        let end = start + l.l.len();
+        while v < diff.pos_a.len() && diff.pos_a[v].pos <= start {
+            v += 1
+        }
+        let mut part_start = start;
+        while v < diff.pos_a.len() && diff.pos_a[v].pos < end {
+            let pos = diff.pos_a[v].pos;
+            if pos > part_start {
+                let part = &l.l[part_start - start..pos - start];
+                split.push(Line {
+                    l: part,
+                    ptr: part.as_ptr(),
+                    before_end_marker: false,
+                    last: false,
+                    ..*l
+                });
+                part_start = pos
+            }
+            v += 1
+        }
+        let part = &l.l[part_start - start..];
+        split.push(Line {
+            l: part,
+            ptr: part.as_ptr(),
+            ..*l
+        })
+    }
+    if split.len() == lines_a.len() {
+        return None;
+    }
+    first_part.push(split.len());
+    let dd = D(dd
+        .0
+        .iter()
+        .map(|r| Replacement {
+            old: first_part[r.old],
+            old_len: first_part[r.old + r.old_len] - first_part[r.old],
+            ..r.clone()
+        })
+        .collect());
+    Some((split, dd))
+}
+
+/// An incremental version of [`LineSplit`], fed with the new version
+/// of a file in windows. Lines found in the old version point to the
+/// old version, and only the other lines, and the last incomplete
+/// line, are kept.
+pub(super) struct NewLines<'a, 'h> {
+    old: &'h HashSet<&'a [u8]>,
+    partial: Vec<u8>,
+    lines: Vec<super::bin::NewChunk<'a>>,
+    /// Contents of the `NewChunk::New` lines.
+    new: Vec<u8>,
+}
+
+impl<'a, 'h> NewLines<'a, 'h> {
+    pub fn new(old: &'h HashSet<&'a [u8]>) -> Self {
+        NewLines {
+            old,
+            partial: Vec::new(),
+            lines: Vec::new(),
+            new: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, mut bytes: &[u8]) {
+        while let Some(i) = bytes.iter().position(|&c| c == b'\n') {
+            let (l, rest) = bytes.split_at(i + 1);
+            if self.partial.is_empty() {
+                self.push(l)
+            } else {
+                let mut partial = std::mem::take(&mut self.partial);
+                partial.extend_from_slice(l);
+                self.push(&partial);
+                partial.clear();
+                self.partial = partial
+            }
+            bytes = rest
+        }
+        self.partial.extend_from_slice(bytes)
+    }
+
+    /// The lines of the new version, and the contents of the new
+    /// lines.
+    pub fn finish(mut self) -> (Vec<super::bin::NewChunk<'a>>, Vec<u8>) {
+        if !self.partial.is_empty() {
+            let partial = std::mem::take(&mut self.partial);
+            self.push(&partial)
+        }
+        (self.lines, self.new)
+    }
+
+    fn push(&mut self, l: &[u8]) {
+        if let Some(old) = self.old.get(l) {
+            self.lines.push(super::bin::NewChunk::Old(old))
+        } else {
+            self.lines.push(super::bin::NewChunk::New {
+                start: self.new.len(),
+                len: l.len(),
+            });
+            self.new.extend_from_slice(l)
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::output::ConflictId;
 use crate::pristine::*;
 use crate::vertex_buffer;
 use crate::{HashMap, HashSet};
@@ -13,6 +14,7 @@ pub(super) struct Diff {
     conflict_stack: Vec<Conflict>,
     pub conflict_ends: Vec<ConflictEnds>,
     pub cyclic_conflict_bytes: Vec<(usize, usize)>,
+    ids: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -58,6 +60,7 @@ impl Diff {
         inode: Position<Option<ChangeId>>,
         path: String,
         graph: &crate::alive::Graph,
+        ids: bool,
     ) -> Self {
         Diff {
             inode,
@@ -79,6 +82,7 @@ impl Diff {
                 conflict_type: ConflictType::Root,
             }],
             cyclic_conflict_bytes: Vec::new(),
+            ids,
         }
     }
 }
@@ -154,21 +158,21 @@ impl vertex_buffer::VertexBuffer for Diff {
         Ok(())
     }
 
-    fn begin_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         self.begin_conflict_(ConflictType::Order);
-        self.output_conflict_marker(vertex_buffer::START_MARKER)
+        self.output_conflict_marker(&vertex_buffer::start_marker(id, self.ids))
     }
 
-    fn begin_cyclic_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_cyclic_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         let len = self.contents_a.len();
         self.begin_conflict_(ConflictType::Cyclic);
         self.cyclic_conflict_bytes.push((len, len));
-        self.output_conflict_marker(vertex_buffer::START_MARKER)
+        self.output_conflict_marker(&vertex_buffer::start_marker(id, self.ids))
     }
 
-    fn begin_zombie_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_zombie_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         self.begin_conflict_(ConflictType::Zombie);
-        self.output_conflict_marker(vertex_buffer::START_MARKER)
+        self.output_conflict_marker(&vertex_buffer::start_marker(id, self.ids))
     }
 
     fn end_conflict(&mut self) -> Result<(), std::io::Error> {
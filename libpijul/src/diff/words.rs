@@ -0,0 +1,141 @@
+//! Intra-line diffs: the lines replaced by a line diff are split into
+//! words and diffed again, so that a small edit in a long line only
+//! inserts and deletes the words that changed.
+use super::diff::{Replacement, D};
+use super::{DiffAlgorithm, Line};
+
+/// The old and new versions of a file split into words where the line
+/// diff found replacements, and the diff of these words.
+pub(super) struct Words<'a> {
+    pub a: Vec<Line<'a>>,
+    pub b: Vec<Line<'a>>,
+    pub dd: D,
+    pub lines: WordLines,
+}
+
+/// The lines of the words of the new version.
+pub(super) struct WordLines {
+    /// The index in the lines of the new version of each word.
+    line_of: Vec<usize>,
+    lines_b: usize,
+}
+
+impl WordLines {
+    /// The line number (starting at 1) of the line containing word
+    /// number `word` (starting at 1) of the new version.
+    pub fn line(&self, word: usize) -> usize {
+        if let Some(&l) = self.line_of.get(word - 1) {
+            l + 1
+        } else {
+            self.lines_b + 1
+        }
+    }
+}
+
+impl<'a> Words<'a> {
+    fn push_lines(&mut self, a: &[Line<'a>], b: &[Line<'a>], new: usize, new_end: usize) {
+        self.a.extend_from_slice(a);
+        self.b.extend_from_slice(&b[new..new_end]);
+        self.lines.line_of.extend(new..new_end);
+    }
+}
+
+pub(super) fn refine<'a>(
+    lines_a: &[Line<'a>],
+    lines_b: &[Line<'a>],
+    dd: &D,
+    algorithm: &dyn DiffAlgorithm,
+) -> Words<'a> {
+    let mut w = Words {
+        a: Vec::with_capacity(lines_a.len()),
+        b: Vec::with_capacity(lines_b.len()),
+        dd: D(Vec::with_capacity(dd.len())),
+        lines: WordLines {
+            line_of: Vec::with_capacity(lines_b.len()),
+            lines_b: lines_b.len(),
+        },
+    };
+    let (mut old, mut new) = (0, 0);
+    for r in dd.0.iter() {
+        w.push_lines(&lines_a[old..r.old], lines_b, new, r.new);
+        let old_lines = &lines_a[r.old..r.old + r.old_len];
+        let new_lines = &lines_b[r.new..r.new + r.new_len];
+        if r.old_len > 0
+            && r.new_len > 0
+            && !r.is_cyclic
+            && old_lines.iter().all(splittable)
+            && new_lines.iter().all(splittable)
+        {
+            let a_start = w.a.len();
+            let b_start = w.b.len();
+            for l in old_lines {
+                split_words(l, &mut w.a)
+            }
+            for (i, l) in new_lines.iter().enumerate() {
+                split_words(l, &mut w.b);
+                w.lines.line_of.resize(w.b.len(), r.new + i);
+            }
+            for repl in algorithm.diff(&w.a[a_start..], &w.b[b_start..]) {
+                w.dd.0.push(Replacement::new(
+                    a_start + repl.old,
+                    repl.old_len,
+                    b_start + repl.new,
+                    repl.new_len,
+                ))
+            }
+        } else {
+            let (a_start, b_start) = (w.a.len(), w.b.len());
+            w.push_lines(old_lines, lines_b, r.new, r.new + r.new_len);
+            w.dd.0.push(Replacement {
+                old: a_start,
+                new: b_start,
+                ..r.clone()
+            })
+        }
+        old = r.old + r.old_len;
+        new = r.new + r.new_len;
+    }
+    w.push_lines(&lines_a[old..], lines_b, new, lines_b.len());
+    debug!("words: {:?}", w.dd);
+    w
+}
+
+/// Lines with conflict markers or in cyclic conflicts are compared
+/// as a whole.
+fn splittable(l: &Line) -> bool {
+    l.marker.is_none() && !l.cyclic && !l.before_end_marker
+}
+
+/// Split `l` into words: runs of alphanumeric characters, runs of
+/// spaces and tabs, line endings and single punctuation characters.
+/// Bytes outside of ASCII are treated as alphanumeric, so that
+/// multibyte characters are never split.
+fn split_words<'a>(l: &Line<'a>, words: &mut Vec<Line<'a>>) {
+    let bytes = l.l;
+    let mut i = 0;
+    while i < bytes.len() {
+        let len = match bytes[i] {
+            b'\n' => 1,
+            b'\r' if bytes.get(i + 1) == Some(&b'\n') => 2,
+            b' ' | b'\t' => run(&bytes[i..], |c| c == b' ' || c == b'\t'),
+            c if is_word(c) => run(&bytes[i..], is_word),
+            _ => 1,
+        };
+        let w = &bytes[i..i + len];
+        i += len;
+        words.push(Line {
+            l: w,
+            ptr: w.as_ptr(),
+            last: l.last && i >= bytes.len(),
+            ..*l
+        })
+    }
+}
+
+fn is_word(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c >= 0x80
+}
+
+fn run(bytes: &[u8], f: impl Fn(u8) -> bool) -> usize {
+    bytes.iter().position(|&c| !f(c)).unwrap_or(bytes.len())
+}
@@ -0,0 +1,122 @@
+//! A cache of the size and modification time of the files of the
+//! working copy, like the index of Git.
+//!
+//! Record only diffs the files modified since the channel was last
+//! touched, but modification times are compared with the granularity
+//! of a second, so the files of a large tree modified in the same
+//! second as the last record are read and diffed every time. When
+//! record finds a file identical to the pristine,
+//! [`Recorded::save_file_stats`](crate::record::Recorded::save_file_stats)
+//! stores its size and exact modification time here, along with a
+//! digest of the changes touching it (see
+//! [`crate::file_stats::history`]). Later records skip the files whose
+//! size, modification time and history are still the same, without
+//! reading them.
+//!
+//! As in Git, an entry is only trusted if the file was modified in
+//! an earlier second than the entry was written: otherwise, the file
+//! could be modified again without its modification time changing.
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use std::time::SystemTime;
+
+/// A file as it was when it was last found identical to the
+/// pristine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    /// Modification time, in seconds and nanoseconds since the Unix
+    /// epoch.
+    pub mtime: (u64, u32),
+    /// Digest of the changes touching the file, see
+    /// [`crate::file_stats::history`].
+    pub history: Hash,
+    /// Time at which this entry was written, in the same format as
+    /// `mtime`.
+    pub written: (u64, u32),
+}
+
+/// The size and modification time of a file of the working copy,
+/// read before its contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,
+    pub mtime: (u64, u32),
+}
+
+fn timestamp(t: SystemTime) -> (u64, u32) {
+    let d = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    (d.as_secs(), d.subsec_nanos())
+}
+
+/// The size and modification time of `path`, or `None` if the working
+/// copy doesn't tell.
+pub fn stat<W: WorkingCopy>(working_copy: &W, path: &str) -> Option<Stat> {
+    let size = working_copy.file_size(path).ok()??;
+    let mtime = working_copy.modified_time(path).ok()?;
+    Some(Stat {
+        size,
+        mtime: timestamp(mtime),
+    })
+}
+
+/// The cache entry of file `inode`. Entries that can't be read are
+/// ignored.
+pub fn load<T: TreeTxnT>(
+    txn: &T,
+    inode: Inode,
+) -> Result<Option<CacheEntry>, TxnErr<T::TreeError>> {
+    if let Some(bytes) = txn.get_dirty_cache(inode)? {
+        Ok(bincode::deserialize(bytes).ok())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Store `entry` as the cache entry of file `inode`.
+pub fn save<T: TreeMutTxnT>(
+    txn: &mut T,
+    inode: Inode,
+    entry: &CacheEntry,
+) -> Result<(), TxnErr<T::TreeError>> {
+    let bytes = bincode::serialize(entry).unwrap();
+    txn.put_dirty_cache(inode, &bytes)
+}
+
+/// Remember that file `inode`, whose inode vertex is `pos`, was
+/// identical to the pristine of `channel` when it was `stat`.
+pub fn update<T: MutTxnT>(
+    txn: &mut T,
+    channel: &T::Channel,
+    inode: Inode,
+    pos: Position<ChangeId>,
+    stat: Stat,
+) -> Result<(), TxnErr<T::GraphError>> {
+    let entry = CacheEntry {
+        size: stat.size,
+        mtime: stat.mtime,
+        history: crate::file_stats::history(txn, channel, pos)?,
+        written: timestamp(SystemTime::now()),
+    };
+    save(txn, inode, &entry)
+}
+
+/// Whether file `inode`, whose inode vertex is `pos`, is still
+/// identical to the pristine of `channel`, according to the cache.
+pub fn is_clean<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    inode: Inode,
+    pos: Position<ChangeId>,
+    stat: Stat,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    let entry = if let Some(entry) = load(txn, inode)? {
+        entry
+    } else {
+        return Ok(false);
+    };
+    Ok(entry.size == stat.size
+        && entry.mtime == stat.mtime
+        && entry.mtime.0 < entry.written.0
+        && entry.history == crate::file_stats::history(txn, channel, pos)?)
+}
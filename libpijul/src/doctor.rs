@@ -0,0 +1,508 @@
+//! Automated diagnosis of a repository.
+//!
+//! [`diagnose`] runs a battery of checks on a pristine, a change store
+//! and a working copy, and collects what it finds in a [`Report`],
+//! along with suggested fixes. Reports are serializable, so that they
+//! can be attached to bug reports.
+//!
+//! None of the checks modify the repository. Problems found in the
+//! repository are reported as [`Finding`]s; only errors preventing
+//! the checks from running at all are returned as errors.
+use crate::changestore::ChangeStore;
+use crate::fs::iter_working_copy;
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use crate::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The checks run by [`diagnose`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Check {
+    /// Readability of the tables, and consistency of the graph.
+    Pristine,
+    /// Presence and integrity of the changes of each channel.
+    Changestore,
+    /// Files tracked in the tree, but not linked to an alive vertex.
+    Tree,
+    /// Modification times in the future.
+    ClockSkew,
+    /// Size of the graphs, and proportion of pseudo-edges.
+    TableSize,
+    /// Tracked files missing from the working copy.
+    WorkingCopy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    /// Not a problem, but worth knowing.
+    Info,
+    /// Something that may cause surprising behaviour.
+    Warning,
+    /// Something broken.
+    Error,
+}
+
+/// A problem found by a check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub check: Check,
+    pub severity: Severity,
+    pub channel: Option<String>,
+    pub path: Option<String>,
+    pub message: String,
+    /// A suggested fix, if there is one.
+    pub fix: Option<String>,
+}
+
+/// The result of a diagnosis.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    /// The checks that were run.
+    pub checks: Vec<Check>,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Whether no finding is a warning or an error.
+    pub fn is_healthy(&self) -> bool {
+        self.findings.iter().all(|f| f.severity == Severity::Info)
+    }
+
+    /// The severity of the worst finding, if any.
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity).max()
+    }
+
+    /// Write this report to `w`, as JSON.
+    pub fn to_json<W: std::io::Write>(&self, w: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(w, self)
+    }
+
+    fn ran(&mut self, check: Check) {
+        if !self.checks.contains(&check) {
+            self.checks.push(check)
+        }
+    }
+
+    fn push(
+        &mut self,
+        check: Check,
+        severity: Severity,
+        channel: Option<&str>,
+        path: Option<&str>,
+        message: String,
+        fix: Option<String>,
+    ) {
+        self.findings.push(Finding {
+            check,
+            severity,
+            channel: channel.map(|c| c.to_string()),
+            path: path.map(|p| p.to_string()),
+            message,
+            fix,
+        })
+    }
+}
+
+/// Thresholds used by the checks.
+#[derive(Debug, Clone, Copy)]
+pub struct DoctorOptions {
+    /// Number of seconds a modification time may be in the future
+    /// before it is reported.
+    pub clock_skew_tolerance: u64,
+    /// Number of entries in the graph of a channel above which the
+    /// graph is reported as large.
+    pub max_graph_entries: usize,
+    /// Proportion of pseudo-edges in the graph of a channel above
+    /// which pruning them is suggested. Only graphs with at least
+    /// `min_pseudo_edges` pseudo-edges are considered.
+    pub max_pseudo_ratio: f64,
+    pub min_pseudo_edges: usize,
+}
+
+impl Default for DoctorOptions {
+    fn default() -> Self {
+        DoctorOptions {
+            clock_skew_tolerance: 60,
+            max_graph_entries: 10_000_000,
+            max_pseudo_ratio: 0.5,
+            min_pseudo_edges: 1000,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Run all the checks, `channel` being the channel the working copy
+/// is checked out on.
+pub fn diagnose<T: TxnT + GraphIter, C: ChangeStore, W: WorkingCopy>(
+    txn: &T,
+    changes: &C,
+    working_copy: &W,
+    channel: &ChannelRef<T>,
+    options: &DoctorOptions,
+) -> Result<Report, TxnErr<T::GraphError>> {
+    let mut report = Report::default();
+    check_graph(txn, channel, &mut report);
+    check_changestore(txn, changes, &mut report)?;
+    check_tree(txn, channel, &mut report)?;
+    check_clock_skew(txn, working_copy, channel, options, &mut report)?;
+    check_table_sizes(txn, options, &mut report)?;
+    check_working_copy(txn, working_copy, &mut report)?;
+    Ok(report)
+}
+
+/// Check that the tables of `pristine` can be read, adding the
+/// result to `report`. This is the only check specific to the
+/// Sanakirja backend.
+pub fn check_tables(
+    pristine: &crate::pristine::sanakirja::Pristine,
+    report: &mut Report,
+) -> Result<(), crate::pristine::sanakirja::SanakirjaError> {
+    report.ran(Check::Pristine);
+    let txn = match pristine.txn_begin_degraded() {
+        Ok(txn) => txn,
+        Err(crate::pristine::sanakirja::SanakirjaError::PristineCorrupt) => {
+            report.push(
+                Check::Pristine,
+                Severity::Error,
+                None,
+                None,
+                "The tables listing changes and channels are corrupt".to_string(),
+                Some("Clone the repository again, and copy the working copy over".to_string()),
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    for table in txn.broken_tables.iter() {
+        report.push(
+            Check::Pristine,
+            Severity::Error,
+            None,
+            None,
+            format!("Table {:?} is corrupt", table),
+            Some("Rebuild the pristine by applying the changes again".to_string()),
+        )
+    }
+    for channel in txn.broken_channels.iter() {
+        report.push(
+            Check::Pristine,
+            Severity::Error,
+            Some(channel),
+            None,
+            format!("Channel {} is corrupt", channel),
+            Some(format!(
+                "Delete channel {} and pull it again from a remote",
+                channel
+            )),
+        )
+    }
+    Ok(())
+}
+
+/// Check that the alive vertices of the graph of `channel` are
+/// exactly those reachable from the root.
+pub fn check_graph<T: ChannelTxnT + GraphIter>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    report: &mut Report,
+) {
+    report.ran(Check::Pristine);
+    let channel = channel.read();
+    let name = txn.name(&*channel);
+    let (alive, reachable) = check_alive(txn, txn.graph(&*channel));
+    if !alive.is_empty() {
+        report.push(
+            Check::Pristine,
+            Severity::Error,
+            Some(name),
+            None,
+            format!(
+                "{} alive vertices are unreachable from the root",
+                alive.len()
+            ),
+            Some(format!(
+                "Rebuild channel {} by applying its changes to a new channel",
+                name
+            )),
+        )
+    }
+    if !reachable.is_empty() {
+        report.push(
+            Check::Pristine,
+            Severity::Error,
+            Some(name),
+            None,
+            format!(
+                "{} vertices are reachable only through pseudo-edges",
+                reachable.len()
+            ),
+            Some(format!(
+                "Rebuild channel {} by applying its changes to a new channel",
+                name
+            )),
+        )
+    }
+}
+
+/// Check that the changes of all channels are in the change store,
+/// and can be read.
+pub fn check_changestore<T: TxnT, C: ChangeStore>(
+    txn: &T,
+    changes: &C,
+    report: &mut Report,
+) -> Result<(), TxnErr<T::GraphError>> {
+    report.ran(Check::Changestore);
+    let mut seen = HashSet::default();
+    for c in txn.iter_channels("")? {
+        let (name, c) = c?;
+        let c = c.read();
+        for x in changeid_log(txn, &*c, L64(0))? {
+            let (_, p) = x?;
+            if !seen.insert(p.a) {
+                continue;
+            }
+            let h: Hash = if let Some(h) = txn.get_external(&p.a)? {
+                h.into()
+            } else {
+                report.push(
+                    Check::Changestore,
+                    Severity::Error,
+                    Some(name.as_str()),
+                    None,
+                    format!("Change {:?} has no hash", p.a),
+                    Some("Rebuild the pristine by applying the changes again".to_string()),
+                );
+                continue;
+            };
+            if let Err(e) = changes.get_change(&h) {
+                report.push(
+                    Check::Changestore,
+                    Severity::Error,
+                    Some(name.as_str()),
+                    None,
+                    format!("Change {} is missing or corrupt: {}", h.to_base32(), e),
+                    Some(format!("Pull change {} again from a remote", h.to_base32())),
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that each file of the tree is linked to an alive vertex of
+/// `channel`, and can be reached from the root of the tree.
+pub fn check_tree<T: TxnT>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    report: &mut Report,
+) -> Result<(), TxnErr<T::GraphError>> {
+    report.ran(Check::Tree);
+    let channel = channel.read();
+    let name = txn.name(&*channel);
+    for x in txn.iter_inodes()? {
+        let (inode, pos) = x?;
+        let path = crate::fs::inode_filename(txn, *inode)?;
+        if path.is_none() {
+            report.push(
+                Check::Tree,
+                Severity::Error,
+                None,
+                None,
+                format!("Inode {:?} is not reachable from the root", inode),
+                Some("Export the tree, fix it, and import it again".to_string()),
+            );
+            continue;
+        }
+        let path = path.as_deref();
+        if txn.get_external(&pos.change)?.is_none() {
+            report.push(
+                Check::Tree,
+                Severity::Error,
+                None,
+                path,
+                format!("The file refers to unknown change {:?}", pos.change),
+                Some("Run `pijul reset` to output the channel again".to_string()),
+            )
+        } else if !is_alive(txn, txn.graph(&*channel), &pos.inode_vertex())? {
+            report.push(
+                Check::Tree,
+                Severity::Warning,
+                Some(name),
+                path,
+                "The file is tracked, but deleted in the channel".to_string(),
+                Some("Run `pijul reset` to output the channel again".to_string()),
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Check that neither `channel` nor the tracked files have been
+/// modified in the future. Recording skips the files modified before
+/// the last modification of the channel, so such times can hide
+/// changes from `pijul record`.
+pub fn check_clock_skew<T: TxnT, W: WorkingCopy>(
+    txn: &T,
+    working_copy: &W,
+    channel: &ChannelRef<T>,
+    options: &DoctorOptions,
+    report: &mut Report,
+) -> Result<(), TxnErr<T::GraphError>> {
+    report.ran(Check::ClockSkew);
+    let limit = now() + options.clock_skew_tolerance;
+    let channel = channel.read();
+    let name = txn.name(&*channel);
+    let last_modified = txn.last_modified(&*channel);
+    if last_modified > limit {
+        report.push(
+            Check::ClockSkew,
+            Severity::Warning,
+            Some(name),
+            None,
+            format!(
+                "The channel was last modified {} seconds in the future",
+                last_modified - now()
+            ),
+            Some("Fix the system clock, and touch the files modified since".to_string()),
+        )
+    }
+    for x in iter_working_copy(txn, Inode::ROOT) {
+        let (_, path) = x.map_err(TxnErr)?;
+        let mtime = if let Ok(t) = working_copy.modified_time(&path) {
+            t
+        } else {
+            continue;
+        };
+        let mtime = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if mtime > limit {
+            report.push(
+                Check::ClockSkew,
+                Severity::Warning,
+                None,
+                Some(&path),
+                format!(
+                    "The file was modified {} seconds in the future",
+                    mtime - now()
+                ),
+                Some("Fix the system clock, and touch the file".to_string()),
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Number of entries in a graph.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GraphStats {
+    pub entries: usize,
+    /// Number of entries that are pseudo-edges.
+    pub pseudo: usize,
+}
+
+impl GraphStats {
+    /// Proportion of pseudo-edges among the entries.
+    pub fn pseudo_ratio(&self) -> f64 {
+        if self.entries == 0 {
+            0.
+        } else {
+            self.pseudo as f64 / self.entries as f64
+        }
+    }
+}
+
+/// Count the entries of `graph`. This reads the entire graph.
+pub fn graph_stats<T: GraphIter>(
+    txn: &T,
+    graph: &T::Graph,
+) -> Result<GraphStats, TxnErr<T::GraphError>> {
+    let mut stats = GraphStats::default();
+    for x in txn.iter_graph(graph, None)? {
+        let (_, e) = x?;
+        stats.entries += 1;
+        if e.flag().contains(EdgeFlags::PSEUDO) {
+            stats.pseudo += 1
+        }
+    }
+    Ok(stats)
+}
+
+/// Report the graphs that are very large, or made mostly of
+/// pseudo-edges.
+pub fn check_table_sizes<T: TxnT + GraphIter>(
+    txn: &T,
+    options: &DoctorOptions,
+    report: &mut Report,
+) -> Result<(), TxnErr<T::GraphError>> {
+    report.ran(Check::TableSize);
+    for c in txn.iter_channels("")? {
+        let (name, c) = c?;
+        let c = c.read();
+        let stats = graph_stats(txn, txn.graph(&*c))?;
+        if stats.entries > options.max_graph_entries {
+            report.push(
+                Check::TableSize,
+                Severity::Info,
+                Some(name.as_str()),
+                None,
+                format!("The graph has {} entries", stats.entries),
+                None,
+            )
+        }
+        if stats.pseudo >= options.min_pseudo_edges
+            && stats.pseudo_ratio() > options.max_pseudo_ratio
+        {
+            report.push(
+                Check::TableSize,
+                Severity::Warning,
+                Some(name.as_str()),
+                None,
+                format!(
+                    "{} of the {} graph entries are pseudo-edges",
+                    stats.pseudo, stats.entries
+                ),
+                Some(format!(
+                    "Prune the redundant pseudo-edges of channel {}",
+                    name.as_str()
+                )),
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Check that all the files tracked in the tree exist in the working
+/// copy.
+pub fn check_working_copy<T: TxnT, W: WorkingCopy>(
+    txn: &T,
+    working_copy: &W,
+    report: &mut Report,
+) -> Result<(), TxnErr<T::GraphError>> {
+    report.ran(Check::WorkingCopy);
+    for x in iter_working_copy(txn, Inode::ROOT) {
+        let (_, path) = x.map_err(TxnErr)?;
+        if working_copy.file_metadata(&path).is_err() {
+            report.push(
+                Check::WorkingCopy,
+                Severity::Warning,
+                None,
+                Some(&path),
+                "The file is tracked, but missing from the working copy".to_string(),
+                Some(format!(
+                    "Run `pijul remove {}` to stop tracking it, or `pijul reset {}` to restore it",
+                    path, path
+                )),
+            )
+        }
+    }
+    Ok(())
+}
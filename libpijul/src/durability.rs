@@ -0,0 +1,138 @@
+//! Durability of the files written to the working copy.
+//!
+//! Output writes files, then updates the tree tables of the pristine
+//! to describe them, and the caller commits these updates. The
+//! pristine is always committed durably, but the files may still be
+//! in the caches of the operating system at that point, in which case
+//! a crash leaves a tree table describing files that were never
+//! written. A [`Durability`] policy other than [`Durability::None`]
+//! makes the files durable before output returns, hence before the
+//! tree-table updates are committed.
+//!
+//! Files aren't synchronised one by one as they are written: the
+//! paths written by an operation are collected in a [`SyncBatch`],
+//! and synchronised all at once at the end of the operation, which
+//! costs much less than a synchronisation per file on most
+//! filesystems.
+use crate::HashSet;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+
+/// How much to synchronise with the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// Leave it to the operating system. This is the fastest, but a
+    /// crash may lose the files written just before it.
+    None,
+    /// Synchronise the contents of the files written, but not the
+    /// directories containing them, so new names and renames may
+    /// still be lost.
+    FsyncData,
+    /// Synchronise the files written along with their metadata, and
+    /// the directories containing them.
+    FsyncAll,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::None
+    }
+}
+
+impl std::str::FromStr for Durability {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "fsync-data" => Ok(Durability::FsyncData),
+            "fsync-all" => Ok(Durability::FsyncAll),
+            _ => Err(format!("Unknown durability policy: {:?}", s)),
+        }
+    }
+}
+
+/// The paths written since the last [`SyncBatch::sync`].
+#[derive(Debug, Default)]
+pub struct SyncBatch {
+    durability: Mutex<Durability>,
+    paths: Mutex<HashSet<PathBuf>>,
+}
+
+impl SyncBatch {
+    pub fn new(durability: Durability) -> Self {
+        SyncBatch {
+            durability: Mutex::new(durability),
+            paths: Mutex::new(HashSet::default()),
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        *self.durability.lock()
+    }
+
+    /// Change the policy of this batch, for instance for a single
+    /// operation. The paths already pushed are kept, and synchronised
+    /// according to the new policy at the next [`SyncBatch::sync`].
+    pub fn set_durability(&self, durability: Durability) {
+        *self.durability.lock() = durability
+    }
+
+    /// Remember that `path` was written, created, renamed or
+    /// deleted. This does nothing with [`Durability::None`].
+    pub fn push(&self, path: &Path) {
+        if self.durability() != Durability::None {
+            self.paths.lock().insert(path.to_path_buf());
+        }
+    }
+
+    /// Synchronise the paths pushed since the last call, according to
+    /// the policy of this batch. Paths that no longer exist are
+    /// skipped, but with [`Durability::FsyncAll`], the directories
+    /// they were in are still synchronised.
+    pub fn sync(&self) -> Result<(), std::io::Error> {
+        let paths = std::mem::take(&mut *self.paths.lock());
+        let durability = self.durability();
+        let mut dirs = HashSet::default();
+        for path in paths.iter() {
+            match std::fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_file() => {
+                    let file = std::fs::File::open(path)?;
+                    if durability == Durability::FsyncAll {
+                        file.sync_all()?
+                    } else {
+                        file.sync_data()?
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            if durability == Durability::FsyncAll {
+                if let Some(parent) = path.parent() {
+                    dirs.insert(parent);
+                }
+            }
+        }
+        for dir in dirs {
+            sync_dir(dir)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    match std::fs::File::open(dir) {
+        Ok(dir) => dir.sync_all(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Directories can't be opened on Windows, where their entries are
+// made durable along with the files.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
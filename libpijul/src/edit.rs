@@ -0,0 +1,217 @@
+//! Mechanical edits of files, recorded without a working copy.
+//!
+//! Bots doing repository-wide updates, such as bumping the year in a
+//! license header, know which lines to change in each file, and don't
+//! need to output the repository to change them. [`apply_mechanical`]
+//! applies line edits to the files as they are in the pristine, and
+//! diffs the result against the pristine, which gives the hunks of a
+//! change.
+use crate::alive::output_graph;
+use crate::alive::retrieve::{retrieve_limited, RetrieveLimits};
+use crate::changestore::{ChangeStore, FileMetadata};
+use crate::diff::{Algorithm, DiffError};
+use crate::fs::FsErrorC;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::record::{Builder, Recorded};
+use crate::text_encoding::Encoding;
+use crate::vertex_buffer::Writer;
+use std::collections::BTreeMap;
+
+/// An edit of the lines of a file. Lines are numbered from 0, and
+/// `text` is inserted as is, so it should usually end with a newline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineEdit {
+    /// Insert `text` before line `line`. Inserting before the number
+    /// of lines of the file appends to the file.
+    Insert { line: usize, text: Vec<u8> },
+    /// Replace lines `start..end` with `text`.
+    Replace {
+        start: usize,
+        end: usize,
+        text: Vec<u8>,
+    },
+}
+
+impl LineEdit {
+    fn range(&self) -> (usize, usize) {
+        match *self {
+            LineEdit::Insert { line, .. } => (line, line),
+            LineEdit::Replace { start, end, .. } => (start, end),
+        }
+    }
+
+    fn text(&self) -> &[u8] {
+        match *self {
+            LineEdit::Insert { ref text, .. } => text,
+            LineEdit::Replace { ref text, .. } => text,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EditError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Changestore(C),
+    #[error(transparent)]
+    Fs(#[from] FsErrorC<C, T>),
+    #[error(transparent)]
+    Output(#[from] FileError<C, T>),
+    #[error(transparent)]
+    Diff(#[from] DiffError<C, T>),
+    #[error(transparent)]
+    Block(#[from] BlockError<T>),
+    #[error("Ambiguous path: {0}")]
+    AmbiguousPath(String),
+    #[error("Not a file: {0}")]
+    NotAFile(String),
+    #[error("Lines {start}..{end} are out of the bounds of {path}")]
+    OutOfBounds {
+        path: String,
+        start: usize,
+        end: usize,
+    },
+    #[error("Overlapping edits in {0}")]
+    Overlapping(String),
+    #[error(transparent)]
+    Chunks(#[from] crate::record::ChunksError),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for EditError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        EditError::Txn(e.0)
+    }
+}
+
+/// Apply `edits` to the files of `channel`, and record the result.
+/// The edits of each file are relative to its current version in the
+/// pristine, and must not overlap.
+///
+/// The returned [`Recorded`] has no inode updates, and its actions
+/// are turned into a change as with any other recording. Files whose
+/// graphs exceed `limits` fail with [`FileError::GraphTooLarge`].
+pub fn apply_mechanical<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    edits: Vec<(String, LineEdit)>,
+    limits: RetrieveLimits,
+) -> Result<Recorded, EditError<C::Error, T::GraphError>> {
+    let mut files = BTreeMap::new();
+    for (path, edit) in edits {
+        files.entry(path).or_insert_with(Vec::new).push(edit)
+    }
+    let mut builder = Builder::new();
+    builder.graph_limits = limits;
+    let rec = builder.recorded();
+    for (path, edits) in files {
+        let (pos, ambiguous) = crate::fs::follow_oldest_path(changes, txn, channel, &path)?;
+        if ambiguous {
+            return Err(EditError::AmbiguousPath(path));
+        }
+        let encoding = file_encoding(changes, txn, channel, &path, pos)?;
+
+        let mut graph =
+            retrieve_limited(txn, txn.graph(channel), pos, limits).map_err(FileError::from)?;
+        let mut old = Writer::new(Vec::new());
+        output_graph(changes, txn, channel, &mut old, &mut graph, &mut Vec::new())?;
+        let new = apply_edits(&path, &old.into_inner(), edits)?;
+
+        let mut graph =
+            retrieve_limited(txn, txn.graph(channel), pos, limits).map_err(FileError::from)?;
+        rec.lock().diff(
+            changes,
+            txn,
+            channel,
+            &Algorithm::default(),
+            path,
+            pos.to_option(),
+            &mut graph,
+            &new,
+            &encoding,
+        )?;
+    }
+    std::mem::drop(rec);
+    Ok(builder.finish()?)
+}
+
+/// The encoding of the file at `pos`, as recorded in its name.
+fn file_encoding<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    path: &str,
+    pos: Position<ChangeId>,
+) -> Result<Option<Encoding>, EditError<C::Error, T::GraphError>> {
+    let f0 = EdgeFlags::FOLDER | EdgeFlags::PARENT;
+    let f1 = EdgeFlags::all();
+    for name in iter_adjacent(txn, txn.graph(channel), pos.inode_vertex(), f0, f1)? {
+        let name = name?;
+        if name.flag().contains(EdgeFlags::DELETED) {
+            continue;
+        }
+        let name_dest = txn.find_block_end(txn.graph(channel), name.dest())?;
+        let mut meta = Vec::new();
+        let FileMetadata {
+            metadata, encoding, ..
+        } = changes
+            .get_file_meta(
+                |p| txn.get_external(&p).unwrap().map(From::from),
+                *name_dest,
+                &mut meta,
+            )
+            .map_err(EditError::Changestore)?;
+        if metadata.is_dir() {
+            return Err(EditError::NotAFile(path.to_string()));
+        }
+        return Ok(encoding);
+    }
+    Err(EditError::NotAFile(path.to_string()))
+}
+
+/// Apply `edits` to the lines of `old`.
+fn apply_edits<C: std::error::Error + 'static, T: std::error::Error + 'static>(
+    path: &str,
+    old: &[u8],
+    mut edits: Vec<LineEdit>,
+) -> Result<Vec<u8>, EditError<C, T>> {
+    let mut starts = Vec::new();
+    let mut start = 0;
+    while start < old.len() {
+        starts.push(start);
+        start = match old[start..].iter().position(|&c| c == b'\n') {
+            Some(n) => start + n + 1,
+            None => old.len(),
+        }
+    }
+    let n = starts.len();
+    starts.push(old.len());
+
+    // Sorting is stable, so insertions at the same line are kept in
+    // order.
+    edits.sort_by_key(|e| e.range());
+    let mut new = Vec::with_capacity(old.len());
+    let mut current = 0;
+    for e in edits.iter() {
+        let (start, end) = e.range();
+        if start > end || end > n {
+            return Err(EditError::OutOfBounds {
+                path: path.to_string(),
+                start,
+                end,
+            });
+        }
+        if start < current {
+            return Err(EditError::Overlapping(path.to_string()));
+        }
+        new.extend_from_slice(&old[starts[current]..starts[start]]);
+        new.extend_from_slice(e.text());
+        current = end;
+    }
+    new.extend_from_slice(&old[starts[current]..]);
+    Ok(new)
+}
@@ -0,0 +1,95 @@
+//! Executors running the background jobs of libpijul.
+//!
+//! [`Builder::record`](crate::record::Builder::record) diffs the
+//! modified files and reads the new ones in jobs running alongside
+//! the traversal of the working copy. These jobs are handed to an
+//! [`Executor`], so that embedders decide where they run: on
+//! [`Threads`] spawned for them (the default), on an existing pool
+//! (such as a `rayon::ThreadPool`, with the `rayon` feature), or on
+//! the [`CurrentThread`], for single-threaded builds.
+//!
+//! Jobs run until the caller tells them to stop, and the caller waits
+//! for them before returning. A concurrent executor must therefore
+//! eventually run all the jobs it is given, and must not be a pool
+//! whose only threads are waiting for these jobs.
+use std::sync::mpsc;
+
+/// A job handed to an [`Executor`].
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs jobs, see the [module documentation](self).
+pub trait Executor: Send + Sync {
+    /// Run `job`, on another thread if this executor
+    /// [`is_concurrent`](Executor::is_concurrent).
+    fn execute(&self, job: Job);
+
+    /// Whether the jobs run concurrently with their caller. If not,
+    /// callers do the work on their own thread instead of spawning
+    /// jobs.
+    fn is_concurrent(&self) -> bool {
+        true
+    }
+}
+
+/// Spawn a new thread for each job.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Threads;
+
+impl Executor for Threads {
+    fn execute(&self, job: Job) {
+        std::thread::spawn(job);
+    }
+}
+
+/// Run each job on the calling thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CurrentThread;
+
+impl Executor for CurrentThread {
+    fn execute(&self, job: Job) {
+        job()
+    }
+
+    fn is_concurrent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Executor for rayon::ThreadPool {
+    fn execute(&self, job: Job) {
+        self.spawn(job)
+    }
+}
+
+/// The result of a job spawned with [`spawn`].
+pub struct JobHandle<R> {
+    result: mpsc::Receiver<std::thread::Result<R>>,
+}
+
+impl<R> JobHandle<R> {
+    /// Wait for the job to finish. Like
+    /// [`std::thread::JoinHandle::join`], this returns an error if
+    /// the job panicked, or if the executor dropped it.
+    pub fn join(self) -> std::thread::Result<R> {
+        match self.result.recv() {
+            Ok(result) => result,
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+/// Run `f` on `executor`. Panics are caught and returned by
+/// [`JobHandle::join`], rather than unwinding through the executor.
+pub fn spawn<E: Executor + ?Sized, R: Send + 'static, F: FnOnce() -> R + Send + 'static>(
+    executor: &E,
+    f: F,
+) -> JobHandle<R> {
+    let (sender, result) = mpsc::channel();
+    executor.execute(Box::new(move || {
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        // The handle may have been dropped.
+        sender.send(r).unwrap_or(())
+    }));
+    JobHandle { result }
+}
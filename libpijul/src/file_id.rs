@@ -0,0 +1,273 @@
+//! Stable identifiers for files.
+//!
+//! Internally, a file is identified by the position of its inode
+//! vertex in the graph, i.e. by the change that added it, and the
+//! position of the inode in that change. Renames never change this
+//! position, and since it only refers to a change hash, it is the
+//! same in all repositories containing that change. This makes it a
+//! suitable identifier for tools that need to track files across
+//! history, such as code review or coverage tools.
+//!
+//! Most functions of this module work on the current state of a
+//! channel, except [`file_ids_in_history`], which looks for a path in
+//! the changes of the channel's log.
+use crate::change::{Atom, Hunk};
+use crate::changestore::ChangeStore;
+use crate::fs::{find_path, follow_oldest_path, FsErrorC};
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::HashSet;
+
+/// A stable identifier of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FileId(pub Position<Hash>);
+
+impl Base32 for FileId {
+    fn to_base32(&self) -> String {
+        self.0.to_base32()
+    }
+    fn from_base32(b: &[u8]) -> Option<Self> {
+        Position::from_base32(b).map(FileId)
+    }
+}
+
+impl std::fmt::Display for FileId {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.to_base32())
+    }
+}
+
+/// A name a file had at some point in the history of a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileName {
+    /// The full path, as of the current state of the channel's
+    /// directories.
+    pub path: String,
+    /// The change that gave this name to the file.
+    pub change: Hash,
+    /// Whether the file still has this name.
+    pub alive: bool,
+}
+
+fn internal<T: GraphTxnT>(
+    txn: &T,
+    id: &FileId,
+) -> Result<Option<Position<ChangeId>>, TxnErr<T::GraphError>> {
+    if let Some(&change) = txn.get_internal(&id.0.change.into())? {
+        Ok(Some(Position {
+            change,
+            pos: id.0.pos,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+fn external<T: GraphTxnT>(
+    txn: &T,
+    pos: Position<ChangeId>,
+) -> Result<Option<FileId>, TxnErr<T::GraphError>> {
+    Ok(txn.get_external(&pos.change)?.map(|change| {
+        FileId(Position {
+            change: change.into(),
+            pos: pos.pos,
+        })
+    }))
+}
+
+/// The identifier of the file at `path` on `channel`. If `path`
+/// leads to several files because of name conflicts, the oldest one
+/// is returned.
+pub fn file_id<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    path: &str,
+) -> Result<Option<FileId>, FileError<C::Error, T::GraphError>> {
+    match follow_oldest_path(changes, txn, channel, path) {
+        Ok((pos, _)) if pos.change.is_root() => Ok(None),
+        Ok((pos, _)) => Ok(external(txn, pos)?),
+        Err(FsErrorC::NotFound(_)) => Ok(None),
+        Err(FsErrorC::Changestore(e)) => Err(FileError::Changestore(e)),
+        Err(FsErrorC::Txn(e)) => Err(FileError::Txn(e)),
+    }
+}
+
+/// The identifiers of all the files that were given name `path` by
+/// the changes of `channel`, in the order of the log, including files
+/// renamed or deleted since.
+pub fn file_ids_in_history<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    path: &str,
+) -> Result<Vec<FileId>, FileError<C::Error, T::GraphError>> {
+    let mut ids = Vec::new();
+    for x in changeid_log(txn, channel, L64(0))? {
+        let (_, p) = x?;
+        let hash: Hash = if let Some(h) = txn.get_external(&p.a)? {
+            h.into()
+        } else {
+            continue;
+        };
+        let change = changes.get_change(&hash).map_err(FileError::Changestore)?;
+        for hunk in change.changes.iter() {
+            if hunk.path() != path {
+                continue;
+            }
+            let inode = match hunk {
+                Hunk::FileAdd {
+                    add_inode: Atom::NewVertex(ref n),
+                    ..
+                }
+                | Hunk::FileCopy {
+                    add_inode: Atom::NewVertex(ref n),
+                    ..
+                } => Position {
+                    change: hash,
+                    pos: n.start,
+                },
+                Hunk::FileMove {
+                    add: Atom::NewVertex(ref n),
+                    ..
+                } => {
+                    if let Some(inode) = n.down_context.first() {
+                        Position {
+                            change: inode.change.unwrap_or(hash),
+                            pos: inode.pos,
+                        }
+                    } else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+            let id = FileId(inode);
+            if !ids.contains(&id) {
+                ids.push(id)
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// The current path of file `id` on `channel`, or `None` if that
+/// file is not on the channel or has been deleted.
+pub fn file_path<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    id: &FileId,
+) -> Result<Option<String>, FileError<C::Error, T::GraphError>> {
+    let pos = if let Some(pos) = internal(txn, id)? {
+        pos
+    } else {
+        return Ok(None);
+    };
+    if txn
+        .get_changeset(txn.changes(channel), &pos.change)?
+        .is_none()
+    {
+        return Ok(None);
+    }
+    match find_path(changes, txn, channel, true, pos)? {
+        Some((path, true)) => Ok(Some(path)),
+        _ => Ok(None),
+    }
+}
+
+/// All the names file `id` has had on `channel`, in the order in
+/// which they were given, which allows one to follow a file across
+/// renames.
+pub fn file_names<T: ChannelTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    id: &FileId,
+) -> Result<Vec<FileName>, FileError<C::Error, T::GraphError>> {
+    let pos = if let Some(pos) = internal(txn, id)? {
+        pos
+    } else {
+        return Ok(Vec::new());
+    };
+    let graph = txn.graph(channel);
+    let mut names = Vec::new();
+    let mut seen = HashSet::default();
+    let mut buf = Vec::new();
+    for e in iter_adjacent(
+        txn,
+        graph,
+        pos.inode_vertex(),
+        EdgeFlags::FOLDER | EdgeFlags::PARENT,
+        EdgeFlags::all(),
+    )? {
+        let e = e?;
+        if !e.flag().contains(EdgeFlags::PARENT) || e.flag().contains(EdgeFlags::PSEUDO) {
+            continue;
+        }
+        let name = if let Ok(name) = txn.find_block_end(graph, e.dest()) {
+            *name
+        } else {
+            continue;
+        };
+        if !seen.insert(name) {
+            continue;
+        }
+        let time = if let Some(t) = txn.get_changeset(txn.changes(channel), &name.change)? {
+            *t
+        } else {
+            continue;
+        };
+        let basename = changes
+            .get_file_meta(
+                |p| txn.get_external(&p).ok().flatten().map(|x| x.into()),
+                name,
+                &mut buf,
+            )
+            .map_err(FileError::Changestore)?
+            .basename
+            .to_string();
+        // Renames and deletions delete the edge from the parent
+        // directory to the name.
+        let mut parent = None;
+        let mut alive = !e.flag().contains(EdgeFlags::DELETED);
+        for p in iter_adjacent(
+            txn,
+            graph,
+            name,
+            EdgeFlags::FOLDER | EdgeFlags::PARENT,
+            EdgeFlags::all(),
+        )? {
+            let p = p?;
+            if p.flag().contains(EdgeFlags::PARENT) && !p.flag().contains(EdgeFlags::PSEUDO) {
+                parent = Some(p.dest());
+                alive &= !p.flag().contains(EdgeFlags::DELETED);
+                break;
+            }
+        }
+        let path = match parent {
+            Some(parent) if !parent.change.is_root() => {
+                match find_path(changes, txn, channel, true, parent)? {
+                    Some((dir, _)) if !dir.is_empty() => format!("{}/{}", dir, basename),
+                    _ => basename,
+                }
+            }
+            _ => basename,
+        };
+        let change = if let Some(change) = txn.get_external(&name.change)? {
+            change.into()
+        } else {
+            continue;
+        };
+        names.push((
+            time,
+            FileName {
+                path,
+                change,
+                alive,
+            },
+        ))
+    }
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(names.into_iter().map(|(_, n)| n).collect())
+}
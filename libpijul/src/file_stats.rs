@@ -0,0 +1,287 @@
+//! Content statistics of the files of the working copy, stored in the
+//! pristine.
+//!
+//! Recording a file computes its text encoding, whether it is binary,
+//! and its line endings, and
+//! [`Recorded::save_file_stats`](crate::record::Recorded::save_file_stats)
+//! stores them by inode, so that diffs and listings can tell how a
+//! file is encoded without reading and detecting it again, and the
+//! next record of a file still in the same encoding skips detection
+//! (see [`FileStats::same_encoding`]). The
+//! statistics are those of the file as last recorded, not as it is
+//! now in the working copy.
+//!
+//! When record finds a file identical to the pristine, its statistics
+//! also remember the hash of its contents, along with a digest of the
+//! changes touching it (see [`Verified`]). Later records use them to
+//! skip diffing files whose only changes are their name or
+//! permissions.
+use crate::pristine::*;
+use crate::text_encoding::Encoding;
+
+/// The line endings of a text file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eol {
+    /// No line endings, i.e. at most one line.
+    None,
+    Lf,
+    Crlf,
+    Cr,
+    /// Several kinds of line endings.
+    Mixed,
+}
+
+/// The content statistics of a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStats {
+    /// Label of the text encoding, or `None` for binary files.
+    pub encoding: Option<String>,
+    /// Size in bytes.
+    pub size: u64,
+    /// Number of `\n` not preceded by `\r`.
+    pub lf: u64,
+    /// Number of `\r\n`.
+    pub crlf: u64,
+    /// Number of `\r` not followed by `\n`.
+    pub cr: u64,
+    /// Whether the file ends with a line ending.
+    pub final_eol: bool,
+    /// Set if the file was identical to the pristine when these
+    /// statistics were computed.
+    pub verified: Option<Verified>,
+}
+
+/// Proof that the contents of a file were in the pristine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Verified {
+    /// Hash of the contents, after applying the line ending policy.
+    pub contents: Hash,
+    /// Digest of the changes touching the file, see [`history`]. If
+    /// the file is still touched by the same changes, its contents in
+    /// the pristine are still `contents`.
+    pub history: Hash,
+}
+
+impl FileStats {
+    /// Compute the statistics of `contents`, detected as `encoding`
+    /// (`None` for binary files).
+    pub fn compute(contents: &[u8], encoding: &Option<Encoding>) -> Self {
+        let mut stats = FileStats {
+            encoding: encoding.as_ref().map(|e| e.label().to_string()),
+            size: contents.len() as u64,
+            lf: 0,
+            crlf: 0,
+            cr: 0,
+            final_eol: contents.ends_with(b"\n") || contents.ends_with(b"\r"),
+            verified: None,
+        };
+        // Line endings of other encodings aren't single bytes.
+        if !encoding
+            .as_ref()
+            .map(|e| e.0.is_ascii_compatible())
+            .unwrap_or(false)
+        {
+            stats.final_eol = false;
+            return stats;
+        }
+        let mut i = 0;
+        while i < contents.len() {
+            match contents[i] {
+                b'\n' => stats.lf += 1,
+                b'\r' if contents.get(i + 1) == Some(&b'\n') => {
+                    stats.crlf += 1;
+                    i += 1
+                }
+                b'\r' => stats.cr += 1,
+                _ => {}
+            }
+            i += 1
+        }
+        stats
+    }
+
+    /// Add the statistics of `contents`, read after the contents
+    /// these statistics were computed on, for files read in several
+    /// parts. `after_cr` is `true` if the previous part ended with
+    /// `\r`.
+    pub fn extend(&mut self, contents: &[u8], encoding: &Option<Encoding>, after_cr: bool) {
+        if contents.is_empty() {
+            return;
+        }
+        let next = FileStats::compute(contents, encoding);
+        self.size += next.size;
+        self.lf += next.lf;
+        self.crlf += next.crlf;
+        self.cr += next.cr;
+        self.final_eol = next.final_eol;
+        if after_cr && contents[0] == b'\n' && self.cr > 0 {
+            // A `\r\n` split between the two parts.
+            self.cr -= 1;
+            self.lf -= 1;
+            self.crlf += 1
+        }
+    }
+
+    /// The encoding of `contents`, the new contents of the file these
+    /// statistics were computed on, if it can be told without running
+    /// an encoding detector: binary files still containing a NUL byte
+    /// stay binary, UTF-8 files still valid UTF-8 stay UTF-8, and
+    /// ASCII contents keep the encoding of the file if it is
+    /// ASCII-compatible. Returns `None` if the encoding must be
+    /// detected again.
+    pub fn same_encoding(&self, contents: &[u8]) -> Option<Option<Encoding>> {
+        let has_nul = memchr::memchr(0, contents).is_some();
+        let label = if let Some(ref label) = self.encoding {
+            label
+        } else if has_nul {
+            return Some(None);
+        } else {
+            return None;
+        };
+        if has_nul {
+            return None;
+        }
+        let encoding = encoding_rs::Encoding::for_label_no_replacement(label.as_bytes())?;
+        if (encoding == encoding_rs::UTF_8 && std::str::from_utf8(contents).is_ok())
+            || (encoding.is_ascii_compatible() && contents.is_ascii())
+        {
+            Some(Some(Encoding(encoding)))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.encoding.is_none()
+    }
+
+    /// The line ending style of this file.
+    pub fn eol(&self) -> Eol {
+        match (self.lf > 0, self.crlf > 0, self.cr > 0) {
+            (false, false, false) => Eol::None,
+            (true, false, false) => Eol::Lf,
+            (false, true, false) => Eol::Crlf,
+            (false, false, true) => Eol::Cr,
+            _ => Eol::Mixed,
+        }
+    }
+
+    /// Number of lines, counting a last line without a line ending.
+    pub fn lines(&self) -> u64 {
+        let eols = self.lf + self.crlf + self.cr;
+        if self.size > 0 && !self.final_eol {
+            eols + 1
+        } else {
+            eols
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FileStatsError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Fs(#[from] crate::fs::FsError<T>),
+    #[error("Malformed statistics for inode {0:?}")]
+    Malformed(Inode),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for FileStatsError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        FileStatsError::Txn(e.0)
+    }
+}
+
+/// The statistics of file `inode`, if it was recorded since
+/// statistics were introduced.
+pub fn load<T: TxnT>(
+    txn: &T,
+    inode: Inode,
+) -> Result<Option<FileStats>, FileStatsError<T::GraphError>> {
+    if let Some(bytes) = txn.get_file_stats(inode)? {
+        Ok(Some(
+            bincode::deserialize(bytes).map_err(|_| FileStatsError::Malformed(inode))?,
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The statistics of the file at `path`.
+pub fn get<T: TxnT>(
+    txn: &T,
+    path: &str,
+) -> Result<Option<FileStats>, FileStatsError<T::GraphError>> {
+    let inode = crate::fs::find_inode(txn, path)?;
+    load(txn, inode)
+}
+
+/// The statistics of all the files of the working copy, with their
+/// paths, sorted by path.
+pub fn list<T: TxnT>(txn: &T) -> Result<Vec<(String, FileStats)>, FileStatsError<T::GraphError>> {
+    let mut result = Vec::new();
+    for (inode, bytes) in txn.iter_file_stats()? {
+        // Statistics of files that were removed from the tree
+        // without being recorded.
+        let path = if let Some(path) = crate::fs::inode_filename(txn, inode)? {
+            path
+        } else {
+            continue;
+        };
+        let stats = bincode::deserialize(bytes).map_err(|_| FileStatsError::Malformed(inode))?;
+        result.push((path, stats))
+    }
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(result)
+}
+
+/// The [`Verified`] contents of file `inode`, if any. Statistics that
+/// can't be read are ignored.
+pub fn verified<T: TxnT>(txn: &T, inode: Inode) -> Result<Option<Verified>, TxnErr<T::GraphError>> {
+    if let Some(bytes) = txn.get_file_stats(inode)? {
+        Ok(bincode::deserialize::<FileStats>(bytes)
+            .ok()
+            .and_then(|s| s.verified))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A digest of the set of changes of `channel` touching the file
+/// whose inode vertex is `pos`.
+pub fn history<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    pos: Position<ChangeId>,
+) -> Result<Hash, TxnErr<T::GraphError>> {
+    let mut touching = Vec::new();
+    for x in txn.iter_touched(&pos)? {
+        let (p, change) = x?;
+        if *p > pos {
+            break;
+        } else if *p < pos {
+            continue;
+        }
+        if txn.get_changeset(txn.changes(channel), change)?.is_some() {
+            touching.push(*change)
+        }
+    }
+    touching.sort();
+    let mut hasher = Hasher::default();
+    for change in touching {
+        hasher.update(&u64::from(change.0).to_le_bytes())
+    }
+    Ok(hasher.finish())
+}
+
+/// Store `stats` as the statistics of file `inode`.
+pub fn save<T: MutTxnT>(
+    txn: &mut T,
+    inode: Inode,
+    stats: &FileStats,
+) -> Result<(), TxnErr<T::GraphError>> {
+    let bytes = bincode::serialize(stats).unwrap();
+    txn.put_file_stats(inode, &bytes)
+}
@@ -0,0 +1,397 @@
+//! Build repositories with prescribed histories, for the integration
+//! tests of tools using this crate.
+//!
+//! A [`Fixture`] is an anonymous pristine, with changes and the
+//! working copy stored in memory. Changes are recorded with
+//! [`Fixture::record`], by editing the working copy after switching
+//! it to a channel, and can be pulled between channels. Common
+//! situations, such as each kind of [`Conflict`], have their own
+//! method.
+//!
+//! ```ignore
+//! let mut f = Fixture::new()?;
+//! f.record("main", "init", |e| e.write("a", b"a\nb\n"))?;
+//! f.order_conflict("main", "b")?;
+//! let conflicts = f.switch("main")?;
+//! ```
+use crate::apply::{ApplyError, LocalApplyError};
+use crate::change::{Change, ChangeHeader};
+use crate::changestore::{self, ChangeStore};
+use crate::output::{Conflict, OutputError};
+use crate::pristine::sanakirja::{MutTxn, Pristine, SanakirjaError};
+use crate::pristine::*;
+use crate::record::{Algorithm, Builder, RecordError};
+use crate::working_copy::{self, WorkingCopy};
+use crate::{FsError, MutTxnTExt, TxnTExt};
+use std::io::Write;
+
+type WorkingCopyError = working_copy::memory::Error;
+type ChangestoreError = changestore::memory::Error;
+
+#[derive(Debug, Error)]
+pub enum FixtureError {
+    #[error(transparent)]
+    Txn(#[from] SanakirjaError),
+    #[error(transparent)]
+    Changestore(ChangestoreError),
+    #[error(transparent)]
+    WorkingCopy(#[from] WorkingCopyError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Fs(#[from] FsError<SanakirjaError>),
+    #[error(transparent)]
+    Fork(#[from] ForkError<SanakirjaError>),
+    #[error(transparent)]
+    Record(#[from] RecordError<ChangestoreError, WorkingCopyError, SanakirjaError>),
+    #[error(transparent)]
+    Apply(#[from] ApplyError<ChangestoreError, SanakirjaError>),
+    #[error(transparent)]
+    LocalApply(#[from] LocalApplyError<SanakirjaError>),
+    #[error(transparent)]
+    Output(#[from] OutputError<ChangestoreError, SanakirjaError, WorkingCopyError>),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+}
+
+impl std::convert::From<TxnErr<SanakirjaError>> for FixtureError {
+    fn from(e: TxnErr<SanakirjaError>) -> Self {
+        FixtureError::Txn(e.0)
+    }
+}
+
+/// A repository under construction.
+pub struct Fixture {
+    pub pristine: Pristine,
+    pub txn: ArcTxn<MutTxn<()>>,
+    pub changes: changestore::memory::Memory,
+    /// The working copy, replaced by a new one each time it is
+    /// switched to another channel.
+    pub working_copy: working_copy::memory::Memory,
+    current: Option<String>,
+    forks: usize,
+}
+
+/// Edits of the working copy of a channel, made before recording.
+pub struct Edit<'a> {
+    txn: &'a ArcTxn<MutTxn<()>>,
+    repo: &'a working_copy::memory::Memory,
+}
+
+impl<'a> Edit<'a> {
+    /// Set the contents of file `path`, adding it if it is not yet
+    /// tracked.
+    pub fn write(&mut self, path: &str, contents: &[u8]) -> Result<(), FixtureError> {
+        if !self.txn.read().is_tracked(path)? {
+            self.txn.write().add_file(path, 0)?;
+        }
+        self.repo.write_file(path)?.write_all(contents)?;
+        Ok(())
+    }
+
+    /// Add directory `path`.
+    pub fn add_dir(&mut self, path: &str) -> Result<(), FixtureError> {
+        self.repo.add_dir(path);
+        self.txn.write().add_dir(path, 0)?;
+        Ok(())
+    }
+
+    /// Rename `from` to `to`.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FixtureError> {
+        self.repo.rename(from, to)?;
+        self.txn.write().move_file(from, to, 0)?;
+        Ok(())
+    }
+
+    /// Delete file or directory `path`.
+    pub fn remove(&mut self, path: &str) -> Result<(), FixtureError> {
+        self.repo.remove_path(path, true)?;
+        Ok(())
+    }
+}
+
+/// `len` bytes generated from `seed`, which are unlikely to be
+/// detected as text.
+pub fn binary_contents(len: usize, seed: u64) -> Vec<u8> {
+    // xorshift64*, so that fixtures are the same on all platforms.
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+        })
+        .collect()
+}
+
+impl Fixture {
+    pub fn new() -> Result<Self, FixtureError> {
+        let pristine = Pristine::new_anon()?;
+        let txn = pristine.arc_txn_begin()?;
+        Ok(Fixture {
+            pristine,
+            txn,
+            changes: changestore::memory::Memory::new(),
+            working_copy: working_copy::memory::Memory::new(),
+            current: None,
+            forks: 0,
+        })
+    }
+
+    /// Open channel `name`, creating it if it doesn't exist.
+    pub fn channel(&self, name: &str) -> Result<ChannelRef<MutTxn<()>>, FixtureError> {
+        Ok(self.txn.write().open_or_create_channel(name)?)
+    }
+
+    /// Output channel `channel` to a new working copy, returning the
+    /// conflicts.
+    pub fn switch(&mut self, channel: &str) -> Result<Vec<Conflict>, FixtureError> {
+        let channel_ref = self.channel(channel)?;
+        self.working_copy = working_copy::memory::Memory::new();
+        let conflicts = crate::output::output_repository_no_pending(
+            &self.working_copy,
+            &self.changes,
+            &self.txn,
+            &channel_ref,
+            "",
+            true,
+            None,
+            1,
+            0,
+        )?;
+        self.current = Some(channel.to_string());
+        Ok(conflicts)
+    }
+
+    /// Record the edits made by `f` on channel `channel`, which is
+    /// created if needed.
+    pub fn record<F: FnOnce(&mut Edit) -> Result<(), FixtureError>>(
+        &mut self,
+        channel: &str,
+        message: &str,
+        f: F,
+    ) -> Result<Hash, FixtureError> {
+        if self.current.as_deref() != Some(channel) {
+            self.switch(channel)?;
+        }
+        let channel_ref = self.channel(channel)?;
+        let repo = &self.working_copy;
+        f(&mut Edit {
+            txn: &self.txn,
+            repo,
+        })?;
+
+        let mut state = Builder::new();
+        state.record(
+            self.txn.clone(),
+            Algorithm::default(),
+            channel_ref.clone(),
+            repo,
+            &self.changes,
+            "",
+            1,
+        )?;
+        let rec = state.finish().map_err(RecordError::from)?;
+        let actions = rec
+            .actions
+            .into_iter()
+            .map(|rec| rec.globalize(&*self.txn.read()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let change = Change::make_change(
+            &*self.txn.read(),
+            &channel_ref,
+            actions,
+            std::mem::take(&mut *rec.contents.lock()),
+            ChangeHeader {
+                message: message.to_string(),
+                authors: Vec::new(),
+                description: None,
+                timestamp: chrono::Utc::now(),
+            },
+            Vec::new(),
+        )?;
+        let hash = self
+            .changes
+            .save_change(&change)
+            .map_err(FixtureError::Changestore)?;
+        crate::apply::apply_local_change(
+            &mut *self.txn.write(),
+            &channel_ref,
+            &change,
+            &hash,
+            &rec.updatables,
+        )?;
+        Ok(hash)
+    }
+
+    /// Fork channel `from` into a new channel `to`.
+    pub fn fork(&mut self, from: &str, to: &str) -> Result<(), FixtureError> {
+        let from = self
+            .txn
+            .read()
+            .load_channel(from)?
+            .ok_or_else(|| FixtureError::ChannelNotFound(from.to_string()))?;
+        self.txn.write().fork(&from, to)?;
+        Ok(())
+    }
+
+    /// Apply all the changes of `from` that are not on `to` to `to`,
+    /// in the order of `from`. Returns the applied changes.
+    pub fn pull(&mut self, from: &str, to: &str) -> Result<Vec<Hash>, FixtureError> {
+        let from = self
+            .txn
+            .read()
+            .load_channel(from)?
+            .ok_or_else(|| FixtureError::ChannelNotFound(from.to_string()))?;
+        let to_ref = self.channel(to)?;
+        let mut pulled = Vec::new();
+        {
+            let txn = self.txn.read();
+            for x in txn.log(&*from.read(), 0)? {
+                let (_, (h, _)) = x?;
+                let h: Hash = h.into();
+                if txn.has_change(&to_ref, &h)?.is_none() {
+                    pulled.push(h)
+                }
+            }
+        }
+        for h in pulled.iter() {
+            crate::apply::apply_change_arc(&self.changes, &self.txn, &to_ref, h)?;
+        }
+        if self.current.as_deref() == Some(to) {
+            // The working copy is outdated.
+            self.current = None
+        }
+        Ok(pulled)
+    }
+
+    /// Fork `channel` into `n` new channels, named after `channel`
+    /// followed by `-` and a number starting at 1.
+    pub fn channels(&mut self, channel: &str, n: usize) -> Result<Vec<String>, FixtureError> {
+        self.channel(channel)?;
+        let mut names = Vec::with_capacity(n);
+        for i in 1..=n {
+            let name = format!("{}-{}", channel, i);
+            self.fork(channel, &name)?;
+            names.push(name)
+        }
+        Ok(names)
+    }
+
+    /// Record `a` and `b` on two new forks of `channel`, and pull
+    /// both into `channel`. The forks are named after `channel`
+    /// followed by `~` and a number, and are left in the pristine.
+    pub fn concurrent<A, B>(
+        &mut self,
+        channel: &str,
+        a: A,
+        b: B,
+    ) -> Result<(Hash, Hash), FixtureError>
+    where
+        A: FnOnce(&mut Edit) -> Result<(), FixtureError>,
+        B: FnOnce(&mut Edit) -> Result<(), FixtureError>,
+    {
+        self.channel(channel)?;
+        let mut next_fork = || {
+            self.forks += 1;
+            format!("{}~{}", channel, self.forks)
+        };
+        let (fork_a, fork_b) = (next_fork(), next_fork());
+        self.fork(channel, &fork_a)?;
+        self.fork(channel, &fork_b)?;
+        let ha = self.record(&fork_a, "a", a)?;
+        let hb = self.record(&fork_b, "b", b)?;
+        self.pull(&fork_a, channel)?;
+        self.pull(&fork_b, channel)?;
+        Ok((ha, hb))
+    }
+
+    /// Create an order conflict in file `path` of `channel`, by
+    /// inserting two lines concurrently at the same place.
+    pub fn order_conflict(&mut self, channel: &str, path: &str) -> Result<(), FixtureError> {
+        self.record(channel, "order conflict", |e| e.write(path, b"a\nb\n"))?;
+        self.concurrent(
+            channel,
+            |e| e.write(path, b"a\nx\nb\n"),
+            |e| e.write(path, b"a\ny\nb\n"),
+        )?;
+        Ok(())
+    }
+
+    /// Create a zombie conflict in file `path` of `channel`, by
+    /// deleting lines while another change inserts a line between
+    /// them.
+    pub fn zombie_conflict(&mut self, channel: &str, path: &str) -> Result<(), FixtureError> {
+        self.record(channel, "zombie conflict", |e| {
+            e.write(path, b"a\nb\nc\nd\n")
+        })?;
+        self.concurrent(
+            channel,
+            |e| e.write(path, b"a\nd\n"),
+            |e| e.write(path, b"a\nb\nx\nc\nd\n"),
+        )?;
+        Ok(())
+    }
+
+    /// Create a cyclic conflict in file `path` of `channel`, by
+    /// solving an order conflict in two different ways concurrently.
+    pub fn cyclic_conflict(&mut self, channel: &str, path: &str) -> Result<(), FixtureError> {
+        self.order_conflict(channel, path)?;
+        self.concurrent(
+            channel,
+            |e| e.write(path, b"a\nx\ny\nb\n"),
+            |e| e.write(path, b"a\ny\nx\nb\n"),
+        )?;
+        Ok(())
+    }
+
+    /// Create a name conflict on `channel`, by adding two different
+    /// files at `path` concurrently.
+    pub fn name_conflict(&mut self, channel: &str, path: &str) -> Result<(), FixtureError> {
+        self.concurrent(
+            channel,
+            |e| e.write(path, b"a\n"),
+            |e| e.write(path, b"b\n"),
+        )?;
+        Ok(())
+    }
+
+    /// Create a file with multiple names on `channel`, by renaming
+    /// `path` to `a` and to `b` concurrently.
+    pub fn multiple_names_conflict(
+        &mut self,
+        channel: &str,
+        path: &str,
+        a: &str,
+        b: &str,
+    ) -> Result<(), FixtureError> {
+        self.record(channel, "multiple names conflict", |e| {
+            e.write(path, b"a\n")
+        })?;
+        self.concurrent(channel, |e| e.rename(path, a), |e| e.rename(path, b))?;
+        Ok(())
+    }
+
+    /// Create a zombie file at `path` on `channel`, by deleting the
+    /// file while another change edits it.
+    pub fn zombie_file_conflict(&mut self, channel: &str, path: &str) -> Result<(), FixtureError> {
+        self.record(channel, "zombie file conflict", |e| {
+            e.write(path, b"a\nb\n")
+        })?;
+        self.concurrent(channel, |e| e.remove(path), |e| e.write(path, b"a\nx\nb\n"))?;
+        Ok(())
+    }
+
+    /// Record a binary file of `len` bytes at `path` on `channel`.
+    pub fn binary_file(
+        &mut self,
+        channel: &str,
+        path: &str,
+        len: usize,
+        seed: u64,
+    ) -> Result<Hash, FixtureError> {
+        let contents = binary_contents(len, seed);
+        self.record(channel, "binary file", |e| e.write(path, &contents))
+    }
+}
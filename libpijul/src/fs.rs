@@ -66,15 +66,169 @@ pub(crate) fn create_new_inode<T: TreeMutTxnT>(
     use std::hash::{BuildHasher, Hash, Hasher};
     let mut s = crate::Hasher::default().build_hasher();
     (parent_id, salt).hash(&mut s);
-    let mut i: u64 = s.finish();
+    free_inode(txn, s.finish())
+}
+
+/// The first unused inode starting from `i`.
+fn free_inode<T: TreeTxnT>(txn: &T, mut i: u64) -> Result<Inode, TxnErr<T::TreeError>> {
     let mut inode = Inode(L64(i.to_le()));
-    while txn.get_revtree(&inode, None)?.is_some() {
-        i += 1;
+    while inode == Inode::ROOT
+        || txn.get_revtree(&inode, None)?.is_some()
+        || txn.get_inodes(&inode, None)?.is_some()
+    {
+        i = i.wrapping_add(1);
         inode = Inode(L64(i.to_le()));
     }
     Ok(inode)
 }
 
+/// The inode of a file in [`InodeAllocation::Deterministic`] mode,
+/// derived from the change that introduced the inode vertex of the
+/// file, and from the position of that vertex in the change.
+pub fn deterministic_inode(change: &Hash, pos: ChangePosition) -> Inode {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(change.to_base32().as_bytes());
+    hasher.update(&pos.0.as_u64().to_le_bytes());
+    let mut i = [0; 8];
+    i.copy_from_slice(&hasher.finalize().as_bytes()[..8]);
+    Inode(L64(u64::from_le_bytes(i).to_le()))
+}
+
+/// The inode that `vertex` should have in the deterministic mode, or
+/// `None` in the salted mode.
+fn deterministic_inode_for<T: GraphTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+    txn: &T,
+    vertex: Position<ChangeId>,
+) -> Result<Option<Inode>, TxnErr<T::GraphError>> {
+    if txn.inode_allocation() == InodeAllocation::Deterministic {
+        if let Some(hash) = txn.get_external(&vertex.change)? {
+            let Inode(L64(i)) = deterministic_inode(&hash.into(), vertex.pos);
+            return Ok(Some(free_inode(txn, u64::from_le(i))?));
+        }
+    }
+    Ok(None)
+}
+
+/// Allocate an inode for a file at `parent_id`, whose inode vertex is
+/// `vertex`, according to the inode allocation mode of `txn`.
+pub(crate) fn create_inode_for<
+    T: GraphTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+>(
+    txn: &mut T,
+    parent_id: &PathId,
+    salt: u64,
+    vertex: Position<ChangeId>,
+) -> Result<Inode, TxnErr<T::TreeError>> {
+    if let Some(inode) = deterministic_inode_for(txn, vertex)? {
+        Ok(inode)
+    } else {
+        create_new_inode(txn, parent_id, salt)
+    }
+}
+
+/// Replace inode `old` with `new` in the tree, including in the keys
+/// of the children of `old` if it is a directory.
+fn renumber_inode<T: TreeMutTxnT>(
+    txn: &mut T,
+    old: Inode,
+    new: Inode,
+) -> Result<(), TxnErr<T::TreeError>> {
+    if let Some(parent) = txn.get_revtree(&old, None)?.map(|x| x.to_owned()) {
+        del_tree_with_rev(txn, &parent, &old)?;
+        put_tree_with_rev(txn, &parent, &new)?;
+    }
+    let mut children = Vec::new();
+    for x in txn.iter_tree(&OwnedPathId::inode(old), None)? {
+        let (pid, &child) = x?;
+        if pid.parent_inode < old {
+            continue;
+        } else if pid.parent_inode > old {
+            break;
+        }
+        children.push((pid.to_owned(), child))
+    }
+    for (pid, child) in children {
+        let new_pid = OwnedPathId {
+            parent_inode: new,
+            basename: pid.basename.clone(),
+        };
+        if pid.basename.is_empty() {
+            // The marker of directory `old`.
+            txn.del_tree(&pid, Some(&old))?;
+            txn.put_tree(&new_pid, &new)?;
+        } else {
+            del_tree_with_rev(txn, &pid, &child)?;
+            put_tree_with_rev(txn, &new_pid, &child)?;
+        }
+    }
+    if let Some(&vertex) = txn.get_inodes(&old, None)? {
+        del_inodes_with_rev(txn, &old, &vertex)?;
+        put_inodes_with_rev(txn, &new, &vertex)?;
+    }
+    Ok(())
+}
+
+/// In the deterministic mode, give the inode of a file recorded
+/// locally its deterministic value, once the change introducing it
+/// has been applied.
+pub(crate) fn settle_inode<T: GraphTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+    txn: &mut T,
+    inode: Inode,
+    vertex: Position<ChangeId>,
+) -> Result<(), TxnErr<T::TreeError>> {
+    if txn.inode_allocation() != InodeAllocation::Deterministic {
+        return Ok(());
+    }
+    if let Some(hash) = txn.get_external(&vertex.change)? {
+        let target = deterministic_inode(&hash.into(), vertex.pos);
+        if inode != target {
+            let Inode(L64(i)) = target;
+            let new = free_inode(txn, u64::from_le(i))?;
+            renumber_inode(txn, inode, new)?
+        }
+    }
+    Ok(())
+}
+
+/// Switch `txn` to the deterministic inode allocation mode, and give
+/// all recorded files their deterministic inode. This is the
+/// migration path for existing repositories. Returns the number of
+/// inodes changed.
+///
+/// Files added but not yet recorded keep their salted inode until
+/// they are recorded.
+pub fn make_inodes_deterministic<
+    T: GraphTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+>(
+    txn: &mut T,
+) -> Result<usize, TxnErr<T::TreeError>> {
+    txn.set_inode_allocation(InodeAllocation::Deterministic);
+    let mut inodes = Vec::new();
+    for x in txn.iter_inodes()? {
+        let (&inode, &vertex) = x?;
+        if inode != Inode::ROOT {
+            inodes.push((inode, vertex))
+        }
+    }
+    let mut n = 0;
+    for (inode, vertex) in inodes {
+        let hash: Hash = if let Some(hash) = txn.get_external(&vertex.change)? {
+            hash.into()
+        } else {
+            continue;
+        };
+        let target = deterministic_inode(&hash, vertex.pos);
+        if target == inode {
+            continue;
+        }
+        let Inode(L64(i)) = target;
+        let new = free_inode(txn, u64::from_le(i))?;
+        renumber_inode(txn, inode, new)?;
+        n += 1
+    }
+    Ok(n)
+}
+
 /// Test whether `inode` is the inode of a directory (as opposed to a
 /// file).
 pub fn is_directory<T: TreeTxnT>(txn: &T, inode: Inode) -> Result<bool, TxnErr<T::TreeError>> {
@@ -899,8 +1053,6 @@ pub fn find_path<T: ChannelTxnT, C: ChangeStore>(
                     alive = true;
                 } else if alive {
                     break;
-                } else {
-                    all_alive = false
                 }
                 if let Some((_, p_age, _)) = next_v {
                     if (age > p_age) ^ youngest {
@@ -911,6 +1063,7 @@ pub fn find_path<T: ChannelTxnT, C: ChangeStore>(
             }
         }
         let (name, _, next) = next_v.unwrap();
+        all_alive &= alive;
         if alive {
             name_buf.clear();
             debug!("getting contents {:?}", name);
@@ -932,6 +1085,99 @@ pub fn find_path<T: ChannelTxnT, C: ChangeStore>(
     Ok(Some((path.join("/"), all_alive)))
 }
 
+/// The inode vertex of the file `inode` was copied from, if `inode`
+/// was added by a [`FileCopy`](crate::change::Hunk::FileCopy) hunk
+/// recording its source. The source may have been renamed or deleted
+/// since, use [`find_path`] to get its current name.
+pub fn copy_source<T: GraphTxnT, C: ChangeStore>(
+    changes: &C,
+    txn: &T,
+    inode: Position<ChangeId>,
+) -> Result<Option<Position<ChangeId>>, FsErrorC<C::Error, T::GraphError>> {
+    use crate::change::{Atom, Hunk};
+    let hash: Hash = if let Some(h) = txn.get_external(&inode.change)? {
+        h.into()
+    } else {
+        return Ok(None);
+    };
+    let hunks = changes.get_changes(&hash).map_err(FsErrorC::Changestore)?;
+    for hunk in hunks.iter() {
+        if let Hunk::FileCopy {
+            add_inode: Atom::NewVertex(ref add),
+            source_inode,
+            ..
+        } = hunk
+        {
+            if add.start != inode.pos {
+                continue;
+            }
+            if let Some(Position {
+                change: Some(source),
+                pos,
+            }) = *source_inode
+            {
+                if let Some(&change) = txn.get_internal(&source.into())? {
+                    return Ok(Some(Position { change, pos }));
+                }
+            }
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// The changes of `channel` touching file `inode`, in the order of
+/// the log. If `inode` was copied from another file, the changes
+/// touching that file before the copy are included too, following
+/// chains of copies.
+pub fn file_log<
+    T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
+    C: ChangeStore,
+>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    inode: Position<ChangeId>,
+) -> Result<Vec<Hash>, FsErrorC<C::Error, T::GraphError>> {
+    let mut log = Vec::new();
+    let mut current = inode;
+    let mut until = u64::MAX;
+    let mut visited = HashSet::default();
+    while visited.insert(current) {
+        for x in txn.iter_touched(&current)? {
+            let (p, change) = x?;
+            if *p > current {
+                break;
+            } else if *p < current {
+                continue;
+            }
+            if let Some(&t) = txn.get_changeset(txn.changes(channel), change)? {
+                if u64::from(t) <= until {
+                    log.push((u64::from(t), *change))
+                }
+            }
+        }
+        if let Some(source) = copy_source(changes, txn, current)? {
+            // Only the changes to the source up to the copy.
+            if let Some(&t) = txn.get_changeset(txn.changes(channel), &current.change)? {
+                until = until.min(u64::from(t))
+            }
+            current = source
+        } else {
+            break;
+        }
+    }
+    log.sort();
+    log.dedup();
+    let mut result = Vec::with_capacity(log.len());
+    for (_, change) in log {
+        if let Some(h) = txn.get_external(&change)? {
+            result.push(h.into())
+        }
+    }
+    Ok(result)
+}
+
 pub fn get_latest_touch<'a, T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>>(
     txn: &T,
     channel: &T::Channel,
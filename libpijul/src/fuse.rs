@@ -0,0 +1,364 @@
+//! A read-only FUSE filesystem exposing the state of channels.
+//!
+//! The root of the filesystem has one directory per [`View`], i.e.
+//! per channel given to [`ChannelsFs::new`]. Directories are listed,
+//! and files reconstructed from the pristine, only when they are
+//! accessed, and the contents of the files read last are cached, so
+//! that browsing history doesn't require checking anything out.
+//!
+//! Tags can be exposed by restoring them into a channel first, with
+//! [`restore_channel`](crate::tag::restore_channel).
+use crate::changestore::ChangeStore;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::vertex_buffer::Writer;
+use crate::HashMap;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const TTL: Duration = Duration::from_secs(60);
+const ROOT_INO: u64 = 1;
+
+/// A channel exposed as a directory of the root.
+pub struct View<T: TxnT> {
+    pub name: String,
+    pub channel: ChannelRef<T>,
+}
+
+struct Node {
+    /// Index of the view, `None` for the root.
+    view: Option<usize>,
+    pos: Position<ChangeId>,
+    meta: InodeMetadata,
+    children: Option<Vec<(String, u64)>>,
+    size: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum FuseError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Changestore(C),
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("No such inode: {0}")]
+    NoSuchInode(u64),
+    #[error("No such file: {0}")]
+    NoSuchFile(String),
+    #[error("Not a directory")]
+    NotADirectory,
+    #[error("Is a directory")]
+    IsADirectory,
+    #[error(transparent)]
+    GraphTooLarge(crate::alive::retrieve::GraphTooLarge),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for FuseError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        FuseError::Txn(e.0)
+    }
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<FileError<C, T>>
+    for FuseError<C, T>
+{
+    fn from(e: FileError<C, T>) -> Self {
+        match e {
+            FileError::Changestore(e) => FuseError::Changestore(e),
+            FileError::Txn(e) => FuseError::Txn(e),
+            FileError::Io(e) => FuseError::Io(e),
+            FileError::GraphTooLarge(e) => FuseError::GraphTooLarge(e),
+        }
+    }
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> FuseError<C, T> {
+    fn errno(&self) -> i32 {
+        match self {
+            FuseError::NoSuchInode(_) | FuseError::NoSuchFile(_) => libc::ENOENT,
+            FuseError::NotADirectory => libc::ENOTDIR,
+            FuseError::IsADirectory => libc::EISDIR,
+            _ => libc::EIO,
+        }
+    }
+}
+
+/// A read-only filesystem showing a list of channels.
+pub struct ChannelsFs<T: TxnT, P: ChangeStore> {
+    txn: ArcTxn<T>,
+    changes: P,
+    views: Vec<View<T>>,
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<(usize, Position<ChangeId>), u64>,
+    cache: lru_cache::LruCache<u64, Arc<Vec<u8>>>,
+    mtime: SystemTime,
+}
+
+impl<T: TxnT, P: ChangeStore> ChannelsFs<T, P> {
+    /// Expose `views`, keeping the contents of at most `cache_size`
+    /// files in memory.
+    pub fn new(txn: ArcTxn<T>, changes: P, views: Vec<View<T>>, cache_size: usize) -> Self {
+        let mut nodes = HashMap::default();
+        nodes.insert(
+            ROOT_INO,
+            Node {
+                view: None,
+                pos: Position::ROOT,
+                meta: InodeMetadata::new(0o555, true),
+                children: None,
+                size: None,
+            },
+        );
+        ChannelsFs {
+            txn,
+            changes,
+            views,
+            nodes,
+            inodes: HashMap::default(),
+            cache: lru_cache::LruCache::new(cache_size),
+            mtime: SystemTime::now(),
+        }
+    }
+
+    /// Mount this filesystem at `mountpoint`, blocking until it is
+    /// unmounted.
+    pub fn mount<Q: AsRef<std::path::Path>>(self, mountpoint: Q) -> Result<(), std::io::Error> {
+        fuser::mount2(
+            self,
+            mountpoint,
+            &[MountOption::RO, MountOption::FSName("pijul".to_string())],
+        )
+    }
+
+    fn ino(&mut self, view: usize, pos: Position<ChangeId>, meta: InodeMetadata) -> u64 {
+        let nodes = &mut self.nodes;
+        *self.inodes.entry((view, pos)).or_insert_with(|| {
+            let ino = nodes.len() as u64 + 1;
+            nodes.insert(
+                ino,
+                Node {
+                    view: Some(view),
+                    pos,
+                    meta,
+                    children: None,
+                    size: None,
+                },
+            );
+            ino
+        })
+    }
+
+    /// The entries of directory `ino`, sorted by name.
+    pub fn read_dir(
+        &mut self,
+        ino: u64,
+    ) -> Result<Vec<(String, u64)>, FuseError<P::Error, T::GraphError>> {
+        let node = self.nodes.get(&ino).ok_or(FuseError::NoSuchInode(ino))?;
+        if !node.meta.is_dir() {
+            return Err(FuseError::NotADirectory);
+        }
+        if let Some(ref children) = node.children {
+            return Ok(children.clone());
+        }
+        let mut children = Vec::new();
+        if let Some(view) = node.view {
+            let pos = node.pos;
+            let mut entries = Vec::new();
+            {
+                let txn = self.txn.read();
+                let channel = self.views[view].channel.read();
+                for x in
+                    crate::fs::iter_graph_children(&*txn, &self.changes, txn.graph(&*channel), pos)
+                        .map_err(FuseError::Txn)?
+                {
+                    let (pos, _, meta, name) = x.map_err(FuseError::Txn)?;
+                    entries.push((name, pos, meta))
+                }
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            // Only show the first file of each name conflict.
+            entries.dedup_by(|a, b| a.0 == b.0);
+            for (name, pos, meta) in entries {
+                let ino = self.ino(view, pos, meta);
+                children.push((name, ino))
+            }
+        } else {
+            for view in 0..self.views.len() {
+                let ino = self.ino(view, Position::ROOT, InodeMetadata::new(0o555, true));
+                children.push((self.views[view].name.clone(), ino))
+            }
+            children.sort();
+        }
+        self.nodes.get_mut(&ino).unwrap().children = Some(children.clone());
+        Ok(children)
+    }
+
+    /// The inode of entry `name` of directory `parent`.
+    pub fn lookup_name(
+        &mut self,
+        parent: u64,
+        name: &str,
+    ) -> Result<u64, FuseError<P::Error, T::GraphError>> {
+        self.read_dir(parent)?
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, ino)| ino)
+            .ok_or_else(|| FuseError::NoSuchFile(name.to_string()))
+    }
+
+    /// The contents of file `ino`.
+    pub fn read_file(
+        &mut self,
+        ino: u64,
+    ) -> Result<Arc<Vec<u8>>, FuseError<P::Error, T::GraphError>> {
+        if let Some(contents) = self.cache.get_mut(&ino) {
+            return Ok(contents.clone());
+        }
+        let node = self
+            .nodes
+            .get_mut(&ino)
+            .ok_or(FuseError::NoSuchInode(ino))?;
+        let view = if let (Some(view), false) = (node.view, node.meta.is_dir()) {
+            view
+        } else {
+            return Err(FuseError::IsADirectory);
+        };
+        let mut w = Writer::new(Vec::new());
+        {
+            let txn = self.txn.read();
+            let channel = self.views[view].channel.read();
+            crate::output::output_file(
+                &self.changes,
+                &*txn,
+                &*channel,
+                node.pos,
+                &mut w,
+                crate::alive::retrieve::RetrieveLimits::UNLIMITED,
+            )?;
+        }
+        let contents = Arc::new(w.into_inner());
+        node.size = Some(contents.len() as u64);
+        self.cache.insert(ino, contents.clone());
+        Ok(contents)
+    }
+
+    fn attr(&mut self, ino: u64) -> Result<FileAttr, FuseError<P::Error, T::GraphError>> {
+        let node = self.nodes.get(&ino).ok_or(FuseError::NoSuchInode(ino))?;
+        let (kind, size, perm) = if node.meta.is_dir() {
+            (FileType::Directory, 0, 0o555)
+        } else {
+            let perm = if node.meta.permissions() & 0o100 != 0 {
+                0o555
+            } else {
+                0o444
+            };
+            let size = if let Some(size) = node.size {
+                size
+            } else {
+                self.read_file(ino)?.len() as u64
+            };
+            (FileType::RegularFile, size, perm)
+        };
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind,
+            perm,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 512,
+            padding: 0,
+            flags: 0,
+        })
+    }
+}
+
+impl<T: TxnT, P: ChangeStore> Filesystem for ChannelsFs<T, P> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = if let Some(name) = name.to_str() {
+            name
+        } else {
+            return reply.error(libc::ENOENT);
+        };
+        match self
+            .lookup_name(parent, name)
+            .and_then(|ino| self.attr(ino))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_file(ino) {
+            Ok(contents) => {
+                let start = (offset.max(0) as usize).min(contents.len());
+                let end = (start + size as usize).min(contents.len());
+                reply.data(&contents[start..end])
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let children = match self.read_dir(ino) {
+            Ok(children) => children,
+            Err(e) => return reply.error(e.errno()),
+        };
+        let entries = [(".".to_string(), ino), ("..".to_string(), ino)];
+        for (i, (name, child)) in entries
+            .iter()
+            .chain(children.iter())
+            .enumerate()
+            .skip(offset.max(0) as usize)
+        {
+            let kind = match self.nodes.get(child) {
+                Some(node) if !node.meta.is_dir() => FileType::RegularFile,
+                _ => FileType::Directory,
+            };
+            if reply.add(*child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
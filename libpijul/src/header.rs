@@ -0,0 +1,169 @@
+//! Validation of change headers.
+//!
+//! A [`HeaderValidator`] checks the header of a change before it is
+//! saved, when the records of a [`Recorded`](crate::record::Recorded)
+//! are made into a [`Change`](crate::change::Change), and can be run
+//! again by servers on the changes pushed to them, before applying
+//! them. [`HeaderPolicy`] is the validator configured by
+//! repositories, for instance to enforce [conventional
+//! commits](https://www.conventionalcommits.org) or to require some
+//! keys in the authors of changes.
+use crate::change::ChangeHeader;
+
+/// A reason why a header was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Violation {
+    #[error("Empty change message")]
+    EmptyMessage,
+    #[error("Message is not of the form \"type(scope): description\": {message:?}")]
+    NotConventional { message: String },
+    #[error("Unknown change type {kind:?}, expected one of {allowed:?}")]
+    UnknownType { kind: String, allowed: Vec<String> },
+    #[error("Message too long: {len} characters, at most {max} allowed")]
+    MessageTooLong { len: usize, max: usize },
+    #[error("Missing description")]
+    MissingDescription,
+    #[error("Missing authors")]
+    MissingAuthors,
+    #[error("Author {author} has no {key:?} key")]
+    MissingAuthorKey { author: usize, key: String },
+}
+
+/// The violations found in a header.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub struct InvalidHeader {
+    pub violations: Vec<Violation>,
+}
+
+impl std::fmt::Display for InvalidHeader {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Invalid change header")?;
+        for (i, v) in self.violations.iter().enumerate() {
+            write!(fmt, "{} {}", if i == 0 { ":" } else { ";" }, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// A hook checking the headers of changes.
+pub trait HeaderValidator {
+    /// Push the violations found in `header` to `violations`.
+    fn validate(&self, header: &ChangeHeader, violations: &mut Vec<Violation>);
+}
+
+/// Check `header` with `validator`.
+pub fn check<V: HeaderValidator + ?Sized>(
+    validator: &V,
+    header: &ChangeHeader,
+) -> Result<(), InvalidHeader> {
+    let mut violations = Vec::new();
+    validator.validate(header, &mut violations);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidHeader { violations })
+    }
+}
+
+/// The rules enforced on the headers of a repository. The default
+/// policy accepts all headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeaderPolicy {
+    /// Require messages of the form `type(scope)!: description`,
+    /// where the scope and the `!` are optional.
+    pub conventional: bool,
+    /// If not empty, and `conventional` is set, the allowed types.
+    pub types: Vec<String>,
+    /// Maximal length of the message, in characters.
+    pub max_message_length: Option<usize>,
+    pub require_description: bool,
+    pub require_authors: bool,
+    /// Keys every author must have, such as `"key"` for signed
+    /// identities.
+    pub required_author_keys: Vec<String>,
+}
+
+impl HeaderPolicy {
+    /// Whether this policy can reject headers at all.
+    pub fn is_enabled(&self) -> bool {
+        *self != HeaderPolicy::default()
+    }
+}
+
+impl HeaderValidator for HeaderPolicy {
+    fn validate(&self, header: &ChangeHeader, violations: &mut Vec<Violation>) {
+        let message = header.message.trim();
+        if message.is_empty() {
+            if self.is_enabled() {
+                violations.push(Violation::EmptyMessage)
+            }
+        } else if self.conventional {
+            match conventional_type(message) {
+                None => violations.push(Violation::NotConventional {
+                    message: message.to_string(),
+                }),
+                Some(kind) if !self.types.is_empty() && !self.types.iter().any(|t| t == kind) => {
+                    violations.push(Violation::UnknownType {
+                        kind: kind.to_string(),
+                        allowed: self.types.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(max) = self.max_message_length {
+            let len = message.chars().count();
+            if len > max {
+                violations.push(Violation::MessageTooLong { len, max })
+            }
+        }
+        if self.require_description
+            && header
+                .description
+                .as_ref()
+                .map(|d| d.trim().is_empty())
+                .unwrap_or(true)
+        {
+            violations.push(Violation::MissingDescription)
+        }
+        if self.require_authors && header.authors.is_empty() {
+            violations.push(Violation::MissingAuthors)
+        }
+        for (author, a) in header.authors.iter().enumerate() {
+            for key in self.required_author_keys.iter() {
+                if a.0.get(key).map(|v| v.is_empty()).unwrap_or(true) {
+                    violations.push(Violation::MissingAuthorKey {
+                        author,
+                        key: key.clone(),
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// The type of a conventional commit message, i.e. `"fix"` in
+/// `"fix(parser)!: description"`, or `None` if `message` isn't
+/// conventional.
+pub fn conventional_type(message: &str) -> Option<&str> {
+    let colon = message.find(": ")?;
+    let (prefix, description) = (&message[..colon], &message[colon + 2..]);
+    if description.trim().is_empty() {
+        return None;
+    }
+    let prefix = prefix.strip_suffix('!').unwrap_or(prefix);
+    let kind = if let Some(paren) = prefix.find('(') {
+        let scope = prefix[paren + 1..].strip_suffix(')')?;
+        if scope.is_empty() || scope.contains(|c| c == '(' || c == ')') {
+            return None;
+        }
+        &prefix[..paren]
+    } else {
+        prefix
+    };
+    if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+    Some(kind)
+}
@@ -0,0 +1,148 @@
+//! Custom hunk kinds, registered by embedders.
+//!
+//! Embedders can extend changes with hunks of their own kinds, such
+//! as operations on a database schema, without patching this crate.
+//! A kind is registered once per process with [`register`], and is
+//! identified in changes by its tag. Hunks of custom kinds
+//! ([`Hunk::Custom`]) carry an opaque payload, and don't touch the
+//! graph: instead, [`HunkKind::apply`] is called when a change
+//! containing them is applied to a channel, and called on the
+//! inverted payload when the change is unrecorded.
+//!
+//! The pristine remembers the kinds of the changes registered in it,
+//! and [`validate`] checks, when opening a repository, that all of
+//! them are registered in the current process.
+use crate::apply::LocalApplyError;
+use crate::change::{Change, Hunk};
+use crate::pristine::*;
+use crate::HashMap;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// The handlers of a custom hunk kind.
+pub trait HunkKind: Send + Sync {
+    /// The tag of this kind, stored in the hunks of this kind.
+    fn tag(&self) -> &str;
+
+    /// Called when a change containing a hunk of this kind, on file
+    /// `path`, is applied to `channel`. This runs inside the
+    /// transaction applying the change, but the effects of this
+    /// function are not rolled back if the transaction is.
+    fn apply(&self, channel: &str, path: &str, payload: &[u8]) -> Result<(), HunkKindError>;
+
+    /// The payload of the inverse hunk, undoing `payload`.
+    fn invert(&self, payload: &[u8]) -> Result<Vec<u8>, HunkKindError>;
+
+    /// A human-readable description of `payload`, shown in the text
+    /// format of changes.
+    fn render(&self, payload: &[u8]) -> String;
+}
+
+/// An error returned by the handlers of a [`HunkKind`].
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct HunkKindError(pub String);
+
+#[derive(Debug, Error)]
+pub enum RegisterError {
+    #[error("Hunk kind {0:?} is already registered")]
+    AlreadyRegistered(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ValidateError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Hunk kinds used in this repository are not registered: {0:?}")]
+    Unregistered(Vec<String>),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for ValidateError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        ValidateError::Txn(e.0)
+    }
+}
+
+lazy_static! {
+    static ref KINDS: RwLock<HashMap<String, Arc<dyn HunkKind>>> = RwLock::new(HashMap::default());
+}
+
+/// Register `kind`, failing if its tag is already taken.
+pub fn register(kind: Arc<dyn HunkKind>) -> Result<(), RegisterError> {
+    let mut kinds = KINDS.write();
+    let tag = kind.tag().to_string();
+    if kinds.contains_key(&tag) {
+        return Err(RegisterError::AlreadyRegistered(tag));
+    }
+    kinds.insert(tag, kind);
+    Ok(())
+}
+
+/// The kind registered with tag `tag`, if any.
+pub fn get(tag: &str) -> Option<Arc<dyn HunkKind>> {
+    KINDS.read().get(tag).cloned()
+}
+
+/// Check that the kinds of all the changes registered in the pristine
+/// are registered.
+pub fn validate<T: DepsTxnT>(txn: &T) -> Result<(), ValidateError<T::DepsError>> {
+    let kinds = KINDS.read();
+    let missing: Vec<_> = txn
+        .hunk_kinds()?
+        .into_iter()
+        .filter(|k| !kinds.contains_key(k))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidateError::Unregistered(missing))
+    }
+}
+
+/// Invert a payload of kind `tag`. Kinds must be registered before
+/// their changes are inverted, see [`validate`].
+pub(crate) fn invert(tag: &str, payload: &[u8]) -> Result<Vec<u8>, HunkKindError> {
+    if let Some(kind) = get(tag) {
+        kind.invert(payload)
+    } else {
+        Err(HunkKindError(format!(
+            "Hunk kind {:?} is not registered",
+            tag
+        )))
+    }
+}
+
+/// Call the handlers of the custom hunks of `change`, applied to
+/// `channel`, or unrecorded from it if `inverse` is true.
+pub(crate) fn apply_hunks<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    change: &Change,
+    inverse: bool,
+) -> Result<(), LocalApplyError<T::GraphError>> {
+    for hunk in change.changes.iter() {
+        if let Hunk::Custom {
+            kind,
+            payload,
+            local,
+        } = hunk
+        {
+            let k = if let Some(k) = get(kind) {
+                k
+            } else {
+                return Err(LocalApplyError::UnknownHunkKind { kind: kind.clone() });
+            };
+            let result = if inverse {
+                k.invert(payload)
+                    .and_then(|payload| k.apply(txn.name(channel), &local.path, &payload))
+            } else {
+                k.apply(txn.name(channel), &local.path, payload)
+            };
+            result.map_err(|error| LocalApplyError::HunkKind {
+                kind: kind.clone(),
+                error,
+            })?
+        }
+    }
+    Ok(())
+}
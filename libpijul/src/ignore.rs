@@ -0,0 +1,141 @@
+//! Ignore files, read by record.
+//!
+//! Each directory of the working copy may have ignore files, named
+//! after [`IGNORE_FILES`], with one pattern per line in the syntax of
+//! `.gitignore`: blank lines and lines starting with `#` are skipped,
+//! `!` negates a pattern, a trailing `/` only matches directories, and
+//! patterns containing a `/` other than a trailing one are relative
+//! to the directory of the ignore file, whereas other patterns match
+//! names at any depth. The last matching pattern wins, and the
+//! patterns of deeper directories take precedence.
+//!
+//! With [`crate::record::Builder::ignore_files`] set, record doesn't
+//! add the ignored paths that aren't in the channel yet, even if they
+//! were added to the tree. Edits to tracked ignored files and their
+//! deletions are recorded as usual. The `.gitignore` files are left
+//! to the command line, which reads them when adding files.
+use crate::record::Glob;
+use crate::working_copy::WorkingCopy;
+use crate::HashMap;
+
+/// Names of the ignore files.
+pub const IGNORE_FILES: &[&str] = &[".ignore", ".pijulignore"];
+
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: Glob,
+    dir_only: bool,
+    negated: bool,
+}
+
+/// The rules of the ignore files of a directory.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules(Vec<Rule>);
+
+impl IgnoreRules {
+    /// Parse the contents of an ignore file, appending its rules to
+    /// these rules.
+    pub fn parse(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = if let Some(line) = line.strip_prefix('!') {
+                (true, line)
+            } else {
+                (false, line.strip_prefix('\\').unwrap_or(line))
+            };
+            let (dir_only, line) = if let Some(line) = line.strip_suffix('/') {
+                (true, line)
+            } else {
+                (false, line)
+            };
+            let glob = if line.contains('/') {
+                Glob::new(line)
+            } else {
+                Glob::new(&format!("**/{}", line))
+            };
+            self.0.push(Rule {
+                glob,
+                dir_only,
+                negated,
+            })
+        }
+    }
+
+    /// Whether `path`, relative to the directory of these rules, is
+    /// ignored (`Some(true)`), explicitly not ignored (`Some(false)`),
+    /// or not matched by any rule (`None`).
+    pub fn matched(&self, path: &[&str], is_dir: bool) -> Option<bool> {
+        self.0
+            .iter()
+            .rev()
+            .find(|r| (is_dir || !r.dir_only) && r.glob.matches(path, false))
+            .map(|r| !r.negated)
+    }
+}
+
+/// The ignore files of a working copy, read as directories are
+/// visited.
+#[derive(Debug, Default)]
+pub struct Ignores {
+    dirs: HashMap<String, IgnoreRules>,
+}
+
+impl Ignores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `path` or one of its ancestors is ignored by the ignore
+    /// files of `working_copy`.
+    pub fn is_ignored<W: WorkingCopy>(
+        &mut self,
+        working_copy: &W,
+        path: &str,
+        is_dir: bool,
+    ) -> bool {
+        let path: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        for n in 1..=path.len() {
+            let is_dir = n < path.len() || is_dir;
+            let mut ignored = false;
+            for d in 0..n {
+                if let Some(m) = self
+                    .rules(working_copy, &path[..d])
+                    .matched(&path[d..n], is_dir)
+                {
+                    ignored = m
+                }
+            }
+            if ignored {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The rules of directory `dir`, read from `working_copy` if they
+    /// haven't been read yet.
+    fn rules<W: WorkingCopy>(&mut self, working_copy: &W, dir: &[&str]) -> &IgnoreRules {
+        let dir = dir.join("/");
+        if !self.dirs.contains_key(&dir) {
+            let mut rules = IgnoreRules::default();
+            let mut buf = Vec::new();
+            for name in IGNORE_FILES {
+                let file = if dir.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", dir, name)
+                };
+                buf.clear();
+                if working_copy.read_file(&file, &mut buf).is_ok() {
+                    debug!("reading ignore file {:?}", file);
+                    rules.parse(&String::from_utf8_lossy(&buf))
+                }
+            }
+            self.dirs.insert(dir.clone(), rules);
+        }
+        self.dirs.get(&dir).unwrap()
+    }
+}
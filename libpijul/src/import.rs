@@ -0,0 +1,247 @@
+//! Initial import of large trees.
+//!
+//! The first record of a tree only adds files, so most of what
+//! [`Builder::record`](crate::record::Builder::record) does is useless
+//! there: there is no graph to retrieve, and nothing to diff.
+//! [`snapshot`] records the files added under a prefix without
+//! looking at the graph, walking the tree of tracked files once and
+//! reading the files on several threads. Each file is appended to the
+//! contents of the change as soon as it is read, and its buffer is
+//! then dropped, but the contents of the change are kept in memory
+//! until the change is saved, so the import needs about as much
+//! memory as the total size of the files.
+//!
+//! The hashes of the files are computed by the same threads, and
+//! remembered as [verified](crate::file_stats::Verified) by
+//! [`Recorded::save_file_stats`], so that later records don't diff
+//! moved files again.
+//!
+//! The files must already be tracked, for instance with
+//! [`FileSystem::add_prefix_rec`](crate::working_copy::FileSystem::add_prefix_rec),
+//! which walks the working copy in parallel.
+use crate::channel_settings::EolPolicy;
+use crate::path::{components, Components};
+use crate::pristine::*;
+use crate::record::{
+    read_new_file, retry, Builder, NewFile, ReadErrorPolicy, RecordItem, RecordReport, Recorded,
+};
+use crate::small_string::SmallString;
+use crate::working_copy::WorkingCopy;
+use crossbeam_deque::{Injector, Steal};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Options of [`snapshot`].
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotOptions {
+    /// Number of threads reading the files, including the calling
+    /// thread.
+    pub threads: usize,
+    /// What to do with the files that can't be read.
+    pub read_error_policy: ReadErrorPolicy,
+    pub eol_policy: EolPolicy,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            threads: 1,
+            read_error_policy: ReadErrorPolicy::default(),
+            eol_policy: EolPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError<W: std::error::Error, T: std::error::Error + 'static> {
+    #[error("Working copy error: {0}")]
+    WorkingCopy(W),
+    #[error(transparent)]
+    Txn(T),
+    #[error("Path not in repository: {0}")]
+    PathNotInRepo(String),
+    #[error(transparent)]
+    Chunks(#[from] crate::record::ChunksError),
+}
+
+impl<W: std::error::Error, T: std::error::Error + 'static> From<TxnErr<T>> for SnapshotError<W, T> {
+    fn from(e: TxnErr<T>) -> Self {
+        SnapshotError::Txn(e.0)
+    }
+}
+
+/// Record the tracked files under `prefix` that were never recorded,
+/// along with their parent directories. Files already recorded are
+/// left untouched, [`Builder::record`] records their modifications.
+///
+/// Files that can't be read are handled according to
+/// `options.read_error_policy`, and returned in the report if they
+/// were skipped. Tracked files missing from the working copy are
+/// always skipped.
+pub fn snapshot<T: TreeTxnT, W: WorkingCopy + Clone + Send + Sync + 'static>(
+    txn: &T,
+    working_copy: &W,
+    prefix: &str,
+    options: &SnapshotOptions,
+) -> Result<(Recorded, RecordReport), SnapshotError<W::Error, T::TreeError>>
+where
+    W::Error: 'static,
+{
+    let mut builder = Builder::new();
+    builder.read_error_policy = options.read_error_policy;
+    builder.eol_policy = Some(options.eol_policy);
+
+    // Directories are added first, on this thread, since their
+    // children need their vertices.
+    let dirs = builder.recorded();
+    let work = Arc::new(Injector::new());
+    let mut stack = vec![(
+        Inode::ROOT,
+        String::new(),
+        Position::OPTION_ROOT,
+        components(prefix),
+    )];
+    while let Some((inode, path, vertex, components)) = stack.pop() {
+        walk(
+            txn,
+            working_copy,
+            &mut dirs.lock(),
+            &work,
+            &mut stack,
+            inode,
+            &path,
+            vertex,
+            components,
+            prefix,
+        )?
+    }
+
+    let mut threads = Vec::new();
+    for t in 1..options.threads {
+        let working_copy = working_copy.clone();
+        let work = work.clone();
+        let rec = builder.recorded();
+        let policy = options.read_error_policy;
+        threads.push(std::thread::spawn(move || {
+            ingest(&working_copy, &work, &rec, policy, t)
+        }))
+    }
+    let rec = builder.recorded();
+    let mut result = ingest(working_copy, &work, &rec, options.read_error_policy, 0);
+    for t in threads {
+        match t.join() {
+            Ok(Err(e)) if result.is_ok() => result = Err(e),
+            Ok(_) => {}
+            Err(e) => warn!("Thread error {:?}", e),
+        }
+    }
+    result.map_err(SnapshotError::WorkingCopy)?;
+    std::mem::drop(dirs);
+    std::mem::drop(rec);
+    Ok(builder.finish_with_report()?)
+}
+
+/// Push the children of directory `inode`, at `path` and with vertex
+/// `vertex`: the directories that were never recorded are added, and
+/// the files are queued.
+#[allow(clippy::too_many_arguments)]
+fn walk<'a, T: TreeTxnT, W: WorkingCopy>(
+    txn: &T,
+    working_copy: &W,
+    dirs: &mut Recorded,
+    work: &Injector<RecordItem>,
+    stack: &mut Vec<(Inode, String, Position<Option<ChangeId>>, Components<'a>)>,
+    inode: Inode,
+    path: &str,
+    vertex: Position<Option<ChangeId>>,
+    mut components: Components<'a>,
+    prefix: &str,
+) -> Result<(), SnapshotError<W::Error, T::TreeError>> {
+    let comp = components.next();
+    let fileid = OwnedPathId {
+        parent_inode: inode,
+        basename: SmallString::new(),
+    };
+    let mut has_matching_children = false;
+    for x in txn.iter_tree(&fileid, None)? {
+        let (fileid_, child_inode) = x?;
+        if fileid_.parent_inode < inode || fileid_.basename.is_empty() {
+            continue;
+        } else if fileid_.parent_inode > inode {
+            break;
+        }
+        if let Some(comp) = comp {
+            if comp != fileid_.basename.as_str() {
+                continue;
+            }
+        }
+        has_matching_children = true;
+        let basename = fileid_.basename.as_str().to_string();
+        let mut full_path = path.to_string();
+        crate::path::push(&mut full_path, &basename);
+        let metadata = match working_copy.file_metadata(&full_path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                dirs.report.lock().skip(&full_path, &e);
+                continue;
+            }
+        };
+        if let Some(pos) = txn.get_inodes(child_inode, None)? {
+            if metadata.is_dir() {
+                stack.push((*child_inode, full_path, pos.to_option(), components.clone()))
+            }
+            continue;
+        }
+        let item = RecordItem {
+            v_papa: vertex,
+            papa: inode,
+            inode: *child_inode,
+            basename,
+            full_path: full_path.clone(),
+            metadata,
+        };
+        if metadata.is_dir() {
+            let file = NewFile {
+                meta: metadata,
+                contents: Vec::new(),
+                encoding: None,
+            };
+            if let Some(v) = dirs.push_file_addition(item, file) {
+                stack.push((*child_inode, full_path, v, components.clone()))
+            }
+        } else {
+            work.push(item)
+        }
+    }
+    if comp.is_some() && !has_matching_children {
+        return Err(SnapshotError::PathNotInRepo(prefix.to_string()));
+    }
+    Ok(())
+}
+
+/// Read the queued files into `rec`, until the queue is empty.
+fn ingest<W: WorkingCopy>(
+    working_copy: &W,
+    work: &Injector<RecordItem>,
+    rec: &Mutex<Recorded>,
+    policy: ReadErrorPolicy,
+    t: usize,
+) -> Result<(), W::Error> {
+    rec.lock().verify_additions = true;
+    loop {
+        match work.steal() {
+            Steal::Success(item) => {
+                debug!("ingest {:?} on thread {:?}", item.full_path, t);
+                match retry(policy, || read_new_file(working_copy, &item)) {
+                    Ok(file) => {
+                        rec.lock().push_file_addition(item, file);
+                    }
+                    Err(e) if policy == ReadErrorPolicy::Fail => return Err(e),
+                    Err(e) => rec.lock().report.lock().skip(&item.full_path, &e),
+                }
+            }
+            Steal::Retry => {}
+            Steal::Empty => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Coordination of the operations modifying a channel within a
+//! process.
+//!
+//! Recording reads the graph of a channel in several steps, and then
+//! applies the recorded change to that channel. If another thread
+//! applies changes to the same channel in between, for instance a
+//! daemon pulling in the background while the user records, the
+//! recorded change refers to a graph that doesn't exist anymore, and
+//! the bookkeeping of the working copy is corrupted.
+//!
+//! An [`Intents`] registry serialises these operations: before
+//! recording or applying, a thread declares its [`Intent`] on a set
+//! of channels with [`Intents::begin`], which waits until none of
+//! these channels is used by another operation, and takes all of
+//! them at once, so that operations on several channels can't
+//! deadlock. The returned [`IntentGuard`] remembers the state of the
+//! channels, and [`IntentGuard::check`] fails with
+//! [`IntentError::Moved`] if one of them was modified by an operation
+//! that didn't go through the registry. Errors for which
+//! [`IntentError::is_retryable`] is true leave the pristine
+//! untouched, and the operation can be started again.
+//!
+//! Recording and applying consult a registry passed in
+//! [`crate::record::Builder::intents`] and
+//! [`crate::ApplyOptions::intents`]: [`crate::record::Builder::record`]
+//! takes the channel until the [`crate::record::Recorded`] is
+//! applied or dropped, and checks it before applying.
+use crate::pristine::*;
+use crate::HashMap;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The operation a thread is about to run on a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    Record,
+    Apply,
+    Unrecord,
+}
+
+#[derive(Debug, Error)]
+pub enum IntentError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Channel {channel} is busy ({intent:?})")]
+    Busy { channel: String, intent: Intent },
+    #[error("Timed out waiting for channel {channel} ({intent:?})")]
+    Timeout { channel: String, intent: Intent },
+    #[error("Channel {channel} was modified during the operation: expected state {}, found {}", .expected.to_base32(), .found.to_base32())]
+    Moved {
+        channel: String,
+        expected: Merkle,
+        found: Merkle,
+    },
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+}
+
+impl<T: std::error::Error + 'static> IntentError<T> {
+    /// Whether the operation can be started again: these errors are
+    /// returned before anything is written.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            IntentError::Busy { .. } | IntentError::Timeout { .. } | IntentError::Moved { .. }
+        )
+    }
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for IntentError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        IntentError::Txn(e.0)
+    }
+}
+
+/// The channels in use in a process. Cloning an `Intents` returns a
+/// handle to the same registry.
+#[derive(Debug, Clone, Default)]
+pub struct Intents {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    held: Mutex<HashMap<String, Intent>>,
+    released: Condvar,
+}
+
+/// Channels held by an operation, released on drop.
+#[derive(Debug)]
+pub struct IntentGuard {
+    inner: Arc<Inner>,
+    intent: Intent,
+    /// The channels held, with their state when they were taken.
+    channels: Vec<(String, Merkle)>,
+}
+
+impl Intents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take `channels` for `intent`, waiting at most `timeout` for
+    /// the other operations on them to finish, or not waiting at all
+    /// if `timeout` is `None`.
+    pub fn begin<T: TxnT>(
+        &self,
+        txn: &T,
+        channels: &[&str],
+        intent: Intent,
+        timeout: Option<Duration>,
+    ) -> Result<IntentGuard, IntentError<T::GraphError>> {
+        let mut names: Vec<String> = channels.iter().map(|c| c.to_string()).collect();
+        names.sort();
+        names.dedup();
+        let mut held = self.wait(&names, intent, timeout)?;
+        // Read the states before taking the channels, so that
+        // nothing needs to be undone on errors.
+        let mut states = Vec::with_capacity(names.len());
+        for name in names {
+            let channel = if let Some(c) = txn.load_channel(&name)? {
+                c
+            } else {
+                return Err(IntentError::ChannelNotFound(name));
+            };
+            let state = current_state(txn, &*channel.read())?;
+            states.push((name, state))
+        }
+        Ok(self.take(&mut held, intent, states))
+    }
+
+    /// Same as [`Intents::begin`], on channels already loaded, for
+    /// instance when the caller holds a lock on them.
+    pub fn begin_channels<T: ChannelTxnT>(
+        &self,
+        txn: &T,
+        channels: &[&T::Channel],
+        intent: Intent,
+        timeout: Option<Duration>,
+    ) -> Result<IntentGuard, IntentError<T::GraphError>> {
+        let mut channels: Vec<_> = channels.iter().map(|c| (txn.name(c), *c)).collect();
+        channels.sort_by(|a, b| a.0.cmp(b.0));
+        channels.dedup_by(|a, b| a.0 == b.0);
+        let names: Vec<String> = channels.iter().map(|(n, _)| n.to_string()).collect();
+        let mut held = self.wait(&names, intent, timeout)?;
+        let mut states = Vec::with_capacity(names.len());
+        for (name, (_, channel)) in names.into_iter().zip(channels) {
+            states.push((name, current_state(txn, channel)?))
+        }
+        Ok(self.take(&mut held, intent, states))
+    }
+
+    /// Wait until none of `names` is held, or fail after `timeout`.
+    fn wait<E: std::error::Error + 'static>(
+        &self,
+        names: &[String],
+        intent: Intent,
+        timeout: Option<Duration>,
+    ) -> Result<MutexGuard<HashMap<String, Intent>>, IntentError<E>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut held = self.inner.held.lock();
+        loop {
+            let busy = names
+                .iter()
+                .find_map(|c| held.get(c).map(|&i| (c.clone(), i)));
+            let (channel, other) = if let Some(b) = busy {
+                b
+            } else {
+                return Ok(held);
+            };
+            debug!("intent {:?}: {:?} is busy ({:?})", intent, channel, other);
+            match deadline {
+                None => {
+                    return Err(IntentError::Busy {
+                        channel,
+                        intent: other,
+                    })
+                }
+                Some(deadline) => {
+                    if self
+                        .inner
+                        .released
+                        .wait_until(&mut held, deadline)
+                        .timed_out()
+                    {
+                        return Err(IntentError::Timeout {
+                            channel,
+                            intent: other,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn take(
+        &self,
+        held: &mut HashMap<String, Intent>,
+        intent: Intent,
+        states: Vec<(String, Merkle)>,
+    ) -> IntentGuard {
+        for (name, _) in states.iter() {
+            held.insert(name.clone(), intent);
+        }
+        IntentGuard {
+            inner: self.inner.clone(),
+            intent,
+            channels: states,
+        }
+    }
+
+    /// The operation running on `channel`, if any.
+    pub fn holder(&self, channel: &str) -> Option<Intent> {
+        self.inner.held.lock().get(channel).cloned()
+    }
+}
+
+impl IntentGuard {
+    pub fn intent(&self) -> Intent {
+        self.intent
+    }
+
+    /// Check that the channels held are still in the state they were
+    /// in when they were taken. Call this in the transaction that
+    /// will write the result of the operation, before writing it.
+    pub fn check<T: TxnT>(&self, txn: &T) -> Result<(), IntentError<T::GraphError>> {
+        for (name, expected) in self.channels.iter() {
+            let channel = if let Some(c) = txn.load_channel(name)? {
+                c
+            } else {
+                return Err(IntentError::ChannelNotFound(name.clone()));
+            };
+            let found = current_state(txn, &*channel.read())?;
+            if found != *expected {
+                return Err(IntentError::Moved {
+                    channel: name.clone(),
+                    expected: *expected,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that the operation moved `channel` to state `state`, so
+    /// that later checks expect this new state.
+    pub fn advance(&mut self, channel: &str, state: Merkle) {
+        if let Some((_, s)) = self.channels.iter_mut().find(|(c, _)| c == channel) {
+            *s = state
+        }
+    }
+}
+
+impl Drop for IntentGuard {
+    fn drop(&mut self) {
+        let mut held = self.inner.held.lock();
+        for (name, _) in self.channels.iter() {
+            held.remove(name);
+        }
+        self.inner.released.notify_all();
+    }
+}
@@ -0,0 +1,227 @@
+//! Intents of operations on channels, stored in the pristine for
+//! crash recovery.
+//!
+//! Applying or unrecording changes updates both the pristine, which
+//! is transactional, and the working copy, which isn't. Before such
+//! an operation, [`begin`] stores its intent (the operation, the
+//! changes and the state of the channel before the operation) in a
+//! transaction committed on its own. The operation and [`finish`]
+//! are then done in another transaction. If the process is killed in
+//! between, the intent is still there the next time the pristine is
+//! opened: [`pending`] lists these intents, and [`recover`] rolls the
+//! pristine forward or back, after which the caller outputs the
+//! channel to bring the working copy back in sync.
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+
+/// An operation on a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    Apply,
+    Unrecord,
+}
+
+/// The intent of an operation, stored before the operation starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Intent {
+    pub operation: Operation,
+    /// The changes applied or unrecorded, in order.
+    pub hashes: Vec<Hash>,
+    /// State of the channel before the operation.
+    pub prior_state: Merkle,
+}
+
+/// How to recover from an unfinished operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Complete the operation.
+    Forward,
+    /// Undo the part of the operation that reached the pristine.
+    Back,
+}
+
+#[derive(Debug, Error)]
+pub enum JournalError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+    #[error("Malformed journal entry for channel {0}")]
+    Malformed(String),
+    #[error("Channel {0} has an unfinished operation")]
+    Unfinished(String),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for JournalError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        JournalError::Txn(e.0)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RecoverError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Journal(#[from] JournalError<T>),
+    #[error(transparent)]
+    Apply(#[from] crate::apply::ApplyError<C, T>),
+    #[error(transparent)]
+    Unrecord(#[from] crate::unrecord::UnrecordError<C, T>),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for RecoverError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        RecoverError::Journal(e.into())
+    }
+}
+
+/// Load the intent of the unfinished operation on `channel`, if any.
+pub fn load<T: TxnT>(
+    txn: &T,
+    channel: &str,
+) -> Result<Option<Intent>, JournalError<T::GraphError>> {
+    if let Some(bytes) = txn.get_journal(channel)? {
+        Ok(Some(bincode::deserialize(bytes).map_err(|_| {
+            JournalError::Malformed(channel.to_string())
+        })?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The intents of all unfinished operations, by channel.
+pub fn pending<T: TxnT>(txn: &T) -> Result<Vec<(String, Intent)>, JournalError<T::GraphError>> {
+    let mut result = Vec::new();
+    for c in txn.iter_channels("")? {
+        let (name, _) = c?;
+        if let Some(intent) = load(txn, name.as_str())? {
+            result.push((name.as_str().to_string(), intent))
+        }
+    }
+    Ok(result)
+}
+
+/// Store the intent of applying or unrecording `hashes` on `channel`.
+/// The transaction must be committed right before the operation
+/// starts. Fails if another operation on `channel` is unfinished.
+///
+/// Only the changes not already applied (or already unrecorded) are
+/// stored, so that going back doesn't undo what was there before the
+/// operation. If there are none, nothing is stored and this returns
+/// `None`.
+pub fn begin<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+    operation: Operation,
+    hashes: &[Hash],
+) -> Result<Option<Intent>, JournalError<T::GraphError>> {
+    if txn.get_journal(channel)?.is_some() {
+        return Err(JournalError::Unfinished(channel.to_string()));
+    }
+    let c = if let Some(c) = txn.load_channel(channel)? {
+        c
+    } else {
+        return Err(JournalError::ChannelNotFound(channel.to_string()));
+    };
+    let c = c.read();
+    let prior_state = current_state(&*txn, &*c)?;
+    let mut todo = Vec::with_capacity(hashes.len());
+    for h in hashes {
+        if is_on_channel(&*txn, &*c, h)? != (operation == Operation::Apply) {
+            todo.push(*h)
+        }
+    }
+    std::mem::drop(c);
+    if todo.is_empty() {
+        return Ok(None);
+    }
+    let intent = Intent {
+        operation,
+        hashes: todo,
+        prior_state,
+    };
+    txn.put_journal(channel, &bincode::serialize(&intent).unwrap())?;
+    Ok(Some(intent))
+}
+
+/// The paths touched by the changes of `intent`, which are the only
+/// ones to output after recovering from it. This is `None` if one of
+/// the changes moves files, in which case the former paths aren't
+/// known and the whole channel must be output.
+pub fn touched_paths<P: ChangeStore>(
+    changes: &P,
+    intent: &Intent,
+) -> Result<Option<Vec<String>>, P::Error> {
+    let mut paths = std::collections::BTreeSet::new();
+    for h in intent.hashes.iter() {
+        let change = changes.get_change(h)?;
+        for t in change.hashed.touched_paths() {
+            if t.kinds.contains(&crate::change::HunkKind::FileMove) {
+                return Ok(None);
+            }
+            paths.insert(t.path);
+        }
+    }
+    Ok(Some(paths.into_iter().collect()))
+}
+
+/// Delete the intent of the operation on `channel`, in the same
+/// transaction as the operation.
+pub fn finish<T: MutTxnT>(txn: &mut T, channel: &str) -> Result<bool, TxnErr<T::GraphError>> {
+    txn.del_journal(channel)
+}
+
+fn is_on_channel<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hash: &Hash,
+) -> Result<bool, TxnErr<T::GraphError>> {
+    if let Some(id) = txn.get_internal(&hash.into())? {
+        Ok(txn.get_changeset(txn.changes(channel), id)?.is_some())
+    } else {
+        Ok(false)
+    }
+}
+
+/// Recover from the unfinished operation on `channel`, if any, and
+/// delete its intent. Going forward applies (or unrecords) the
+/// changes of the intent that are not already applied (or
+/// unrecorded); going back does the opposite, but keeps the
+/// dependencies applied along with the changes. Either way, the
+/// working copy must then be output from the channel.
+///
+/// Returns the intent that was recovered from.
+pub fn recover<T: MutTxnT, P: ChangeStore>(
+    txn: &mut T,
+    changes: &P,
+    channel: &ChannelRef<T>,
+    direction: Direction,
+    salt: u64,
+) -> Result<Option<Intent>, RecoverError<P::Error, T::GraphError>> {
+    let name = txn.name(&*channel.read()).to_string();
+    let intent = if let Some(intent) = load(&*txn, &name)? {
+        intent
+    } else {
+        return Ok(None);
+    };
+    let apply = match (intent.operation, direction) {
+        (Operation::Apply, Direction::Forward) | (Operation::Unrecord, Direction::Back) => true,
+        (Operation::Apply, Direction::Back) | (Operation::Unrecord, Direction::Forward) => false,
+    };
+    // Going back undoes the changes in the reverse order.
+    let mut hashes = intent.hashes.clone();
+    if direction == Direction::Back {
+        hashes.reverse()
+    }
+    for h in hashes.iter() {
+        let on_channel = is_on_channel(&*txn, &*channel.read(), h)?;
+        if apply && !on_channel {
+            crate::apply::apply_change_rec(changes, txn, &mut *channel.write(), h, false)?
+        } else if !apply && on_channel {
+            crate::unrecord::unrecord(txn, channel, changes, h, salt)?;
+        }
+    }
+    txn.del_journal(&name)?;
+    Ok(Some(intent))
+}
@@ -12,19 +12,66 @@ extern crate thiserror;
 extern crate lazy_static;
 
 pub mod alive;
+#[cfg(feature = "ondisk-repos")]
+pub mod api;
 mod apply;
+pub mod attributes;
+pub mod audit;
+#[cfg(feature = "ondisk-repos")]
+pub mod backup;
 pub mod change;
 pub mod changestore;
+pub mod channel;
+pub mod channel_settings;
+pub mod chunks;
+pub mod ci_status;
+pub mod compose;
+pub mod dedup;
+pub mod deps;
 mod diff;
+pub mod dirty_cache;
+pub mod doctor;
+pub mod durability;
+pub mod edit;
+pub mod executor;
+pub mod file_id;
+pub mod file_stats;
 mod find_alive;
 pub mod fs;
+pub mod header;
+pub mod hunk_kind;
+pub mod ignore;
+pub mod import;
+pub mod intent;
+pub mod journal;
+pub mod maintenance;
+pub mod merge;
 mod missing_context;
+pub mod notes;
+pub mod opaque;
 pub mod output;
 pub mod path;
+#[cfg(feature = "ondisk-repos")]
+pub mod pool;
 pub mod pristine;
+pub mod proof;
+pub mod provenance;
+pub mod quota;
 pub mod record;
+pub mod redact;
+pub mod rename;
+pub mod render;
+#[cfg(feature = "ondisk-repos")]
+pub mod repository;
+pub mod resolution;
+pub mod review;
+pub mod roots;
+pub mod search;
 pub mod small_string;
+pub mod state;
+pub mod subrepo;
 mod text_encoding;
+pub mod tree_export;
 mod unrecord;
 mod vector2;
 pub mod vertex_buffer;
@@ -35,6 +82,12 @@ pub mod tag;
 
 mod chardetng;
 
+#[cfg(feature = "fuse")]
+pub mod fuse;
+
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+
 #[cfg(test)]
 mod tests;
 
@@ -61,15 +114,26 @@ pub enum RemoteError {
 }
 
 pub use crate::apply::Workspace as ApplyWorkspace;
-pub use crate::apply::{apply_change_arc, ApplyError, LocalApplyError};
+pub use crate::apply::{
+    apply_change_arc, apply_change_report, apply_report, preview_working_copy, ApplyError,
+    ApplyOptions, ApplyReport, LocalApplyError, PreviewAction, PreviewError, PreviewFile,
+    RenameEditConflict, TimestampPolicy, WorkingCopyPreview,
+};
+pub use crate::diff::{
+    incremental, working_copy_gutter, BufferDiff, BufferEdit, ChannelAlgorithm, DiffAlgorithm,
+    GutterError, Line, LineChange, LineChangeKind, PendingHunk, Replacement, UnknownAlgorithm,
+};
 pub use crate::fs::{FsError, WorkingCopyIterator};
-pub use crate::output::{Archive, Conflict};
+#[allow(deprecated)]
+pub use crate::output::Archive;
+pub use crate::output::{Conflict, OutputSink};
 pub use crate::pristine::{
     ArcTxn, Base32, ChangeId, ChannelMutTxnT, ChannelRef, ChannelTxnT, DepsTxnT, EdgeFlags,
     GraphTxnT, Hash, Inode, Merkle, MutTxnT, OwnedPathId, RemoteRef, TreeTxnT, TxnT, Vertex,
 };
 pub use crate::record::Builder as RecordBuilder;
 pub use crate::record::{Algorithm, InodeUpdate};
+pub use crate::text_encoding::{DefaultEncodingDetector, Encoding, EncodingDetector, Utf8Only};
 pub use crate::unrecord::UnrecordError;
 
 // Making hashmaps deterministic (for testing)
@@ -133,6 +197,24 @@ pub trait MutTxnTExt: pristine::MutTxnT {
         crate::apply::apply_change_rec(changes, self, channel, hash, false)
     }
 
+    fn apply_change_rec_opt<C: changestore::ChangeStore>(
+        &mut self,
+        changes: &C,
+        channel: &mut Self::Channel,
+        hash: &pristine::Hash,
+        options: &ApplyOptions,
+    ) -> Result<(), crate::apply::ApplyError<C::Error, Self::GraphError>> {
+        crate::apply::apply_change_rec_opt(
+            changes,
+            self,
+            channel,
+            hash,
+            &mut ApplyWorkspace::new(),
+            false,
+            options,
+        )
+    }
+
     fn apply_deps_rec<C: changestore::ChangeStore>(
         &mut self,
         changes: &C,
@@ -206,7 +288,7 @@ pub trait MutTxnTExt: pristine::MutTxnT {
             changes,
             prefix,
         )?;
-        Ok(builder.finish())
+        Ok(builder.finish()?)
     }
     */
 
@@ -216,6 +298,9 @@ pub trait MutTxnTExt: pristine::MutTxnT {
         recorded: record::Recorded,
         changestore: &C,
     ) -> Result<pristine::Hash, crate::apply::ApplyError<C::Error, Self::GraphError>> {
+        recorded
+            .check_intent(self)
+            .map_err(apply::LocalApplyError::Intent)?;
         let contents_hash = {
             let mut hasher = pristine::Hasher::default();
             hasher.update(&recorded.contents.lock()[..]);
@@ -321,7 +406,7 @@ pub trait MutTxnTExt: pristine::MutTxnT {
         fs::remove_file(self, a)
     }
 
-    fn archive_with_state<P: changestore::ChangeStore, A: Archive>(
+    fn archive_with_state<P: changestore::ChangeStore, A: output::OutputSink>(
         &mut self,
         changes: &P,
         channel: &mut pristine::ChannelRef<Self>,
@@ -348,7 +433,7 @@ pub trait MutTxnTExt: pristine::MutTxnT {
     fn archive_prefix_with_state<
         'a,
         P: changestore::ChangeStore,
-        A: Archive,
+        A: output::OutputSink,
         I: Iterator<Item = &'a str>,
     >(
         &mut self,
@@ -575,7 +660,7 @@ pub trait TxnTExt: pristine::TxnT {
         fs::follow_oldest_path(changes, self, &channel.read(), path)
     }
 
-    fn archive<C: changestore::ChangeStore, A: Archive>(
+    fn archive<C: changestore::ChangeStore, A: output::OutputSink>(
         &self,
         changes: &C,
         channel: &pristine::ChannelRef<Self>,
@@ -585,7 +670,12 @@ pub trait TxnTExt: pristine::TxnT {
         output::archive(changes, self, channel, &mut std::iter::empty(), arch)
     }
 
-    fn archive_prefix<'a, C: changestore::ChangeStore, I: Iterator<Item = &'a str>, A: Archive>(
+    fn archive_prefix<
+        'a,
+        C: changestore::ChangeStore,
+        I: Iterator<Item = &'a str>,
+        A: output::OutputSink,
+    >(
         &self,
         changes: &C,
         channel: &pristine::ChannelRef<Self>,
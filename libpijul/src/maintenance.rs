@@ -0,0 +1,221 @@
+//! Automatic maintenance after applying and unrecording changes.
+//!
+//! An [`AutoMaintenance`] counts the changes applied to and
+//! unrecorded from each channel during the life of a process. When a
+//! channel reaches the number of operations set in its
+//! [`MaintenancePolicy`], or when the proportion of pseudo-edges in
+//! its graph passes a threshold, it builds a [`MaintenanceRequest`]
+//! to prune the redundant pseudo-edges of that channel, and to purge
+//! the channels soft-deleted for too long.
+//!
+//! Requests are handed to a [`MaintenanceScheduler`]. The default,
+//! [`Inline`], runs them immediately, in the same transaction.
+//! Embedders with their own schedulers can instead keep the request,
+//! and call [`run`] later, for example when the repository is idle.
+use crate::channel::{purge, DeletedChannel, SoftDeleteError};
+use crate::doctor::graph_stats;
+use crate::pristine::*;
+use crate::HashMap;
+use std::time::Duration;
+
+/// When to run maintenance.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MaintenancePolicy {
+    /// Run maintenance on a channel after this many changes have
+    /// been applied to it or unrecorded from it.
+    pub every: Option<u64>,
+    /// Run maintenance on a channel when the proportion of
+    /// pseudo-edges in its graph exceeds this. Checking it requires
+    /// reading the entire graph after each batch of operations.
+    pub max_pseudo_ratio: Option<f64>,
+    /// Don't trigger on `max_pseudo_ratio` below this number of
+    /// pseudo-edges.
+    pub min_pseudo_edges: usize,
+    /// If set, maintenance also purges the channels soft-deleted for
+    /// longer than this.
+    pub purge_retention: Option<Duration>,
+}
+
+impl MaintenancePolicy {
+    /// Whether this policy can trigger maintenance at all.
+    pub fn is_enabled(&self) -> bool {
+        self.every.is_some() || self.max_pseudo_ratio.is_some()
+    }
+}
+
+/// What triggered a maintenance request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// The number of operations since the last maintenance.
+    Operations(u64),
+    /// The proportion of pseudo-edges in the graph.
+    PseudoRatio(f64),
+}
+
+/// Maintenance to be run on a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceRequest {
+    pub channel: String,
+    pub trigger: Trigger,
+    /// If set, also purge the channels soft-deleted for longer than
+    /// this.
+    pub purge_retention: Option<Duration>,
+}
+
+/// The result of a maintenance run.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MaintenanceReport {
+    pub prune: PruneReport,
+    pub purged: Vec<DeletedChannel>,
+}
+
+/// What a scheduler decided to do with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Run the request now, in the current transaction.
+    Now,
+    /// The scheduler will run the request itself, later.
+    Deferred,
+}
+
+/// Decides when maintenance requests are run.
+pub trait MaintenanceScheduler {
+    fn schedule(&mut self, request: &MaintenanceRequest) -> Schedule;
+}
+
+/// A scheduler running all requests immediately.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Inline;
+
+impl MaintenanceScheduler for Inline {
+    fn schedule(&mut self, _: &MaintenanceRequest) -> Schedule {
+        Schedule::Now
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MaintenanceError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Prune(#[from] PruneError<T>),
+    #[error(transparent)]
+    Purge(#[from] SoftDeleteError<T>),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for MaintenanceError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        MaintenanceError::Txn(e.0)
+    }
+}
+
+/// Counts operations on channels, and triggers maintenance according
+/// to a policy.
+pub struct AutoMaintenance<S: MaintenanceScheduler = Inline> {
+    pub policy: MaintenancePolicy,
+    pub scheduler: S,
+    operations: HashMap<String, u64>,
+}
+
+impl AutoMaintenance<Inline> {
+    pub fn new(policy: MaintenancePolicy) -> Self {
+        Self::with_scheduler(policy, Inline)
+    }
+}
+
+impl<S: MaintenanceScheduler> AutoMaintenance<S> {
+    pub fn with_scheduler(policy: MaintenancePolicy, scheduler: S) -> Self {
+        AutoMaintenance {
+            policy,
+            scheduler,
+            operations: HashMap::default(),
+        }
+    }
+
+    /// To be called after applying `n` changes to `channel`. Returns
+    /// the report of the maintenance, if it was triggered and run
+    /// immediately.
+    pub fn after_apply<T: MutTxnT + GraphIter>(
+        &mut self,
+        txn: &mut T,
+        channel: &ChannelRef<T>,
+        n: u64,
+    ) -> Result<Option<MaintenanceReport>, MaintenanceError<T::GraphError>> {
+        self.after_operations(txn, channel, n)
+    }
+
+    /// To be called after unrecording `n` changes from `channel`.
+    pub fn after_unrecord<T: MutTxnT + GraphIter>(
+        &mut self,
+        txn: &mut T,
+        channel: &ChannelRef<T>,
+        n: u64,
+    ) -> Result<Option<MaintenanceReport>, MaintenanceError<T::GraphError>> {
+        self.after_operations(txn, channel, n)
+    }
+
+    fn after_operations<T: MutTxnT + GraphIter>(
+        &mut self,
+        txn: &mut T,
+        channel: &ChannelRef<T>,
+        n: u64,
+    ) -> Result<Option<MaintenanceReport>, MaintenanceError<T::GraphError>> {
+        if !self.policy.is_enabled() {
+            return Ok(None);
+        }
+        let name = txn.name(&*channel.read()).to_string();
+        let ops = self.operations.entry(name.clone()).or_insert(0);
+        *ops += n;
+        let ops = *ops;
+        let trigger = if self.policy.every.map(|e| ops >= e).unwrap_or(false) {
+            Some(Trigger::Operations(ops))
+        } else if let Some(max) = self.policy.max_pseudo_ratio {
+            let stats = graph_stats(txn, txn.graph(&*channel.read()))?;
+            if stats.pseudo >= self.policy.min_pseudo_edges && stats.pseudo_ratio() > max {
+                Some(Trigger::PseudoRatio(stats.pseudo_ratio()))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        let request = if let Some(trigger) = trigger {
+            MaintenanceRequest {
+                channel: name.clone(),
+                trigger,
+                purge_retention: self.policy.purge_retention,
+            }
+        } else {
+            return Ok(None);
+        };
+        debug!("maintenance request {:?}", request);
+        // Deferred requests are the scheduler's responsibility from
+        // now on, so the count starts over in both cases.
+        self.operations.insert(name, 0);
+        match self.scheduler.schedule(&request) {
+            Schedule::Now => Ok(Some(run(txn, &request)?)),
+            Schedule::Deferred => Ok(None),
+        }
+    }
+}
+
+/// Run the maintenance described by `request`.
+pub fn run<T: MutTxnT>(
+    txn: &mut T,
+    request: &MaintenanceRequest,
+) -> Result<MaintenanceReport, MaintenanceError<T::GraphError>> {
+    let channel = if let Some(c) = txn.load_channel(&request.channel)? {
+        c
+    } else {
+        return Err(MaintenanceError::ChannelNotFound(request.channel.clone()));
+    };
+    let prune = prune_pseudo(txn, &mut *channel.write())?;
+    let purged = if let Some(retention) = request.purge_retention {
+        purge(txn, retention)?
+    } else {
+        Vec::new()
+    };
+    Ok(MaintenanceReport { prune, purged })
+}
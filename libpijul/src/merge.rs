@@ -0,0 +1,426 @@
+//! Merging the conflicts of binary files with mergers provided by
+//! embedders.
+//!
+//! Conflict markers make no sense in binary files: a conflicted image
+//! or game asset can't be opened to resolve its conflicts. A
+//! [`BinaryMerger`] receives the versions of a conflicted binary file,
+//! and possibly their common ancestor, as byte buffers, and may merge
+//! them using knowledge of their format. [`merge_binary_conflicts`]
+//! writes the merged files to the working copy, and records them as a
+//! resolution change, applied to the channel.
+use crate::apply::LocalApplyError;
+use crate::change::{Change, ChangeHeader};
+use crate::changestore::ChangeStore;
+use crate::output::{ConflictId, FileError};
+use crate::pristine::*;
+use crate::record::{Builder, RecordError};
+use crate::vertex_buffer::{ConflictsWriter, VertexBuffer};
+use crate::working_copy::WorkingCopy;
+use crate::HashSet;
+use std::io::Write;
+
+/// A conflicted binary file, passed to a [`BinaryMerger`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryConflict<'a> {
+    pub path: &'a str,
+    /// The versions of the file. Version `k` has side `k` of each
+    /// conflict of the file, or its last side if it has fewer sides.
+    /// The sides of nested conflicts are always their first side.
+    pub sides: Vec<Vec<u8>>,
+    /// The file before the conflicting changes, if the bytes replaced
+    /// by each conflict could be found, i.e. if the changes of its
+    /// sides deleted a single sequence of bytes at its position.
+    pub ancestor: Option<Vec<u8>>,
+}
+
+/// A merger of conflicted binary files.
+pub trait BinaryMerger {
+    /// Merge `conflict`, returning the merged contents, or `None` to
+    /// leave the conflict unresolved.
+    fn merge(&self, conflict: &BinaryConflict) -> Option<Vec<u8>>;
+}
+
+impl<F: Fn(&BinaryConflict) -> Option<Vec<u8>>> BinaryMerger for F {
+    fn merge(&self, conflict: &BinaryConflict) -> Option<Vec<u8>> {
+        self(conflict)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MergeError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + 'static,
+> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Changestore error: {0}")]
+    Changestore(C),
+    #[error(transparent)]
+    File(#[from] FileError<C, T>),
+    #[error("Working copy error: {0}")]
+    WorkingCopy(W),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Block(#[from] BlockError<T>),
+    #[error(transparent)]
+    Record(#[from] RecordError<C, W, T>),
+    #[error(transparent)]
+    LocalApply(#[from] LocalApplyError<T>),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static, W: std::error::Error>
+    From<TxnErr<T>> for MergeError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        MergeError::Txn(e.0)
+    }
+}
+
+/// Call `merger` on each binary file of `channel` with conflicts,
+/// write the files it merged to `working_copy`, and record them as a
+/// change with header `header`, applied to `channel`. Files edited in
+/// `working_copy` since they were output are left untouched.
+///
+/// Returns the hash of the resolution change and the paths of the
+/// merged files, or `None` if no file was merged.
+pub fn merge_binary_conflicts<T, C, W, M>(
+    changes: &C,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    working_copy: &W,
+    merger: &M,
+    header: ChangeHeader,
+) -> Result<Option<(Hash, Vec<String>)>, MergeError<C::Error, T::GraphError, W::Error>>
+where
+    T: MutTxnT + Send + Sync + 'static,
+    C: ChangeStore + Clone + Send + 'static,
+    W: WorkingCopy + Clone + Send + Sync + 'static,
+    W::Error: 'static,
+    M: BinaryMerger + ?Sized,
+{
+    let merged = {
+        let txn = txn.read();
+        let channel = channel.read();
+        merge_files(changes, &*txn, &*channel, working_copy, merger)?
+    };
+    if merged.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = Builder::new();
+    for (path, contents) in merged.iter() {
+        let mut w = working_copy
+            .write_file(path)
+            .map_err(MergeError::WorkingCopy)?;
+        w.write_all(contents)?;
+        std::mem::drop(w);
+        builder.record(
+            txn.clone(),
+            crate::diff::Algorithm::default(),
+            channel.clone(),
+            working_copy,
+            changes,
+            path,
+            1,
+        )?;
+    }
+    let rec = builder.finish().map_err(RecordError::from)?;
+    if rec.actions.is_empty() {
+        return Ok(None);
+    }
+    let mut txn = txn.write();
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(MergeError::Txn)?;
+    let change = Change::make_change(
+        &*txn,
+        channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        header,
+        Vec::new(),
+    )?;
+    let hash = changes
+        .save_change(&change)
+        .map_err(MergeError::Changestore)?;
+    crate::apply::apply_local_change(&mut *txn, channel, &change, &hash, &rec.updatables)?;
+    Ok(Some((
+        hash,
+        merged.into_iter().map(|(path, _)| path).collect(),
+    )))
+}
+
+/// Call `merger` on each binary file of `channel` with conflicts that
+/// is still as output in `working_copy`, and return the merged files.
+fn merge_files<T, C, W, M>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    working_copy: &W,
+    merger: &M,
+) -> Result<Vec<(String, Vec<u8>)>, MergeError<C::Error, T::GraphError, W::Error>>
+where
+    T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+    C: ChangeStore,
+    W: WorkingCopy,
+    W::Error: 'static,
+    M: BinaryMerger + ?Sized,
+{
+    let mut files = Vec::new();
+    for x in crate::fs::iter_working_copy(txn, Inode::ROOT) {
+        let (inode, path) = x.map_err(MergeError::Txn)?;
+        if crate::fs::is_directory(txn, inode)? {
+            continue;
+        }
+        if let Some(pos) = crate::record::get_inodes(txn, channel, &inode)? {
+            files.push((*pos, path))
+        }
+    }
+    let mut merged = Vec::new();
+    for (pos, path) in files {
+        let mut sides = Sides::default();
+        crate::output::output_file(
+            changes,
+            txn,
+            channel,
+            pos,
+            &mut sides,
+            working_copy.graph_limits(),
+        )?;
+        if !sides.has_conflicts() {
+            continue;
+        }
+        let n = sides.len();
+        let versions: Vec<_> = (0..n).map(|k| sides.version(k)).collect();
+        if !versions.iter().any(|v| crate::search::is_binary(v)) {
+            continue;
+        }
+        // Only merge files that are still as output.
+        let mut conflicts = Vec::new();
+        let mut full = ConflictsWriter::new(Vec::new(), &path, &mut conflicts);
+        crate::output::output_file(
+            changes,
+            txn,
+            channel,
+            pos,
+            &mut full,
+            working_copy.graph_limits(),
+        )?;
+        let mut current = Vec::new();
+        if working_copy.read_file(&path, &mut current).is_err() || current != full.w {
+            debug!("{:?} was edited, not merging", path);
+            continue;
+        }
+        let ancestor = sides.ancestor(changes, txn, txn.graph(channel), pos)?;
+        let conflict = BinaryConflict {
+            path: &path,
+            sides: versions,
+            ancestor,
+        };
+        if let Some(contents) = merger.merge(&conflict) {
+            debug!("merged {:?}", path);
+            merged.push((path, contents))
+        }
+    }
+    Ok(merged)
+}
+
+/// The output of a file, split into the parts outside conflicts and
+/// the sides of the conflicts.
+#[derive(Default)]
+struct Sides {
+    segments: Vec<Segment>,
+    /// Side numbers of the nested conflicts being output.
+    nested: Vec<usize>,
+    /// Whether the last segment is a conflict being output.
+    in_conflict: bool,
+    /// The last vertex output outside conflicts.
+    last: Option<Vertex<ChangeId>>,
+}
+
+enum Segment {
+    Common(Vec<u8>),
+    Conflict {
+        /// The last vertex before the conflict.
+        before: Option<Vertex<ChangeId>>,
+        sides: Vec<Vec<u8>>,
+        vertices: Vec<Vertex<ChangeId>>,
+    },
+}
+
+impl VertexBuffer for Sides {
+    fn output_line<E, F>(&mut self, v: Vertex<ChangeId>, c: F) -> Result<(), E>
+    where
+        E: From<std::io::Error>,
+        F: FnOnce(&mut Vec<u8>) -> Result<(), E>,
+    {
+        let mut buf = Vec::new();
+        c(&mut buf)?;
+        if self.nested.iter().any(|&side| side > 0) {
+            return Ok(());
+        }
+        if self.in_conflict {
+            if let Some(Segment::Conflict {
+                sides, vertices, ..
+            }) = self.segments.last_mut()
+            {
+                sides.last_mut().unwrap().extend_from_slice(&buf);
+                vertices.push(v);
+            }
+            return Ok(());
+        }
+        self.last = Some(v);
+        if let Some(Segment::Common(c)) = self.segments.last_mut() {
+            c.extend_from_slice(&buf)
+        } else {
+            self.segments.push(Segment::Common(buf))
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker(&mut self, _: &str) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn begin_conflict(&mut self, _: ConflictId) -> Result<(), std::io::Error> {
+        if self.in_conflict {
+            self.nested.push(0)
+        } else {
+            self.in_conflict = true;
+            self.segments.push(Segment::Conflict {
+                before: self.last,
+                sides: vec![Vec::new()],
+                vertices: Vec::new(),
+            })
+        }
+        Ok(())
+    }
+
+    fn conflict_next(&mut self) -> Result<(), std::io::Error> {
+        if let Some(side) = self.nested.last_mut() {
+            *side += 1
+        } else if let Some(Segment::Conflict { sides, .. }) = self.segments.last_mut() {
+            sides.push(Vec::new())
+        }
+        Ok(())
+    }
+
+    fn end_conflict(&mut self) -> Result<(), std::io::Error> {
+        if self.nested.pop().is_none() {
+            self.in_conflict = false
+        }
+        Ok(())
+    }
+
+    fn end_cyclic_conflict(&mut self) -> Result<(), std::io::Error> {
+        self.end_conflict()
+    }
+}
+
+impl Sides {
+    fn has_conflicts(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, Segment::Conflict { .. }))
+    }
+
+    /// The largest number of sides of a conflict.
+    fn len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Common(_) => 1,
+                Segment::Conflict { sides, .. } => sides.len(),
+            })
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// The file with side `k` of each conflict.
+    fn version(&self, k: usize) -> Vec<u8> {
+        let mut v = Vec::new();
+        for s in self.segments.iter() {
+            match s {
+                Segment::Common(c) => v.extend_from_slice(c),
+                Segment::Conflict { sides, .. } => {
+                    v.extend_from_slice(&sides[k.min(sides.len() - 1)])
+                }
+            }
+        }
+        v
+    }
+
+    /// The file with each conflict replaced by the bytes deleted by
+    /// the changes of its sides, if these bytes are a single sequence
+    /// of vertices following the last vertex before the conflict.
+    fn ancestor<T: GraphTxnT, C: ChangeStore, W: std::error::Error + 'static>(
+        &self,
+        changes: &C,
+        txn: &T,
+        graph: &T::Graph,
+        inode: Position<ChangeId>,
+    ) -> Result<Option<Vec<u8>>, MergeError<C::Error, T::GraphError, W>> {
+        let mut v = Vec::new();
+        let mut buf = Vec::new();
+        for s in self.segments.iter() {
+            let (before, vertices) = match s {
+                Segment::Common(c) => {
+                    v.extend_from_slice(c);
+                    continue;
+                }
+                Segment::Conflict {
+                    before, vertices, ..
+                } => (before.unwrap_or(inode.inode_vertex()), vertices),
+            };
+            // The changes that introduced the sides.
+            let mut intro = HashSet::default();
+            for &w in vertices.iter() {
+                for e in iter_adjacent(txn, graph, w, EdgeFlags::PARENT, EdgeFlags::all())? {
+                    let e = e?;
+                    if !e.flag().contains(EdgeFlags::DELETED) {
+                        intro.insert(e.introduced_by());
+                    }
+                }
+            }
+            // Follow the vertices they deleted.
+            let mut current = before;
+            let mut visited = HashSet::default();
+            loop {
+                let mut next = None;
+                for e in iter_adjacent(txn, graph, current, EdgeFlags::empty(), EdgeFlags::all())? {
+                    let e = e?;
+                    if !e.flag().contains(EdgeFlags::DELETED)
+                        || e.flag().intersects(EdgeFlags::PARENT | EdgeFlags::FOLDER)
+                        || !intro.contains(&e.introduced_by())
+                    {
+                        continue;
+                    }
+                    let dest = *txn.find_block(graph, e.dest())?;
+                    if next.map(|n| n != dest).unwrap_or(false) {
+                        // Several sequences of bytes were deleted.
+                        return Ok(None);
+                    }
+                    next = Some(dest)
+                }
+                if let Some(next) = next {
+                    if !visited.insert(next) {
+                        return Ok(None);
+                    }
+                    let hash = txn.get_external(&next.change)?.map(From::from);
+                    changes
+                        .get_contents(|_| hash, next, &mut buf)
+                        .map_err(MergeError::Changestore)?;
+                    v.extend_from_slice(&buf);
+                    current = next
+                } else {
+                    break;
+                }
+            }
+        }
+        Ok(Some(v))
+    }
+}
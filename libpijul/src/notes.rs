@@ -0,0 +1,158 @@
+//! Notes on changes.
+//!
+//! Changes are immutable, but tools often need to attach information
+//! to them after they are recorded. A note is an arbitrary byte
+//! string attached to a change under a namespace, which names the
+//! tool owning the note, such as [`crate::review::NAMESPACE`]. A
+//! change has at most one note in each namespace.
+//!
+//! Notes are stored in the pristine, by change, and exchanged with
+//! other repositories when pulling, one namespace at a time
+//! ([`list`], [`write_list`], [`read_list`]). Only the owner of a
+//! namespace knows how to combine two versions of a note, hence
+//! [`import`] takes a merge function.
+use crate::pristine::*;
+use std::collections::BTreeMap;
+
+/// The note of a change in some namespace, as exchanged with other
+/// repositories.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeNote {
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub change: Hash,
+    pub note: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum NotesError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed notes on change {}", .0.to_base32())]
+    Malformed(Hash),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for NotesError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        NotesError::Txn(e.0)
+    }
+}
+
+/// The notes of `change`, by namespace.
+fn get_all<T: TxnT>(
+    txn: &T,
+    change: &Hash,
+) -> Result<BTreeMap<String, Vec<u8>>, NotesError<T::GraphError>> {
+    if let Some(bytes) = txn.get_notes(change)? {
+        Ok(bincode::deserialize(bytes).map_err(|_| NotesError::Malformed(*change))?)
+    } else {
+        Ok(BTreeMap::new())
+    }
+}
+
+fn put_all<T: MutTxnT>(
+    txn: &mut T,
+    change: &Hash,
+    notes: &BTreeMap<String, Vec<u8>>,
+) -> Result<(), NotesError<T::GraphError>> {
+    if notes.is_empty() {
+        txn.del_notes(change)?;
+    } else {
+        txn.put_notes(change, &bincode::serialize(notes).unwrap())?;
+    }
+    Ok(())
+}
+
+/// The note of `change` in `namespace`.
+pub fn get<T: TxnT>(
+    txn: &T,
+    change: &Hash,
+    namespace: &str,
+) -> Result<Option<Vec<u8>>, NotesError<T::GraphError>> {
+    Ok(get_all(txn, change)?.remove(namespace))
+}
+
+/// The namespaces in which `change` has a note.
+pub fn namespaces<T: TxnT>(
+    txn: &T,
+    change: &Hash,
+) -> Result<Vec<String>, NotesError<T::GraphError>> {
+    Ok(get_all(txn, change)?.into_iter().map(|(k, _)| k).collect())
+}
+
+/// All the notes in `namespace`.
+pub fn list<T: TxnT>(
+    txn: &T,
+    namespace: &str,
+) -> Result<Vec<ChangeNote>, NotesError<T::GraphError>> {
+    let mut result = Vec::new();
+    for (change, bytes) in txn.iter_notes()? {
+        let mut notes: BTreeMap<String, Vec<u8>> =
+            bincode::deserialize(bytes).map_err(|_| NotesError::Malformed(change))?;
+        if let Some(note) = notes.remove(namespace) {
+            result.push(ChangeNote { change, note })
+        }
+    }
+    Ok(result)
+}
+
+/// Set the note of `change` in `namespace`, replacing any previous
+/// one.
+pub fn put<T: MutTxnT>(
+    txn: &mut T,
+    change: &Hash,
+    namespace: &str,
+    note: &[u8],
+) -> Result<(), NotesError<T::GraphError>> {
+    let mut notes = get_all(txn, change)?;
+    notes.insert(namespace.to_string(), note.to_vec());
+    put_all(txn, change, &notes)
+}
+
+/// Delete the note of `change` in `namespace`, returning whether
+/// there was one.
+pub fn del<T: MutTxnT>(
+    txn: &mut T,
+    change: &Hash,
+    namespace: &str,
+) -> Result<bool, NotesError<T::GraphError>> {
+    let mut notes = get_all(txn, change)?;
+    if notes.remove(namespace).is_none() {
+        return Ok(false);
+    }
+    put_all(txn, change, &notes)?;
+    Ok(true)
+}
+
+/// Merge the notes of another repository in `namespace`, returning
+/// the number of notes that changed. `merge` is called with the
+/// current note of a change, if any, and the one received, and
+/// returns the merged note, or `None` to keep the current one.
+pub fn import<T: MutTxnT, F: FnMut(Option<&[u8]>, &[u8]) -> Option<Vec<u8>>>(
+    txn: &mut T,
+    namespace: &str,
+    notes: &[ChangeNote],
+    mut merge: F,
+) -> Result<usize, NotesError<T::GraphError>> {
+    let mut changed = 0;
+    for n in notes {
+        let current = get(txn, &n.change, namespace)?;
+        if let Some(merged) = merge(current.as_deref(), &n.note) {
+            if current.as_ref() != Some(&merged) {
+                put(txn, &n.change, namespace, &merged)?;
+                changed += 1
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Write `notes` in the format read by [`read_list`], to send them to
+/// another repository.
+pub fn write_list<W: std::io::Write>(notes: &[ChangeNote], w: W) -> Result<(), serde_json::Error> {
+    serde_json::to_writer(w, notes)
+}
+
+/// Parse a list of notes written by [`write_list`].
+pub fn read_list(list: &[u8]) -> Result<Vec<ChangeNote>, serde_json::Error> {
+    serde_json::from_slice(list)
+}
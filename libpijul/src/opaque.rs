@@ -0,0 +1,622 @@
+//! Opaque directories, recorded as a single file.
+//!
+//! Vendored dependencies and other imported trees are replaced as a
+//! whole, and recording them file by file produces changes with
+//! thousands of hunks. A directory matched by a pattern with the
+//! `opaque` attribute in the [`ATTRIBUTES_FILE`] at the root of the
+//! working copy, such as
+//!
+//! ```text
+//! vendor/ opaque
+//! ```
+//!
+//! is seen by record, through an [`Opaque`] working copy, as a file
+//! whose contents are an archive of the directory, and must be added
+//! to the tree as a file. Its hunks are edits of that archive, and
+//! output expands it back into a directory (see [`unpack`]), whether
+//! or not it goes through an [`Opaque`] working copy.
+//!
+//! The archive lists the entries of the directory in depth-first
+//! order, sorted by name, each as a header line `<kind> <permissions>
+//! <length> <path>` followed by `<length>` bytes of contents and a
+//! newline. The kind is `d`, `f` or `l` for directories, files and
+//! symbolic links (whose contents are their target), and permissions
+//! are in octal. Archives of text files are text, diffed by lines, so
+//! that updating a few files of a large vendored tree only changes a
+//! few lines of its archive.
+//!
+//! Since archives come from changes, output doesn't expand archives
+//! that would write outside of their directory: entries can't be
+//! under a symbolic link of the archive, and link targets must be
+//! relative and stay in the directory. Archives can only be recorded
+//! from working copies listing their directories (see
+//! [`WorkingCopy::read_dir`]).
+use crate::pristine::InodeMetadata;
+use crate::record::Glob;
+use crate::subrepo::SubrepoState;
+use crate::text_encoding::Encoding;
+use crate::working_copy::WorkingCopy;
+use crate::HashSet;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Name of the attributes file, at the root of the working copy.
+pub const ATTRIBUTES_FILE: &str = ".pijulattributes";
+
+/// The attribute of opaque directories.
+pub const OPAQUE_ATTRIBUTE: &str = "opaque";
+
+#[derive(Debug, Error)]
+pub enum OpaqueError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    WorkingCopy(E),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<OpaqueError<std::io::Error>> for std::io::Error {
+    fn from(e: OpaqueError<std::io::Error>) -> Self {
+        match e {
+            OpaqueError::WorkingCopy(e) | OpaqueError::Io(e) => e,
+        }
+    }
+}
+
+/// The patterns of the opaque directories.
+#[derive(Debug, Clone, Default)]
+pub struct OpaqueRules(Vec<(Glob, bool)>);
+
+impl OpaqueRules {
+    /// Parse the contents of an attributes file, appending its rules
+    /// to these rules. Each line is a pattern followed by attributes,
+    /// separated by whitespace: `opaque` makes the directories matched
+    /// by the pattern opaque, and `-opaque` makes them not opaque.
+    /// Other attributes are ignored, as are blank lines and lines
+    /// starting with `#`. As in ignore files, patterns containing a
+    /// `/` other than a trailing one are relative to the root, and
+    /// other patterns match names at any depth.
+    pub fn parse(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(p) if !p.starts_with('#') => p,
+                _ => continue,
+            };
+            let opaque = words.fold(None, |opaque, attr| {
+                if attr == OPAQUE_ATTRIBUTE {
+                    Some(true)
+                } else if attr.strip_prefix('-') == Some(OPAQUE_ATTRIBUTE) {
+                    Some(false)
+                } else {
+                    opaque
+                }
+            });
+            let opaque = if let Some(opaque) = opaque {
+                opaque
+            } else {
+                continue;
+            };
+            let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+            let glob = if pattern.contains('/') {
+                Glob::new(pattern)
+            } else {
+                Glob::new(&format!("**/{}", pattern))
+            };
+            self.0.push((glob, opaque))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `path` is opaque if it is a directory. The last
+    /// matching rule wins.
+    pub fn is_opaque(&self, path: &str) -> bool {
+        let path: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.0
+            .iter()
+            .rev()
+            .find(|(glob, _)| glob.matches(&path, false))
+            .map_or(false, |(_, opaque)| *opaque)
+    }
+}
+
+/// The names of the entries of directory `dir` of `working_copy`,
+/// failing if `working_copy` can't list them, since the archive
+/// would then be recorded as empty.
+fn read_dir<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+) -> Result<Vec<String>, OpaqueError<W::Error>>
+where
+    W::Error: 'static,
+{
+    if let Some(names) = working_copy
+        .read_dir(dir)
+        .map_err(OpaqueError::WorkingCopy)?
+    {
+        Ok(names)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Can't list the entries of opaque directory {}", dir),
+        )
+        .into())
+    }
+}
+
+/// The entries of directory `dir` of `working_copy`, in the order of
+/// the archive, with their paths relative to `dir`.
+fn entries<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+) -> Result<Vec<(String, InodeMetadata)>, OpaqueError<W::Error>>
+where
+    W::Error: 'static,
+{
+    let mut entries = Vec::new();
+    let mut stack: Vec<String> = read_dir(working_copy, dir)?.into_iter().rev().collect();
+    while let Some(rel) = stack.pop() {
+        if rel.contains('\n') {
+            // Can't be written in a header.
+            continue;
+        }
+        let mut full = dir.to_string();
+        crate::path::push(&mut full, &rel);
+        let meta = working_copy
+            .file_metadata(&full)
+            .map_err(OpaqueError::WorkingCopy)?;
+        if meta.is_dir() {
+            for name in read_dir(working_copy, &full)?.into_iter().rev() {
+                stack.push(format!("{}/{}", rel, name))
+            }
+        }
+        entries.push((rel, meta))
+    }
+    Ok(entries)
+}
+
+/// Append the archive of directory `dir` of `working_copy` to
+/// `archive`.
+pub fn pack<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+    archive: &mut Vec<u8>,
+) -> Result<(), OpaqueError<W::Error>>
+where
+    W::Error: 'static,
+{
+    let mut contents = Vec::new();
+    for (rel, meta) in entries(working_copy, dir)? {
+        contents.clear();
+        let kind = if meta.is_dir() {
+            'd'
+        } else {
+            let mut full = dir.to_string();
+            crate::path::push(&mut full, &rel);
+            working_copy
+                .read_file(&full, &mut contents)
+                .map_err(OpaqueError::WorkingCopy)?;
+            if meta.is_symlink() {
+                'l'
+            } else {
+                'f'
+            }
+        };
+        writeln!(
+            archive,
+            "{} {:o} {} {}",
+            kind,
+            meta.permissions(),
+            contents.len(),
+            rel
+        )?;
+        archive.extend_from_slice(&contents);
+        archive.push(b'\n');
+    }
+    Ok(())
+}
+
+/// The latest modification time of directory `dir` of
+/// `working_copy` and its entries.
+pub fn modified_time<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+) -> Result<std::time::SystemTime, OpaqueError<W::Error>>
+where
+    W::Error: 'static,
+{
+    let modified = |path: &str| {
+        working_copy
+            .modified_time(path)
+            .map_err(OpaqueError::WorkingCopy)
+    };
+    let mut time = modified(dir)?;
+    for (rel, _) in entries(working_copy, dir)? {
+        let mut full = dir.to_string();
+        crate::path::push(&mut full, &rel);
+        time = time.max(modified(&full)?)
+    }
+    Ok(time)
+}
+
+struct Entry<'a> {
+    kind: u8,
+    permissions: u16,
+    path: &'a str,
+    contents: &'a [u8],
+}
+
+/// Parse `archive`, or return `None` if it is malformed, for instance
+/// because it has conflict markers.
+fn parse(archive: &[u8]) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut rest = archive;
+    while !rest.is_empty() {
+        let nl = rest.iter().position(|&c| c == b'\n')?;
+        let header = std::str::from_utf8(&rest[..nl]).ok()?;
+        let mut fields = header.splitn(4, ' ');
+        let kind = match fields.next()? {
+            "d" => b'd',
+            "f" => b'f',
+            "l" => b'l',
+            _ => return None,
+        };
+        let permissions = u16::from_str_radix(fields.next()?, 8).ok()?;
+        let len: usize = fields.next()?.parse().ok()?;
+        let path = fields.next()?;
+        if path
+            .split('/')
+            .any(|c| c.is_empty() || c == "." || c == "..")
+        {
+            return None;
+        }
+        rest = &rest[nl + 1..];
+        if rest.len() <= len || rest[len] != b'\n' {
+            return None;
+        }
+        entries.push(Entry {
+            kind,
+            permissions,
+            path,
+            contents: &rest[..len],
+        });
+        rest = &rest[len + 1..];
+    }
+    Some(entries)
+}
+
+/// Whether the entries of an archive can be written without leaving
+/// the archived directory: paths must be unique and not go through
+/// the symbolic links of the archive, and link targets must be
+/// relative paths staying in the archived directory, with `..`
+/// components only at the start.
+fn is_contained(entries: &[Entry]) -> bool {
+    let mut paths = HashSet::default();
+    let mut links = HashSet::default();
+    for entry in entries {
+        if !paths.insert(entry.path) {
+            return false;
+        }
+        if entry.kind == b'l' {
+            links.insert(entry.path);
+        }
+    }
+    entries.iter().all(|entry| {
+        let mut p = entry.path;
+        while let Some(parent) = crate::path::parent(p) {
+            if parent.is_empty() {
+                break;
+            }
+            if links.contains(parent) {
+                return false;
+            }
+            p = parent
+        }
+        entry.kind != b'l'
+            || std::str::from_utf8(entry.contents).map_or(false, |target| {
+                crate::path::is_contained_link(entry.path, target)
+            })
+    })
+}
+
+/// Replace `dir` in `working_copy` with the directory archived in
+/// `archive`. Returns `false`, without touching `working_copy`, if
+/// `archive` is malformed or would write outside of `dir`.
+///
+/// The archive is expanded next to `dir` first, and then renamed to
+/// `dir`, so that `dir` is left as it was if that fails. Symbolic
+/// links are created after all the other entries.
+pub fn unpack<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+    archive: &[u8],
+) -> Result<bool, OpaqueError<W::Error>> {
+    let entries = if let Some(entries) = parse(archive) {
+        entries
+    } else {
+        return Ok(false);
+    };
+    if !is_contained(&entries) {
+        debug!("unpack {:?}: archive escapes the directory", dir);
+        return Ok(false);
+    }
+    let tmp = format!("{}.{}", dir, UNPACK_SUFFIX);
+    let old = format!("{}.{}", dir, OLD_SUFFIX);
+    working_copy
+        .remove_path(&tmp, true)
+        .map_err(OpaqueError::WorkingCopy)?;
+    if let Err(e) = unpack_to(working_copy, &tmp, &entries) {
+        working_copy.remove_path(&tmp, true).unwrap_or(());
+        return Err(e);
+    }
+    let existed = working_copy.file_metadata(dir).is_ok();
+    if existed {
+        working_copy
+            .remove_path(&old, true)
+            .map_err(OpaqueError::WorkingCopy)?;
+        working_copy
+            .rename(dir, &old)
+            .map_err(OpaqueError::WorkingCopy)?;
+    }
+    if let Err(e) = working_copy.rename(&tmp, dir) {
+        if existed {
+            working_copy.rename(&old, dir).unwrap_or(());
+        }
+        return Err(OpaqueError::WorkingCopy(e));
+    }
+    if existed {
+        working_copy
+            .remove_path(&old, true)
+            .map_err(OpaqueError::WorkingCopy)?;
+    }
+    Ok(true)
+}
+
+/// Suffixes of the temporary names used by [`unpack`].
+const UNPACK_SUFFIX: &str = "pijul-unpack";
+const OLD_SUFFIX: &str = "pijul-old";
+
+/// Write `entries` to a new directory `dir`.
+fn unpack_to<W: WorkingCopy>(
+    working_copy: &W,
+    dir: &str,
+    entries: &[Entry],
+) -> Result<(), OpaqueError<W::Error>> {
+    working_copy
+        .create_dir_all(dir)
+        .map_err(OpaqueError::WorkingCopy)?;
+    let (links, others): (Vec<_>, Vec<_>) = entries.iter().partition(|e| e.kind == b'l');
+    for entry in others.into_iter().chain(links) {
+        let mut path = dir.to_string();
+        crate::path::push(&mut path, entry.path);
+        if entry.kind == b'd' {
+            working_copy
+                .create_dir_all(&path)
+                .map_err(OpaqueError::WorkingCopy)?;
+        } else if entry.kind == b'l'
+            && working_copy
+                .create_symlink(&path, &String::from_utf8_lossy(entry.contents))
+                .map_err(OpaqueError::WorkingCopy)?
+        {
+            continue;
+        } else {
+            let mut w = working_copy
+                .write_file(&path)
+                .map_err(OpaqueError::WorkingCopy)?;
+            w.write_all(entry.contents)?;
+        }
+        working_copy
+            .set_permissions(&path, entry.permissions)
+            .map_err(OpaqueError::WorkingCopy)?;
+    }
+    Ok(())
+}
+
+/// A working copy in which the opaque directories of another working
+/// copy are files, and their entries are hidden.
+#[derive(Debug, Clone)]
+pub struct Opaque<W> {
+    working_copy: W,
+    rules: Arc<OpaqueRules>,
+}
+
+impl<W: WorkingCopy> Opaque<W> {
+    /// Wrap `working_copy`, with the rules of its attributes file if
+    /// it has one.
+    pub fn new(working_copy: W) -> Result<Self, W::Error> {
+        let mut rules = OpaqueRules::default();
+        if working_copy.file_metadata(ATTRIBUTES_FILE).is_ok() {
+            let mut buf = Vec::new();
+            working_copy.read_file(ATTRIBUTES_FILE, &mut buf)?;
+            rules.parse(&String::from_utf8_lossy(&buf));
+        }
+        Ok(Self::with_rules(working_copy, rules))
+    }
+
+    pub fn with_rules(working_copy: W, rules: OpaqueRules) -> Self {
+        Opaque {
+            working_copy,
+            rules: Arc::new(rules),
+        }
+    }
+
+    pub fn inner(&self) -> &W {
+        &self.working_copy
+    }
+
+    /// Whether `path` is an opaque directory.
+    fn is_opaque(&self, path: &str) -> bool {
+        !self.rules.is_empty()
+            && self.rules.is_opaque(path)
+            && self
+                .working_copy
+                .file_metadata(path)
+                .map_or(false, |m| m.is_dir())
+    }
+
+    /// Whether `path` is in an opaque directory.
+    fn is_hidden(&self, path: &str) -> bool {
+        let mut p = path;
+        while let Some(parent) = crate::path::parent(p) {
+            if parent.is_empty() {
+                break;
+            }
+            if self.is_opaque(parent) {
+                return true;
+            }
+            p = parent
+        }
+        false
+    }
+
+    fn check(&self, path: &str) -> Result<(), OpaqueError<W::Error>> {
+        if self.is_hidden(path) {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is in an opaque directory", path),
+            )
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<W: WorkingCopy> WorkingCopy for Opaque<W>
+where
+    W::Error: 'static,
+{
+    type Error = OpaqueError<W::Error>;
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        self.working_copy
+            .create_dir_all(path)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        self.check(file)?;
+        if self.is_opaque(file) {
+            return Ok(InodeMetadata::new_opaque());
+        }
+        self.working_copy
+            .file_metadata(file)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        self.check(file)?;
+        if self.is_opaque(file) {
+            pack(&self.working_copy, file, buffer)
+        } else {
+            self.working_copy
+                .read_file(file, buffer)
+                .map_err(OpaqueError::WorkingCopy)
+        }
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        self.check(file)?;
+        if self.is_opaque(file) {
+            modified_time(&self.working_copy, file)
+        } else {
+            self.working_copy
+                .modified_time(file)
+                .map_err(OpaqueError::WorkingCopy)
+        }
+    }
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        self.check(file)?;
+        if self.is_opaque(file) {
+            Ok(None)
+        } else {
+            self.working_copy
+                .file_size(file)
+                .map_err(OpaqueError::WorkingCopy)
+        }
+    }
+    fn read_file_windows(
+        &self,
+        file: &str,
+        window: usize,
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), Self::Error> {
+        self.check(file)?;
+        if self.is_opaque(file) {
+            let mut buf = Vec::new();
+            self.read_file(file, &mut buf)?;
+            for w in buf.chunks(window) {
+                if !f(w) {
+                    break;
+                }
+            }
+            Ok(())
+        } else {
+            self.working_copy
+                .read_file_windows(file, window, f)
+                .map_err(OpaqueError::WorkingCopy)
+        }
+    }
+    fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error> {
+        self.working_copy
+            .remove_path(name, rec)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        self.working_copy
+            .rename(former, new)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn permission_mask(&self) -> u16 {
+        self.working_copy.permission_mask()
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.working_copy.conflict_options()
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        self.working_copy
+            .set_permissions(name, permissions)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn output_hook(&self, name: &str, meta: InodeMetadata) -> Result<(), Self::Error> {
+        self.working_copy
+            .output_hook(name, meta)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn is_writable(&self, path: &str) -> Result<bool, Self::Error> {
+        self.working_copy
+            .is_writable(path)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        self.working_copy
+            .available_space()
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        self.working_copy
+            .read_dir(path)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn subrepo_state(&self, path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        self.working_copy
+            .subrepo_state(path)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn sync(&self) -> Result<(), Self::Error> {
+        self.working_copy.sync().map_err(OpaqueError::WorkingCopy)
+    }
+    fn create_symlink(&self, name: &str, target: &str) -> Result<bool, Self::Error> {
+        self.working_copy
+            .create_symlink(name, target)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        self.working_copy.detect_encoding(file, contents, last)
+    }
+
+    type Writer = W::Writer;
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        self.working_copy
+            .write_file(file)
+            .map_err(OpaqueError::WorkingCopy)
+    }
+}
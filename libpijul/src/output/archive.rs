@@ -3,14 +3,7 @@ use crate::changestore::ChangeStore;
 use crate::Conflict;
 use crate::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
-
-pub trait Archive {
-    type File: std::io::Write;
-    type Error: std::error::Error;
-    fn create_file(&mut self, path: &str, mtime: u64, perm: u16) -> Self::File;
-    fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error>;
-    fn close_file(&mut self, f: Self::File) -> Result<(), Self::Error>;
-}
+use std::io::Write;
 
 #[cfg(feature = "tarball")]
 pub struct Tarball<W: std::io::Write> {
@@ -18,6 +11,8 @@ pub struct Tarball<W: std::io::Write> {
     pub prefix: Option<String>,
     pub buffer: Vec<u8>,
     pub umask: u16,
+    /// Limits on the graphs of the files archived, none by default.
+    pub graph_limits: crate::alive::retrieve::RetrieveLimits,
 }
 
 #[cfg(feature = "tarball")]
@@ -47,38 +42,42 @@ impl<W: std::io::Write> Tarball<W> {
             buffer: Vec::new(),
             prefix,
             umask,
+            graph_limits: crate::alive::retrieve::RetrieveLimits::UNLIMITED,
+        }
+    }
+}
+
+#[cfg(feature = "tarball")]
+impl<W: std::io::Write> Tarball<W> {
+    fn path(&self, path: &str) -> String {
+        if let Some(ref prefix) = self.prefix {
+            prefix.clone() + path
+        } else {
+            path.to_string()
         }
     }
 }
 
 #[cfg(feature = "tarball")]
-impl<W: std::io::Write> Archive for Tarball<W> {
+impl<W: std::io::Write> OutputSink for Tarball<W> {
     type File = File;
     type Error = std::io::Error;
-    fn create_file(&mut self, path: &str, mtime: u64, permissions: u16) -> Self::File {
+    fn create_file(&mut self, path: &str, meta: SinkMetadata) -> Result<Self::File, Self::Error> {
         self.buffer.clear();
-        File {
+        Ok(File {
             buf: std::mem::replace(&mut self.buffer, Vec::new()),
-            path: if let Some(ref prefix) = self.prefix {
-                prefix.clone() + path
-            } else {
-                path.to_string()
-            },
-            mtime,
-            permissions: permissions & !self.umask,
-        }
+            path: self.path(path),
+            mtime: meta.mtime,
+            permissions: meta.permissions & !self.umask,
+        })
     }
-    fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error> {
+    fn create_dir(&mut self, path: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
         let mut header = tar::Header::new_gnu();
-        header.set_mode((permissions & !self.umask) as u32);
-        header.set_mtime(mtime);
+        header.set_mode((meta.permissions & !self.umask) as u32);
+        header.set_mtime(meta.mtime);
         header.set_entry_type(tar::EntryType::Directory);
-        if let Some(ref prefix) = self.prefix {
-            let path = prefix.clone() + path;
-            self.archive.append_data(&mut header, &path, &[][..])?;
-        } else {
-            self.archive.append_data(&mut header, &path, &[][..])?;
-        }
+        let path = self.path(path);
+        self.archive.append_data(&mut header, &path, &[][..])?;
         Ok(())
     }
 
@@ -93,6 +92,21 @@ impl<W: std::io::Write> Archive for Tarball<W> {
         self.buffer = file.buf;
         Ok(())
     }
+
+    fn symlink(&mut self, path: &str, target: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode((meta.permissions & !self.umask) as u32);
+        header.set_mtime(meta.mtime);
+        header.set_link_name(target)?;
+        let path = self.path(path);
+        self.archive.append_data(&mut header, &path, &[][..])?;
+        Ok(())
+    }
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        self.graph_limits
+    }
 }
 
 #[derive(Debug, Error)]
@@ -135,7 +149,7 @@ pub(crate) fn archive<
     T: ChannelTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphError>,
     P: ChangeStore,
     I: Iterator<Item = &'a str>,
-    A: Archive,
+    A: OutputSink,
 >(
     changes: &P,
     txn: &T,
@@ -232,34 +246,80 @@ pub(crate) fn archive<
                         &mut next_files,
                     )?;
                     if len == next_files.len() {
-                        arch.create_dir(&path, latest_touch, 0o777)
-                            .map_err(ArchiveError::A)?;
+                        arch.create_dir(
+                            &path,
+                            SinkMetadata {
+                                mtime: latest_touch,
+                                permissions: 0o777,
+                            },
+                        )
+                        .map_err(ArchiveError::A)?;
                     }
                 } else {
                     debug!("latest_touch: {:?}", latest_touch);
-                    let mut l = crate::alive::retrieve(txn, txn.graph(&channel), output_item.pos)?;
+                    let mut l = retrieve_limited(
+                        txn,
+                        txn.graph(&channel),
+                        output_item.pos,
+                        arch.graph_limits(),
+                    )
+                    .map_err(FileError::from)?;
                     let perms = if output_item.meta.permissions() & 0o100 != 0 {
                         0o777
                     } else {
                         0o666
                     };
-                    let mut f = arch.create_file(&path, latest_touch, perms);
-                    {
-                        let mut f = crate::vertex_buffer::ConflictsWriter::new(
-                            &mut f,
-                            &output_item.path,
-                            &mut conflicts,
-                        );
-                        crate::alive::output_graph(
-                            changes,
-                            txn,
-                            &channel,
-                            &mut f,
-                            &mut l,
-                            &mut Vec::new(),
-                        )?;
+                    let meta = SinkMetadata {
+                        mtime: latest_touch,
+                        permissions: perms,
+                    };
+                    if output_item.meta.is_symlink() {
+                        // Links with conflicts are written as files,
+                        // like in checkouts.
+                        let n = conflicts.len();
+                        let mut target = Vec::new();
+                        {
+                            let mut f = crate::vertex_buffer::ConflictsWriter::new(
+                                &mut target,
+                                &path,
+                                &mut conflicts,
+                            );
+                            crate::alive::output_graph(
+                                changes,
+                                txn,
+                                &channel,
+                                &mut f,
+                                &mut l,
+                                &mut Vec::new(),
+                            )?;
+                        }
+                        if conflicts.len() == n {
+                            arch.symlink(&path, &String::from_utf8_lossy(&target), meta)
+                                .map_err(ArchiveError::A)?;
+                        } else {
+                            let mut f = arch.create_file(&path, meta).map_err(ArchiveError::A)?;
+                            f.write_all(&target).map_err(PristineOutputError::Io)?;
+                            arch.close_file(f).map_err(ArchiveError::A)?;
+                        }
+                    } else {
+                        let mut f = arch.create_file(&path, meta).map_err(ArchiveError::A)?;
+                        {
+                            let mut f = crate::vertex_buffer::ConflictsWriter::new(
+                                &mut f,
+                                &path,
+                                &mut conflicts,
+                            );
+                            crate::alive::output_graph(
+                                changes,
+                                txn,
+                                &channel,
+                                &mut f,
+                                &mut l,
+                                &mut Vec::new(),
+                            )?;
+                        }
+                        arch.close_file(f).map_err(ArchiveError::A)?;
                     }
-                    arch.close_file(f).map_err(ArchiveError::A)?;
                 }
                 if output_item.is_zombie {
                     conflicts.push(Conflict::ZombieFile {
@@ -0,0 +1,299 @@
+//! Manifests of the files written by output.
+//!
+//! Outputting to a [`ManifestWorkingCopy`] instead of the working copy
+//! itself records the path, size and hash of each file written, in a
+//! [`Manifest`]. That manifest can be stored along with the checkout,
+//! and [`Manifest::verify`] later checks the working copy against it
+//! without the pristine or the change store, for example to prove
+//! that a deployed checkout matches the state of the channel it was
+//! output from.
+//!
+//! Only the files written by output are recorded: files outside of
+//! the manifest aren't checked by [`Manifest::verify`].
+use crate::pristine::{Hash, Hasher, InodeMetadata, Merkle};
+use crate::subrepo::SubrepoState;
+use crate::text_encoding::Encoding;
+use crate::working_copy::WorkingCopy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A file recorded in a [`Manifest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Hash of the contents of the file.
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub hash: Hash,
+    /// Size of the file, in bytes.
+    pub size: u64,
+}
+
+/// The files written by an output, by path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The state of the channel that was output, if set by the
+    /// caller.
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub state: Merkle,
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            state: Merkle::zero(),
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+/// A difference between a working copy and a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ManifestMismatch {
+    /// The file is in the manifest, but can't be read from the
+    /// working copy.
+    Missing { path: String },
+    /// The file doesn't have the size or hash recorded in the
+    /// manifest.
+    Modified {
+        path: String,
+        expected: ManifestEntry,
+        #[serde(with = "crate::pristine::serde_base32")]
+        hash: Hash,
+        size: u64,
+    },
+}
+
+impl Manifest {
+    /// Hash `contents` the way files are hashed in manifests.
+    pub fn hash(contents: &[u8]) -> ManifestEntry {
+        let mut hasher = Hasher::default();
+        hasher.update(contents);
+        ManifestEntry {
+            hash: hasher.finish(),
+            size: contents.len() as u64,
+        }
+    }
+
+    /// Check all the files of this manifest in `working_copy`, and
+    /// return the files that don't match, ordered by path. This reads
+    /// each file of the manifest exactly once, and nothing else.
+    pub fn verify<W: WorkingCopy>(
+        &self,
+        working_copy: &W,
+    ) -> Result<Vec<ManifestMismatch>, W::Error> {
+        let mut mismatches = Vec::new();
+        let mut buf = Vec::new();
+        for (path, expected) in self.files.iter() {
+            buf.clear();
+            if working_copy.read_file(path, &mut buf).is_err() {
+                mismatches.push(ManifestMismatch::Missing { path: path.clone() });
+                continue;
+            }
+            let actual = Self::hash(&buf);
+            if actual != *expected {
+                mismatches.push(ManifestMismatch::Modified {
+                    path: path.clone(),
+                    expected: *expected,
+                    hash: actual.hash,
+                    size: actual.size,
+                })
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Write this manifest to `w`, as JSON.
+    pub fn to_json<W: std::io::Write>(&self, w: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(w, self)
+    }
+
+    /// Read a manifest written by [`Manifest::to_json`].
+    pub fn from_json<R: std::io::Read>(r: R) -> Result<Self, serde_json::Error> {
+        serde_json::from_reader(r)
+    }
+
+    fn remove(&mut self, path: &str, rec: bool) {
+        self.files.remove(path);
+        if rec {
+            let under: Vec<_> = self.under(path).collect();
+            for p in under {
+                self.files.remove(&p);
+            }
+        }
+    }
+
+    fn rename(&mut self, former: &str, new: &str) {
+        if let Some(e) = self.files.remove(former) {
+            self.files.insert(new.to_string(), e);
+        }
+        let under: Vec<_> = self.under(former).collect();
+        for p in under {
+            let e = self.files.remove(&p).unwrap();
+            self.files
+                .insert(format!("{}{}", new, &p[former.len()..]), e);
+        }
+    }
+
+    /// The files strictly under directory `path`.
+    fn under<'a>(&'a self, path: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.files
+            .range::<str, _>((std::ops::Bound::Excluded(path), std::ops::Bound::Unbounded))
+            .take_while(move |(p, _)| p.starts_with(path))
+            .filter(move |(p, _)| path.is_empty() || p[path.len()..].starts_with('/'))
+            .map(|(p, _)| p.clone())
+    }
+}
+
+/// A working copy recording a [`Manifest`] of the files written to
+/// it. Files are recorded when the writer returned by
+/// [`WorkingCopy::write_file`] is dropped, and removed from the
+/// manifest when they are removed from the working copy.
+#[derive(Clone)]
+pub struct ManifestWorkingCopy<W> {
+    pub working_copy: W,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+impl<W> ManifestWorkingCopy<W> {
+    /// Record the files written to `working_copy` in a new, empty
+    /// manifest.
+    pub fn new(working_copy: W) -> Self {
+        Self::with_manifest(working_copy, Manifest::default())
+    }
+
+    /// Update `manifest` with the files written to `working_copy`,
+    /// for instance after a partial output.
+    pub fn with_manifest(working_copy: W, manifest: Manifest) -> Self {
+        ManifestWorkingCopy {
+            working_copy,
+            manifest: Arc::new(Mutex::new(manifest)),
+        }
+    }
+
+    /// The manifest recorded so far.
+    pub fn manifest(&self) -> Manifest {
+        self.manifest.lock().clone()
+    }
+}
+
+/// A file of a [`ManifestWorkingCopy`] being written.
+pub struct ManifestWriter<F> {
+    w: F,
+    path: String,
+    hasher: Hasher,
+    size: u64,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+impl<F: std::io::Write> std::io::Write for ManifestWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let n = self.w.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.w.flush()
+    }
+}
+
+impl<F> Drop for ManifestWriter<F> {
+    fn drop(&mut self) {
+        let entry = ManifestEntry {
+            hash: self.hasher.finish(),
+            size: self.size,
+        };
+        debug!("manifest: {:?} {:?}", self.path, entry);
+        self.manifest
+            .lock()
+            .files
+            .insert(std::mem::replace(&mut self.path, String::new()), entry);
+    }
+}
+
+impl<W: WorkingCopy> WorkingCopy for ManifestWorkingCopy<W> {
+    type Error = W::Error;
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        self.working_copy.create_dir_all(path)
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        self.working_copy.file_metadata(file)
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        self.working_copy.read_file(file, buffer)
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        self.working_copy.modified_time(file)
+    }
+    fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error> {
+        self.working_copy.remove_path(name, rec)?;
+        self.manifest.lock().remove(name, rec);
+        Ok(())
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        self.working_copy.rename(former, new)?;
+        self.manifest.lock().rename(former, new);
+        Ok(())
+    }
+    fn permission_mask(&self) -> u16 {
+        self.working_copy.permission_mask()
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.working_copy.conflict_options()
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        self.working_copy.set_permissions(name, permissions)
+    }
+    fn output_hook(&self, name: &str, meta: InodeMetadata) -> Result<(), Self::Error> {
+        self.working_copy.output_hook(name, meta)
+    }
+    fn create_symlink(&self, name: &str, target: &str) -> Result<bool, Self::Error> {
+        self.working_copy.create_symlink(name, target)
+    }
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        self.working_copy.read_dir(path)
+    }
+    fn subrepo_state(&self, path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        self.working_copy.subrepo_state(path)
+    }
+    fn sync(&self) -> Result<(), Self::Error> {
+        self.working_copy.sync()
+    }
+    fn is_writable(&self, path: &str) -> Result<bool, Self::Error> {
+        self.working_copy.is_writable(path)
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        self.working_copy.available_space()
+    }
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        self.working_copy.detect_encoding(file, contents, last)
+    }
+
+    type Writer = ManifestWriter<W::Writer>;
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        Ok(ManifestWriter {
+            w: self.working_copy.write_file(file)?,
+            path: file.to_string(),
+            hasher: Hasher::default(),
+            size: 0,
+            manifest: self.manifest.clone(),
+        })
+    }
+    fn decode_file(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        self.working_copy.decode_file(file, buffer)
+    }
+    fn decode_file_with_stats(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+        stats: Option<&crate::file_stats::FileStats>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        self.working_copy
+            .decode_file_with_stats(file, buffer, stats)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::alive::retrieve::{retrieve_limited, GraphTooLarge, RetrieveError, RetrieveLimits};
 use crate::changestore::{ChangeStore, FileMetadata};
 use crate::path;
 use crate::pristine::*;
@@ -7,6 +8,16 @@ mod output;
 pub use output::*;
 mod archive;
 pub use archive::*;
+mod sink;
+pub use sink::*;
+mod preflight;
+pub use preflight::*;
+mod update;
+pub use update::*;
+mod manifest;
+pub use manifest::*;
+mod sidecar;
+pub use sidecar::*;
 
 #[derive(Debug, Error)]
 pub enum OutputError<
@@ -31,6 +42,8 @@ pub enum PristineOutputError<ChangestoreError: std::error::Error, Txn: std::erro
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Fs(#[from] crate::fs::FsError<Txn>),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
 }
 
 impl<C: std::error::Error, T: std::error::Error + 'static> From<TxnErr<T>>
@@ -49,6 +62,17 @@ impl<C: std::error::Error, T: std::error::Error + 'static, W: std::error::Error
     }
 }
 
+impl<C: std::error::Error, T: std::error::Error + 'static, W: std::error::Error + Send>
+    From<WorkingCopySinkError<W>> for OutputError<C, T, W>
+{
+    fn from(e: WorkingCopySinkError<W>) -> Self {
+        match e {
+            WorkingCopySinkError::WorkingCopy(e) => OutputError::WorkingCopy(e),
+            WorkingCopySinkError::Io(e) => OutputError::Pristine(PristineOutputError::Io(e)),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum FileError<ChangestoreError: std::error::Error + 'static, T: std::error::Error + 'static> {
     #[error(transparent)]
@@ -57,6 +81,8 @@ pub enum FileError<ChangestoreError: std::error::Error + 'static, T: std::error:
     Txn(T),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
 }
 
 impl<C: std::error::Error, T: std::error::Error + 'static> From<FileError<C, T>>
@@ -67,6 +93,7 @@ impl<C: std::error::Error, T: std::error::Error + 'static> From<FileError<C, T>>
             FileError::Changestore(e) => PristineOutputError::Changestore(e),
             FileError::Io(e) => PristineOutputError::Io(e),
             FileError::Txn(t) => PristineOutputError::Txn(t),
+            FileError::GraphTooLarge(e) => PristineOutputError::GraphTooLarge(e),
         }
     }
 }
@@ -77,6 +104,52 @@ impl<C: std::error::Error, T: std::error::Error + 'static> From<TxnErr<T>> for F
     }
 }
 
+impl<C: std::error::Error, T: std::error::Error + 'static> From<RetrieveError<T>>
+    for FileError<C, T>
+{
+    fn from(e: RetrieveError<T>) -> Self {
+        match e {
+            RetrieveError::Txn(t) => FileError::Txn(t),
+            RetrieveError::GraphTooLarge(e) => FileError::GraphTooLarge(e),
+        }
+    }
+}
+
+/// The order of the sides of conflicts, in outputs and in the diffs
+/// against them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictOrder {
+    /// By the timestamp of the oldest change of each side, then by
+    /// the hash of that change. This only depends on the changes, so
+    /// a conflict is rendered identically on every machine.
+    Timestamp,
+    /// By the position in the channel of the first change of each
+    /// side applied to the channel. This depends on the order in which
+    /// changes were applied locally.
+    Application,
+}
+
+impl Default for ConflictOrder {
+    fn default() -> Self {
+        ConflictOrder::Application
+    }
+}
+
+/// How conflicts inside files are rendered, returned by
+/// [`WorkingCopy::conflict_options`](crate::working_copy::WorkingCopy::conflict_options).
+/// Record compares the working copy with an output of the pristine
+/// rendered with the same options, so these must not change between
+/// an output and the next record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictOptions {
+    /// The order of the sides of conflicts.
+    pub order: ConflictOrder,
+    /// Embed the [identifiers](ConflictId) of conflicts in their
+    /// start markers.
+    pub ids: bool,
+}
+
 #[derive(Debug, Clone)]
 struct OutputItem {
     parent: Inode,
@@ -193,6 +266,8 @@ fn is_zombie<T: GraphTxnT>(
     }
 }
 
+/// Output the file at `v0` to `out`, failing with
+/// [`FileError::GraphTooLarge`] if its graph exceeds `limits`.
 pub fn output_file<
     T: TreeTxnT + ChannelTxnT,
     C: crate::changestore::ChangeStore,
@@ -203,9 +278,10 @@ pub fn output_file<
     channel: &T::Channel,
     v0: Position<ChangeId>,
     out: &mut V,
+    limits: RetrieveLimits,
 ) -> Result<(), FileError<C::Error, T::GraphError>> {
     let mut forward = Vec::new();
-    let mut graph = crate::alive::retrieve(&*txn, txn.graph(&*channel), v0)?;
+    let mut graph = retrieve_limited(&*txn, txn.graph(&*channel), v0, limits)?;
     crate::alive::output_graph(changes, &*txn, &*channel, out, &mut graph, &mut forward)?;
     Ok(())
 }
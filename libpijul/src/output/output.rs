@@ -1,11 +1,16 @@
 //! Output the pristine to the working copy, synchronising file
 //! changes (file additions, deletions and renames) in the process.
-use super::{collect_children, OutputError, OutputItem, PristineOutputError};
-use crate::alive::retrieve;
+use super::{
+    collect_children, FileError, OutputError, OutputItem, OutputSink, PristineOutputError,
+    SinkMetadata, WorkingCopySink,
+};
+use crate::alive::retrieve::retrieve_limited;
 use crate::changestore::ChangeStore;
-use crate::fs::{create_new_inode, inode_filename};
+use crate::fs::{create_inode_for, inode_filename};
+use crate::opaque::OpaqueError;
 use crate::pristine::*;
 use crate::small_string::SmallString;
+use crate::subrepo::SubrepoError;
 use crate::working_copy::WorkingCopy;
 use crate::{alive, path, vertex_buffer};
 use crate::{HashMap, HashSet};
@@ -14,7 +19,7 @@ use std::collections::hash_map::Entry;
 use std::sync::Arc;
 
 /// A structure representing a file with conflicts.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Conflict {
     Name {
         path: String,
@@ -40,6 +45,78 @@ pub enum Conflict {
     },
 }
 
+/// The identifier of a conflict inside a file, derived from the
+/// hashes of the changes and the positions of the vertices starting
+/// the sides of the conflict. It is the same on all machines and in
+/// all channels where the conflict appears, whatever the order in
+/// which the changes were applied, and survives the edits that don't
+/// touch the first line of a side.
+///
+/// Identifiers are written in hexadecimal, and embedded in the start
+/// markers of conflicts when enabled by
+/// [`ConflictOptions::ids`](crate::output::ConflictOptions::ids), from
+/// which [`marker_id`](crate::vertex_buffer::marker_id) reads them
+/// back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ConflictId(pub u64);
+
+impl ConflictId {
+    pub(crate) const ORDER: u8 = 0;
+    pub(crate) const ZOMBIE: u8 = 1;
+    pub(crate) const CYCLIC: u8 = 2;
+
+    /// The identifier of a conflict of kind `kind`, involving the
+    /// vertices starting at `vertices`, in any order.
+    pub(crate) fn new(kind: u8, vertices: &mut [(Hash, ChangePosition)]) -> Self {
+        vertices.sort();
+        let mut hasher = Hasher::default();
+        hasher.update(&[kind]);
+        for (h, pos) in vertices.iter() {
+            match h {
+                Hash::None => hasher.update(&[0]),
+                h => hasher.update(&h.to_bytes()),
+            }
+            hasher.update(&pos.0.as_u64().to_le_bytes())
+        }
+        match hasher.finish() {
+            Hash::Blake3(h) => {
+                let mut id = [0; 8];
+                id.clone_from_slice(&h[..8]);
+                ConflictId(u64::from_le_bytes(id))
+            }
+            Hash::None => unreachable!(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConflictId {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{:016x}", self.0)
+    }
+}
+
+impl std::str::FromStr for ConflictId {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ConflictId(u64::from_str_radix(s, 16)?))
+    }
+}
+
+impl Conflict {
+    /// The key by which conflicts are listed: by path, then by line,
+    /// conflicts on files (which don't have a line) first.
+    fn sort_key(&self) -> (&str, usize, u8) {
+        match *self {
+            Conflict::Name { ref path } => (path.as_str(), 0, 0),
+            Conflict::ZombieFile { ref path } => (path.as_str(), 0, 1),
+            Conflict::MultipleNames { ref path, .. } => (path.as_str(), 0, 2),
+            Conflict::Zombie { ref path, line } => (path.as_str(), line, 3),
+            Conflict::Cyclic { ref path, line } => (path.as_str(), line, 4),
+            Conflict::Order { ref path, line } => (path.as_str(), line, 5),
+        }
+    }
+}
+
 /// Output updates the working copy after applying changes, including
 /// the graph-file correspondence.
 ///
@@ -88,6 +165,7 @@ fn output_loop<
     channel: ChannelRef<T>,
     work: Arc<crossbeam_deque::Injector<(OutputItem, String, Option<String>)>>,
     stop: Arc<std::sync::atomic::AtomicBool>,
+    hooks: Arc<parking_lot::Mutex<Vec<(String, InodeMetadata)>>>,
     t: usize,
 ) -> Result<Vec<Conflict>, OutputError<P::Error, T::GraphError, R::Error>> {
     use crossbeam_deque::*;
@@ -98,7 +176,7 @@ fn output_loop<
         match work.steal() {
             Steal::Success((item, path, tmp)) => {
                 info!("Outputting {:?} (tmp {:?}), on thread {}", path, tmp, t);
-                let path = tmp.as_deref().unwrap_or(&path);
+                let tmp_ = tmp.as_deref().unwrap_or(&path);
                 output_item::<_, _, R>(
                     txn.clone(),
                     channel.clone(),
@@ -106,11 +184,15 @@ fn output_loop<
                     &item,
                     &mut conflicts,
                     &repo,
-                    path,
+                    tmp_,
                 )?;
-                debug!("setting permissions for {:?}", path);
-                repo.set_permissions(path, item.meta.permissions())
-                    .map_err(OutputError::WorkingCopy)?;
+                if tmp.is_some() {
+                    // The hook is called once the file is in place.
+                    hooks.lock().push((path.clone(), item.meta))
+                } else {
+                    repo.output_hook(&path, item.meta)
+                        .map_err(OutputError::WorkingCopy)?;
+                }
                 debug!("output {:?}", path);
             }
             Steal::Retry => {}
@@ -152,16 +234,20 @@ where
 {
     let work = Arc::new(crossbeam_deque::Injector::new());
     let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    // Output hooks of the paths output under a temporary name, called
+    // after renaming them.
+    let hooks = Arc::new(parking_lot::Mutex::new(Vec::new()));
     let mut threads = Vec::new();
     for t in 0..n_workers - 1 {
         let repo = repo.clone();
         let work = work.clone();
         let stop = stop.clone();
+        let hooks = hooks.clone();
         let txn = txn.clone();
         let channel = channel.clone();
         let changes = changes.clone();
         threads.push(std::thread::spawn(move || {
-            output_loop(&repo, &changes, txn, channel, work, stop, t + 1)
+            output_loop(&repo, &changes, txn, channel, work, stop, hooks, t + 1)
         }))
     }
 
@@ -175,7 +261,7 @@ where
             let txn_ = txn.read();
             let channel = channel.read();
             let graph = txn_.graph(&*channel);
-            collect_dead_files(&*txn_, graph, pending_change_id, Inode::ROOT)?
+            collect_dead_files(&*txn_, graph, pending_change_id, Inode::ROOT, false)?
         };
         debug!("dead (line {}) = {:?}", line!(), dead);
         if !dead.is_empty() {
@@ -297,7 +383,13 @@ where
                     let dead = {
                         let txn_ = txn.read();
                         let channel = channel.read();
-                        collect_dead_files(&*txn_, txn_.graph(&*channel), pending_change_id, inode)?
+                        collect_dead_files(
+                            &*txn_,
+                            txn_.graph(&*channel),
+                            pending_change_id,
+                            inode,
+                            false,
+                        )?
                     };
                     debug!("dead (line {}) = {:?}", line!(), dead);
                     if !dead.is_empty() {
@@ -308,8 +400,11 @@ where
                 }
                 if output_item.meta.is_dir() {
                     let tmp_ = tmp.as_deref().unwrap_or(&path);
-                    repo.create_dir_all(tmp_)
-                        .map_err(OutputError::WorkingCopy)?;
+                    WorkingCopySink {
+                        working_copy: repo,
+                        hooks: false,
+                    }
+                    .create_dir(tmp_, sink_metadata(output_item.meta))?;
                     {
                         let txn = txn.read();
                         let channel = channel.read();
@@ -325,9 +420,12 @@ where
                             &mut next_files,
                         )?;
                     }
-                    debug!("setting permissions for {:?}", path);
-                    repo.set_permissions(tmp_, output_item.meta.permissions())
-                        .map_err(OutputError::WorkingCopy)?;
+                    if tmp.is_some() {
+                        hooks.lock().push((path.clone(), output_item.meta))
+                    } else {
+                        repo.output_hook(&path, output_item.meta)
+                            .map_err(OutputError::WorkingCopy)?;
+                    }
                 } else {
                     if needs_output(repo, if_modified_after, &path) {
                         work.push((output_item.clone(), path.clone(), tmp.clone()));
@@ -345,7 +443,7 @@ where
         std::mem::swap(&mut files, &mut next_files);
     }
     stop.store(true, std::sync::atomic::Ordering::Relaxed);
-    let o = output_loop(repo, changes, txn, channel, work, stop, 0);
+    let o = output_loop(repo, changes, txn, channel, work, stop, hooks.clone(), 0);
     for t in threads {
         conflicts.extend(t.join().unwrap()?.into_iter());
     }
@@ -353,6 +451,15 @@ where
     for (a, b) in actual_moves.iter() {
         repo.rename(a, b).map_err(OutputError::WorkingCopy)?
     }
+    for (path, meta) in std::mem::take(&mut *hooks.lock()) {
+        repo.output_hook(&path, meta)
+            .map_err(OutputError::WorkingCopy)?
+    }
+    // Before the caller commits the tree-table updates.
+    repo.sync().map_err(OutputError::WorkingCopy)?;
+    // Files are output by several threads, list the conflicts in an
+    // order that doesn't depend on them.
+    conflicts.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
     Ok(conflicts)
 }
 
@@ -381,7 +488,11 @@ fn needs_output<R: WorkingCopy>(
     true
 }
 
-fn move_or_create<T: TreeMutTxnT, R: WorkingCopy, C: ChangeStore>(
+fn move_or_create<
+    T: TreeMutTxnT + GraphTxnT<GraphError = <T as TreeTxnT>::TreeError>,
+    R: WorkingCopy,
+    C: ChangeStore,
+>(
     txn: ArcTxn<T>,
     repo: &R,
     output_item: &OutputItem,
@@ -474,7 +585,7 @@ fn move_or_create<T: TreeMutTxnT, R: WorkingCopy, C: ChangeStore>(
             crate::fs::rec_delete(&mut *txn_, &file_id, inode, true)
                 .map_err(PristineOutputError::Fs)?;
         }
-        let inode = create_new_inode(&mut *txn_, &file_id, salt)?;
+        let inode = create_inode_for(&mut *txn_, &file_id, salt, output_item.pos)?;
         debug!(
             "created new inode {:?} {:?} {:?}",
             inode, output_item.pos, file_id
@@ -492,6 +603,17 @@ fn move_or_create<T: TreeMutTxnT, R: WorkingCopy, C: ChangeStore>(
     }
 }
 
+fn sink_metadata(meta: InodeMetadata) -> SinkMetadata {
+    SinkMetadata {
+        // Working copies set their own modification times.
+        mtime: 0,
+        permissions: meta.permissions(),
+    }
+}
+
+/// Write the contents of a file, link or directory to `path`. Output
+/// hooks are left to the caller, since `path` may be a temporary
+/// name.
 fn output_item<T: ChannelMutTxnT + GraphMutTxnT, P: ChangeStore, W: WorkingCopy>(
     txn: ArcTxn<T>,
     channel: ChannelRef<T>,
@@ -502,14 +624,99 @@ fn output_item<T: ChannelMutTxnT + GraphMutTxnT, P: ChangeStore, W: WorkingCopy>
     path: &str,
 ) -> Result<(), OutputError<P::Error, T::GraphError, W::Error>> {
     let mut forward = Vec::new();
+    let options = repo.conflict_options();
+    let mut sink = WorkingCopySink {
+        working_copy: repo,
+        hooks: false,
+    };
+    let meta = sink_metadata(output_item.meta);
     {
         let txn = txn.read();
         let channel = channel.read();
-        let mut l = retrieve(&*txn, txn.graph(&*channel), output_item.pos)?;
-        let w = repo.write_file(&path).map_err(OutputError::WorkingCopy)?;
-        let mut f = vertex_buffer::ConflictsWriter::new(w, &path, conflicts);
-        alive::output_graph(changes, &*txn, &*channel, &mut f, &mut l, &mut forward)
+        let mut l = retrieve_limited(
+            &*txn,
+            txn.graph(&*channel),
+            output_item.pos,
+            repo.graph_limits(),
+        )
+        .map_err(FileError::from)
+        .map_err(PristineOutputError::from)?;
+        if output_item.meta.is_subrepo() {
+            // Pointers to nested repositories are always written to
+            // their pointer file, with the conflict markers if any.
+            let mut contents = Vec::new();
+            let mut f = vertex_buffer::ConflictsWriter::new(&mut contents, &path, conflicts);
+            f.ids = options.ids;
+            alive::output_graph_ordered(
+                changes,
+                &*txn,
+                &*channel,
+                &mut f,
+                &mut l,
+                &mut forward,
+                options.order,
+            )
             .map_err(PristineOutputError::from)?;
+            crate::subrepo::materialize(repo, &path, &contents).map_err(|e| match e {
+                SubrepoError::WorkingCopy(e) => OutputError::WorkingCopy(e),
+                SubrepoError::Io(e) => PristineOutputError::Io(e).into(),
+            })?;
+            repo.set_permissions(path, meta.permissions)
+                .map_err(OutputError::WorkingCopy)?;
+        } else if output_item.meta.is_symlink() || output_item.meta.is_opaque() {
+            // Links and opaque directories with conflicts are output
+            // as regular files, so that the conflicts can be solved.
+            let n = conflicts.len();
+            let mut contents = Vec::new();
+            let mut f = vertex_buffer::ConflictsWriter::new(&mut contents, &path, conflicts);
+            f.ids = options.ids;
+            alive::output_graph_ordered(
+                changes,
+                &*txn,
+                &*channel,
+                &mut f,
+                &mut l,
+                &mut forward,
+                options.order,
+            )
+            .map_err(PristineOutputError::from)?;
+            if conflicts.len() == n && output_item.meta.is_symlink() {
+                sink.symlink(path, &String::from_utf8_lossy(&contents), meta)?;
+            } else if conflicts.len() == n
+                && crate::opaque::unpack(repo, &path, &contents).map_err(|e| match e {
+                    OpaqueError::WorkingCopy(e) => OutputError::WorkingCopy(e),
+                    OpaqueError::Io(e) => PristineOutputError::Io(e).into(),
+                })?
+            {
+                repo.set_permissions(path, meta.permissions)
+                    .map_err(OutputError::WorkingCopy)?;
+            } else {
+                if output_item.meta.is_opaque() {
+                    repo.remove_path(&path, true)
+                        .map_err(OutputError::WorkingCopy)?;
+                }
+                let mut w = sink.create_file(path, meta)?;
+                std::io::Write::write_all(&mut w, &contents).map_err(PristineOutputError::Io)?;
+                sink.close_file(w)?;
+            }
+        } else {
+            let mut w = sink.create_file(path, meta)?;
+            {
+                let mut f = vertex_buffer::ConflictsWriter::new(&mut w, &path, conflicts);
+                f.ids = options.ids;
+                alive::output_graph_ordered(
+                    changes,
+                    &*txn,
+                    &*channel,
+                    &mut f,
+                    &mut l,
+                    &mut forward,
+                    options.order,
+                )
+                .map_err(PristineOutputError::from)?;
+            }
+            sink.close_file(w)?;
+        }
     }
     if forward.is_empty() {
         return Ok(());
@@ -532,7 +739,7 @@ fn output_item<T: ChannelMutTxnT + GraphMutTxnT, P: ChangeStore, W: WorkingCopy>
     Ok(())
 }
 
-fn is_alive_or_zombie<T: GraphTxnT>(
+pub(super) fn is_alive_or_zombie<T: GraphTxnT>(
     txn: &T,
     channel: &T::Graph,
     a: &Vertex<ChangeId>,
@@ -558,13 +765,18 @@ fn is_alive_or_zombie<T: GraphTxnT>(
     Ok(false)
 }
 
-fn collect_dead_files<T: TreeTxnT + GraphTxnT<GraphError = <T as TreeTxnT>::TreeError>>(
+/// Collect the dead files under `inode`. If `inode_is_dead` is true,
+/// all the files under it are dead.
+pub(super) fn collect_dead_files<
+    T: TreeTxnT + GraphTxnT<GraphError = <T as TreeTxnT>::TreeError>,
+>(
     txn: &T,
     channel: &T::Graph,
     pending_change_id: ChangeId,
     inode: Inode,
+    inode_is_dead: bool,
 ) -> Result<HashMap<OwnedPathId, (Inode, Option<String>)>, TxnErr<T::GraphError>> {
-    let mut inodes = vec![(inode, false)];
+    let mut inodes = vec![(inode, inode_is_dead)];
     let mut next_inodes = Vec::new();
     let mut dead = HashMap::default();
     while !inodes.is_empty() {
@@ -603,7 +815,7 @@ fn collect_dead_files<T: TreeTxnT + GraphTxnT<GraphError = <T as TreeTxnT>::Tree
     Ok(dead)
 }
 
-fn kill_dead_files<
+pub(super) fn kill_dead_files<
     T: ChannelTxnT<GraphError = T::TreeError> + TreeMutTxnT,
     W: WorkingCopy + Clone,
     C: ChangeStore,
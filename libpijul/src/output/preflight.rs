@@ -0,0 +1,214 @@
+//! Checks run before outputting a channel, so that callers can fail
+//! before touching the working copy.
+use super::*;
+use crate::working_copy::WorkingCopy;
+use crate::HashSet;
+use std::collections::BTreeSet;
+
+/// A problem found by [`preflight`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightIssue {
+    /// `path`, or its closest existing ancestor, can't be written.
+    ReadOnly { path: String },
+    /// The files to output are larger than the space available in
+    /// the working copy.
+    NotEnoughSpace { needed: u64, available: u64 },
+    /// Several files of the channel have the same path. Output
+    /// renames all but one of them.
+    Collision { path: String },
+    /// An untracked file of the working copy would be overwritten.
+    Untracked { path: String },
+    /// Two files in the same directory have names differing only by
+    /// case, which are the same name on case-insensitive filesystems.
+    CaseConflict { path: String, other: String },
+}
+
+/// The result of [`preflight`].
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    /// Number of files to output.
+    pub files: usize,
+    /// Estimated size of these files, computed from the size of their
+    /// alive vertices.
+    pub bytes: u64,
+    pub issues: Vec<PreflightIssue>,
+}
+
+impl PreflightReport {
+    /// Whether output can proceed. Case conflicts are only a problem
+    /// on case-insensitive filesystems, and don't make the report fail.
+    pub fn is_ok(&self) -> bool {
+        self.issues
+            .iter()
+            .all(|i| matches!(i, PreflightIssue::CaseConflict { .. }))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PreflightError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + 'static,
+> {
+    #[error("Working copy error: {0}")]
+    WorkingCopy(W),
+    #[error(transparent)]
+    Pristine(#[from] PristineOutputError<C, T>),
+}
+
+impl<C: std::error::Error, T: std::error::Error + 'static, W: std::error::Error> From<TxnErr<T>>
+    for PreflightError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        PreflightError::Pristine(e.into())
+    }
+}
+
+/// Check that the files of `channel` under `paths` (or all its files,
+/// if `paths` is empty) can be output to `working_copy`, without
+/// modifying the working copy or the pristine.
+pub fn preflight<
+    T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+    W: WorkingCopy,
+>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    working_copy: &W,
+    paths: &[&str],
+) -> Result<PreflightReport, PreflightError<P::Error, T::GraphError, W::Error>>
+where
+    W::Error: 'static,
+{
+    let channel = channel.read();
+    let graph = txn.graph(&channel);
+    let mut report = PreflightReport::default();
+    let roots = if paths.is_empty() {
+        vec![""]
+    } else {
+        paths.to_vec()
+    };
+
+    let mut output = BTreeSet::new();
+    let mut visited = HashSet::default();
+    for root in roots.iter() {
+        let mut prefix = crate::path::components(root);
+        let mut files = HashMap::default();
+        let mut next_files = HashMap::default();
+        collect_children(
+            txn,
+            changes,
+            graph,
+            Position::ROOT,
+            Inode::ROOT,
+            "",
+            None,
+            prefix.next(),
+            &mut files,
+        )?;
+        while !files.is_empty() {
+            let next_prefix = prefix.next();
+            for (path, items) in files.drain() {
+                let mut positions = Vec::new();
+                for (_, item) in items {
+                    if !positions.iter().any(|(p, _)| *p == item.pos) {
+                        positions.push((item.pos, item.meta))
+                    }
+                }
+                if positions.len() > 1 {
+                    report
+                        .issues
+                        .push(PreflightIssue::Collision { path: path.clone() })
+                }
+                for (pos, meta) in positions {
+                    if !visited.insert(pos) {
+                        continue;
+                    }
+                    if meta.is_dir() {
+                        collect_children(
+                            txn,
+                            changes,
+                            graph,
+                            pos,
+                            Inode::ROOT,
+                            &path,
+                            None,
+                            next_prefix,
+                            &mut next_files,
+                        )?;
+                    } else {
+                        let l = crate::alive::retrieve::retrieve_limited(
+                            txn,
+                            graph,
+                            pos,
+                            working_copy.graph_limits(),
+                        )
+                        .map_err(FileError::from)
+                        .map_err(PristineOutputError::from)?;
+                        report.files += 1;
+                        report.bytes += l.len_bytes() as u64;
+                    }
+                }
+                output.insert(path);
+            }
+            std::mem::swap(&mut files, &mut next_files);
+        }
+    }
+
+    for root in roots.iter() {
+        if !working_copy
+            .is_writable(root)
+            .map_err(PreflightError::WorkingCopy)?
+        {
+            report.issues.push(PreflightIssue::ReadOnly {
+                path: root.to_string(),
+            })
+        }
+    }
+
+    let mut names: HashMap<(&str, String), &str> = HashMap::default();
+    for path in output.iter() {
+        let (parent, basename) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", &path[..]),
+        };
+        if let Some(other) = names.insert((parent, basename.to_lowercase()), path) {
+            report.issues.push(PreflightIssue::CaseConflict {
+                path: other.to_string(),
+                other: path.to_string(),
+            })
+        }
+        if let Ok(meta) = working_copy.file_metadata(path) {
+            if meta.is_dir() {
+                continue;
+            }
+            if !crate::fs::is_tracked(txn, path)? {
+                report
+                    .issues
+                    .push(PreflightIssue::Untracked { path: path.clone() });
+            }
+            if !working_copy
+                .is_writable(path)
+                .map_err(PreflightError::WorkingCopy)?
+            {
+                report
+                    .issues
+                    .push(PreflightIssue::ReadOnly { path: path.clone() })
+            }
+        }
+    }
+
+    if let Some(available) = working_copy
+        .available_space()
+        .map_err(PreflightError::WorkingCopy)?
+    {
+        if report.bytes > available {
+            report.issues.push(PreflightIssue::NotEnoughSpace {
+                needed: report.bytes,
+                available,
+            })
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,482 @@
+//! Conflicts written to sidecar files instead of inline markers.
+//!
+//! Outputting to a [`Sidecars`] working copy writes conflicted files
+//! with the first side of each conflict inline, so that they still
+//! build, and moves the conflicts to a sidecar directory next to the
+//! file, named after the file with [`SIDECAR_SUFFIX`] appended:
+//!
+//! - `conflicts.json` lists the conflicts, with their line in the
+//!   file and their number of sides, along with the hash of the file
+//!   as written.
+//! - `<n>-<k>` is side `k` of conflict `n`, both starting at 1.
+//! - `full` is the file with the usual conflict markers.
+//!
+//! Recording through the same [`Sidecars`] working copy reads files
+//! that weren't edited since they were written as their `full`
+//! version, so that the conflict isn't recorded as resolved. Editing
+//! a file resolves all its conflicts with the contents of the file,
+//! and [`Sidecars::clean_resolved`] then removes its sidecars, once
+//! the resolution is recorded.
+use crate::pristine::{Hash, Hasher, InodeMetadata};
+use crate::subrepo::SubrepoState;
+use crate::text_encoding::Encoding;
+use crate::vertex_buffer::{is_start_marker, END_MARKER, SEPARATOR, START_MARKER};
+use crate::working_copy::WorkingCopy;
+use crate::HashSet;
+use parking_lot::Mutex;
+use std::io::Write;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Suffix of the sidecar directory of a file.
+pub const SIDECAR_SUFFIX: &str = ".pijul-conflict";
+
+const META: &str = "conflicts.json";
+const FULL: &str = "full";
+
+#[derive(Debug, Error)]
+pub enum SidecarError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    WorkingCopy(E),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<SidecarError<std::io::Error>> for std::io::Error {
+    fn from(e: SidecarError<std::io::Error>) -> Self {
+        match e {
+            SidecarError::WorkingCopy(e) | SidecarError::Io(e) => e,
+        }
+    }
+}
+
+/// The sidecar directory of `path`.
+pub fn sidecar_dir(path: &str) -> String {
+    format!("{}{}", path, SIDECAR_SUFFIX)
+}
+
+/// A conflict moved to a sidecar.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SidecarConflict {
+    /// Line of the file where the conflict is, starting at 1.
+    pub line: usize,
+    /// Number of sides of the conflict.
+    pub sides: usize,
+}
+
+/// The contents of `conflicts.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SidecarMeta {
+    /// Hash of the file as written, used to tell whether it was
+    /// edited.
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub hash: Hash,
+    pub conflicts: Vec<SidecarConflict>,
+}
+
+/// A file with its conflicts split out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitFile {
+    /// The file, with the first side of each conflict.
+    pub inline: Vec<u8>,
+    /// The line of each conflict in `inline`, and its sides.
+    pub conflicts: Vec<(usize, Vec<Vec<u8>>)>,
+}
+
+fn marker(m: &str) -> &[u8] {
+    m.trim().as_bytes()
+}
+
+fn is_marker(line: &[u8], m: &str) -> bool {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    line == marker(m)
+}
+
+/// Split the conflicts out of `contents`, an output with conflict
+/// markers, or return `None` if `contents` has no conflicts. The
+/// sides of nested conflicts are kept with their markers in the
+/// sides of the enclosing conflict, and the first side is chosen in
+/// the inline version.
+pub fn split_conflicts(contents: &[u8]) -> Option<SplitFile> {
+    let mut inline = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut sides: Vec<Vec<u8>> = Vec::new();
+    let mut start = 0;
+    for l in contents.split_inclusive(|&c| c == b'\n') {
+        if depth == 0 {
+            if is_start_marker(l) {
+                depth = 1;
+                sides = vec![Vec::new()];
+                start = line;
+            } else {
+                inline.extend_from_slice(l);
+                line += 1;
+            }
+            continue;
+        }
+        if is_start_marker(l) {
+            depth += 1
+        } else if is_marker(l, END_MARKER) {
+            depth -= 1;
+            if depth == 0 {
+                let first = if let Some(s) = split_conflicts(&sides[0]) {
+                    s.inline
+                } else {
+                    sides[0].clone()
+                };
+                line += first.iter().filter(|&&c| c == b'\n').count();
+                inline.extend_from_slice(&first);
+                conflicts.push((start, std::mem::take(&mut sides)));
+                continue;
+            }
+        } else if depth == 1 && is_marker(l, SEPARATOR) {
+            sides.push(Vec::new());
+            continue;
+        }
+        sides.last_mut().unwrap().extend_from_slice(l)
+    }
+    if conflicts.is_empty() {
+        return None;
+    }
+    if depth > 0 {
+        // Unterminated conflict: keep what was read inline.
+        for s in sides {
+            inline.extend_from_slice(&s)
+        }
+    }
+    Some(SplitFile { inline, conflicts })
+}
+
+fn hash(contents: &[u8]) -> Hash {
+    let mut hasher = Hasher::default();
+    hasher.update(contents);
+    hasher.finish()
+}
+
+/// A working copy writing conflicts to sidecar files.
+#[derive(Clone)]
+pub struct Sidecars<W> {
+    pub working_copy: W,
+    /// Files written with conflict markers, not yet split.
+    marked: Arc<Mutex<HashSet<String>>>,
+    /// Files found edited while reading them, i.e. resolved.
+    resolved: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<W: WorkingCopy> Sidecars<W>
+where
+    W::Error: 'static,
+{
+    pub fn new(working_copy: W) -> Self {
+        Sidecars {
+            working_copy,
+            marked: Arc::new(Mutex::new(HashSet::default())),
+            resolved: Arc::new(Mutex::new(HashSet::default())),
+        }
+    }
+
+    /// The sidecar metadata of `path`, if it has sidecars.
+    pub fn meta(&self, path: &str) -> Option<SidecarMeta> {
+        let mut buf = Vec::new();
+        let meta = format!("{}/{}", sidecar_dir(path), META);
+        self.working_copy.read_file(&meta, &mut buf).ok()?;
+        serde_json::from_slice(&buf).ok()
+    }
+
+    /// Whether `path` was edited since its sidecars were written.
+    /// Returns `None` if `path` has no sidecars.
+    pub fn is_resolved(&self, path: &str) -> Result<Option<bool>, SidecarError<W::Error>> {
+        if let Some(meta) = self.meta(path) {
+            let mut buf = Vec::new();
+            self.working_copy
+                .read_file(path, &mut buf)
+                .map_err(SidecarError::WorkingCopy)?;
+            Ok(Some(hash(&buf) != meta.hash))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Remove the sidecars of the files found resolved since the last
+    /// call, and return these files. Call this after recording their
+    /// resolution.
+    pub fn clean_resolved(&self) -> Result<Vec<String>, SidecarError<W::Error>> {
+        let mut resolved: Vec<_> = std::mem::take(&mut *self.resolved.lock())
+            .into_iter()
+            .collect();
+        resolved.sort();
+        for path in resolved.iter() {
+            debug!("sidecars: cleaning {:?}", path);
+            self.working_copy
+                .remove_path(&sidecar_dir(path), true)
+                .map_err(SidecarError::WorkingCopy)?;
+        }
+        Ok(resolved)
+    }
+
+    /// Split the conflicts of `path` to its sidecars, or remove the
+    /// sidecars of `path` if it has no conflicts anymore.
+    fn split(&self, path: &str) -> Result<(), SidecarError<W::Error>> {
+        let mut buf = Vec::new();
+        self.working_copy
+            .read_file(path, &mut buf)
+            .map_err(SidecarError::WorkingCopy)?;
+        let dir = sidecar_dir(path);
+        let split = if let Some(split) = split_conflicts(&buf) {
+            split
+        } else {
+            if self.meta(path).is_some() {
+                self.working_copy
+                    .remove_path(&dir, true)
+                    .map_err(SidecarError::WorkingCopy)?;
+            }
+            return Ok(());
+        };
+        debug!("sidecars: splitting {:?}", path);
+        self.working_copy
+            .create_dir_all(&dir)
+            .map_err(SidecarError::WorkingCopy)?;
+        self.write(&format!("{}/{}", dir, FULL), &buf)?;
+        let mut meta = SidecarMeta {
+            hash: hash(&split.inline),
+            conflicts: Vec::with_capacity(split.conflicts.len()),
+        };
+        for (n, (line, sides)) in split.conflicts.iter().enumerate() {
+            for (k, side) in sides.iter().enumerate() {
+                self.write(&format!("{}/{}-{}", dir, n + 1, k + 1), side)?;
+            }
+            meta.conflicts.push(SidecarConflict {
+                line: *line,
+                sides: sides.len(),
+            })
+        }
+        self.write(
+            &format!("{}/{}", dir, META),
+            &serde_json::to_vec_pretty(&meta).unwrap(),
+        )?;
+        self.write(path, &split.inline)
+    }
+
+    /// If `file`, read in `buffer[init..]`, wasn't edited since its
+    /// sidecars were written, replace it with its conflict markers
+    /// version. Else, remember that it was resolved.
+    fn unresolved(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+        init: usize,
+    ) -> Result<(), SidecarError<W::Error>> {
+        if let Some(meta) = self.meta(file) {
+            if hash(&buffer[init..]) == meta.hash {
+                buffer.truncate(init);
+                let full = format!("{}/{}", sidecar_dir(file), FULL);
+                self.working_copy
+                    .read_file(&full, buffer)
+                    .map_err(SidecarError::WorkingCopy)?;
+            } else {
+                self.resolved.lock().insert(file.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), SidecarError<W::Error>> {
+        let mut w = self
+            .working_copy
+            .write_file(path)
+            .map_err(SidecarError::WorkingCopy)?;
+        w.write_all(contents)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// A file of a [`Sidecars`] working copy being written.
+pub struct SidecarWriter<F> {
+    w: F,
+    path: String,
+    has_markers: bool,
+    /// The last bytes written, shorter than a marker, so that markers
+    /// split across two writes are found.
+    tail: Vec<u8>,
+    marked: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<F: std::io::Write> std::io::Write for SidecarWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let n = self.w.write(buf)?;
+        if !self.has_markers {
+            let m = marker(START_MARKER);
+            self.tail.extend_from_slice(&buf[..n]);
+            self.has_markers = self.tail.windows(m.len()).any(|w| w == m);
+            let keep = self.tail.len().min(m.len() - 1);
+            self.tail.drain(..self.tail.len() - keep);
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.w.flush()
+    }
+}
+
+impl<F> Drop for SidecarWriter<F> {
+    fn drop(&mut self) {
+        if self.has_markers {
+            self.marked
+                .lock()
+                .insert(std::mem::replace(&mut self.path, String::new()));
+        }
+    }
+}
+
+impl<W: WorkingCopy> WorkingCopy for Sidecars<W>
+where
+    W::Error: 'static,
+{
+    type Error = SidecarError<W::Error>;
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        self.working_copy
+            .create_dir_all(path)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        self.working_copy
+            .file_metadata(file)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let init = buffer.len();
+        self.working_copy
+            .read_file(file, buffer)
+            .map_err(SidecarError::WorkingCopy)?;
+        self.unresolved(file, buffer, init)
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        self.working_copy
+            .modified_time(file)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error> {
+        self.working_copy
+            .remove_path(name, rec)
+            .map_err(SidecarError::WorkingCopy)?;
+        if self.meta(name).is_some() {
+            self.working_copy
+                .remove_path(&sidecar_dir(name), true)
+                .map_err(SidecarError::WorkingCopy)?;
+        }
+        Ok(())
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        self.working_copy
+            .rename(former, new)
+            .map_err(SidecarError::WorkingCopy)?;
+        if self.meta(former).is_some() {
+            self.working_copy
+                .rename(&sidecar_dir(former), &sidecar_dir(new))
+                .map_err(SidecarError::WorkingCopy)?;
+        }
+        let mut marked = self.marked.lock();
+        if marked.remove(former) {
+            marked.insert(new.to_string());
+        }
+        Ok(())
+    }
+    fn permission_mask(&self) -> u16 {
+        self.working_copy.permission_mask()
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.working_copy.conflict_options()
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        self.working_copy
+            .set_permissions(name, permissions)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn create_symlink(&self, name: &str, target: &str) -> Result<bool, Self::Error> {
+        self.working_copy
+            .create_symlink(name, target)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        self.working_copy
+            .read_dir(path)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn subrepo_state(&self, path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        self.working_copy
+            .subrepo_state(path)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn sync(&self) -> Result<(), Self::Error> {
+        self.working_copy.sync().map_err(SidecarError::WorkingCopy)
+    }
+    fn output_hook(&self, name: &str, meta: InodeMetadata) -> Result<(), Self::Error> {
+        if meta.is_file() && !meta.is_symlink() && !meta.is_opaque() && !meta.is_subrepo() {
+            let marked = self.marked.lock().remove(name);
+            if marked || self.meta(name).is_some() {
+                self.split(name)?
+            }
+        }
+        self.working_copy
+            .output_hook(name, meta)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn is_writable(&self, path: &str) -> Result<bool, Self::Error> {
+        self.working_copy
+            .is_writable(path)
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        self.working_copy
+            .available_space()
+            .map_err(SidecarError::WorkingCopy)
+    }
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        self.working_copy.detect_encoding(file, contents, last)
+    }
+
+    type Writer = SidecarWriter<W::Writer>;
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        Ok(SidecarWriter {
+            w: self
+                .working_copy
+                .write_file(file)
+                .map_err(SidecarError::WorkingCopy)?,
+            path: file.to_string(),
+            has_markers: false,
+            tail: Vec::new(),
+            marked: self.marked.clone(),
+        })
+    }
+    fn decode_file(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        let init = buffer.len();
+        let encoding = self
+            .working_copy
+            .decode_file(file, buffer)
+            .map_err(SidecarError::WorkingCopy)?;
+        self.unresolved(file, buffer, init)?;
+        Ok(encoding)
+    }
+    fn decode_file_with_stats(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+        stats: Option<&crate::file_stats::FileStats>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        let init = buffer.len();
+        let encoding = self
+            .working_copy
+            .decode_file_with_stats(file, buffer, stats)
+            .map_err(SidecarError::WorkingCopy)?;
+        self.unresolved(file, buffer, init)?;
+        Ok(encoding)
+    }
+}
@@ -0,0 +1,299 @@
+//! Destinations for the files of a channel.
+//!
+//! Both the checkouts of [`output_repository_no_pending`] and
+//! [`archive`](crate::TxnTExt::archive) reconstruct the files of a
+//! channel and write them to an [`OutputSink`]. Implementations are
+//! provided for working copies (on disk or in memory), tarballs (with
+//! the `tarball` feature), maps in memory, and object stores.
+//!
+//! [`output_repository_no_pending`]: super::output_repository_no_pending
+use crate::pristine::InodeMetadata;
+use crate::working_copy::WorkingCopy;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Metadata of a file or directory written to a sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkMetadata {
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// Unix permissions.
+    pub permissions: u16,
+}
+
+pub trait OutputSink {
+    type File: std::io::Write;
+    type Error: std::error::Error;
+    /// Create a directory. Directories are always created before
+    /// their contents.
+    fn create_dir(&mut self, path: &str, meta: SinkMetadata) -> Result<(), Self::Error>;
+    /// Start writing a file. The file is complete once passed to
+    /// [`close_file`](OutputSink::close_file).
+    fn create_file(&mut self, path: &str, meta: SinkMetadata) -> Result<Self::File, Self::Error>;
+    fn close_file(&mut self, file: Self::File) -> Result<(), Self::Error>;
+    /// Create a symbolic link at `path`, pointing to `target`.
+    fn symlink(&mut self, path: &str, target: &str, meta: SinkMetadata) -> Result<(), Self::Error>;
+    /// Limits on the graphs of the files written to this sink, none
+    /// by default.
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        crate::alive::retrieve::RetrieveLimits::UNLIMITED
+    }
+}
+
+/// The former name of [`OutputSink`], implemented by all sinks.
+#[deprecated(note = "use `OutputSink` instead")]
+pub trait Archive: OutputSink {}
+
+#[allow(deprecated)]
+impl<A: OutputSink> Archive for A {}
+
+/// A sink writing to a working copy, which is what checkouts do.
+/// Symbolic links are created with [`WorkingCopy::create_symlink`],
+/// and written as files containing their target if the working copy
+/// doesn't create them.
+pub struct WorkingCopySink<'a, W: WorkingCopy> {
+    pub working_copy: &'a W,
+    /// Whether to call [`WorkingCopy::output_hook`] on each path
+    /// once written. Checkouts writing to temporary names call it
+    /// themselves, after renaming.
+    pub hooks: bool,
+}
+
+impl<'a, W: WorkingCopy> WorkingCopySink<'a, W> {
+    pub fn new(working_copy: &'a W) -> Self {
+        WorkingCopySink {
+            working_copy,
+            hooks: true,
+        }
+    }
+
+    fn hook(&self, path: &str, meta: InodeMetadata) -> Result<(), WorkingCopySinkError<W::Error>> {
+        if self.hooks {
+            self.working_copy
+                .output_hook(path, meta)
+                .map_err(WorkingCopySinkError::WorkingCopy)?
+        }
+        Ok(())
+    }
+}
+
+pub struct WorkingCopyFile<F> {
+    w: F,
+    path: String,
+    permissions: u16,
+}
+
+impl<F: std::io::Write> std::io::Write for WorkingCopyFile<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.w.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.w.flush()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WorkingCopySinkError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    WorkingCopy(E),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl<'a, W: WorkingCopy> OutputSink for WorkingCopySink<'a, W>
+where
+    W::Error: 'static,
+{
+    type File = WorkingCopyFile<W::Writer>;
+    type Error = WorkingCopySinkError<W::Error>;
+    fn create_dir(&mut self, path: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        let wc = self.working_copy;
+        wc.create_dir_all(path)
+            .map_err(WorkingCopySinkError::WorkingCopy)?;
+        wc.set_permissions(path, meta.permissions)
+            .map_err(WorkingCopySinkError::WorkingCopy)?;
+        self.hook(path, InodeMetadata::new(meta.permissions as usize, true))
+    }
+    fn create_file(&mut self, path: &str, meta: SinkMetadata) -> Result<Self::File, Self::Error> {
+        Ok(WorkingCopyFile {
+            w: self
+                .working_copy
+                .write_file(path)
+                .map_err(WorkingCopySinkError::WorkingCopy)?,
+            path: path.to_string(),
+            permissions: meta.permissions,
+        })
+    }
+    fn close_file(&mut self, mut file: Self::File) -> Result<(), Self::Error> {
+        file.w.flush()?;
+        std::mem::drop(file.w);
+        let wc = self.working_copy;
+        wc.set_permissions(&file.path, file.permissions)
+            .map_err(WorkingCopySinkError::WorkingCopy)?;
+        self.hook(
+            &file.path,
+            InodeMetadata::new(file.permissions as usize, false),
+        )
+    }
+    fn symlink(&mut self, path: &str, target: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        if self
+            .working_copy
+            .create_symlink(path, target)
+            .map_err(WorkingCopySinkError::WorkingCopy)?
+        {
+            self.hook(path, InodeMetadata::new_symlink())
+        } else {
+            let mut f = self.create_file(path, meta)?;
+            f.write_all(target.as_bytes())?;
+            self.close_file(f)
+        }
+    }
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        self.working_copy.graph_limits()
+    }
+}
+
+/// An entry of a [`MemorySink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEntry {
+    Dir {
+        meta: SinkMetadata,
+    },
+    File {
+        contents: Vec<u8>,
+        meta: SinkMetadata,
+    },
+    Symlink {
+        target: String,
+        meta: SinkMetadata,
+    },
+}
+
+/// A sink keeping everything in memory, indexed by path, for
+/// instance to serve previews.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    pub entries: BTreeMap<String, MemoryEntry>,
+}
+
+pub struct MemoryFile {
+    path: String,
+    contents: Vec<u8>,
+    meta: SinkMetadata,
+}
+
+impl std::io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.contents.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl OutputSink for MemorySink {
+    type File = MemoryFile;
+    type Error = std::convert::Infallible;
+    fn create_dir(&mut self, path: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        self.entries
+            .insert(path.to_string(), MemoryEntry::Dir { meta });
+        Ok(())
+    }
+    fn create_file(&mut self, path: &str, meta: SinkMetadata) -> Result<Self::File, Self::Error> {
+        Ok(MemoryFile {
+            path: path.to_string(),
+            contents: Vec::new(),
+            meta,
+        })
+    }
+    fn close_file(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        self.entries.insert(
+            file.path,
+            MemoryEntry::File {
+                contents: file.contents,
+                meta: file.meta,
+            },
+        );
+        Ok(())
+    }
+    fn symlink(&mut self, path: &str, target: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        self.entries.insert(
+            path.to_string(),
+            MemoryEntry::Symlink {
+                target: target.to_string(),
+                meta,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// The kind of object stored by an [`ObjectStoreSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Dir,
+    File,
+    /// A symbolic link, whose contents is the target.
+    Symlink,
+}
+
+/// A flat key-value store, such as a cloud object store or a web
+/// cache. Implementations are left to the users of this crate.
+pub trait ObjectStore {
+    type Error: std::error::Error;
+    fn put(
+        &mut self,
+        key: &str,
+        kind: ObjectKind,
+        contents: &[u8],
+        meta: SinkMetadata,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A sink writing each file as an object of an [`ObjectStore`],
+/// under key `prefix` followed by its path. Directories are written
+/// as empty objects whose key ends with a `/`.
+pub struct ObjectStoreSink<S: ObjectStore> {
+    pub store: S,
+    pub prefix: String,
+    buffer: Vec<u8>,
+}
+
+impl<S: ObjectStore> ObjectStoreSink<S> {
+    pub fn new(store: S, prefix: &str) -> Self {
+        ObjectStoreSink {
+            store,
+            prefix: prefix.to_string(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<S: ObjectStore> OutputSink for ObjectStoreSink<S> {
+    type File = MemoryFile;
+    type Error = S::Error;
+    fn create_dir(&mut self, path: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        let key = format!("{}{}/", self.prefix, path);
+        self.store.put(&key, ObjectKind::Dir, &[], meta)
+    }
+    fn create_file(&mut self, path: &str, meta: SinkMetadata) -> Result<Self::File, Self::Error> {
+        let mut contents = std::mem::replace(&mut self.buffer, Vec::new());
+        contents.clear();
+        Ok(MemoryFile {
+            path: self.prefix.clone() + path,
+            contents,
+            meta,
+        })
+    }
+    fn close_file(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        self.store
+            .put(&file.path, ObjectKind::File, &file.contents, file.meta)?;
+        self.buffer = file.contents;
+        Ok(())
+    }
+    fn symlink(&mut self, path: &str, target: &str, meta: SinkMetadata) -> Result<(), Self::Error> {
+        let key = self.prefix.clone() + path;
+        self.store
+            .put(&key, ObjectKind::Symlink, target.as_bytes(), meta)
+    }
+}
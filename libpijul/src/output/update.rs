@@ -0,0 +1,217 @@
+//! Differential output: bring a working copy from one state of a
+//! channel to the next, by only outputting the files touched by the
+//! changes applied in between, instead of walking the entire tree.
+use super::output::{collect_dead_files, is_alive_or_zombie, kill_dead_files};
+use super::{output_repository_no_pending, Conflict, OutputError, PristineOutputError};
+use crate::changestore::ChangeStore;
+use crate::fs::{find_path, inode_filename, iter_basenames, iter_graph_children};
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use crate::{HashMap, HashSet};
+
+#[derive(Debug, Error)]
+pub enum UpdateError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + Send + 'static,
+> {
+    #[error(transparent)]
+    Output(#[from] OutputError<C, T, W>),
+    #[error("State {} not found on this channel", .0.to_base32())]
+    StateNotFound(Merkle),
+    #[error("State {} is not the current state of this channel", .0.to_base32())]
+    NotCurrent(Merkle),
+}
+
+impl<C: std::error::Error, T: std::error::Error + 'static, W: std::error::Error + Send>
+    From<TxnErr<T>> for UpdateError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        UpdateError::Output(e.into())
+    }
+}
+
+impl<C: std::error::Error, T: std::error::Error + 'static, W: std::error::Error + Send>
+    From<PristineOutputError<C, T>> for UpdateError<C, T, W>
+{
+    fn from(e: PristineOutputError<C, T>) -> Self {
+        UpdateError::Output(e.into())
+    }
+}
+
+/// What [`update`] did to the working copy.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Update {
+    /// The paths output, i.e. the files and directories added, edited
+    /// or moved, sorted.
+    pub output: Vec<String>,
+    /// The paths removed from the working copy, sorted.
+    pub removed: Vec<String>,
+    /// Whether some of the files touched couldn't be found in the
+    /// graph, in which case the entire repository was output.
+    pub full: bool,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Update the working copy `repo`, which is an output of `channel`
+/// at state `from`, to state `to`, which must be the current state of
+/// `channel`. Only the files touched by the changes applied after
+/// `from` are written or deleted, which makes the cost of this
+/// function proportional to the size of these changes, rather than
+/// to the size of the repository.
+///
+/// `from` may be [`Merkle::zero()`], for an empty working copy.
+///
+/// **WARNING:** Like [`output_repository_no_pending`], this
+/// overwrites the touched files, cancelling any unrecorded change to
+/// them.
+pub fn update<
+    T: MutTxnT + Send + Sync + 'static,
+    R: WorkingCopy + Send + Clone + Sync + 'static,
+    P: ChangeStore + Send + Clone + 'static,
+>(
+    repo: &R,
+    changes: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    from: &Merkle,
+    to: &Merkle,
+    n_workers: usize,
+) -> Result<Update, UpdateError<P::Error, T::GraphError, R::Error>>
+where
+    T::Channel: Send + Sync + 'static,
+{
+    let mut result = Update::default();
+    let mut paths = Vec::new();
+    let mut dead = HashMap::default();
+    {
+        let txn_ = txn.read();
+        let channel_ = channel.read();
+        if current_state(&*txn_, &*channel_)? != *to {
+            return Err(UpdateError::NotCurrent(*to));
+        }
+        let start = if *from == Merkle::zero() {
+            0
+        } else if let Some(n) = txn_.channel_has_state(txn_.states(&*channel_), &from.into())? {
+            u64::from_le(n.0) + 1
+        } else {
+            return Err(UpdateError::StateNotFound(*from));
+        };
+
+        let mut touched = HashSet::default();
+        for x in changeid_log(&*txn_, &*channel_, L64(start.to_le()))? {
+            let (_, p) = x?;
+            for y in txn_.iter_rev_touched(&p.a)? {
+                let (int, pos) = y?;
+                if *int < p.a {
+                    continue;
+                } else if *int > p.a {
+                    break;
+                }
+                touched.insert(*pos);
+            }
+        }
+        debug!("update: touched = {:?}", touched);
+
+        let graph = txn_.graph(&*channel_);
+        for pos in touched {
+            if is_alive_or_zombie(&*txn_, graph, &pos.inode_vertex())? {
+                let path = if pos.is_root() {
+                    String::new()
+                } else if let Some((path, _)) = find_path(changes, &*txn_, &*channel_, false, pos)
+                    .map_err(PristineOutputError::from)?
+                {
+                    path
+                } else {
+                    result.full = true;
+                    break;
+                };
+                // Changes that add or move a file only touch its new
+                // parent: directories that are already in the working
+                // copy only get their new or moved children output.
+                let inode = if pos.is_root() {
+                    Some(Inode::ROOT)
+                } else {
+                    txn_.get_revinodes(&pos, None)?.cloned()
+                };
+                let inode = if let Some(inode) = inode {
+                    inode
+                } else {
+                    paths.push(path);
+                    continue;
+                };
+                let mut is_dir = pos.is_root();
+                for x in iter_basenames(&*txn_, changes, graph, pos).map_err(TxnErr)? {
+                    is_dir |= x.map_err(TxnErr)?.1.is_dir();
+                }
+                if !is_dir {
+                    paths.push(path);
+                    continue;
+                }
+                for x in iter_graph_children(&*txn_, changes, graph, pos).map_err(TxnErr)? {
+                    let (child, _, _, basename) = x.map_err(TxnErr)?;
+                    if let Some(&child_inode) = txn_.get_revinodes(&child, None)? {
+                        if let Some(id) = txn_.get_revtree(&child_inode, None)? {
+                            if id.parent_inode == inode && id.basename.as_str() == basename {
+                                continue;
+                            }
+                        }
+                    }
+                    paths.push(if path.is_empty() {
+                        basename
+                    } else {
+                        format!("{}/{}", path, basename)
+                    })
+                }
+                continue;
+            }
+            // Dead files still in the tree are removed, along with
+            // everything under them.
+            if let Some(&inode) = txn_.get_revinodes(&pos, None)? {
+                if let Some(id) = txn_.get_revtree(&inode, None)? {
+                    let name = inode_filename(&*txn_, inode)?;
+                    dead.insert(id.to_owned(), (inode, name));
+                    dead.extend(collect_dead_files(
+                        &*txn_,
+                        graph,
+                        ChangeId::ROOT,
+                        inode,
+                        true,
+                    )?);
+                }
+            }
+        }
+    }
+
+    if result.full {
+        debug!("update: falling back to a full output");
+        result.conflicts = output_repository_no_pending(
+            repo, changes, txn, channel, "", true, None, n_workers, 0,
+        )?;
+        return Ok(result);
+    }
+
+    if !dead.is_empty() {
+        kill_dead_files::<T, R, P>(&mut *txn.write(), channel, repo, &dead)?;
+    }
+    result.removed = dead.into_iter().filter_map(|(_, (_, name))| name).collect();
+    result.removed.sort();
+
+    // Outputting a directory outputs everything under it.
+    paths.sort();
+    for path in paths {
+        if let Some(last) = result.output.last() {
+            if path == *last
+                || (path.starts_with(last.as_str()) && path[last.len()..].starts_with('/'))
+            {
+                continue;
+            }
+        }
+        debug!("update: output {:?}", path);
+        result.conflicts.extend(output_repository_no_pending(
+            repo, changes, txn, channel, &path, true, None, n_workers, 0,
+        )?);
+        result.output.push(path)
+    }
+    Ok(result)
+}
@@ -147,3 +147,37 @@ pub fn pop(path: &mut String) {
         path.clear()
     }
 }
+
+/// Whether a symbolic link at `path` to `target` points inside the
+/// root `path` is relative to: `target` must be relative, with `..`
+/// components only at its start, and no more of them than the depth
+/// of `path`, so that the link stays in the root even if it goes
+/// through other links in the root.
+/// ```ignore
+/// use libpijul::path::is_contained_link;
+/// assert!(is_contained_link("a/b", "../c"));
+/// assert!(!is_contained_link("a/b", "../../c"));
+/// assert!(!is_contained_link("a/b", "c/../.."));
+/// assert!(!is_contained_link("a/b", "/etc"));
+/// ```
+pub fn is_contained_link(path: &str, target: &str) -> bool {
+    if target.is_empty() || target.starts_with('/') || target.contains('\\') {
+        return false;
+    }
+    // Depth of the directory containing the link.
+    let mut depth = path.split('/').filter(|c| !c.is_empty()).count();
+    if depth == 0 {
+        return false;
+    }
+    depth -= 1;
+    let mut descending = false;
+    for c in target.split('/') {
+        match c {
+            "" | "." => {}
+            ".." if descending || depth == 0 => return false,
+            ".." => depth -= 1,
+            _ => descending = true,
+        }
+    }
+    true
+}
@@ -0,0 +1,352 @@
+//! A pool of open repositories, for servers.
+//!
+//! Opening a pristine maps its file and takes a lock on it, which is
+//! too expensive to do on each request, and too costly in file
+//! descriptors and address space to do once for all the repositories
+//! of a server. A [`Pool`] keeps at most
+//! [`capacity`](PoolConfig::capacity) repositories open, and closes
+//! the least recently used one when opening another one.
+//!
+//! Transactions are taken through the pool, with [`Pool::read`] and
+//! [`Pool::write`], which return handles gating the use of each
+//! repository: there is at most one write transaction per repository
+//! at a time, and optionally a bounded number of read transactions. A
+//! repository isn't closed while it has live handles: when all the
+//! repositories are in use, the pool temporarily grows past its
+//! capacity instead.
+use crate::changestore::filesystem::FileSystem;
+use crate::pristine::sanakirja::{MutTxn, Pristine, SanakirjaError, Txn};
+use crate::pristine::{ArcTxn, MutTxnT};
+use crate::{HashMap, HashSet};
+use parking_lot::{Condvar, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error(transparent)]
+    Pristine(#[from] SanakirjaError),
+    #[error("No repository at {0:?}")]
+    NotFound(PathBuf),
+}
+
+/// The parameters of a [`Pool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Maximal number of repositories kept open while not in use.
+    pub capacity: usize,
+    /// Maximal number of simultaneous read transactions on each
+    /// repository, or `None` for no limit.
+    pub max_readers: Option<usize>,
+    /// Size of the change cache of the change stores returned by the
+    /// pool.
+    pub change_cache: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            capacity: 64,
+            max_readers: None,
+            change_cache: 256,
+        }
+    }
+}
+
+/// Counters of the activity of a [`Pool`], since its creation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Repositories currently open.
+    pub open: usize,
+    /// Requests for a repository that was already open.
+    pub hits: u64,
+    /// Requests for a repository that had to be opened.
+    pub misses: u64,
+    /// Repositories closed to make room for others.
+    pub evictions: u64,
+    /// Read transactions started.
+    pub reads: u64,
+    /// Write transactions started.
+    pub writes: u64,
+    /// Transactions that had to wait for another one to finish.
+    pub waits: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    waits: AtomicU64,
+}
+
+/// A pool of repositories, identified by the path of their root (the
+/// parent of their `.pijul` directory).
+pub struct Pool {
+    config: PoolConfig,
+    repos: Mutex<Repos>,
+    /// Notified when a repository is done opening.
+    opened: Condvar,
+    counters: Counters,
+}
+
+#[derive(Default)]
+struct Repos {
+    open: HashMap<PathBuf, Arc<Repo>>,
+    /// Repositories being opened, outside of the lock on `Repos`.
+    opening: HashSet<PathBuf>,
+    clock: u64,
+}
+
+struct Repo {
+    pristine: Pristine,
+    changes_dir: PathBuf,
+    last_used: AtomicU64,
+    gate: Mutex<Gate>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Gate {
+    readers: usize,
+    writer: bool,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig) -> Self {
+        Pool {
+            config,
+            repos: Mutex::new(Repos::default()),
+            opened: Condvar::new(),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// Start a read transaction on the repository at `root`, opening
+    /// it if needed. This blocks while the repository has
+    /// [`max_readers`](PoolConfig::max_readers) read transactions.
+    pub fn read<P: AsRef<Path>>(&self, root: P) -> Result<ReadHandle, PoolError> {
+        let repo = self.get(root.as_ref())?;
+        let permit = self.acquire(repo, false);
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        Ok(ReadHandle {
+            txn: permit.repo.pristine.txn_begin()?,
+            changes: permit.changes(self.config.change_cache),
+            _permit: permit,
+        })
+    }
+
+    /// Start a write transaction on the repository at `root`, opening
+    /// it if needed. This blocks while another write transaction is
+    /// running on that repository through this pool.
+    pub fn write<P: AsRef<Path>>(&self, root: P) -> Result<WriteHandle, PoolError> {
+        let repo = self.get(root.as_ref())?;
+        let permit = self.acquire(repo, true);
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(WriteHandle {
+            txn: permit.repo.pristine.arc_txn_begin()?,
+            changes: permit.changes(self.config.change_cache),
+            _permit: permit,
+        })
+    }
+
+    /// Close the repository at `root` if it is open and not in use,
+    /// for instance before deleting or moving it. Returns whether the
+    /// repository was closed.
+    pub fn close<P: AsRef<Path>>(&self, root: P) -> bool {
+        let mut repos = self.repos.lock();
+        match repos.open.get(root.as_ref()) {
+            Some(repo) if Arc::strong_count(repo) == 1 => {
+                repos.open.remove(root.as_ref());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The current metrics of this pool.
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            open: self.repos.lock().open.len(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            reads: self.counters.reads.load(Ordering::Relaxed),
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            waits: self.counters.waits.load(Ordering::Relaxed),
+        }
+    }
+
+    fn get(&self, root: &Path) -> Result<Arc<Repo>, PoolError> {
+        let mut repos = self.repos.lock();
+        // Wait if another thread is opening this repository.
+        while repos.opening.contains(root) {
+            self.opened.wait(&mut repos);
+        }
+        repos.clock += 1;
+        let now = repos.clock;
+        if let Some(repo) = repos.open.get(root) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            repo.last_used.store(now, Ordering::Relaxed);
+            return Ok(repo.clone());
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let dot_dir = root.join(crate::DOT_DIR);
+        if std::fs::metadata(&dot_dir).is_err() {
+            return Err(PoolError::NotFound(root.to_path_buf()));
+        }
+        // Make room before opening, so that at most `capacity`
+        // repositories are open unless they're all in use.
+        let mut closed = Vec::new();
+        while repos.open.len() >= self.config.capacity {
+            let lru = repos
+                .open
+                .iter()
+                .filter(|(_, r)| Arc::strong_count(r) == 1)
+                .min_by_key(|(_, r)| r.last_used.load(Ordering::Relaxed))
+                .map(|(p, _)| p.clone());
+            if let Some(p) = lru {
+                debug!("pool: closing {:?}", p);
+                closed.extend(repos.open.remove(&p));
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            } else {
+                break;
+            }
+        }
+        repos.opening.insert(root.to_path_buf());
+        std::mem::drop(repos);
+
+        // Opening and closing pristines maps and locks files, which
+        // is done without blocking the other repositories.
+        std::mem::drop(closed);
+        debug!("pool: opening {:?}", root);
+        let pristine = open_pristine(&dot_dir.join("pristine").join("db"));
+
+        let mut repos = self.repos.lock();
+        repos.opening.remove(root);
+        self.opened.notify_all();
+        let repo = Arc::new(Repo {
+            pristine: pristine?,
+            changes_dir: dot_dir.join("changes"),
+            last_used: AtomicU64::new(now),
+            gate: Mutex::new(Gate::default()),
+            released: Condvar::new(),
+        });
+        repos.open.insert(root.to_path_buf(), repo.clone());
+        Ok(repo)
+    }
+
+    fn acquire(&self, repo: Arc<Repo>, write: bool) -> Permit {
+        let mut gate = repo.gate.lock();
+        let mut waited = false;
+        loop {
+            let busy = if write {
+                gate.writer
+            } else {
+                self.config
+                    .max_readers
+                    .map(|max| gate.readers >= max)
+                    .unwrap_or(false)
+            };
+            if !busy {
+                break;
+            }
+            waited = true;
+            repo.released.wait(&mut gate);
+        }
+        if waited {
+            self.counters.waits.fetch_add(1, Ordering::Relaxed);
+        }
+        if write {
+            gate.writer = true
+        } else {
+            gate.readers += 1
+        }
+        std::mem::drop(gate);
+        Permit { repo, write }
+    }
+}
+
+/// Open the pristine at `path`, initializing it if it was never
+/// written to, so that read transactions can be started on it.
+fn open_pristine(path: &Path) -> Result<Pristine, SanakirjaError> {
+    let pristine = Pristine::new(path)?;
+    if let Err(SanakirjaError::Version) = pristine.txn_begin() {
+        // Fails with the same error if the pristine has another version.
+        pristine.mut_txn_begin()?.commit()?;
+    }
+    Ok(pristine)
+}
+
+/// The right to use a repository of a pool, released on drop.
+struct Permit {
+    repo: Arc<Repo>,
+    write: bool,
+}
+
+impl Permit {
+    fn changes(&self, cap: usize) -> FileSystem {
+        FileSystem::from_changes(self.repo.changes_dir.clone(), cap)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut gate = self.repo.gate.lock();
+        if self.write {
+            gate.writer = false
+        } else {
+            gate.readers -= 1
+        }
+        self.repo.released.notify_all();
+    }
+}
+
+/// A read transaction on a repository of a [`Pool`].
+pub struct ReadHandle {
+    // Fields are dropped in order: the transaction must end before
+    // the permit is released.
+    txn: Txn,
+    changes: FileSystem,
+    _permit: Permit,
+}
+
+impl ReadHandle {
+    pub fn txn(&self) -> &Txn {
+        &self.txn
+    }
+
+    pub fn changes(&self) -> &FileSystem {
+        &self.changes
+    }
+}
+
+/// A write transaction on a repository of a [`Pool`]. Dropping it
+/// without calling [`WriteHandle::commit`] cancels the transaction.
+pub struct WriteHandle {
+    txn: ArcTxn<MutTxn<()>>,
+    changes: FileSystem,
+    _permit: Permit,
+}
+
+impl WriteHandle {
+    pub fn txn(&self) -> &ArcTxn<MutTxn<()>> {
+        &self.txn
+    }
+
+    pub fn changes(&self) -> &FileSystem {
+        &self.changes
+    }
+
+    /// Commit the transaction, and release the repository.
+    pub fn commit(self) -> Result<(), SanakirjaError> {
+        self.txn.commit()
+    }
+}
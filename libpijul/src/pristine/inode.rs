@@ -1,7 +1,7 @@
 use super::L64;
 /// A unique identifier for files or directories in the actual
 /// file system, to map "files from the graph" to real files.
-#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Inode(pub(in crate) super::L64);
 use byteorder::{BigEndian, ByteOrder};
 
@@ -1,9 +1,12 @@
 /// Metadata about an inode, including unix-style permissions and
-/// whether this inode is a directory.
+/// whether this inode is a directory or a symbolic link.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 #[doc(hidden)]
 pub struct InodeMetadata(pub u16);
 const DIR_BIT: u16 = 0x200;
+const SYMLINK_BIT: u16 = 0x400;
+const OPAQUE_BIT: u16 = 0x800;
+const SUBREPO_BIT: u16 = 0x1000;
 
 impl InodeMetadata {
     /// Create a new file metadata with the given Unix permissions,
@@ -19,6 +22,24 @@ impl InodeMetadata {
         m
     }
 
+    /// Create the metadata of a symbolic link. The contents of a
+    /// symbolic link are its target.
+    pub fn new_symlink() -> Self {
+        InodeMetadata(SYMLINK_BIT)
+    }
+
+    /// Create the metadata of an opaque directory, recorded as a
+    /// single file (see [`crate::opaque`]).
+    pub fn new_opaque() -> Self {
+        InodeMetadata(OPAQUE_BIT | 0o100)
+    }
+
+    /// Create the metadata of the pointer to a nested repository (see
+    /// [`crate::subrepo`]).
+    pub fn new_subrepo() -> Self {
+        InodeMetadata(SUBREPO_BIT | 0o100)
+    }
+
     pub fn from_basename(b: &[u8]) -> Self {
         use byteorder::ByteOrder;
         InodeMetadata(byteorder::BigEndian::read_u16(b))
@@ -29,6 +50,13 @@ impl InodeMetadata {
         self.0 & 0x1ff
     }
 
+    /// Tell whether `perm` are full Unix permissions, as recorded by
+    /// working copies preserving them, rather than just the executable
+    /// bit. Files recorded by other working copies have no other bit.
+    pub fn is_full_permissions(perm: u16) -> bool {
+        perm & 0o677 != 0
+    }
+
     /// Set the permissions to the supplied parameters.
     pub fn set_permissions(&mut self, perm: u16) {
         self.0 |= perm & 0x1ff
@@ -39,11 +67,29 @@ impl InodeMetadata {
         self.0 & DIR_BIT != 0
     }
 
-    /// Tell whether this `InodeMetadata` is a file.
+    /// Tell whether this `InodeMetadata` is a file. Symbolic links
+    /// are files.
     pub fn is_file(&self) -> bool {
         self.0 & DIR_BIT == 0
     }
 
+    /// Tell whether this `InodeMetadata` is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.0 & SYMLINK_BIT != 0
+    }
+
+    /// Tell whether this `InodeMetadata` is an opaque directory. These
+    /// are files in the pristine.
+    pub fn is_opaque(&self) -> bool {
+        self.0 & OPAQUE_BIT != 0
+    }
+
+    /// Tell whether this `InodeMetadata` is the pointer to a nested
+    /// repository. These are files in the pristine.
+    pub fn is_subrepo(&self) -> bool {
+        self.0 & SUBREPO_BIT != 0
+    }
+
     /// Set the metadata to be a directory.
     pub fn set_dir(&mut self) {
         self.0 |= DIR_BIT
@@ -21,6 +21,8 @@ mod path_id;
 pub use path_id::*;
 mod merkle;
 pub use merkle::*;
+mod prune;
+pub use prune::*;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct L64(pub u64);
@@ -146,6 +148,23 @@ pub trait Base32: Sized {
     fn from_base32(b: &[u8]) -> Option<Self>;
 }
 
+/// Serialize hashes and states as base32 strings, with
+/// `#[serde(with = "crate::pristine::serde_base32")]`, for formats
+/// meant to be read by humans.
+pub(crate) mod serde_base32 {
+    use super::Base32;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<B: Base32, S: Serializer>(b: &B, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&b.to_base32())
+    }
+
+    pub fn deserialize<'de, B: Base32, D: Deserializer<'de>>(d: D) -> Result<B, D::Error> {
+        let s = String::deserialize(d)?;
+        B::from_base32(s.as_bytes()).ok_or_else(|| serde::de::Error::custom("Invalid base32"))
+    }
+}
+
 pub mod sanakirja;
 
 pub type ApplyTimestamp = u64;
@@ -537,6 +556,9 @@ pub trait DepsTxnT: Sized {
         &self,
         p: &ChangeId,
     ) -> Result<Cursor<Self, &Self, Self::DepCursor, ChangeId, ChangeId>, TxnErr<Self::DepsError>>;
+    /// The custom hunk kinds of the changes registered in this
+    /// pristine, see [crate::hunk_kind].
+    fn hunk_kinds(&self) -> Result<Vec<String>, TxnErr<Self::DepsError>>;
     fn iter_dep(
         &self,
         p: &ChangeId,
@@ -567,8 +589,28 @@ pub trait DepsTxnT: Sized {
     iter!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
 }
 
+/// How inodes are allocated to new files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeAllocation {
+    /// Inodes are derived from the path of the file when it is added
+    /// or output, and from a salt, so that they depend on the history
+    /// of the pristine.
+    Salted,
+    /// Inodes of recorded files are derived from the change that
+    /// introduced them (see [`crate::fs::deterministic_inode`]), so
+    /// that pristines with the same channels have the same inodes.
+    Deterministic,
+}
+
+impl Default for InodeAllocation {
+    fn default() -> Self {
+        InodeAllocation::Salted
+    }
+}
+
 pub trait TreeTxnT: Sized {
     type TreeError: std::error::Error + Send + Sync + 'static;
+    fn inode_allocation(&self) -> InodeAllocation;
     table!(tree);
     table_get!(tree, PathId, Inode, TreeError);
     iter!(tree, PathId, Inode, TreeError);
@@ -608,6 +650,11 @@ pub trait TreeTxnT: Sized {
         Cursor<Self, &'txn Self, Self::PartialsCursor, SmallStr, Position<ChangeId>>,
         TxnErr<Self::TreeError>,
     >;
+
+    /// Serialized size and modification time of file `inode` when it
+    /// was last found identical to the pristine, see
+    /// [crate::dirty_cache].
+    fn get_dirty_cache(&self, inode: Inode) -> Result<Option<&[u8]>, TxnErr<Self::TreeError>>;
 }
 
 /// The trait of immutable transactions.
@@ -711,6 +758,72 @@ pub trait TxnT:
         hash: &SerializedMerkle,
     ) -> Result<bool, TxnErr<Self::GraphError>>;
 
+    /// Serialized entry `n` of the audit log, see [crate::audit].
+    fn get_audit(&self, n: u64) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// Number of entries in the audit log.
+    fn audit_len(&self) -> Result<u64, TxnErr<Self::GraphError>>;
+
+    /// Serialized conflict resolution session of the channel with id
+    /// `channel`, see [crate::resolution].
+    fn get_resolution(&self, channel: &RemoteId)
+        -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// Serialized record settings of the channel with id `channel`,
+    /// see [crate::channel_settings].
+    fn get_channel_settings(
+        &self,
+        channel: &RemoteId,
+    ) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// Serialized intent of the unfinished operation on channel
+    /// `channel`, see [crate::journal].
+    fn get_journal(&self, channel: &str) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// Serialized named roots of the channel with id `channel`, see
+    /// [crate::roots].
+    fn get_roots(&self, channel: &RemoteId) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// Serialized content statistics of file `inode`, see
+    /// [crate::file_stats].
+    fn get_file_stats(&self, inode: Inode) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// All the serialized content statistics, by inode.
+    fn iter_file_stats(&self) -> Result<Vec<(Inode, &[u8])>, TxnErr<Self::GraphError>>;
+
+    /// Serialized provenance links from change `old` to the changes
+    /// rewriting it, see [crate::provenance].
+    fn get_provenance(&self, old: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// All the serialized provenance links, by rewritten change.
+    fn iter_provenance(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>>;
+
+    /// Serialized CI statuses of change `change`, see
+    /// [crate::ci_status].
+    fn get_ci_statuses(&self, change: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// All the serialized CI statuses, by change.
+    fn iter_ci_statuses(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>>;
+
+    /// Serialized notes on change `change`, by namespace, see
+    /// [crate::notes].
+    fn get_notes(&self, change: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>>;
+
+    /// All the serialized notes, by change.
+    fn iter_notes(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>>;
+
+    /// Time of deletion, in seconds since the Unix epoch, of the
+    /// channel with id `channel` if it is soft-deleted, see
+    /// [crate::channel::soft_delete].
+    fn get_deleted_channel(
+        &self,
+        channel: &RemoteId,
+    ) -> Result<Option<u64>, TxnErr<Self::GraphError>>;
+
+    /// All the soft-deleted channels, by channel id, with their time
+    /// of deletion.
+    fn iter_deleted_channels(&self) -> Result<Vec<(RemoteId, u64)>, TxnErr<Self::GraphError>>;
+
     fn current_channel(&self) -> Result<&str, Self::GraphError>;
 }
 
@@ -1691,9 +1804,13 @@ pub trait DepsMutTxnT: DepsTxnT {
     put_del!(revdep, ChangeId, ChangeId, DepsError);
     put_del!(touched_files, Position<ChangeId>, ChangeId, DepsError);
     put_del!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+    /// Remember that a change with hunks of kind `kind` was
+    /// registered.
+    fn put_hunk_kind(&mut self, kind: &str) -> Result<(), TxnErr<Self::DepsError>>;
 }
 
 pub trait TreeMutTxnT: TreeTxnT {
+    fn set_inode_allocation(&mut self, allocation: InodeAllocation);
     put_del!(inodes, Inode, Position<ChangeId>, TreeError);
     put_del!(revinodes, Position<ChangeId>, Inode, TreeError);
     put_del!(tree, PathId, Inode, TreeError);
@@ -1709,6 +1826,18 @@ pub trait TreeMutTxnT: TreeTxnT {
         k: &str,
         e: Option<Position<ChangeId>>,
     ) -> Result<bool, TxnErr<Self::TreeError>>;
+
+    /// Store the serialized dirty cache entry of file `inode`,
+    /// replacing any previous one.
+    fn put_dirty_cache(
+        &mut self,
+        inode: Inode,
+        entry: &[u8],
+    ) -> Result<(), TxnErr<Self::TreeError>>;
+
+    /// Delete the dirty cache entry of file `inode`, returning whether
+    /// there was one.
+    fn del_dirty_cache(&mut self, inode: Inode) -> Result<bool, TxnErr<Self::TreeError>>;
 }
 
 /// The trait of immutable transactions.
@@ -1772,6 +1901,111 @@ pub trait MutTxnT:
     fn drop_named_remote(&mut self, id: RemoteId) -> Result<bool, Self::GraphError>;
 
     fn set_current_channel(&mut self, cur: &str) -> Result<(), Self::GraphError>;
+
+    /// Append a serialized entry to the audit log, returning its
+    /// index.
+    fn put_audit(&mut self, entry: &[u8]) -> Result<u64, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized conflict resolution session of the
+    /// channel with id `channel`, replacing any previous one.
+    fn put_resolution(
+        &mut self,
+        channel: &RemoteId,
+        session: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the conflict resolution session of the channel with id
+    /// `channel`, returning whether there was one.
+    fn del_resolution(&mut self, channel: &RemoteId) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized record settings of the channel with id
+    /// `channel`, replacing any previous ones.
+    fn put_channel_settings(
+        &mut self,
+        channel: &RemoteId,
+        settings: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the record settings of the channel with id `channel`,
+    /// returning whether there were any.
+    fn del_channel_settings(
+        &mut self,
+        channel: &RemoteId,
+    ) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized intent of an operation on channel
+    /// `channel`, replacing any previous one.
+    fn put_journal(&mut self, channel: &str, intent: &[u8])
+        -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the intent of the operation on channel `channel`,
+    /// returning whether there was one.
+    fn del_journal(&mut self, channel: &str) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized named roots of the channel with id
+    /// `channel`, replacing any previous ones.
+    fn put_roots(
+        &mut self,
+        channel: &RemoteId,
+        roots: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the named roots of the channel with id `channel`,
+    /// returning whether there were any.
+    fn del_roots(&mut self, channel: &RemoteId) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized content statistics of file `inode`,
+    /// replacing any previous ones.
+    fn put_file_stats(
+        &mut self,
+        inode: Inode,
+        stats: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the content statistics of file `inode`, returning
+    /// whether there were any.
+    fn del_file_stats(&mut self, inode: Inode) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized provenance links of change `old`,
+    /// replacing any previous ones.
+    fn put_provenance(&mut self, old: &Hash, links: &[u8]) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the provenance links of change `old`, returning whether
+    /// there were any.
+    fn del_provenance(&mut self, old: &Hash) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized CI statuses of change `change`, replacing
+    /// any previous ones.
+    fn put_ci_statuses(
+        &mut self,
+        change: &Hash,
+        statuses: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the CI statuses of change `change`, returning whether
+    /// there were any.
+    fn del_ci_statuses(&mut self, change: &Hash) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Store the serialized notes on change `change`, replacing any
+    /// previous ones.
+    fn put_notes(&mut self, change: &Hash, notes: &[u8]) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Delete the notes on change `change`, returning whether there
+    /// were any.
+    fn del_notes(&mut self, change: &Hash) -> Result<bool, TxnErr<Self::GraphError>>;
+
+    /// Mark the channel with id `channel` as soft-deleted at time
+    /// `deleted_at`.
+    fn put_deleted_channel(
+        &mut self,
+        channel: &RemoteId,
+        deleted_at: u64,
+    ) -> Result<(), TxnErr<Self::GraphError>>;
+
+    /// Unmark the channel with id `channel` as soft-deleted,
+    /// returning whether it was.
+    fn del_deleted_channel(&mut self, channel: &RemoteId)
+        -> Result<bool, TxnErr<Self::GraphError>>;
 }
 
 pub(crate) fn put_inodes_with_rev<T: TreeMutTxnT>(
@@ -1961,6 +2195,11 @@ pub(crate) fn register_change<
         txn.put_revdep(&dep_internal, internal)?;
         txn.put_dep(internal, &dep_internal)?;
     }
+    for hunk in change.changes.iter() {
+        if let Hunk::Custom { ref kind, .. } = hunk {
+            txn.put_hunk_kind(kind)?;
+        }
+    }
     for hunk in change.changes.iter().flat_map(|r| r.iter()) {
         let (inode, pos) = match *hunk {
             Atom::NewVertex(NewVertex {
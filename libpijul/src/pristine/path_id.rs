@@ -5,6 +5,7 @@ use crate::small_string::*;
 /// name of the child (file or directory).
 #[doc(hidden)]
 #[derive(Debug, Hash, Eq, PartialEq, Clone, PartialOrd, Ord)]
+#[repr(C)]
 pub struct OwnedPathId {
     /// The parent of this path.
     pub parent_inode: Inode,
@@ -24,6 +25,7 @@ impl OwnedPathId {
 /// A borrow on a [`OwnedPathId`](struct.OwnedPathId.html).
 #[derive(Hash, Eq, PartialEq, Ord, PartialOrd)]
 #[doc(hidden)]
+#[repr(C)]
 pub struct PathId {
     pub parent_inode: Inode,
     pub basename: SmallStr,
@@ -0,0 +1,137 @@
+//! Removal of redundant pseudo-edges.
+//!
+//! Pseudo-edges are added when deleting lines, to keep the alive lines
+//! of a file connected. Many of them become redundant over time, when
+//! their target is also reachable from their source through another
+//! path. These are removed when a file is output, but files that are
+//! never output keep accumulating them, which slows down retrieving
+//! their graph. [`prune_pseudo`] removes them from all the files of a
+//! channel at once.
+use super::*;
+use crate::alive::{forward_edges, retrieve, Graph, VertexId};
+
+/// Summary of a pruning pass.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Number of files and directories visited.
+    pub files: usize,
+    /// Number of pseudo-edges removed.
+    pub removed: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum PruneError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Pruning changed the alive vertices of {pos:?}")]
+    AliveChanged { pos: Position<ChangeId> },
+    #[error("Pruning disconnected {to:?} from {from:?}")]
+    Disconnected {
+        from: Vertex<ChangeId>,
+        to: Vertex<ChangeId>,
+    },
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for PruneError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        PruneError::Txn(e.0)
+    }
+}
+
+/// Remove the redundant pseudo-edges of all the files of `channel`.
+/// This doesn't change the alive lines of any file, nor their order.
+pub fn prune_pseudo<T: ChannelMutTxnT + GraphMutTxnT>(
+    txn: &mut T,
+    channel: &mut T::Channel,
+) -> Result<PruneReport, PruneError<T::GraphError>> {
+    prune(txn, channel, false)
+}
+
+/// Same as [`prune_pseudo`], but also retrieve each file again after
+/// pruning it, and check that its alive vertices are unchanged, and
+/// that the target of each removed edge is still reachable from its
+/// source. This is much slower, and meant for debugging.
+///
+/// If this returns an error, the transaction should not be committed.
+pub fn prune_pseudo_checked<T: ChannelMutTxnT + GraphMutTxnT>(
+    txn: &mut T,
+    channel: &mut T::Channel,
+) -> Result<PruneReport, PruneError<T::GraphError>> {
+    prune(txn, channel, true)
+}
+
+fn prune<T: ChannelMutTxnT + GraphMutTxnT>(
+    txn: &mut T,
+    channel: &mut T::Channel,
+    check: bool,
+) -> Result<PruneReport, PruneError<T::GraphError>> {
+    let files = crate::fs::iter_graph_descendants(txn, txn.graph(channel), Position::ROOT)
+        .map_err(PruneError::Txn)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PruneError::Txn)?;
+    let mut report = PruneReport::default();
+    for pos in files {
+        report.files += 1;
+        let graph = T::graph_mut(channel);
+        let (before, forward) = forward_edges(txn, graph, pos)?;
+        let mut removed = Vec::with_capacity(forward.len());
+        for (vertex, edge) in forward {
+            let dest = *txn.find_block(graph, edge.dest()).unwrap();
+            debug!("pruning {:?} {:?} {:?}", vertex, dest, edge);
+            if del_graph_with_rev(txn, graph, edge.flag(), vertex, dest, edge.introduced_by())? {
+                removed.push((vertex, dest))
+            }
+        }
+        report.removed += removed.len();
+        if check && !removed.is_empty() {
+            let after = retrieve(txn, graph, pos)?;
+            check_pruned(pos, &before, &after, &removed)?
+        }
+    }
+    Ok(report)
+}
+
+/// Check that `after` has the same vertices as `before`, and that
+/// the removed edges were indeed redundant.
+fn check_pruned<T: std::error::Error + 'static>(
+    pos: Position<ChangeId>,
+    before: &Graph,
+    after: &Graph,
+    removed: &[(Vertex<ChangeId>, Vertex<ChangeId>)],
+) -> Result<(), PruneError<T>> {
+    let vertices =
+        |g: &Graph| -> HashSet<Vertex<ChangeId>> { g.lines.iter().map(|l| l.vertex).collect() };
+    if vertices(before) != vertices(after) {
+        return Err(PruneError::AliveChanged { pos });
+    }
+    let ids: HashMap<_, _> = after
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.vertex, VertexId(i)))
+        .collect();
+    for &(from, to) in removed {
+        let reachable = match (ids.get(&from), ids.get(&to)) {
+            (Some(&a), Some(&b)) => reaches(after, a, b),
+            _ => false,
+        };
+        if !reachable {
+            return Err(PruneError::Disconnected { from, to });
+        }
+    }
+    Ok(())
+}
+
+fn reaches(graph: &Graph, from: VertexId, to: VertexId) -> bool {
+    let mut visited = HashSet::default();
+    let mut stack = vec![from];
+    while let Some(v) = stack.pop() {
+        if v == to {
+            return true;
+        }
+        if visited.insert(v) {
+            stack.extend(graph.children(v).iter().map(|&(_, child)| child))
+        }
+    }
+    false
+}
@@ -112,6 +112,27 @@ pub enum Root {
     RevTouchedFiles,
     Partials,
     Remotes,
+    Audit,
+    Resolutions,
+    ChannelSettings,
+    InodeAllocation,
+    Journal,
+    HunkKinds,
+    Roots,
+    FileStats,
+    Provenance,
+    CiStatuses,
+    DirtyCache,
+    Notes,
+    DeletedChannels,
+}
+
+fn inode_allocation(root: u64) -> InodeAllocation {
+    if root == 1 {
+        InodeAllocation::Deterministic
+    } else {
+        InodeAllocation::Salted
+    }
 }
 
 const VERSION: L64 = L64(1u64.to_le());
@@ -137,6 +158,19 @@ impl Pristine {
                 partials: txn.root_db(Root::Partials as usize)?,
                 dep: txn.root_db(Root::Dep as usize)?,
                 remotes: txn.root_db(Root::Remotes as usize)?,
+                audit: txn.root_db(Root::Audit as usize),
+                resolutions: txn.root_db(Root::Resolutions as usize),
+                channel_settings: txn.root_db(Root::ChannelSettings as usize),
+                journal: txn.root_db(Root::Journal as usize),
+                hunk_kinds: txn.root_db(Root::HunkKinds as usize),
+                roots: txn.root_db(Root::Roots as usize),
+                file_stats: txn.root_db(Root::FileStats as usize),
+                provenance: txn.root_db(Root::Provenance as usize),
+                ci_statuses: txn.root_db(Root::CiStatuses as usize),
+                dirty_cache: txn.root_db(Root::DirtyCache as usize),
+                notes: txn.root_db(Root::Notes as usize),
+                deleted_channels: txn.root_db(Root::DeletedChannels as usize),
+                inode_allocation: inode_allocation(txn.root(Root::InodeAllocation as usize)),
                 open_channels: Mutex::new(HashMap::default()),
                 open_remotes: Mutex::new(HashMap::default()),
                 txn,
@@ -230,6 +264,69 @@ impl Pristine {
             } else {
                 btree::create_db_(&mut txn)?
             },
+            audit: if let Some(db) = txn.root_db(Root::Audit as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            resolutions: if let Some(db) = txn.root_db(Root::Resolutions as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            channel_settings: if let Some(db) = txn.root_db(Root::ChannelSettings as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            journal: if let Some(db) = txn.root_db(Root::Journal as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            hunk_kinds: if let Some(db) = txn.root_db(Root::HunkKinds as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            roots: if let Some(db) = txn.root_db(Root::Roots as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            file_stats: if let Some(db) = txn.root_db(Root::FileStats as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            provenance: if let Some(db) = txn.root_db(Root::Provenance as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            ci_statuses: if let Some(db) = txn.root_db(Root::CiStatuses as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            dirty_cache: if let Some(db) = txn.root_db(Root::DirtyCache as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            notes: if let Some(db) = txn.root_db(Root::Notes as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            deleted_channels: if let Some(db) = txn.root_db(Root::DeletedChannels as usize) {
+                Some(db)
+            } else {
+                Some(btree::create_db_(&mut txn)?)
+            },
+            inode_allocation: inode_allocation(
+                txn.root(Root::InodeAllocation as usize).unwrap_or(0),
+            ),
             open_channels: Mutex::new(HashMap::default()),
             open_remotes: Mutex::new(HashMap::default()),
             txn,
@@ -273,6 +370,42 @@ pub struct GenericTxn<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::s
     partials: UDb<SmallStr, Position<ChangeId>>,
     channels: UDb<SmallStr, SerializedChannel>,
     remotes: UDb<RemoteId, SerializedRemote>,
+    /// Absent in read-only transactions on repositories created
+    /// before the audit log was introduced.
+    audit: Option<UDb<L64, [u8]>>,
+    /// Conflict resolution sessions, by channel id. Absent in the
+    /// same cases as `audit`.
+    resolutions: Option<UDb<SmallStr, [u8]>>,
+    /// Record settings, by channel name. Absent in the same cases as
+    /// `audit`.
+    channel_settings: Option<UDb<SmallStr, [u8]>>,
+    /// Intents of unfinished operations, by channel name. Absent in
+    /// the same cases as `audit`.
+    journal: Option<UDb<SmallStr, [u8]>>,
+    /// Custom hunk kinds of the registered changes, with empty
+    /// values. Absent in the same cases as `audit`.
+    hunk_kinds: Option<UDb<SmallStr, [u8]>>,
+    /// Named roots, by channel id. Absent in the same cases as
+    /// `audit`.
+    roots: Option<UDb<SmallStr, [u8]>>,
+    /// Content statistics of the files, by inode. Absent in the same
+    /// cases as `audit`.
+    file_stats: Option<UDb<Inode, [u8]>>,
+    /// Provenance links, by rewritten change. Absent in the same
+    /// cases as `audit`.
+    provenance: Option<UDb<SerializedHash, [u8]>>,
+    /// CI statuses, by change. Absent in the same cases as `audit`.
+    ci_statuses: Option<UDb<SerializedHash, [u8]>>,
+    /// Size and modification time of the files found identical to
+    /// the pristine, by inode. Absent in the same cases as `audit`.
+    dirty_cache: Option<UDb<Inode, [u8]>>,
+    /// Notes on changes, by change. Absent in the same cases as
+    /// `audit`.
+    notes: Option<UDb<SerializedHash, [u8]>>,
+    /// Time of deletion of the soft-deleted channels, by channel id.
+    /// Absent in the same cases as `audit`.
+    deleted_channels: Option<UDb<RemoteId, L64>>,
+    inode_allocation: InodeAllocation,
 
     pub(crate) open_channels: Mutex<HashMap<SmallString, ChannelRef<Self>>>,
     open_remotes: Mutex<HashMap<RemoteId, RemoteRef<Self>>>,
@@ -359,6 +492,181 @@ impl Txn {
     }
 }
 
+/// A read-only transaction on a pristine where some tables could not
+/// be read. Only the channels whose tables are intact can be loaded.
+pub struct DegradedTxn {
+    pub txn: Txn,
+    /// Global tables that need repair.
+    pub broken_tables: Vec<Root>,
+    /// Channels with at least one unreadable table.
+    pub broken_channels: Vec<String>,
+}
+
+impl DegradedTxn {
+    /// Whether nothing needs repair.
+    pub fn is_intact(&self) -> bool {
+        self.broken_tables.is_empty() && self.broken_channels.is_empty()
+    }
+
+    /// Load channel `name`, returning `None` if it doesn't exist or
+    /// is broken.
+    pub fn load_channel(
+        &self,
+        name: &str,
+    ) -> Result<Option<ChannelRef<Txn>>, TxnErr<SanakirjaError>> {
+        if self.broken_channels.iter().any(|c| c == name) {
+            return Ok(None);
+        }
+        self.txn.load_channel(name)
+    }
+}
+
+lazy_static! {
+    static ref PAGE_HASHER: crc32fast::Hasher = crc32fast::Hasher::new();
+}
+
+/// Check that all the pages of `db` can be loaded and have the CRC
+/// they were written with. `pages` caches the results of the pages
+/// already checked, since tables may share pages.
+fn check_table<
+    T: ::sanakirja::LoadPage<Error = ::sanakirja::Error>,
+    K: ::sanakirja::Storable + ?Sized,
+    V: ::sanakirja::Storable + ?Sized,
+    P: btree::BTreePage<K, V>,
+>(
+    txn: &T,
+    db: &btree::Db_<K, V, P>,
+    pages: &mut HashMap<u64, bool>,
+) -> bool {
+    let mut stack = vec![db.db];
+    while let Some(p) = stack.pop() {
+        match pages.get(&p) {
+            Some(true) => continue,
+            Some(false) => return false,
+            None => {}
+        }
+        let page = match txn.load_page(p) {
+            Ok(page) if page.crc_check(&PAGE_HASHER) => page,
+            _ => {
+                pages.insert(p, false);
+                return false;
+            }
+        };
+        pages.insert(p, true);
+        let mut c = P::cursor_first(&page);
+        let l = P::left_child(page.as_page(), &c);
+        if l > 0 {
+            stack.push(l)
+        }
+        while let Some((_, _, r)) = P::next(txn, page.as_page(), &mut c) {
+            if r > 0 {
+                stack.push(r)
+            }
+        }
+    }
+    true
+}
+
+impl Pristine {
+    /// Open a read-only transaction even if some tables of the
+    /// pristine are corrupt, reporting which ones. The tables
+    /// needed to find changes (`Internal`, `External`) and channels
+    /// (`Channels`) must be intact; `log` and `archive` then work on
+    /// the intact channels. Output to a working copy additionally
+    /// needs the `Tree` and `Inodes` tables and their reverses.
+    ///
+    /// A table is intact if all its pages can be loaded, and still
+    /// have the CRC they were committed with.
+    pub fn txn_begin_degraded(&self) -> Result<DegradedTxn, SanakirjaError> {
+        let txn = self.txn_begin()?;
+        let mut pages = HashMap::default();
+        let mut broken_tables = Vec::new();
+        macro_rules! check {
+            ($root: expr, $db: expr) => {
+                if !check_table(&txn.txn, &$db, &mut pages) {
+                    broken_tables.push($root)
+                }
+            };
+        }
+        check!(Root::Internal, txn.internal);
+        check!(Root::External, txn.external);
+        check!(Root::Channels, txn.channels);
+        if !broken_tables.is_empty() {
+            return Err(SanakirjaError::PristineCorrupt);
+        }
+        check!(Root::Inodes, txn.inodes);
+        check!(Root::RevInodes, txn.revinodes);
+        check!(Root::Tree, txn.tree);
+        check!(Root::RevTree, txn.revtree);
+        check!(Root::RevDep, txn.revdep);
+        check!(Root::Dep, txn.dep);
+        check!(Root::TouchedFiles, txn.touched_files);
+        check!(Root::RevTouchedFiles, txn.rev_touched_files);
+        check!(Root::Partials, txn.partials);
+        check!(Root::Remotes, txn.remotes);
+        if let Some(ref audit) = txn.audit {
+            check!(Root::Audit, *audit);
+        }
+        if let Some(ref resolutions) = txn.resolutions {
+            check!(Root::Resolutions, *resolutions);
+        }
+        if let Some(ref settings) = txn.channel_settings {
+            check!(Root::ChannelSettings, *settings);
+        }
+        if let Some(ref journal) = txn.journal {
+            check!(Root::Journal, *journal);
+        }
+        if let Some(ref kinds) = txn.hunk_kinds {
+            check!(Root::HunkKinds, *kinds);
+        }
+        if let Some(ref roots) = txn.roots {
+            check!(Root::Roots, *roots);
+        }
+        if let Some(ref stats) = txn.file_stats {
+            check!(Root::FileStats, *stats);
+        }
+        if let Some(ref provenance) = txn.provenance {
+            check!(Root::Provenance, *provenance);
+        }
+        if let Some(ref statuses) = txn.ci_statuses {
+            check!(Root::CiStatuses, *statuses);
+        }
+        if let Some(ref cache) = txn.dirty_cache {
+            check!(Root::DirtyCache, *cache);
+        }
+        if let Some(ref notes) = txn.notes {
+            check!(Root::Notes, *notes);
+        }
+        if let Some(ref deleted) = txn.deleted_channels {
+            check!(Root::DeletedChannels, *deleted);
+        }
+
+        let mut broken_channels = Vec::new();
+        for x in btree::iter(&txn.txn, &txn.channels, None)? {
+            let (name, tup) = x?;
+            let graph: Db<Vertex<ChangeId>, SerializedEdge> = Db::from_page(tup.graph.into());
+            let changes: Db<ChangeId, L64> = Db::from_page(tup.changes.into());
+            let revchanges: UDb<L64, Pair<ChangeId, SerializedMerkle>> =
+                UDb::from_page(tup.revchanges.into());
+            let states: UDb<SerializedMerkle, L64> = UDb::from_page(tup.states.into());
+            let tags: UDb<L64, SerializedHash> = UDb::from_page(tup.tags.into());
+            if !check_table(&txn.txn, &graph, &mut pages)
+                || !check_table(&txn.txn, &changes, &mut pages)
+                || !check_table(&txn.txn, &revchanges, &mut pages)
+                || !check_table(&txn.txn, &states, &mut pages)
+                || !check_table(&txn.txn, &tags, &mut pages)
+            {
+                broken_channels.push(name.as_str().to_string())
+            }
+        }
+        Ok(DegradedTxn {
+            txn,
+            broken_tables,
+            broken_channels,
+        })
+    }
+}
+
 impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GraphTxnT
     for GenericTxn<T>
 {
@@ -1008,6 +1316,18 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         P<ChangeId, Position<ChangeId>>,
     >;
     sanakirja_iter!(rev_touched_files, ChangeId, Position<ChangeId>);
+
+    fn hunk_kinds(&self) -> Result<Vec<String>, TxnErr<Self::DepsError>> {
+        let mut kinds = Vec::new();
+        if let Some(ref db) = self.hunk_kinds {
+            for x in btree::iter(&self.txn, db, None)? {
+                let (k, _) = x?;
+                kinds.push(k.as_str().to_string())
+            }
+        }
+        Ok(kinds)
+    }
+
     fn iter_revdep(
         &self,
         k: &ChangeId,
@@ -1053,6 +1373,9 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
     for GenericTxn<T>
 {
     type TreeError = SanakirjaError;
+    fn inode_allocation(&self) -> InodeAllocation {
+        self.inode_allocation
+    }
     type Inodes = Db<Inode, Position<ChangeId>>;
     type Revinodes = Db<Position<ChangeId>, Inode>;
     sanakirja_table_get!(inodes, Inode, Position<ChangeId>, TreeError);
@@ -1112,6 +1435,18 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
         let k0 = SmallString::from_str(k);
         self.cursor_partials(&self.partials, Some((&k0, None)))
     }
+
+    fn get_dirty_cache(&self, inode: Inode) -> Result<Option<&[u8]>, TxnErr<Self::TreeError>> {
+        let cache = if let Some(ref c) = self.dirty_cache {
+            c
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, cache, &inode, None)? {
+            Some((k, v)) if *k == inode => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
 }
 
 impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> GenericTxn<T> {
@@ -1445,6 +1780,212 @@ impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPag
             _ => Ok(false),
         }
     }
+    fn get_audit(&self, n: u64) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let audit = if let Some(ref audit) = self.audit {
+            audit
+        } else {
+            return Ok(None);
+        };
+        let n: L64 = n.into();
+        match btree::get(&self.txn, audit, &n, None)? {
+            Some((k, v)) if *k == n => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn audit_len(&self) -> Result<u64, TxnErr<Self::GraphError>> {
+        let audit = if let Some(ref audit) = self.audit {
+            audit
+        } else {
+            return Ok(0);
+        };
+        if let Some(x) = btree::rev_iter(&self.txn, audit, None)?.next() {
+            let (k, _) = x?;
+            Ok(u64::from(*k) + 1)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn get_resolution(
+        &self,
+        channel: &RemoteId,
+    ) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let resolutions = if let Some(ref r) = self.resolutions {
+            r
+        } else {
+            return Ok(None);
+        };
+        let name = SmallString::from_str(&channel.to_string());
+        match btree::get(&self.txn, resolutions, &name, None)? {
+            Some((k, v)) if k == name.as_ref() => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_channel_settings(
+        &self,
+        channel: &RemoteId,
+    ) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let settings = if let Some(ref s) = self.channel_settings {
+            s
+        } else {
+            return Ok(None);
+        };
+        let name = SmallString::from_str(&channel.to_string());
+        match btree::get(&self.txn, settings, &name, None)? {
+            Some((k, v)) if k == name.as_ref() => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_journal(&self, channel: &str) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let journal = if let Some(ref j) = self.journal {
+            j
+        } else {
+            return Ok(None);
+        };
+        let name = SmallString::from_str(channel);
+        match btree::get(&self.txn, journal, &name, None)? {
+            Some((k, v)) if k == name.as_ref() => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_roots(&self, channel: &RemoteId) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let roots = if let Some(ref r) = self.roots {
+            r
+        } else {
+            return Ok(None);
+        };
+        let name = SmallString::from_str(&channel.to_string());
+        match btree::get(&self.txn, roots, &name, None)? {
+            Some((k, v)) if k == name.as_ref() => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_file_stats(&self, inode: Inode) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let stats = if let Some(ref s) = self.file_stats {
+            s
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, stats, &inode, None)? {
+            Some((k, v)) if *k == inode => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_file_stats(&self) -> Result<Vec<(Inode, &[u8])>, TxnErr<Self::GraphError>> {
+        let mut result = Vec::new();
+        if let Some(ref stats) = self.file_stats {
+            for x in btree::iter(&self.txn, stats, None)? {
+                let (k, v) = x?;
+                result.push((*k, v))
+            }
+        }
+        Ok(result)
+    }
+
+    fn get_provenance(&self, old: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let provenance = if let Some(ref p) = self.provenance {
+            p
+        } else {
+            return Ok(None);
+        };
+        let key: SerializedHash = old.into();
+        match btree::get(&self.txn, provenance, &key, None)? {
+            Some((k, v)) if *k == key => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_provenance(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>> {
+        let mut result = Vec::new();
+        if let Some(ref provenance) = self.provenance {
+            for x in btree::iter(&self.txn, provenance, None)? {
+                let (k, v) = x?;
+                result.push((k.into(), v))
+            }
+        }
+        Ok(result)
+    }
+
+    fn get_ci_statuses(&self, change: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let statuses = if let Some(ref s) = self.ci_statuses {
+            s
+        } else {
+            return Ok(None);
+        };
+        let key: SerializedHash = change.into();
+        match btree::get(&self.txn, statuses, &key, None)? {
+            Some((k, v)) if *k == key => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_ci_statuses(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>> {
+        let mut result = Vec::new();
+        if let Some(ref statuses) = self.ci_statuses {
+            for x in btree::iter(&self.txn, statuses, None)? {
+                let (k, v) = x?;
+                result.push((k.into(), v))
+            }
+        }
+        Ok(result)
+    }
+
+    fn get_notes(&self, change: &Hash) -> Result<Option<&[u8]>, TxnErr<Self::GraphError>> {
+        let notes = if let Some(ref n) = self.notes {
+            n
+        } else {
+            return Ok(None);
+        };
+        let key: SerializedHash = change.into();
+        match btree::get(&self.txn, notes, &key, None)? {
+            Some((k, v)) if *k == key => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_notes(&self) -> Result<Vec<(Hash, &[u8])>, TxnErr<Self::GraphError>> {
+        let mut result = Vec::new();
+        if let Some(ref notes) = self.notes {
+            for x in btree::iter(&self.txn, notes, None)? {
+                let (k, v) = x?;
+                result.push((k.into(), v))
+            }
+        }
+        Ok(result)
+    }
+
+    fn get_deleted_channel(
+        &self,
+        channel: &RemoteId,
+    ) -> Result<Option<u64>, TxnErr<Self::GraphError>> {
+        let deleted = if let Some(ref d) = self.deleted_channels {
+            d
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, deleted, channel, None)? {
+            Some((k, v)) if k == channel => Ok(Some((*v).into())),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_deleted_channels(&self) -> Result<Vec<(RemoteId, u64)>, TxnErr<Self::GraphError>> {
+        let mut result = Vec::new();
+        if let Some(ref deleted) = self.deleted_channels {
+            for x in btree::iter(&self.txn, deleted, None)? {
+                let (k, v) = x?;
+                result.push((*k, (*v).into()))
+            }
+        }
+        Ok(result)
+    }
+
     fn current_channel(&self) -> Result<&str, Self::GraphError> {
         if let Some(ref c) = self.cur_channel {
             Ok(c)
@@ -1617,6 +2158,7 @@ impl ChannelMutTxnT for MutTxn<()> {
                 &t.into(),
                 &Pair { a: p, b: m.into() }
             )?);
+            btree::put(&mut self.txn, &mut channel.states, &m.into(), &t.into())?;
             Ok(Some(m.into()))
         }
     }
@@ -1632,7 +2174,7 @@ impl ChannelMutTxnT for MutTxn<()> {
         for x in btree::iter(&self.txn, &channel.revchanges, Some((&tl, None)))? {
             let (t_, p) = x?;
             if *t_ >= tl {
-                repl.push((*t_, p.a))
+                repl.push((*t_, p.a, p.b))
             }
         }
         let mut m = Merkle::zero();
@@ -1643,9 +2185,10 @@ impl ChannelMutTxnT for MutTxn<()> {
                 break;
             }
         }
-        for (t_, p) in repl.iter() {
+        for (t_, p, old) in repl.iter() {
             debug!("del_changes {:?} {:?}", t_, p);
             btree::del(&mut self.txn, &mut channel.revchanges, t_, None)?;
+            btree::del(&mut self.txn, &mut channel.states, old, None)?;
             if *t_ > tl {
                 m = m.next(&self.get_external(p)?.unwrap().into());
                 btree::put(
@@ -1654,6 +2197,7 @@ impl ChannelMutTxnT for MutTxn<()> {
                     t_,
                     &Pair { a: *p, b: m.into() },
                 )?;
+                btree::put(&mut self.txn, &mut channel.states, &m.into(), t_)?;
             }
         }
         btree::del(&mut self.txn, &mut channel.tags, &t.into(), None)?;
@@ -1690,9 +2234,22 @@ impl DepsMutTxnT for MutTxn<()> {
     sanakirja_put_del!(revdep, ChangeId, ChangeId, DepsError);
     sanakirja_put_del!(touched_files, Position<ChangeId>, ChangeId, DepsError);
     sanakirja_put_del!(rev_touched_files, ChangeId, Position<ChangeId>, DepsError);
+
+    fn put_hunk_kind(&mut self, kind: &str) -> Result<(), TxnErr<Self::DepsError>> {
+        let name = SmallString::from_str(kind);
+        let db = self.hunk_kinds.as_mut().unwrap();
+        // Empty slices can't be stored as values.
+        let present: &[u8] = &[1];
+        btree::del(&mut self.txn, db, &name, None)?;
+        btree::put(&mut self.txn, db, &name, present)?;
+        Ok(())
+    }
 }
 
 impl TreeMutTxnT for MutTxn<()> {
+    fn set_inode_allocation(&mut self, allocation: InodeAllocation) {
+        self.inode_allocation = allocation
+    }
     sanakirja_put_del!(inodes, Inode, Position<ChangeId>, TreeError);
     sanakirja_put_del!(revinodes, Position<ChangeId>, Inode, TreeError);
 
@@ -1721,9 +2278,188 @@ impl TreeMutTxnT for MutTxn<()> {
             e.as_ref(),
         )?)
     }
+
+    fn put_dirty_cache(
+        &mut self,
+        inode: Inode,
+        entry: &[u8],
+    ) -> Result<(), TxnErr<Self::TreeError>> {
+        self.del_dirty_cache(inode)?;
+        let db = self.dirty_cache.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &inode, entry)?;
+        Ok(())
+    }
+
+    fn del_dirty_cache(&mut self, inode: Inode) -> Result<bool, TxnErr<Self::TreeError>> {
+        let db = self.dirty_cache.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &inode, None)?)
+    }
 }
 
 impl MutTxnT for MutTxn<()> {
+    fn put_audit(&mut self, entry: &[u8]) -> Result<u64, TxnErr<Self::GraphError>> {
+        let n = self.audit_len()?;
+        let audit = self.audit.as_mut().unwrap();
+        btree::put(&mut self.txn, audit, &n.into(), entry)?;
+        Ok(n)
+    }
+
+    fn put_resolution(
+        &mut self,
+        channel: &RemoteId,
+        session: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_resolution(channel)?;
+        let name = SmallString::from_str(&channel.to_string());
+        let resolutions = self.resolutions.as_mut().unwrap();
+        btree::put(&mut self.txn, resolutions, &name, session)?;
+        Ok(())
+    }
+
+    fn del_resolution(&mut self, channel: &RemoteId) -> Result<bool, TxnErr<Self::GraphError>> {
+        let name = SmallString::from_str(&channel.to_string());
+        let resolutions = self.resolutions.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, resolutions, &name, None)?)
+    }
+
+    fn put_channel_settings(
+        &mut self,
+        channel: &RemoteId,
+        settings: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_channel_settings(channel)?;
+        let name = SmallString::from_str(&channel.to_string());
+        let db = self.channel_settings.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &name, settings)?;
+        Ok(())
+    }
+
+    fn del_channel_settings(
+        &mut self,
+        channel: &RemoteId,
+    ) -> Result<bool, TxnErr<Self::GraphError>> {
+        let name = SmallString::from_str(&channel.to_string());
+        let db = self.channel_settings.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &name, None)?)
+    }
+
+    fn put_journal(
+        &mut self,
+        channel: &str,
+        intent: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_journal(channel)?;
+        let name = SmallString::from_str(channel);
+        let db = self.journal.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &name, intent)?;
+        Ok(())
+    }
+
+    fn del_journal(&mut self, channel: &str) -> Result<bool, TxnErr<Self::GraphError>> {
+        let name = SmallString::from_str(channel);
+        let db = self.journal.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &name, None)?)
+    }
+
+    fn put_roots(
+        &mut self,
+        channel: &RemoteId,
+        roots: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_roots(channel)?;
+        let name = SmallString::from_str(&channel.to_string());
+        let db = self.roots.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &name, roots)?;
+        Ok(())
+    }
+
+    fn del_roots(&mut self, channel: &RemoteId) -> Result<bool, TxnErr<Self::GraphError>> {
+        let name = SmallString::from_str(&channel.to_string());
+        let db = self.roots.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &name, None)?)
+    }
+
+    fn put_file_stats(
+        &mut self,
+        inode: Inode,
+        stats: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_file_stats(inode)?;
+        let db = self.file_stats.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &inode, stats)?;
+        Ok(())
+    }
+
+    fn del_file_stats(&mut self, inode: Inode) -> Result<bool, TxnErr<Self::GraphError>> {
+        let db = self.file_stats.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &inode, None)?)
+    }
+
+    fn put_provenance(&mut self, old: &Hash, links: &[u8]) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_provenance(old)?;
+        let key: SerializedHash = old.into();
+        let db = self.provenance.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &key, links)?;
+        Ok(())
+    }
+
+    fn del_provenance(&mut self, old: &Hash) -> Result<bool, TxnErr<Self::GraphError>> {
+        let key: SerializedHash = old.into();
+        let db = self.provenance.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &key, None)?)
+    }
+
+    fn put_ci_statuses(
+        &mut self,
+        change: &Hash,
+        statuses: &[u8],
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_ci_statuses(change)?;
+        let key: SerializedHash = change.into();
+        let db = self.ci_statuses.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &key, statuses)?;
+        Ok(())
+    }
+
+    fn del_ci_statuses(&mut self, change: &Hash) -> Result<bool, TxnErr<Self::GraphError>> {
+        let key: SerializedHash = change.into();
+        let db = self.ci_statuses.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &key, None)?)
+    }
+
+    fn put_notes(&mut self, change: &Hash, notes: &[u8]) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_notes(change)?;
+        let key: SerializedHash = change.into();
+        let db = self.notes.as_mut().unwrap();
+        btree::put(&mut self.txn, db, &key, notes)?;
+        Ok(())
+    }
+
+    fn del_notes(&mut self, change: &Hash) -> Result<bool, TxnErr<Self::GraphError>> {
+        let key: SerializedHash = change.into();
+        let db = self.notes.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, &key, None)?)
+    }
+
+    fn put_deleted_channel(
+        &mut self,
+        channel: &RemoteId,
+        deleted_at: u64,
+    ) -> Result<(), TxnErr<Self::GraphError>> {
+        self.del_deleted_channel(channel)?;
+        let db = self.deleted_channels.as_mut().unwrap();
+        btree::put(&mut self.txn, db, channel, &deleted_at.into())?;
+        Ok(())
+    }
+
+    fn del_deleted_channel(
+        &mut self,
+        channel: &RemoteId,
+    ) -> Result<bool, TxnErr<Self::GraphError>> {
+        let db = self.deleted_channels.as_mut().unwrap();
+        Ok(btree::del(&mut self.txn, db, channel, None)?)
+    }
+
     fn put_remote(
         &mut self,
         remote: &mut RemoteRef<Self>,
@@ -1866,6 +2602,10 @@ impl MutTxnT for MutTxn<()> {
                     })),
                 };
                 self.open_channels.lock().insert(name, br.clone());
+                // Make the new channel visible to `iter_channels` before
+                // this transaction is committed.
+                self.put_channel(br.clone())
+                    .map_err(|e| ForkError::Txn(e.into()))?;
                 Ok(br)
             }
         }
@@ -2045,12 +2785,14 @@ impl MutTxnT for MutTxn<()> {
 
     fn commit(mut self) -> Result<(), Self::GraphError> {
         use std::ops::DerefMut;
+        let mut committed = Vec::new();
         {
             let open_channels =
                 std::mem::replace(self.open_channels.lock().deref_mut(), HashMap::default());
             for (name, channel) in open_channels {
                 debug!("commit_channel {:?}", name);
-                self.commit_channel(channel)?
+                self.commit_channel(channel)?;
+                committed.push(name.as_str().to_string())
             }
         }
         {
@@ -2086,7 +2828,53 @@ impl MutTxnT for MutTxn<()> {
         self.txn
             .set_root(Root::RevTouchedFiles as usize, self.rev_touched_files.db);
         self.txn.set_root(Root::Partials as usize, self.partials.db);
+        if let Some(ref audit) = self.audit {
+            self.txn.set_root(Root::Audit as usize, audit.db);
+        }
+        if let Some(ref resolutions) = self.resolutions {
+            self.txn
+                .set_root(Root::Resolutions as usize, resolutions.db);
+        }
+        if let Some(ref settings) = self.channel_settings {
+            self.txn
+                .set_root(Root::ChannelSettings as usize, settings.db);
+        }
+        if let Some(ref journal) = self.journal {
+            self.txn.set_root(Root::Journal as usize, journal.db);
+        }
+        if let Some(ref kinds) = self.hunk_kinds {
+            self.txn.set_root(Root::HunkKinds as usize, kinds.db);
+        }
+        if let Some(ref roots) = self.roots {
+            self.txn.set_root(Root::Roots as usize, roots.db);
+        }
+        if let Some(ref stats) = self.file_stats {
+            self.txn.set_root(Root::FileStats as usize, stats.db);
+        }
+        if let Some(ref provenance) = self.provenance {
+            self.txn.set_root(Root::Provenance as usize, provenance.db);
+        }
+        if let Some(ref statuses) = self.ci_statuses {
+            self.txn.set_root(Root::CiStatuses as usize, statuses.db);
+        }
+        if let Some(ref cache) = self.dirty_cache {
+            self.txn.set_root(Root::DirtyCache as usize, cache.db);
+        }
+        if let Some(ref notes) = self.notes {
+            self.txn.set_root(Root::Notes as usize, notes.db);
+        }
+        if let Some(ref deleted) = self.deleted_channels {
+            self.txn
+                .set_root(Root::DeletedChannels as usize, deleted.db);
+        }
+        let allocation = match self.inode_allocation {
+            InodeAllocation::Salted => 0,
+            InodeAllocation::Deterministic => 1,
+        };
+        self.txn
+            .set_root(Root::InodeAllocation as usize, allocation);
         self.txn.commit()?;
+        crate::channel::notify(&committed);
         Ok(())
     }
 
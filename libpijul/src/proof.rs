@@ -0,0 +1,119 @@
+//! Compact proofs about channel states.
+//!
+//! The state of a channel is obtained by folding `Merkle::next` over
+//! the hashes of its changes, in the order in which they were
+//! applied. This means that anyone knowing an intermediate state and
+//! the list of changes applied after it can recompute the final
+//! state, without access to the pristine. The proofs in this module
+//! are exactly that.
+use crate::pristine::*;
+
+/// A proof that a change is included in a channel state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    /// State of the channel just before the change was applied.
+    pub previous: Merkle,
+    /// The change this proof is about.
+    pub change: Hash,
+    /// Changes applied after `change`, in order.
+    pub suffix: Vec<Hash>,
+}
+
+impl InclusionProof {
+    /// Check that this proof leads to `state`.
+    pub fn verify(&self, state: &Merkle) -> bool {
+        let mut m = self.previous.next(&self.change);
+        for h in self.suffix.iter() {
+            m = m.next(h)
+        }
+        m == *state
+    }
+}
+
+/// A proof that a state is obtained by applying changes on top of
+/// an earlier state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionProof {
+    /// Changes applied after the earlier state, in order.
+    pub changes: Vec<Hash>,
+}
+
+impl ExtensionProof {
+    /// Check that applying the changes of this proof to `old` yields
+    /// `new`.
+    pub fn verify(&self, old: &Merkle, new: &Merkle) -> bool {
+        let mut m = *old;
+        for h in self.changes.iter() {
+            m = m.next(h)
+        }
+        m == *new
+    }
+}
+
+/// Hashes of the changes of `channel` applied strictly after
+/// position `n`, in order.
+fn changes_after<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    n: Option<u64>,
+) -> Result<Vec<Hash>, TxnErr<T::GraphError>> {
+    let from = n.map(|n| n + 1).unwrap_or(0);
+    let mut result = Vec::new();
+    for x in changeid_log(txn, channel, L64(from.to_le()))? {
+        let (_, p) = x?;
+        let h = txn.get_external(&p.a)?.unwrap();
+        result.push(h.into())
+    }
+    Ok(result)
+}
+
+/// Produce a proof that change `hash` is included in the current
+/// state of `channel`, or `None` if `hash` isn't on the channel.
+pub fn inclusion_proof<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    hash: &Hash,
+) -> Result<Option<InclusionProof>, TxnErr<T::GraphError>> {
+    let id = if let Some(id) = txn.get_internal(&hash.into())? {
+        *id
+    } else {
+        return Ok(None);
+    };
+    let n = if let Some(n) = txn.get_changeset(txn.changes(channel), &id)? {
+        u64::from_le(n.0)
+    } else {
+        return Ok(None);
+    };
+    let mut previous = Merkle::zero();
+    for x in txn.rev_cursor_revchangeset(txn.rev_changes(channel), Some(L64(n.to_le())))? {
+        let (m, p) = x?;
+        if u64::from_le(m.0) < n {
+            previous = (&p.b).into();
+            break;
+        }
+    }
+    Ok(Some(InclusionProof {
+        previous,
+        change: *hash,
+        suffix: changes_after(txn, channel, Some(n))?,
+    }))
+}
+
+/// Produce a proof that the current state of `channel` extends
+/// `old`, or `None` if `old` was never a state of `channel`.
+pub fn extension_proof<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    old: &Merkle,
+) -> Result<Option<ExtensionProof>, TxnErr<T::GraphError>> {
+    let n = if *old == Merkle::zero() {
+        None
+    } else if let Some(n) = txn.channel_has_state(txn.states(channel), &old.into())? {
+        Some(u64::from_le(n.0))
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(ExtensionProof {
+        changes: changes_after(txn, channel, n)?,
+    }))
+}
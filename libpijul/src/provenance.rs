@@ -0,0 +1,315 @@
+//! Provenance of the changes produced by rewriting other changes.
+//!
+//! Amending a change, splitting it or rewriting it with a filter
+//! replaces it by one or more new changes with different hashes. A
+//! [`ProvenanceLink`] records each such replacement, along with the
+//! operation and its author, so that the history of a rewritten
+//! change can still be audited once the old change is gone, and so
+//! that a repository pulling from a remote can tell which of its
+//! changes were replaced there.
+//!
+//! Links are stored in the pristine, by rewritten change. They are
+//! never deleted, and adding a link twice has no effect, which makes
+//! [`import`] suitable for exchanging them with remotes. Once the
+//! links and the new changes are known, [`migrate`] removes the
+//! rewritten changes from a channel.
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+use crate::unrecord::UnrecordError;
+use crate::{HashMap, HashSet, MutTxnTExt, TxnTExt};
+
+/// The operation that replaced a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProvenanceOperation {
+    Amend,
+    Split,
+    /// Rewritten by a filter, for instance to remove a file from the
+    /// history.
+    Rewrite,
+}
+
+impl std::fmt::Display for ProvenanceOperation {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProvenanceOperation::Amend => write!(fmt, "amend"),
+            ProvenanceOperation::Split => write!(fmt, "split"),
+            ProvenanceOperation::Rewrite => write!(fmt, "rewrite"),
+        }
+    }
+}
+
+/// Change `old` was replaced by change `new`. A change split in
+/// several parts has one link for each part.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceLink {
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub old: Hash,
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub new: Hash,
+    pub operation: ProvenanceOperation,
+    /// Identity of the author of the operation.
+    pub actor: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl ProvenanceLink {
+    pub fn new(old: Hash, new: Hash, operation: ProvenanceOperation, actor: &str) -> Self {
+        ProvenanceLink {
+            old,
+            new,
+            operation,
+            actor: actor.to_string(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    /// Whether `self` and `other` record the same replacement,
+    /// regardless of who recorded it and when.
+    fn same(&self, other: &ProvenanceLink) -> bool {
+        self.old == other.old && self.new == other.new && self.operation == other.operation
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProvenanceError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed provenance links for change {}", .0.to_base32())]
+    Malformed(Hash),
+    #[error("A change cannot replace itself: {}", .0.to_base32())]
+    Cycle(Hash),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for ProvenanceError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        ProvenanceError::Txn(e.0)
+    }
+}
+
+/// The links from change `old` to the changes that replaced it.
+pub fn successors<T: TxnT>(
+    txn: &T,
+    old: &Hash,
+) -> Result<Vec<ProvenanceLink>, ProvenanceError<T::GraphError>> {
+    if let Some(bytes) = txn.get_provenance(old)? {
+        Ok(bincode::deserialize(bytes).map_err(|_| ProvenanceError::Malformed(*old))?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// All the links, oldest first.
+pub fn list<T: TxnT>(txn: &T) -> Result<Vec<ProvenanceLink>, ProvenanceError<T::GraphError>> {
+    let mut result = Vec::new();
+    for (old, bytes) in txn.iter_provenance()? {
+        let links: Vec<ProvenanceLink> =
+            bincode::deserialize(bytes).map_err(|_| ProvenanceError::Malformed(old))?;
+        result.extend(links.into_iter())
+    }
+    result.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    Ok(result)
+}
+
+/// The links to change `new` from the changes it replaced.
+pub fn predecessors<T: TxnT>(
+    txn: &T,
+    new: &Hash,
+) -> Result<Vec<ProvenanceLink>, ProvenanceError<T::GraphError>> {
+    let mut result = list(txn)?;
+    result.retain(|l| l.new == *new);
+    Ok(result)
+}
+
+/// All the links leading to change `hash`, directly or through other
+/// rewritten changes, oldest first.
+pub fn ancestry<T: TxnT>(
+    txn: &T,
+    hash: &Hash,
+) -> Result<Vec<ProvenanceLink>, ProvenanceError<T::GraphError>> {
+    let mut by_new: HashMap<Hash, Vec<ProvenanceLink>> = HashMap::default();
+    for l in list(txn)? {
+        by_new.entry(l.new).or_default().push(l)
+    }
+    let mut result = Vec::new();
+    let mut visited = HashSet::default();
+    let mut stack = vec![*hash];
+    while let Some(h) = stack.pop() {
+        if !visited.insert(h) {
+            continue;
+        }
+        if let Some(links) = by_new.remove(&h) {
+            for l in links {
+                stack.push(l.old);
+                result.push(l)
+            }
+        }
+    }
+    result.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    Ok(result)
+}
+
+/// The changes that `hash` was eventually replaced by, following the
+/// links until changes that weren't rewritten. This is `[hash]` if
+/// `hash` was never rewritten.
+pub fn latest<T: TxnT>(txn: &T, hash: &Hash) -> Result<Vec<Hash>, ProvenanceError<T::GraphError>> {
+    let mut result = Vec::new();
+    let mut visited = HashSet::default();
+    let mut stack = vec![*hash];
+    while let Some(h) = stack.pop() {
+        if !visited.insert(h) {
+            continue;
+        }
+        let succ = successors(txn, &h)?;
+        if succ.is_empty() {
+            result.push(h)
+        } else {
+            stack.extend(succ.into_iter().map(|l| l.new))
+        }
+    }
+    Ok(result)
+}
+
+/// Add `link`, returning `false` if the same replacement was already
+/// known.
+pub fn add<T: MutTxnT>(
+    txn: &mut T,
+    link: ProvenanceLink,
+) -> Result<bool, ProvenanceError<T::GraphError>> {
+    if link.old == link.new {
+        return Err(ProvenanceError::Cycle(link.old));
+    }
+    let mut links = successors(txn, &link.old)?;
+    if links.iter().any(|l| l.same(&link)) {
+        return Ok(false);
+    }
+    let old = link.old;
+    links.push(link);
+    txn.put_provenance(&old, &bincode::serialize(&links).unwrap())?;
+    Ok(true)
+}
+
+/// Record that `old` was replaced by all the changes in `new`, for
+/// instance the parts of a split change. Returns the number of links
+/// that weren't already known.
+pub fn replace<T: MutTxnT>(
+    txn: &mut T,
+    old: Hash,
+    new: &[Hash],
+    operation: ProvenanceOperation,
+    actor: &str,
+) -> Result<usize, ProvenanceError<T::GraphError>> {
+    let mut added = 0;
+    for n in new {
+        if add(txn, ProvenanceLink::new(old, *n, operation, actor))? {
+            added += 1
+        }
+    }
+    Ok(added)
+}
+
+/// Add the links of another repository, returning the number of
+/// links that weren't already known.
+pub fn import<T: MutTxnT>(
+    txn: &mut T,
+    links: Vec<ProvenanceLink>,
+) -> Result<usize, ProvenanceError<T::GraphError>> {
+    let mut added = 0;
+    for l in links {
+        if add(txn, l)? {
+            added += 1
+        }
+    }
+    Ok(added)
+}
+
+/// Write `links` in the format read by [`read_list`], to send them
+/// to another repository.
+pub fn write_list<W: std::io::Write>(
+    links: &[ProvenanceLink],
+    w: W,
+) -> Result<(), serde_json::Error> {
+    serde_json::to_writer(w, links)
+}
+
+/// Parse a list of links written by [`write_list`].
+pub fn read_list(list: &[u8]) -> Result<Vec<ProvenanceLink>, serde_json::Error> {
+    serde_json::from_slice(list)
+}
+
+/// A change of a channel that was rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub old: Hash,
+    /// The latest changes replacing `old`, as returned by [`latest`].
+    pub new: Vec<Hash>,
+}
+
+/// The rewritten changes of `channel`, most recently applied first.
+pub fn migrations<T: TxnTExt>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<Vec<Migration>, ProvenanceError<T::GraphError>> {
+    let mut rewritten = Vec::new();
+    for x in txn
+        .reverse_log(channel, None)
+        .map_err(ProvenanceError::Txn)?
+    {
+        let (_, (h, _)) = x.map_err(ProvenanceError::Txn)?;
+        let h: Hash = h.into();
+        if txn.get_provenance(&h)?.is_some() {
+            rewritten.push(h)
+        }
+    }
+    let mut result = Vec::with_capacity(rewritten.len());
+    for old in rewritten {
+        let new = latest(txn, &old)?;
+        result.push(Migration { old, new })
+    }
+    Ok(result)
+}
+
+#[derive(Debug, Error)]
+pub enum MigrateError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Provenance(#[from] ProvenanceError<T>),
+    #[error(transparent)]
+    Unrecord(#[from] UnrecordError<C, T>),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for MigrateError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        MigrateError::Provenance(e.into())
+    }
+}
+
+/// Unrecord from `channel` the rewritten changes all of whose
+/// replacements are on `channel`, returning the changes unrecorded.
+/// Changes still depended upon by other changes of the channel are
+/// left in place.
+pub fn migrate<T: MutTxnT + MutTxnTExt + TxnTExt, C: ChangeStore>(
+    txn: &mut T,
+    changes: &C,
+    channel: &ChannelRef<T>,
+) -> Result<Vec<Hash>, MigrateError<C::Error, T::GraphError>> {
+    let migrations = migrations(txn, &*channel.read())?;
+    let mut migrated = Vec::new();
+    'outer: for m in migrations {
+        for n in m.new.iter() {
+            let on_channel = txn
+                .get_revchanges(channel, n)
+                .map_err(ProvenanceError::Txn)?;
+            if on_channel.is_none() {
+                continue 'outer;
+            }
+        }
+        match txn.unrecord(changes, channel, &m.old, 0) {
+            Ok(_) => migrated.push(m.old),
+            Err(UnrecordError::ChangeIsDependedUpon { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(migrated)
+}
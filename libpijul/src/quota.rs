@@ -0,0 +1,181 @@
+//! Space used by changes in the change store, and quotas.
+//!
+//! Changes are stored only once, even when several channels contain
+//! them. [`usage`] reports, for each channel, the total size of its
+//! changes, the size of the changes found in no other channel, and
+//! its share of the store, where each change is split evenly between
+//! the channels containing it.
+//!
+//! Servers can reject pushes exceeding a [`Quota`] with
+//! [`check_quota`], or call [`Quota::allows`] directly on changes
+//! they haven't stored yet.
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+use crate::{HashMap, HashSet};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Error)]
+pub enum QuotaError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Changestore error: {0}")]
+    Changestore(C),
+    #[error("Quota exceeded: {used} bytes used, {additional} bytes added")]
+    Exceeded { used: u64, additional: u64 },
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for QuotaError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        QuotaError::Txn(e.0)
+    }
+}
+
+/// Space used by a channel, in bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelUsage {
+    /// Number of changes in the channel.
+    pub changes: usize,
+    /// Total size of the changes in the channel.
+    pub total: u64,
+    /// Size of the changes found in no other channel.
+    pub exclusive: u64,
+    /// Size of the changes in the channel, where each change is
+    /// split evenly between the channels containing it.
+    pub attributed: f64,
+}
+
+/// Space used by the changes of a repository.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    /// Total size of the changes in at least one channel, each
+    /// counted once.
+    pub total: u64,
+    pub channels: BTreeMap<String, ChannelUsage>,
+    /// Size of each change in at least one channel.
+    pub sizes: HashMap<Hash, u64>,
+}
+
+/// Space used by a change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeUsage {
+    /// Size of the change alone.
+    pub size: u64,
+    /// Size of the change and all its transitive dependencies, each
+    /// counted once.
+    pub with_dependencies: u64,
+}
+
+/// Compute the space used by the changes of each channel.
+pub fn usage<T: TxnT, P: ChangeStore>(
+    txn: &T,
+    changes: &P,
+) -> Result<Usage, QuotaError<P::Error, T::GraphError>> {
+    let mut channels = Vec::new();
+    let mut n_channels = HashMap::default();
+    for c in txn.iter_channels("")? {
+        let (name, c) = c?;
+        let c = c.read();
+        let mut hashes = Vec::new();
+        for x in changeid_log(txn, &*c, L64(0))? {
+            let (_, p) = x?;
+            let h: Hash = txn.get_external(&p.a)?.unwrap().into();
+            *n_channels.entry(h).or_insert(0usize) += 1;
+            hashes.push(h)
+        }
+        channels.push((name.as_str().to_string(), hashes))
+    }
+    let mut usage = Usage::default();
+    for (&h, _) in n_channels.iter() {
+        let size = changes.get_size(&h).map_err(QuotaError::Changestore)?;
+        usage.total += size;
+        usage.sizes.insert(h, size);
+    }
+    for (name, hashes) in channels {
+        let mut c = ChannelUsage {
+            changes: hashes.len(),
+            ..ChannelUsage::default()
+        };
+        for h in hashes {
+            let size = usage.sizes[&h];
+            let n = n_channels[&h];
+            c.total += size;
+            if n == 1 {
+                c.exclusive += size
+            }
+            c.attributed += size as f64 / n as f64;
+        }
+        usage.channels.insert(name, c);
+    }
+    Ok(usage)
+}
+
+/// Compute the space used by change `hash`, alone and with its
+/// dependencies.
+pub fn change_usage<P: ChangeStore>(changes: &P, hash: &Hash) -> Result<ChangeUsage, P::Error> {
+    let size = changes.get_size(hash)?;
+    let mut with_dependencies = 0;
+    let mut visited = HashSet::default();
+    let mut stack = vec![*hash];
+    while let Some(h) = stack.pop() {
+        if h == Hash::None || !visited.insert(h) {
+            continue;
+        }
+        with_dependencies += changes.get_size(&h)?;
+        stack.extend(changes.get_dependencies(&h)?)
+    }
+    Ok(ChangeUsage {
+        size,
+        with_dependencies,
+    })
+}
+
+/// A limit on the space used by a repository.
+pub trait Quota {
+    /// Whether `additional` bytes of changes can be added to a
+    /// repository currently using `usage`.
+    fn allows(&self, usage: &Usage, additional: u64) -> bool;
+}
+
+/// A maximal total size, in bytes.
+impl Quota for u64 {
+    fn allows(&self, usage: &Usage, additional: u64) -> bool {
+        usage.total.saturating_add(additional) <= *self
+    }
+}
+
+/// Check that adding `hashes` and their dependencies to the channels
+/// of the repository stays within `quota`. All these changes must be
+/// in `changes`. Returns the number of bytes added, i.e. the size of
+/// the changes not already in a channel.
+pub fn check_quota<T: TxnT, P: ChangeStore, Q: Quota + ?Sized>(
+    txn: &T,
+    changes: &P,
+    hashes: &[Hash],
+    quota: &Q,
+) -> Result<u64, QuotaError<P::Error, T::GraphError>> {
+    let usage = usage(txn, changes)?;
+    let mut additional = 0;
+    let mut visited = HashSet::default();
+    let mut stack = hashes.to_vec();
+    while let Some(h) = stack.pop() {
+        if h == Hash::None || usage.sizes.contains_key(&h) || !visited.insert(h) {
+            continue;
+        }
+        additional += changes.get_size(&h).map_err(QuotaError::Changestore)?;
+        stack.extend(
+            changes
+                .get_dependencies(&h)
+                .map_err(QuotaError::Changestore)?,
+        )
+    }
+    if quota.allows(&usage, additional) {
+        Ok(additional)
+    } else {
+        Err(QuotaError::Exceeded {
+            used: usage.total,
+            additional,
+        })
+    }
+}
@@ -1,18 +1,41 @@
 //! Hunk a change from a pristine and a working copy.
+use crate::alive::retrieve::{retrieve_limited, GraphTooLarge, RetrieveError, RetrieveLimits};
 use crate::changestore::ChangeStore;
+use crate::channel_settings::{
+    BinaryPolicy, ChannelSettings, ChannelSettingsError, EolPolicy, TextPolicy,
+};
 use crate::diff;
-pub use crate::diff::Algorithm;
+pub use crate::diff::{Algorithm, ChannelAlgorithm, DiffAlgorithm};
+use crate::executor::Executor;
+use crate::file_stats::FileStats;
+use crate::ignore::Ignores;
 use crate::path::{components, Components};
 use crate::pristine::*;
 use crate::small_string::SmallString;
+use crate::text_encoding::Encoding;
 use crate::working_copy::WorkingCopy;
-use crate::{alive::retrieve, text_encoding::Encoding};
 use crate::{change::*, changestore::FileMetadata};
 use crate::{HashMap, HashSet};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 use std::collections::VecDeque;
 use std::sync::Arc;
 
+mod filter;
+pub use filter::*;
+
+mod sort;
+pub use sort::*;
+
+mod chunks;
+pub use chunks::ChunksError;
+
+mod session;
+pub use session::*;
+
+/// Size of the windows in which large files are read, see
+/// [`Builder::stream_threshold`].
+const STREAM_WINDOW: usize = 1 << 16;
+
 #[derive(Debug, Error)]
 pub enum RecordError<
     C: std::error::Error + 'static,
@@ -28,11 +51,41 @@ pub enum RecordError<
     #[error(transparent)]
     Txn(T),
     #[error(transparent)]
-    Diff(#[from] diff::DiffError<C, T>),
+    Diff(diff::DiffError<C, T>),
     #[error("Path not in repository: {0}")]
     PathNotInRepo(String),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("Malformed settings for channel {0}")]
+    ChannelSettings(String),
+    #[error(transparent)]
+    GraphTooLarge(#[from] GraphTooLarge),
+    #[error("Record cancelled")]
+    Cancelled,
+    #[error("{0} is a nested repository, but files inside it are still tracked")]
+    TrackedInSubrepo(String),
+    #[error(transparent)]
+    Fs(crate::fs::FsError<T>),
+    #[error(transparent)]
+    Session(SessionError<T>),
+    #[error(transparent)]
+    Intent(crate::intent::IntentError<T>),
+    #[error(transparent)]
+    Chunks(#[from] ChunksError),
+}
+
+impl<
+        C: std::error::Error + 'static,
+        W: std::error::Error + 'static,
+        T: std::error::Error + 'static,
+    > std::convert::From<diff::DiffError<C, T>> for RecordError<C, W, T>
+{
+    fn from(e: diff::DiffError<C, T>) -> Self {
+        match e {
+            diff::DiffError::Cancelled => RecordError::Cancelled,
+            e => RecordError::Diff(e),
+        }
+    }
 }
 
 impl<
@@ -46,6 +99,35 @@ impl<
     }
 }
 
+impl<C: std::error::Error + 'static, W: std::error::Error, T: std::error::Error + 'static>
+    RecordError<C, W, T>
+{
+    /// Convert the working copy errors with `f`, for instance when
+    /// recording through a wrapper of a working copy.
+    pub fn map_working_copy<W2: std::error::Error, F: FnOnce(W) -> W2>(
+        self,
+        f: F,
+    ) -> RecordError<C, W2, T> {
+        match self {
+            RecordError::Changestore(e) => RecordError::Changestore(e),
+            RecordError::WorkingCopy(e) => RecordError::WorkingCopy(f(e)),
+            RecordError::SystemTimeError(e) => RecordError::SystemTimeError(e),
+            RecordError::Txn(e) => RecordError::Txn(e),
+            RecordError::Diff(e) => RecordError::Diff(e),
+            RecordError::PathNotInRepo(p) => RecordError::PathNotInRepo(p),
+            RecordError::Io(e) => RecordError::Io(e),
+            RecordError::ChannelSettings(c) => RecordError::ChannelSettings(c),
+            RecordError::GraphTooLarge(e) => RecordError::GraphTooLarge(e),
+            RecordError::Cancelled => RecordError::Cancelled,
+            RecordError::TrackedInSubrepo(p) => RecordError::TrackedInSubrepo(p),
+            RecordError::Fs(e) => RecordError::Fs(e),
+            RecordError::Session(e) => RecordError::Session(e),
+            RecordError::Intent(e) => RecordError::Intent(e),
+            RecordError::Chunks(e) => RecordError::Chunks(e),
+        }
+    }
+}
+
 impl<
         C: std::error::Error + 'static,
         W: std::error::Error + 'static,
@@ -57,6 +139,235 @@ impl<
             crate::output::FileError::Changestore(e) => RecordError::Changestore(e),
             crate::output::FileError::Io(e) => RecordError::Io(e),
             crate::output::FileError::Txn(t) => RecordError::Txn(t),
+            crate::output::FileError::GraphTooLarge(e) => RecordError::GraphTooLarge(e),
+        }
+    }
+}
+
+impl<
+        C: std::error::Error + 'static,
+        W: std::error::Error + 'static,
+        T: std::error::Error + 'static,
+    > std::convert::From<crate::rename::RenameError<C, T, W>> for RecordError<C, W, T>
+{
+    fn from(e: crate::rename::RenameError<C, T, W>) -> Self {
+        match e {
+            crate::rename::RenameError::Txn(t) => RecordError::Txn(t),
+            crate::rename::RenameError::File(e) => e.into(),
+            crate::rename::RenameError::Fs(crate::fs::FsError::Txn(t)) => RecordError::Txn(t),
+            crate::rename::RenameError::Fs(e) => RecordError::Fs(e),
+            crate::rename::RenameError::WorkingCopy(w) => RecordError::WorkingCopy(w),
+        }
+    }
+}
+
+impl<
+        C: std::error::Error + 'static,
+        W: std::error::Error + 'static,
+        T: std::error::Error + 'static,
+    > std::convert::From<RetrieveError<T>> for RecordError<C, W, T>
+{
+    fn from(e: RetrieveError<T>) -> Self {
+        match e {
+            RetrieveError::Txn(t) => RecordError::Txn(t),
+            RetrieveError::GraphTooLarge(e) => RecordError::GraphTooLarge(e),
+        }
+    }
+}
+
+impl<
+        C: std::error::Error + 'static,
+        W: std::error::Error + 'static,
+        T: std::error::Error + 'static,
+    > std::convert::From<ChannelSettingsError<T>> for RecordError<C, W, T>
+{
+    fn from(e: ChannelSettingsError<T>) -> Self {
+        match e {
+            ChannelSettingsError::Txn(t) => RecordError::Txn(t),
+            ChannelSettingsError::Malformed(c) => RecordError::ChannelSettings(c),
+        }
+    }
+}
+
+/// What to do when a file can't be read from the working copy while
+/// recording, for instance because of its permissions, or because it
+/// was deleted in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadErrorPolicy {
+    /// Abort the record.
+    Fail,
+    /// Skip the file, and list it in the [`RecordReport`].
+    Skip,
+    /// Try again up to `attempts` times, pausing for `pause` before
+    /// each attempt, and then skip the file.
+    Retry {
+        attempts: usize,
+        pause: std::time::Duration,
+    },
+}
+
+impl Default for ReadErrorPolicy {
+    fn default() -> Self {
+        ReadErrorPolicy::Fail
+    }
+}
+
+/// A file that couldn't be read while recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: String,
+    pub error: String,
+}
+
+/// The files skipped while recording, as returned by
+/// [`Builder::finish_with_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordReport {
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl RecordReport {
+    pub(crate) fn skip<E: std::error::Error>(&mut self, path: &str, e: &E) {
+        debug!("skipping {:?}: {:?}", path, e);
+        self.skipped.push(SkippedFile {
+            path: path.to_string(),
+            error: e.to_string(),
+        })
+    }
+}
+
+/// An event reported to a [`RecordObserver`] while recording, so
+/// that interactive tools can warn about a change before it is
+/// pushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordEvent {
+    /// A file larger than [`RecordLimits::large_file`] was recorded.
+    LargeFile { path: String, size: u64 },
+    /// A file without a text encoding was recorded.
+    BinaryFile { path: String },
+    /// The diff of a file produced more than
+    /// [`RecordLimits::many_hunks`] hunks.
+    ManyHunks { path: String, hunks: usize },
+}
+
+/// A callback invoked with the events and the progress of a
+/// recording, for instance to warn about a change before it is
+/// pushed, or to show a progress bar. Files may be read on several
+/// threads, see [`Builder::io_concurrency`].
+pub trait RecordObserver: Send + Sync {
+    fn event(&self, _event: RecordEvent) {}
+    /// The recording of a prefix started, with `files` tracked paths
+    /// to scan. Paths left out by [`Builder::filter`] or ignore
+    /// files are counted but not scanned.
+    fn started(&self, _files: usize) {}
+    /// `path` was reached while traversing the working copy.
+    fn file_scanned(&self, _path: &str) {}
+    /// `bytes` more bytes of the new version of `path` were diffed
+    /// against the pristine.
+    fn bytes_diffed(&self, _path: &str, _bytes: u64) {}
+    /// `hunks` hunks were produced for `path`, by a diff or an
+    /// addition.
+    fn hunks_produced(&self, _path: &str, _hunks: usize) {}
+}
+
+impl<F: Fn(RecordEvent) + Send + Sync> RecordObserver for F {
+    fn event(&self, event: RecordEvent) {
+        self(event)
+    }
+}
+
+/// A flag to interrupt a recording from another thread, for instance
+/// when the user presses Ctrl-C. [`Builder::record`] checks it
+/// between files and while diffing, and returns
+/// [`RecordError::Cancelled`] once it is set, after stopping its
+/// threads. Nothing is recorded in that case.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<std::sync::atomic::AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the recordings using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// A rewrite of the contents of tracked text files before they are
+/// diffed, for instance by a formatter. The rewritten contents are
+/// recorded instead of the original ones, but the working copy is
+/// left untouched. New files are recorded as they are.
+pub trait RecordTransform: Send + Sync {
+    /// The new contents of the file at `path`, or `None` to record
+    /// `contents` unchanged.
+    fn transform(&self, path: &str, contents: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl<F: Fn(&str, &[u8]) -> Option<Vec<u8>> + Send + Sync> RecordTransform for F {
+    fn transform(&self, path: &str, contents: &[u8]) -> Option<Vec<u8>> {
+        self(path, contents)
+    }
+}
+
+/// The thresholds above which a [`RecordEvent`] is reported. These
+/// are only warnings: recording goes on regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordLimits {
+    /// Size of a file, in bytes.
+    pub large_file: u64,
+    /// Number of hunks in the diff of a single file.
+    pub many_hunks: usize,
+}
+
+impl Default for RecordLimits {
+    fn default() -> Self {
+        RecordLimits {
+            large_file: 10 << 20,
+            many_hunks: 100,
+        }
+    }
+}
+
+/// The number of tracked paths under `prefix`, reported to
+/// [`RecordObserver::started`].
+fn tracked_paths<T: TreeTxnT>(txn: &T, prefix: &str) -> Result<usize, TxnErr<T::TreeError>> {
+    let inode = match crate::fs::find_inode(txn, prefix) {
+        Ok(inode) => inode,
+        Err(crate::fs::FsError::Txn(e)) => return Err(TxnErr(e)),
+        Err(_) => return Ok(0),
+    };
+    let mut n = if inode == Inode::ROOT { 0 } else { 1 };
+    for x in crate::fs::iter_working_copy(txn, inode) {
+        x.map_err(TxnErr)?;
+        n += 1
+    }
+    Ok(n)
+}
+
+/// Call `f` until it succeeds, or until the attempts allowed by
+/// `policy` are exhausted.
+pub(crate) fn retry<X, E>(
+    policy: ReadErrorPolicy,
+    mut f: impl FnMut() -> Result<X, E>,
+) -> Result<X, E> {
+    let mut attempt = 0;
+    loop {
+        let e = match f() {
+            Ok(x) => return Ok(x),
+            Err(e) => e,
+        };
+        match policy {
+            ReadErrorPolicy::Retry { attempts, pause } if attempt < attempts => {
+                attempt += 1;
+                std::thread::sleep(pause)
+            }
+            _ => return Err(e),
         }
     }
 }
@@ -69,7 +380,98 @@ pub struct Builder {
     deleted_vertices: Arc<Mutex<HashSet<Position<ChangeId>>>>,
     pub force_rediff: bool,
     pub ignore_missing: bool,
+    /// Number of threads reading new files from the working copy. New
+    /// files are read on the recording thread if this is at most 1.
+    pub io_concurrency: usize,
+    /// Record new files identical to a tracked file as `FileCopy`
+    /// hunks instead of `FileAdd`.
+    pub detect_copies: bool,
+    /// Record the tracked files missing from the working copy as
+    /// moved to the added files at least this similar, see
+    /// [`crate::rename::detect_renames`].
+    pub detect_renames: Option<f64>,
+    /// The renames found by [`Builder::detect_renames`].
+    pub renames: Vec<crate::rename::Rename>,
+    /// Diff algorithm, overriding the algorithm passed to
+    /// [`Builder::record`]. Other [`DiffAlgorithm`]s can only be
+    /// passed to [`Builder::record`].
+    pub diff_algorithm: Option<Algorithm>,
+    /// Binary policy, overriding the settings of the channel.
+    pub binary_policy: Option<BinaryPolicy>,
+    /// End-of-line policy, overriding the settings of the channel.
+    pub eol_policy: Option<EolPolicy>,
+    /// Policy for very long lines and text files with NUL bytes,
+    /// overriding the `text` attributes of the files, see
+    /// [`crate::attributes`].
+    pub text_policy: Option<TextPolicy>,
+    text_rules: Arc<crate::attributes::TextRules>,
+    /// Diff the words of the modified lines of text files, so that
+    /// a small edit in a long line only replaces the words that
+    /// changed instead of the whole line.
+    pub word_diff: bool,
+    /// Join the replacements separated by at most this many unchanged
+    /// lines (words with `word_diff`, chunks in binary files) into a
+    /// single replacement, producing fewer and larger hunks.
+    pub join_distance: Option<usize>,
+    /// Files larger than this many bytes are read and diffed in
+    /// windows, keeping only the parts of their new version not found
+    /// in the old one. Text files are only streamed when diffed by
+    /// lines, without [`Builder::word_diff`], transforms or
+    /// [`EolPolicy::Lf`], and are read entirely otherwise.
+    pub stream_threshold: Option<u64>,
+    settings: ChannelSettings,
+    conflict_options: crate::output::ConflictOptions,
+    /// What to do with files that can't be read.
+    pub read_error_policy: ReadErrorPolicy,
+    report: Arc<Mutex<RecordReport>>,
+    /// Called on the events and the progress of the recording, see
+    /// [`RecordObserver`].
+    pub observer: Option<Arc<dyn RecordObserver>>,
+    /// Thresholds of the events reported to `observer`.
+    pub limits: RecordLimits,
+    /// Limits on the graphs of the modified files, see
+    /// [`RetrieveLimits`]. None by default.
+    pub graph_limits: RetrieveLimits,
+    /// Interrupts the recording when cancelled.
+    pub cancellation: CancellationToken,
+    /// Applied to the modified files before diffing them.
+    pub transform: Option<Arc<dyn RecordTransform>>,
+    /// Only record the paths selected by this filter. Unlike the
+    /// `prefix` argument of [`Builder::record`], a filter can select
+    /// several parts of the working copy in a single traversal.
+    pub filter: Option<PathFilter>,
+    /// Don't add the paths matched by ignore files that aren't in the
+    /// channel yet, see [`crate::ignore`]. Off by default, since the
+    /// paths of the tree were added explicitly.
+    pub ignore_files: bool,
+    /// Skip the files found identical to the pristine by a previous
+    /// recording, if their size and modification time are the same,
+    /// see [`crate::dirty_cache`].
+    pub dirty_cache: bool,
+    /// Runs the jobs diffing the modified files and reading the new
+    /// ones, see [`crate::executor`].
+    pub executor: Arc<dyn Executor>,
+    ignores: Ignores,
+    /// Remove the redundant edges found during the recording at the
+    /// end of [`Builder::record`], see
+    /// [`Recorded::apply_redundant_cleanup`].
+    pub cleanup_redundant: bool,
     pub contents: Arc<Mutex<Vec<u8>>>,
+    /// Save a session to this directory after recording each child
+    /// of the prefix passed to [`Builder::record`], so that a long
+    /// recording interrupted by a restart can be resumed with
+    /// [`Builder::resume_session`].
+    pub checkpoint: Option<std::path::PathBuf>,
+    /// The prefixes recorded so far, see [`Builder::save_session`].
+    prefixes: Vec<String>,
+    /// Take the channel in this registry from the first call to
+    /// [`Builder::record`] until the [`Recorded`] is applied or
+    /// dropped, see [`crate::intent`].
+    pub intents: Option<crate::intent::Intents>,
+    /// How long to wait for the other operations on the channel in
+    /// `intents`. Fail immediately if `None`.
+    pub intent_timeout: Option<std::time::Duration>,
+    intent: Option<crate::intent::IntentGuard>,
 }
 
 #[derive(Debug)]
@@ -98,10 +500,53 @@ pub struct Recorded {
     pub oldest_change: std::time::SystemTime,
     /// Redundant edges found during the comparison.
     pub redundant: Vec<(Vertex<ChangeId>, SerializedEdge)>,
+    /// Content statistics of the files read, as they are in the
+    /// working copy, see [`Recorded::save_file_stats`].
+    pub file_stats: HashMap<Inode, FileStats>,
+    /// Hashes of the contents of the files found identical to the
+    /// pristine.
+    unchanged: HashMap<Inode, Hash>,
+    /// Size and modification time of the files found identical to the
+    /// pristine, see [`crate::dirty_cache`].
+    clean: HashMap<Inode, crate::dirty_cache::Stat>,
+    /// The ranges of `contents` appended by this `Recorded`, in
+    /// order, see [`Builder::finish`].
+    pub(crate) chunks: Vec<std::ops::Range<usize>>,
+    /// Whether the files added by this recording are known to be
+    /// identical to the pristine once it is applied, see
+    /// [`crate::import::snapshot`].
+    pub(crate) verify_additions: bool,
     /// Force a re-diff
     force_rediff: bool,
+    /// Whether to skip the files found clean in the dirty cache.
+    dirty_cache: bool,
     deleted_vertices: Arc<Mutex<HashSet<Position<ChangeId>>>>,
     recorded_inodes: Arc<Mutex<HashMap<Inode, Position<Option<ChangeId>>>>>,
+    detect_copies: bool,
+    /// The `FileAdd` hunks of non-empty files, by index in `actions`,
+    /// with the hash and size of their contents, to be turned into
+    /// `FileCopy` hunks at the end of the recording.
+    copy_candidates: Vec<(usize, Hash, u64)>,
+    pub(crate) binary_policy: BinaryPolicy,
+    pub(crate) word_diff: bool,
+    pub(crate) join_distance: Option<usize>,
+    stream_threshold: Option<u64>,
+    eol_policy: EolPolicy,
+    text_policy: Option<TextPolicy>,
+    text_rules: Arc<crate::attributes::TextRules>,
+    read_error_policy: ReadErrorPolicy,
+    pub(crate) report: Arc<Mutex<RecordReport>>,
+    pub(crate) observer: Option<Arc<dyn RecordObserver>>,
+    pub(crate) cancellation: CancellationToken,
+    limits: RecordLimits,
+    graph_limits: RetrieveLimits,
+    /// How the conflicts of the pristine are rendered before diffing
+    /// them, see [`WorkingCopy::conflict_options`].
+    pub(crate) conflict_options: crate::output::ConflictOptions,
+    transform: Option<Arc<dyn RecordTransform>>,
+    /// The channel taken by [`Builder::record`], see
+    /// [`Builder::intents`].
+    intent: Option<crate::intent::IntentGuard>,
 }
 
 impl Default for Builder {
@@ -111,8 +556,40 @@ impl Default for Builder {
             recorded_inodes: Arc::new(Mutex::new(HashMap::default())),
             force_rediff: false,
             ignore_missing: false,
+            io_concurrency: 1,
+            detect_copies: false,
+            detect_renames: None,
+            renames: Vec::new(),
+            diff_algorithm: None,
+            binary_policy: None,
+            eol_policy: None,
+            text_policy: None,
+            text_rules: Arc::new(crate::attributes::TextRules::default()),
+            word_diff: false,
+            join_distance: None,
+            stream_threshold: None,
+            settings: ChannelSettings::default(),
+            conflict_options: crate::output::ConflictOptions::default(),
+            read_error_policy: ReadErrorPolicy::default(),
+            report: Arc::new(Mutex::new(RecordReport::default())),
+            observer: None,
+            cancellation: CancellationToken::new(),
+            limits: RecordLimits::default(),
+            graph_limits: RetrieveLimits::UNLIMITED,
+            transform: None,
+            filter: None,
+            ignore_files: false,
+            dirty_cache: true,
+            executor: Arc::new(crate::executor::Threads),
+            ignores: Ignores::new(),
+            cleanup_redundant: false,
             deleted_vertices: Arc::new(Mutex::new(HashSet::default())),
             contents: Arc::new(Mutex::new(Vec::new())),
+            checkpoint: None,
+            prefixes: Vec::new(),
+            intents: None,
+            intent_timeout: None,
+            intent: None,
         }
     }
 }
@@ -138,29 +615,63 @@ impl Builder {
             has_binary_files: false,
             oldest_change: std::time::SystemTime::UNIX_EPOCH,
             redundant: Vec::new(),
+            file_stats: HashMap::default(),
+            unchanged: HashMap::default(),
+            clean: HashMap::default(),
+            chunks: Vec::new(),
+            verify_additions: false,
             force_rediff: self.force_rediff,
+            dirty_cache: self.dirty_cache,
             deleted_vertices: self.deleted_vertices.clone(),
             recorded_inodes: self.recorded_inodes.clone(),
+            detect_copies: self.detect_copies,
+            copy_candidates: Vec::new(),
+            binary_policy: self
+                .binary_policy
+                .or(self.settings.binary)
+                .unwrap_or_default(),
+            word_diff: self.word_diff,
+            join_distance: self.join_distance,
+            stream_threshold: self.stream_threshold,
+            eol_policy: self.eol_policy.or(self.settings.eol).unwrap_or_default(),
+            text_policy: self.text_policy,
+            text_rules: self.text_rules.clone(),
+            read_error_policy: self.read_error_policy,
+            report: self.report.clone(),
+            observer: self.observer.clone(),
+            cancellation: self.cancellation.clone(),
+            limits: self.limits,
+            graph_limits: self.graph_limits,
+            conflict_options: self.conflict_options,
+            transform: self.transform.clone(),
+            intent: None,
         }
     }
 
     /// Finish the recording.
-    pub fn finish(mut self) -> Recorded {
+    pub fn finish(mut self) -> Result<Recorded, ChunksError> {
         if self.rec.is_empty() {
             self.recorded();
         }
-        let mut it = self.rec.into_iter();
-        let mut result = if let Ok(rec) = Arc::try_unwrap(it.next().unwrap()) {
-            rec.into_inner()
-        } else {
-            unreachable!()
-        };
+        let mut recs: Vec<Recorded> = self
+            .rec
+            .into_iter()
+            .map(|rec| {
+                if let Ok(rec) = Arc::try_unwrap(rec) {
+                    rec.into_inner()
+                } else {
+                    unreachable!()
+                }
+            })
+            .collect();
+        let mut contents = self.contents.lock();
+        chunks::relocate(&mut contents, &mut recs)?;
+        let mut it = recs.into_iter();
+        let mut result = it.next().unwrap();
+        result.chunks = vec![0..contents.len()];
+        result.intent = self.intent.take();
+        std::mem::drop(contents);
         for rec in it {
-            let rec = if let Ok(rec) = Arc::try_unwrap(rec) {
-                rec.into_inner()
-            } else {
-                unreachable!()
-            };
             let off = result.actions.len();
             result.actions.extend(rec.actions.into_iter());
             for (a, b) in rec.updatables {
@@ -174,20 +685,32 @@ impl Builder {
             {
                 result.oldest_change = rec.oldest_change
             }
-            result.redundant.extend(rec.redundant.into_iter())
+            result.redundant.extend(rec.redundant.into_iter());
+            result.file_stats.extend(rec.file_stats.into_iter());
+            result.unchanged.extend(rec.unchanged.into_iter());
+            result.clean.extend(rec.clean.into_iter())
         }
         debug!(
             "result = {:?}, updatables = {:?}",
             result.actions, result.updatables
         );
-        result
+        Ok(result)
+    }
+
+    /// Finish the recording, and return the files that were skipped
+    /// along with the result.
+    pub fn finish_with_report(self) -> Result<(Recorded, RecordReport), ChunksError> {
+        let report = self.report.clone();
+        let rec = self.finish()?;
+        let report = std::mem::take(&mut *report.lock());
+        Ok((rec, report))
     }
 }
 
 /// An account of the files that have been added, moved or deleted, as
 /// returned by record, and used by apply (when applying a change
 /// created locally) to update the trees and inodes databases.
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InodeUpdate {
     Add {
         /// Inode vertex in the graph.
@@ -202,13 +725,13 @@ pub enum InodeUpdate {
 }
 
 #[derive(Debug, Clone)]
-struct RecordItem {
-    v_papa: Position<Option<ChangeId>>,
-    papa: Inode,
-    inode: Inode,
-    basename: String,
-    full_path: String,
-    metadata: InodeMetadata,
+pub(crate) struct RecordItem {
+    pub(crate) v_papa: Position<Option<ChangeId>>,
+    pub(crate) papa: Inode,
+    pub(crate) inode: Inode,
+    pub(crate) basename: String,
+    pub(crate) full_path: String,
+    pub(crate) metadata: InodeMetadata,
 }
 
 impl RecordItem {
@@ -235,7 +758,10 @@ fn get_inodes_<T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphErro
     Ok(get_inodes(&*txn, &*channel, inode)?.map(|x| *x))
 }
 
-fn get_inodes<'a, T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+pub(crate) fn get_inodes<
+    'a,
+    T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+>(
     txn: &'a T,
     channel: &T::Channel,
     inode: &Inode,
@@ -270,54 +796,187 @@ struct Tasks {
     )>,
 }
 
+/// New files waiting to be read by the ingestion threads. Each file
+/// gets its own `Recorded`, allocated in traversal order, so that
+/// `Builder::finish` puts the hunks back in order regardless of the
+/// order in which files were read.
+struct Ingestion {
+    stop: bool,
+    t: VecDeque<(RecordItem, Arc<Mutex<Recorded>>)>,
+}
+
 impl Builder {
+    /// Record the changes under `prefix` in the working copy, diffing
+    /// modified files with `diff_algorithm` unless
+    /// [`Builder::diff_algorithm`] names another [`Algorithm`]. The
+    /// settings of the channel are only used if `diff_algorithm` is
+    /// [`ChannelAlgorithm`]. Modified files are diffed by `n_workers` jobs
+    /// run by [`Builder::executor`], or on this thread only if the
+    /// executor isn't concurrent; the result doesn't depend on
+    /// `n_workers`.
     pub fn record<
         T,
         W: WorkingCopy + Clone + Send + Sync + 'static,
         C: ChangeStore + Clone + Send + 'static,
+        A: DiffAlgorithm + 'static,
+    >(
+        &mut self,
+        txn: ArcTxn<T>,
+        diff_algorithm: A,
+        channel: ChannelRef<T>,
+        working_copy: &W,
+        changes: &C,
+        prefix: &str,
+        n_workers: usize,
+    ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
+    where
+        T: ChannelMutTxnT
+            + TxnT
+            + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>
+            + Send
+            + Sync
+            + 'static,
+        T::Channel: Send + Sync,
+        <W as WorkingCopy>::Error: 'static,
+    {
+        if let (Some(intents), None) = (&self.intents, &self.intent) {
+            let txn = txn.read();
+            let channel = channel.r.read();
+            let intent = crate::intent::Intent::Record;
+            self.intent = Some(
+                intents
+                    .begin_channels(&*txn, &[&*channel], intent, self.intent_timeout)
+                    .map_err(RecordError::Intent)?,
+            )
+        }
+        self.ignores = Ignores::new();
+        self.conflict_options = working_copy.conflict_options();
+        self.text_rules = Arc::new(
+            crate::attributes::TextRules::load(working_copy).map_err(RecordError::WorkingCopy)?,
+        );
+        self.settings = {
+            let txn = txn.read();
+            let channel = channel.r.read();
+            crate::channel_settings::load(&*txn, &*channel)?
+        };
+        if let Some(threshold) = self.detect_renames {
+            // The added files are compared with the pristine as they
+            // would be recorded.
+            let rec = self.recorded_();
+            let mut txn = txn.write();
+            let channel = channel.r.read();
+            let renames = crate::rename::detect_renames(
+                &mut *txn,
+                &*channel,
+                working_copy,
+                changes,
+                prefix,
+                threshold,
+                |path, contents, encoding| {
+                    let encoding = rec.text_encoding(path, contents, encoding);
+                    rec.apply_eol_policy(contents, &encoding)
+                },
+            )?;
+            self.renames.extend(renames)
+        }
+        let diff_algorithm: Arc<dyn DiffAlgorithm> = if let Some(a) = self.diff_algorithm {
+            Arc::new(a)
+        } else if (&diff_algorithm as &dyn std::any::Any).is::<ChannelAlgorithm>() {
+            Arc::new(self.settings.diff_algorithm.unwrap_or_default())
+        } else {
+            Arc::new(diff_algorithm)
+        };
+        if let Some(dir) = self.checkpoint.clone() {
+            return self.record_checkpointed(
+                txn,
+                diff_algorithm,
+                channel,
+                working_copy,
+                changes,
+                prefix,
+                n_workers,
+                &dir,
+            );
+        }
+        self.record_prefix(
+            txn,
+            diff_algorithm,
+            channel,
+            working_copy,
+            changes,
+            prefix,
+            n_workers,
+        )
+    }
+
+    /// Record `prefix`, once the settings are loaded and the renames
+    /// detected.
+    fn record_prefix<
+        T,
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        C: ChangeStore + Clone + Send + 'static,
     >(
         &mut self,
         txn: ArcTxn<T>,
-        diff_algorithm: diff::Algorithm,
+        diff_algorithm: Arc<dyn DiffAlgorithm>,
         channel: ChannelRef<T>,
         working_copy: &W,
         changes: &C,
         prefix: &str,
-        _n_workers: usize,
+        n_workers: usize,
     ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
     where
         T: ChannelMutTxnT
-            + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>
+            + TxnT
+            + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>
             + Send
             + Sync
             + 'static,
         T::Channel: Send + Sync,
         <W as WorkingCopy>::Error: 'static,
     {
+        if let Some(ref observer) = self.observer {
+            observer.started(tracked_paths(&*txn.read(), prefix)?)
+        }
+
+        // Modified files are diffed by `n_workers - 1` jobs, along
+        // with this thread once the traversal is over.
+        let executor = self.executor.clone();
+        let n_workers = if executor.is_concurrent() {
+            n_workers
+        } else {
+            1
+        };
         let work = Arc::new(Mutex::new(Tasks {
             t: VecDeque::new(),
             stop: false,
         }));
-        let mut workers: Vec<std::thread::JoinHandle<_>> = Vec::new();
-        for t in 0..0 {
-            // n_workers - 1 {
+        let work_ready = Arc::new(Condvar::new());
+        let mut workers = Vec::new();
+        for t in 0..n_workers.saturating_sub(1) {
+            let diff_algorithm = diff_algorithm.clone();
             let working_copy = working_copy.clone();
             let changes = changes.clone();
             let channel = channel.clone();
             let work = work.clone();
+            let work_ready = work_ready.clone();
             let txn = txn.clone();
-            workers.push(std::thread::spawn(move || {
+            let cancellation = self.cancellation.clone();
+            workers.push(crate::executor::spawn(&*executor, move || {
                 loop {
                     let (w, stop) = {
                         let mut work = work.lock();
                         (work.t.pop_front(), work.stop)
                     };
-                    if let Some((item, vertex, rec, new_papa)) = w {
+                    if cancellation.is_cancelled() {
+                        info!("cancelled {:?}", t);
+                        break;
+                    } else if let Some((item, vertex, rec, new_papa)) = w {
                         // This parent has changed.
                         info!("record existing file {:?} on thread {:?}", item, t);
                         rec.lock().record_existing_file(
                             &txn,
-                            diff_algorithm,
+                            &*diff_algorithm,
                             &channel,
                             working_copy.clone(),
                             &changes,
@@ -330,17 +989,71 @@ impl Builder {
                         break;
                     } else {
                         info!("yield {:?}", t);
-                        std::thread::park_timeout(std::time::Duration::from_secs(1));
+                        let mut work = work.lock();
+                        if work.t.is_empty() && !work.stop {
+                            work_ready.wait_for(&mut work, std::time::Duration::from_secs(1));
+                        }
                     }
                 }
                 Ok::<_, RecordError<C::Error, W::Error, T::GraphError>>(())
             }))
         }
 
+        let ingestion = Arc::new(Mutex::new(Ingestion {
+            t: VecDeque::new(),
+            stop: false,
+        }));
+        let ingestion_ready = Arc::new(Condvar::new());
+        let mut ingesters = Vec::new();
+        let n_ingesters = if self.io_concurrency > 1 && executor.is_concurrent() {
+            self.io_concurrency
+        } else {
+            0
+        };
+        for t in 0..n_ingesters {
+            let working_copy = working_copy.clone();
+            let ingestion = ingestion.clone();
+            let ingestion_ready = ingestion_ready.clone();
+            let policy = self.read_error_policy;
+            let report = self.report.clone();
+            let cancellation = self.cancellation.clone();
+            ingesters.push(crate::executor::spawn(&*executor, move || loop {
+                let (w, stop) = {
+                    let mut ingestion = ingestion.lock();
+                    (ingestion.t.pop_front(), ingestion.stop)
+                };
+                if cancellation.is_cancelled() {
+                    break Ok(());
+                } else if let Some((item, rec)) = w {
+                    info!("ingest new file {:?} on thread {:?}", item, t);
+                    match retry(policy, || read_new_file(&working_copy, &item)) {
+                        Ok(file) => {
+                            rec.lock().push_file_addition(item, file);
+                        }
+                        Err(e) if policy == ReadErrorPolicy::Fail => break Err(e),
+                        Err(e) => report.lock().skip(&item.full_path, &e),
+                    }
+                } else if stop {
+                    break Ok(());
+                } else {
+                    let mut ingestion = ingestion.lock();
+                    if ingestion.t.is_empty() && !ingestion.stop {
+                        ingestion_ready.wait_for(&mut ingestion, std::time::Duration::from_secs(1));
+                    }
+                }
+            }))
+        }
+
         let now = std::time::Instant::now();
         let mut stack = vec![(RecordItem::root(), components(prefix))];
         while let Some((mut item, mut components)) = stack.pop() {
             debug!("stack.pop() = Some({:?})", item);
+            if self.cancellation.is_cancelled() {
+                break;
+            }
+            if let (Some(observer), false) = (&self.observer, item.inode == Inode::ROOT) {
+                observer.file_scanned(&item.full_path)
+            }
 
             // Check for moves and file conflicts.
             let vertex: Option<Position<Option<ChangeId>>> =
@@ -386,25 +1099,32 @@ impl Builder {
                     recorded.insert(item.inode, vertex.to_option());
                     recorded.get(&item.papa).cloned()
                 };
-                let mut work = work.lock();
-                work.t.push_back((item.clone(), vertex, rec, new_papa));
-                std::mem::drop(work);
-                for t in workers.iter() {
-                    t.thread().unpark()
-                }
+                work.lock()
+                    .t
+                    .push_back((item.clone(), vertex, rec, new_papa));
+                work_ready.notify_all();
 
                 vertex.to_option()
+            } else if !ingesters.is_empty() && !item.metadata.is_dir() {
+                // New files have no children, they can be read in
+                // the background.
+                let rec = self.recorded();
+                ingestion.lock().t.push_back((item, rec));
+                ingestion_ready.notify_all();
+                continue;
             } else {
                 let rec = self.recorded();
                 debug!("TAKING LOCK {}", line!());
                 let mut rec = rec.lock();
-                match rec.add_file(working_copy, item.clone()) {
-                    Ok(Some(vertex)) => {
-                        // Path addition (maybe just a single directory).
-                        self.recorded_inodes.lock().insert(item.inode, vertex);
-                        vertex
-                    }
-                    _ => continue,
+                if let Some(vertex) = rec
+                    .add_file(working_copy, item.clone())
+                    .map_err(RecordError::WorkingCopy)?
+                {
+                    // Path addition (maybe just a single directory).
+                    self.recorded_inodes.lock().insert(item.inode, vertex);
+                    vertex
+                } else {
+                    continue;
                 }
             };
 
@@ -427,21 +1147,21 @@ impl Builder {
 
         info!("stop work");
         work.lock().stop = true;
-        for t in workers.iter() {
-            t.thread().unpark()
-        }
+        work_ready.notify_all();
         loop {
             let w = {
                 let mut work = work.lock();
                 debug!("waiting, stop = {:?}", work.stop);
                 work.t.pop_front()
             };
-            if let Some((item, vertex, rec, new_papa)) = w {
+            if self.cancellation.is_cancelled() {
+                break;
+            } else if let Some((item, vertex, rec, new_papa)) = w {
                 // This parent has changed.
                 info!("record existing file {:?}", item);
                 rec.lock().record_existing_file(
                     &txn,
-                    diff_algorithm,
+                    &*diff_algorithm,
                     &channel,
                     working_copy.clone(),
                     changes,
@@ -453,20 +1173,165 @@ impl Builder {
                 break;
             }
         }
+        let mut result = Ok(());
         for (n, t) in workers.into_iter().enumerate() {
             debug!("WAITING {:?}", n);
             match t.join() {
-                Ok(x) => x?,
+                Ok(Err(e)) if result.is_ok() => result = Err(e),
+                Ok(_) => {}
                 Err(e) => {
                     warn!("Thread error {:?}", e);
                 }
             }
         }
+
+        ingestion.lock().stop = true;
+        ingestion_ready.notify_all();
+        for (n, t) in ingesters.into_iter().enumerate() {
+            debug!("WAITING INGESTER {:?}", n);
+            match t.join() {
+                Ok(Err(e)) if result.is_ok() => result = Err(RecordError::WorkingCopy(e)),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Thread error {:?}", e);
+                }
+            }
+        }
+        result?;
+        if self.cancellation.is_cancelled() {
+            info!("record cancelled");
+            return Err(RecordError::Cancelled);
+        }
+        if self.detect_copies {
+            let txn = txn.read();
+            let channel = channel.r.read();
+            self.find_copies(&*txn, &*channel, changes)?;
+        }
+        if self.cleanup_redundant {
+            let mut txn = txn.write();
+            let mut channel = channel.r.write();
+            for rec in self.rec.iter() {
+                rec.lock()
+                    .apply_redundant_cleanup(&mut *txn, &mut *channel)?;
+            }
+        }
+        self.prefixes.push(prefix.to_string());
         crate::TIMERS.lock().unwrap().record += now.elapsed();
         info!("record done");
         Ok(())
     }
 
+    /// Turn the additions of files identical to a tracked file into
+    /// `FileCopy` hunks. The tracked files are only read if something
+    /// was added.
+    fn find_copies<T: TxnT, C: ChangeStore>(
+        &mut self,
+        txn: &T,
+        channel: &T::Channel,
+        changes: &C,
+    ) -> Result<(), crate::output::FileError<C::Error, T::GraphError>> {
+        let mut wanted = HashSet::default();
+        let mut sizes = HashSet::default();
+        for rec in self.rec.iter() {
+            for (_, hash, size) in rec.lock().copy_candidates.iter() {
+                wanted.insert(*hash);
+                sizes.insert(*size);
+            }
+        }
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        // The sizes in the statistics are those of the working copy,
+        // which may differ from the pristine when line endings are
+        // converted.
+        let eol = self.eol_policy.or(self.settings.eol).unwrap_or_default();
+        let sources = copy_sources(
+            txn,
+            channel,
+            changes,
+            self.graph_limits,
+            &wanted,
+            if eol == EolPolicy::Lf {
+                None
+            } else {
+                Some(&sizes)
+            },
+        )?;
+        for rec in self.rec.iter() {
+            let mut rec = rec.lock();
+            let rec = &mut *rec;
+            for (i, hash, _) in std::mem::take(&mut rec.copy_candidates) {
+                let (source, source_inode) = if let Some(s) = sources.get(&hash) {
+                    s.clone()
+                } else {
+                    continue;
+                };
+                let hunk = &mut rec.actions[i];
+                if let Hunk::FileAdd {
+                    add_name,
+                    add_inode,
+                    contents,
+                    path,
+                    encoding,
+                } = hunk
+                {
+                    if *path == source {
+                        continue;
+                    }
+                    *hunk = Hunk::FileCopy {
+                        add_name: add_name.clone(),
+                        add_inode: add_inode.clone(),
+                        contents: contents.clone(),
+                        path: std::mem::take(path),
+                        source,
+                        source_inode: Some(source_inode.to_option()),
+                        encoding: encoding.clone(),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the paths of the working copy selected by the glob
+    /// `patterns` (see [`PathFilter`]), replacing [`Builder::filter`].
+    pub fn record_with_filter<
+        T,
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        C: ChangeStore + Clone + Send + 'static,
+        A: DiffAlgorithm + 'static,
+    >(
+        &mut self,
+        txn: ArcTxn<T>,
+        diff_algorithm: A,
+        channel: ChannelRef<T>,
+        working_copy: &W,
+        changes: &C,
+        patterns: &[&str],
+        n_workers: usize,
+    ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
+    where
+        T: ChannelMutTxnT
+            + TxnT
+            + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>
+            + Send
+            + Sync
+            + 'static,
+        T::Channel: Send + Sync,
+        <W as WorkingCopy>::Error: 'static,
+    {
+        self.filter = Some(PathFilter::new(patterns));
+        self.record(
+            txn,
+            diff_algorithm,
+            channel,
+            working_copy,
+            changes,
+            "",
+            n_workers,
+        )
+    }
+
     fn delete_obsolete_children<
         T: GraphTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
         W: WorkingCopy,
@@ -521,6 +1386,9 @@ impl Builder {
                         full_path.push('/');
                     }
                     full_path.push_str(meta.basename);
+                    if !self.selects(&full_path, false) {
+                        continue;
+                    }
                     // delete recursively.
                     let rec = self.recorded();
                     let mut rec = rec.lock();
@@ -538,6 +1406,21 @@ impl Builder {
         Ok(())
     }
 
+    /// Whether `path` is selected by [`Builder::filter`], or, if it
+    /// is a directory, may contain selected paths.
+    fn selects(&self, path: &str, is_dir: bool) -> bool {
+        match self.filter {
+            Some(ref filter) if is_dir => filter.descends(path),
+            Some(ref filter) => filter.matches(path),
+            None => true,
+        }
+    }
+
+    /// Whether `path` is ignored, see [`Builder::ignore_files`].
+    fn ignored<W: WorkingCopy>(&mut self, working_copy: &W, path: &str, is_dir: bool) -> bool {
+        self.ignore_files && self.ignores.is_ignored(working_copy, path, is_dir)
+    }
+
     fn push_children<
         'a,
         T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
@@ -559,6 +1442,28 @@ impl Builder {
         <W as crate::working_copy::WorkingCopy>::Error: 'static,
     {
         debug!("push_children, item = {:?}", item);
+        if item.metadata.is_subrepo() {
+            // Nested repositories are recorded as pointers, their
+            // files are left to them. The files recorded here before
+            // the directory became a nested repository would neither
+            // be recorded nor deleted, so they must be removed first.
+            let fileid = OwnedPathId {
+                parent_inode: item.inode,
+                basename: SmallString::new(),
+            };
+            for x in txn.iter_tree(&fileid, None)? {
+                let (fileid_, child_inode) = x?;
+                if fileid_.parent_inode > item.inode {
+                    break;
+                } else if fileid_.parent_inode == item.inode
+                    && !fileid_.basename.is_empty()
+                    && get_inodes(txn, &channel, child_inode)?.is_some()
+                {
+                    return Err(RecordError::TrackedInSubrepo(item.full_path.clone()));
+                }
+            }
+            return Ok(());
+        }
         let comp = components.next();
         let full_path = item.full_path.clone();
         let fileid = OwnedPathId {
@@ -587,7 +1492,20 @@ impl Builder {
                 full_path.clone() + "/" + &basename
             };
             debug!("fileid_ {:?} child_inode {:?}", fileid_, child_inode);
-            if let Ok(meta) = working_copy.file_metadata(&full_path) {
+            if let Ok(mut meta) = working_copy.file_metadata(&full_path) {
+                if meta.is_dir() && crate::subrepo::is_subrepo(working_copy, &full_path) {
+                    meta = InodeMetadata::new_subrepo()
+                }
+                if !self.selects(&full_path, meta.is_dir()) {
+                    debug!("filtered out {:?}", full_path);
+                    continue;
+                }
+                if get_inodes(txn, &channel, child_inode)?.is_none()
+                    && self.ignored(working_copy, &full_path, meta.is_dir())
+                {
+                    debug!("ignored {:?}", full_path);
+                    continue;
+                }
                 stack.push((
                     RecordItem {
                         papa: item.inode,
@@ -599,6 +1517,8 @@ impl Builder {
                     },
                     components.clone(),
                 ));
+            } else if !self.selects(&full_path, false) {
+                debug!("filtered out {:?}", full_path);
             } else if let Some(vertex) = get_inodes(txn, &channel, child_inode)? {
                 let rec = self.recorded();
                 let mut rec = rec.lock();
@@ -643,24 +1563,248 @@ fn modified_since_last_commit<T: ChannelTxnT, W: WorkingCopy>(
     }
 }
 
+/// The tracked files of `channel` whose contents hash to one of
+/// `wanted`, and their inode vertices, indexed by that hash.
+///
+/// The hashes stored in the file statistics are used when they are
+/// still valid, and the other files are only output if their size is
+/// in `sizes`, or if `sizes` is `None`. Since statistics can be older
+/// than the pristine, this may miss a few sources, in which case
+/// copies are recorded as plain additions.
+fn copy_sources<T: TxnT, C: ChangeStore>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    limits: RetrieveLimits,
+    wanted: &HashSet<Hash>,
+    sizes: Option<&HashSet<u64>>,
+) -> Result<
+    HashMap<Hash, (String, Position<ChangeId>)>,
+    crate::output::FileError<C::Error, T::GraphError>,
+> {
+    let mut sources = HashMap::default();
+    for x in crate::fs::iter_working_copy(txn, Inode::ROOT) {
+        let (inode, path) = x.map_err(crate::output::FileError::Txn)?;
+        let pos = if let Some(pos) = get_inodes(txn, channel, &inode)? {
+            *pos
+        } else {
+            continue;
+        };
+        let stats = crate::file_stats::load(txn, inode).ok().flatten();
+        let known = if let Some(verified) = stats.as_ref().and_then(|s| s.verified) {
+            if verified.history == crate::file_stats::history(txn, channel, pos)? {
+                Some(verified.contents)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        let hash = if let Some(hash) = known {
+            hash
+        } else {
+            match (sizes, &stats) {
+                (Some(sizes), Some(stats)) if !sizes.contains(&stats.size) => continue,
+                _ => {}
+            }
+            let mut w = crate::vertex_buffer::Writer::new(Vec::new());
+            crate::output::output_file(changes, txn, channel, pos, &mut w, limits)?;
+            let contents = w.into_inner();
+            // Directories have no contents.
+            if contents.is_empty() {
+                continue;
+            }
+            let mut hasher = Hasher::default();
+            hasher.update(&contents);
+            hasher.finish()
+        };
+        if wanted.contains(&hash) {
+            sources.entry(hash).or_insert((path, pos));
+        }
+    }
+    Ok(sources)
+}
+
+/// A new file, read from the working copy.
+pub(crate) struct NewFile {
+    pub(crate) meta: InodeMetadata,
+    pub(crate) contents: Vec<u8>,
+    pub(crate) encoding: Option<Encoding>,
+}
+
+pub(crate) fn read_new_file<W: WorkingCopy>(
+    working_copy: &W,
+    item: &RecordItem,
+) -> Result<NewFile, W::Error> {
+    if item.metadata.is_subrepo() {
+        return Ok(NewFile {
+            meta: item.metadata,
+            contents: crate::subrepo::read_pointer(working_copy, &item.full_path)?
+                .unwrap_or_default(),
+            encoding: Some(Encoding(encoding_rs::UTF_8)),
+        });
+    }
+    let meta = working_copy.file_metadata(&item.full_path)?;
+    let mut contents = Vec::new();
+    let encoding = if meta.is_file() {
+        working_copy.decode_file(&item.full_path, &mut contents)?
+    } else {
+        None
+    };
+    Ok(NewFile {
+        meta,
+        contents,
+        encoding,
+    })
+}
+
 impl Recorded {
+    /// Remove the redundant edges found while comparing the files with
+    /// the pristine, i.e. the pseudo-edges whose target is also
+    /// reachable through another path, and return the number of edges
+    /// removed.
+    ///
+    /// These edges are only known to be redundant in the graph that
+    /// was recorded, so this must be called in the same transaction
+    /// as the recording, before applying anything to `channel`.
+    pub fn apply_redundant_cleanup<T: ChannelMutTxnT>(
+        &mut self,
+        txn: &mut T,
+        channel: &mut T::Channel,
+    ) -> Result<usize, TxnErr<T::GraphError>> {
+        let graph = T::graph_mut(channel);
+        let mut removed = 0;
+        for (vertex, edge) in std::mem::take(&mut self.redundant) {
+            let dest = *txn.find_block(graph, edge.dest()).unwrap();
+            debug!("removing redundant edge {:?} {:?} {:?}", vertex, dest, edge);
+            if del_graph_with_rev(txn, graph, edge.flag(), vertex, dest, edge.introduced_by())? {
+                removed += 1
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Store the content statistics of the files read by this
+    /// recording in the pristine, along with the
+    /// [dirty cache](crate::dirty_cache) entries of the files found
+    /// identical to the pristine, and delete those of the files it
+    /// deleted. This must be called after applying the recorded
+    /// change to `channel`, in the same transaction, since the
+    /// statistics of the files found identical to the pristine depend
+    /// on the changes touching them.
+    pub fn save_file_stats<T: MutTxnT>(
+        &self,
+        txn: &mut T,
+        channel: &T::Channel,
+    ) -> Result<(), TxnErr<T::GraphError>> {
+        for (inode, stats) in self.file_stats.iter() {
+            let mut stats = stats.clone();
+            if let Some(contents) = self.unchanged.get(inode) {
+                if let Some(pos) = txn.get_inodes(inode, None)? {
+                    let pos = *pos;
+                    stats.verified = Some(crate::file_stats::Verified {
+                        contents: *contents,
+                        history: crate::file_stats::history(txn, channel, pos)?,
+                    })
+                }
+            }
+            crate::file_stats::save(txn, *inode, &stats)?
+        }
+        for (inode, stat) in self.clean.iter() {
+            if let Some(pos) = txn.get_inodes(inode, None)? {
+                let pos = *pos;
+                crate::dirty_cache::update(txn, channel, *inode, pos, *stat)?
+            }
+        }
+        for update in self.updatables.values() {
+            if let InodeUpdate::Deleted { inode } = update {
+                txn.del_file_stats(*inode)?;
+                txn.del_dirty_cache(*inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no change was applied to or unrecorded from the
+    /// channel since [`Builder::record`] took it in
+    /// [`Builder::intents`], if it did. This must be called before
+    /// applying the recorded change, in the same transaction.
+    pub fn check_intent<T: TxnT>(
+        &self,
+        txn: &T,
+    ) -> Result<(), crate::intent::IntentError<T::GraphError>> {
+        if let Some(ref intent) = self.intent {
+            intent.check(txn)?
+        }
+        Ok(())
+    }
+
+    /// Add `item` to the change, returning its inode vertex if it is
+    /// a directory. Files that can't be read are skipped, unless the
+    /// read error policy is [`ReadErrorPolicy::Fail`].
     fn add_file<W: WorkingCopy>(
         &mut self,
         working_copy: &W,
         item: RecordItem,
     ) -> Result<Option<Position<Option<ChangeId>>>, W::Error> {
         debug!("record_file_addition {:?}", item);
-        let meta = working_copy.file_metadata(&item.full_path)?;
+        match retry(self.read_error_policy, || {
+            read_new_file(working_copy, &item)
+        }) {
+            Ok(file) => Ok(self.push_file_addition(item, file)),
+            Err(e) if self.read_error_policy == ReadErrorPolicy::Fail => Err(e),
+            Err(e) => {
+                self.report.lock().skip(&item.full_path, &e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Add a `FileAdd` hunk for `file`, returning the inode vertex if
+    /// `file` is a directory.
+    pub(crate) fn push_file_addition(
+        &mut self,
+        item: RecordItem,
+        mut file: NewFile,
+    ) -> Option<Position<Option<ChangeId>>> {
+        let meta = file.meta;
+        if meta.is_file() {
+            file.encoding = self.text_encoding(&item.full_path, &file.contents, file.encoding);
+            self.file_stats.insert(
+                item.inode,
+                FileStats::compute(&file.contents, &file.encoding),
+            );
+        }
+        self.apply_eol_policy(&mut file.contents, &file.encoding);
+        let contents_hash = if meta.is_file() && (self.verify_additions || self.detect_copies) {
+            let mut hasher = Hasher::default();
+            hasher.update(&file.contents);
+            Some(hasher.finish())
+        } else {
+            None
+        };
+        if let (true, Some(hash)) = (self.verify_additions, contents_hash) {
+            self.unchanged.insert(item.inode, hash);
+        }
+        let subrepo_state = if meta.is_subrepo() {
+            Some(crate::subrepo::SubrepoState::parse(&file.contents).unwrap_or_default())
+        } else {
+            None
+        };
         let mut contents = self.contents.lock();
+        let chunk_start = contents.len();
         contents.push(0);
         let inode_pos = ChangePosition(contents.len().into());
         contents.push(0);
         let (contents_, encoding) = if meta.is_file() {
             let start = ChangePosition(contents.len().into());
-            let encoding = working_copy.decode_file(&item.full_path, &mut contents)?;
+            contents.extend_from_slice(&file.contents);
+            let encoding = file.encoding;
             self.has_binary_files |= encoding.is_none();
             let end = ChangePosition(contents.len().into());
-            self.largest_file = self.largest_file.max(end.0.as_u64() - start.0.as_u64());
+            let size = end.0.as_u64() - start.0.as_u64();
+            self.largest_file = self.largest_file.max(size);
+            self.observe_file(&item.full_path, size, &encoding);
             contents.push(0);
             if end > start {
                 (
@@ -696,31 +1840,56 @@ impl Recorded {
         file_meta.write(&mut contents);
         let name_end = ChangePosition(contents.len().into());
         contents.push(0);
-        self.actions.push(Hunk::FileAdd {
-            add_name: Atom::NewVertex(NewVertex {
-                up_context: vec![item.v_papa],
-                down_context: vec![],
-                start: name_start,
-                end: name_end,
-                flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
-                inode: item.v_papa,
-            }),
-            add_inode: Atom::NewVertex(NewVertex {
-                up_context: vec![Position {
-                    change: None,
-                    pos: name_end,
-                }],
-                down_context: vec![],
-                start: inode_pos,
-                end: inode_pos,
-                flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
-                inode: item.v_papa,
-            }),
-            contents: contents_,
-            path: item.full_path.clone(),
-            encoding,
+        self.chunks.push(chunk_start..contents.len());
+        let add_name = Atom::NewVertex(NewVertex {
+            up_context: vec![item.v_papa],
+            down_context: vec![],
+            start: name_start,
+            end: name_end,
+            flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
+            inode: item.v_papa,
         });
+        let add_inode = Atom::NewVertex(NewVertex {
+            up_context: vec![Position {
+                change: None,
+                pos: name_end,
+            }],
+            down_context: vec![],
+            start: inode_pos,
+            end: inode_pos,
+            flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
+            inode: item.v_papa,
+        });
+        let hunk = if let Some(state) = subrepo_state {
+            Hunk::SubrepoPointer {
+                add_name,
+                add_inode,
+                contents: contents_,
+                path: item.full_path.clone(),
+                channel: state.channel,
+                change: state.change,
+                encoding,
+            }
+        } else {
+            if let (true, Some(hash), false) =
+                (self.detect_copies, contents_hash, file.contents.is_empty())
+            {
+                self.copy_candidates
+                    .push((self.actions.len(), hash, file.contents.len() as u64))
+            }
+            Hunk::FileAdd {
+                add_name,
+                add_inode,
+                contents: contents_,
+                path: item.full_path.clone(),
+                encoding,
+            }
+        };
+        self.actions.push(hunk);
         debug!("{:?}", self.actions.last().unwrap());
+        if let Some(ref observer) = self.observer {
+            observer.hunks_produced(&item.full_path, 1)
+        }
         self.updatables.insert(
             self.actions.len(),
             InodeUpdate::Add {
@@ -729,23 +1898,230 @@ impl Recorded {
             },
         );
         if meta.is_dir() {
-            Ok(Some(Position {
+            Some(Position {
                 change: None,
                 pos: inode_pos,
-            }))
+            })
         } else {
-            Ok(None)
+            None
+        }
+    }
+
+    fn observe(&self, event: RecordEvent) {
+        if let Some(ref observer) = self.observer {
+            observer.event(event)
+        }
+    }
+
+    /// Report the size and encoding of a file of `size` bytes.
+    fn observe_file(&self, path: &str, size: u64, encoding: &Option<Encoding>) {
+        if size > self.limits.large_file {
+            self.observe(RecordEvent::LargeFile {
+                path: path.to_string(),
+                size,
+            })
+        }
+        if encoding.is_none() {
+            self.observe(RecordEvent::BinaryFile {
+                path: path.to_string(),
+            })
         }
     }
 
+    /// The text policy of `path`: [`Builder::text_policy`] if set, or
+    /// else the policy of its `text` attribute.
+    pub(crate) fn text_policy(&self, path: &str) -> TextPolicy {
+        self.text_policy
+            .or_else(|| self.text_rules.policy(path))
+            .unwrap_or_default()
+    }
+
+    /// The encoding of `contents`, the contents of `path`, recorded as
+    /// text under [`TextPolicy::Hybrid`] if it is mostly text, even
+    /// though `encoding` says it's binary.
+    fn text_encoding(
+        &self,
+        path: &str,
+        contents: &[u8],
+        encoding: Option<Encoding>,
+    ) -> Option<Encoding> {
+        match (encoding, self.text_policy(path)) {
+            (None, TextPolicy::Hybrid { .. })
+                if crate::text_encoding::mostly_text(contents, true) =>
+            {
+                Some(encoding_rs::UTF_8.into())
+            }
+            (encoding, _) => encoding,
+        }
+    }
+
+    fn apply_eol_policy(&self, contents: &mut Vec<u8>, encoding: &Option<Encoding>) {
+        if let (EolPolicy::Lf, Some(encoding)) = (self.eol_policy, encoding) {
+            // Line endings of other encodings aren't single bytes.
+            if encoding.0.is_ascii_compatible() {
+                crate::channel_settings::normalize_eol(contents)
+            }
+        }
+    }
+
+    /// Whether `path` is to be diffed in windows, see
+    /// [`Builder::stream_threshold`], and if so, its encoding (`None`
+    /// for binary files). Only the first window is read to detect the
+    /// encoding. Text files are only streamed if they are diffed by
+    /// lines, without words, transforms or line ending normalisation,
+    /// and if their encoding is ASCII-compatible.
+    fn streams<W: WorkingCopy>(&self, working_copy: &W, path: &str) -> Option<Option<Encoding>> {
+        match self.stream_threshold {
+            Some(threshold) if self.transform.is_none() => match working_copy.file_size(path) {
+                Ok(Some(size)) if size > threshold => {}
+                _ => return None,
+            },
+            _ => return None,
+        }
+        let hybrid = matches!(self.text_policy(path), TextPolicy::Hybrid { .. });
+        let mut streams = None;
+        let read = working_copy.read_file_windows(path, STREAM_WINDOW, &mut |w| {
+            streams = match working_copy.detect_encoding(path, w, false) {
+                None if self.binary_policy == BinaryPolicy::Lines => None,
+                None if hybrid && crate::text_encoding::mostly_text(w, false) => None,
+                None => Some(None),
+                Some(encoding)
+                    if encoding.0.is_ascii_compatible()
+                        && !hybrid
+                        && !self.word_diff
+                        && self.eol_policy != EolPolicy::Lf =>
+                {
+                    Some(Some(encoding))
+                }
+                Some(_) => None,
+            };
+            false
+        });
+        if read.is_ok() {
+            streams
+        } else {
+            None
+        }
+    }
+
+    /// Diff `item` in windows. Returns the hash and size of the file,
+    /// or `None` if it couldn't be read and was skipped.
+    fn record_streamed_file<T: ChannelTxnT, W: WorkingCopy, C: ChangeStore>(
+        &mut self,
+        txn: &T,
+        channel: &T::Channel,
+        diff_algorithm: &dyn DiffAlgorithm,
+        working_copy: &W,
+        changes: &C,
+        item: &RecordItem,
+        vertex: Position<ChangeId>,
+        encoding: &Option<Encoding>,
+    ) -> Result<Option<(Hash, FileStats)>, RecordError<C::Error, W::Error, T::GraphError>>
+    where
+        W::Error: 'static,
+    {
+        debug!("streaming {:?}", item.full_path);
+        let mut attempt = 0;
+        loop {
+            // The graph is consumed by the diff, and needs to be
+            // retrieved again for each attempt.
+            let mut ret = retrieve_limited(txn, txn.graph(channel), vertex, self.graph_limits)?;
+            let mut hasher = Hasher::default();
+            let mut stats = FileStats::compute(&[], encoding);
+            let mut after_cr = false;
+            let mut error = None;
+            self.diff_windows(
+                changes,
+                txn,
+                channel,
+                diff_algorithm,
+                item.full_path.clone(),
+                vertex.to_option(),
+                &mut ret,
+                encoding,
+                &mut |f| {
+                    let read =
+                        working_copy.read_file_windows(&item.full_path, STREAM_WINDOW, &mut |w| {
+                            hasher.update(w);
+                            stats.extend(w, encoding, after_cr);
+                            after_cr = w.last().map_or(after_cr, |&c| c == b'\r');
+                            f(w);
+                            true
+                        });
+                    match read {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error = Some(e);
+                            false
+                        }
+                    }
+                },
+            )?;
+            let e = if let Some(e) = error {
+                e
+            } else {
+                return Ok(Some((hasher.finish(), stats)));
+            };
+            match self.read_error_policy {
+                ReadErrorPolicy::Retry { attempts, pause } if attempt < attempts => {
+                    attempt += 1;
+                    std::thread::sleep(pause)
+                }
+                ReadErrorPolicy::Fail => return Err(RecordError::WorkingCopy(e)),
+                _ => {
+                    self.report.lock().skip(&item.full_path, &e);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Report the hunks produced by diffing `item` from `len` on, or
+    /// remember that it was unchanged, along with its `stat` if any.
+    fn diffed<W: WorkingCopy>(
+        &mut self,
+        working_copy: &W,
+        item: &RecordItem,
+        len: usize,
+        contents_hash: Hash,
+        stat: Option<crate::dirty_cache::Stat>,
+    ) {
+        let hunks = self.actions.len() - len;
+        if hunks > self.limits.many_hunks {
+            self.observe(RecordEvent::ManyHunks {
+                path: item.full_path.clone(),
+                hunks,
+            })
+        }
+        if self.actions.len() > len {
+            if let Ok(last_modified) = working_copy.modified_time(&item.full_path) {
+                if self.oldest_change == std::time::SystemTime::UNIX_EPOCH {
+                    self.oldest_change = last_modified;
+                } else {
+                    self.oldest_change = self.oldest_change.min(last_modified);
+                }
+            }
+        } else {
+            self.unchanged.insert(item.inode, contents_hash);
+            if let Some(stat) = stat {
+                self.clean.insert(item.inode, stat);
+            }
+        }
+        debug!(
+            "new actions: {:?}, total {:?}",
+            &self.actions.len() - len,
+            self.actions.len()
+        );
+    }
+
     fn record_existing_file<
-        T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+        T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError> + TxnT,
         W: WorkingCopy + Clone,
         C: ChangeStore,
     >(
         &mut self,
         txn: &ArcTxn<T>,
-        diff_algorithm: diff::Algorithm,
+        diff_algorithm: &dyn DiffAlgorithm,
         channel: &ChannelRef<T>,
         working_copy: W,
         changes: &C,
@@ -765,6 +2141,7 @@ impl Recorded {
         let f0 = EdgeFlags::FOLDER | EdgeFlags::PARENT;
         let f1 = EdgeFlags::all();
         let mut is_deleted = true;
+        let mut has_deleted_name = false;
         let txn_ = txn.read();
         let channel_ = channel.read();
         for name_ in iter_adjacent(
@@ -783,6 +2160,7 @@ impl Recorded {
             if name_.flag().contains(EdgeFlags::DELETED) {
                 debug!("is_deleted {:?}: {:?}", item.full_path, name_);
                 is_deleted = true;
+                has_deleted_name = true;
                 break;
             }
             let name_dest = txn_
@@ -823,8 +2201,24 @@ impl Recorded {
             item, former_parents, is_deleted,
         );
         assert!(!former_parents.is_empty());
+        // Only compare the permission bits tracked by both the
+        // working copy and the recorded metadata, keeping the others.
+        let item = &{
+            let former = former_parents[0].metadata.permissions();
+            let mask = if InodeMetadata::is_full_permissions(former) {
+                working_copy.permission_mask() & 0o777
+            } else {
+                working_copy.permission_mask() & 0o100
+            };
+            let mut item = item.clone();
+            item.metadata = InodeMetadata(
+                (item.metadata.0 & !0o777) | (former & !mask) | (item.metadata.0 & mask),
+            );
+            item
+        };
         if let Ok(new_meta) = working_copy.file_metadata(&item.full_path) {
             debug!("new_meta = {:?}", new_meta);
+            let before_move = self.actions.len();
             if former_parents.len() > 1
                 || former_parents[0].basename != item.basename
                 || former_parents[0].metadata != item.metadata
@@ -832,6 +2226,10 @@ impl Recorded {
                 || is_deleted
             {
                 debug!("new_papa = {:?}", new_papa);
+                let meta_only = former_parents.len() == 1
+                    && former_parents[0].basename == item.basename
+                    && former_parents[0].parent == item.v_papa
+                    && !has_deleted_name;
                 self.record_moved_file::<_, _, W>(
                     changes,
                     &*txn_,
@@ -840,24 +2238,17 @@ impl Recorded {
                     vertex,
                     new_papa.unwrap(),
                     former_parents[0].encoding.clone(),
+                    meta_only,
                 )?
             }
-            if new_meta.is_file()
-                && (self.force_rediff
-                    || modified_since_last_commit(
-                        &*txn_,
-                        &*channel_,
-                        &working_copy,
-                        &item.full_path,
-                    )?)
-            {
-                let mut ret = retrieve(&*txn_, txn_.graph(&*channel_), vertex)?;
-                let mut b = Vec::new();
-                let encoding = working_copy
-                    .decode_file(&item.full_path, &mut b)
-                    .map_err(RecordError::WorkingCopy)?;
-                debug!("diffing…");
-                let len = self.actions.len();
+            let moved = self.actions.len() > before_move;
+            if item.metadata.is_subrepo() {
+                // The contents of a nested repository are its pointer.
+                let b = crate::subrepo::read_pointer(&working_copy, &item.full_path)
+                    .map_err(RecordError::WorkingCopy)?
+                    .unwrap_or_default();
+                let mut ret =
+                    retrieve_limited(&*txn_, txn_.graph(&*channel_), vertex, self.graph_limits)?;
                 self.diff(
                     changes,
                     &*txn_,
@@ -867,22 +2258,128 @@ impl Recorded {
                     vertex.to_option(),
                     &mut ret,
                     &b,
-                    &encoding,
+                    &Some(Encoding(encoding_rs::UTF_8)),
                 )?;
-                if self.actions.len() > len {
-                    if let Ok(last_modified) = working_copy.modified_time(&item.full_path) {
-                        if self.oldest_change == std::time::SystemTime::UNIX_EPOCH {
-                            self.oldest_change = last_modified;
-                        } else {
-                            self.oldest_change = self.oldest_change.min(last_modified);
+            } else if new_meta.is_file()
+                && (self.force_rediff
+                    || modified_since_last_commit(
+                        &*txn_,
+                        &*channel_,
+                        &working_copy,
+                        &item.full_path,
+                    )?)
+            {
+                // Read before the contents, so that a modification
+                // while reading invalidates the cache entry.
+                let stat = if self.dirty_cache && !self.force_rediff {
+                    crate::dirty_cache::stat(&working_copy, &item.full_path)
+                } else {
+                    None
+                };
+                if let Some(stat) = stat {
+                    if crate::dirty_cache::is_clean(&*txn_, &*channel_, item.inode, vertex, stat)? {
+                        debug!("clean in the dirty cache: {:?}", item.full_path);
+                        self.clean.insert(item.inode, stat);
+                        return Ok(());
+                    }
+                }
+                if let Some(encoding) = self.streams(&working_copy, &item.full_path) {
+                    let len = self.actions.len();
+                    if let Some((contents_hash, stats)) = self.record_streamed_file(
+                        &*txn_,
+                        &*channel_,
+                        diff_algorithm,
+                        &working_copy,
+                        changes,
+                        item,
+                        vertex,
+                        &encoding,
+                    )? {
+                        self.observe_file(&item.full_path, stats.size, &encoding);
+                        self.file_stats.insert(item.inode, stats);
+                        self.diffed(&working_copy, item, len, contents_hash, stat);
+                    }
+                    return Ok(());
+                }
+                // The statistics of the last record save detecting
+                // the encoding again if the file is still in it.
+                let stats = if self.force_rediff {
+                    None
+                } else {
+                    crate::file_stats::load(&*txn_, item.inode).ok().flatten()
+                };
+                let mut b = Vec::new();
+                let encoding = retry(self.read_error_policy, || {
+                    b.clear();
+                    working_copy.decode_file_with_stats(&item.full_path, &mut b, stats.as_ref())
+                });
+                let encoding = match encoding {
+                    Ok(encoding) => Some(encoding),
+                    Err(e) if self.read_error_policy == ReadErrorPolicy::Fail => {
+                        return Err(RecordError::WorkingCopy(e))
+                    }
+                    Err(e) => {
+                        self.report.lock().skip(&item.full_path, &e);
+                        None
+                    }
+                };
+                if let Some(encoding) = encoding {
+                    let encoding = self.text_encoding(&item.full_path, &b, encoding);
+                    if let (Some(transform), Some(_)) = (&self.transform, &encoding) {
+                        if let Some(new) = transform.transform(&item.full_path, &b) {
+                            if new != b {
+                                debug!("transformed {:?}", item.full_path);
+                                b = new
+                            }
+                        }
+                    }
+                    self.file_stats
+                        .insert(item.inode, FileStats::compute(&b, &encoding));
+                    self.apply_eol_policy(&mut b, &encoding);
+                    self.observe_file(&item.full_path, b.len() as u64, &encoding);
+                    let mut hasher = Hasher::default();
+                    hasher.update(&b);
+                    let contents_hash = hasher.finish();
+                    // If only the name or permissions of the file
+                    // changed, and its contents are the ones last
+                    // found identical to the pristine, the diff would
+                    // be empty.
+                    if moved && !self.force_rediff {
+                        if let Some(verified) = crate::file_stats::verified(&*txn_, item.inode)? {
+                            if verified.contents == contents_hash
+                                && verified.history
+                                    == crate::file_stats::history(&*txn_, &*channel_, vertex)?
+                            {
+                                debug!("metadata-only change: {:?}", item.full_path);
+                                self.unchanged.insert(item.inode, contents_hash);
+                                if let Some(stat) = stat {
+                                    self.clean.insert(item.inode, stat);
+                                }
+                                return Ok(());
+                            }
                         }
                     }
+                    let mut ret = retrieve_limited(
+                        &*txn_,
+                        txn_.graph(&*channel_),
+                        vertex,
+                        self.graph_limits,
+                    )?;
+                    debug!("diffing…");
+                    let len = self.actions.len();
+                    self.diff(
+                        changes,
+                        &*txn_,
+                        &*channel_,
+                        diff_algorithm,
+                        item.full_path.clone(),
+                        vertex.to_option(),
+                        &mut ret,
+                        &b,
+                        &encoding,
+                    )?;
+                    self.diffed(&working_copy, item, len, contents_hash, stat);
                 }
-                debug!(
-                    "new actions: {:?}, total {:?}",
-                    &self.actions.len() - len,
-                    self.actions.len()
-                );
             }
         } else {
             debug!("calling record_deleted_file on {:?}", item.full_path);
@@ -907,11 +2404,12 @@ impl Recorded {
         vertex: Position<ChangeId>,
         new_papa: Position<Option<ChangeId>>,
         encoding: Option<Encoding>,
+        meta_only: bool,
     ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
     where
         <W as crate::working_copy::WorkingCopy>::Error: 'static,
     {
-        debug!("record_moved_file {:?}", item);
+        debug!("record_moved_file {:?} {:?}", item, meta_only);
         let mut contents = self.contents.lock();
         let basename = item.basename.as_str();
         let meta_start = ChangePosition(contents.len().into());
@@ -949,22 +2447,27 @@ impl Recorded {
         }
         if !moved.edges.is_empty() {
             if moved.need_new_name {
-                self.actions.push(Hunk::FileMove {
-                    del: Atom::EdgeMap(EdgeMap {
-                        edges: moved.edges,
-                        inode: item.v_papa,
-                    }),
-                    add: Atom::NewVertex(NewVertex {
-                        up_context: vec![item.v_papa],
-                        down_context: vec![vertex.to_option()],
-                        start: meta_start,
-                        end: meta_end,
-                        flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
-                        inode: item.v_papa,
-                    }),
-                    path: crate::fs::find_path(changes, txn, channel, true, vertex)?
-                        .unwrap()
-                        .0,
+                let del = Atom::EdgeMap(EdgeMap {
+                    edges: moved.edges,
+                    inode: item.v_papa,
+                });
+                let add = Atom::NewVertex(NewVertex {
+                    up_context: vec![item.v_papa],
+                    down_context: vec![vertex.to_option()],
+                    start: meta_start,
+                    end: meta_end,
+                    flag: EdgeFlags::FOLDER | EdgeFlags::BLOCK,
+                    inode: item.v_papa,
+                });
+                let path = crate::fs::find_path(changes, txn, channel, true, vertex)?
+                    .unwrap()
+                    .0;
+                // A file whose name and parent are unchanged only had
+                // its permissions changed.
+                self.actions.push(if meta_only {
+                    Hunk::FileMeta { del, add, path }
+                } else {
+                    Hunk::FileMove { del, add, path }
                 });
             } else {
                 self.actions.push(Hunk::SolveNameConflict {
@@ -979,6 +2482,7 @@ impl Recorded {
         } else {
             contents.truncate(meta_start.0.as_usize())
         }
+        self.chunks.push(meta_start.0.as_usize()..contents.len());
         Ok(())
     }
 }
@@ -0,0 +1,173 @@
+//! Putting the contents of a change in a deterministic order.
+//!
+//! All the [`Recorded`] of a [`Builder`](super::Builder) append to the
+//! same contents, but files are diffed on several threads, so the
+//! order in which their bytes are appended depends on scheduling.
+//! Each [`Recorded`] remembers the chunks of contents it appended,
+//! and [`relocate`] concatenates these chunks in the order of the
+//! [`Recorded`] (i.e. the order of traversal), moving the positions
+//! of the new vertices accordingly. Recording the same working copy
+//! with any number of threads yields the same change.
+use super::{InodeUpdate, Recorded};
+use crate::change::{Atom, Hunk, Local};
+use crate::pristine::*;
+use std::ops::Range;
+use thiserror::Error;
+
+/// The chunks recorded by the [`Recorded`] don't cover their
+/// contents, so that the order of the contents can't be made
+/// deterministic.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("Contents chunks cover {covered} bytes out of {len}")]
+pub struct ChunksError {
+    pub covered: usize,
+    pub len: usize,
+}
+
+/// The chunks of the old contents, sorted by start, with their start
+/// in the new contents.
+struct Chunks(Vec<(Range<usize>, usize)>);
+
+impl Chunks {
+    /// The new position of `pos`. Up contexts point to the end of
+    /// vertices, and down contexts to their start, which may be the
+    /// end of the previous chunk.
+    fn relocate(&self, pos: ChangePosition, up: bool) -> ChangePosition {
+        let p = pos.0.as_usize();
+        let i = if up {
+            self.0.partition_point(|(c, _)| c.start < p)
+        } else {
+            self.0.partition_point(|(c, _)| c.start <= p)
+        };
+        if i > 0 {
+            let (ref c, new_start) = self.0[i - 1];
+            if (up && p <= c.end) || (!up && p < c.end) {
+                return ChangePosition((p - c.start + new_start).into());
+            }
+        }
+        pos
+    }
+
+    fn position(&self, p: &mut Position<Option<ChangeId>>, up: bool) {
+        if p.change.is_none() {
+            p.pos = self.relocate(p.pos, up)
+        }
+    }
+
+    fn vertex(&self, start: &mut ChangePosition, end: &mut ChangePosition) {
+        let len = end.0.as_usize() - start.0.as_usize();
+        *start = self.relocate(*start, false);
+        *end = ChangePosition((start.0.as_usize() + len).into());
+    }
+
+    fn atom(&self, atom: &mut Atom<Option<ChangeId>>) {
+        match atom {
+            Atom::NewVertex(v) => {
+                for p in v.up_context.iter_mut() {
+                    self.position(p, true)
+                }
+                for p in v.down_context.iter_mut() {
+                    self.position(p, false)
+                }
+                self.position(&mut v.inode, true);
+                self.vertex(&mut v.start, &mut v.end)
+            }
+            Atom::EdgeMap(e) => {
+                for edge in e.edges.iter_mut() {
+                    self.position(&mut edge.from, true);
+                    if edge.to.change.is_none() {
+                        self.vertex(&mut edge.to.start, &mut edge.to.end)
+                    }
+                }
+                self.position(&mut e.inode, true)
+            }
+        }
+    }
+}
+
+fn atoms_mut(hunk: &mut Hunk<Option<ChangeId>, Local>) -> Vec<&mut Atom<Option<ChangeId>>> {
+    match hunk {
+        Hunk::FileMove { del, add, .. } | Hunk::FileMeta { del, add, .. } => vec![del, add],
+        Hunk::FileDel { del, contents, .. } => std::iter::once(del).chain(contents).collect(),
+        Hunk::FileUndel {
+            undel, contents, ..
+        } => std::iter::once(undel).chain(contents).collect(),
+        Hunk::FileAdd {
+            add_name,
+            add_inode,
+            contents,
+            ..
+        }
+        | Hunk::FileCopy {
+            add_name,
+            add_inode,
+            contents,
+            ..
+        }
+        | Hunk::SubrepoPointer {
+            add_name,
+            add_inode,
+            contents,
+            ..
+        } => vec![add_name, add_inode]
+            .into_iter()
+            .chain(contents)
+            .collect(),
+        Hunk::SolveNameConflict { name, .. } | Hunk::UnsolveNameConflict { name, .. } => {
+            vec![name]
+        }
+        Hunk::Edit { change, .. }
+        | Hunk::SolveOrderConflict { change, .. }
+        | Hunk::UnsolveOrderConflict { change, .. }
+        | Hunk::ResurrectZombies { change, .. } => vec![change],
+        Hunk::Replacement {
+            change,
+            replacement,
+            ..
+        } => vec![change, replacement],
+        Hunk::BinaryEdit { delete, insert, .. } => delete.iter_mut().chain(insert).collect(),
+        Hunk::Custom { .. } => Vec::new(),
+    }
+}
+
+/// Rewrite `contents` as the concatenation of the chunks of `recs`,
+/// in order, and move the new vertices of `recs` accordingly. If the
+/// chunks don't cover `contents` exactly, nothing is changed and an
+/// error is returned.
+pub(super) fn relocate(contents: &mut Vec<u8>, recs: &mut [Recorded]) -> Result<(), ChunksError> {
+    let mut chunks = Vec::new();
+    let mut new = Vec::with_capacity(contents.len());
+    for rec in recs.iter() {
+        for c in rec.chunks.iter() {
+            if c.start < c.end {
+                chunks.push((c.clone(), new.len()));
+                new.extend_from_slice(&contents[c.clone()]);
+            }
+        }
+    }
+    if new.len() != contents.len() {
+        return Err(ChunksError {
+            covered: new.len(),
+            len: contents.len(),
+        });
+    }
+    if chunks.iter().all(|(c, new_start)| c.start == *new_start) {
+        return Ok(());
+    }
+    chunks.sort_by_key(|(c, _)| c.start);
+    let chunks = Chunks(chunks);
+    for rec in recs.iter_mut() {
+        for hunk in rec.actions.iter_mut() {
+            for atom in atoms_mut(hunk) {
+                chunks.atom(atom)
+            }
+        }
+        for update in rec.updatables.values_mut() {
+            if let InodeUpdate::Add { ref mut pos, .. } = update {
+                *pos = chunks.relocate(*pos, false)
+            }
+        }
+    }
+    *contents = new;
+    Ok(())
+}
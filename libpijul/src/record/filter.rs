@@ -0,0 +1,173 @@
+//! Selecting the paths to record with glob patterns.
+//!
+//! A [`PathFilter`] is a list of include patterns and exclude
+//! patterns (starting with `!`). Patterns are matched against whole
+//! paths relative to the root of the repository, component by
+//! component: `*` matches any part of a component, `?` any single
+//! character, and a `**` component any number of components,
+//! including none. For instance, `["src/**", "!src/generated/**"]`
+//! selects `src` and everything below it, except `src/generated`.
+//!
+//! The filter is evaluated while traversing the tree, so that the
+//! excluded directories are not even read.
+
+/// Include and exclude patterns, see the [module documentation](self).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PathFilter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+/// A glob pattern, split into path components.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Glob(Vec<Vec<char>>);
+
+impl PathFilter {
+    /// A filter from glob patterns. Patterns starting with `!` are
+    /// exclude patterns. If there are no include patterns, all the
+    /// paths that aren't excluded are recorded.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Self {
+        let mut filter = PathFilter::default();
+        for p in patterns {
+            let p = p.as_ref();
+            if let Some(p) = p.strip_prefix('!') {
+                filter.exclude.push(Glob::new(p))
+            } else {
+                filter.include.push(Glob::new(p))
+            }
+        }
+        filter
+    }
+
+    /// A filter selecting `prefixes` and everything below them.
+    pub fn prefixes<S: AsRef<str>>(prefixes: &[S]) -> Self {
+        let mut filter = PathFilter::default();
+        for p in prefixes {
+            let mut glob = Glob::new(p.as_ref());
+            glob.0.push(vec!['*', '*']);
+            filter.include.push(glob)
+        }
+        filter
+    }
+
+    /// Whether `path` is selected by this filter.
+    pub fn matches(&self, path: &str) -> bool {
+        let path: Vec<_> = components(path).collect();
+        !self.excluded(&path)
+            && (self.include.is_empty() || self.include.iter().any(|g| g.matches(&path, false)))
+    }
+
+    /// Whether directory `path` may contain paths selected by this
+    /// filter, and must therefore be traversed.
+    pub fn descends(&self, path: &str) -> bool {
+        let path: Vec<_> = components(path).collect();
+        !self.excluded(&path)
+            && (self.include.is_empty() || self.include.iter().any(|g| g.matches(&path, true)))
+    }
+
+    /// Whether `path` or one of its ancestors is excluded.
+    fn excluded(&self, path: &[&str]) -> bool {
+        (1..=path.len()).any(|n| self.exclude.iter().any(|g| g.matches(&path[..n], false)))
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Self {
+        Glob(components(pattern).map(|c| c.chars().collect()).collect())
+    }
+
+    /// Whether `path` matches this pattern, or, if `partial` is
+    /// `true`, is a prefix of a path matching it.
+    ///
+    /// The results for each pair of suffixes of the pattern and the
+    /// path are memoized, so that consecutive `**` components don't
+    /// make the matching exponential.
+    pub(crate) fn matches(&self, path: &[&str], partial: bool) -> bool {
+        let path: Vec<Vec<char>> = path.iter().map(|c| c.chars().collect()).collect();
+        let mut memo = Memo::new(self.0.len(), path.len());
+        self.matches_from(0, &path, 0, partial, &mut memo)
+    }
+
+    fn matches_from(
+        &self,
+        i: usize,
+        path: &[Vec<char>],
+        j: usize,
+        partial: bool,
+        memo: &mut Memo,
+    ) -> bool {
+        if let Some(m) = memo.get(i, j) {
+            return m;
+        }
+        let m = match self.0.get(i) {
+            None => j == path.len(),
+            Some(p) if p[..] == ['*', '*'] => {
+                self.matches_from(i + 1, path, j, partial, memo)
+                    || (j < path.len() && self.matches_from(i, path, j + 1, partial, memo))
+            }
+            Some(p) => match path.get(j) {
+                None => partial,
+                Some(c) => {
+                    component_matches(p, c) && self.matches_from(i + 1, path, j + 1, partial, memo)
+                }
+            },
+        };
+        memo.set(i, j, m);
+        m
+    }
+}
+
+/// Results of matching suffixes of a pattern of length `n` against
+/// suffixes of a sequence of length `m`, indexed by the start of both
+/// suffixes.
+struct Memo {
+    width: usize,
+    results: Vec<Option<bool>>,
+}
+
+impl Memo {
+    fn new(n: usize, m: usize) -> Self {
+        Memo {
+            width: m + 1,
+            results: vec![None; (n + 1) * (m + 1)],
+        }
+    }
+    fn get(&self, i: usize, j: usize) -> Option<bool> {
+        self.results[i * self.width + j]
+    }
+    fn set(&mut self, i: usize, j: usize, m: bool) {
+        self.results[i * self.width + j] = Some(m)
+    }
+}
+
+/// Whether the component `name` matches `pattern`.
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    fn rec(pattern: &[char], i: usize, name: &[char], j: usize, memo: &mut Memo) -> bool {
+        if let Some(m) = memo.get(i, j) {
+            return m;
+        }
+        let m = match (pattern.get(i), name.get(j)) {
+            (None, None) => true,
+            (Some('*'), _) => {
+                rec(pattern, i + 1, name, j, memo)
+                    || (j < name.len() && rec(pattern, i, name, j + 1, memo))
+            }
+            (Some('?'), Some(_)) => rec(pattern, i + 1, name, j + 1, memo),
+            (Some(a), Some(b)) if a == b => rec(pattern, i + 1, name, j + 1, memo),
+            _ => false,
+        };
+        memo.set(i, j, m);
+        m
+    }
+    rec(
+        pattern,
+        0,
+        name,
+        0,
+        &mut Memo::new(pattern.len(), name.len()),
+    )
+}
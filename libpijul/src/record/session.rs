@@ -0,0 +1,405 @@
+//! Saving a recording in progress, and resuming it in another
+//! process.
+//!
+//! Recording a large working copy one prefix at a time, or selecting
+//! hunks interactively, can take long enough for the process to be
+//! interrupted. [`Builder::save_session`] writes the prefixes
+//! recorded so far, the hunks of each [`Recorded`], the options of
+//! the builder and the state of the channel and of the tracked files
+//! to a session file, and the contents of the change, which can be
+//! much larger, to a separate spill file. [`Builder::resume_session`]
+//! reads them back into a new builder, on which more prefixes can be
+//! recorded before finishing the change. With
+//! [`Builder::checkpoint`] set, [`Builder::record`] saves a session
+//! after each child of its prefix, and skips the children recorded
+//! before the session was resumed, so that a single long recording
+//! survives a restart.
+//!
+//! Hunks refer to the vertices of the pristine by their internal
+//! identifiers, and to the files by their inodes, so a session can
+//! only be resumed if no change was applied to or unrecorded from the
+//! channel in between, and if no file was added, moved or removed.
+//! The redundant edges, file statistics and dirty cache entries found
+//! by the first process aren't saved, nor are the options that can't
+//! be serialized: the observer, the transform, the executor, the
+//! cancellation token and the diff algorithms other than
+//! [`crate::Algorithm`].
+use super::{Builder, InodeUpdate, ReadErrorPolicy, RecordError, RecordLimits, Recorded};
+use crate::alive::retrieve::RetrieveLimits;
+use crate::change::{Hunk, Local};
+use crate::changestore::ChangeStore;
+use crate::channel_settings::{BinaryPolicy, EolPolicy, TextPolicy};
+use crate::diff::{Algorithm, DiffAlgorithm};
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+const VERSION: u64 = 2;
+
+/// Name of the session file in a session directory.
+pub const SESSION_FILE: &str = "session";
+/// Name of the spill file of contents in a session directory.
+pub const CONTENTS_FILE: &str = "contents";
+
+#[derive(Debug, Error)]
+pub enum SessionError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+    #[error("Unsupported session version {0}")]
+    Version(u64),
+    #[error("The session was recorded on channel {expected}, not {got}")]
+    WrongChannel { expected: String, got: String },
+    #[error("Channel {0} has changed since the session was saved")]
+    ChannelChanged(String),
+    #[error("The tracked files have changed since the session was saved")]
+    TreeChanged,
+    #[error("The contents of the session are truncated")]
+    TruncatedContents,
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for SessionError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        SessionError::Txn(e.0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRecorded {
+    actions: Vec<Hunk<Option<ChangeId>, Local>>,
+    updatables: Vec<(usize, InodeUpdate)>,
+    chunks: Vec<Range<usize>>,
+    largest_file: u64,
+    has_binary_files: bool,
+    oldest_change: SystemTime,
+    copy_candidates: Vec<(usize, Hash, u64)>,
+}
+
+/// The options of a [`Builder`] that are saved with a session.
+#[derive(Serialize, Deserialize)]
+struct SavedOptions {
+    force_rediff: bool,
+    ignore_missing: bool,
+    io_concurrency: usize,
+    detect_copies: bool,
+    detect_renames: Option<f64>,
+    renames: Vec<crate::rename::Rename>,
+    diff_algorithm: Option<Algorithm>,
+    binary_policy: Option<BinaryPolicy>,
+    eol_policy: Option<EolPolicy>,
+    text_policy: Option<TextPolicy>,
+    word_diff: bool,
+    join_distance: Option<usize>,
+    stream_threshold: Option<u64>,
+    read_error_policy: ReadErrorPolicy,
+    limits: RecordLimits,
+    graph_limits: RetrieveLimits,
+    filter: Option<super::PathFilter>,
+    ignore_files: bool,
+    dirty_cache: bool,
+    cleanup_redundant: bool,
+    checkpoint: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    version: u64,
+    channel: String,
+    channel_id: [u8; 16],
+    state: Merkle,
+    tree: Hash,
+    options: SavedOptions,
+    prefixes: Vec<String>,
+    recorded: Vec<SavedRecorded>,
+    recorded_inodes: Vec<(Inode, Position<Option<ChangeId>>)>,
+    deleted_vertices: Vec<Position<ChangeId>>,
+    contents_len: u64,
+    data: Vec<u8>,
+}
+
+/// Write `bytes` to `path` atomically, by renaming a temporary file.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let tmp = path.with_extension("tmp");
+    let mut f = std::fs::File::create(&tmp)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// A hash of the tracked paths and their inodes, to tell whether the
+/// inodes of a session are still valid.
+fn tree_state<T: TreeTxnT>(txn: &T) -> Result<Hash, SessionError<T::TreeError>> {
+    let mut hasher = Hasher::default();
+    for x in crate::fs::iter_working_copy(txn, Inode::ROOT) {
+        let (inode, path) = x.map_err(SessionError::Txn)?;
+        hasher.update(&bincode::serialize(&inode)?);
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+    }
+    Ok(hasher.finish())
+}
+
+impl Builder {
+    /// The prefixes recorded by this builder, including the ones
+    /// recorded before the session was resumed.
+    pub fn recorded_prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    /// Save the recordings made so far on `channel` to directory
+    /// `dir`, along with `data`, which applications can use to save
+    /// the hunks selected so far in an interactive session, for
+    /// example. This should only be called between calls to
+    /// [`Builder::record`] that succeeded.
+    pub fn save_session<T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+        &self,
+        txn: &T,
+        channel: &T::Channel,
+        dir: &Path,
+        data: &[u8],
+    ) -> Result<(), SessionError<T::GraphError>> {
+        std::fs::create_dir_all(dir)?;
+        let contents = self.contents.lock();
+        let contents_len = contents.len() as u64;
+        write_atomic(&dir.join(CONTENTS_FILE), &contents)?;
+        std::mem::drop(contents);
+        let recorded = self
+            .rec
+            .iter()
+            .map(|rec| {
+                let rec = rec.lock();
+                SavedRecorded {
+                    actions: rec.actions.clone(),
+                    updatables: rec
+                        .updatables
+                        .iter()
+                        .map(|(&n, u)| (n, u.clone()))
+                        .collect(),
+                    chunks: rec.chunks.clone(),
+                    largest_file: rec.largest_file,
+                    has_binary_files: rec.has_binary_files,
+                    oldest_change: rec.oldest_change,
+                    copy_candidates: rec.copy_candidates.clone(),
+                }
+            })
+            .collect();
+        let session = Session {
+            version: VERSION,
+            channel: txn.name(channel).to_string(),
+            channel_id: txn.id(channel).0,
+            state: crate::pristine::current_state(txn, channel)?,
+            tree: tree_state(txn)?,
+            options: SavedOptions {
+                force_rediff: self.force_rediff,
+                ignore_missing: self.ignore_missing,
+                io_concurrency: self.io_concurrency,
+                detect_copies: self.detect_copies,
+                detect_renames: self.detect_renames,
+                renames: self.renames.clone(),
+                diff_algorithm: self.diff_algorithm,
+                binary_policy: self.binary_policy,
+                eol_policy: self.eol_policy,
+                text_policy: self.text_policy,
+                word_diff: self.word_diff,
+                join_distance: self.join_distance,
+                stream_threshold: self.stream_threshold,
+                read_error_policy: self.read_error_policy,
+                limits: self.limits,
+                graph_limits: self.graph_limits,
+                filter: self.filter.clone(),
+                ignore_files: self.ignore_files,
+                dirty_cache: self.dirty_cache,
+                cleanup_redundant: self.cleanup_redundant,
+                checkpoint: self.checkpoint.clone(),
+            },
+            prefixes: self.prefixes.clone(),
+            recorded,
+            recorded_inodes: self
+                .recorded_inodes
+                .lock()
+                .iter()
+                .map(|(&i, &p)| (i, p))
+                .collect(),
+            deleted_vertices: self.deleted_vertices.lock().iter().cloned().collect(),
+            contents_len,
+            data: data.to_vec(),
+        };
+        write_atomic(&dir.join(SESSION_FILE), &bincode::serialize(&session)?)?;
+        Ok(())
+    }
+
+    /// Resume the session saved in directory `dir` on `channel`,
+    /// returning a builder with the options it was saved with, and
+    /// the data saved with the session.
+    pub fn resume_session<T: ChannelTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+        txn: &T,
+        channel: &T::Channel,
+        dir: &Path,
+    ) -> Result<(Builder, Vec<u8>), SessionError<T::GraphError>> {
+        let session: Session = bincode::deserialize(&std::fs::read(dir.join(SESSION_FILE))?)?;
+        if session.version != VERSION {
+            return Err(SessionError::Version(session.version));
+        }
+        let name = txn.name(channel);
+        if session.channel_id != txn.id(channel).0 {
+            return Err(SessionError::WrongChannel {
+                expected: session.channel,
+                got: name.to_string(),
+            });
+        }
+        if crate::pristine::current_state(txn, channel)? != session.state {
+            return Err(SessionError::ChannelChanged(name.to_string()));
+        }
+        if tree_state(txn)? != session.tree {
+            return Err(SessionError::TreeChanged);
+        }
+        let mut contents = std::fs::read(dir.join(CONTENTS_FILE))?;
+        if (contents.len() as u64) < session.contents_len {
+            return Err(SessionError::TruncatedContents);
+        }
+        contents.truncate(session.contents_len as usize);
+
+        let options = session.options;
+        let mut builder = Builder {
+            force_rediff: options.force_rediff,
+            ignore_missing: options.ignore_missing,
+            io_concurrency: options.io_concurrency,
+            detect_copies: options.detect_copies,
+            detect_renames: options.detect_renames,
+            renames: options.renames,
+            diff_algorithm: options.diff_algorithm,
+            binary_policy: options.binary_policy,
+            eol_policy: options.eol_policy,
+            text_policy: options.text_policy,
+            word_diff: options.word_diff,
+            join_distance: options.join_distance,
+            stream_threshold: options.stream_threshold,
+            read_error_policy: options.read_error_policy,
+            limits: options.limits,
+            graph_limits: options.graph_limits,
+            filter: options.filter,
+            ignore_files: options.ignore_files,
+            dirty_cache: options.dirty_cache,
+            cleanup_redundant: options.cleanup_redundant,
+            checkpoint: options.checkpoint,
+            prefixes: session.prefixes,
+            ..Builder::new()
+        };
+        *builder.contents.lock() = contents;
+        builder
+            .recorded_inodes
+            .lock()
+            .extend(session.recorded_inodes);
+        builder
+            .deleted_vertices
+            .lock()
+            .extend(session.deleted_vertices);
+        for saved in session.recorded {
+            let rec = Recorded {
+                actions: saved.actions,
+                updatables: saved.updatables.into_iter().collect(),
+                chunks: saved.chunks,
+                largest_file: saved.largest_file,
+                has_binary_files: saved.has_binary_files,
+                oldest_change: saved.oldest_change,
+                copy_candidates: saved.copy_candidates,
+                ..builder.recorded_()
+            };
+            builder
+                .rec
+                .push(std::sync::Arc::new(parking_lot::Mutex::new(rec)));
+        }
+        Ok((builder, session.data))
+    }
+
+    /// Record each child of `prefix` that wasn't recorded yet, and
+    /// save a session to `dir` after each of them, see
+    /// [`Builder::checkpoint`]. The settings are loaded and the
+    /// renames detected by [`Builder::record`] for the whole prefix.
+    pub(super) fn record_checkpointed<
+        T,
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        C: ChangeStore + Clone + Send + 'static,
+    >(
+        &mut self,
+        txn: ArcTxn<T>,
+        diff_algorithm: Arc<dyn DiffAlgorithm>,
+        channel: ChannelRef<T>,
+        working_copy: &W,
+        changes: &C,
+        prefix: &str,
+        n_workers: usize,
+        dir: &Path,
+    ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
+    where
+        T: ChannelMutTxnT
+            + TxnT
+            + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>
+            + Send
+            + Sync
+            + 'static,
+        T::Channel: Send + Sync,
+        <W as WorkingCopy>::Error: 'static,
+    {
+        let children = {
+            let txn = txn.read();
+            match crate::fs::find_inode(&*txn, prefix) {
+                Ok(inode) if crate::fs::is_directory(&*txn, inode)? => {
+                    let mut children = Vec::new();
+                    for x in
+                        crate::fs::working_copy_children(&*txn, inode).map_err(RecordError::Txn)?
+                    {
+                        let (name, _) = x.map_err(RecordError::Txn)?;
+                        let mut path = prefix.to_string();
+                        crate::path::push(&mut path, name.as_str());
+                        children.push(path)
+                    }
+                    children
+                }
+                Ok(_) | Err(crate::fs::FsError::NotFound(_)) => Vec::new(),
+                Err(e) => return Err(RecordError::Fs(e)),
+            }
+        };
+        if children.is_empty() {
+            if !self.prefixes.iter().any(|p| p == prefix) {
+                self.record_prefix(
+                    txn.clone(),
+                    diff_algorithm,
+                    channel.clone(),
+                    working_copy,
+                    changes,
+                    prefix,
+                    n_workers,
+                )?;
+                self.save_session(&*txn.read(), &*channel.read(), dir, &[])
+                    .map_err(RecordError::Session)?;
+            }
+            return Ok(());
+        }
+        for child in children {
+            if self.prefixes.contains(&child) {
+                // Recorded before the session was resumed.
+                continue;
+            }
+            self.record_prefix(
+                txn.clone(),
+                diff_algorithm.clone(),
+                channel.clone(),
+                working_copy,
+                changes,
+                &child,
+                n_workers,
+            )?;
+            self.save_session(&*txn.read(), &*channel.read(), dir, &[])
+                .map_err(RecordError::Session)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Reordering the hunks of a recorded change, so that its text
+//! representation reads well in reviews.
+use super::{InodeUpdate, Recorded};
+use crate::change::{Atom, Hunk, Local};
+use crate::pristine::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// The order of the hunks of a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// The order in which the working copy was traversed.
+    Traversal,
+    /// Hunks grouped by path.
+    Path,
+    /// File moves first, then deletions, additions, name conflict
+    /// resolutions, edits and order conflict resolutions, each
+    /// grouped by path.
+    Kind,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Traversal
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown sort order: {0}")]
+pub struct UnknownSortOrder(String);
+
+impl std::str::FromStr for SortOrder {
+    type Err = UnknownSortOrder;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "traversal" => Ok(SortOrder::Traversal),
+            "path" => Ok(SortOrder::Path),
+            "kind" => Ok(SortOrder::Kind),
+            _ => Err(UnknownSortOrder(s.to_string())),
+        }
+    }
+}
+
+fn kind<H>(hunk: &Hunk<H, Local>) -> u8 {
+    match hunk {
+        Hunk::FileMove { .. } | Hunk::FileMeta { .. } => 0,
+        Hunk::FileDel { .. } => 1,
+        Hunk::FileUndel { .. } => 2,
+        Hunk::FileAdd { .. } | Hunk::FileCopy { .. } | Hunk::SubrepoPointer { .. } => 3,
+        Hunk::SolveNameConflict { .. } | Hunk::UnsolveNameConflict { .. } => 4,
+        Hunk::Edit { .. } | Hunk::Replacement { .. } | Hunk::BinaryEdit { .. } => 5,
+        Hunk::SolveOrderConflict { .. }
+        | Hunk::UnsolveOrderConflict { .. }
+        | Hunk::ResurrectZombies { .. } => 6,
+        Hunk::Custom { .. } => 7,
+    }
+}
+
+/// The vertices introduced by the hunks of a change, sorted by start.
+struct Vertices(Vec<(ChangePosition, ChangePosition, usize)>);
+
+impl Vertices {
+    /// The hunks introducing a vertex containing `pos`. Up contexts
+    /// point to the end of vertices, and down contexts to their start.
+    fn owners(&self, pos: ChangePosition, up: bool, f: &mut dyn FnMut(usize)) {
+        let mut i = match self.0.binary_search_by(|&(start, _, _)| start.cmp(&pos)) {
+            // Several empty vertices may start at `pos`.
+            Ok(mut i) => {
+                while i + 1 < self.0.len() && self.0[i + 1].0 == pos {
+                    i += 1
+                }
+                i + 1
+            }
+            Err(i) => i,
+        };
+        while i > 0 {
+            i -= 1;
+            let (start, end, hunk) = self.0[i];
+            if end < pos {
+                break;
+            }
+            let contains = if start == end {
+                pos == start
+            } else if up {
+                start < pos && pos <= end
+            } else {
+                start <= pos && pos < end
+            };
+            if contains {
+                f(hunk)
+            }
+        }
+    }
+}
+
+impl Recorded {
+    /// Reorder the hunks of this change according to `order`. Hunks
+    /// that refer to vertices introduced by other hunks of the same
+    /// change (such as the contents of a new file, which refer to its
+    /// inode) are always kept after these other hunks, so that the
+    /// change can still be applied.
+    pub fn sort_hunks(&mut self, order: SortOrder) {
+        if order == SortOrder::Traversal || self.actions.len() <= 1 {
+            return;
+        }
+        let n = self.actions.len();
+        let mut vertices = Vec::new();
+        for (i, hunk) in self.actions.iter().enumerate() {
+            for atom in hunk.iter() {
+                if let Atom::NewVertex(ref v) = atom {
+                    vertices.push((v.start, v.end, i))
+                }
+            }
+        }
+        vertices.sort_unstable();
+        let vertices = Vertices(vertices);
+
+        // Dependencies between hunks.
+        let mut successors = vec![Vec::new(); n];
+        let mut predecessors = vec![0usize; n];
+        for (j, hunk) in self.actions.iter().enumerate() {
+            let mut add = |i: usize| {
+                if i != j && !successors[i].contains(&j) {
+                    successors[i].push(j);
+                    predecessors[j] += 1
+                }
+            };
+            let mut local = |p: &Position<Option<ChangeId>>, up: bool| {
+                if p.change.is_none() {
+                    vertices.owners(p.pos, up, &mut add)
+                }
+            };
+            for atom in hunk.iter() {
+                match atom {
+                    Atom::NewVertex(v) => {
+                        for p in v.up_context.iter() {
+                            local(p, true)
+                        }
+                        for p in v.down_context.iter() {
+                            local(p, false)
+                        }
+                        local(&v.inode, true)
+                    }
+                    Atom::EdgeMap(e) => {
+                        for edge in e.edges.iter() {
+                            local(&edge.from, true);
+                            local(&edge.to.start_pos(), false)
+                        }
+                        local(&e.inode, true)
+                    }
+                }
+            }
+        }
+
+        let mut rank: Vec<usize> = (0..n).collect();
+        match order {
+            SortOrder::Path => {
+                rank.sort_by(|&a, &b| self.actions[a].path().cmp(self.actions[b].path()))
+            }
+            SortOrder::Kind => rank.sort_by(|&a, &b| {
+                let (a, b) = (&self.actions[a], &self.actions[b]);
+                (kind(a), a.path()).cmp(&(kind(b), b.path()))
+            }),
+            SortOrder::Traversal => {}
+        }
+        let mut position = vec![0; n];
+        for (r, &i) in rank.iter().enumerate() {
+            position[i] = r
+        }
+
+        // Topological sort, taking the smallest available hunk first.
+        let mut heap: BinaryHeap<_> = (0..n)
+            .filter(|&i| predecessors[i] == 0)
+            .map(|i| Reverse((position[i], i)))
+            .collect();
+        let mut sorted = Vec::with_capacity(n);
+        while let Some(Reverse((_, i))) = heap.pop() {
+            sorted.push(i);
+            for &j in successors[i].iter() {
+                predecessors[j] -= 1;
+                if predecessors[j] == 0 {
+                    heap.push(Reverse((position[j], j)))
+                }
+            }
+        }
+        if sorted.len() < n {
+            // The dependencies are cyclic, keep the original order.
+            return;
+        }
+
+        let mut new_index = vec![0; n];
+        for (k, &i) in sorted.iter().enumerate() {
+            new_index[i] = k
+        }
+        let mut actions: Vec<_> = self.actions.drain(..).map(Some).collect();
+        for &i in sorted.iter() {
+            self.actions.push(actions[i].take().unwrap())
+        }
+        // Additions are keyed by the index of their hunk plus one,
+        // deletions by the index of their hunk.
+        self.updatables = std::mem::take(&mut self.updatables)
+            .into_iter()
+            .map(|(k, u)| {
+                let k = match u {
+                    InodeUpdate::Add { .. } if k > 0 && k <= n => new_index[k - 1] + 1,
+                    InodeUpdate::Deleted { .. } if k < n => new_index[k],
+                    _ => k,
+                };
+                (k, u)
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,197 @@
+//! Hiding changes from some of the clients of a server.
+//!
+//! A server may host changes that not all its clients are allowed to
+//! read, such as fixes to security issues not disclosed yet. A
+//! [`Redactor`] decides what a requester may see of each change.
+//! Redacted changes are still listed in the log of their channels,
+//! and clients can still apply them, since they need their hashes and
+//! their vertices and edges to keep the same states as the server.
+//! Only their contents are withheld (see [`Visibility`]).
+//! [`RedactionPolicy`] is the redactor configured by repositories.
+//!
+//! The hash of a change covers its header and hunks, which are
+//! therefore kept by [`redact`], and checked by [`check_redacted`].
+//! Only the contents of a redacted change can't be checked against
+//! its contents hash. Since the marker of redacted changes is in
+//! their unhashed section, it is never trusted: redacted changes are
+//! only accepted by clients that opt in, are stored apart from the
+//! full changes, and servers refuse them (see [`is_redacted_file`]).
+use crate::change::{Atom, Change, ChangeError, Hunk};
+use crate::pristine::{Base32, Hash};
+use std::io::{Read, Seek, SeekFrom};
+
+/// The key of the unhashed section of redacted changes, whose value
+/// is the hash of the original change, in base32.
+pub const REDACTED_KEY: &str = "redacted";
+
+/// What a requester may see of a change, from the most to the least
+/// visible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    /// The whole change.
+    Full,
+    /// The header and hunks of the change, without its contents, as
+    /// in partial downloads. The message and description of the
+    /// change are visible.
+    NoContents,
+    /// The [redacted](redact) version of the change, whose contents
+    /// are masked.
+    Hidden,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Hidden
+    }
+}
+
+/// A hook deciding which changes are visible to whom.
+pub trait Redactor {
+    /// What `requester` (or an anonymous requester, if `None`) may see
+    /// of change `hash`.
+    fn visibility(&self, requester: Option<&str>, hash: &Hash) -> Visibility;
+}
+
+/// A rule of a [`RedactionPolicy`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionRule {
+    /// The changes covered by this rule, in base32.
+    pub changes: Vec<String>,
+    /// The requesters allowed to see these changes in full.
+    pub readers: Vec<String>,
+    /// What the other requesters see of these changes.
+    pub visibility: Visibility,
+}
+
+/// The redaction rules of a repository. The default policy shows all
+/// changes to everyone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionPolicy {
+    pub rules: Vec<RedactionRule>,
+}
+
+impl Redactor for RedactionPolicy {
+    /// The least visibility given by the rules covering `hash` and not
+    /// listing `requester` as a reader.
+    fn visibility(&self, requester: Option<&str>, hash: &Hash) -> Visibility {
+        if self.rules.is_empty() {
+            return Visibility::Full;
+        }
+        let hash = hash.to_base32();
+        self.rules
+            .iter()
+            .filter(|rule| rule.changes.iter().any(|c| *c == hash))
+            .filter(|rule| !requester.map_or(false, |r| rule.readers.iter().any(|x| x == r)))
+            .map(|rule| rule.visibility)
+            .max()
+            .unwrap_or(Visibility::Full)
+    }
+}
+
+/// The length of the prefix of the change file `r` (starting at the
+/// current position of `r`) that can be served with `visibility`, or
+/// `None` if the change must be [redacted](redact) instead.
+pub fn visible_len<R: Read + Seek>(
+    visibility: Visibility,
+    r: &mut R,
+) -> Result<Option<u64>, ChangeError> {
+    let pos = r.seek(SeekFrom::Current(0))?;
+    let end = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(pos))?;
+    match visibility {
+        Visibility::Full => Ok(Some(end - pos)),
+        Visibility::NoContents => Ok(Some(Change::size_no_contents(r)?)),
+        Visibility::Hidden => Ok(None),
+    }
+}
+
+/// The redacted version of `change`, whose hash is `hash`. Every
+/// byte of the contents of its files other than newlines is replaced
+/// by `?`. Its hashed section, including its header and the payloads
+/// of its custom hunks (see [`crate::hunk_kind`]), is kept, since
+/// clients couldn't check it otherwise.
+pub fn redact(change: &Change, hash: &Hash) -> Change {
+    let mut redacted = Change::new();
+    redacted.hashed = change.hashed.clone();
+    redacted.contents = change.contents.clone();
+    for hunk in change.changes.iter() {
+        match hunk {
+            Hunk::FileAdd { contents, .. }
+            | Hunk::FileCopy { contents, .. }
+            | Hunk::SubrepoPointer { contents, .. }
+            | Hunk::FileUndel { contents, .. }
+            | Hunk::FileDel { contents, .. } => mask(contents.as_ref(), &mut redacted.contents),
+            Hunk::Edit { change, .. } | Hunk::ResurrectZombies { change, .. } => {
+                mask(Some(change), &mut redacted.contents)
+            }
+            Hunk::Replacement { replacement, .. } => {
+                mask(Some(replacement), &mut redacted.contents)
+            }
+            Hunk::BinaryEdit { insert, .. } => mask(insert.as_ref(), &mut redacted.contents),
+            Hunk::FileMove { .. }
+            | Hunk::FileMeta { .. }
+            | Hunk::SolveNameConflict { .. }
+            | Hunk::UnsolveNameConflict { .. }
+            | Hunk::SolveOrderConflict { .. }
+            | Hunk::UnsolveOrderConflict { .. }
+            | Hunk::Custom { .. } => {}
+        }
+    }
+    let mut unhashed = serde_json::Map::new();
+    unhashed.insert(
+        REDACTED_KEY.to_string(),
+        serde_json::Value::String(hash.to_base32()),
+    );
+    redacted.unhashed = Some(serde_json::Value::Object(unhashed));
+    redacted
+}
+
+fn mask<H>(atom: Option<&Atom<H>>, contents: &mut [u8]) {
+    if let Some(Atom::NewVertex(n)) = atom {
+        if let Some(contents) = contents.get_mut(n.start.us()..n.end.us()) {
+            for b in contents.iter_mut().filter(|b| **b != b'\n') {
+                *b = b'?'
+            }
+        }
+    }
+}
+
+/// Read the change file at `path`, whose hash is `hash`, and
+/// serialize its redacted version.
+#[cfg(feature = "zstd")]
+pub fn redact_file(path: &str, hash: &Hash) -> Result<Vec<u8>, ChangeError> {
+    let change = Change::deserialize(path, Some(hash))?;
+    let mut file = Vec::new();
+    redact(&change, hash).serialize(&mut file)?;
+    Ok(file)
+}
+
+/// Whether the unhashed section `unhashed` marks a redacted version
+/// of change `hash`.
+fn is_redacted(unhashed: Option<&serde_json::Value>, hash: &Hash) -> bool {
+    unhashed
+        .and_then(|un| un.get(REDACTED_KEY))
+        .and_then(|h| h.as_str())
+        .map_or(false, |h| h == hash.to_base32())
+}
+
+/// Whether the change file `buf` is a redacted version of change
+/// `hash`.
+#[cfg(feature = "zstd")]
+pub fn is_redacted_file(buf: &[u8], hash: &Hash) -> Result<bool, ChangeError> {
+    let offsets: crate::change::Offsets = bincode::deserialize_from(buf)?;
+    let sections = crate::change::Sections::from_header(&offsets);
+    let unhashed = crate::change::read_unhashed(&mut std::io::Cursor::new(buf), &sections)?;
+    Ok(is_redacted(unhashed.as_ref(), hash))
+}
+
+/// Check the change file `buf` against `hash`, like
+/// [`Change::check_from_buffer`], except for the contents of
+/// redacted changes, which are masked.
+#[cfg(feature = "zstd")]
+pub fn check_redacted(buf: &[u8], hash: &Hash) -> Result<(), ChangeError> {
+    Change::check_buffer(buf, hash, !is_redacted_file(buf, hash)?)
+}
@@ -0,0 +1,239 @@
+//! Detection of the files renamed without telling Pijul.
+//!
+//! Moves are normally recorded because the tree tracks inodes: `pijul
+//! mv` moves the inode, and record finds it under its new name. A file
+//! deleted and added again under another name (for instance after
+//! `rm` and `cp`) has a new inode, and is recorded as a deletion and
+//! an addition, losing its history.
+//!
+//! [`detect_renames`] pairs the tracked files missing from the working
+//! copy with the added files whose contents are similar, and moves
+//! the inodes of the former to the names of the latter in the tree, so
+//! that the next record emits a `FileMove` hunk, followed by the
+//! edits between the two versions.
+use crate::changestore::ChangeStore;
+use crate::fs::FsError;
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::working_copy::WorkingCopy;
+use crate::HashMap;
+
+/// A file found renamed by [`detect_renames`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rename {
+    /// Path of the tracked file missing from the working copy.
+    pub from: String,
+    /// Path of the added file.
+    pub to: String,
+    /// Similarity of the two versions, see [`similarity`].
+    pub similarity: f64,
+}
+
+#[derive(Debug, Error)]
+pub enum RenameError<
+    C: std::error::Error + 'static,
+    T: std::error::Error + 'static,
+    W: std::error::Error + 'static,
+> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    File(#[from] FileError<C, T>),
+    #[error(transparent)]
+    Fs(#[from] FsError<T>),
+    #[error("Working copy error: {0}")]
+    WorkingCopy(W),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static, W: std::error::Error>
+    From<TxnErr<T>> for RenameError<C, T, W>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        RenameError::Txn(e.0)
+    }
+}
+
+/// The lines of a file, counted by contents.
+struct Lines<'a> {
+    counts: HashMap<&'a [u8], usize>,
+    total: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(contents: &'a [u8]) -> Self {
+        let mut counts = HashMap::default();
+        let mut total = 0;
+        for l in contents.split_inclusive(|&c| c == b'\n') {
+            *counts.entry(l).or_insert(0) += 1;
+            total += 1
+        }
+        Lines { counts, total }
+    }
+
+    /// Number of lines of `self` also in `other`, counting repeated
+    /// lines as many times as they appear in both.
+    fn common(&self, other: &Lines) -> usize {
+        let (small, large) = if self.counts.len() <= other.counts.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        small
+            .counts
+            .iter()
+            .map(|(l, n)| (*n).min(large.counts.get(l).cloned().unwrap_or(0)))
+            .sum()
+    }
+
+    fn similarity(&self, other: &Lines) -> f64 {
+        if self.total + other.total == 0 {
+            return 1.;
+        }
+        (2 * self.common(other)) as f64 / (self.total + other.total) as f64
+    }
+}
+
+/// Similarity of the contents `a` and `b`, between 0 and 1: the
+/// number of lines they have in common, counted in both files, over
+/// their total number of lines. Identical contents have similarity 1,
+/// contents without any common line have similarity 0.
+pub fn similarity(a: &[u8], b: &[u8]) -> f64 {
+    if a == b {
+        return 1.;
+    }
+    Lines::new(a).similarity(&Lines::new(b))
+}
+
+/// Whether `path` is `prefix` or under it.
+fn is_under(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || (path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/')))
+}
+
+/// Find the files under `prefix` renamed in `working_copy` without
+/// moving them in the tree, i.e. the pairs of a tracked file of
+/// `channel` missing from `working_copy` and an added file not yet
+/// recorded, whose contents have a [`similarity`] of at least
+/// `threshold`, and move the inodes of the missing files to the names
+/// of the added files.
+///
+/// The tracked files are compared as they are in the pristine, and
+/// the added files after `normalize`, which is given their paths,
+/// contents and encodings, and should transform them as record would, for
+/// instance to normalise their line endings.
+///
+/// Each file is paired at most once, the most similar pairs first.
+/// Empty files and directories are never paired. The returned renames
+/// are sorted by their new path.
+pub fn detect_renames<T, W, C, N>(
+    txn: &mut T,
+    channel: &T::Channel,
+    working_copy: &W,
+    changes: &C,
+    prefix: &str,
+    threshold: f64,
+    normalize: N,
+) -> Result<Vec<Rename>, RenameError<C::Error, T::GraphError, W::Error>>
+where
+    T: ChannelTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+    W: WorkingCopy,
+    C: ChangeStore,
+    N: Fn(&str, &mut Vec<u8>, Option<crate::Encoding>),
+{
+    let mut files = Vec::new();
+    for x in crate::fs::iter_working_copy(&*txn, Inode::ROOT) {
+        let (inode, path) = x.map_err(RenameError::Txn)?;
+        if is_under(prefix, &path) {
+            files.push((inode, path))
+        }
+    }
+    let mut missing = Vec::new();
+    let mut added = Vec::new();
+    for (inode, path) in files {
+        if crate::fs::is_directory(&*txn, inode)? {
+            continue;
+        }
+        let in_wc = working_copy.file_metadata(&path);
+        if let Some(pos) = crate::record::get_inodes(&*txn, channel, &inode)? {
+            if in_wc.is_ok() {
+                continue;
+            }
+            let pos = *pos;
+            let mut w = crate::vertex_buffer::Writer::new(Vec::new());
+            crate::output::output_file(
+                changes,
+                &*txn,
+                channel,
+                pos,
+                &mut w,
+                working_copy.graph_limits(),
+            )?;
+            missing.push((path, w.into_inner()))
+        } else if txn.get_inodes(&inode, None)?.is_none() {
+            match in_wc {
+                Ok(meta) if meta.is_file() => {}
+                _ => continue,
+            }
+            let mut contents = Vec::new();
+            let encoding = working_copy
+                .decode_file(&path, &mut contents)
+                .map_err(RenameError::WorkingCopy)?;
+            normalize(&path, &mut contents, encoding);
+            added.push((path, contents))
+        }
+    }
+    missing.retain(|(_, c)| !c.is_empty());
+    added.retain(|(_, c)| !c.is_empty());
+    if missing.is_empty() || added.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let missing_lines: Vec<_> = missing.iter().map(|(_, c)| Lines::new(c)).collect();
+    let added_lines: Vec<_> = added.iter().map(|(_, c)| Lines::new(c)).collect();
+    let mut pairs = Vec::new();
+    for (i, m) in missing_lines.iter().enumerate() {
+        for (j, a) in added_lines.iter().enumerate() {
+            // The similarity is at most that of the smaller file
+            // entirely included in the larger one.
+            let bound = (2 * m.total.min(a.total)) as f64 / (m.total + a.total) as f64;
+            if bound < threshold {
+                continue;
+            }
+            let s = m.similarity(a);
+            if s >= threshold {
+                pairs.push((s, i, j))
+            }
+        }
+    }
+    pairs.sort_by(|&(sa, ia, ja), &(sb, ib, jb)| {
+        let a = (&missing[ia].0, &added[ja].0);
+        let b = (&missing[ib].0, &added[jb].0);
+        sb.partial_cmp(&sa)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.cmp(&b))
+    });
+
+    let mut missing_done = vec![false; missing.len()];
+    let mut added_done = vec![false; added.len()];
+    let mut renames = Vec::new();
+    for (similarity, i, j) in pairs {
+        if missing_done[i] || added_done[j] {
+            continue;
+        }
+        missing_done[i] = true;
+        added_done[j] = true;
+        let (from, to) = (&missing[i].0, &added[j].0);
+        debug!("detected rename {:?} -> {:?} ({})", from, to, similarity);
+        crate::fs::remove_file(txn, to)?;
+        crate::fs::move_file(txn, from, to, 0)?;
+        renames.push(Rename {
+            from: from.clone(),
+            to: to.clone(),
+            similarity,
+        })
+    }
+    renames.sort_by(|a, b| a.to.cmp(&b.to));
+    Ok(renames)
+}
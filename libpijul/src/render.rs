@@ -0,0 +1,185 @@
+//! Display-ready rendering of hunk contents and conflicts.
+//!
+//! The contents of hunks are bytes, often but not always valid UTF-8,
+//! and slicing them to highlight the part of a line that changed can
+//! cut a character, or split a character from its combining accents.
+//! The functions of this module return [`Segment`]s, whose text is
+//! always valid UTF-8 with invalid bytes escaped as `\xNN`, and whose
+//! boundaries fall between extended grapheme clusters.
+use crate::diff::markers::{self, MarkerKind};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What a segment is part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Style {
+    /// Unchanged text, or text outside conflicts.
+    Context,
+    Deleted,
+    Added,
+    /// A conflict marker line.
+    Marker,
+    /// Side `n` of a conflict, starting at 0.
+    Side(usize),
+}
+
+/// A piece of text to display.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub style: Style,
+    /// Whether this is the part of a line that changed.
+    pub emphasis: bool,
+    /// Whether this segment is escaped invalid UTF-8.
+    pub escaped: bool,
+}
+
+/// A grapheme cluster, or an invalid byte.
+struct Unit<'a> {
+    bytes: &'a [u8],
+    escaped: bool,
+}
+
+fn units(mut bytes: &[u8]) -> Vec<Unit> {
+    let mut result = Vec::new();
+    while !bytes.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(s) => (s, 0),
+            Err(e) => (
+                // Unwrap ok: these bytes were just validated.
+                std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+                e.error_len().unwrap_or(bytes.len() - e.valid_up_to()),
+            ),
+        };
+        for g in valid.graphemes(true) {
+            result.push(Unit {
+                bytes: g.as_bytes(),
+                escaped: false,
+            })
+        }
+        let rest = &bytes[valid.len()..];
+        for i in 0..invalid {
+            result.push(Unit {
+                bytes: &rest[i..i + 1],
+                escaped: true,
+            })
+        }
+        bytes = &rest[invalid..];
+    }
+    result
+}
+
+/// Append `units` to `segments`, merging them with the last segment
+/// when they have the same attributes.
+fn push_units(segments: &mut Vec<Segment>, units: &[Unit], style: Style, emphasis: bool) {
+    for u in units {
+        let text = if u.escaped {
+            format!("\\x{:02x}", u.bytes[0])
+        } else {
+            // Unwrap ok: non-escaped units are valid UTF-8.
+            std::str::from_utf8(u.bytes).unwrap().to_string()
+        };
+        match segments.last_mut() {
+            Some(s) if s.style == style && s.emphasis == emphasis && s.escaped == u.escaped => {
+                s.text.push_str(&text)
+            }
+            _ => segments.push(Segment {
+                text,
+                style,
+                emphasis,
+                escaped: u.escaped,
+            }),
+        }
+    }
+}
+
+/// Escape the invalid UTF-8 in `bytes`.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len());
+    for s in segments(bytes, Style::Context) {
+        result.push_str(&s.text)
+    }
+    result
+}
+
+/// Render `bytes` in a single style.
+pub fn segments(bytes: &[u8], style: Style) -> Vec<Segment> {
+    let mut result = Vec::new();
+    push_units(&mut result, &units(bytes), style, false);
+    result
+}
+
+/// Render text `old` replaced by `new`, emphasizing the part between
+/// their longest common prefix and suffix. This is meant for
+/// replacements of a single line, or a few lines.
+pub fn replacement(old: &[u8], new: &[u8]) -> (Vec<Segment>, Vec<Segment>) {
+    let old = units(old);
+    let new = units(new);
+    let same = |a: &Unit, b: &Unit| a.bytes == b.bytes;
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    (
+        emphasize(&old, prefix, suffix, Style::Deleted),
+        emphasize(&new, prefix, suffix, Style::Added),
+    )
+}
+
+fn emphasize(units: &[Unit], prefix: usize, suffix: usize, style: Style) -> Vec<Segment> {
+    let mut result = Vec::new();
+    let end = units.len() - suffix;
+    push_units(&mut result, &units[..prefix], style, false);
+    push_units(&mut result, &units[prefix..end], style, true);
+    push_units(&mut result, &units[end..], style, false);
+    result
+}
+
+/// Render `contents`, an output with conflict markers, styling each
+/// line by the innermost conflict side it belongs to. If the markers
+/// are malformed, the whole contents are rendered as context.
+pub fn conflicts(contents: &[u8]) -> Vec<Segment> {
+    let markers = if let Ok(markers) = markers::parse(contents) {
+        markers
+    } else {
+        return segments(contents, Style::Context);
+    };
+    let mut result = Vec::new();
+    // Side of each conflict containing the current line.
+    let mut sides: Vec<usize> = Vec::new();
+    let mut pos = 0;
+    while pos < contents.len() {
+        let end = contents[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(contents.len());
+        let style = if let Some(marker) = markers.get(pos) {
+            match marker.kind {
+                MarkerKind::Begin => sides.push(0),
+                MarkerKind::Next => {
+                    if let Some(last) = sides.last_mut() {
+                        *last = marker.side
+                    }
+                }
+                MarkerKind::End => {
+                    sides.pop();
+                }
+            }
+            Style::Marker
+        } else if let Some(&side) = sides.last() {
+            Style::Side(side)
+        } else {
+            Style::Context
+        };
+        push_units(&mut result, &units(&contents[pos..end]), style, false);
+        pos = end
+    }
+    result
+}
@@ -0,0 +1,288 @@
+//! Creating repositories from templates.
+//!
+//! [`bootstrap`] initializes a repository (the `.pijul` directory,
+//! its pristine and its changes) and records an initial change adding
+//! the files of a [`Template`]. The header of that change, including
+//! its timestamp and authors, is taken from the template, and the
+//! change has no dependencies, so that the same template always
+//! yields the same hash. This is meant for project generators and for
+//! the tests of tools using this crate.
+use crate::apply::LocalApplyError;
+use crate::change::{Change, ChangeHeader};
+use crate::changestore::{self, ChangeStore};
+use crate::pristine::sanakirja::{Pristine, SanakirjaError};
+use crate::pristine::*;
+use crate::record::{Algorithm, Builder, RecordError};
+use crate::working_copy::{self, WorkingCopy};
+use crate::{FsError, MutTxnTExt};
+use std::io::Write;
+use std::path::Path;
+
+pub const PRISTINE_DIR: &str = "pristine";
+pub const CHANGES_DIR: &str = "changes";
+pub const CONFIG_FILE: &str = "config";
+
+type ChangestoreError = changestore::filesystem::Error;
+
+#[derive(Debug, Error)]
+pub enum BootstrapError {
+    #[error(transparent)]
+    Txn(#[from] SanakirjaError),
+    #[error(transparent)]
+    Changestore(ChangestoreError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Fs(#[from] FsError<SanakirjaError>),
+    #[error(transparent)]
+    Record(#[from] RecordError<ChangestoreError, std::io::Error, SanakirjaError>),
+    #[error(transparent)]
+    LocalApply(#[from] LocalApplyError<SanakirjaError>),
+    #[error("Already in a repository: {0:?}")]
+    AlreadyInRepository(std::path::PathBuf),
+    #[error("Invalid path in template: {0:?}")]
+    InvalidPath(String),
+    #[error("Refusing to overwrite {0:?}")]
+    WouldOverwrite(std::path::PathBuf),
+}
+
+impl std::convert::From<TxnErr<SanakirjaError>> for BootstrapError {
+    fn from(e: TxnErr<SanakirjaError>) -> Self {
+        BootstrapError::Txn(e.0)
+    }
+}
+
+/// A file of a [`Template`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateFile {
+    /// Path relative to the root of the repository, with `/` as the
+    /// separator. Parent directories are created and added as needed.
+    pub path: String,
+    pub contents: Vec<u8>,
+    /// Unix permissions of the file. Only the executable bit is
+    /// recorded.
+    pub permissions: u16,
+}
+
+/// The description of a new repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    /// The channel on which the initial change is recorded.
+    pub channel: String,
+    pub files: Vec<TemplateFile>,
+    /// Empty directories, the parents of files are added anyway.
+    pub dirs: Vec<String>,
+    /// Contents of the configuration file of the repository, if any.
+    pub config: Option<String>,
+    /// The header of the initial change. Its timestamp and authors
+    /// should be fixed for the hash to be reproducible.
+    pub header: ChangeHeader,
+    /// How durable the files written must be before the initial
+    /// change is committed, see [`crate::durability`].
+    #[serde(default)]
+    pub durability: crate::durability::Durability,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template {
+            channel: "main".to_string(),
+            files: Vec::new(),
+            dirs: Vec::new(),
+            config: None,
+            header: ChangeHeader::default(),
+            durability: crate::durability::Durability::None,
+        }
+    }
+}
+
+impl Template {
+    /// Add a regular file to this template.
+    pub fn file(mut self, path: &str, contents: &[u8]) -> Self {
+        self.files.push(TemplateFile {
+            path: path.to_string(),
+            contents: contents.to_vec(),
+            permissions: 0o644,
+        });
+        self
+    }
+
+    /// Add an executable file to this template.
+    pub fn executable(mut self, path: &str, contents: &[u8]) -> Self {
+        self.files.push(TemplateFile {
+            path: path.to_string(),
+            contents: contents.to_vec(),
+            permissions: 0o755,
+        });
+        self
+    }
+}
+
+/// A repository created by [`bootstrap`].
+pub struct Bootstrapped {
+    pub pristine: Pristine,
+    pub changes: changestore::filesystem::FileSystem,
+    pub working_copy: working_copy::filesystem::FileSystem,
+    /// The hash of the initial change, or `None` if the template has
+    /// no files.
+    pub hash: Option<Hash>,
+}
+
+/// Initialize a repository at `path` (which is created if needed)
+/// and record the files of `template` in an initial change.
+///
+/// This fails if `path` or one of its ancestors is already in a
+/// repository, or if one of the files of the template already
+/// exists. If anything fails after that, the `.pijul` directory and
+/// everything written by this function are removed.
+pub fn bootstrap<P: AsRef<Path>>(
+    path: P,
+    template: &Template,
+) -> Result<Bootstrapped, BootstrapError> {
+    let path = path.as_ref();
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+    for a in absolute.ancestors() {
+        let dot_dir = a.join(crate::DOT_DIR);
+        if std::fs::metadata(&dot_dir).is_ok() {
+            return Err(BootstrapError::AlreadyInRepository(dot_dir));
+        }
+    }
+    for p in template
+        .files
+        .iter()
+        .map(|f| &f.path)
+        .chain(template.dirs.iter())
+    {
+        if p.split('/')
+            .any(|c| c.is_empty() || c == "." || c == ".." || c == crate::DOT_DIR)
+        {
+            return Err(BootstrapError::InvalidPath(p.clone()));
+        }
+    }
+    // Everything this function creates, parents first, to be removed
+    // if it fails.
+    let mut created = vec![path.join(crate::DOT_DIR)];
+    for f in template.files.iter() {
+        let p = path.join(&f.path);
+        if std::fs::symlink_metadata(&p).is_ok() {
+            return Err(BootstrapError::WouldOverwrite(p));
+        }
+    }
+    for p in template
+        .files
+        .iter()
+        .map(|f| &f.path)
+        .chain(template.dirs.iter())
+    {
+        let mut q = path.to_path_buf();
+        for c in p.split('/') {
+            q.push(c);
+            match std::fs::symlink_metadata(&q) {
+                Ok(m) if m.is_dir() => {}
+                Ok(_) => return Err(BootstrapError::WouldOverwrite(q)),
+                Err(_) => {
+                    if !created.contains(&q) {
+                        created.push(q.clone())
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    if std::fs::metadata(path).is_err() {
+        created.insert(0, path.to_path_buf())
+    }
+    match bootstrap_(path, template) {
+        Ok(b) => Ok(b),
+        Err(e) => {
+            for p in created.iter() {
+                if let Ok(m) = std::fs::symlink_metadata(p) {
+                    if m.is_dir() {
+                        std::fs::remove_dir_all(p).unwrap_or(())
+                    } else {
+                        std::fs::remove_file(p).unwrap_or(())
+                    }
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+fn bootstrap_(path: &Path, template: &Template) -> Result<Bootstrapped, BootstrapError> {
+    let dot_dir = path.join(crate::DOT_DIR);
+    let pristine_dir = dot_dir.join(PRISTINE_DIR);
+    std::fs::create_dir_all(&pristine_dir)?;
+    if let Some(ref config) = template.config {
+        std::fs::write(dot_dir.join(CONFIG_FILE), config)?;
+    }
+    let pristine = Pristine::new(&pristine_dir.join("db"))?;
+    let changes = changestore::filesystem::FileSystem::from_changes(dot_dir.join(CHANGES_DIR), 1);
+    let repo =
+        working_copy::filesystem::FileSystem::from_root(path).with_durability(template.durability);
+
+    let txn = pristine.arc_txn_begin()?;
+    let channel = txn.write().open_or_create_channel(&template.channel)?;
+    for d in template.dirs.iter() {
+        repo.create_dir_all(d)?;
+        txn.write().add_dir(d, 0)?;
+    }
+    for f in template.files.iter() {
+        repo.write_file(&f.path)?.write_all(&f.contents)?;
+        repo.set_permissions(&f.path, f.permissions)?;
+        txn.write().add_file(&f.path, 0)?;
+    }
+    repo.sync()?;
+
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish().map_err(RecordError::from)?;
+    let hash = if rec.actions.is_empty() {
+        None
+    } else {
+        let actions = rec
+            .actions
+            .into_iter()
+            .map(|rec| rec.globalize(&*txn.read()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let change = Change::make_change(
+            &*txn.read(),
+            &channel,
+            actions,
+            std::mem::take(&mut *rec.contents.lock()),
+            template.header.clone(),
+            Vec::new(),
+        )?;
+        let hash = changes
+            .save_change(&change)
+            .map_err(BootstrapError::Changestore)?;
+        crate::apply::apply_local_change(
+            &mut *txn.write(),
+            &channel,
+            &change,
+            &hash,
+            &rec.updatables,
+        )?;
+        Some(hash)
+    };
+    txn.write().touch_channel(&mut *channel.write(), None);
+    txn.commit()?;
+    Ok(Bootstrapped {
+        pristine,
+        changes,
+        working_copy: repo,
+        hash,
+    })
+}
@@ -0,0 +1,277 @@
+//! Sessions of interactive conflict resolution, stored in the
+//! pristine.
+//!
+//! A session is started on a channel with the conflicts found when
+//! outputting it, and records what the user did about each of them.
+//! This allows a tool to be closed and reopened in the middle of a
+//! resolution, and to check that all conflicts were visited before
+//! recording. A session is only valid for the state of the channel
+//! it was started on: any change applied to or unrecorded from the
+//! channel makes it stale.
+use crate::output::Conflict;
+use crate::pristine::*;
+
+/// What the user did about a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictStatus {
+    /// Not looked at yet.
+    Pending,
+    /// Looked at, without choosing a side.
+    Visited,
+    /// Resolved, by choosing side `side` (counting from 0), or by
+    /// editing the conflict manually if `side` is `None`.
+    Resolved { side: Option<usize> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionEntry {
+    pub conflict: Conflict,
+    pub status: ConflictStatus,
+}
+
+/// A conflict resolution session on a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// State of the channel when the session was started.
+    pub state: Merkle,
+    pub conflicts: Vec<SessionEntry>,
+}
+
+#[derive(Debug, Error)]
+pub enum ResolutionError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Channel not found: {0}")]
+    ChannelNotFound(String),
+    #[error("Malformed resolution session for channel {0}")]
+    Malformed(String),
+    #[error("No resolution session on channel {0}")]
+    NoSession(String),
+    #[error("No conflict number {0} in this session")]
+    NoSuchConflict(usize),
+    #[error("The channel has changed since the resolution session started")]
+    Stale,
+    #[error("{remaining} conflicts have not been visited")]
+    Incomplete { remaining: usize },
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for ResolutionError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        ResolutionError::Txn(e.0)
+    }
+}
+
+impl Session {
+    pub fn new(state: Merkle, conflicts: Vec<Conflict>) -> Self {
+        Session {
+            state,
+            conflicts: conflicts
+                .into_iter()
+                .map(|conflict| SessionEntry {
+                    conflict,
+                    status: ConflictStatus::Pending,
+                })
+                .collect(),
+        }
+    }
+
+    /// Mark conflict `n` as visited, unless it is already resolved.
+    pub fn visit(&mut self, n: usize) -> bool {
+        if let Some(e) = self.conflicts.get_mut(n) {
+            if e.status == ConflictStatus::Pending {
+                e.status = ConflictStatus::Visited
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Mark conflict `n` as resolved.
+    pub fn resolve(&mut self, n: usize, side: Option<usize>) -> bool {
+        if let Some(e) = self.conflicts.get_mut(n) {
+            e.status = ConflictStatus::Resolved { side };
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The conflicts that haven't been visited yet, with their
+    /// numbers.
+    pub fn remaining(&self) -> impl Iterator<Item = (usize, &SessionEntry)> {
+        self.conflicts
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.status == ConflictStatus::Pending)
+    }
+
+    /// Have all conflicts been visited?
+    pub fn is_complete(&self) -> bool {
+        self.remaining().next().is_none()
+    }
+}
+
+/// The id of `channel`, under which its session is stored, so that
+/// sessions follow their channel when it is renamed.
+fn channel_id<T: TxnT>(
+    txn: &T,
+    channel: &str,
+) -> Result<Option<RemoteId>, ResolutionError<T::GraphError>> {
+    if let Some(c) = txn.load_channel(channel)? {
+        Ok(Some(*txn.id(&*c.read())))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Load the session of `channel`, if any. Stale sessions are
+/// returned too, their state can be compared to the state of the
+/// channel.
+pub fn load<T: TxnT>(
+    txn: &T,
+    channel: &str,
+) -> Result<Option<Session>, ResolutionError<T::GraphError>> {
+    let id = if let Some(id) = channel_id(txn, channel)? {
+        id
+    } else {
+        return Ok(None);
+    };
+    if let Some(bytes) = txn.get_resolution(&id)? {
+        Ok(Some(bincode::deserialize(bytes).map_err(|_| {
+            ResolutionError::Malformed(channel.to_string())
+        })?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Save `session` as the session of `channel`.
+pub fn save<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+    session: &Session,
+) -> Result<(), ResolutionError<T::GraphError>> {
+    let id = if let Some(id) = channel_id(&*txn, channel)? {
+        id
+    } else {
+        return Err(ResolutionError::ChannelNotFound(channel.to_string()));
+    };
+    let bytes = bincode::serialize(session).unwrap();
+    txn.put_resolution(&id, &bytes)?;
+    Ok(())
+}
+
+/// Start a session on `channel` with `conflicts`, replacing any
+/// previous session on that channel.
+pub fn start<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+    conflicts: Vec<Conflict>,
+) -> Result<Session, ResolutionError<T::GraphError>> {
+    let state = if let Some(c) = txn.load_channel(channel)? {
+        current_state(&*txn, &*c.read())?
+    } else {
+        return Err(ResolutionError::ChannelNotFound(channel.to_string()));
+    };
+    let session = Session::new(state, conflicts);
+    save(txn, channel, &session)?;
+    Ok(session)
+}
+
+fn update<T: MutTxnT, F: FnOnce(&mut Session) -> bool>(
+    txn: &mut T,
+    channel: &str,
+    n: usize,
+    f: F,
+) -> Result<Session, ResolutionError<T::GraphError>> {
+    let mut session = if let Some(s) = load(&*txn, channel)? {
+        s
+    } else {
+        return Err(ResolutionError::NoSession(channel.to_string()));
+    };
+    check_state(&*txn, channel, &session)?;
+    if !f(&mut session) {
+        return Err(ResolutionError::NoSuchConflict(n));
+    }
+    save(txn, channel, &session)?;
+    Ok(session)
+}
+
+/// Mark conflict `n` of the session of `channel` as visited, and
+/// save the session.
+pub fn visit<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+    n: usize,
+) -> Result<Session, ResolutionError<T::GraphError>> {
+    update(txn, channel, n, |s| s.visit(n))
+}
+
+/// Mark conflict `n` of the session of `channel` as resolved, and
+/// save the session.
+pub fn resolve<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+    n: usize,
+    side: Option<usize>,
+) -> Result<Session, ResolutionError<T::GraphError>> {
+    update(txn, channel, n, |s| s.resolve(n, side))
+}
+
+fn check_state<T: TxnT>(
+    txn: &T,
+    channel: &str,
+    session: &Session,
+) -> Result<(), ResolutionError<T::GraphError>> {
+    let c = if let Some(c) = txn.load_channel(channel)? {
+        c
+    } else {
+        return Err(ResolutionError::ChannelNotFound(channel.to_string()));
+    };
+    if current_state(txn, &*c.read())? != session.state {
+        return Err(ResolutionError::Stale);
+    }
+    Ok(())
+}
+
+/// Check that all the conflicts of the session of `channel` were
+/// visited, and delete the session. Stale sessions are deleted
+/// without any check, and channels without a session always pass.
+/// This is meant to be called before recording.
+pub fn finish<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+) -> Result<(), ResolutionError<T::GraphError>> {
+    let session = if let Some(s) = load(&*txn, channel)? {
+        s
+    } else {
+        return Ok(());
+    };
+    match check_state(&*txn, channel, &session) {
+        Ok(()) => {
+            let remaining = session.remaining().count();
+            if remaining > 0 {
+                return Err(ResolutionError::Incomplete { remaining });
+            }
+        }
+        Err(ResolutionError::Stale) => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(id) = channel_id(&*txn, channel)? {
+        txn.del_resolution(&id)?;
+    }
+    Ok(())
+}
+
+/// Abandon the session of `channel`, returning whether there was one.
+pub fn abandon<T: MutTxnT>(
+    txn: &mut T,
+    channel: &str,
+) -> Result<bool, ResolutionError<T::GraphError>> {
+    if let Some(id) = channel_id(&*txn, channel)? {
+        Ok(txn.del_resolution(&id)?)
+    } else {
+        Ok(false)
+    }
+}
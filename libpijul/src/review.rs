@@ -0,0 +1,361 @@
+//! Review annotations on the hunks of changes.
+//!
+//! Reviewers approve hunks, ask for more work on them, or comment on
+//! some of their lines. Annotations are addressed by [`HunkAddress`]:
+//! the hash of the change, the index of the hunk in the change, and
+//! optionally a range of lines in that hunk. Since changes are
+//! immutable, these addresses never need to be updated.
+//!
+//! Annotations are stored as [notes](crate::notes) on the changes
+//! they address, in namespace [`NAMESPACE`] ([`get`], [`set_verdict`],
+//! [`comment`]), and exchanged with other repositories along with the
+//! other notes. Reviews received from different reviewers, or from the same
+//! reviewer on different machines, are combined with
+//! [`Review::merge`] (or [`import`] in the pristine), which is
+//! commutative, associative and idempotent, so that the result
+//! doesn't depend on the order in which reviews are synchronised.
+use crate::change::{Change, Hunk, Local};
+use crate::notes::{ChangeNote, NotesError};
+use crate::pristine::*;
+use chrono::{DateTime, Utc};
+
+/// The namespace of the [notes](crate::notes) storing reviews.
+pub const NAMESPACE: &str = "review";
+
+/// The address of a hunk, or of some lines of a hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct HunkAddress {
+    #[serde(with = "crate::pristine::serde_base32")]
+    pub change: Hash,
+    /// Index of the hunk in the change.
+    pub hunk: usize,
+    /// First and last lines (inclusive, starting at 1) of the hunk
+    /// concerned, or `None` for the entire hunk.
+    pub lines: Option<(usize, usize)>,
+}
+
+impl HunkAddress {
+    /// The address of entire hunk `hunk` of `change`.
+    pub fn new(change: Hash, hunk: usize) -> Self {
+        HunkAddress {
+            change,
+            hunk,
+            lines: None,
+        }
+    }
+
+    /// The address of lines `first` to `last` (inclusive) of this
+    /// hunk.
+    pub fn lines(self, first: usize, last: usize) -> Self {
+        HunkAddress {
+            lines: Some((first.min(last), first.max(last))),
+            ..self
+        }
+    }
+
+    /// The hunk of `change` at this address, if `change` is the
+    /// change addressed and has that hunk.
+    pub fn resolve<'a>(
+        &self,
+        hash: &Hash,
+        change: &'a Change,
+    ) -> Option<&'a Hunk<Option<Hash>, Local>> {
+        if *hash != self.change {
+            return None;
+        }
+        change.changes.get(self.hunk)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Approved,
+    NeedsWork,
+}
+
+/// The verdict of a reviewer on a hunk.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct VerdictAnnotation {
+    pub address: HunkAddress,
+    pub reviewer: String,
+    pub timestamp: DateTime<Utc>,
+    pub verdict: Verdict,
+}
+
+/// A comment of a reviewer on a hunk.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Comment {
+    pub address: HunkAddress,
+    pub timestamp: DateTime<Utc>,
+    pub reviewer: String,
+    pub text: String,
+}
+
+/// The review annotations on a set of changes.
+///
+/// Each reviewer has at most one verdict per address: the latest
+/// one. Comments are never overwritten, and are kept sorted by
+/// address and date.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Review {
+    verdicts: Vec<VerdictAnnotation>,
+    comments: Vec<Comment>,
+}
+
+impl Review {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the verdicts, sorted by address and reviewer.
+    pub fn verdicts(&self) -> &[VerdictAnnotation] {
+        &self.verdicts
+    }
+
+    /// All the comments, sorted by address and date.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// Set the verdict of a reviewer on an address. If the reviewer
+    /// already had a verdict there, the latest one is kept. Between
+    /// two verdicts with the same date, [`Verdict::NeedsWork`] wins,
+    /// which keeps merges independent of their order. Returns `false`
+    /// if the current verdict was kept.
+    pub fn set_verdict(&mut self, annotation: VerdictAnnotation) -> bool {
+        match self.verdicts.binary_search_by(|v| {
+            (&v.address, &v.reviewer).cmp(&(&annotation.address, &annotation.reviewer))
+        }) {
+            Ok(i) => {
+                let current = &mut self.verdicts[i];
+                if (annotation.timestamp, annotation.verdict) > (current.timestamp, current.verdict)
+                {
+                    *current = annotation;
+                    true
+                } else {
+                    false
+                }
+            }
+            Err(i) => {
+                self.verdicts.insert(i, annotation);
+                true
+            }
+        }
+    }
+
+    /// Add a comment, unless the exact same comment is already there,
+    /// in which case `false` is returned.
+    pub fn comment(&mut self, comment: Comment) -> bool {
+        if let Err(i) = self.comments.binary_search(&comment) {
+            self.comments.insert(i, comment);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Merge the annotations of `other` into this review, returning
+    /// the number of annotations that changed.
+    pub fn merge(&mut self, other: &Review) -> usize {
+        let mut changed = 0;
+        for v in other.verdicts.iter() {
+            if self.set_verdict(v.clone()) {
+                changed += 1
+            }
+        }
+        for c in other.comments.iter() {
+            if self.comment(c.clone()) {
+                changed += 1
+            }
+        }
+        changed
+    }
+
+    /// The annotations of this review on `change` only.
+    pub fn of_change(&self, change: &Hash) -> Review {
+        Review {
+            verdicts: self
+                .verdicts
+                .iter()
+                .filter(|v| v.address.change == *change)
+                .cloned()
+                .collect(),
+            comments: self
+                .comments
+                .iter()
+                .filter(|c| c.address.change == *change)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// The changes annotated in this review.
+    pub fn changes(&self) -> std::collections::BTreeSet<Hash> {
+        self.verdicts
+            .iter()
+            .map(|v| v.address.change)
+            .chain(self.comments.iter().map(|c| c.address.change))
+            .collect()
+    }
+
+    /// The verdicts on hunk `hunk` of `change`, including the ones on
+    /// some of its lines only.
+    pub fn verdicts_on<'a>(
+        &'a self,
+        change: &'a Hash,
+        hunk: usize,
+    ) -> impl Iterator<Item = &'a VerdictAnnotation> + 'a {
+        self.verdicts
+            .iter()
+            .filter(move |v| v.address.change == *change && v.address.hunk == hunk)
+    }
+
+    /// The comments on hunk `hunk` of `change`, including the ones on
+    /// some of its lines only.
+    pub fn comments_on<'a>(
+        &'a self,
+        change: &'a Hash,
+        hunk: usize,
+    ) -> impl Iterator<Item = &'a Comment> + 'a {
+        self.comments
+            .iter()
+            .filter(move |c| c.address.change == *change && c.address.hunk == hunk)
+    }
+
+    /// The status of hunk `hunk` of `change`: [`Verdict::NeedsWork`]
+    /// if any reviewer asked for more work on any of its lines,
+    /// [`Verdict::Approved`] if it was approved and not rejected, and
+    /// `None` if no reviewer gave a verdict.
+    pub fn status(&self, change: &Hash, hunk: usize) -> Option<Verdict> {
+        self.verdicts_on(change, hunk).map(|v| v.verdict).max()
+    }
+
+    /// Write this review to `w`, as JSON.
+    pub fn to_json<W: std::io::Write>(&self, w: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(w, self)
+    }
+
+    /// Read a review written by [`Review::to_json`]. Reviews edited
+    /// by other tools are normalised, so that merging them behaves as
+    /// expected.
+    pub fn from_json<R: std::io::Read>(r: R) -> Result<Self, serde_json::Error> {
+        let r: Review = serde_json::from_reader(r)?;
+        let mut review = Review::new();
+        review.merge(&r);
+        Ok(review)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReviewError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed review of change {}", .0.to_base32())]
+    Malformed(Hash),
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for ReviewError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        ReviewError::Txn(e.0)
+    }
+}
+
+impl<T: std::error::Error + 'static> From<NotesError<T>> for ReviewError<T> {
+    fn from(e: NotesError<T>) -> Self {
+        match e {
+            NotesError::Txn(e) => ReviewError::Txn(e),
+            NotesError::Malformed(h) => ReviewError::Malformed(h),
+        }
+    }
+}
+
+impl Review {
+    /// The review stored in notes `notes` of namespace [`NAMESPACE`],
+    /// as received from another repository.
+    pub fn from_notes(notes: &[ChangeNote]) -> Result<Self, ReviewError<std::convert::Infallible>> {
+        let mut review = Review::new();
+        for n in notes {
+            let r: Review =
+                bincode::deserialize(&n.note).map_err(|_| ReviewError::Malformed(n.change))?;
+            review.merge(&r.of_change(&n.change));
+        }
+        Ok(review)
+    }
+}
+
+/// The annotations stored on the hunks of `change`.
+pub fn get<T: TxnT>(txn: &T, change: &Hash) -> Result<Review, ReviewError<T::GraphError>> {
+    if let Some(bytes) = crate::notes::get(txn, change, NAMESPACE)? {
+        Ok(bincode::deserialize(&bytes).map_err(|_| ReviewError::Malformed(*change))?)
+    } else {
+        Ok(Review::new())
+    }
+}
+
+fn put<T: MutTxnT>(
+    txn: &mut T,
+    change: &Hash,
+    review: &Review,
+) -> Result<(), ReviewError<T::GraphError>> {
+    crate::notes::put(txn, change, NAMESPACE, &bincode::serialize(review).unwrap())?;
+    Ok(())
+}
+
+/// All the annotations stored in the pristine, on all changes.
+pub fn list<T: TxnT>(txn: &T) -> Result<Review, ReviewError<T::GraphError>> {
+    let mut review = Review::new();
+    for n in crate::notes::list(txn, NAMESPACE)? {
+        let r: Review =
+            bincode::deserialize(&n.note).map_err(|_| ReviewError::Malformed(n.change))?;
+        review.merge(&r);
+    }
+    Ok(review)
+}
+
+/// Store a verdict, returning `false` if the verdict already stored
+/// for the same reviewer and address was kept instead.
+pub fn set_verdict<T: MutTxnT>(
+    txn: &mut T,
+    annotation: VerdictAnnotation,
+) -> Result<bool, ReviewError<T::GraphError>> {
+    let change = annotation.address.change;
+    let mut review = get(txn, &change)?;
+    if !review.set_verdict(annotation) {
+        return Ok(false);
+    }
+    put(txn, &change, &review)?;
+    Ok(true)
+}
+
+/// Store a comment, returning `false` if it was already stored.
+pub fn comment<T: MutTxnT>(
+    txn: &mut T,
+    comment: Comment,
+) -> Result<bool, ReviewError<T::GraphError>> {
+    let change = comment.address.change;
+    let mut review = get(txn, &change)?;
+    if !review.comment(comment) {
+        return Ok(false);
+    }
+    put(txn, &change, &review)?;
+    Ok(true)
+}
+
+/// Merge the annotations of another repository or reviewer into the
+/// pristine, returning the number of annotations that changed.
+pub fn import<T: MutTxnT>(
+    txn: &mut T,
+    review: &Review,
+) -> Result<usize, ReviewError<T::GraphError>> {
+    let mut changed = 0;
+    for change in review.changes() {
+        let mut current = get(txn, &change)?;
+        let n = current.merge(&review.of_change(&change));
+        if n > 0 {
+            put(txn, &change, &current)?;
+            changed += n
+        }
+    }
+    Ok(changed)
+}
@@ -0,0 +1,323 @@
+//! Named roots: several independent top-level trees in a channel.
+//!
+//! A root gives a name to a top-level directory of a channel, such as
+//! `code` for `code/` and `docs` for `docs-site/`. Roots never
+//! overlap, so their files have disjoint sets of inodes. A [`Root`]
+//! translates paths relative to it into paths of the channel, and
+//! records, outputs and lists the log of its own tree only, as if it
+//! were a repository of its own.
+//!
+//! The roots of a channel are stored in the pristine by channel id,
+//! like the [settings](crate::channel_settings) of the channel, and
+//! are kept when the channel is renamed.
+use crate::changestore::ChangeStore;
+use crate::output::{Conflict, FileError, OutputError};
+use crate::pristine::*;
+use crate::record::{Builder, RecordError};
+use crate::working_copy::WorkingCopy;
+use crate::HashMap;
+use std::collections::BTreeMap;
+
+/// The roots of a channel, from their name to their directory.
+pub type Roots = BTreeMap<String, String>;
+
+#[derive(Debug, Error)]
+pub enum RootError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error("Malformed roots for channel {0}")]
+    Malformed(String),
+    #[error("Root not found: {0}")]
+    NotFound(String),
+    #[error("Root already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Invalid directory for a root: {0:?}")]
+    InvalidDirectory(String),
+    #[error("Root {root} overlaps with root {other}")]
+    Overlap { root: String, other: String },
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for RootError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        RootError::Txn(e.0)
+    }
+}
+
+/// A root of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Root {
+    pub name: String,
+    /// The directory of this root, relative to the root of the
+    /// channel.
+    pub directory: String,
+}
+
+impl Root {
+    /// The path in the channel of `path`, relative to this root.
+    pub fn path(&self, path: &str) -> String {
+        let mut p = self.directory.clone();
+        for c in crate::path::components(path) {
+            crate::path::push(&mut p, c)
+        }
+        p
+    }
+
+    /// The path of `path`, a path of the channel, relative to this
+    /// root, or `None` if `path` isn't in this root.
+    pub fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.directory.as_str())?;
+        if rest.is_empty() {
+            Some(rest)
+        } else {
+            rest.strip_prefix('/')
+        }
+    }
+
+    /// Add file `path` of this root to the tree.
+    pub fn add_file<T: crate::MutTxnTExt>(
+        &self,
+        txn: &mut T,
+        path: &str,
+        salt: u64,
+    ) -> Result<(), crate::fs::FsError<T::GraphError>> {
+        txn.add_file(&self.path(path), salt)
+    }
+
+    /// Add directory `path` of this root to the tree.
+    pub fn add_dir<T: crate::MutTxnTExt>(
+        &self,
+        txn: &mut T,
+        path: &str,
+        salt: u64,
+    ) -> Result<(), crate::fs::FsError<T::GraphError>> {
+        txn.add_dir(&self.path(path), salt)
+    }
+
+    /// Record the changes under `prefix`, relative to this root, with
+    /// `builder`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record<
+        T,
+        W: WorkingCopy + Clone + Send + Sync + 'static,
+        C: ChangeStore + Clone + Send + 'static,
+    >(
+        &self,
+        builder: &mut Builder,
+        txn: ArcTxn<T>,
+        diff_algorithm: crate::diff::Algorithm,
+        channel: ChannelRef<T>,
+        working_copy: &W,
+        changes: &C,
+        prefix: &str,
+        n_workers: usize,
+    ) -> Result<(), RecordError<C::Error, W::Error, T::GraphError>>
+    where
+        T: ChannelMutTxnT
+            + TxnT
+            + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>
+            + Send
+            + Sync
+            + 'static,
+        T::Channel: Send + Sync,
+        <W as WorkingCopy>::Error: 'static,
+    {
+        builder.record(
+            txn,
+            diff_algorithm,
+            channel,
+            working_copy,
+            changes,
+            &self.path(prefix),
+            n_workers,
+        )
+    }
+
+    /// Output the files under `prefix`, relative to this root, to
+    /// `repo`. The paths of conflicts are paths of the channel.
+    pub fn output<
+        T: MutTxnT + Send + Sync + 'static,
+        R: WorkingCopy + Send + Clone + Sync + 'static,
+        P: ChangeStore + Send + Clone + 'static,
+    >(
+        &self,
+        repo: &R,
+        changes: &P,
+        txn: &ArcTxn<T>,
+        channel: &ChannelRef<T>,
+        prefix: &str,
+        output_name_conflicts: bool,
+        n_workers: usize,
+    ) -> Result<Vec<Conflict>, OutputError<P::Error, T::GraphError, R::Error>>
+    where
+        T::Channel: Send + Sync + 'static,
+    {
+        crate::output::output_repository_no_pending(
+            repo,
+            changes,
+            txn,
+            channel,
+            &self.path(prefix),
+            output_name_conflicts,
+            None,
+            n_workers,
+            0,
+        )
+    }
+
+    /// The changes of `channel` touching a file of this root, in the
+    /// order of the log.
+    pub fn log<T: TxnT, C: ChangeStore>(
+        &self,
+        txn: &T,
+        changes: &C,
+        channel: &T::Channel,
+    ) -> Result<Vec<Hash>, FileError<C::Error, T::GraphError>> {
+        let mut result = Vec::new();
+        let mut paths = HashMap::default();
+        for x in changeid_log(txn, channel, L64(0))? {
+            let (_, p) = x?;
+            for y in txn.iter_rev_touched(&p.a)? {
+                let (int, pos) = y?;
+                if *int < p.a {
+                    continue;
+                } else if *int > p.a {
+                    break;
+                }
+                let in_root = if let Some(&r) = paths.get(pos) {
+                    r
+                } else {
+                    let r = if let Some((path, _)) =
+                        crate::fs::find_path(changes, txn, channel, false, *pos)?
+                    {
+                        self.relative(&path).is_some()
+                    } else {
+                        false
+                    };
+                    paths.insert(*pos, r);
+                    r
+                };
+                if in_root {
+                    if let Some(h) = txn.get_external(&p.a)? {
+                        result.push(h.into())
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Load the roots of `channel`.
+pub fn load<T: TxnT>(txn: &T, channel: &T::Channel) -> Result<Roots, RootError<T::GraphError>> {
+    if let Some(bytes) = txn.get_roots(txn.id(channel))? {
+        bincode::deserialize(bytes).map_err(|_| RootError::Malformed(txn.name(channel).to_string()))
+    } else {
+        Ok(Roots::new())
+    }
+}
+
+/// Save `roots` as the roots of `channel`. Saving no roots deletes
+/// the entry of `channel`.
+pub fn save<T: MutTxnT>(
+    txn: &mut T,
+    channel: &T::Channel,
+    roots: &Roots,
+) -> Result<(), RootError<T::GraphError>> {
+    let id = *txn.id(channel);
+    if roots.is_empty() {
+        txn.del_roots(&id)?;
+    } else {
+        let bytes = bincode::serialize(roots).unwrap();
+        txn.put_roots(&id, &bytes)?;
+    }
+    Ok(())
+}
+
+/// The root of `channel` named `name`.
+pub fn get<T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    name: &str,
+) -> Result<Root, RootError<T::GraphError>> {
+    if let Some(directory) = load(txn, channel)?.remove(name) {
+        Ok(Root {
+            name: name.to_string(),
+            directory,
+        })
+    } else {
+        Err(RootError::NotFound(name.to_string()))
+    }
+}
+
+/// The root of `channel` containing `path`, if any, along with the
+/// path relative to that root.
+pub fn find<'a, T: TxnT>(
+    txn: &T,
+    channel: &T::Channel,
+    path: &'a str,
+) -> Result<Option<(Root, &'a str)>, RootError<T::GraphError>> {
+    for (name, directory) in load(txn, channel)? {
+        let root = Root { name, directory };
+        if let Some(rel) = root.relative(path) {
+            return Ok(Some((root, rel)));
+        }
+    }
+    Ok(None)
+}
+
+/// Add a root named `name` to `channel`, for directory `directory`,
+/// which must not overlap with the directories of the other roots.
+/// The directory doesn't need to exist yet.
+pub fn add<T: MutTxnT>(
+    txn: &mut T,
+    channel: &T::Channel,
+    name: &str,
+    directory: &str,
+) -> Result<Root, RootError<T::GraphError>> {
+    let directory = directory.trim_end_matches('/');
+    if directory.is_empty()
+        || directory.starts_with('/')
+        || crate::path::components(directory).any(|c| c == "." || c == "..")
+    {
+        return Err(RootError::InvalidDirectory(directory.to_string()));
+    }
+    let root = Root {
+        name: name.to_string(),
+        directory: directory.to_string(),
+    };
+    let mut roots = load(txn, channel)?;
+    if roots.contains_key(name) {
+        return Err(RootError::AlreadyExists(name.to_string()));
+    }
+    for (other, dir) in roots.iter() {
+        let other_root = Root {
+            name: other.clone(),
+            directory: dir.clone(),
+        };
+        if root.relative(dir).is_some() || other_root.relative(directory).is_some() {
+            return Err(RootError::Overlap {
+                root: name.to_string(),
+                other: other.clone(),
+            });
+        }
+    }
+    roots.insert(root.name.clone(), root.directory.clone());
+    save(txn, channel, &roots)?;
+    Ok(root)
+}
+
+/// Remove root `name` from `channel`, returning whether it existed.
+/// The files of the root are left untouched.
+pub fn remove<T: MutTxnT>(
+    txn: &mut T,
+    channel: &T::Channel,
+    name: &str,
+) -> Result<bool, RootError<T::GraphError>> {
+    let mut roots = load(txn, channel)?;
+    if roots.remove(name).is_none() {
+        return Ok(false);
+    }
+    save(txn, channel, &roots)?;
+    Ok(true)
+}
@@ -0,0 +1,367 @@
+//! Search of the files of a channel, without outputting them.
+//!
+//! [`grep`] outputs the files of a channel in memory, one at a time,
+//! and returns the lines matching a pattern, along with the changes
+//! that introduced them, as computed by [`credit`]. This doesn't need
+//! a working copy, and is meant for servers.
+use crate::changestore::ChangeStore;
+use crate::fs::{iter_graph_children, FsErrorC};
+use crate::output::FileError;
+use crate::pristine::*;
+use crate::vertex_buffer::VertexBuffer;
+use crate::{HashMap, HashSet};
+
+/// A pattern searched by [`grep`].
+pub trait Pattern {
+    /// Whether `line`, without its line ending, matches.
+    fn is_match(&self, line: &[u8]) -> bool;
+}
+
+/// Substrings.
+impl Pattern for str {
+    fn is_match(&self, line: &[u8]) -> bool {
+        memchr::memmem::find(line, self.as_bytes()).is_some()
+    }
+}
+
+#[cfg(feature = "text-changes")]
+impl Pattern for regex::bytes::Regex {
+    fn is_match(&self, line: &[u8]) -> bool {
+        regex::bytes::Regex::is_match(self, line)
+    }
+}
+
+/// A line matching the pattern passed to [`grep`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepMatch {
+    pub path: String,
+    /// Number of the line in the output of the file, starting at 1.
+    pub line: usize,
+    /// The line, without its line ending.
+    pub contents: Vec<u8>,
+    /// The changes that introduced the line, sorted. A line made of
+    /// several vertices, for instance after a word diff, is credited
+    /// to the changes of all of them. Conflict markers aren't
+    /// credited to any change.
+    pub changes: Vec<Hash>,
+}
+
+#[derive(Debug, Error)]
+pub enum SearchError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    File(#[from] FileError<C, T>),
+    #[error("Path not found: {0}")]
+    NotFound(String),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for SearchError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        SearchError::Txn(e.0)
+    }
+}
+
+/// Number of bytes at the beginning of files searched for a zero
+/// byte, to tell binary files apart.
+const BINARY_PREFIX: usize = 8000;
+
+/// Whether `contents` look binary, i.e. have a zero byte near their
+/// beginning.
+pub(crate) fn is_binary(contents: &[u8]) -> bool {
+    contents[..contents.len().min(BINARY_PREFIX)].contains(&0)
+}
+
+/// Search the files of `channel` under `paths` (or all the files if
+/// `paths` is empty) for lines matching `pattern`. The files are
+/// output as a checkout would, including conflict markers. Binary
+/// files, i.e. files with a zero byte near their beginning, are
+/// skipped. Files whose graphs exceed `limits` fail the search.
+///
+/// The matches are sorted by path and line number.
+pub fn grep<T, C, P>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    pattern: &P,
+    paths: &[&str],
+    limits: crate::alive::retrieve::RetrieveLimits,
+) -> Result<Vec<GrepMatch>, SearchError<C::Error, T::GraphError>>
+where
+    T: ChannelTxnT + TreeTxnT,
+    C: ChangeStore,
+    P: Pattern + ?Sized,
+{
+    let graph = txn.graph(channel);
+    let mut stack = Vec::new();
+    if paths.is_empty() {
+        stack.push((Position::ROOT, String::new(), true))
+    }
+    for path in paths {
+        let found = find(txn, changes, graph, path)?;
+        if found.is_empty() {
+            return Err(SearchError::NotFound(path.to_string()));
+        }
+        for (pos, is_dir) in found {
+            stack.push((pos, path.to_string(), is_dir))
+        }
+    }
+    let mut matches = Vec::new();
+    let mut out = Output::default();
+    while let Some((pos, path, is_dir)) = stack.pop() {
+        if is_dir {
+            for child in iter_graph_children(txn, changes, graph, pos).map_err(SearchError::Txn)? {
+                let (child, _, meta, basename) = child.map_err(SearchError::Txn)?;
+                let mut child_path = path.clone();
+                crate::path::push(&mut child_path, &basename);
+                stack.push((child, child_path, meta.is_dir()))
+            }
+            continue;
+        }
+        debug!("grep {:?}", path);
+        out.contents.clear();
+        out.vertices.clear();
+        crate::output::output_file(changes, txn, channel, pos, &mut out, limits)?;
+        if is_binary(&out.contents) {
+            debug!("binary file {:?}", path);
+            continue;
+        }
+        out.grep(txn, graph, &path, pattern, &mut matches)?;
+    }
+    matches.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    Ok(matches)
+}
+
+/// The changes that introduced vertex `v`, i.e. the changes of the
+/// edges to `v`, sorted. These are the changes shown by `pijul
+/// credit`.
+pub fn credit<T: GraphTxnT>(
+    txn: &T,
+    graph: &T::Graph,
+    v: Vertex<ChangeId>,
+) -> Result<Vec<Hash>, TxnErr<T::GraphError>> {
+    let mut changes = Vec::new();
+    for e in iter_adjacent(txn, graph, v, EdgeFlags::PARENT, EdgeFlags::all())? {
+        let e = e?;
+        if e.introduced_by().is_root() {
+            continue;
+        }
+        if let Some(intro) = txn.get_external(&e.introduced_by())? {
+            changes.push(intro.into())
+        }
+    }
+    changes.sort();
+    changes.dedup();
+    Ok(changes)
+}
+
+/// The credits of the lines of a copied file, see [`copy_credits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyCredits {
+    /// The change that copied the file.
+    pub change: Hash,
+    /// The changes that introduced the lines of the source, by
+    /// contents of the lines, without their line endings.
+    pub lines: HashMap<Vec<u8>, Vec<Hash>>,
+}
+
+impl CopyCredits {
+    /// The changes to credit for `line` (without its line ending) of
+    /// the copy, given the changes that introduced it in the copy: if
+    /// it was introduced by the copy, the changes that introduced it
+    /// in the source.
+    pub fn credit<'a>(&'a self, line: &[u8], changes: &'a [Hash]) -> &'a [Hash] {
+        if changes != [self.change] {
+            return changes;
+        }
+        self.lines.get(line).map(|c| &c[..]).unwrap_or(changes)
+    }
+}
+
+/// Matches every line.
+struct AnyLine;
+
+impl Pattern for AnyLine {
+    fn is_match(&self, _: &[u8]) -> bool {
+        true
+    }
+}
+
+/// If file `inode` was added by a
+/// [`FileCopy`](crate::change::Hunk::FileCopy) hunk linked to its
+/// source, the credits of the lines of the source, so that the lines
+/// of the copy can be credited to the changes that wrote them rather
+/// than to the copy, following chains of copies. Lines are matched by
+/// contents, in the current version of the source on `channel`.
+pub fn copy_credits<T, C>(
+    changes: &C,
+    txn: &T,
+    channel: &T::Channel,
+    inode: Position<ChangeId>,
+    limits: crate::alive::retrieve::RetrieveLimits,
+) -> Result<Option<CopyCredits>, SearchError<C::Error, T::GraphError>>
+where
+    T: ChannelTxnT + TreeTxnT,
+    C: ChangeStore,
+{
+    let graph = txn.graph(channel);
+    // The copies, from `inode` to the oldest source.
+    let mut chain = Vec::new();
+    let mut current = inode;
+    let mut visited = HashSet::default();
+    while visited.insert(current) {
+        let source = match crate::fs::copy_source(changes, txn, current) {
+            Ok(Some(source)) => source,
+            Ok(None) => break,
+            Err(FsErrorC::Txn(e)) => return Err(SearchError::Txn(e)),
+            Err(FsErrorC::Changestore(e)) => {
+                return Err(SearchError::File(FileError::Changestore(e)))
+            }
+            Err(FsErrorC::NotFound(e)) => return Err(SearchError::NotFound(e.to_string())),
+        };
+        if let Some(h) = txn.get_external(&current.change)? {
+            chain.push((h.into(), source))
+        } else {
+            break;
+        }
+        current = source
+    }
+    let mut result: Option<CopyCredits> = None;
+    let mut out = Output::default();
+    for (change, source) in chain.into_iter().rev() {
+        out.contents.clear();
+        out.vertices.clear();
+        crate::output::output_file(changes, txn, channel, source, &mut out, limits)?;
+        let mut matches = Vec::new();
+        out.grep(txn, graph, "", &AnyLine, &mut matches)?;
+        let mut lines = HashMap::default();
+        for m in matches {
+            let credit = if let Some(ref inner) = result {
+                inner.credit(&m.contents, &m.changes).to_vec()
+            } else {
+                m.changes
+            };
+            lines.entry(m.contents).or_insert(credit);
+        }
+        result = Some(CopyCredits { change, lines })
+    }
+    Ok(result)
+}
+
+/// The files or directories at `path`, and whether they are
+/// directories. A path may lead to several files if names are in
+/// conflict.
+fn find<T: GraphTxnT, C: ChangeStore>(
+    txn: &T,
+    changes: &C,
+    graph: &T::Graph,
+    path: &str,
+) -> Result<Vec<(Position<ChangeId>, bool)>, SearchError<C::Error, T::GraphError>> {
+    let mut current = vec![(Position::ROOT, true)];
+    for c in crate::path::components(path) {
+        let mut next = Vec::new();
+        for (pos, is_dir) in current {
+            if !is_dir {
+                continue;
+            }
+            for child in iter_graph_children(txn, changes, graph, pos).map_err(SearchError::Txn)? {
+                let (child, _, meta, basename) = child.map_err(SearchError::Txn)?;
+                if basename == c {
+                    next.push((child, meta.is_dir()))
+                }
+            }
+        }
+        current = next
+    }
+    Ok(current)
+}
+
+/// The output of a file, with the vertex each byte comes from.
+#[derive(Default)]
+struct Output {
+    contents: Vec<u8>,
+    /// The vertices of `contents`, by starting offset. Conflict
+    /// markers have no vertex.
+    vertices: Vec<(usize, Option<Vertex<ChangeId>>)>,
+    /// Change stores overwrite the buffer passed to them.
+    buf: Vec<u8>,
+}
+
+impl VertexBuffer for Output {
+    fn output_line<E, F>(&mut self, v: Vertex<ChangeId>, c: F) -> Result<(), E>
+    where
+        E: From<std::io::Error>,
+        F: FnOnce(&mut Vec<u8>) -> Result<(), E>,
+    {
+        self.buf.clear();
+        c(&mut self.buf)?;
+        if !self.buf.is_empty() {
+            self.vertices.push((self.contents.len(), Some(v)));
+            self.contents.extend_from_slice(&self.buf)
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker(&mut self, s: &str) -> Result<(), std::io::Error> {
+        let s = if self.contents.is_empty() || self.contents.ends_with(b"\n") {
+            &s[1..]
+        } else {
+            s
+        };
+        self.vertices.push((self.contents.len(), None));
+        self.contents.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Output {
+    fn grep<T: GraphTxnT, P: Pattern + ?Sized>(
+        &self,
+        txn: &T,
+        graph: &T::Graph,
+        path: &str,
+        pattern: &P,
+        matches: &mut Vec<GrepMatch>,
+    ) -> Result<(), TxnErr<T::GraphError>> {
+        let mut start = 0;
+        let mut line = 1;
+        while start < self.contents.len() {
+            let end = memchr::memchr(b'\n', &self.contents[start..])
+                .map(|i| start + i + 1)
+                .unwrap_or(self.contents.len());
+            let l = &self.contents[start..end];
+            let l = l.strip_suffix(b"\n").unwrap_or(l);
+            let l = l.strip_suffix(b"\r").unwrap_or(l);
+            if pattern.is_match(l) {
+                let mut changes = Vec::new();
+                // The first vertex overlapping the line starts at or
+                // before `start`.
+                let first = self
+                    .vertices
+                    .partition_point(|&(s, _)| s <= start)
+                    .saturating_sub(1);
+                for &(s, v) in &self.vertices[first..] {
+                    if s >= end {
+                        break;
+                    }
+                    if let Some(v) = v {
+                        changes.extend(credit(txn, graph, v)?)
+                    }
+                }
+                changes.sort();
+                changes.dedup();
+                matches.push(GrepMatch {
+                    path: path.to_string(),
+                    line,
+                    contents: l.to_vec(),
+                    changes,
+                })
+            }
+            start = end;
+            line += 1
+        }
+        Ok(())
+    }
+}
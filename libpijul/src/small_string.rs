@@ -2,13 +2,14 @@ pub const MAX_LENGTH: usize = 255;
 
 /// A string of length at most 255, with a more compact on-disk
 /// encoding.
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct SmallString {
     pub len: u8,
     pub str: [u8; MAX_LENGTH],
 }
 
 /// A borrowed version of `SmallStr`.
+#[repr(C)]
 pub struct SmallStr {
     len: u8,
     _str: [u8],
@@ -0,0 +1,195 @@
+//! Manifests pinning the exact state of a channel, in order to
+//! reproduce it elsewhere.
+use crate::apply::ApplyError;
+use crate::changestore::ChangeStore;
+use crate::key::{KeyError, SKey, Signature};
+use crate::pristine::*;
+use crate::HashMap;
+
+/// The ordered list of changes of a channel, along with the state
+/// they produce, optionally signed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub channel: String,
+    pub state: Merkle,
+    pub changes: Vec<Hash>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+#[derive(Debug, Error)]
+pub enum StateError<C: std::error::Error + 'static, T: std::error::Error + 'static> {
+    #[error("Channel {0} already exists")]
+    ChannelExists(String),
+    #[error("The changes of the manifest don't produce state {0:?}")]
+    InvalidManifest(Merkle),
+    #[error("Restored state {got:?} doesn't match the manifest ({expected:?})")]
+    StateMismatch { expected: Merkle, got: Merkle },
+    #[error(transparent)]
+    Apply(#[from] ApplyError<C, T>),
+    #[error(transparent)]
+    Key(#[from] KeyError),
+    #[error(transparent)]
+    Txn(T),
+    #[error("Change store error: {0}")]
+    Changestore(C),
+}
+
+impl<C: std::error::Error + 'static, T: std::error::Error + 'static> From<TxnErr<T>>
+    for StateError<C, T>
+{
+    fn from(e: TxnErr<T>) -> Self {
+        StateError::Txn(e.0)
+    }
+}
+
+impl Manifest {
+    /// The bytes covered by the signature: the length of the channel
+    /// name and the name, the state, and each change hash prefixed by
+    /// its algorithm, so that all hashes, including [`Hash::None`],
+    /// are covered.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(33 * (self.changes.len() + 1) + self.channel.len() + 8);
+        b.extend(&(self.channel.len() as u64).to_le_bytes());
+        b.extend(self.channel.as_bytes());
+        b.extend(&self.state.to_bytes());
+        for h in self.changes.iter() {
+            match h {
+                Hash::None => b.push(HashAlgorithm::None as u8),
+                Hash::Blake3(ref h) => {
+                    b.push(HashAlgorithm::Blake3 as u8);
+                    b.extend(h)
+                }
+            }
+        }
+        b
+    }
+
+    /// Sign this manifest with `key`, replacing any previous
+    /// signature.
+    pub fn sign(&mut self, key: &SKey) -> Result<(), KeyError> {
+        self.signature = Some(key.sign(&self.signed_bytes())?);
+        Ok(())
+    }
+
+    /// Check that the changes of this manifest produce its state,
+    /// and that the signature, if any, is valid.
+    pub fn verify(&self) -> Result<bool, KeyError> {
+        let mut m = Merkle::zero();
+        for h in self.changes.iter() {
+            if let Hash::None = h {
+                // Not the hash of a change.
+                return Ok(false);
+            }
+            m = m.next(h)
+        }
+        if m != self.state {
+            return Ok(false);
+        }
+        if let Some(ref sig) = self.signature {
+            sig.verify(&self.signed_bytes())?
+        }
+        Ok(true)
+    }
+}
+
+/// Export the list of changes and the state of `channel`.
+pub fn manifest<T: ChannelTxnT>(
+    txn: &T,
+    channel: &T::Channel,
+) -> Result<Manifest, TxnErr<T::GraphError>> {
+    let mut changes = Vec::new();
+    let mut state = Merkle::zero();
+    for x in changeid_log(txn, channel, L64(0))? {
+        let (_, p) = x?;
+        let h = txn.get_external(&p.a)?.unwrap();
+        changes.push(h.into());
+        state = (&p.b).into();
+    }
+    Ok(Manifest {
+        channel: txn.name(channel).to_string(),
+        state,
+        changes,
+        signature: None,
+    })
+}
+
+/// The changes of `channel` as of `date`, i.e. the changes whose
+/// validated timestamp is at most `date`, in the order of the
+/// channel. The validated timestamp of a change is the latest of its
+/// own timestamp and the validated timestamps of its dependencies, so
+/// that a change with a timestamp older than one of its dependencies
+/// comes with that dependency.
+///
+/// The returned manifest can be materialized into a scratch channel
+/// with [`restore`].
+pub fn state_at<T: ChannelTxnT, C: ChangeStore>(
+    txn: &T,
+    changes: &C,
+    channel: &T::Channel,
+    date: chrono::DateTime<chrono::Utc>,
+) -> Result<Manifest, StateError<C::Error, T::GraphError>> {
+    let mut validated = HashMap::default();
+    let mut result = Vec::new();
+    let mut state = Merkle::zero();
+    for x in changeid_log(txn, channel, L64(0))? {
+        let (_, p) = x?;
+        let h: Hash = txn.get_external(&p.a)?.unwrap().into();
+        let mut t = changes
+            .get_header(&h)
+            .map_err(StateError::Changestore)?
+            .timestamp
+            .with_timezone(&chrono::Utc);
+        for d in changes
+            .get_dependencies(&h)
+            .map_err(StateError::Changestore)?
+        {
+            if let Some(&td) = validated.get(&d) {
+                t = std::cmp::max(t, td)
+            }
+        }
+        validated.insert(h, t);
+        if t <= date {
+            result.push(h);
+            state = state.next(&h);
+        }
+    }
+    Ok(Manifest {
+        channel: txn.name(channel).to_string(),
+        state,
+        changes: result,
+        signature: None,
+    })
+}
+
+/// Apply the changes of `manifest`, in order, to a new channel
+/// called `name`, and check that the resulting state is the one of
+/// the manifest. All changes must be in `changes`.
+pub fn restore<T: MutTxnT, C: ChangeStore>(
+    txn: &mut T,
+    changes: &C,
+    manifest: &Manifest,
+    name: &str,
+) -> Result<ChannelRef<T>, StateError<C::Error, T::GraphError>> {
+    if !manifest.verify()? {
+        return Err(StateError::InvalidManifest(manifest.state));
+    }
+    if txn.load_channel(name)?.is_some() {
+        return Err(StateError::ChannelExists(name.to_string()));
+    }
+    let channel = txn.open_or_create_channel(name).map_err(StateError::Txn)?;
+    let mut state = Merkle::zero();
+    {
+        let mut ch = channel.write();
+        for h in manifest.changes.iter() {
+            state = crate::apply::apply_change(changes, txn, &mut ch, h)?.1;
+        }
+    }
+    if state != manifest.state {
+        return Err(StateError::StateMismatch {
+            expected: manifest.state,
+            got: state,
+        });
+    }
+    Ok(channel)
+}
@@ -0,0 +1,149 @@
+//! Nested repositories.
+//!
+//! A directory of the working copy containing a `.pijul` directory,
+//! or a [`POINTER_FILE`], is a nested repository. Recording doesn't
+//! descend into it: it is recorded as a single file, the pointer,
+//! whose contents are the state of the nested repository (its
+//! current channel and the last change applied to it), in a
+//! [`SubrepoPointer`](crate::change::Hunk::SubrepoPointer) hunk.
+//! Later states are recorded as edits of the pointer.
+//!
+//! Outputting a pointer creates the directory of the nested
+//! repository if needed and writes the pointer to the
+//! [`POINTER_FILE`] in that directory, from which the nested
+//! repository can be cloned. The files of the nested repository are
+//! left to it.
+use crate::pristine::{Base32, Hash};
+use crate::working_copy::WorkingCopy;
+use std::io::Write;
+
+/// Name of the file storing the pointer in the directory of a nested
+/// repository.
+pub const POINTER_FILE: &str = ".pijulsubrepo";
+
+#[derive(Debug, Error)]
+pub enum SubrepoError<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    WorkingCopy(E),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// The state of a nested repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubrepoState {
+    /// The current channel of the nested repository.
+    pub channel: String,
+    /// The last change applied to that channel, if any.
+    pub change: Option<Hash>,
+}
+
+impl SubrepoState {
+    /// The contents of a pointer to this state.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if let Some(ref change) = self.change {
+            format!("{} {}\n", self.channel, change.to_base32()).into_bytes()
+        } else {
+            format!("{}\n", self.channel).into_bytes()
+        }
+    }
+
+    /// Parse the contents of a pointer.
+    pub fn parse(pointer: &[u8]) -> Option<Self> {
+        let pointer = std::str::from_utf8(pointer).ok()?;
+        let mut words = pointer.lines().next()?.split(' ');
+        let channel = words.next().filter(|c| !c.is_empty())?.to_string();
+        let change = if let Some(h) = words.next() {
+            Some(Hash::from_base32(h.as_bytes())?)
+        } else {
+            None
+        };
+        Some(SubrepoState { channel, change })
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+/// Tell whether directory `path` of `working_copy` is a nested
+/// repository.
+pub fn is_subrepo<W: WorkingCopy>(working_copy: &W, path: &str) -> bool {
+    working_copy
+        .file_metadata(&join(path, crate::DOT_DIR))
+        .map_or(false, |m| m.is_dir())
+        || working_copy
+            .file_metadata(&join(path, POINTER_FILE))
+            .map_or(false, |m| m.is_file())
+}
+
+/// The contents of the pointer to nested repository `path`: its
+/// state if `working_copy` can read it (see
+/// [`WorkingCopy::subrepo_state`]), or else the contents of its
+/// [`POINTER_FILE`], if any.
+pub fn read_pointer<W: WorkingCopy>(
+    working_copy: &W,
+    path: &str,
+) -> Result<Option<Vec<u8>>, W::Error> {
+    if let Some(state) = working_copy.subrepo_state(path)? {
+        return Ok(Some(state.to_bytes()));
+    }
+    let pointer_file = join(path, POINTER_FILE);
+    match working_copy.file_metadata(&pointer_file) {
+        Ok(meta) if meta.is_file() => {
+            let mut pointer = Vec::new();
+            working_copy.read_file(&pointer_file, &mut pointer)?;
+            Ok(Some(pointer))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Write `pointer` to the [`POINTER_FILE`] of nested repository
+/// `path`, creating its directory if needed.
+pub fn materialize<W: WorkingCopy>(
+    working_copy: &W,
+    path: &str,
+    pointer: &[u8],
+) -> Result<(), SubrepoError<W::Error>> {
+    if let Ok(meta) = working_copy.file_metadata(path) {
+        if !meta.is_dir() {
+            working_copy
+                .remove_path(path, false)
+                .map_err(SubrepoError::WorkingCopy)?
+        }
+    }
+    working_copy
+        .create_dir_all(path)
+        .map_err(SubrepoError::WorkingCopy)?;
+    working_copy
+        .write_file(&join(path, POINTER_FILE))
+        .map_err(SubrepoError::WorkingCopy)?
+        .write_all(pointer)?;
+    Ok(())
+}
+
+/// Read the state of the nested repository whose pristine is `db`.
+#[cfg(feature = "ondisk-repos")]
+pub fn read_state(
+    db: &std::path::Path,
+) -> Result<SubrepoState, crate::pristine::sanakirja::SanakirjaError> {
+    use crate::pristine::*;
+    use crate::TxnTExt;
+    let pristine = crate::pristine::sanakirja::Pristine::new(db)?;
+    let txn = pristine.txn_begin()?;
+    let channel = txn.current_channel()?.to_string();
+    let mut change = None;
+    if let Some(c) = txn.load_channel(&channel).map_err(|e| e.0)? {
+        let c = c.read();
+        if let Some(x) = txn.reverse_log(&*c, None)?.next() {
+            let (_, (h, _)) = x?;
+            change = Some(h.into())
+        }
+    }
+    Ok(SubrepoState { channel, change })
+}
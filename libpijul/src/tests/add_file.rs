@@ -1,5 +1,19 @@
 use super::*;
+use crate::change::{Atom, Hunk, MoveDetection};
+use crate::file_id::*;
+use crate::opaque::{Opaque, ATTRIBUTES_FILE};
+use crate::record::{
+    CancellationToken, PathFilter, ReadErrorPolicy, RecordError, RecordEvent, RecordLimits,
+    RecordObserver, SkippedFile,
+};
+use crate::rename::Rename;
+use crate::subrepo::{SubrepoState, POINTER_FILE};
+use crate::working_copy::{memory, WorkingCopy};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Add a simple file and clone.
 #[test]
@@ -83,7 +97,7 @@ fn add_dir_test() -> Result<(), anyhow::Error> {
     assert_eq!(name.as_str(), "dir");
     assert!(txn.is_directory(inode).unwrap());
     debug!("name = {:?}", inode);
-    debug_tree(&txn, "debug_tree")?;
+    debug_tree(&txn, debug_path("debug_tree"))?;
     let mut it = crate::fs::working_copy_children(&txn, inode).unwrap();
     let (name, _) = it.next().unwrap().unwrap();
     assert_eq!(name.as_str(), "file");
@@ -296,6 +310,197 @@ fn permissions_test() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Permission changes are recorded as their own hunks, which can be
+/// applied without the edits made before them.
+#[test]
+fn permissions_hunk() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\nc\n".to_vec());
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    txn_alice.write().add_file("file", 0)?;
+    let channel = txn_alice.write().open_or_create_channel("main")?;
+    let alice0 = record_all(&repo_alice, &changes, &txn_alice, &channel, "")?;
+
+    repo_alice.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    record_all(&repo_alice, &changes, &txn_alice, &channel, "")?;
+
+    repo_alice.set_permissions("file", 0o755)?;
+    let (alice2, change) = record_all_change(&repo_alice, &changes, &txn_alice, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    assert!(matches!(change.changes[0], Hunk::FileMeta { .. }));
+    assert_eq!(change.dependencies, vec![alice0]);
+
+    let mut text = Vec::new();
+    change
+        .write(
+            &changes,
+            Some(alice2),
+            |l, _p| format!("{}:{}", l.path, l.line),
+            true,
+            &mut text,
+        )
+        .unwrap();
+    let parsed = Change::read(&text[..], &mut HashMap::default()).unwrap();
+    assert!(matches!(parsed.changes[0], Hunk::FileMeta { .. }));
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel = txn_bob.write().open_or_create_channel("main")?;
+    for h in [alice0, alice2].iter() {
+        apply::apply_change(&changes, &mut *txn_bob.write(), &mut *channel.write(), h)?;
+    }
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_bob, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(repo_bob.file_metadata("file")?.0, 0o100);
+    let mut file = Vec::new();
+    repo_bob.read_file("file", &mut file)?;
+    assert_eq!(file, b"a\nb\nc\n");
+    Ok(())
+}
+
+/// Working copies keeping full permissions record the difference
+/// between 0o600 and 0o644, and restore it on output.
+#[test]
+fn full_permissions() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    repo_alice.set_full_permissions(true);
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("config", b"a\n".to_vec());
+    repo_alice.set_permissions("config", 0o644)?;
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    txn_alice.write().add_file("config", 0)?;
+    let channel = txn_alice.write().open_or_create_channel("main")?;
+    record_all(&repo_alice, &changes, &txn_alice, &channel, "")?;
+
+    repo_alice.set_permissions("config", 0o600)?;
+    let (hash, change) = record_all_change(&repo_alice, &changes, &txn_alice, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    assert!(matches!(change.changes[0], Hunk::FileMeta { .. }));
+
+    let mut text = Vec::new();
+    change
+        .write(
+            &changes,
+            Some(hash),
+            |l, _p| format!("{}:{}", l.path, l.line),
+            true,
+            &mut text,
+        )
+        .unwrap();
+    let parsed = Change::read(&text[..], &mut HashMap::default()).unwrap();
+    match parsed.changes[0] {
+        Hunk::FileMeta {
+            add: Atom::NewVertex(ref add),
+            ..
+        } => {
+            let meta = changestore::FileMetadata::read(
+                &parsed.contents[add.start.0.as_usize()..add.end.0.as_usize()],
+            );
+            assert_eq!(meta.metadata.permissions(), 0o600);
+        }
+        ref h => panic!("unexpected hunk {:?}", h),
+    }
+
+    let repo_bob = working_copy::memory::Memory::new();
+    repo_bob.set_full_permissions(true);
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn_alice, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(repo_bob.file_metadata("config")?.permissions(), 0o600);
+
+    // Other working copies only keep the executable bit.
+    let repo_charlie = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(
+        &repo_charlie,
+        &changes,
+        &txn_alice,
+        &channel,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    assert_eq!(repo_charlie.file_metadata("config")?.permissions(), 0);
+    Ok(())
+}
+
+/// Record only compares the permission bits tracked by both the
+/// working copy and the recorded file, so that collaborators with
+/// and without full permissions don't undo each other's bits.
+#[test]
+fn full_permissions_mixed() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    repo_alice.set_full_permissions(true);
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("config", b"a\n".to_vec());
+    repo_alice.set_permissions("config", 0o644)?;
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("config", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo_alice, &changes, &txn, &channel, "")?;
+
+    // Without full permissions, nothing changed.
+    let repo_bob = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(
+        &repo_bob, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    let (_, change) = record_all_change(&repo_bob, &changes, &txn, &channel, "")?;
+    assert!(change.changes.is_empty());
+
+    // Setting the executable bit keeps the other bits.
+    repo_bob.set_permissions("config", 0o100)?;
+    let (_, change) = record_all_change(&repo_bob, &changes, &txn, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    repo_alice.set_permissions("config", 0)?;
+    output::output_repository_no_pending(
+        &repo_alice,
+        &changes,
+        &txn,
+        &channel,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    assert_eq!(repo_alice.file_metadata("config")?.permissions(), 0o744);
+
+    // Files recorded without full permissions aren't recorded again
+    // by working copies with full permissions.
+    repo_bob.add_file("script", b"a\n".to_vec());
+    repo_bob.set_permissions("script", 0o100)?;
+    txn.write().add_file("script", 0)?;
+    record_all(&repo_bob, &changes, &txn, &channel, "")?;
+    output::output_repository_no_pending(
+        &repo_alice,
+        &changes,
+        &txn,
+        &channel,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    repo_alice.set_permissions("script", 0o755)?;
+    let (_, change) = record_all_change(&repo_alice, &changes, &txn, &channel, "")?;
+    assert!(change.changes.is_empty());
+    Ok(())
+}
+
 /// Move a file to a directory, then delete the file and clone the whole thing.
 #[test]
 fn move_file_test() -> Result<(), anyhow::Error> {
@@ -318,10 +523,10 @@ fn move_file_test() -> Result<(), anyhow::Error> {
 
     repo_alice.add_dir("dir");
     repo_alice.rename("file", "dir/file2")?;
-    debug_tree(&*txn_alice.read(), "debug_tree")?;
+    debug_tree(&*txn_alice.read(), debug_path("debug_tree"))?;
     let alice1 = record_all(&repo_alice, &changes, &txn_alice, &channel, "")?;
     debug!("alice1 = {:?}", alice1);
-    debug_tree(&*txn_alice.read(), "debug_tree")?;
+    debug_tree(&*txn_alice.read(), debug_path("debug_tree"))?;
     debug_inodes(&*txn_alice.read());
     debug!("{:?}", repo_alice);
 
@@ -678,7 +883,7 @@ fn move_delete_test() -> Result<(), anyhow::Error> {
         1,
     )?;
 
-    let rec = state.finish();
+    let rec = state.finish()?;
     let changes_ = rec
         .actions
         .into_iter()
@@ -820,3 +1025,1506 @@ fn record_not_modified() -> Result<(), anyhow::Error> {
     record_all_output(&repo, changes, &txn, &channel, "")?;
     Ok(())
 }
+
+/// Read new files on several threads, and check that the hunks are
+/// in the same order as when reading them sequentially.
+#[test]
+fn add_files_io_concurrency() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut files = Vec::new();
+    for i in 0..200 {
+        let path = format!("dir{}/file{}", i % 7, i);
+        repo.add_file(&path, format!("file {}\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+        files.push(path)
+    }
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let record = |io_concurrency| -> Result<_, anyhow::Error> {
+        let mut state = Builder::new();
+        state.io_concurrency = io_concurrency;
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            1,
+        )?;
+        Ok(state.finish()?)
+    };
+    let paths = |rec: &crate::record::Recorded| -> Vec<String> {
+        rec.actions.iter().map(|h| h.path().to_string()).collect()
+    };
+    let sequential = record(1)?;
+    let rec = record(4)?;
+    assert_eq!(paths(&sequential), paths(&rec));
+    assert_eq!(rec.updatables.len(), sequential.updatables.len());
+
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = crate::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let hash = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &hash, &rec.updatables)?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    for path in files.iter() {
+        let mut a = Vec::new();
+        repo.read_file(path, &mut a)?;
+        let mut b = Vec::new();
+        repo2.read_file(path, &mut b)?;
+        assert_eq!(a, b)
+    }
+    Ok(())
+}
+
+/// Record a copy of a tracked file as a `FileCopy` hunk.
+#[test]
+fn add_file_copy() -> Result<(), anyhow::Error> {
+    use crate::change::Local;
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let contents = b"a\nb\nc\n".to_vec();
+    repo.add_file("a", contents.clone());
+    txn.write().add_file("a", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.add_file("dir/b", contents.clone());
+    repo.add_file("c", b"c\n".to_vec());
+    txn.write().add_file("dir/b", 0)?;
+    txn.write().add_file("c", 0)?;
+    let mut state = Builder::new();
+    state.detect_copies = true;
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+    let actions: Vec<_> = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let copies = |actions: &[Hunk<Option<Hash>, Local>]| -> Vec<(String, String)> {
+        actions
+            .iter()
+            .filter_map(|h| match h {
+                Hunk::FileCopy { path, source, .. } => Some((path.clone(), source.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+    let expected = vec![("dir/b".to_string(), "a".to_string())];
+    assert_eq!(copies(&actions), expected);
+
+    let change = crate::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let hash = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &hash, &rec.updatables)?;
+
+    // The text format keeps the source.
+    let mut text = Vec::new();
+    change
+        .write(
+            &changes,
+            Some(hash),
+            |l, _p| format!("{}:{}", l.path, l.line),
+            true,
+            &mut text,
+        )
+        .unwrap();
+    // New directories are referenced by the positions of their inodes,
+    // which the parser learns from the updatables.
+    let parsed = Change::read(&text[..], &mut rec.updatables.clone()).unwrap();
+    assert_eq!(copies(&parsed.changes), expected);
+    let source_inodes = |actions: &[Hunk<Option<Hash>, Local>]| -> Vec<_> {
+        actions
+            .iter()
+            .filter_map(|h| match h {
+                Hunk::FileCopy { source_inode, .. } => Some(*source_inode),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(
+        source_inodes(&parsed.changes),
+        source_inodes(&change.changes)
+    );
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut b = Vec::new();
+    repo2.read_file("dir/b", &mut b)?;
+    assert_eq!(b, contents);
+
+    // Edit the source after the copy.
+    repo.write_file("a")?.write_all(b"a\nb\nc\nd\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "a")?;
+
+    // The copy is linked to the inode of its source.
+    let txn = txn.read();
+    let (a, _) = txn.follow_oldest_path(&changes, &channel, "a")?;
+    let (b, _) = txn.follow_oldest_path(&changes, &channel, "dir/b")?;
+    assert_eq!(crate::fs::copy_source(&changes, &*txn, b)?, Some(a));
+    assert_eq!(crate::fs::copy_source(&changes, &*txn, a)?, None);
+
+    // The log of the copy includes the history of the source up to
+    // the copy.
+    let channel = channel.read();
+    assert_eq!(
+        crate::fs::file_log(&changes, &*txn, &*channel, b)?,
+        vec![h0, hash]
+    );
+    assert_eq!(
+        crate::fs::file_log(&changes, &*txn, &*channel, a)?,
+        vec![h0, h2]
+    );
+
+    // Lines of the copy are credited to the change that wrote them in
+    // the source.
+    let limits = Default::default();
+    let credits = crate::search::copy_credits(&changes, &*txn, &*channel, b, limits)?.unwrap();
+    assert_eq!(credits.change, hash);
+    assert_eq!(credits.credit(b"b", &[hash]), &[h0]);
+    assert_eq!(credits.credit(b"b", &[h2]), &[h2]);
+    assert!(crate::search::copy_credits(&changes, &*txn, &*channel, a, limits)?.is_none());
+    Ok(())
+}
+
+/// Modified files are rewritten by the transform before being
+/// diffed, and the working copy is left as it was.
+#[test]
+fn record_transform() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("file", b"a\nb\n".to_vec());
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file")?.write_all(b"a  \nb\nc   \n")?;
+    let mut state = Builder::new();
+    // Strip trailing spaces.
+    state.transform = Some(Arc::new(|_: &str, contents: &[u8]| {
+        let mut result = Vec::new();
+        for line in contents.split_inclusive(|&c| c == b'\n') {
+            let end = line.iter().rposition(|&c| c != b' ' && c != b'\n');
+            result.extend(&line[..end.map(|e| e + 1).unwrap_or(0)]);
+            if line.ends_with(b"\n") {
+                result.push(b'\n')
+            }
+        }
+        Some(result)
+    }));
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+    assert_eq!(rec.actions.len(), 1);
+    assert!(matches!(rec.actions[0], crate::change::Hunk::Edit { .. }));
+    assert!(rec.contents.lock().starts_with(b"c\n"));
+
+    let mut contents = Vec::new();
+    repo.read_file("file", &mut contents)?;
+    assert_eq!(contents, b"a  \nb\nc   \n");
+    Ok(())
+}
+
+/// Recording with a filter only records the edits and deletions of
+/// the selected paths.
+#[test]
+fn record_with_filter() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let files = [
+        "src/a.rs",
+        "src/b.txt",
+        "src/generated/g.rs",
+        "doc/d",
+        "top",
+    ];
+    for f in files.iter() {
+        repo.add_file(f, b"a\n".to_vec());
+        txn.write().add_file(f, 0)?;
+    }
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    for f in files.iter() {
+        repo.write_file(f)?.write_all(b"a\nb\n")?;
+    }
+    repo.remove_path("top", false)?;
+
+    let mut state = Builder::new();
+    state.record_with_filter(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        &["src/**/*.rs", "!src/generated/**"],
+        1,
+    )?;
+    let rec = state.finish()?;
+    let paths: Vec<_> = rec.actions.iter().map(|h| h.path().to_string()).collect();
+    assert_eq!(paths, vec!["src/a.rs"]);
+
+    let filter = PathFilter::prefixes(&["doc", "src/generated"]);
+    assert!(filter.matches("doc/d"));
+    assert!(filter.matches("src/generated/g.rs"));
+    assert!(!filter.matches("src/a.rs"));
+    assert!(filter.descends("src"));
+    assert!(!filter.descends("top"));
+
+    // Matching is memoized, these would take ages otherwise.
+    let deep: Vec<_> = std::iter::repeat("d").take(40).collect();
+    let filter = PathFilter::new(&["**/**/**/**/**/**/**/**/**/**/x"]);
+    assert!(!filter.matches(&deep.join("/")));
+    let filter = PathFilter::new(&["*a*a*a*a*a*a*a*a*a*a*b"]);
+    assert!(!filter.matches(&"a".repeat(60)));
+    assert!(filter.matches(&format!("{}b", "a".repeat(60))));
+    Ok(())
+}
+
+struct CancelOnDiff(CancellationToken);
+
+impl RecordObserver for CancelOnDiff {
+    fn bytes_diffed(&self, _path: &str, _bytes: u64) {
+        self.0.cancel()
+    }
+}
+
+/// A cancelled recording stops with `RecordError::Cancelled`, and
+/// doesn't prevent recording again with a new token.
+#[test]
+fn record_cancel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\nb\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\nx\n")?;
+
+    for n_workers in [1, 4].iter() {
+        let mut state = Builder::new();
+        state.cancellation.cancel();
+        match state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            *n_workers,
+        ) {
+            Err(RecordError::Cancelled) => {}
+            r => panic!("{:?}", r.err()),
+        }
+
+        // Cancelled while diffing.
+        let mut state = Builder::new();
+        state.observer = Some(std::sync::Arc::new(CancelOnDiff(
+            state.cancellation.clone(),
+        )));
+        match state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            *n_workers,
+        ) {
+            Err(RecordError::Cancelled) => {}
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    Ok(())
+}
+
+/// Large files, binary files and files with many hunks are reported
+/// to the observer.
+#[test]
+fn record_events() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("text", b"a\nb\nc\nd\ne\nf\n".to_vec());
+    txn.write().add_file("text", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let bin: Vec<u8> = (0..30_000).map(|_| rng.gen()).collect();
+    repo.add_file("bin", bin);
+    txn.write().add_file("bin", 0)?;
+    repo.write_file("text")?.write_all(b"a\nx\nc\ny\ne\nz\n")?;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_ = events.clone();
+    let mut state = Builder::new();
+    state.observer = Some(Arc::new(move |e: RecordEvent| {
+        events_.lock().unwrap().push(e)
+    }));
+    state.limits = RecordLimits {
+        large_file: 1000,
+        many_hunks: 2,
+    };
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    state.finish()?;
+
+    let mut events = events.lock().unwrap().clone();
+    events.sort_by_key(|e| format!("{:?}", e));
+    assert_eq!(
+        events,
+        vec![
+            RecordEvent::BinaryFile {
+                path: "bin".to_string()
+            },
+            RecordEvent::LargeFile {
+                path: "bin".to_string(),
+                size: 30_000
+            },
+            RecordEvent::ManyHunks {
+                path: "text".to_string(),
+                hunks: 3
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[derive(Default)]
+struct Progress {
+    files: Mutex<usize>,
+    scanned: Mutex<Vec<String>>,
+    bytes: Mutex<u64>,
+    hunks: Mutex<Vec<(String, usize)>>,
+}
+
+impl RecordObserver for Progress {
+    fn started(&self, files: usize) {
+        *self.files.lock().unwrap() += files
+    }
+    fn file_scanned(&self, path: &str) {
+        self.scanned.lock().unwrap().push(path.to_string())
+    }
+    fn bytes_diffed(&self, _path: &str, bytes: u64) {
+        *self.bytes.lock().unwrap() += bytes
+    }
+    fn hunks_produced(&self, path: &str, hunks: usize) {
+        self.hunks.lock().unwrap().push((path.to_string(), hunks))
+    }
+}
+
+/// The observer sees the number of files to scan, the files scanned,
+/// the bytes diffed and the hunks produced.
+#[test]
+fn record_progress() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("dir/a", b"a\nb\nc\nd\n".to_vec());
+    txn.write().add_file("dir/a", 0)?;
+    repo.add_file("b", b"b\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("dir/a")?.write_all(b"x\nb\nc\ny\n")?;
+    repo.add_file("c", b"c\n".to_vec());
+    txn.write().add_file("c", 0)?;
+
+    let progress = std::sync::Arc::new(Progress::default());
+    let mut state = Builder::new();
+    state.observer = Some(progress.clone());
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+
+    assert_eq!(*progress.files.lock().unwrap(), 4);
+    let mut scanned = progress.scanned.lock().unwrap().clone();
+    scanned.sort();
+    assert_eq!(scanned, vec!["b", "c", "dir", "dir/a"]);
+    // "b" may be diffed too, since it was recorded less than a second ago.
+    assert!(*progress.bytes.lock().unwrap() >= 8);
+    let mut hunks: Vec<_> = progress
+        .hunks
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, n)| *n > 0)
+        .cloned()
+        .collect();
+    hunks.sort();
+    assert_eq!(hunks, vec![("c".to_string(), 1), ("dir/a".to_string(), 2)]);
+    assert_eq!(rec.actions.len(), 3);
+    Ok(())
+}
+
+/// Diffing files on several threads yields the same change as
+/// diffing them on a single thread.
+#[test]
+fn record_workers() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let mut files = Vec::new();
+    for i in 0..60 {
+        let path = format!("dir{}/file{}", i % 5, i);
+        let contents: String = (0..20).map(|j| format!("line {} {}\n", i, j)).collect();
+        repo.add_file(&path, contents.into_bytes());
+        txn.write().add_file(&path, 0)?;
+        files.push(path)
+    }
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    for (i, path) in files.iter().enumerate() {
+        if i % 3 == 0 {
+            let contents: String = (0..20)
+                .filter(|j| j % 4 != 1)
+                .map(|j| format!("line {} {}{}\n", i, j, if j % 5 == 0 { "!" } else { "" }))
+                .collect();
+            repo.write_file(path)?.write_all(contents.as_bytes())?;
+        }
+    }
+    repo.add_file("new/a", b"a\n".to_vec());
+    txn.write().add_file("new/a", 0)?;
+    repo.rename("dir1/file1", "new/file1")?;
+    txn.write().move_file("dir1/file1", "new/file1", 0)?;
+
+    let record = |n_workers, io_concurrency| -> Result<_, anyhow::Error> {
+        let mut state = Builder::new();
+        state.io_concurrency = io_concurrency;
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            n_workers,
+        )?;
+        Ok(state.finish()?)
+    };
+    let sequential = record(1, 1)?;
+    let rec = record(4, 4)?;
+    assert_eq!(sequential.actions, rec.actions);
+    assert_eq!(sequential.updatables, rec.updatables);
+    assert_eq!(&*sequential.contents.lock(), &*rec.contents.lock());
+
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = crate::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let hash = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &hash, &rec.updatables)?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut paths = repo.list_files();
+    paths.sort();
+    let mut paths2 = repo2.list_files();
+    paths2.sort();
+    assert_eq!(paths, paths2);
+    for path in paths.iter() {
+        if repo.file_metadata(path)?.is_dir() {
+            continue;
+        }
+        let mut a = Vec::new();
+        repo.read_file(path, &mut a)?;
+        let mut b = Vec::new();
+        repo2.read_file(path, &mut b)?;
+        assert_eq!(a, b)
+    }
+    Ok(())
+}
+
+/// Diffing files on an injected executor, or on the current thread
+/// only, yields the same change as the default threads.
+#[test]
+fn record_executor() -> Result<(), anyhow::Error> {
+    use crate::executor::{CurrentThread, Executor, Job, Threads};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    env_logger::try_init().unwrap_or(());
+
+    struct Counting(AtomicUsize);
+    impl Executor for Counting {
+        fn execute(&self, job: Job) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Threads.execute(job)
+        }
+    }
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let mut files = Vec::new();
+    for i in 0..20 {
+        let path = format!("dir{}/file{}", i % 3, i);
+        repo.add_file(&path, format!("line {}\n", i).into_bytes());
+        txn.write().add_file(&path, 0)?;
+        files.push(path)
+    }
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    for path in files.iter().step_by(2) {
+        repo.write_file(path)?.write_all(b"modified\n")?;
+    }
+    repo.add_file("new/a", b"a\n".to_vec());
+    txn.write().add_file("new/a", 0)?;
+
+    let record = |executor: Arc<dyn Executor>| -> Result<_, anyhow::Error> {
+        let mut state = Builder::new();
+        state.io_concurrency = 2;
+        state.executor = executor;
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            3,
+        )?;
+        Ok(state.finish()?)
+    };
+    let threads = record(Arc::new(Threads))?;
+    let counting = Arc::new(Counting(AtomicUsize::new(0)));
+    let injected = record(counting.clone())?;
+    // Two diffing jobs and two reading jobs.
+    assert_eq!(counting.0.load(Ordering::SeqCst), 4);
+    let current = record(Arc::new(CurrentThread))?;
+    assert_eq!(threads.actions, injected.actions);
+    assert_eq!(threads.actions, current.actions);
+    assert_eq!(&*threads.contents.lock(), &*current.contents.lock());
+    Ok(())
+}
+
+/// A working copy failing to read `path` a number of times.
+#[derive(Clone)]
+struct Unreadable {
+    repo: memory::Memory,
+    path: &'static str,
+    failures: Arc<AtomicUsize>,
+}
+
+impl WorkingCopy for Unreadable {
+    type Error = memory::Error;
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        self.repo.create_dir_all(path)
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        self.repo.file_metadata(file)
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        if file == self.path && self.failures.load(Ordering::SeqCst) > 0 {
+            self.failures.fetch_sub(1, Ordering::SeqCst);
+            buffer.extend_from_slice(b"garbage");
+            return Err(memory::Error::NotFound {
+                path: file.to_string(),
+            });
+        }
+        self.repo.read_file(file, buffer)
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        self.repo.modified_time(file)
+    }
+    fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error> {
+        self.repo.remove_path(name, rec)
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        self.repo.rename(former, new)
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        self.repo.set_permissions(name, permissions)
+    }
+    type Writer = memory::Writer;
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        self.repo.write_file(file)
+    }
+}
+
+/// Record files that can't be read, according to the policy.
+#[test]
+fn read_errors() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_file("b", b"b\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("a")?.write_all(b"a\nx\n")?;
+    repo.write_file("b")?.write_all(b"b\nx\n")?;
+    repo.add_file("c", b"c\n".to_vec());
+    txn.write().add_file("c", 0)?;
+
+    let record = |failures: usize, policy: ReadErrorPolicy, path: &'static str, io: usize| {
+        let wc = Unreadable {
+            repo: repo.clone(),
+            path,
+            failures: Arc::new(AtomicUsize::new(failures)),
+        };
+        let mut state = Builder::new();
+        state.force_rediff = true;
+        state.read_error_policy = policy;
+        state.io_concurrency = io;
+        state
+            .record(
+                txn.clone(),
+                Algorithm::default(),
+                channel.clone(),
+                &wc,
+                &changes,
+                "",
+                1,
+            )
+            .and_then(|_| Ok(state.finish_with_report()?))
+    };
+
+    match record(usize::MAX, ReadErrorPolicy::Fail, "a", 1) {
+        Err(RecordError::WorkingCopy(_)) => {}
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("a is unreadable"),
+    }
+
+    let (rec, report) = record(usize::MAX, ReadErrorPolicy::Skip, "a", 1)?;
+    assert_eq!(
+        report.skipped,
+        vec![SkippedFile {
+            path: "a".to_string(),
+            error: "Path not found: a".to_string(),
+        }]
+    );
+    assert_eq!(rec.actions.len(), 2);
+
+    let policy = ReadErrorPolicy::Retry {
+        attempts: 2,
+        pause: std::time::Duration::from_millis(1),
+    };
+    let (rec, report) = record(2, policy, "a", 1)?;
+    assert!(report.skipped.is_empty());
+    assert_eq!(rec.actions.len(), 3);
+
+    // New files too, whether they are read in the background or not.
+    for io in [1, 4].iter() {
+        match record(usize::MAX, ReadErrorPolicy::Fail, "c", *io) {
+            Err(RecordError::WorkingCopy(_)) => {}
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => panic!("c is unreadable"),
+        }
+        let (rec, report) = record(usize::MAX, ReadErrorPolicy::Skip, "c", *io)?;
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].path, "c");
+        assert_eq!(rec.actions.len(), 2);
+    }
+    Ok(())
+}
+
+/// With `ignore_files`, ignored files added to the tree are not
+/// recorded, but tracked ignored files missing from the working copy
+/// are still deleted.
+#[test]
+fn ignore_files() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    for f in ["a.c", "build.o"].iter() {
+        repo.add_file(f, b"a\n".to_vec());
+        txn.write().add_file(f, 0)?;
+    }
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let files = [
+        (".ignore", "# artifacts\n*.o\n!keep.o\ntarget/\n"),
+        ("src/.ignore", "/gen.rs\n"),
+        ("keep.o", "a\n"),
+        ("c.o", "a\n"),
+        ("target/x", "a\n"),
+        ("src/gen.rs", "a\n"),
+        ("src/lib.rs", "a\n"),
+        ("src/sub/gen.rs", "a\n"),
+    ];
+    for (f, contents) in files.iter() {
+        repo.add_file(f, contents.as_bytes().to_vec());
+        txn.write().add_file(f, 0)?;
+    }
+    repo.remove_path("build.o", false)?;
+
+    let mut state = Builder::new();
+    state.ignore_files = true;
+    let (_, change, _) = record_with(state, &repo, &changes, &txn, &channel, "")?;
+    let mut added: Vec<_> = change
+        .changes
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileAdd { path, .. } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect();
+    added.sort();
+    assert_eq!(
+        added,
+        vec![
+            ".ignore",
+            "keep.o",
+            "src",
+            "src/.ignore",
+            "src/lib.rs",
+            "src/sub",
+            "src/sub/gen.rs"
+        ]
+    );
+    let deleted: Vec<_> = change
+        .changes
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileDel { path, .. } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deleted, vec!["build.o"]);
+    Ok(())
+}
+
+/// A change deleting lines already deleted by another change is
+/// semantically empty, and can be kept out of the log.
+#[test]
+fn empty_change() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let repo_bob = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+
+    let channel_alice = txn_alice.write().open_or_create_channel("main").unwrap();
+    txn_alice.write().add_file("file", 0).unwrap();
+    let init_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    let channel_bob = txn_bob.write().open_or_create_channel("main").unwrap();
+    let (_, _, report) = apply::apply_change_report(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel_bob.write(),
+        &init_h,
+    )?;
+    assert!(!report.empty);
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn_bob,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+
+    // Alice and Bob both delete the same line.
+    repo_alice.write_file("file")?.write_all(b"a\nc\n")?;
+    let alice_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+    repo_bob.write_file("file")?.write_all(b"a\nc\n")?;
+    let bob_h = record_all(&repo_bob, &changes, &txn_bob, &channel_bob, "")?;
+    assert_ne!(alice_h, bob_h);
+
+    let (_, _, report) = apply::apply_change_report(
+        &changes,
+        &mut *txn_alice.write(),
+        &mut *channel_alice.write(),
+        &bob_h,
+    )?;
+    assert!(report.empty);
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin().unwrap();
+    let channel = txn.open_or_create_channel("main").unwrap();
+    let options = ApplyOptions {
+        skip_empty: true,
+        ..ApplyOptions::default()
+    };
+    txn.apply_change_rec_opt(&changes, &mut *channel.write(), &alice_h, &options)?;
+    txn.apply_change_rec_opt(&changes, &mut *channel.write(), &bob_h, &options)?;
+    let log: Vec<_> = txn
+        .log(&*channel.read(), 0)?
+        .map(|x| x.map(|(_, (h, _))| h.into()))
+        .collect::<Result<Vec<Hash>, _>>()?;
+    assert_eq!(log, vec![init_h, alice_h]);
+
+    // A change of Bob's depending on his empty change is skipped too,
+    // and can still be applied later without the option.
+    repo_bob.write_file("file")?.write_all(b"a\nc\nd\n")?;
+    let mut state = Builder::new();
+    state.record(
+        txn_bob.clone(),
+        Algorithm::default(),
+        channel_bob.clone(),
+        &repo_bob,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn_bob.read()).unwrap())
+        .collect();
+    let mut dependent = crate::change::Change::make_change(
+        &*txn_bob.read(),
+        &channel_bob,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )?;
+    if !dependent.dependencies.contains(&bob_h) {
+        dependent.dependencies.push(bob_h)
+    }
+    let dependent_h = changes.save_change(&dependent)?;
+    txn.apply_change_rec_opt(&changes, &mut *channel.write(), &dependent_h, &options)?;
+    let log: Vec<_> = txn
+        .log(&*channel.read(), 0)?
+        .map(|x| x.map(|(_, (h, _))| h.into()))
+        .collect::<Result<Vec<Hash>, _>>()?;
+    assert_eq!(log, vec![init_h, alice_h]);
+    txn.apply_change_rec(&changes, &mut *channel.write(), &dependent_h)?;
+    assert!(txn.has_change(&channel, &dependent_h)?.is_some());
+    Ok(())
+}
+
+fn tree<T: TreeTxnT>(txn: &T) -> Vec<(pristine::OwnedPathId, Inode)> {
+    txn.iter_tree(&pristine::OwnedPathId::inode(Inode::ROOT), None)
+        .unwrap()
+        .map(|x| {
+            let (pid, &inode) = x.unwrap();
+            (pid.to_owned(), inode)
+        })
+        .collect()
+}
+
+/// Pristines with the same changes have the same inodes in the
+/// deterministic mode, regardless of their history.
+#[test]
+fn deterministic_inodes() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write()
+        .set_inode_allocation(InodeAllocation::Deterministic);
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("d/f", b"f\n".to_vec());
+    repo.add_file("g", b"g\n".to_vec());
+    txn.write().add_file("d/f", 0)?;
+    txn.write().add_file("g", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.rename("g", "d/g")?;
+    txn.write().move_file("g", "d/g", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let clone = |allocation: InodeAllocation| -> Result<_, anyhow::Error> {
+        let env = pristine::sanakirja::Pristine::new_anon()?;
+        let txn = env.arc_txn_begin().unwrap();
+        txn.write().set_inode_allocation(allocation);
+        let channel = txn.write().open_or_create_channel("main")?;
+        for h in [h0, h1].iter() {
+            apply::apply_change_arc(&changes, &txn, &channel, h)?;
+        }
+        let repo = working_copy::memory::Memory::new();
+        output::output_repository_no_pending(
+            &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+        )?;
+        Ok((env, txn))
+    };
+
+    let (_env2, txn2) = clone(InodeAllocation::Deterministic)?;
+    assert_eq!(tree(&*txn.read()), tree(&*txn2.read()));
+
+    // Migrating a repository with salted inodes.
+    let (_env3, txn3) = clone(InodeAllocation::Salted)?;
+    assert_ne!(tree(&*txn.read()), tree(&*txn3.read()));
+    assert!(crate::fs::make_inodes_deterministic(&mut *txn3.write())? > 0);
+    assert_eq!(
+        txn3.read().inode_allocation(),
+        InodeAllocation::Deterministic
+    );
+    assert_eq!(tree(&*txn.read()), tree(&*txn3.read()));
+    Ok(())
+}
+
+/// Follow a file across two renames.
+#[test]
+fn follow_renames() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let id = file_id(&changes, &*txn.read(), &*channel.read(), "a")?.unwrap();
+    assert_eq!(id.0.change, h0);
+    assert_eq!(FileId::from_base32(id.to_base32().as_bytes()), Some(id));
+    assert!(file_id(&changes, &*txn.read(), &*channel.read(), "b")?.is_none());
+
+    repo.rename("a", "b")?;
+    txn.write().move_file("a", "b", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.rename("b", "c")?;
+    txn.write().move_file("b", "c", 0)?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    assert!(file_id(&changes, &*txn, &*channel, "a")?.is_none());
+    assert_eq!(
+        file_ids_in_history(&changes, &*txn, &*channel, "a")?,
+        vec![id]
+    );
+    assert_eq!(
+        file_ids_in_history(&changes, &*txn, &*channel, "b")?,
+        vec![id]
+    );
+    assert_eq!(file_id(&changes, &*txn, &*channel, "c")?, Some(id));
+    assert_eq!(
+        file_path(&changes, &*txn, &*channel, &id)?,
+        Some("c".to_string())
+    );
+
+    let names = file_names(&changes, &*txn, &*channel, &id)?;
+    let names: Vec<_> = names
+        .iter()
+        .map(|n| (n.path.as_str(), n.change, n.alive))
+        .collect();
+    assert_eq!(
+        names,
+        vec![("a", h0, false), ("b", h1, false), ("c", h2, true)]
+    );
+    Ok(())
+}
+
+/// Record with [`Builder::detect_renames`] set to `threshold`, and
+/// return the renames found. The working copy isn't recorded, but
+/// the renames are left in the tree for the next record.
+fn find_renames<T: MutTxnT + Send + Sync + 'static>(
+    repo: &working_copy::memory::Memory,
+    changes: &changestore::memory::Memory,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    threshold: f64,
+) -> Result<Vec<Rename>, anyhow::Error> {
+    let mut state = Builder::new();
+    state.detect_renames = Some(threshold);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        repo,
+        changes,
+        "",
+        1,
+    )?;
+    Ok(std::mem::take(&mut state.renames))
+}
+
+/// A file deleted and added again under another name, with a small
+/// edit, is recorded as a move followed by the edit.
+#[test]
+fn detect_renames_() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let contents: Vec<u8> = (0..10)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    repo.add_file("a", contents.clone());
+    repo.add_file("b", b"something else\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    // `rm a`, then a copy of `a` with an edit, and an unrelated file.
+    repo.remove_path("a", false)?;
+    let mut edited = contents.clone();
+    edited.extend_from_slice(b"line 10\n");
+    repo.add_file("c", edited.clone());
+    repo.add_file("d", b"unrelated\n".to_vec());
+    txn.write().add_file("c", 0)?;
+    txn.write().add_file("d", 0)?;
+
+    let renames = find_renames(&repo, &changes, &txn, &channel, 0.8)?;
+    assert_eq!(
+        renames,
+        vec![Rename {
+            from: "a".to_string(),
+            to: "c".to_string(),
+            similarity: 20. / 21.,
+        }]
+    );
+
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    let change = changes.get_change(&h)?;
+    let moves = change
+        .changes
+        .iter()
+        .filter(|h| matches!(h, Hunk::FileMove { .. }))
+        .count();
+    let dels = change
+        .changes
+        .iter()
+        .filter(|h| matches!(h, Hunk::FileDel { .. }))
+        .count();
+    let adds: Vec<_> = change
+        .changes
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileAdd { path, .. } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(moves, 1);
+    assert_eq!(dels, 0);
+    assert_eq!(adds, vec!["d"]);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, vec!["b", "c", "d"]);
+    let mut buf = Vec::new();
+    repo2.read_file("c", &mut buf)?;
+    assert_eq!(buf, edited);
+    Ok(())
+}
+
+/// Added files are compared with the pristine after the line ending
+/// policy of the channel, like record would store them.
+#[test]
+fn detect_renames_eol() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let contents: Vec<u8> = (0..10)
+        .flat_map(|i| format!("line {}\r\n", i).into_bytes())
+        .collect();
+    repo.add_file("a", contents.clone());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    crate::channel_settings::save(
+        &mut *txn.write(),
+        &*channel.read(),
+        &crate::channel_settings::ChannelSettings {
+            eol: Some(crate::channel_settings::EolPolicy::Lf),
+            ..Default::default()
+        },
+    )?;
+    txn.write().add_file("a", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.rename("a", "b")?;
+    txn.write().add_file("b", 0)?;
+    let renames = find_renames(&repo, &changes, &txn, &channel, 0.9)?;
+    assert_eq!(
+        renames,
+        vec![Rename {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            similarity: 1.,
+        }]
+    );
+    Ok(())
+}
+
+/// A function moved to another file, and slightly modified on the
+/// way, is detected as a move.
+#[test]
+fn detect_moves() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file(
+        "a",
+        b"header\nfn f() {\n    one();\n    two();\n    three();\n}\nfooter\n".to_vec(),
+    );
+    repo.add_file("b", b"start\nend\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("a")?.write_all(b"header\nfooter\n")?;
+    repo.write_file("b")?
+        .write_all(b"start\nfn f() {\n    one();\n    two();\n    four();\n}\nend\n")?;
+    let (_, mut change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let moves = change.detect_moves(&changes, &MoveDetection::default())?;
+    assert_eq!(moves.len(), 1);
+    let m = &moves[0];
+    assert_eq!(m.from.path, "a");
+    assert_eq!(m.from.lines, 5);
+    assert_eq!(m.to.path, "b");
+    assert_eq!(m.to.line, 2);
+    assert_eq!(m.to.lines, 5);
+    assert!((m.similarity - 0.8).abs() < 1e-9);
+
+    // Stricter parameters reject it.
+    let strict = MoveDetection {
+        min_similarity: 0.9,
+        ..MoveDetection::default()
+    };
+    assert!(change.detect_moves(&changes, &strict)?.is_empty());
+
+    assert!(change.moves().is_empty());
+    change.annotate_moves(&moves);
+    assert_eq!(change.moves(), moves);
+    Ok(())
+}
+
+/// Nested repositories are recorded as a pointer to their state, and
+/// output as their pointer file.
+#[test]
+fn subrepo_pointer() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_dir("sub/.pijul");
+    repo.add_file("sub/.pijulsubrepo", b"main\n".to_vec());
+    repo.add_file("sub/x", b"x\n".to_vec());
+
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_dir("sub", 0)?;
+    txn.write().add_file("sub/x", 0)?;
+    let (h0, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let sub: Vec<_> = change
+        .changes
+        .iter()
+        .filter(|h| h.path().starts_with("sub"))
+        .collect();
+    assert_eq!(sub.len(), 1);
+    match sub[0] {
+        Hunk::SubrepoPointer {
+            path,
+            channel,
+            change,
+            ..
+        } => {
+            assert_eq!(path, "sub");
+            assert_eq!(channel, "main");
+            assert!(change.is_none());
+        }
+        h => panic!("unexpected hunk {:?}", h),
+    }
+    let mut text = Vec::new();
+    change
+        .write(
+            &changes,
+            Some(h0),
+            |l, _p| format!("{}:{}", l.path, l.line),
+            true,
+            &mut text,
+        )
+        .unwrap();
+    let parsed = Change::read(&text[..], &mut HashMap::default()).unwrap();
+    assert!(parsed
+        .changes
+        .iter()
+        .any(|h| matches!(h, Hunk::SubrepoPointer { channel, .. } if channel == "main")));
+
+    // Moving the nested repository forward edits the pointer.
+    let state = SubrepoState {
+        channel: "main".to_string(),
+        change: Some(h0),
+    };
+    repo.write_file(&format!("sub/{}", POINTER_FILE))?
+        .write_all(&state.to_bytes())?;
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert!(!change.changes.is_empty());
+    assert!(change.changes.iter().all(|h| h.path() == "sub"));
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, vec!["a", "sub", "sub/.pijulsubrepo"]);
+    let mut b = Vec::new();
+    repo2.read_file("sub/.pijulsubrepo", &mut b)?;
+    assert_eq!(SubrepoState::parse(&b), Some(state));
+    Ok(())
+}
+
+/// A directory whose files were recorded can't become a nested
+/// repository until they are removed.
+#[test]
+fn subrepo_tracked_files() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file("sub/x", b"x\n".to_vec());
+
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("sub/x", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.add_dir("sub/.pijul");
+    match record_all(&repo, &changes, &txn, &channel, "") {
+        Err(e) => match e.downcast_ref() {
+            Some(crate::record::RecordError::<
+                crate::changestore::memory::Error,
+                crate::working_copy::memory::Error,
+                crate::pristine::sanakirja::SanakirjaError,
+            >::TrackedInSubrepo(path)) => assert_eq!(path, "sub"),
+            _ => panic!("{:?}", e),
+        },
+        Ok(_) => panic!("recorded a nested repository over tracked files"),
+    }
+
+    // Once the files are removed, the pointer can be recorded.
+    txn.write().remove_file("sub/x")?;
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert!(change
+        .changes
+        .iter()
+        .any(|h| matches!(h, Hunk::FileDel { path, .. } if path == "sub/x")));
+    Ok(())
+}
+
+/// Opaque directories are recorded as a single file, and expanded
+/// back by output.
+#[test]
+fn opaque_dir() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file(ATTRIBUTES_FILE, b"vendor/ opaque\n".to_vec());
+    repo.add_file("src/main.rs", b"fn main() {}\n".to_vec());
+    repo.add_file("vendor/a/lib.rs", b"pub fn a() {}\n".to_vec());
+    repo.add_file("vendor/b/lib.rs", b"pub fn b() {}\n".to_vec());
+    repo.add_dir("vendor/empty");
+    let wc = Opaque::new(repo.clone())?;
+    assert!(wc.file_metadata("vendor")?.is_opaque());
+    assert!(wc.file_metadata("vendor/a/lib.rs").is_err());
+
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("src/main.rs", 0)?;
+    txn.write().add_file("vendor", 0)?;
+    let (_, change) = record_all_change(&wc, &changes, &txn, &channel, "")?;
+    let vendored = change
+        .changes
+        .iter()
+        .filter(|h| h.path().starts_with("vendor"))
+        .count();
+    assert_eq!(vendored, 1);
+
+    // Updating a vendored file only edits the archive.
+    repo.write_file("vendor/b/lib.rs")?
+        .write_all(b"pub fn b() -> u32 { 1 }\n")?;
+    repo.add_file("vendor/c/lib.rs", b"pub fn c() {}\n".to_vec());
+    let (_, change) = record_all_change(&wc, &changes, &txn, &channel, "")?;
+    assert!(!change.changes.is_empty());
+    assert!(change.changes.iter().all(|h| h.path() == "vendor"));
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "src",
+            "src/main.rs",
+            "vendor",
+            "vendor/a",
+            "vendor/a/lib.rs",
+            "vendor/b",
+            "vendor/b/lib.rs",
+            "vendor/c",
+            "vendor/c/lib.rs",
+            "vendor/empty",
+        ]
+    );
+    let mut b = Vec::new();
+    repo2.read_file("vendor/b/lib.rs", &mut b)?;
+    assert_eq!(b, b"pub fn b() -> u32 { 1 }\n");
+    Ok(())
+}
+
+/// Archives of opaque directories are only expanded inside the
+/// directory, and directories that can't be listed aren't recorded
+/// as empty.
+#[test]
+fn opaque_unpack_contained() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file("vendor/x", b"x\n".to_vec());
+    let escaping: &[&[u8]] = &[
+        // Writing through a link.
+        b"l 777 4 x\n/etc\nf 644 5 x/passwd\nowned\n",
+        b"l 777 4 x\n/etc\n",
+        b"l 777 5 a/x\n../..\n",
+        b"d 755 0 a\n\nl 777 7 a/x\nb/../..\n",
+        b"f 644 2 a\nb\n\nf 644 2 a\nc\n",
+    ];
+    for archive in escaping {
+        assert!(!crate::opaque::unpack(&repo, "vendor", archive)?);
+        assert_eq!(repo.list_files(), vec!["vendor", "vendor/x"]);
+    }
+
+    let archive = b"d 755 0 a\n\nf 644 2 a/b\nb\n\nl 777 4 a/l\n../a\n";
+    assert!(crate::opaque::unpack(&repo, "vendor", archive)?);
+    let mut files = repo.list_files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["vendor", "vendor/a", "vendor/a/b", "vendor/a/l"]
+    );
+
+    let repo = working_copy::memory::Memory::new();
+    repo.add_file(ATTRIBUTES_FILE, b"vendor/ opaque\n".to_vec());
+    repo.add_file("vendor/a", b"a\n".to_vec());
+    let wc = Opaque::new(Unreadable {
+        repo,
+        path: "",
+        failures: Arc::new(AtomicUsize::new(0)),
+    })?;
+    assert!(wc.read_file("vendor", &mut Vec::new()).is_err());
+    Ok(())
+}
@@ -1,12 +1,31 @@
-use crate::change::*;
-use crate::changestore::*;
-use crate::pristine::*;
-use crate::record::*;
-use crate::working_copy::*;
-use crate::*;
-use std::io::Write;
-
 use super::*;
+use crate::alive::retrieve::RetrieveLimits;
+use crate::apply::{preview_working_copy, PreviewAction, PreviewFile};
+use crate::audit::*;
+use crate::change::{HunkKind, Local, *};
+use crate::ci_status::*;
+use crate::compose::*;
+use crate::dedup::*;
+use crate::diff::{working_copy_gutter, LineChange, LineChangeKind};
+use crate::edit::*;
+use crate::header::*;
+use crate::hunk_kind::*;
+use crate::import::*;
+use crate::output::*;
+use crate::proof::*;
+use crate::provenance::*;
+use crate::record::*;
+use crate::redact::*;
+use crate::render::{self, Segment, Style};
+use crate::review::*;
+use crate::search::{grep, GrepMatch, SearchError};
+use crate::vertex_buffer::{END_MARKER, SEPARATOR, START_MARKER};
+use chrono::{TimeZone, Utc};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::io::{Cursor, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
 
 fn hash_mismatch(change: &Change) -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -55,7 +74,7 @@ fn hash_mism() -> Result<(), anyhow::Error> {
             0,
         )
         .unwrap();
-    let rec = state.finish();
+    let rec = state.finish()?;
     let changes: Vec<_> = rec
         .actions
         .into_iter()
@@ -150,6 +169,75 @@ fn text() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Changes starting or ending vertices in the middle of a line, as
+/// recorded with word diffs, are written and read back in the text
+/// format.
+#[cfg(feature = "text-changes")]
+#[test]
+fn text_mid_line() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"first\nthe quick brown fox\nlast\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &store, &txn, &channel, "")?;
+
+    let edits: [&[u8]; 4] = [
+        b"first\nthe quick red fox\nlast\n",
+        b"first\nthe quick red fox jumps\nlast\n",
+        b"first\nthe slow red fox jumps\nlast",
+        b"first\nthe fox\nlast\n",
+    ];
+    for (i, edit) in edits.iter().enumerate() {
+        repo.write_file("file")?.write_all(edit)?;
+        let mut state = Builder::new();
+        // The last edit is diffed by lines, and deletes a line made
+        // of several vertices.
+        state.word_diff = i < 3;
+        let (h, change, _) = record_with(state, &repo, &store, &txn, &channel, "")?;
+        text_test(&store, &change, h);
+    }
+    Ok(())
+}
+
+/// Changes splitting a long line into segments, as recorded with the
+/// hybrid text policy, are written and read back in the text format.
+#[cfg(feature = "text-changes")]
+#[test]
+fn text_segments() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file(
+        crate::attributes::ATTRIBUTES_FILE,
+        b"file text=hybrid:64\n".to_vec(),
+    );
+    let line: String = (0..200).map(|i| format!("w{} ", i)).collect();
+    repo.add_file("file", format!("first\n{}\nlast\n", line).into_bytes());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &store, &txn, &channel, "")?;
+
+    let edits = [
+        format!("first\n{}X{}\nlast\n", &line[..300], &line[300..]),
+        format!("first\n{}X{}Y\nlast\n", &line[..300], &line[300..]),
+        "first\nlast\n".to_string(),
+    ];
+    for edit in edits.iter() {
+        repo.write_file("file")?.write_all(edit.as_bytes())?;
+        let (h, change) = record_all_change(&repo, &store, &txn, &channel, "")?;
+        text_test(&store, &change, h);
+    }
+    Ok(())
+}
+
 fn text_test<C: ChangeStore>(c: &C, change0: &Change, h: Hash) {
     let mut v = Vec::new();
     // let channel = channel.borrow();
@@ -207,3 +295,1876 @@ fn text_test<C: ChangeStore>(c: &C, change0: &Change, h: Hash) {
     }
     assert_eq!(change0, &change1);
 }
+
+#[test]
+fn metrics() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &store, &txn, &channel, "")?;
+    let m0 = store.get_metrics(&h0)?;
+    assert_eq!(m0.hunks, 1);
+    assert_eq!(m0.added_bytes, 4);
+    assert_eq!(m0.deleted_bytes, 0);
+
+    repo.write_file("file")?.write_all(b"a\nc\nd\n")?;
+    let h1 = record_all(&repo, &store, &txn, &channel, "")?;
+    let change = store.get_change(&h1)?;
+    let m1 = change.hashed.metrics();
+    assert_eq!(
+        m1,
+        ChangeMetrics {
+            hunks: 1,
+            added_bytes: 4,
+            deleted_bytes: 2,
+            largest_file: 6,
+        }
+    );
+
+    // The metrics are readable from the change file alone.
+    let mut buf = tempfile::NamedTempFile::new()?;
+    let h = change.serialize(&mut buf)?;
+    let f = ChangeFile::open(h, buf.path().to_str().unwrap())?;
+    assert!(f.unhashed().as_ref().unwrap().get(METRICS_KEY).is_some());
+    assert_eq!(f.metrics(), m1);
+
+    // Changes without hunks don't get an unhashed section.
+    let empty = Change::make_change(
+        &*txn.read(),
+        &channel,
+        Vec::new(),
+        Vec::new(),
+        ChangeHeader::default(),
+        Vec::new(),
+    )?;
+    let mut buf = tempfile::NamedTempFile::new()?;
+    let h = empty.serialize(&mut buf)?;
+    let f = ChangeFile::open(h, buf.path().to_str().unwrap())?;
+    assert!(f.unhashed().is_none());
+    assert_eq!(f.metrics(), ChangeMetrics::default());
+    Ok(())
+}
+
+/// The paths touched by a change are readable from its unhashed
+/// section, and agree with its hunks.
+#[test]
+fn touched_paths() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_file("b", b"b\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &store, &txn, &channel, "")?;
+
+    repo.write_file("a")?.write_all(b"a\na2\n")?;
+    repo.remove_path("b", false)?;
+    txn.write().remove_file("b")?;
+    repo.add_file("c", b"c\n".to_vec());
+    txn.write().add_file("c", 0)?;
+    let h = record_all(&repo, &store, &txn, &channel, "")?;
+    let change = store.get_change(&h)?;
+    let touched = |path: &str, kinds: &[HunkKind]| TouchedPath {
+        path: path.to_string(),
+        kinds: kinds.to_vec(),
+    };
+    let expected = vec![
+        touched("a", &[HunkKind::Edit]),
+        touched("b", &[HunkKind::FileDel]),
+        touched("c", &[HunkKind::FileAdd]),
+    ];
+    assert_eq!(store.touched_paths(&h)?, expected);
+
+    let mut buf = tempfile::NamedTempFile::new()?;
+    change.serialize(&mut buf)?;
+    let paths = Change::read_touched_paths(buf.path().to_str().unwrap())?;
+    assert_eq!(paths, Some(expected));
+    Change::deserialize(buf.path().to_str().unwrap(), Some(&h))?;
+
+    // The index isn't hashed, a forged one is rejected.
+    let forged = serde_json::json!({ PATHS_KEY: [touched("a", &[HunkKind::Edit])] });
+    assert!(matches!(
+        crate::change::check_path_index(Some(&forged), &change.hashed, &h),
+        Err(ChangeError::PathIndexMismatch { .. })
+    ));
+    Ok(())
+}
+
+/// Sorting the hunks of a change by path keeps it applicable.
+#[test]
+fn sort_hunks() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("z", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("z", 0)?;
+    let h0 = record_all(&repo, &store, &txn, &channel, "")?;
+
+    repo.write_file("z")?.write_all(b"a\nx\nb\n")?;
+    for f in ["b/c/d", "b/a", "a"].iter() {
+        repo.add_file(f, f.as_bytes().to_vec());
+        txn.write().add_file(f, 0)?;
+    }
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &store,
+        "",
+        1,
+    )?;
+    let mut rec = state.finish()?;
+    rec.sort_hunks(SortOrder::Path);
+    let paths: Vec<_> = rec.actions.iter().map(|h| h.path().to_string()).collect();
+    let mut sorted = paths.clone();
+    sorted.sort();
+    assert_eq!(paths, sorted);
+    assert_eq!(paths.last().map(|p| p.as_str()), Some("z"));
+
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        ChangeHeader::default(),
+        Vec::new(),
+    )?;
+    let h1 = store.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &h1, &rec.updatables)?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    let channel2 = txn.write().open_or_create_channel("other")?;
+    apply::apply_change_arc(&store, &txn, &channel2, &h0)?;
+    apply::apply_change_arc(&store, &txn, &channel2, &h1)?;
+    output::output_repository_no_pending(&repo2, &store, &txn, &channel2, "", true, None, 1, 0)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, vec!["a", "b", "b/a", "b/c", "b/c/d", "z"]);
+    let mut buf = Vec::new();
+    repo2.read_file("z", &mut buf)?;
+    assert_eq!(buf, b"a\nx\nb\n");
+    Ok(())
+}
+
+/// Changes written incrementally have the same hash as changes
+/// serialized in memory, and can be read back, applied and output.
+#[test]
+fn streamed() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+    repo.add_file("dir/other", b"d\ne\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    txn.write().add_file("dir/other", 0)?;
+    let h = record_all(&repo, &store, &txn, &channel, "")?;
+    let change = store.get_change(&h)?;
+
+    let dir = tempfile::tempdir()?;
+    let fs = changestore::filesystem::FileSystem::from_changes(dir.path().to_path_buf(), 10);
+    let mut w = fs.change_writer()?;
+    w.header = change.header.clone();
+    w.dependencies = change.dependencies.clone();
+    w.extra_known = change.extra_known.clone();
+    w.metadata = change.metadata.clone();
+    // Write the contents in several pieces.
+    for chunk in change.contents.chunks(3) {
+        w.write_contents(chunk)?;
+    }
+    assert_eq!(w.contents_len().us(), change.contents.len());
+    for hunk in change.changes.iter() {
+        w.push_hunk(hunk)?;
+    }
+    assert_eq!(fs.save_change_writer(w)?, h);
+
+    let path = fs.filename(&h);
+    let path = path.to_str().unwrap();
+    let streamed = Change::deserialize(path, Some(&h))?;
+    // Streamed changes are ordinary change files.
+    assert_eq!(streamed.offsets.version, crate::change::VERSION);
+    assert_eq!(streamed.hashed, change.hashed);
+    assert_eq!(streamed.contents, change.contents);
+    Change::check_from_buffer(&std::fs::read(path)?, &h)?;
+
+    let mut reader = ChangeReader::open(path, Some(&h))?;
+    assert_eq!(reader.len(), change.changes.len() as u64);
+    let hunks: Result<Vec<_>, _> = (&mut reader).collect();
+    assert_eq!(hunks?, change.changes);
+    assert_eq!(reader.contents_hash(), Some(change.contents_hash));
+
+    let without = fs.get_change_without_contents(&h)?;
+    assert_eq!(without.hashed, change.hashed);
+    assert!(without.contents.is_empty());
+    assert_eq!(
+        ChangeFile::open(h, path)?.metrics(),
+        change.hashed.metrics()
+    );
+
+    // Apply and output from the file system store.
+    let repo2 = working_copy::memory::Memory::new();
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    txn2.write().apply_change(&fs, &mut *channel2.write(), &h)?;
+    output::output_repository_no_pending(&repo2, &fs, &txn2, &channel2, "", true, None, 1, 0)
+        .unwrap();
+    let mut buf = Vec::new();
+    repo2.read_file("dir/other", &mut buf)?;
+    assert_eq!(buf, b"d\ne\n");
+    Ok(())
+}
+
+/// Redacted changes are only served in part, except to their readers,
+/// and can still be applied by the others.
+#[test]
+fn redaction_policy() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"secret\nfix\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let (h, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("b", b"public\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    let (h2, _) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+
+    let policy = RedactionPolicy {
+        rules: vec![
+            RedactionRule {
+                changes: vec![h.to_base32()],
+                readers: vec!["alice".to_string()],
+                visibility: Visibility::NoContents,
+            },
+            RedactionRule {
+                changes: vec![h.to_base32()],
+                readers: vec!["alice".to_string(), "bob".to_string()],
+                visibility: Visibility::Hidden,
+            },
+        ],
+    };
+    assert_eq!(policy.visibility(Some("alice"), &h), Visibility::Full);
+    assert_eq!(policy.visibility(Some("bob"), &h), Visibility::NoContents);
+    assert_eq!(policy.visibility(Some("eve"), &h), Visibility::Hidden);
+    assert_eq!(policy.visibility(None, &h), Visibility::Hidden);
+    assert_eq!(policy.visibility(None, &h2), Visibility::Full);
+    assert_eq!(
+        RedactionPolicy::default().visibility(None, &h),
+        Visibility::Full
+    );
+
+    let mut file = Vec::new();
+    change.serialize(&mut file)?;
+    let len = file.len() as u64;
+    let mut r = Cursor::new(&file);
+    assert_eq!(visible_len(Visibility::Full, &mut r)?, Some(len));
+    assert_eq!(visible_len(Visibility::Hidden, &mut r)?, None);
+    let no_contents = visible_len(Visibility::NoContents, &mut r)?.unwrap();
+    assert!(no_contents < len);
+    assert_eq!(no_contents, Change::size_no_contents(&mut r)?);
+    assert!(!is_redacted_file(&file, &h)?);
+
+    // Redact the change: only its contents are masked.
+    let redacted = redact(&change, &h);
+    assert_eq!(redacted.hashed, change.hashed);
+    let secret = b"secret";
+    assert!(change.contents.windows(secret.len()).any(|w| w == secret));
+    assert!(!redacted.contents.windows(secret.len()).any(|w| w == secret));
+
+    // Clients can check the redacted change against its hash, except
+    // for its contents, and store it apart from the full changes.
+    let dir = tempfile::tempdir()?;
+    let fs = changestore::filesystem::FileSystem::from_changes(dir.path().to_path_buf(), 10);
+    let served = redact_file(fs_path(&changes, &h)?.path().to_str().unwrap(), &h)?;
+    assert!(is_redacted_file(&served, &h)?);
+    assert!(Change::check_from_buffer(&served, &h).is_err());
+    assert!(fs.save_from_buf(&served, &h, None).is_err());
+    check_redacted(&served, &h)?;
+    fs.save_redacted_from_buf(&served, &h, None)?;
+    assert!(!fs.filename(&h).exists());
+    assert!(fs.has_change(&h));
+    let path = fs.redacted_filename(&h);
+    Change::deserialize(path.to_str().unwrap(), Some(&h))?;
+    assert!(Change::deserialize(path.to_str().unwrap(), Some(&h2)).is_err());
+    fs.save_change(&changes.get_change(&h2)?)?;
+
+    // The redaction marker doesn't make forged changes pass.
+    let mut forged = Vec::new();
+    redact(&changes.get_change(&h2)?, &h).serialize(&mut forged)?;
+    assert!(is_redacted_file(&forged, &h)?);
+    assert!(check_redacted(&forged, &h).is_err());
+    assert!(fs.save_redacted_from_buf(&forged, &h2, None).is_err());
+
+    // The states of the channel stay the same, and files only
+    // miss their contents.
+    let repo2 = working_copy::memory::Memory::new();
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    txn2.write().apply_change(&fs, &mut *channel2.write(), &h)?;
+    txn2.write()
+        .apply_change(&fs, &mut *channel2.write(), &h2)?;
+    assert_eq!(
+        txn2.read().current_state(&*channel2.read())?,
+        txn.read().current_state(&*channel.read())?
+    );
+    output::output_repository_no_pending(&repo2, &fs, &txn2, &channel2, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("a", &mut buf)?;
+    assert_eq!(buf, b"??????\n???\n");
+    buf.clear();
+    repo2.read_file("b", &mut buf)?;
+    assert_eq!(buf, b"public\n");
+    Ok(())
+}
+
+/// Write change `h` of `changes` to a temporary file.
+fn fs_path(
+    changes: &changestore::memory::Memory,
+    h: &Hash,
+) -> Result<tempfile::NamedTempFile, anyhow::Error> {
+    let mut f = tempfile::NamedTempFile::new()?;
+    changes.get_change(h)?.serialize(&mut f)?;
+    Ok(f)
+}
+
+static COUNTER: AtomicI64 = AtomicI64::new(0);
+
+/// A kind adding its payload to `COUNTER`.
+struct Counter;
+
+impl hunk_kind::HunkKind for Counter {
+    fn tag(&self) -> &str {
+        "test-counter"
+    }
+    fn apply(&self, _channel: &str, _path: &str, payload: &[u8]) -> Result<(), HunkKindError> {
+        let mut b = [0; 8];
+        if payload.len() != 8 {
+            return Err(HunkKindError("Invalid payload".to_string()));
+        }
+        b.copy_from_slice(payload);
+        COUNTER.fetch_add(i64::from_le_bytes(b), Ordering::SeqCst);
+        Ok(())
+    }
+    fn invert(&self, payload: &[u8]) -> Result<Vec<u8>, HunkKindError> {
+        let mut b = [0; 8];
+        if payload.len() != 8 {
+            return Err(HunkKindError("Invalid payload".to_string()));
+        }
+        b.copy_from_slice(payload);
+        Ok((-i64::from_le_bytes(b)).to_le_bytes().to_vec())
+    }
+    fn render(&self, payload: &[u8]) -> String {
+        let mut b = [0; 8];
+        b.copy_from_slice(payload);
+        format!("add {}", i64::from_le_bytes(b))
+    }
+}
+
+/// Apply and unrecord a change with a custom hunk, and check the
+/// kinds of the pristine.
+#[test]
+fn custom_hunk_kind() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let hunk = Hunk::Custom {
+        kind: "test-counter".to_string(),
+        payload: 5i64.to_le_bytes().to_vec(),
+        local: Local {
+            path: "schema".to_string(),
+            line: 1,
+        },
+    };
+    let change = crate::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        vec![hunk],
+        Vec::new(),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let h = changes.save_change(&change)?;
+
+    // Unregistered kinds can't be inverted or applied.
+    assert!(change
+        .inverse(&h, crate::change::ChangeHeader::default(), Vec::new())
+        .is_err());
+    assert!(matches!(
+        txn.write()
+            .apply_change(&changes, &mut *channel.write(), &h),
+        Err(ApplyError::LocalChange {
+            err: LocalApplyError::UnknownHunkKind { .. }
+        })
+    ));
+
+    hunk_kind::register(Arc::new(Counter))?;
+    assert!(matches!(
+        hunk_kind::register(Arc::new(Counter)),
+        Err(RegisterError::AlreadyRegistered(_))
+    ));
+    txn.write()
+        .apply_change(&changes, &mut *channel.write(), &h)?;
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 5);
+    assert_eq!(txn.read().hunk_kinds()?, vec!["test-counter".to_string()]);
+    hunk_kind::validate(&*txn.read())?;
+
+    // The text format renders the payload, and reads it back.
+    let mut text = Vec::new();
+    change
+        .write(
+            &changes,
+            Some(h),
+            |l, _p| format!("{}:{}", l.path, l.line),
+            true,
+            &mut text,
+        )
+        .unwrap();
+    assert!(std::str::from_utf8(&text)?.contains("  add 5\n"));
+    let parsed = crate::change::Change::read(&text[..], &mut HashMap::default()).unwrap();
+    assert_eq!(parsed.changes, change.changes);
+    let inverse = change.inverse(&h, crate::change::ChangeHeader::default(), Vec::new())?;
+    assert!(matches!(
+        &inverse.changes[0],
+        Hunk::Custom { payload, .. } if payload[..] == (-5i64).to_le_bytes()[..]
+    ));
+
+    txn.write().unrecord(&changes, &channel, &h, 0)?;
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+
+    txn.write().put_hunk_kind("test-unregistered")?;
+    assert!(matches!(
+        hunk_kind::validate(&*txn.read()),
+        Err(ValidateError::Unregistered(k)) if k == vec!["test-unregistered".to_string()]
+    ));
+    Ok(())
+}
+
+/// Headers checked against a policy requiring conventional commits
+/// and signed authors.
+#[test]
+fn header_policy() -> Result<(), anyhow::Error> {
+    assert_eq!(conventional_type("fix(parser)!: handle CRLF"), Some("fix"));
+    assert_eq!(conventional_type("feat: add pools"), Some("feat"));
+    assert_eq!(conventional_type("Add pools"), None);
+    assert_eq!(conventional_type("fix(): empty scope"), None);
+    assert_eq!(conventional_type("fix: "), None);
+
+    let mut header = crate::change::ChangeHeader {
+        message: "Fix the parser".to_string(),
+        ..Default::default()
+    };
+    check(&HeaderPolicy::default(), &header)?;
+
+    let policy = HeaderPolicy {
+        conventional: true,
+        types: vec!["fix".to_string(), "feat".to_string()],
+        max_message_length: Some(20),
+        require_authors: true,
+        required_author_keys: vec!["key".to_string()],
+        ..Default::default()
+    };
+    let err = check(&policy, &header).unwrap_err();
+    assert_eq!(
+        err.violations,
+        &[
+            Violation::NotConventional {
+                message: "Fix the parser".to_string()
+            },
+            Violation::MissingAuthors,
+        ]
+    );
+
+    header.message = "docs: explain the parser".to_string();
+    let mut author = std::collections::BTreeMap::new();
+    author.insert("name".to_string(), "alice".to_string());
+    header.authors.push(crate::change::Author(author));
+    let err = check(&policy, &header).unwrap_err();
+    assert_eq!(
+        err.violations,
+        &[
+            Violation::UnknownType {
+                kind: "docs".to_string(),
+                allowed: policy.types.clone(),
+            },
+            Violation::MessageTooLong { len: 24, max: 20 },
+            Violation::MissingAuthorKey {
+                author: 0,
+                key: "key".to_string()
+            },
+        ]
+    );
+
+    header.message = "fix: the parser".to_string();
+    header.authors[0]
+        .0
+        .insert("key".to_string(), "abcd".to_string());
+    check(&policy, &header)?;
+
+    let policy: HeaderPolicy = toml::from_str("conventional = true\nrequire_description = true")?;
+    let err = check(&policy, &header).unwrap_err();
+    assert_eq!(err.violations, &[Violation::MissingDescription]);
+    Ok(())
+}
+
+/// Changes whose headers break the policy of the apply options are
+/// refused by apply.
+#[test]
+fn apply_header_policy() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin().unwrap();
+    let channel2 = txn2.open_or_create_channel("main")?;
+    let mut options = crate::apply::ApplyOptions {
+        headers: HeaderPolicy {
+            conventional: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h, &options) {
+        Err(crate::apply::ApplyError::InvalidHeader { hash, err }) if hash == h => {
+            assert_eq!(
+                err.violations,
+                &[Violation::NotConventional {
+                    message: "test".to_string()
+                }]
+            )
+        }
+        e => panic!("{:?}", e),
+    }
+    assert!(txn2.get_internal(&h.into())?.is_none());
+
+    options.headers = HeaderPolicy::default();
+    txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h, &options)?;
+    assert!(txn2.has_change(&channel2, &h)?.is_some());
+    Ok(())
+}
+
+/// Record a manifest while outputting, then verify the working copy
+/// against it.
+#[test]
+fn output_manifest() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/a", b"a\nb\n".to_vec());
+    repo.add_file("b", b"c\n".to_vec());
+    repo.add_file("c", b"d\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/a", 0)?;
+    txn.write().add_file("b", 0)?;
+    txn.write().add_file("c", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let checkout = working_copy::memory::Memory::new();
+    let wc = ManifestWorkingCopy::new(checkout.clone());
+    output_repository_no_pending(&wc, &changes, &txn, &channel, "", true, None, 1, 0).unwrap();
+    let mut manifest = wc.manifest();
+    manifest.state = txn.read().current_state(&*channel.read())?;
+    debug!("{:?}", manifest);
+    assert_eq!(
+        manifest.files.keys().collect::<Vec<_>>(),
+        &["b", "c", "dir/a"]
+    );
+    assert_eq!(manifest.files["dir/a"], Manifest::hash(b"a\nb\n"));
+    assert!(manifest.verify(&checkout)?.is_empty());
+
+    let mut json = Vec::new();
+    manifest.to_json(&mut json)?;
+    let manifest_ = Manifest::from_json(&json[..])?;
+    assert_eq!(manifest, manifest_);
+
+    checkout.write_file("b")?.write_all(b"x\n")?;
+    checkout.remove_path("c", false)?;
+    let mismatches = manifest.verify(&checkout)?;
+    assert_eq!(mismatches.len(), 2);
+    assert_eq!(
+        mismatches[0],
+        ManifestMismatch::Modified {
+            path: "b".to_string(),
+            expected: Manifest::hash(b"c\n"),
+            hash: Manifest::hash(b"x\n").hash,
+            size: 2,
+        }
+    );
+    assert_eq!(
+        mismatches[1],
+        ManifestMismatch::Missing {
+            path: "c".to_string()
+        }
+    );
+
+    // Removing and renaming through the recording working copy
+    // updates the manifest.
+    wc.remove_path("b", false)?;
+    wc.rename("dir", "dir2")?;
+    let manifest = wc.manifest();
+    assert_eq!(manifest.files.keys().collect::<Vec<_>>(), &["c", "dir2/a"]);
+    Ok(())
+}
+
+/// Check inclusion and extension proofs against the current state
+/// of a channel.
+#[test]
+fn proofs() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let state0 = txn.read().current_state(&*channel.read())?;
+
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let state1 = txn.read().current_state(&*channel.read())?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let p0 = inclusion_proof(&*txn, &*channel, &h0)?.unwrap();
+    assert_eq!(p0.previous, Merkle::zero());
+    assert_eq!(p0.suffix, vec![h1]);
+    assert!(p0.verify(&state1));
+    assert!(!p0.verify(&state0));
+
+    let p1 = inclusion_proof(&*txn, &*channel, &h1)?.unwrap();
+    assert_eq!(p1.previous, state0);
+    assert!(p1.verify(&state1));
+
+    let e = extension_proof(&*txn, &*channel, &state0)?.unwrap();
+    assert_eq!(e.changes, vec![h1]);
+    assert!(e.verify(&state0, &state1));
+    assert!(!e.verify(&Merkle::zero(), &state1));
+    let e = extension_proof(&*txn, &*channel, &Merkle::zero())?.unwrap();
+    assert!(e.verify(&Merkle::zero(), &state1));
+    Ok(())
+}
+
+fn seg(text: &str, style: Style, emphasis: bool, escaped: bool) -> Segment {
+    Segment {
+        text: text.to_string(),
+        style,
+        emphasis,
+        escaped,
+    }
+}
+
+/// Invalid UTF-8 is escaped, and highlights don't split grapheme
+/// clusters.
+#[test]
+fn render() {
+    assert_eq!(render::escape(b"a\xff\xfeb"), "a\\xff\\xfeb");
+    assert_eq!(
+        render::segments(b"a\xffb", Style::Context),
+        vec![
+            seg("a", Style::Context, false, false),
+            seg("\\xff", Style::Context, false, true),
+            seg("b", Style::Context, false, false),
+        ]
+    );
+
+    // The combining accent is highlighted along with its "e".
+    let (old, new) = render::replacement("cafe\u{301}s\n".as_bytes(), b"cafes\n");
+    assert_eq!(
+        old,
+        vec![
+            seg("caf", Style::Deleted, false, false),
+            seg("e\u{301}", Style::Deleted, true, false),
+            seg("s\n", Style::Deleted, false, false),
+        ]
+    );
+    assert_eq!(
+        new,
+        vec![
+            seg("caf", Style::Added, false, false),
+            seg("e", Style::Added, true, false),
+            seg("s\n", Style::Added, false, false),
+        ]
+    );
+
+    let contents = format!("a{}x{}y{}b\n", START_MARKER, SEPARATOR, END_MARKER);
+    let segments = render::conflicts(contents.as_bytes());
+    let styles: Vec<_> = segments.iter().map(|s| s.style).collect();
+    assert_eq!(
+        styles,
+        vec![
+            Style::Context,
+            Style::Marker,
+            Style::Side(0),
+            Style::Marker,
+            Style::Side(1),
+            Style::Marker,
+            Style::Context,
+        ]
+    );
+}
+
+/// The gutter of a file lists its added, modified and removed lines,
+/// and follows the channel as changes are recorded.
+#[test]
+fn working_copy_gutter_lines() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("file", b"a\nb\nc\nd\ne\n".to_vec());
+    txn.write().add_file("file", 0)?;
+
+    let gutter = || {
+        let txn = txn.read();
+        working_copy_gutter(&*txn, &*channel.read(), &changes, &repo, "file")
+    };
+    assert_eq!(
+        gutter()?,
+        vec![LineChange {
+            kind: LineChangeKind::Added,
+            old: 0..0,
+            new: 0..5,
+        }]
+    );
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    assert!(gutter()?.is_empty());
+
+    repo.write_file("file")?.write_all(b"a\nx\nc\ne\nf\n")?;
+    assert_eq!(
+        gutter()?,
+        vec![
+            LineChange {
+                kind: LineChangeKind::Modified,
+                old: 1..2,
+                new: 1..2,
+            },
+            LineChange {
+                kind: LineChangeKind::Removed,
+                old: 3..4,
+                new: 3..3,
+            },
+            LineChange {
+                kind: LineChangeKind::Added,
+                old: 5..5,
+                new: 4..5,
+            },
+        ]
+    );
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    assert!(gutter()?.is_empty());
+    Ok(())
+}
+
+/// Previewing a change lists the files it would touch, and flags those
+/// with unrecorded edits in the working copy.
+#[test]
+fn preview_working_copy_() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    for f in ["a", "b", "c", "e"].iter() {
+        repo.add_file(f, format!("{}\n", f).into_bytes());
+    }
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    for f in ["a", "b", "c", "e"].iter() {
+        txn.write().add_file(f, 0)?;
+    }
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+
+    repo.write_file("a")?.write_all(b"a\na2\n")?;
+    repo.write_file("b")?.write_all(b"b2\n")?;
+    repo.remove_path("c", false)?;
+    txn.write().remove_file("c")?;
+    repo.rename("e", "f")?;
+    txn.write().move_file("e", "f", 0)?;
+    repo.add_file("d", b"d\n".to_vec());
+    txn.write().add_file("d", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    let change = changes.get_change(&h)?;
+
+    // A working copy of `other`, with unrecorded edits to `b`, an
+    // untracked `d`, and `c` deleted.
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &other, "", true, None, 1, 0)?;
+    repo2.write_file("b")?.write_all(b"b\nlocal\n")?;
+    repo2.add_file("d", b"untracked\n".to_vec());
+    repo2.remove_path("c", false)?;
+
+    let txn = txn.read();
+    let preview = preview_working_copy(&changes, &*txn, &*other.read(), &change, &repo2)?;
+    let file = |path: &str, action, locally_modified| PreviewFile {
+        path: path.to_string(),
+        action,
+        locally_modified,
+    };
+    assert_eq!(
+        preview.files,
+        vec![
+            file("a", PreviewAction::Edit, false),
+            file("b", PreviewAction::Edit, true),
+            file("c", PreviewAction::Delete, false),
+            file("d", PreviewAction::Add, true),
+            file("e", PreviewAction::Move, false),
+        ]
+    );
+    let conflicts: Vec<_> = preview.conflicts().map(|f| f.path.as_str()).collect();
+    assert_eq!(conflicts, vec!["b", "d"]);
+    Ok(())
+}
+
+/// Conflicts output to sidecars leave the file without markers, are
+/// still recorded as conflicts, and are cleaned once resolved.
+#[test]
+fn sidecar_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let contents = b"a\nb\n";
+    let alice = b"a\nx\ny\nz\nb\n";
+    let bob = b"a\nu\nv\nw\nb\n";
+
+    let repo_alice = Sidecars::new(working_copy::memory::Memory::new());
+    let changes = changestore::memory::Memory::new();
+    repo_alice.working_copy.add_file("file", contents.to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel_alice = txn.write().open_or_create_channel("alice")?;
+    txn.write().add_file("file", 0)?;
+    let init_h = record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let channel_bob = txn.write().open_or_create_channel("bob")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_bob.write(),
+        &init_h,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    repo_bob.write_file("file").unwrap().write_all(bob).unwrap();
+    let bob_h = record_all(&repo_bob, &changes, &txn, &channel_bob, "")?;
+
+    repo_alice
+        .write_file("file")
+        .unwrap()
+        .write_all(alice)
+        .unwrap();
+    record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_alice.write(),
+        &bob_h,
+    )?;
+    let conflicts = output::output_repository_no_pending(
+        &repo_alice,
+        &changes,
+        &txn,
+        &channel_alice,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    assert_eq!(conflicts.len(), 1);
+
+    // The file has one side inline, the sidecars have both.
+    let mut buf = Vec::new();
+    repo_alice.working_copy.read_file("file", &mut buf)?;
+    debug!("inline = {:?}", std::str::from_utf8(&buf));
+    assert!(buf == alice || buf == bob);
+    let meta = repo_alice.meta("file").unwrap();
+    assert_eq!(meta.conflicts.len(), 1);
+    assert_eq!(meta.conflicts[0].line, 2);
+    assert_eq!(meta.conflicts[0].sides, 2);
+    let mut sides = Vec::new();
+    for k in 1..=2 {
+        let mut side = Vec::new();
+        let path = format!("{}/1-{}", sidecar_dir("file"), k);
+        repo_alice.working_copy.read_file(&path, &mut side)?;
+        sides.push(side)
+    }
+    sides.sort();
+    assert_eq!(sides, vec![b"u\nv\nw\n".to_vec(), b"x\ny\nz\n".to_vec()]);
+    assert_eq!(repo_alice.is_resolved("file")?, Some(false));
+
+    // Reading the file through the sidecars shows the conflict, so
+    // recording it records nothing.
+    let mut buf = Vec::new();
+    repo_alice.read_file("file", &mut buf)?;
+    assert!(buf.windows(4).any(|w| w == b">>>>"));
+    let (_, change) = record_all_change(&repo_alice, &changes, &txn, &channel_alice, "")?;
+    assert!(change.changes.is_empty());
+    assert!(repo_alice.clean_resolved()?.is_empty());
+
+    // Resolve and record.
+    repo_alice
+        .write_file("file")
+        .unwrap()
+        .write_all(b"a\nx\ny\nz\nu\nv\nw\nb\n")
+        .unwrap();
+    assert_eq!(repo_alice.is_resolved("file")?, Some(true));
+    let (_, change) = record_all_change(&repo_alice, &changes, &txn, &channel_alice, "")?;
+    assert!(!change.changes.is_empty());
+    assert_eq!(repo_alice.clean_resolved()?, vec!["file".to_string()]);
+    assert!(repo_alice.meta("file").is_none());
+    assert_eq!(repo_alice.is_resolved("file")?, None);
+    Ok(())
+}
+
+/// Markers split across two writes are still found, and the file
+/// is split to its sidecars.
+#[test]
+fn sidecar_split_write() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+    let repo = Sidecars::new(working_copy::memory::Memory::new());
+    let contents = format!(
+        "a{}x\n{}u\n{}b\n",
+        START_MARKER,
+        SEPARATOR.trim_start(),
+        END_MARKER.trim_start()
+    );
+    let cut = contents.find('>').unwrap() + 10;
+    let mut w = repo.write_file("file")?;
+    w.write_all(&contents.as_bytes()[..cut])?;
+    w.write_all(&contents.as_bytes()[cut..])?;
+    std::mem::drop(w);
+    repo.output_hook("file", InodeMetadata::new(0o644, false))?;
+
+    let meta = repo.meta("file").unwrap();
+    assert_eq!(meta.conflicts.len(), 1);
+    let mut buf = Vec::new();
+    repo.working_copy.read_file("file", &mut buf)?;
+    assert_eq!(buf, b"a\nx\nb\n");
+    Ok(())
+}
+
+/// Reviews from several reviewers merge to the same result in any
+/// order, and their addresses resolve to the hunks of the change.
+#[test]
+fn review_merge() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\nb\nc\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    let change = changes.get_change(&h)?;
+
+    let addr = HunkAddress::new(h, 0);
+    assert!(addr.resolve(&h, &change).is_some());
+    assert!(HunkAddress::new(h, change.changes.len())
+        .resolve(&h, &change)
+        .is_none());
+
+    let verdict = |reviewer: &str, t: i64, verdict| VerdictAnnotation {
+        address: addr,
+        reviewer: reviewer.to_string(),
+        timestamp: Utc.timestamp_opt(t, 0).unwrap(),
+        verdict,
+    };
+    let mut alice = Review::new();
+    alice.set_verdict(verdict("alice", 1, Verdict::NeedsWork));
+    alice.comment(Comment {
+        address: addr.lines(3, 2),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+        reviewer: "alice".to_string(),
+        text: "Why c?".to_string(),
+    });
+    let mut alice2 = Review::new();
+    alice2.set_verdict(verdict("alice", 2, Verdict::Approved));
+    let mut bob = Review::new();
+    bob.set_verdict(verdict("bob", 1, Verdict::Approved));
+
+    assert_eq!(alice.status(&h, 0), Some(Verdict::NeedsWork));
+    assert_eq!(Review::new().status(&h, 0), None);
+
+    let mut a = alice.clone();
+    a.merge(&bob);
+    a.merge(&alice2);
+    let mut b = alice2.clone();
+    b.merge(&bob);
+    b.merge(&alice);
+    b.merge(&alice);
+    assert_eq!(a, b);
+    assert_eq!(a.verdicts().len(), 2);
+    assert_eq!(a.status(&h, 0), Some(Verdict::Approved));
+    assert_eq!(a.comments_on(&h, 0).count(), 1);
+    assert_eq!(a.comments()[0].address.lines, Some((2, 3)));
+
+    // Same date: needs-work wins, whatever the order.
+    let mut c = a.clone();
+    c.set_verdict(verdict("bob", 1, Verdict::NeedsWork));
+    c.set_verdict(verdict("bob", 1, Verdict::Approved));
+    assert_eq!(c.status(&h, 0), Some(Verdict::NeedsWork));
+
+    let mut json = Vec::new();
+    c.to_json(&mut json)?;
+    assert_eq!(Review::from_json(&json[..])?, c);
+    Ok(())
+}
+
+/// Reviews are stored in the pristine by change, and imported from
+/// other repositories in any order.
+#[test]
+fn review_store() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let h = Hash::Blake3([1; 32]);
+    let h2 = Hash::Blake3([2; 32]);
+    let verdict = |change, reviewer: &str, t: i64, verdict| VerdictAnnotation {
+        address: HunkAddress::new(change, 0),
+        reviewer: reviewer.to_string(),
+        timestamp: Utc.timestamp_opt(t, 0).unwrap(),
+        verdict,
+    };
+    let note = Comment {
+        address: HunkAddress::new(h2, 1).lines(1, 4),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+        reviewer: "bob".to_string(),
+        text: "Looks fine".to_string(),
+    };
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin()?;
+    assert_eq!(crate::review::get(&txn, &h)?, Review::new());
+    assert!(crate::review::set_verdict(
+        &mut txn,
+        verdict(h, "alice", 2, Verdict::Approved)
+    )?);
+    assert!(!crate::review::set_verdict(
+        &mut txn,
+        verdict(h, "alice", 1, Verdict::NeedsWork)
+    )?);
+    assert!(crate::review::comment(&mut txn, note.clone())?);
+    assert!(!crate::review::comment(&mut txn, note.clone())?);
+    assert_eq!(
+        crate::review::get(&txn, &h)?.status(&h, 0),
+        Some(Verdict::Approved)
+    );
+    assert_eq!(crate::review::get(&txn, &h)?.comments().len(), 0);
+    assert_eq!(crate::review::get(&txn, &h2)?.comments(), &[note.clone()]);
+
+    assert_eq!(crate::notes::namespaces(&txn, &h)?, vec![NAMESPACE]);
+
+    // Another repository, where bob already reviewed, pulls these
+    // annotations with the notes.
+    let mut list = Vec::new();
+    crate::notes::write_list(&crate::notes::list(&txn, NAMESPACE)?, &mut list)?;
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin()?;
+    crate::review::set_verdict(&mut txn2, verdict(h, "bob", 1, Verdict::NeedsWork))?;
+    let received = Review::from_notes(&crate::notes::read_list(&list)?)?;
+    assert_eq!(crate::review::import(&mut txn2, &received)?, 2);
+    assert_eq!(crate::review::import(&mut txn2, &received)?, 0);
+    let all = crate::review::list(&txn2)?;
+    assert_eq!(all.verdicts().len(), 2);
+    assert_eq!(all.status(&h, 0), Some(Verdict::NeedsWork));
+    assert_eq!(all.comments(), &[note]);
+    Ok(())
+}
+
+/// Notes are stored by change and namespace, and merged by the owner
+/// of their namespace when imported.
+#[test]
+fn notes_store() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let h = Hash::Blake3([1; 32]);
+    let h2 = Hash::Blake3([2; 32]);
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin()?;
+    crate::notes::put(&mut txn, &h, "a", b"1")?;
+    crate::notes::put(&mut txn, &h, "b", b"2")?;
+    crate::notes::put(&mut txn, &h2, "a", b"3")?;
+    assert_eq!(crate::notes::get(&txn, &h, "a")?, Some(b"1".to_vec()));
+    assert_eq!(crate::notes::namespaces(&txn, &h)?, vec!["a", "b"]);
+    assert_eq!(
+        crate::notes::list(&txn, "b")?,
+        vec![crate::notes::ChangeNote {
+            change: h,
+            note: b"2".to_vec()
+        }]
+    );
+    assert!(crate::notes::del(&mut txn, &h, "b")?);
+    assert!(!crate::notes::del(&mut txn, &h, "b")?);
+    assert_eq!(crate::notes::get(&txn, &h, "b")?, None);
+
+    // Another repository concatenates the notes it receives.
+    let mut list = Vec::new();
+    crate::notes::write_list(&crate::notes::list(&txn, "a")?, &mut list)?;
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin()?;
+    crate::notes::put(&mut txn2, &h, "a", b"0")?;
+    let received = crate::notes::read_list(&list)?;
+    let concat = |current: Option<&[u8]>, received: &[u8]| {
+        let mut note = current.unwrap_or(&[]).to_vec();
+        note.extend_from_slice(received);
+        Some(note)
+    };
+    assert_eq!(crate::notes::import(&mut txn2, "a", &received, concat)?, 2);
+    assert_eq!(crate::notes::get(&txn2, &h, "a")?, Some(b"01".to_vec()));
+    assert_eq!(crate::notes::get(&txn2, &h2, "a")?, Some(b"3".to_vec()));
+    assert_eq!(
+        crate::notes::import(&mut txn2, "a", &received, |_, _| None)?,
+        0
+    );
+    Ok(())
+}
+
+/// Matching lines are credited to the change that introduced them,
+/// binary files are skipped, and the search can be restricted to some
+/// paths.
+#[test]
+fn grep_channel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"one\ntwo\nthree\n".to_vec());
+    repo.add_file("dir/b", b"twenty-two\n".to_vec());
+    repo.add_file("bin", b"two\0two\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("dir/b", 0)?;
+    txn.write().add_file("bin", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?
+        .write_all(b"one\ntwo\ntwo and a half\nthree\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let matches = grep(
+        &*txn,
+        &*channel,
+        &changes,
+        "two",
+        &[],
+        RetrieveLimits::UNLIMITED,
+    )?;
+    assert_eq!(
+        matches,
+        vec![
+            GrepMatch {
+                path: "a".to_string(),
+                line: 2,
+                contents: b"two".to_vec(),
+                changes: vec![h0],
+            },
+            GrepMatch {
+                path: "a".to_string(),
+                line: 3,
+                contents: b"two and a half".to_vec(),
+                changes: vec![h1],
+            },
+            GrepMatch {
+                path: "dir/b".to_string(),
+                line: 1,
+                contents: b"twenty-two".to_vec(),
+                changes: vec![h0],
+            },
+        ]
+    );
+
+    let matches = grep(
+        &*txn,
+        &*channel,
+        &changes,
+        "two",
+        &["dir"],
+        RetrieveLimits::UNLIMITED,
+    )?;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].path, "dir/b");
+
+    match grep(
+        &*txn,
+        &*channel,
+        &changes,
+        "two",
+        &["c"],
+        RetrieveLimits::UNLIMITED,
+    ) {
+        Err(SearchError::NotFound(p)) => assert_eq!(p, "c"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    Ok(())
+}
+
+/// Edit files without a working copy, as a bot updating license
+/// headers would.
+#[test]
+fn mechanical_edit() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a/file", b"fn main() {}\n// 2020\n".to_vec());
+    repo.add_file("b", b"x\ny\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("a/file", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let rec = apply_mechanical(
+        &changes,
+        &*txn.read(),
+        &*channel.read(),
+        vec![
+            (
+                "a/file".to_string(),
+                LineEdit::Replace {
+                    start: 1,
+                    end: 2,
+                    text: b"// 2021\n".to_vec(),
+                },
+            ),
+            (
+                "a/file".to_string(),
+                LineEdit::Insert {
+                    line: 0,
+                    text: b"// License\n".to_vec(),
+                },
+            ),
+            (
+                "b".to_string(),
+                LineEdit::Insert {
+                    line: 2,
+                    text: b"z\n".to_vec(),
+                },
+            ),
+        ],
+        RetrieveLimits::UNLIMITED,
+    )?;
+    assert!(rec.updatables.is_empty());
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let h = changes.save_change(&change)?;
+    txn.write()
+        .apply_change(&changes, &mut *channel.write(), &h)
+        .unwrap();
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+
+    let mut buf = Vec::new();
+    repo.read_file("a/file", &mut buf)?;
+    assert_eq!(
+        std::str::from_utf8(&buf),
+        Ok("// License\nfn main() {}\n// 2021\n")
+    );
+    buf.clear();
+    repo.read_file("b", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("x\ny\nz\n"));
+
+    // Overlapping edits and missing lines are rejected.
+    let overlapping = vec![
+        (
+            "b".to_string(),
+            LineEdit::Replace {
+                start: 0,
+                end: 2,
+                text: Vec::new(),
+            },
+        ),
+        (
+            "b".to_string(),
+            LineEdit::Insert {
+                line: 1,
+                text: b"w\n".to_vec(),
+            },
+        ),
+    ];
+    assert!(apply_mechanical(
+        &changes,
+        &*txn.read(),
+        &*channel.read(),
+        overlapping,
+        RetrieveLimits::UNLIMITED
+    )
+    .is_err());
+    let missing = vec![(
+        "b".to_string(),
+        LineEdit::Insert {
+            line: 4,
+            text: b"w\n".to_vec(),
+        },
+    )];
+    assert!(apply_mechanical(
+        &changes,
+        &*txn.read(),
+        &*channel.read(),
+        missing,
+        RetrieveLimits::UNLIMITED
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn compose_record_output_log() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env_a = pristine::sanakirja::Pristine::new_anon()?;
+    let env_b = pristine::sanakirja::Pristine::new_anon()?;
+    let mut composition = Composition::new();
+    for (env, prefix) in [(&env_a, "lib"), (&env_b, "app/web")].iter() {
+        let txn = env.arc_txn_begin().unwrap();
+        let channel = txn.write().open_or_create_channel("main")?;
+        composition.mount(Component {
+            prefix: prefix.to_string(),
+            txn,
+            channel,
+            changes: changes.clone(),
+        })?;
+    }
+    let env_c = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env_c.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    assert!(composition
+        .mount(Component {
+            prefix: "lib/sub".to_string(),
+            txn,
+            channel,
+            changes: changes.clone(),
+        })
+        .is_err());
+
+    repo.add_file("lib/a", b"a\n".to_vec());
+    repo.add_file("app/web/b", b"b\n".to_vec());
+    composition.add_file("lib/a", 0)?;
+    composition.add_file("app/web/b", 0)?;
+    assert!(composition.add_file("app/c", 0).is_err());
+    assert_eq!(composition.route("app/web/b"), Some((1, "b")));
+
+    let header = |message: &str| ChangeHeader {
+        message: message.to_string(),
+        ..ChangeHeader::default()
+    };
+    let recorded = composition.record(&repo, "", Algorithm::default(), &header("first"))?;
+    assert_eq!(recorded.len(), 2);
+    let hashes: Vec<_> = recorded.iter().map(|(_, h)| *h).collect();
+    {
+        let txn = composition.components()[0].txn.read();
+        assert!(crate::fs::find_inode(&*txn, "a").is_ok());
+        assert!(crate::fs::find_inode(&*txn, "b").is_err());
+    }
+
+    // Recording a single component.
+    repo.write_file("lib/a")?.write_all(b"a\na\n")?;
+    repo.write_file("app/web/b")?.write_all(b"b\nb\n")?;
+    let recorded = composition.record(&repo, "lib", Algorithm::default(), &header("second"))?;
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(recorded[0].0, 0);
+
+    assert!(composition.move_file("lib/a", "app/web/a", 0).is_err());
+
+    let log = composition.log()?;
+    assert_eq!(log.len(), 3);
+    assert_eq!(log[0].hash, recorded[0].1);
+    assert!(hashes.iter().all(|h| log.iter().any(|e| e.hash == *h)));
+
+    let repo2 = working_copy::memory::Memory::new();
+    composition.output(&repo2, "")?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, vec!["app", "app/web", "app/web/b", "lib", "lib/a"]);
+    let mut buf = Vec::new();
+    repo2.read_file("lib/a", &mut buf)?;
+    assert_eq!(buf, b"a\na\n");
+    Ok(())
+}
+
+/// A snapshot adds the tracked files like record, and remembers their
+/// contents as verified.
+#[test]
+fn import_snapshot() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let files = [
+        ("top", &b"top\n"[..]),
+        ("a/x", &b"x\n"[..]),
+        ("a/b/y", &b"y\ny\n"[..]),
+        ("a/b/c/z", &b""[..]),
+    ];
+    for (path, contents) in files.iter() {
+        repo.add_file(path, contents.to_vec());
+        txn.write().add_file(path, 0)?;
+    }
+
+    let options = SnapshotOptions {
+        threads: 3,
+        ..SnapshotOptions::default()
+    };
+    let (mut rec, report) = snapshot(&*txn.read(), &repo, "", &options)?;
+    assert!(report.skipped.is_empty());
+    // 3 directories and 4 files.
+    assert_eq!(rec.actions.len(), 7);
+
+    let mut txn_ = txn.write();
+    let hunks: Vec<_> = std::mem::take(&mut rec.actions)
+        .into_iter()
+        .map(|h| h.globalize(&*txn_).unwrap())
+        .collect();
+    let change = crate::change::Change::make_change(
+        &*txn_,
+        &channel,
+        hunks,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )?;
+    let hash = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn_, &channel, &change, &hash, &rec.updatables)?;
+    rec.save_file_stats(&mut *txn_, &*channel.read())?;
+    assert!(crate::file_stats::get(&*txn_, "a/b/y")?
+        .unwrap()
+        .verified
+        .is_some());
+    std::mem::drop(txn_);
+
+    // Nothing is left to record, or to import.
+    let (rec, _) = snapshot(&*txn.read(), &repo, "", &options)?;
+    assert!(rec.actions.is_empty());
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish()?.actions.is_empty());
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    for (path, contents) in files.iter() {
+        let mut buf = Vec::new();
+        repo2.read_file(path, &mut buf)?;
+        assert_eq!(&buf[..], *contents);
+    }
+    Ok(())
+}
+
+/// Links are followed in both directions, added only once, and
+/// survive a round trip through the exchange format.
+#[test]
+fn provenance() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin()?;
+    let h = |i: u8| Hash::Blake3([i; 32]);
+
+    // 0 is amended into 1, which is split into 2 and 3.
+    assert!(provenance::add(
+        &mut txn,
+        ProvenanceLink::new(h(0), h(1), ProvenanceOperation::Amend, "alice")
+    )?);
+    assert!(provenance::add(
+        &mut txn,
+        ProvenanceLink::new(h(1), h(2), ProvenanceOperation::Split, "bob")
+    )?);
+    assert!(provenance::add(
+        &mut txn,
+        ProvenanceLink::new(h(1), h(3), ProvenanceOperation::Split, "bob")
+    )?);
+    assert!(!provenance::add(
+        &mut txn,
+        ProvenanceLink::new(h(0), h(1), ProvenanceOperation::Amend, "carol")
+    )?);
+    assert!(matches!(
+        provenance::add(
+            &mut txn,
+            ProvenanceLink::new(h(4), h(4), ProvenanceOperation::Rewrite, "alice")
+        ),
+        Err(ProvenanceError::Cycle(_))
+    ));
+
+    assert_eq!(provenance::successors(&txn, &h(1))?.len(), 2);
+    assert_eq!(provenance::predecessors(&txn, &h(3))?[0].old, h(1));
+    let mut rewritten = provenance::latest(&txn, &h(0))?;
+    rewritten.sort();
+    assert_eq!(rewritten, vec![h(2), h(3)]);
+    assert_eq!(provenance::latest(&txn, &h(4))?, vec![h(4)]);
+    let ancestry: Vec<_> = provenance::ancestry(&txn, &h(3))?
+        .into_iter()
+        .map(|l| l.old)
+        .collect();
+    assert_eq!(ancestry, vec![h(0), h(1)]);
+
+    let mut list_ = Vec::new();
+    provenance::write_list(&provenance::list(&txn)?, &mut list_)?;
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin()?;
+    assert_eq!(
+        provenance::import(&mut txn2, provenance::read_list(&list_)?)?,
+        3
+    );
+    assert_eq!(
+        provenance::import(&mut txn2, provenance::read_list(&list_)?)?,
+        0
+    );
+    assert_eq!(provenance::list(&txn2)?, provenance::list(&txn)?);
+    Ok(())
+}
+
+/// Rewritten changes are unrecorded once their replacements are on
+/// the channel, unless other changes depend on them.
+#[test]
+fn migrate_rewritten() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+    use std::io::Write;
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let main = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("a", b"a\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    record_all(&repo, &changes, &txn, &main, "")?;
+    let other = txn.write().fork(&main, "other")?;
+
+    repo.add_file("b", b"x\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &main, "")?;
+    repo.write_file("b")?.write_all(b"x\nz\n")?;
+    let h3 = record_all(&repo, &changes, &txn, &main, "")?;
+    repo.write_file("b")?.write_all(b"y\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &other, "")?;
+
+    assert_eq!(
+        provenance::replace(
+            &mut *txn.write(),
+            h1,
+            &[h2],
+            ProvenanceOperation::Rewrite,
+            "alice"
+        )?,
+        1
+    );
+    assert_eq!(
+        provenance::migrations(&*txn.read(), &*main.read())?,
+        vec![Migration {
+            old: h1,
+            new: vec![h2]
+        }]
+    );
+    // The replacement isn't on the channel yet.
+    assert!(provenance::migrate(&mut *txn.write(), &changes, &main)?.is_empty());
+
+    txn.write()
+        .apply_change(&changes, &mut *main.write(), &h2)?;
+    // h3 depends on h1.
+    assert!(provenance::migrate(&mut *txn.write(), &changes, &main)?.is_empty());
+
+    txn.write().unrecord(&changes, &main, &h3, 0)?;
+    assert_eq!(
+        provenance::migrate(&mut *txn.write(), &changes, &main)?,
+        vec![h1]
+    );
+    assert!(txn.read().get_revchanges(&main, &h1)?.is_none());
+    assert!(txn.read().get_revchanges(&main, &h2)?.is_some());
+    assert!(provenance::migrations(&*txn.read(), &*main.read())?.is_empty());
+    Ok(())
+}
+
+/// Append entries to the audit log, and read them back.
+#[test]
+fn audit_log() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let h = {
+        let txn = env.arc_txn_begin().unwrap();
+        let channel = txn.write().open_or_create_channel("main").unwrap();
+        txn.write().add_file("file", 0)?;
+        let h = record_all(&repo, &changes, &txn, &channel, "")?;
+        let mut txn_ = txn.write();
+        assert_eq!(
+            append(
+                &mut *txn_,
+                "alice",
+                AuditOperation::Record { hash: h },
+                "main"
+            )?,
+            0
+        );
+        txn_.fork(&channel, "other")?;
+        assert_eq!(
+            append(
+                &mut *txn_,
+                "bob",
+                AuditOperation::ForkChannel {
+                    from: "main".to_string()
+                },
+                "other"
+            )?,
+            1
+        );
+        std::mem::drop(txn_);
+        txn.commit()?;
+        h
+    };
+
+    let txn = env.txn_begin()?;
+    assert_eq!(txn.audit_len()?, 2);
+    let state = txn.current_state(&*txn.load_channel("main")?.unwrap().read())?;
+    let entries: Vec<_> = iter(&txn, 0).map(|e| e.unwrap()).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].1.identity, "alice");
+    assert_eq!(entries[0].1.operation, AuditOperation::Record { hash: h });
+    assert_eq!(entries[0].1.state, state);
+    assert_eq!(entries[1].1.channel, "other");
+    assert_eq!(entries[1].1.state, state);
+    assert_eq!(iter(&txn, 1).count(), 1);
+
+    let mut json = Vec::new();
+    export_json(&txn, &mut json)?;
+    assert_eq!(json.iter().filter(|&&c| c == b'\n').count(), 2);
+    Ok(())
+}
+
+/// Entries appended with [`append_state`] while applying several
+/// changes record the state after each of them.
+#[test]
+fn audit_log_apply() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nb\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let other = txn.write().open_or_create_channel("other")?;
+    let mut states = Vec::new();
+    for h in [h0, h1].iter() {
+        let mut txn_ = txn.write();
+        txn_.apply_change(&changes, &mut *other.write(), h)?;
+        let state = txn_.current_state(&*other.read())?;
+        append_state(
+            &mut *txn_,
+            "alice",
+            AuditOperation::Apply { hash: *h },
+            "other",
+            state,
+        )?;
+        states.push(state)
+    }
+    assert_ne!(states[0], states[1]);
+
+    let txn = txn.read();
+    let entries: Vec<_> = iter(&*txn, 0).map(|e| e.unwrap().1.state).collect();
+    assert_eq!(entries, states);
+    Ok(())
+}
+
+/// Statuses are kept per name, the most recent one winning, and
+/// merge the same in any order.
+#[test]
+fn ci_status() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn = env.mut_txn_begin()?;
+    let h = Hash::Blake3([1; 32]);
+    assert_eq!(ci_status::summary(&txn, &h)?, None);
+
+    let pending = CiStatus::new("test", CiState::Pending, None);
+    let mut failed = CiStatus::new("test", CiState::Failure, Some("https://ci/1"));
+    failed.timestamp = pending.timestamp + chrono::Duration::seconds(10);
+    let build = CiStatus::new("build", CiState::Success, None);
+    assert!(ci_status::set(&mut txn, &h, failed.clone())?);
+    assert!(!ci_status::set(&mut txn, &h, pending.clone())?);
+    assert!(ci_status::set(&mut txn, &h, build.clone())?);
+    assert_eq!(
+        ci_status::get(&txn, &h)?,
+        vec![build.clone(), failed.clone()]
+    );
+    assert_eq!(ci_status::summary(&txn, &h)?, Some(CiState::Failure));
+
+    // A later success replaces the failure.
+    let mut fixed = CiStatus::new("test", CiState::Success, None);
+    fixed.timestamp = failed.timestamp + chrono::Duration::seconds(10);
+    assert!(ci_status::set(&mut txn, &h, fixed.clone())?);
+    assert_eq!(ci_status::summary(&txn, &h)?, Some(CiState::Success));
+
+    // Another repository receiving the statuses in another order.
+    let mut list_ = Vec::new();
+    ci_status::write_list(&ci_status::list(&txn)?, &mut list_)?;
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin()?;
+    ci_status::set(&mut txn2, &h, failed)?;
+    ci_status::set(&mut txn2, &h, pending)?;
+    ci_status::import(&mut txn2, ci_status::read_list(&list_)?)?;
+    assert_eq!(ci_status::get(&txn2, &h)?, vec![build, fixed]);
+    assert_eq!(
+        ci_status::import(&mut txn2, ci_status::read_list(&list_)?)?,
+        0
+    );
+    Ok(())
+}
+
+/// The same asset added twice, under two names and in two changes,
+/// is reported as duplicated.
+#[test]
+fn dedup() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let asset: Vec<u8> = (0..50_000).map(|_| rng.gen()).collect();
+    repo.add_file("asset.bin", asset.clone());
+    repo.add_file("small", b"a\nb\n".to_vec());
+    txn.write().add_file("asset.bin", 0)?;
+    txn.write().add_file("small", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("copy/asset.bin", asset);
+    txn.write().add_file("copy/asset.bin", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let index = index(&*txn.read(), &changes)?;
+    let stats = index.stats();
+    assert_eq!(stats.total_bytes, 100_004);
+    assert_eq!(stats.duplicated_bytes(), 50_000);
+
+    let duplicates = index.duplicates(0);
+    assert!(!duplicates.is_empty());
+    let wasted: u64 = duplicates.iter().map(|d| d.wasted()).sum();
+    assert_eq!(wasted, 50_000);
+    let mut occurrences = duplicates[0].occurrences.clone();
+    occurrences.sort();
+    let mut expected = vec![
+        Occurrence {
+            change: h0,
+            path: "asset.bin".to_string(),
+        },
+        Occurrence {
+            change: h1,
+            path: "copy/asset.bin".to_string(),
+        },
+    ];
+    expected.sort();
+    assert_eq!(occurrences, expected);
+    Ok(())
+}
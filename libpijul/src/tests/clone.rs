@@ -1,6 +1,16 @@
 use super::*;
+use crate::api::{Identity, Repository};
+use crate::channel::soft_delete;
+use crate::doctor::*;
+use crate::intent::*;
+use crate::maintenance::*;
+use crate::pool::*;
+use crate::quota::*;
+use crate::roots::*;
+use crate::state::*;
 use crate::working_copy::WorkingCopy;
 use std::io::Write;
+use std::sync::Arc;
 
 #[test]
 fn clone_simple() -> Result<(), anyhow::Error> {
@@ -129,3 +139,910 @@ fn clone_prefixes() -> Result<(), anyhow::Error> {
     txn2.open_or_create_channel("main2").unwrap();
     Ok(())
 }
+
+/// Apply a change and its dependency with limits on the resources.
+#[test]
+fn clone_limits() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    {
+        let mut txn2 = env2.mut_txn_begin().unwrap();
+        let channel2 = txn2.open_or_create_channel("main").unwrap();
+        let options = ApplyOptions {
+            max_pending_changes: Some(1),
+            ..ApplyOptions::default()
+        };
+        match txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h, &options) {
+            Err(ApplyError::ResourceExceeded {
+                resource: apply::Resource::PendingChanges,
+                limit: 1,
+            }) => {}
+            e => panic!("{:?}", e),
+        };
+    }
+    {
+        // The first change is refused before being loaded.
+        let mut txn2 = env2.mut_txn_begin().unwrap();
+        let channel2 = txn2.open_or_create_channel("main").unwrap();
+        let options = ApplyOptions {
+            max_memory: Some(1),
+            ..ApplyOptions::default()
+        };
+        match txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h, &options) {
+            Err(ApplyError::ResourceExceeded {
+                resource: apply::Resource::Memory,
+                limit: 1,
+            }) => {}
+            e => panic!("{:?}", e),
+        };
+        assert_eq!(txn2.log(&*channel2.read(), 0)?.count(), 0);
+    }
+
+    let mut txn2 = env2.mut_txn_begin().unwrap();
+    let channel2 = txn2.open_or_create_channel("main").unwrap();
+    let options = ApplyOptions {
+        max_pending_changes: Some(2),
+        max_memory: Some(1 << 20),
+        ..ApplyOptions::default()
+    };
+    txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h, &options)?;
+    assert_eq!(txn2.log(&*channel2.read(), 0)?.count(), 2);
+    Ok(())
+}
+
+/// Timestamps keep their offset, and can be checked when applying.
+#[test]
+fn clone_timestamps() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    let (_, c0) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    let (_, mut c1) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+
+    // A change dated before its dependency, in another timezone.
+    let offset = FixedOffset::east(2 * 3600);
+    c1.hashed.header.timestamp = c0.header.timestamp - Duration::hours(1);
+    c1.set_timezone(offset);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("c1");
+    let h = c1.serialize(std::fs::File::create(&path)?)?;
+    let mut c1_ = Change::deserialize(path.to_str().unwrap(), Some(&h))?;
+    assert_eq!(c1_.local_timestamp().offset(), &offset);
+    assert_eq!(c1_.local_timestamp(), c1.header.timestamp);
+    c1_.unhashed = None;
+    assert_eq!(c1_.serialize(&mut Vec::new())?, h);
+    let h1 = changes.save_change(&c1)?;
+
+    let mut future = c0.clone();
+    future.hashed.header.timestamp = Utc::now() + Duration::days(1);
+    let h_future = changes.save_change(&future)?;
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn2 = env2.mut_txn_begin().unwrap();
+    let channel2 = txn2.open_or_create_channel("main").unwrap();
+    let options = ApplyOptions {
+        timestamps: TimestampPolicy {
+            max_future: Some(std::time::Duration::from_secs(3600)),
+            monotonic: true,
+        },
+        ..ApplyOptions::default()
+    };
+    match txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h1, &options) {
+        Err(ApplyError::InvalidTimestamp {
+            hash,
+            reason: apply::TimestampViolation::NonMonotonic,
+        }) if hash == h1 => {}
+        e => panic!("{:?}", e),
+    }
+    match txn2.apply_change_rec_opt(&changes, &mut *channel2.write(), &h_future, &options) {
+        Err(ApplyError::InvalidTimestamp {
+            reason: apply::TimestampViolation::Future,
+            ..
+        }) => {}
+        e => panic!("{:?}", e),
+    }
+
+    // The default policy accepts all timestamps.
+    let env3 = pristine::sanakirja::Pristine::new_anon()?;
+    let mut txn3 = env3.mut_txn_begin().unwrap();
+    let channel3 = txn3.open_or_create_channel("main").unwrap();
+    txn3.apply_change_rec_opt(
+        &changes,
+        &mut *channel3.write(),
+        &h1,
+        &ApplyOptions::default(),
+    )?;
+    assert_eq!(txn3.log(&*channel3.read(), 0)?.count(), 2);
+    Ok(())
+}
+
+/// Record, clone, pull and output through the high-level interface.
+#[test]
+fn api_clone_pull() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let a = tempfile::tempdir()?;
+    let b = tempfile::tempdir()?;
+    let repo_a = Repository::init(a.path())?;
+    std::fs::create_dir_all(a.path().join("src"))?;
+    std::fs::write(a.path().join("src/main.rs"), b"fn main() {}\n")?;
+    repo_a.add("src")?;
+    let alice = Identity::new("alice");
+    let h0 = repo_a.record("main", "init", &alice)?.unwrap();
+    assert!(repo_a.record("main", "nothing", &alice)?.is_none());
+    let change = changestore::filesystem::FileSystem::from_root(a.path(), 1)
+        .get_change(&Hash::from_base32(h0.to_base32().as_bytes()).unwrap())?;
+    assert_eq!(change.hashed.header.authors[0].0["name"], "alice");
+    assert_eq!(repo_a.channels()?, vec!["main".to_string()]);
+
+    let repo_b = Repository::clone(&repo_a, "main", b.path().join("clone"))?;
+    assert_eq!(
+        std::fs::read(b.path().join("clone/src/main.rs"))?,
+        b"fn main() {}\n"
+    );
+    std::fs::write(
+        b.path().join("clone/src/main.rs"),
+        b"fn main() {\n    println!(\"hi\")\n}\n",
+    )?;
+    let h1 = repo_b.record("main", "hi", &Identity::new("bob"))?.unwrap();
+
+    assert_eq!(repo_a.pull("main", &repo_b, "main")?, vec![h1]);
+    let log: Vec<_> = repo_a.log("main")?.into_iter().map(|e| e.hash).collect();
+    assert_eq!(log, vec![h0, h1]);
+    assert!(repo_a.conflicts("main")?.is_empty());
+    assert!(repo_a.output("main")?.is_empty());
+    assert_eq!(
+        std::fs::read(a.path().join("src/main.rs"))?,
+        b"fn main() {\n    println!(\"hi\")\n}\n"
+    );
+    assert!(repo_b.push("main", &repo_a, "main")?.is_empty());
+    Ok(())
+}
+
+/// Soft-deleted channels can't be pushed to.
+#[test]
+fn api_push_deleted_channel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let a = tempfile::tempdir()?;
+    let b = tempfile::tempdir()?;
+    let repo_a = Repository::init(a.path())?;
+    std::fs::write(a.path().join("a"), b"a\n")?;
+    repo_a.add("a")?;
+    repo_a.record("main", "init", &Identity::new("alice"))?;
+    let repo_b = Repository::clone(&repo_a, "main", b.path())?;
+    std::fs::write(b.path().join("a"), b"a\nb\n")?;
+    let h = repo_b.record("main", "b", &Identity::new("bob"))?.unwrap();
+
+    std::mem::drop(repo_a);
+    {
+        let db = a
+            .path()
+            .join(crate::DOT_DIR)
+            .join(crate::repository::PRISTINE_DIR)
+            .join("db");
+        let env = pristine::sanakirja::Pristine::new(&db)?;
+        let mut txn = env.mut_txn_begin()?;
+        soft_delete(&mut txn, "main")?;
+        txn.commit()?;
+    }
+    let repo_a = Repository::open(a.path())?;
+    match repo_b.push("main", &repo_a, "main") {
+        Err(crate::api::ApiError::ChannelDeleted(c)) => assert_eq!(c, "main"),
+        r => panic!("{:?}", r),
+    }
+    assert!(repo_a.channels()?.is_empty());
+
+    // The channel can't be written to through the rest of the API
+    // either.
+    match repo_a.apply("main", &h) {
+        Err(crate::api::ApiError::ChannelDeleted(c)) => assert_eq!(c, "main"),
+        r => panic!("{:?}", r),
+    }
+    Ok(())
+}
+
+/// Diff the working copy and read changes through the high-level
+/// interface.
+#[test]
+fn api_diff() -> Result<(), anyhow::Error> {
+    use crate::api::{FileDiff, LineChange, LineChangeKind};
+    env_logger::try_init().unwrap_or(());
+
+    let dir = tempfile::tempdir()?;
+    let repo = Repository::init(dir.path())?;
+    std::fs::write(dir.path().join("a"), b"a\nb\nc\n")?;
+    std::fs::write(dir.path().join("b"), b"d\n")?;
+    repo.add("a")?;
+    repo.add("b")?;
+    let h = repo
+        .record("main", "init", &Identity::new("alice"))?
+        .unwrap();
+    assert!(repo.diff("main")?.is_empty());
+    let change = repo.change(&h)?;
+    assert_eq!(change.message, "init");
+    assert!(change.dependencies.is_empty());
+    assert_eq!(repo.state("main")?, repo.log("main")?[0].state);
+
+    std::fs::write(dir.path().join("a"), b"a\nx\nc\n")?;
+    std::fs::remove_file(dir.path().join("b"))?;
+    let state = repo.state("main")?;
+    assert_eq!(
+        repo.diff("main")?,
+        vec![
+            FileDiff {
+                path: "a".to_string(),
+                deleted: false,
+                lines: vec![LineChange {
+                    kind: LineChangeKind::Modified,
+                    old: 1..2,
+                    new: 1..2,
+                }],
+            },
+            FileDiff {
+                path: "b".to_string(),
+                deleted: true,
+                lines: Vec::new(),
+            },
+        ]
+    );
+    assert_eq!(repo.state("main")?, state);
+    Ok(())
+}
+
+/// Changes shared by several channels are split between them.
+#[test]
+fn quota() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("a", b"a\nb\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    txn.write().fork(&channel, "other")?;
+    repo.write_file("a")?.write_all(b"a\nx\nb\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let s0 = changes.get_size(&h0)?;
+    let s1 = changes.get_size(&h1)?;
+    let u = usage(&*txn.read(), &changes)?;
+    assert_eq!(u.total, s0 + s1);
+    let main = &u.channels["main"];
+    assert_eq!((main.changes, main.total, main.exclusive), (2, s0 + s1, s1));
+    assert_eq!(main.attributed, s0 as f64 / 2. + s1 as f64);
+    let other = &u.channels["other"];
+    assert_eq!((other.changes, other.total, other.exclusive), (1, s0, 0));
+    assert_eq!(other.attributed, s0 as f64 / 2.);
+
+    let c = change_usage(&changes, &h1)?;
+    assert_eq!(c.size, s1);
+    assert_eq!(c.with_dependencies, s0 + s1);
+
+    // A change recorded in another repository, sharing the same store.
+    let repo2 = working_copy::memory::Memory::new();
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    repo2.add_file("b", b"b\n".to_vec());
+    txn2.write().add_file("b", 0)?;
+    let h2 = record_all(&repo2, &changes, &txn2, &channel2, "")?;
+    let s2 = changes.get_size(&h2)?;
+
+    let quota = s0 + s1 + s2;
+    assert_eq!(check_quota(&*txn.read(), &changes, &[h1], &quota)?, 0);
+    assert_eq!(check_quota(&*txn.read(), &changes, &[h2], &quota)?, s2);
+    match check_quota(&*txn.read(), &changes, &[h2], &(quota - 1)) {
+        Err(QuotaError::Exceeded { used, additional }) if used == s0 + s1 && additional == s2 => {}
+        e => panic!("{:?}", e),
+    }
+    Ok(())
+}
+
+/// Repositories are opened on demand, the least recently used one is
+/// closed when the pool is full, and repositories in use are never
+/// closed.
+#[test]
+fn pool_eviction() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let a = tempfile::tempdir()?;
+    let b = tempfile::tempdir()?;
+    for r in &[&a, &b] {
+        std::fs::create_dir_all(r.path().join(DOT_DIR).join("pristine"))?;
+    }
+    let pool = Arc::new(Pool::new(PoolConfig {
+        capacity: 1,
+        max_readers: Some(1),
+        ..PoolConfig::default()
+    }));
+    match pool.read(a.path().join("missing")) {
+        Err(PoolError::NotFound(_)) => {}
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("opened a missing repository"),
+    }
+
+    let w = pool.write(a.path())?;
+    w.txn().write().open_or_create_channel("main")?;
+    w.commit()?;
+
+    // Opening `b` closes `a`, which isn't in use.
+    let rb = pool.read(b.path())?;
+    assert!(rb.txn().load_channel("main")?.is_none());
+    assert_eq!(pool.metrics().open, 1);
+    assert_eq!(pool.metrics().evictions, 1);
+
+    // `b` is in use, so the pool grows.
+    let ra = pool.read(a.path())?;
+    assert!(ra.txn().load_channel("main")?.is_some());
+    assert_eq!(pool.metrics().open, 2);
+    assert!(!pool.close(a.path()));
+
+    // At most one reader per repository.
+    let t = {
+        let pool = pool.clone();
+        let path = a.path().to_path_buf();
+        std::thread::spawn(move || pool.read(path).map(|_| ()))
+    };
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    std::mem::drop(ra);
+    t.join().unwrap()?;
+    std::mem::drop(rb);
+
+    let m = pool.metrics();
+    assert_eq!(m.waits, 1);
+    assert_eq!(m.reads, 3);
+    assert_eq!(m.writes, 1);
+    assert_eq!(m.hits, 1);
+    assert!(pool.close(a.path()));
+    assert_eq!(pool.metrics().open, 1);
+    Ok(())
+}
+
+#[test]
+fn topo_layers() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("a", b"a\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let ha = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("b", b"b\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    let hb = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\na\n")?;
+    let ha2 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\na\na\n")?;
+    let ha3 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut first = vec![ha, hb];
+    first.sort();
+    let layers = crate::deps::topo_layers(&*txn.read(), &*channel.read())?;
+    assert_eq!(layers, vec![first, vec![ha2], vec![ha3]]);
+    Ok(())
+}
+
+#[test]
+fn explain_dependency() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("a", b"a\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let ha = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.add_file("b", b"b\n".to_vec());
+    txn.write().add_file("b", 0)?;
+    let hb = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\na\n")?;
+    let ha2 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\na\na\n")?;
+    let ha3 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    use crate::change::{explain_dependency, DependencyReason};
+    let txn = txn.read();
+    let e = explain_dependency(&*txn, &changes, &ha3, &ha2)?;
+    assert!(e.via.is_empty());
+    assert!(e
+        .causes
+        .iter()
+        .any(|c| c.path == "a" && c.reason == DependencyReason::UpContext));
+
+    // Either direct, or inherited from ha2.
+    let e = explain_dependency(&*txn, &changes, &ha3, &ha)?;
+    assert!(e.via.is_empty() || e.via == vec![ha2]);
+    assert!(!e.causes.is_empty());
+
+    assert!(explain_dependency(&*txn, &changes, &ha3, &hb).is_err());
+    Ok(())
+}
+
+#[derive(Default)]
+struct Collect(Vec<MaintenanceRequest>);
+
+impl MaintenanceScheduler for Collect {
+    fn schedule(&mut self, request: &MaintenanceRequest) -> Schedule {
+        self.0.push(request.clone());
+        Schedule::Deferred
+    }
+}
+
+/// Trigger maintenance after two operations, first inline, then
+/// deferred to another scheduler.
+#[test]
+fn auto_maintenance() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let policy = MaintenancePolicy {
+        every: Some(2),
+        ..MaintenancePolicy::default()
+    };
+    let mut maintenance = AutoMaintenance::new(policy);
+    assert!(maintenance
+        .after_apply(&mut *txn.write(), &channel, 1)?
+        .is_none());
+    let report = maintenance.after_unrecord(&mut *txn.write(), &channel, 1)?;
+    debug!("{:?}", report);
+    assert!(report.unwrap().prune.files >= 1);
+    // The count starts over.
+    assert!(maintenance
+        .after_apply(&mut *txn.write(), &channel, 1)?
+        .is_none());
+
+    let mut maintenance = AutoMaintenance::with_scheduler(policy, Collect::default());
+    assert!(maintenance
+        .after_apply(&mut *txn.write(), &channel, 3)?
+        .is_none());
+    assert_eq!(maintenance.scheduler.0.len(), 1);
+    let request = &maintenance.scheduler.0[0];
+    assert_eq!(request.channel, "main");
+    assert_eq!(request.trigger, Trigger::Operations(3));
+    let report = run(&mut *txn.write(), request)?;
+    assert_eq!(report.prune.removed, 0);
+
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nx\nd\n"));
+    Ok(())
+}
+
+/// Diagnose a healthy repository, then remove a file from the
+/// working copy and a change from the change store.
+#[test]
+fn doctor_report() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\n".to_vec());
+    repo.add_file("other", b"c\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    txn.write().add_file("other", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let options = DoctorOptions::default();
+    let report = diagnose(&*txn.read(), &changes, &repo, &channel, &options)?;
+    debug!("{:?}", report);
+    assert!(report.is_healthy());
+    assert_eq!(report.checks.len(), 6);
+
+    repo.remove_path("other", false)?;
+    changes.del_change(&h)?;
+    let report = diagnose(&*txn.read(), &changes, &repo, &channel, &options)?;
+    debug!("{:?}", report);
+    assert_eq!(report.worst(), Some(Severity::Error));
+    assert!(report
+        .findings
+        .iter()
+        .any(|f| f.check == Check::Changestore && f.channel.as_deref() == Some("main")));
+    assert!(report
+        .findings
+        .iter()
+        .any(|f| f.check == Check::WorkingCopy && f.path.as_deref() == Some("other")));
+    assert!(report.findings.iter().all(|f| f.fix.is_some()));
+
+    let mut json = Vec::new();
+    report.to_json(&mut json)?;
+    let report_: Report = serde_json::from_slice(&json)?;
+    assert_eq!(report, report_);
+    Ok(())
+}
+
+/// Export a manifest from a channel and restore it into another one.
+#[test]
+fn manifest_restore() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut m = manifest(&*txn.read(), &*channel.read())?;
+    assert_eq!(m.channel, "main");
+    assert_eq!(m.changes.len(), 2);
+    assert_eq!(m.state, txn.read().current_state(&*channel.read())?);
+
+    let key = crate::key::SKey::generate(None);
+    m.sign(&key)?;
+    assert!(m.verify()?);
+
+    let pinned = restore(&mut *txn.write(), &changes, &m, "pinned")?;
+    assert_eq!(txn.read().current_state(&*pinned.read())?, m.state);
+    assert!(matches!(
+        restore(&mut *txn.write(), &changes, &m, "pinned"),
+        Err(StateError::ChannelExists(_))
+    ));
+
+    let mut with_none = Manifest {
+        channel: m.channel.clone(),
+        state: m.state.clone(),
+        changes: m.changes.clone(),
+        signature: None,
+    };
+    with_none.changes.push(Hash::None);
+    with_none.sign(&key)?;
+    assert!(!with_none.verify()?);
+
+    m.changes.pop();
+    assert!(!m.verify()?);
+    assert!(matches!(
+        restore(&mut *txn.write(), &changes, &m, "other"),
+        Err(StateError::InvalidManifest(_))
+    ));
+    Ok(())
+}
+
+/// The state of a channel at a date, with timestamps raised to the
+/// ones of their dependencies.
+#[test]
+fn state_at_date() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    txn.write().add_file("file", 0)?;
+    let (h0, c0) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    let (_, c1) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let t0 = c0.header.timestamp.with_timezone(&Utc);
+
+    let mut future = c1.clone();
+    future.hashed.header.timestamp = (t0 + Duration::days(10)).into();
+    let h_future = changes.save_change(&future)?;
+    let mut past = c1.clone();
+    past.hashed.header.timestamp = (t0 - Duration::days(10)).into();
+    let h_past = changes.save_change(&past)?;
+
+    let mut txn = txn.write();
+    let a = txn.open_or_create_channel("a")?;
+    txn.apply_change(&changes, &mut *a.write(), &h0)?;
+    txn.apply_change(&changes, &mut *a.write(), &h_future)?;
+    let b = txn.open_or_create_channel("b")?;
+    txn.apply_change(&changes, &mut *b.write(), &h0)?;
+    txn.apply_change(&changes, &mut *b.write(), &h_past)?;
+
+    let m = state_at(&*txn, &changes, &*a.read(), t0 + Duration::days(1))?;
+    assert_eq!(m.changes, vec![h0]);
+    let scratch = restore(&mut *txn, &changes, &m, "scratch")?;
+    assert_eq!(txn.current_state(&*scratch.read())?, m.state);
+    let m = state_at(&*txn, &changes, &*a.read(), t0 + Duration::days(11))?;
+    assert_eq!(m.changes, vec![h0, h_future]);
+    assert_eq!(m.state, txn.current_state(&*a.read())?);
+
+    // A change dated before its dependency is only in the states
+    // where its dependency is.
+    let m = state_at(&*txn, &changes, &*b.read(), t0 - Duration::days(1))?;
+    assert!(m.changes.is_empty());
+    let m = state_at(&*txn, &changes, &*b.read(), t0)?;
+    assert_eq!(m.changes, vec![h0, h_past]);
+    Ok(())
+}
+
+/// Two roots in the same channel, recorded, output and logged
+/// independently.
+#[test]
+fn roots() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("code/main.rs", b"a\n".to_vec());
+    repo.add_file("docs-site/index.md", b"b\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main")?;
+
+    let code = add(&mut *txn.write(), &*channel.read(), "code", "code/")?;
+    let docs = add(&mut *txn.write(), &*channel.read(), "docs", "docs-site")?;
+    assert_eq!(code.directory, "code");
+    match add(&mut *txn.write(), &*channel.read(), "sub", "code/sub") {
+        Err(RootError::Overlap { other, .. }) => assert_eq!(other, "code"),
+        r => panic!("{:?}", r),
+    }
+    match add(&mut *txn.write(), &*channel.read(), "code", "other") {
+        Err(RootError::AlreadyExists(_)) => {}
+        r => panic!("{:?}", r),
+    }
+    match add(&mut *txn.write(), &*channel.read(), "up", "../up") {
+        Err(RootError::InvalidDirectory(_)) => {}
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(load(&*txn.read(), &*channel.read())?.len(), 2);
+    let other = txn.write().open_or_create_channel("other")?;
+    assert!(load(&*txn.read(), &*other.read())?.is_empty());
+    assert_eq!(get(&*txn.read(), &*channel.read(), "docs")?, docs);
+    let (root, rel) = find(&*txn.read(), &*channel.read(), "docs-site/index.md")?.unwrap();
+    assert_eq!(root, docs);
+    assert_eq!(rel, "index.md");
+    assert!(find(&*txn.read(), &*channel.read(), "docs-site2/index.md")?.is_none());
+
+    code.add_file(&mut *txn.write(), "main.rs", 0)?;
+    docs.add_file(&mut *txn.write(), "index.md", 0)?;
+    let h_code = record_all(&repo, &changes, &txn, &channel, &code.path(""))?;
+    let h_docs = record_all(&repo, &changes, &txn, &channel, &docs.path(""))?;
+    assert_eq!(
+        code.log(&*txn.read(), &changes, &*channel.read())?,
+        &[h_code]
+    );
+    assert_eq!(
+        docs.log(&*txn.read(), &changes, &*channel.read())?,
+        &[h_docs]
+    );
+
+    // Recording a root ignores the other roots.
+    repo.write_file("code/main.rs")?.write_all(b"a\nb\n")?;
+    repo.write_file("docs-site/index.md")?
+        .write_all(b"b\nc\n")?;
+    let mut builder = record::Builder::new();
+    code.record(
+        &mut builder,
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = builder.finish()?;
+    assert_eq!(rec.actions.len(), 1);
+
+    let repo2 = working_copy::memory::Memory::new();
+    docs.output(&repo2, &changes, &txn, &channel, "", true, 1)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, &["docs-site", "docs-site/index.md"]);
+
+    // Roots are kept when the channel is renamed.
+    txn.write().rename_channel(&mut channel, "renamed")?;
+    assert_eq!(get(&*txn.read(), &*channel.read(), "docs")?, docs);
+
+    assert!(remove(&mut *txn.write(), &*channel.read(), "docs")?);
+    assert!(!remove(&mut *txn.write(), &*channel.read(), "docs")?);
+    match get(&*txn.read(), &*channel.read(), "docs") {
+        Err(RootError::NotFound(_)) => {}
+        r => panic!("{:?}", r),
+    }
+    Ok(())
+}
+
+/// Operations on a channel exclude each other, and changes made to a
+/// channel behind the back of an operation are detected.
+#[test]
+fn intents() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().open_or_create_channel("other")?;
+    txn.write().add_file("a", 0)?;
+
+    let intents = Intents::new();
+    let mut record = intents.begin(&*txn.read(), &["main"], Intent::Record, None)?;
+    assert_eq!(intents.holder("main"), Some(Intent::Record));
+    match intents.begin(&*txn.read(), &["other", "main"], Intent::Apply, None) {
+        Err(IntentError::Busy { channel, intent }) => {
+            assert_eq!(channel, "main");
+            assert_eq!(intent, Intent::Record);
+        }
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("main taken twice"),
+    }
+    match intents.begin(
+        &*txn.read(),
+        &["main"],
+        Intent::Apply,
+        Some(std::time::Duration::from_millis(10)),
+    ) {
+        Err(e @ IntentError::Timeout { .. }) => assert!(e.is_retryable()),
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("main taken twice"),
+    }
+    // Failing to take "main" didn't take "other".
+    assert_eq!(intents.holder("other"), None);
+    match intents.begin(&*txn.read(), &["missing"], Intent::Apply, None) {
+        Err(IntentError::ChannelNotFound(c)) => assert_eq!(c, "missing"),
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("missing channel taken"),
+    }
+
+    record.check(&*txn.read())?;
+    // Modify the channel without going through `intents`.
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    match record.check(&*txn.read()) {
+        Err(e @ IntentError::Moved { .. }) => assert!(e.is_retryable()),
+        r => panic!("{:?}", r),
+    }
+    let state = txn.read().current_state(&*channel.read())?;
+    record.advance("main", state);
+    record.check(&*txn.read())?;
+
+    // Waiting operations start when the channel is released.
+    let t = {
+        let intents = intents.clone();
+        let txn = txn.clone();
+        std::thread::spawn(move || {
+            intents
+                .begin(
+                    &*txn.read(),
+                    &["main"],
+                    Intent::Apply,
+                    Some(std::time::Duration::from_secs(10)),
+                )
+                .map(|g| g.intent())
+                .map_err(|e| e.to_string())
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    std::mem::drop(record);
+    assert_eq!(t.join().unwrap(), Ok(Intent::Apply));
+    assert_eq!(intents.holder("main"), None);
+    Ok(())
+}
+
+/// Recording and applying take the channel in the registry they are
+/// given, and recorded changes aren't applied to a channel modified
+/// since the recording started.
+#[test]
+fn intents_record_apply() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main")?;
+    let other = txn.write().open_or_create_channel("other")?;
+    txn.write().add_file("a", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let intents = Intents::new();
+    let options = ApplyOptions {
+        intents: Some(intents.clone()),
+        ..ApplyOptions::default()
+    };
+    repo.write_file("a")?.write_all(b"a\nb\n")?;
+    let mut builder = crate::record::Builder::new();
+    builder.intents = Some(intents.clone());
+    builder.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = builder.finish()?;
+    assert_eq!(intents.holder("main"), Some(Intent::Record));
+
+    // Other channels can still be applied to.
+    crate::apply::apply_change_rec_opt(
+        &changes,
+        &mut *txn.write(),
+        &mut *other.write(),
+        &h,
+        &mut crate::apply::Workspace::new(),
+        false,
+        &options,
+    )?;
+    assert_eq!(intents.holder("other"), None);
+    match crate::apply::apply_change_rec_opt(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel.write(),
+        &h,
+        &mut crate::apply::Workspace::new(),
+        false,
+        &options,
+    ) {
+        Err(ApplyError::LocalChange {
+            err: LocalApplyError::Intent(IntentError::Busy { intent, .. }),
+        }) => assert_eq!(intent, Intent::Record),
+        r => panic!("{:?}", r),
+    }
+
+    // Unrecording without the registry moves the channel.
+    txn.write().unrecord(&changes, &channel, &h, 0)?;
+    match txn.write().apply_recorded(&mut channel, rec, &changes) {
+        Err(ApplyError::LocalChange {
+            err: LocalApplyError::Intent(e @ IntentError::Moved { .. }),
+        }) => assert!(e.is_retryable()),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(intents.holder("main"), None);
+    Ok(())
+}
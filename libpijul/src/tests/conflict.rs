@@ -1,4 +1,9 @@
 use super::*;
+use crate::diff::markers::{self, MarkerKind};
+use crate::merge::{merge_binary_conflicts, BinaryConflict};
+use crate::output::{Conflict, ConflictId, ConflictOptions};
+use crate::resolution::*;
+use crate::vertex_buffer::{marker_id, END_MARKER, SEPARATOR, START_MARKER};
 use std::io::Write;
 
 #[test]
@@ -700,6 +705,68 @@ fn edit_conflict_sides() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// A side missing its final newline is matched against the new
+/// version even if the markers of the new version can't be parsed.
+#[test]
+fn unbalanced_markers_missing_eol() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel_alice = txn.write().open_or_create_channel("alice")?;
+    txn.write().add_file("file", 0)?;
+    let init_h = record_all(&repo, &changes, &txn, &channel_alice, "")?;
+    let channel_bob = txn.write().open_or_create_channel("bob")?;
+    apply::apply_change_arc(&changes, &txn, &channel_bob, &init_h)?;
+
+    repo.write_file("file")?.write_all(b"a\nx\n")?;
+    record_all(&repo, &changes, &txn, &channel_alice, "")?;
+    repo.write_file("file")?.write_all(b"a\ny")?;
+    let bob_h = record_all(&repo, &changes, &txn, &channel_bob, "")?;
+    apply::apply_change_arc(&changes, &txn, &channel_alice, &bob_h)?;
+    output::output_repository_no_pending(
+        &repo,
+        &changes,
+        &txn,
+        &channel_alice,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    debug!("{:?}", std::str::from_utf8(&buf));
+
+    // Delete the start marker, leaving markers that don't parse.
+    let edited: Vec<u8> = buf
+        .split_inclusive(|&c| c == b'\n')
+        .filter(|l| !l.starts_with(b">>>"))
+        .flatten()
+        .copied()
+        .collect();
+    assert!(markers::parse(&edited).is_err());
+    repo.write_file("file")?.write_all(&edited)?;
+    // Markers aren't in the graph, so there is nothing to record.
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel_alice.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish()?.actions.is_empty());
+    Ok(())
+}
+
 #[test]
 fn edit_after_conflict() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -1188,7 +1255,7 @@ fn zombie_last_line() -> Result<(), anyhow::Error> {
                 1,
             )
             .unwrap();
-        let rec = state.finish();
+        let rec = state.finish()?;
         assert!(rec.actions.is_empty())
     }
 
@@ -2634,3 +2701,601 @@ fn check_tree_inodes<T: TxnT>(txn: &T, channel: &T::Channel) {
         }
     }
 }
+
+/// With [`output::ConflictOrder::Timestamp`], conflicts are rendered
+/// identically in channels where the changes were applied in
+/// different orders.
+#[test]
+fn conflict_order_deterministic() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let repo_bob = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel_alice = txn.write().open_or_create_channel("alice")?;
+    let channel_bob = txn.write().open_or_create_channel("bob")?;
+    txn.write().add_file("file", 0)?;
+    let init_h = record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_bob.write(),
+        &init_h,
+    )?;
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+
+    repo_alice.write_file("file")?.write_all(b"a\nx\nb\n")?;
+    let alice_h = record_all(&repo_alice, &changes, &txn, &channel_alice, "")?;
+    repo_bob.write_file("file")?.write_all(b"a\ny\nb\n")?;
+    let bob_h = record_all(&repo_bob, &changes, &txn, &channel_bob, "")?;
+
+    // Each applies the other's change, so the changes are in a
+    // different order in both channels.
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_alice.write(),
+        &bob_h,
+    )?;
+    apply::apply_change(
+        &changes,
+        &mut *txn.write(),
+        &mut *channel_bob.write(),
+        &alice_h,
+    )?;
+
+    let inode = {
+        let txn = txn.read();
+        let inode = crate::fs::find_inode(&*txn, "file")?;
+        *txn.get_inodes(&inode, None)?.unwrap()
+    };
+    let render = |channel: &ChannelRef<_>, order| -> Result<Vec<u8>, anyhow::Error> {
+        let txn = txn.read();
+        let channel = channel.read();
+        let mut graph = crate::alive::retrieve(&*txn, txn.graph(&*channel), inode)?;
+        let mut w = crate::vertex_buffer::Writer::new(Vec::new());
+        crate::alive::output_graph_ordered(
+            &changes,
+            &*txn,
+            &*channel,
+            &mut w,
+            &mut graph,
+            &mut Vec::new(),
+            order,
+        )?;
+        Ok(w.into_inner())
+    };
+    let lines = |b: &[u8]| -> Vec<String> {
+        std::str::from_utf8(b)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    };
+
+    let alice = render(&channel_alice, output::ConflictOrder::Timestamp)?;
+    let bob = render(&channel_bob, output::ConflictOrder::Timestamp)?;
+    assert_eq!(std::str::from_utf8(&alice), std::str::from_utf8(&bob));
+    // Alice's change is the oldest.
+    let alice = lines(&alice);
+    assert_eq!(alice[2], "x");
+    assert_eq!(alice[4], "y");
+
+    // By default, each channel shows its own change first.
+    let alice = lines(&render(&channel_alice, output::ConflictOrder::Application)?);
+    let bob = lines(&render(&channel_bob, output::ConflictOrder::Application)?);
+    assert_eq!((alice[2].as_str(), alice[4].as_str()), ("x", "y"));
+    assert_eq!((bob[2].as_str(), bob[4].as_str()), ("y", "x"));
+    Ok(())
+}
+
+/// Two repositories applying the same changes in different orders
+/// find the same conflict identifiers, and markers carrying these
+/// identifiers are parsed like plain markers.
+#[test]
+fn conflict_id() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let repo_bob = working_copy::memory::Memory::new();
+    let options = ConflictOptions {
+        ids: true,
+        ..ConflictOptions::default()
+    };
+    repo_alice.set_conflict_options(options);
+    repo_bob.set_conflict_options(options);
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel_alice = txn_alice.write().open_or_create_channel("main")?;
+    let channel_bob = txn_bob.write().open_or_create_channel("main")?;
+    txn_alice.write().add_file("file", 0)?;
+    let init_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+    apply::apply_change_arc(&changes, &txn_bob, &channel_bob, &init_h)?;
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn_bob,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+
+    repo_alice.write_file("file")?.write_all(b"a\nx\nb\n")?;
+    let alice_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+    repo_bob.write_file("file")?.write_all(b"a\ny\nb\n")?;
+    let bob_h = record_all(&repo_bob, &changes, &txn_bob, &channel_bob, "")?;
+
+    apply::apply_change_arc(&changes, &txn_alice, &channel_alice, &bob_h)?;
+    apply::apply_change_arc(&changes, &txn_bob, &channel_bob, &alice_h)?;
+    let conflicts_alice = output::output_repository_no_pending(
+        &repo_alice,
+        &changes,
+        &txn_alice,
+        &channel_alice,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    let conflicts_bob = output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn_bob,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    assert_eq!(conflicts_alice.len(), 1);
+    assert_eq!(conflicts_bob.len(), 1);
+    let start_id = |repo: &working_copy::memory::Memory| -> Result<_, anyhow::Error> {
+        let mut file = Vec::new();
+        repo.read_file("file", &mut file)?;
+        let start = file
+            .split_inclusive(|&c| c == b'\n')
+            .find(|l| l.starts_with(b">>>"))
+            .unwrap();
+        Ok(marker_id(start))
+    };
+    let id = start_id(&repo_alice)?.unwrap();
+    assert_eq!(start_id(&repo_bob)?, Some(id));
+
+    // The identifiers don't show up as edits.
+    let mut state = Builder::new();
+    state.record(
+        txn_alice.clone(),
+        Algorithm::default(),
+        channel_alice.clone(),
+        &repo_alice,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish()?.actions.is_empty());
+
+    // The identifier is written and parsed in hexadecimal.
+    let id_str = id.to_string();
+    assert_eq!(id_str.len(), 16);
+    assert_eq!(id_str.parse::<ConflictId>()?, id);
+
+    let start = format!("{} {}\n", START_MARKER.trim_end(), id);
+    assert_eq!(marker_id(start.trim_start().as_bytes()), Some(id));
+    assert_eq!(marker_id(START_MARKER.trim_start().as_bytes()), None);
+    let f = format!("a{}x{}y{}b\n", start, SEPARATOR, END_MARKER);
+    let m = markers::parse(f.as_bytes()).unwrap();
+    let kinds: Vec<_> = m.markers.iter().map(|(_, m)| m.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![MarkerKind::Begin, MarkerKind::Next, MarkerKind::End]
+    );
+    Ok(())
+}
+
+/// Start a session, resolve its conflicts one by one, and check that
+/// it can only be finished once they have all been visited, and that
+/// it becomes stale when the channel changes.
+#[test]
+fn resolution_session() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    assert!(load(&*txn.read(), "main")?.is_none());
+    assert!(finish(&mut *txn.write(), "main").is_ok());
+
+    let conflicts = vec![
+        Conflict::Order {
+            path: "file".to_string(),
+            line: 2,
+        },
+        Conflict::Zombie {
+            path: "file".to_string(),
+            line: 5,
+        },
+    ];
+    let session = start(&mut *txn.write(), "main", conflicts.clone())?;
+    assert_eq!(session.remaining().count(), 2);
+    assert!(matches!(
+        start(&mut *txn.write(), "other", conflicts),
+        Err(ResolutionError::ChannelNotFound(_))
+    ));
+
+    resolve(&mut *txn.write(), "main", 0, Some(1))?;
+    assert!(matches!(
+        visit(&mut *txn.write(), "main", 2),
+        Err(ResolutionError::NoSuchConflict(2))
+    ));
+    assert!(matches!(
+        finish(&mut *txn.write(), "main"),
+        Err(ResolutionError::Incomplete { remaining: 1 })
+    ));
+
+    // The session survives the failed attempt.
+    let session = load(&*txn.read(), "main")?.unwrap();
+    assert_eq!(
+        session.conflicts[0].status,
+        ConflictStatus::Resolved { side: Some(1) }
+    );
+    assert_eq!(session.conflicts[1].status, ConflictStatus::Pending);
+
+    visit(&mut *txn.write(), "main", 1)?;
+    // Visiting a resolved conflict doesn't change its status.
+    let session = visit(&mut *txn.write(), "main", 0)?;
+    assert!(session.is_complete());
+    assert_eq!(
+        session.conflicts[0].status,
+        ConflictStatus::Resolved { side: Some(1) }
+    );
+    finish(&mut *txn.write(), "main")?;
+    assert!(load(&*txn.read(), "main")?.is_none());
+
+    // Sessions become stale when the channel changes.
+    let session = start(
+        &mut *txn.write(),
+        "main",
+        session.conflicts[..1]
+            .iter()
+            .map(|e| e.conflict.clone())
+            .collect(),
+    )?;
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    assert!(matches!(
+        visit(&mut *txn.write(), "main", 0),
+        Err(ResolutionError::Stale)
+    ));
+    assert_eq!(load(&*txn.read(), "main")?, Some(session));
+    finish(&mut *txn.write(), "main")?;
+    assert!(load(&*txn.read(), "main")?.is_none());
+    assert!(!abandon(&mut *txn.write(), "main")?);
+
+    // Sessions follow their channel when it is renamed.
+    let session = start(&mut *txn.write(), "main", Vec::new())?;
+    txn.write().rename_channel(&mut channel, "renamed")?;
+    assert_eq!(load(&*txn.read(), "renamed")?, Some(session));
+    assert!(load(&*txn.read(), "main")?.is_none());
+    txn.write().open_or_create_channel("main")?;
+    assert!(load(&*txn.read(), "main")?.is_none());
+    assert!(abandon(&mut *txn.write(), "renamed")?);
+    Ok(())
+}
+
+/// A binary merger receives both sides of a conflict and their
+/// ancestor, and its result is recorded as the resolution.
+#[test]
+fn merge_binary_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let old: Vec<u8> = (0..200).collect();
+    let replace = |b: u8| {
+        let mut v = old.clone();
+        for x in &mut v[100..110] {
+            *x = b
+        }
+        v
+    };
+    let (a, b, merged) = (replace(0xf0), replace(0xf1), replace(0xf2));
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", old.clone());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+
+    repo.write_file("file")?.write_all(&a)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &other, "", true, None, 1, 0)?;
+    repo2.write_file("file")?.write_all(&b)?;
+    let hb = record_all(&repo2, &changes, &txn, &other, "")?;
+
+    apply::apply_change_arc(&changes, &txn, &channel, &hb)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    assert!(!conflicts.is_empty());
+
+    let merger = |c: &BinaryConflict| {
+        assert_eq!(c.path, "file");
+        assert_eq!(c.sides.len(), 2);
+        assert!(c.sides.contains(&a));
+        assert!(c.sides.contains(&b));
+        assert_eq!(c.ancestor.as_deref(), Some(&old[..]));
+        Some(merged.clone())
+    };
+    let header = crate::change::ChangeHeader {
+        message: "Merge file".to_string(),
+        ..Default::default()
+    };
+    let (h, files) =
+        merge_binary_conflicts(&changes, &txn, &channel, &repo, &merger, header)?.unwrap();
+    assert_eq!(files, vec!["file"]);
+    assert!(txn.read().get_revchanges(&channel, &h)?.is_some());
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    assert_eq!(buf, merged);
+
+    let repo3 = working_copy::memory::Memory::new();
+    let conflicts = output::output_repository_no_pending(
+        &repo3, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    assert!(conflicts.is_empty());
+    let mut buf = Vec::new();
+    repo3.read_file("file", &mut buf)?;
+    assert_eq!(buf, merged);
+    Ok(())
+}
+
+/// Word edits of different words of a line merge without conflicts,
+/// and a conflict between two edits of the same word, in the middle
+/// of a line, can be solved and the solution applied elsewhere.
+#[test]
+fn word_diff_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nthe quick brown fox\nb\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &other, "", true, None, 1, 0)?;
+    let words = || {
+        let mut state = Builder::new();
+        state.word_diff = true;
+        state
+    };
+    let read = |repo: &working_copy::memory::Memory| -> Result<Vec<u8>, anyhow::Error> {
+        let mut buf = Vec::new();
+        repo.read_file("file", &mut buf)?;
+        Ok(buf)
+    };
+
+    // Different words.
+    repo.write_file("file")?
+        .write_all(b"a\nthe quick brown cat\nb\n")?;
+    let h = record_with(words(), &repo, &changes, &txn, &channel, "")?.0;
+    repo2
+        .write_file("file")?
+        .write_all(b"a\nthe slow brown fox\nb\n")?;
+    let h2 = record_with(words(), &repo2, &changes, &txn, &other, "")?.0;
+    apply::apply_change_arc(&changes, &txn, &channel, &h2)?;
+    apply::apply_change_arc(&changes, &txn, &other, &h)?;
+    for (repo, channel) in [(&repo, &channel), (&repo2, &other)].iter() {
+        let conflicts = output::output_repository_no_pending(
+            *repo, &changes, &txn, channel, "", true, None, 1, 0,
+        )?;
+        assert!(conflicts.is_empty());
+        assert_eq!(read(repo)?, b"a\nthe slow brown cat\nb\n");
+    }
+
+    // The same word.
+    repo.write_file("file")?
+        .write_all(b"a\nthe slow red cat\nb\n")?;
+    let h = record_with(words(), &repo, &changes, &txn, &channel, "")?.0;
+    repo2
+        .write_file("file")?
+        .write_all(b"a\nthe slow green cat\nb\n")?;
+    let h2 = record_with(words(), &repo2, &changes, &txn, &other, "")?.0;
+    apply::apply_change_arc(&changes, &txn, &channel, &h2)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(conflicts.len(), 1);
+    let conflict = read(&repo)?;
+    debug!("{:?}", std::str::from_utf8(&conflict));
+    let conflict = std::str::from_utf8(&conflict)?;
+    assert!(conflict.contains("red") && conflict.contains("green"));
+
+    // Solve the conflict, and apply the solution to the other channel.
+    repo.write_file("file")?
+        .write_all(b"a\nthe slow red-green cat\nb\n")?;
+    let solution = record_with(Builder::new(), &repo, &changes, &txn, &channel, "")?.0;
+    apply::apply_change_arc(&changes, &txn, &other, &h)?;
+    apply::apply_change_arc(&changes, &txn, &other, &solution)?;
+    for (repo, channel) in [(&repo, &channel), (&repo2, &other)].iter() {
+        let conflicts = output::output_repository_no_pending(
+            *repo, &changes, &txn, channel, "", true, None, 1, 0,
+        )?;
+        assert!(conflicts.is_empty());
+        assert_eq!(read(repo)?, b"a\nthe slow red-green cat\nb\n");
+    }
+    Ok(())
+}
+
+/// Solving an order conflict by lines, where one side is a line made
+/// of several vertices by a word diff, deletes that line.
+#[test]
+fn word_diff_order_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &other, "", true, None, 1, 0)?;
+
+    repo.write_file("file")?.write_all(b"a\nx y z\nb\n")?;
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    let mut words = Builder::new();
+    words.word_diff = true;
+    repo.write_file("file")?.write_all(b"a\nx Q z\nb\n")?;
+    let h1 = record_with(words, &repo, &changes, &txn, &channel, "")?.0;
+    repo2.write_file("file")?.write_all(b"a\nw\nb\n")?;
+    let h2 = record_all(&repo2, &changes, &txn, &other, "")?;
+    apply::apply_change_arc(&changes, &txn, &channel, &h2)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(conflicts.len(), 1);
+
+    let mut buf = Vec::new();
+    repo.read_file("file", &mut buf)?;
+    let solved = std::str::from_utf8(&buf)?
+        .replacen("a\n", "a\nnew\n", 1)
+        .replacen("x Q z\n", "", 1);
+    repo.write_file("file")?.write_all(solved.as_bytes())?;
+    let solution = record_all(&repo, &changes, &txn, &channel, "")?;
+    for h in [h0, h1, solution].iter() {
+        apply::apply_change_arc(&changes, &txn, &other, h)?;
+    }
+    for (repo, channel) in [(&repo, &channel), (&repo2, &other)].iter() {
+        let conflicts = output::output_repository_no_pending(
+            *repo, &changes, &txn, channel, "", true, None, 1, 0,
+        )?;
+        assert!(conflicts.is_empty());
+        let mut buf = Vec::new();
+        repo.read_file("file", &mut buf)?;
+        assert_eq!(buf, b"a\nnew\nw\nb\n");
+    }
+    Ok(())
+}
+
+/// With the hybrid text policy, edits of different segments of a long
+/// line merge without conflicts, and a conflict between two edits of
+/// the same segment can be solved by lines.
+#[test]
+fn segments_conflict() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file(
+        crate::attributes::ATTRIBUTES_FILE,
+        b"file text=hybrid:64\n".to_vec(),
+    );
+    let line: String = (0..200).map(|i| format!("w{} ", i)).collect();
+    repo.add_file("file", format!("a\n{}\nb\n", line).into_bytes());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write()
+        .add_file(crate::attributes::ATTRIBUTES_FILE, 0)?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let other = txn.write().fork(&channel, "other")?;
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &other, "", true, None, 1, 0)?;
+    let read = |repo: &working_copy::memory::Memory| -> Result<Vec<u8>, anyhow::Error> {
+        let mut buf = Vec::new();
+        repo.read_file("file", &mut buf)?;
+        Ok(buf)
+    };
+
+    // Different segments.
+    let (start, end) = (&line[..100], &line[600..]);
+    let mid = &line[100..600];
+    repo.write_file("file")?
+        .write_all(format!("a\n{}{}X{}\nb\n", start, mid, end).as_bytes())?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo2
+        .write_file("file")?
+        .write_all(format!("a\nY{}{}{}\nb\n", start, mid, end).as_bytes())?;
+    let h2 = record_all(&repo2, &changes, &txn, &other, "")?;
+    apply::apply_change_arc(&changes, &txn, &channel, &h2)?;
+    apply::apply_change_arc(&changes, &txn, &other, &h)?;
+    let merged = format!("a\nY{}{}X{}\nb\n", start, mid, end);
+    for (repo, channel) in [(&repo, &channel), (&repo2, &other)].iter() {
+        let conflicts = output::output_repository_no_pending(
+            *repo, &changes, &txn, channel, "", true, None, 1, 0,
+        )?;
+        assert!(conflicts.is_empty());
+        assert_eq!(read(repo)?, merged.as_bytes());
+    }
+
+    // The same segment.
+    repo.write_file("file")?
+        .write_all(format!("a\nY{}1{}X{}\nb\n", start, mid, end).as_bytes())?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo2
+        .write_file("file")?
+        .write_all(format!("a\nY{}2{}X{}\nb\n", start, mid, end).as_bytes())?;
+    let h2 = record_all(&repo2, &changes, &txn, &other, "")?;
+    apply::apply_change_arc(&changes, &txn, &channel, &h2)?;
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    assert_eq!(conflicts.len(), 1);
+
+    let solved = format!("a\nY{}12{}X{}\nb\n", start, mid, end);
+    repo.write_file("file")?.write_all(solved.as_bytes())?;
+    let solution = record_all(&repo, &changes, &txn, &channel, "")?;
+    apply::apply_change_arc(&changes, &txn, &other, &h)?;
+    apply::apply_change_arc(&changes, &txn, &other, &solution)?;
+    for (repo, channel) in [(&repo, &channel), (&repo2, &other)].iter() {
+        let conflicts = output::output_repository_no_pending(
+            *repo, &changes, &txn, channel, "", true, None, 1, 0,
+        )?;
+        assert!(conflicts.is_empty());
+        assert_eq!(read(repo)?, solved.as_bytes());
+    }
+    Ok(())
+}
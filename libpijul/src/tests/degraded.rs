@@ -0,0 +1,72 @@
+use super::*;
+use std::io::Write;
+
+/// Opening an intact pristine in degraded mode reports nothing to
+/// repair.
+#[test]
+fn degraded_intact() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\nc\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    {
+        let txn = env.arc_txn_begin().unwrap();
+        txn.write().add_file("dir/file", 0).unwrap();
+        let channel = txn.write().open_or_create_channel("main").unwrap();
+        record_all(&repo, &changes, &txn, &channel, "").unwrap();
+        txn.commit().unwrap()
+    }
+    let txn = env.txn_begin_degraded()?;
+    assert!(txn.is_intact());
+    let channel = txn.load_channel("main")?.unwrap();
+    assert_eq!(txn.txn.log(&*channel.read(), 0)?.count(), 1);
+    Ok(())
+}
+
+/// Corrupt pages are found, and only make their tables or channels
+/// unavailable.
+#[test]
+fn degraded_corrupt() -> Result<(), anyhow::Error> {
+    use std::io::{Seek, SeekFrom};
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("a", b"a\nb\nc\n".to_vec());
+    repo.add_file("b", b"d\ne\n".to_vec());
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db");
+    let (graph, tree) = {
+        let env = pristine::sanakirja::Pristine::new(&path)?;
+        let txn = env.arc_txn_begin().unwrap();
+        let main = txn.write().open_or_create_channel("main").unwrap();
+        txn.write().add_file("a", 0).unwrap();
+        record_all(&repo, &changes, &txn, &main, "a").unwrap();
+        let other = txn.write().open_or_create_channel("other").unwrap();
+        txn.write().add_file("b", 0).unwrap();
+        record_all(&repo, &changes, &txn, &other, "b").unwrap();
+        let graph = main.read().graph.db;
+        let tree = txn.read().tree.db;
+        txn.commit().unwrap();
+        (graph, tree)
+    };
+
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path)?;
+    for page in [graph, tree].iter() {
+        f.seek(SeekFrom::Start(page + 100))?;
+        f.write_all(&[0xff; 16])?;
+    }
+    std::mem::drop(f);
+
+    let env = pristine::sanakirja::Pristine::new(&path)?;
+    let txn = env.txn_begin_degraded()?;
+    assert!(!txn.is_intact());
+    assert_eq!(txn.broken_tables, vec![pristine::sanakirja::Root::Tree]);
+    assert_eq!(txn.broken_channels, vec!["main".to_string()]);
+    assert!(txn.load_channel("main")?.is_none());
+    let channel = txn.load_channel("other")?.unwrap();
+    assert_eq!(txn.txn.log(&*channel.read(), 0)?.count(), 1);
+    Ok(())
+}
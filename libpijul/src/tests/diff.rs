@@ -1,9 +1,21 @@
 use super::*;
 use crate::alive::retrieve;
+use crate::alive::retrieve::{retrieve_limited, RetrieveError, RetrieveLimits};
+use crate::attributes::ATTRIBUTES_FILE;
+use crate::change::{Atom, Hunk};
+use crate::chunks::*;
+use crate::diff::markers::*;
+use crate::diff::{DiffAlgorithm, Line, Replacement};
+use crate::record::{ReadErrorPolicy, RecordError, RecordReport};
+use crate::text_encoding::{Encoding, EncodingDetector, Utf8Only};
+use crate::vertex_buffer::{END_MARKER, SEPARATOR, START_MARKER};
+use crate::working_copy::{memory, simulated, Simulated};
 use rand::distributions::Alphanumeric;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 #[test]
 fn bin_diff_test() -> Result<(), anyhow::Error> {
@@ -61,7 +73,7 @@ fn bin_diff_test() -> Result<(), anyhow::Error> {
     {
         let txn = env.arc_txn_begin().unwrap();
         let channel = txn.write().open_or_create_channel("main").unwrap();
-        debug_to_file(&*txn.read(), &channel, "debug").unwrap();
+        debug_to_file(&*txn.read(), &channel, debug_path("debug")).unwrap();
         let mut rec = crate::record::Builder::new();
         let rec = rec.recorded();
         let vertex = Position {
@@ -73,7 +85,7 @@ fn bin_diff_test() -> Result<(), anyhow::Error> {
             &changes,
             &*txn.read(),
             &*channel.read(),
-            crate::record::Algorithm::Myers,
+            &crate::record::Algorithm::Myers,
             String::new(),
             vertex.to_option(),
             &mut ret,
@@ -82,8 +94,909 @@ fn bin_diff_test() -> Result<(), anyhow::Error> {
         )?;
         debug!("{:#?}", rec.lock().actions);
         record_all(&repo, &changes, &txn, &channel, "").unwrap();
-        debug_to_file(&*txn.read(), &channel, "debug").unwrap();
+        debug_to_file(&*txn.read(), &channel, debug_path("debug")).unwrap();
         // txn.commit().unwrap()
     }
     Ok(())
 }
+
+#[test]
+fn binary_edit() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let old: Vec<u8> = (0..100_000).map(|_| rng.gen()).collect();
+    repo.add_file("file", old.clone());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut new = old.clone();
+    for b in &mut new[80_000..80_010] {
+        *b = !*b
+    }
+    // Also insert bytes before that, shifting the rest of the file.
+    new.splice(2_000..2_000, (0..100).map(|_| rng.gen::<u8>()));
+    repo.write_file("file")?.write_all(&new)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    let change = changes.get_change(&h)?;
+    assert!(change.changes.len() > 1);
+    for hunk in change.changes.iter() {
+        assert!(matches!(hunk, Hunk::BinaryEdit { .. }));
+    }
+    assert_eq!(change.apply_binary_edits("file", &old), Some(new.clone()));
+
+    // The text format round-trips.
+    let mut text = Vec::new();
+    change.write(
+        &changes,
+        Some(h),
+        |l, _p| format!("{}:{}", l.path, l.line),
+        true,
+        &mut text,
+    )?;
+    let parsed = crate::change::Change::read(&text[..], &mut HashMap::default())?;
+    assert_eq!(parsed.changes, change.changes);
+
+    // The offsets of the inverse are offsets in the new file.
+    let inverse = change.inverse(&h, crate::change::ChangeHeader::default(), Vec::new())?;
+    let mut shift = 0i64;
+    for (hunk, inv) in change.changes.iter().zip(inverse.changes.iter()) {
+        match (hunk, inv) {
+            (
+                Hunk::BinaryEdit {
+                    offset, delete_len, ..
+                },
+                Hunk::BinaryEdit {
+                    offset: inv_offset,
+                    delete_len: inv_delete_len,
+                    ..
+                },
+            ) => {
+                assert_eq!(*inv_offset as i64, *offset as i64 + shift);
+                assert!(*inv_offset + *inv_delete_len <= new.len() as u64);
+                shift += *inv_delete_len as i64 - *delete_len as i64
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    let mut buf = Vec::new();
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    repo.read_file("file", &mut buf)?;
+    assert_eq!(buf, new);
+    Ok(())
+}
+
+/// Update a buffer diff with random edits, and check that the hunks
+/// still turn the old version into the buffer.
+#[test]
+fn incremental_diff() -> Result<(), anyhow::Error> {
+    use crate::diff::*;
+    env_logger::try_init().unwrap_or(());
+
+    fn split(b: &[u8]) -> Vec<&[u8]> {
+        b.split_inclusive(|&c| c == b'\n').collect()
+    }
+    fn patch(d: &BufferDiff) -> Vec<u8> {
+        let old = split(d.old());
+        let new = split(d.buffer());
+        let mut result = Vec::new();
+        let mut last = 0;
+        for h in d.hunks() {
+            assert!(h.old.start >= last);
+            result.extend(old[last..h.old.start].concat());
+            result.extend(new[h.new.clone()].concat());
+            last = h.old.end
+        }
+        result.extend(old[last..].concat());
+        result
+    }
+
+    let old = b"a\nb\nc\nd\ne\n".to_vec();
+    let d = BufferDiff::new(old.clone(), old.clone(), Algorithm::default());
+    assert!(d.hunks().is_empty());
+    let d = incremental(
+        d,
+        &[BufferEdit {
+            start: 2,
+            end: 4,
+            text: b"x\ny\n".to_vec(),
+        }],
+    );
+    assert_eq!(d.buffer(), b"a\nx\ny\nc\nd\ne\n");
+    assert_eq!(
+        d.hunks(),
+        &[PendingHunk {
+            old: 1..2,
+            new: 1..3
+        }]
+    );
+
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let mut d = d;
+    for _ in 0..1000 {
+        let len = d.buffer().len();
+        let start = rng.gen_range(0, len + 1);
+        let end = rng.gen_range(start, len.min(start + 10) + 1);
+        let text: Vec<u8> = (0..rng.gen_range(0, 6))
+            .map(|_| b"ab\n"[rng.gen_range(0, 3)])
+            .collect();
+        d = incremental(d, &[BufferEdit { start, end, text }]);
+        assert_eq!(patch(&d), d.buffer());
+    }
+    Ok(())
+}
+
+/// Edits separated by a few unchanged lines are recorded as a single
+/// replacement when they are within the join distance.
+#[test]
+fn join_distance() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("file", b"a\nb\nc\nd\ne\nf\ng\nh\n".to_vec());
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file")?
+        .write_all(b"a\nB\nc\nD\ne\nf\ng\nH\n")?;
+    let replacements = |join_distance| -> Result<usize, anyhow::Error> {
+        let mut state = Builder::new();
+        state.join_distance = join_distance;
+        state.record(
+            txn.clone(),
+            Algorithm::default(),
+            channel.clone(),
+            &repo,
+            &changes,
+            "",
+            1,
+        )?;
+        Ok(state
+            .finish()?
+            .actions
+            .iter()
+            .filter(|h| matches!(h, Hunk::Replacement { .. }))
+            .count())
+    };
+    assert_eq!(replacements(None)?, 3);
+    // `c` is joined, `e\nf\ng` is too far.
+    assert_eq!(replacements(Some(1))?, 2);
+
+    // The joined change still produces the new version.
+    let mut state = Builder::new();
+    state.join_distance = Some(3);
+    let (_, change, _) = record_with(state, &repo, &changes, &txn, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(&buf[..], b"a\nB\nc\nD\ne\nf\ng\nH\n");
+    Ok(())
+}
+
+/// Editing a word in a long line only inserts that word, the edited
+/// line can be edited again, and lines can be joined by deleting
+/// words only.
+#[test]
+fn word_diff() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file(
+        "file",
+        b"first\nthe quick brown fox jumps over the lazy dog\nlast\n".to_vec(),
+    );
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let edits: [&[u8]; 3] = [
+        b"first\nthe quick red fox jumps over the lazy dog\nlast\n",
+        b"first\nthe quick red fox jumps over the sleepy dog!\nlast\n",
+        b"the quick red fox jumps\nlast\n",
+    ];
+    let inserted: [&[u8]; 3] = [b"red\0", b"sleepy\0!\0", b""];
+    for (edit, ins) in edits.iter().zip(inserted.iter()) {
+        repo.write_file("file")?.write_all(edit)?;
+        let mut state = Builder::new();
+        state.word_diff = true;
+        let (_, change, _) = record_with(state, &repo, &changes, &txn, &channel, "")?;
+        assert_eq!(&change.contents[..], *ins);
+
+        let repo2 = working_copy::memory::Memory::new();
+        output::output_repository_no_pending(
+            &repo2, &changes, &txn, &channel, "", true, None, 1, 0,
+        )?;
+        let mut buf = Vec::new();
+        repo2.read_file("file", &mut buf)?;
+        assert_eq!(&buf[..], *edit);
+    }
+    Ok(())
+}
+
+/// Editing a large binary file recorded in windows only inserts the
+/// windows around the edits.
+#[test]
+fn stream_diff() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let old: Vec<u8> = (0..100_000).map(|_| rng.gen()).collect();
+    repo.add_file("file", old.clone());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut new = old.clone();
+    for b in &mut new[50_000..50_010] {
+        *b = !*b
+    }
+    new.splice(80_000..80_000, b"inserted".iter().cloned());
+    new.truncate(95_000);
+    repo.write_file("file")?.write_all(&new)?;
+    let streamed = || {
+        let mut state = Builder::new();
+        state.stream_threshold = Some(10_000);
+        state
+    };
+    let (_, change, _) = record_with(streamed(), &repo, &changes, &txn, &channel, "")?;
+    assert!(change.contents.len() < 40_000);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(buf, new);
+
+    // Recording again finds nothing to record.
+    let (_, change, _) = record_with(streamed(), &repo, &changes, &txn, &channel, "")?;
+    assert!(change.changes.is_empty());
+    Ok(())
+}
+
+/// Large text files recorded in windows are diffed by lines, even
+/// when lines and line endings are split between windows.
+#[test]
+fn stream_text_diff() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let mut lines: Vec<String> = vec!["#header\r\n".to_string()];
+    lines.extend((0..20_000).map(|i| format!("line {}\r\n", i)));
+    let old = lines.concat().into_bytes();
+    // The first window ends between `\r` and `\n`.
+    assert_eq!(&old[(1 << 16) - 1..(1 << 16) + 1], b"\r\n");
+    repo.add_file("file", old.clone());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    lines[15_000] = "edited\r\n".to_string();
+    lines.insert(10_000, "inserted\r\n".to_string());
+    lines.push("no final eol".to_string());
+    let new = lines.concat().into_bytes();
+    repo.write_file("file")?.write_all(&new)?;
+    let streamed = || {
+        let mut state = Builder::new();
+        state.stream_threshold = Some(10_000);
+        state
+    };
+    let (_, change, rec) = record_with(streamed(), &repo, &changes, &txn, &channel, "")?;
+    let (bytes, lines) = inserted(&change);
+    assert_eq!(bytes, 30);
+    assert_eq!(lines, vec![10_001, 15_002, 20_003]);
+    let stats = rec.file_stats.values().next().unwrap();
+    assert_eq!(stats.encoding.as_deref(), Some("UTF-8"));
+    assert_eq!((stats.crlf, stats.lf, stats.cr), (20_002, 0, 0));
+    assert!(!stats.final_eol);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut buf = Vec::new();
+    repo2.read_file("file", &mut buf)?;
+    assert_eq!(buf, new);
+
+    let (_, change, _) = record_with(streamed(), &repo, &changes, &txn, &channel, "")?;
+    assert!(change.changes.is_empty());
+    Ok(())
+}
+
+/// Number of bytes inserted by the hunks of `change`, and the lines
+/// of its edits.
+fn inserted(change: &Change) -> (u64, Vec<usize>) {
+    let mut bytes = 0;
+    let mut lines = Vec::new();
+    for hunk in change.changes.iter() {
+        match hunk {
+            Hunk::Edit { local, .. } | Hunk::Replacement { local, .. } => lines.push(local.line),
+            _ => {}
+        }
+        for atom in hunk.iter() {
+            if let Atom::NewVertex(n) = atom {
+                bytes += n.end.0.as_u64() - n.start.0.as_u64()
+            }
+        }
+    }
+    (bytes, lines)
+}
+
+/// With the hybrid text policy, set by the attributes file, an edit in a very long line only
+/// replaces the segments of the line around the edit.
+#[test]
+fn long_lines() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file(ATTRIBUTES_FILE, b"file text=hybrid:256\n".to_vec());
+
+    let mut line = String::new();
+    for i in 0..2000 {
+        line.push_str(&format!("w{} ", (i * 7919) % 10007))
+    }
+    let contents = format!("first\n{}\nlast\n", line);
+    repo.add_file("file", contents.as_bytes().to_vec());
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mid = line.len() / 2;
+    let edited = format!("first\n{}CHANGED{}\nlast\n", &line[..mid], &line[mid..]);
+    repo.write_file("file")?.write_all(edited.as_bytes())?;
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let (bytes, lines) = inserted(&change);
+    assert!(bytes > 0 && bytes < 4 * 256, "{} bytes inserted", bytes);
+    assert!(!lines.is_empty());
+    assert!(lines.iter().all(|&l| l == 2), "{:?}", lines);
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut file = Vec::new();
+    repo2.read_file("file", &mut file)?;
+    assert_eq!(file, edited.as_bytes());
+    Ok(())
+}
+
+/// Treats files containing NUL bytes as binary.
+struct NulBinary;
+
+impl EncodingDetector for NulBinary {
+    fn detect(&self, path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        if contents.contains(&0) {
+            None
+        } else {
+            Utf8Only.detect(path, contents, last)
+        }
+    }
+}
+
+/// With the hybrid text policy, text files with a few NUL bytes are
+/// diffed by lines. Files without the attribute are still binary.
+#[test]
+fn nul_text() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.set_encoding_detector(NulBinary);
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file(ATTRIBUTES_FILE, b"*.log text=hybrid\n".to_vec());
+
+    let mut contents = Vec::new();
+    for i in 0..40 {
+        writeln!(contents, "line {}", i)?;
+    }
+    contents[8] = 0;
+    repo.add_file("a.log", contents.clone());
+    repo.add_file("a.bin", contents.clone());
+    txn.write().add_file("a.log", 0)?;
+    txn.write().add_file("a.bin", 0)?;
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let mut encodings: Vec<_> = change
+        .changes
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileAdd { path, encoding, .. } => Some((path.as_str(), encoding.is_some())),
+            _ => None,
+        })
+        .collect();
+    encodings.sort();
+    assert_eq!(encodings, vec![("a.bin", false), ("a.log", true)]);
+
+    let pos = contents.windows(8).position(|w| w == b"line 20\n").unwrap();
+    contents[pos + 5] = b'X';
+    repo.write_file("a.log")?.write_all(&contents)?;
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    match change.changes[0] {
+        Hunk::Replacement {
+            ref encoding,
+            ref local,
+            ..
+        } => {
+            assert!(encoding.is_some());
+            assert_eq!(local.line, 21);
+        }
+        ref h => panic!("unexpected hunk {:?}", h),
+    }
+    Ok(())
+}
+
+/// Replaces the whole file as soon as a line differs.
+#[derive(Default)]
+struct Rewrite {
+    calls: AtomicUsize,
+}
+
+impl DiffAlgorithm for Rewrite {
+    fn diff(&self, a: &[Line], b: &[Line]) -> Vec<Replacement> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if a == b {
+            Vec::new()
+        } else {
+            vec![Replacement::new(0, a.len(), 0, b.len())]
+        }
+    }
+}
+
+/// A diff algorithm defined outside of libpijul is used by record.
+#[test]
+fn diff_algorithm() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    // The settings of the channel don't override the algorithm
+    // passed to record.
+    crate::channel_settings::save(
+        &mut *txn.write(),
+        &*channel.read(),
+        &crate::channel_settings::ChannelSettings {
+            diff_algorithm: Some(Algorithm::Patience),
+            ..Default::default()
+        },
+    )?;
+    let algorithm = Arc::new(Rewrite::default());
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        algorithm.clone(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+    assert_eq!(algorithm.calls.load(Ordering::SeqCst), 1);
+    assert_eq!(&rec.contents.lock()[..6], b"a\nx\nc\n");
+
+    // The built-in algorithms only insert the new line.
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::Patience,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish()?;
+    assert_eq!(&rec.contents.lock()[..2], b"x\n");
+    Ok(())
+}
+
+fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 56) as u8
+        })
+        .collect()
+}
+
+/// Transfer a file to a receiver that already has a similar file, and
+/// check that only the chunks around the edit are sent.
+#[test]
+fn chunk_transfer() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let old = pseudo_random(200_000, 1);
+    let mut new = old.clone();
+    new.splice(100_000..100_010, pseudo_random(1000, 2));
+
+    // Sender side.
+    let chunks = split(&new);
+    assert_eq!(chunks.iter().map(|c| c.len).sum::<usize>(), new.len());
+    assert!(chunks.iter().all(|c| c.len <= MAX_CHUNK));
+    let mut list = Vec::new();
+    write_list(&chunks, &mut list)?;
+
+    // Receiver side.
+    let mut index = ChunkIndex::new();
+    index.add(&old);
+    let chunks_ = read_list(&list)?;
+    assert_eq!(chunks, chunks_);
+    let missing = index.missing(&chunks_);
+    assert!(!missing.is_empty());
+    assert!(missing.len() * 4 < chunks.len());
+
+    let mut sent = Vec::new();
+    let mut start = 0;
+    for (i, c) in chunks.iter().enumerate() {
+        if missing.contains(&i) {
+            sent.extend_from_slice(&new[start..start + c.len])
+        }
+        start += c.len
+    }
+    assert!(sent.len() < new.len() / 4);
+    assert_eq!(index.reassemble(&chunks_, &sent)?, new);
+
+    // Corrupted chunks are rejected.
+    let mut index = ChunkIndex::new();
+    index.add(&old);
+    sent[0] ^= 1;
+    assert!(index.reassemble(&chunks_, &sent).is_err());
+    Ok(())
+}
+
+fn file(parts: &[&str]) -> Vec<u8> {
+    let mut f = String::new();
+    for p in parts {
+        if p.starts_with('\n') {
+            f.push_str(&p[1..])
+        } else {
+            f.push_str(p)
+        }
+    }
+    f.into_bytes()
+}
+
+#[test]
+fn markers_nested() {
+    let f = file(&[
+        "a\n",
+        START_MARKER,
+        "b\n",
+        SEPARATOR,
+        START_MARKER,
+        "c\n",
+        SEPARATOR,
+        "d\n",
+        END_MARKER,
+        SEPARATOR,
+        "e\n",
+        END_MARKER,
+        "f\n",
+    ]);
+    let m = parse(&f).unwrap();
+    let kinds: Vec<_> = m.markers.iter().map(|(_, m)| (m.kind, m.side)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (MarkerKind::Begin, 0),
+            (MarkerKind::Next, 1),
+            (MarkerKind::Begin, 0),
+            (MarkerKind::Next, 1),
+            (MarkerKind::End, 1),
+            (MarkerKind::Next, 2),
+            (MarkerKind::End, 2),
+        ]
+    );
+    assert_eq!(m.conflicts.len(), 1);
+    let c = &m.conflicts[0];
+    assert_eq!(c.start, 2);
+    assert_eq!(c.end, f.len() - 2);
+    assert_eq!(c.sides.len(), 3);
+    assert_eq!(&f[c.sides[0].start..c.sides[0].end], b"b\n");
+    assert_eq!(&f[c.sides[2].start..c.sides[2].end], b"e\n");
+    assert!(c.sides[0].conflicts.is_empty());
+    assert_eq!(c.sides[1].conflicts.len(), 1);
+    let inner = &c.sides[1].conflicts[0];
+    assert_eq!(&f[inner.sides[0].start..inner.sides[0].end], b"c\n");
+    assert_eq!(&f[inner.sides[1].start..inner.sides[1].end], b"d\n");
+    for &(pos, marker) in m.markers.iter() {
+        assert_eq!(m.get(pos), Some(marker))
+    }
+    assert_eq!(m.get(0), None);
+}
+
+#[test]
+fn markers_single_side() {
+    // Cyclic and zombie conflicts can have a single side.
+    let f = file(&[START_MARKER, "a\n", END_MARKER]);
+    let m = parse(&f).unwrap();
+    assert_eq!(m.conflicts.len(), 1);
+    assert_eq!(m.conflicts[0].sides.len(), 1);
+    assert_eq!(m.conflicts[0].end, f.len());
+    // The last marker may lack its final newline.
+    let m = parse(&f[..f.len() - 1]).unwrap();
+    assert_eq!(m.conflicts[0].end, f.len() - 1);
+}
+
+#[test]
+fn markers_errors() {
+    let f = file(&["a\n", SEPARATOR]);
+    assert_eq!(parse(&f), Err(MarkerError::UnexpectedSeparator { pos: 2 }));
+    let f = file(&["a\n", END_MARKER]);
+    assert_eq!(parse(&f), Err(MarkerError::UnexpectedEnd { pos: 2 }));
+    let f = file(&["a\n", START_MARKER, "b\n", SEPARATOR]);
+    assert_eq!(parse(&f), Err(MarkerError::Unterminated { pos: 2 }));
+    let f = b"a\n>>>> not a marker\n";
+    assert_eq!(parse(f).unwrap(), Markers::default());
+}
+
+/// Retrieving a graph larger than the limits fails with the size of
+/// what was retrieved, and succeeds without limits.
+#[test]
+fn graph_limits() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let mut contents = b"a\n".to_vec();
+    for line in b"bcdefgh" {
+        contents.extend_from_slice(&[*line, b'\n']);
+        repo.write_file("file")?.write_all(&contents)?;
+        record_all(&repo, &changes, &txn, &channel, "")?;
+    }
+
+    let txn = txn.read();
+    let (pos, _) = txn.follow_oldest_path(&changes, &channel, "file")?;
+    let channel = channel.read();
+    let graph = txn.graph(&*channel);
+    let limits = RetrieveLimits {
+        vertices: Some(4),
+        edges: None,
+    };
+    match retrieve_limited(&*txn, graph, pos, limits) {
+        Err(RetrieveError::GraphTooLarge(e)) => {
+            assert_eq!(e.pos, pos);
+            assert_eq!(e.vertices, 5);
+            assert_eq!(e.limits, limits);
+        }
+        r => panic!("unexpected result {:?}", r.map(|g| g.len_vertices())),
+    }
+    let graph = retrieve_limited(&*txn, graph, pos, RetrieveLimits::UNLIMITED)?;
+    // The inode, the eight lines and a dummy vertex.
+    assert_eq!(graph.len_vertices(), 10);
+    Ok(())
+}
+
+/// Outputs use the limits of their working copy, and recordings the
+/// limits of their builder.
+#[test]
+fn graph_limits_callers() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\n".to_vec());
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file")?.write_all(b"a\nb\nc\nd\ne\nf\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let limits = RetrieveLimits {
+        vertices: Some(2),
+        edges: None,
+    };
+    let repo2 = working_copy::memory::Memory::new();
+    repo2.set_graph_limits(limits);
+    match output::output_repository_no_pending(
+        &repo2, &changes, &txn, &channel, "", true, None, 1, 0,
+    ) {
+        Err(output::OutputError::Pristine(output::PristineOutputError::GraphTooLarge(e))) => {
+            assert_eq!(e.limits, limits)
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    // Other callers aren't affected.
+    let repo3 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo3, &changes, &txn, &channel, "", true, None, 1, 0)?;
+
+    repo.write_file("file")?.write_all(b"a\nb\nc\nd\ne\n")?;
+    let mut builder = crate::record::Builder::new();
+    builder.graph_limits = limits;
+    match builder.record(
+        txn.clone(),
+        crate::Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    ) {
+        Err(crate::record::RecordError::GraphTooLarge(e)) => assert_eq!(e.limits, limits),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+    Ok(())
+}
+
+type Error<T> =
+    RecordError<<changestore::memory::Memory as ChangeStore>::Error, simulated::Error, T>;
+
+fn record<T>(
+    wc: &Simulated,
+    changes: &changestore::memory::Memory,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    policy: ReadErrorPolicy,
+) -> Result<(crate::record::Recorded, RecordReport), Error<T::GraphError>>
+where
+    T: MutTxnT + Send + Sync + 'static,
+    T::Channel: Send + Sync,
+{
+    let mut state = Builder::new();
+    state.force_rediff = true;
+    state.read_error_policy = policy;
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        wc,
+        changes,
+        "",
+        1,
+    )?;
+    Ok(state.finish_with_report()?)
+}
+
+/// On a case-insensitive filesystem, writing a file under another
+/// case modifies the tracked file.
+#[test]
+fn simulated_case_insensitive() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("README", b"a\n".to_vec());
+    txn.write().add_file("README", 0)?;
+    let wc = Simulated::new(repo.clone());
+    wc.set_case_insensitive(true);
+    record_all(&wc, &changes, &txn, &channel, "")?;
+
+    assert!(wc.file_metadata("ReadMe").is_ok());
+    wc.write_file("readme")?.write_all(b"a\nb\n")?;
+    assert_eq!(repo.list_files(), vec!["README"]);
+    let (rec, _) = record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Fail)?;
+    assert_eq!(rec.actions.len(), 1);
+
+    // Two files differing only by their case end up in the same file.
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_file("A", b"A\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("A", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let wc2 = Simulated::new(memory::Memory::new());
+    wc2.set_case_insensitive(true);
+    output::output_repository_no_pending(&wc2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let files = wc2.memory().list_files();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files.iter().filter(|f| f.as_str() != "README").count(), 1);
+    Ok(())
+}
+
+/// A file tracked with a composed name, stored with a decomposed name
+/// by the filesystem, is still found.
+#[test]
+fn simulated_normalization_insensitive() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("\u{e9}t\u{e9}", b"a\n".to_vec());
+    txn.write().add_file("\u{e9}t\u{e9}", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let decomposed = memory::Memory::new();
+    decomposed.add_file("e\u{301}te\u{301}", b"a\n".to_vec());
+    let wc = Simulated::new(decomposed.clone());
+    wc.set_normalization_insensitive(true);
+    let (rec, report) = record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Fail)?;
+    assert!(rec.actions.is_empty());
+    assert!(report.skipped.is_empty());
+
+    wc.write_file("\u{e9}t\u{e9}")?.write_all(b"b\n")?;
+    assert_eq!(decomposed.list_files(), vec!["e\u{301}te\u{301}"]);
+    let (rec, _) = record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Fail)?;
+    assert_eq!(rec.actions.len(), 1);
+    Ok(())
+}
+
+/// Files that can't be read, or vanish while being recorded, are
+/// handled according to the read error policy.
+#[test]
+fn simulated_read_errors() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_file("b", b"b\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    txn.write().add_file("b", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("a")?.write_all(b"a\nx\n")?;
+    repo.write_file("b")?.write_all(b"b\nx\n")?;
+
+    let wc = Simulated::new(repo.clone());
+    wc.deny("a");
+    assert!(!wc.is_writable("a")?);
+    match record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Fail) {
+        Err(RecordError::WorkingCopy(simulated::Error::PermissionDenied { .. })) => {}
+        Err(e) => panic!("{:?}", e),
+        Ok(_) => panic!("a is unreadable"),
+    }
+
+    wc.vanish_on_read("b");
+    let (rec, report) = record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Skip)?;
+    assert!(rec.actions.is_empty());
+    let mut skipped: Vec<_> = report.skipped.into_iter().map(|s| s.error).collect();
+    skipped.sort();
+    assert_eq!(skipped, vec!["Path not found: b", "Permission denied: a"]);
+    assert_eq!(repo.list_files(), vec!["a"]);
+
+    // Once readable, the file is recorded, and the vanished one is
+    // deleted.
+    wc.allow("a");
+    let (rec, report) = record(&wc, &changes, &txn, &channel, ReadErrorPolicy::Fail)?;
+    assert!(report.skipped.is_empty());
+    assert_eq!(rec.actions.len(), 2);
+    Ok(())
+}
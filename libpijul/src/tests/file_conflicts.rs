@@ -162,7 +162,7 @@ fn same_file_(file: &str, alice: &str, bob: &str) -> Result<(), anyhow::Error> {
 
     debug!("repo_alice = {:?}", repo_alice.list_files());
     debug!("repo_bob = {:?}", repo_bob.list_files());
-    debug_tree(&*txn_bob.read(), "debug_tree")?;
+    debug_tree(&*txn_bob.read(), debug_path("debug_tree"))?;
     Ok(())
 }
 
@@ -190,7 +190,7 @@ fn same_name_test() -> Result<(), anyhow::Error> {
     txn_alice.write().add_file("file2", 0)?;
     info!("recording file additions");
     debug!("working_copy = {:?}", repo_alice);
-    debug_tree(&*txn_alice.read(), "debug_tree")?;
+    debug_tree(&*txn_alice.read(), debug_path("debug_tree"))?;
     let init_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
 
     // Bob clones
@@ -317,7 +317,7 @@ fn file_conflicts_same_name_and_two_names() -> Result<(), anyhow::Error> {
     txn_alice.write().add_file("file2", 0)?;
     info!("recording file additions");
     debug!("working_copy = {:?}", repo_alice);
-    debug_tree(&*txn_alice.read(), "debug_tree")?;
+    debug_tree(&*txn_alice.read(), debug_path("debug_tree"))?;
     let init_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
 
     // Bob clones and renames "file2" to "file"
@@ -1562,3 +1562,92 @@ fn move_into_deleted_test() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+/// A file renamed by Alice and edited by Bob under its old name is
+/// reported when applying either change on top of the other.
+#[test]
+fn rename_edit_report_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let repo_bob = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+
+    let channel_alice = txn_alice.write().open_or_create_channel("alice").unwrap();
+    txn_alice.write().add_file("file", 0).unwrap();
+    let init_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    // Bob clones
+    let channel_bob = txn_bob.write().open_or_create_channel("bob").unwrap();
+    apply::apply_change_arc(&changes, &txn_bob, &channel_bob, &init_h).unwrap();
+    output::output_repository_no_pending(
+        &repo_bob,
+        &changes,
+        &txn_bob,
+        &channel_bob,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+
+    // Alice renames "file" to "renamed"
+    repo_alice.rename("file", "renamed")?;
+    txn_alice.write().move_file("file", "renamed", 0)?;
+    let alice_h = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    // Bob edits "file"
+    repo_bob.write_file("file")?.write_all(b"a\nx\nc\n")?;
+    let bob_h = record_all(&repo_bob, &changes, &txn_bob, &channel_bob, "")?;
+
+    let expected = vec![apply::RenameEditConflict {
+        edited_path: "file".to_string(),
+        current_path: "renamed".to_string(),
+        edit: bob_h,
+        rename: alice_h,
+    }];
+
+    // Alice applies Bob's edit
+    let (_, _, report) = apply::apply_change_report(
+        &changes,
+        &mut *txn_alice.write(),
+        &mut *channel_alice.write(),
+        &bob_h,
+    )?;
+    assert_eq!(report.rename_edit_conflicts, expected);
+
+    // Bob applies Alice's rename
+    let (_, _, report) = apply::apply_change_report(
+        &changes,
+        &mut *txn_bob.write(),
+        &mut *channel_bob.write(),
+        &alice_h,
+    )?;
+    assert_eq!(report.rename_edit_conflicts, expected);
+
+    // The same conflict is reported for changes already applied.
+    let report = apply::apply_report(
+        &changes,
+        &*txn_alice.read(),
+        &*channel_alice.read(),
+        &alice_h,
+    )?;
+    assert_eq!(report.rename_edit_conflicts, expected);
+
+    // Changes that know about each other aren't reported.
+    let report = apply::apply_report(
+        &changes,
+        &*txn_alice.read(),
+        &*channel_alice.read(),
+        &init_h,
+    )?;
+    assert!(report.rename_edit_conflicts.is_empty());
+    Ok(())
+}
@@ -1,5 +1,13 @@
 use super::*;
+use crate::change::{Author, ChangeHeader};
+use crate::channel::*;
+use crate::journal::*;
+use crate::output::{preflight, PreflightIssue, *};
+use crate::repository::{bootstrap, BootstrapError, Template};
+use crate::working_copy::WorkingCopy;
 use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
 fn filesystem() -> Result<(), anyhow::Error> {
@@ -9,7 +17,7 @@ fn filesystem() -> Result<(), anyhow::Error> {
     let repo = working_copy::filesystem::FileSystem::from_root(r.path());
 
     let f = tempfile::tempdir()?;
-    let changes = changestore::filesystem::FileSystem::from_root(f.path());
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 256);
 
     repo.write_file("dir/file")
         .unwrap()
@@ -45,7 +53,7 @@ fn symlink() -> Result<(), anyhow::Error> {
     let repo = working_copy::filesystem::FileSystem::from_root(r.path());
 
     let f = tempfile::tempdir()?;
-    let changes = changestore::filesystem::FileSystem::from_root(f.path());
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 256);
 
     repo.write_file("dir/file")
         .unwrap()
@@ -84,7 +92,7 @@ fn record_dead_symlink() -> Result<(), anyhow::Error> {
     let repo = working_copy::filesystem::FileSystem::from_root(r.path());
 
     let f = tempfile::tempdir()?;
-    let changes = changestore::filesystem::FileSystem::from_root(f.path());
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 256);
 
     std::fs::create_dir_all(&r.path().join("dir")).unwrap();
     std::os::unix::fs::symlink("../file", &r.path().join("dir/link")).unwrap();
@@ -115,7 +123,7 @@ fn overwrite_dead_symlink() -> Result<(), anyhow::Error> {
     let repo = working_copy::filesystem::FileSystem::from_root(r.path());
 
     let f = tempfile::tempdir()?;
-    let changes = changestore::filesystem::FileSystem::from_root(f.path());
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 256);
 
     repo.write_file("dir/file")
         .unwrap()
@@ -145,3 +153,705 @@ fn overwrite_dead_symlink() -> Result<(), anyhow::Error> {
     txn.commit().unwrap();
     Ok(())
 }
+
+#[test]
+fn output_hook() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 1);
+    repo.write_file("dir/file")?.write_all(&b"a\nb\n"[..])?;
+
+    let f = tempfile::tempdir()?;
+    let env = pristine::sanakirja::Pristine::new(f.path().join("pristine"))?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0).unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    let h0 = record_all(&repo, &changes, &txn, &channel, "").unwrap();
+
+    let r2 = tempfile::tempdir()?;
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let repo2 = working_copy::filesystem::FileSystem::from_root(r2.path()).with_output_hook(
+        move |path, meta| {
+            seen_
+                .lock()
+                .unwrap()
+                .push((path.to_path_buf(), meta.is_dir()));
+            Ok(())
+        },
+    );
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main").unwrap();
+    apply::apply_change_arc(&changes, &txn2, &channel2, &h0)?;
+    output::output_repository_no_pending(&repo2, &changes, &txn2, &channel2, "", true, None, 1, 0)
+        .unwrap();
+    {
+        let seen = seen.lock().unwrap();
+        assert!(seen
+            .iter()
+            .any(|(p, is_dir)| !is_dir && p.ends_with("file")));
+        assert!(seen.iter().any(|(_, is_dir)| *is_dir));
+    }
+
+    // Moved paths are output under a temporary name, but the hook
+    // only sees their final names.
+    std::fs::rename(r.path().join("dir"), r.path().join("dir2"))?;
+    txn.write().move_file("dir", "dir2", 0)?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "").unwrap();
+    apply::apply_change_arc(&changes, &txn2, &channel2, &h1)?;
+    seen.lock().unwrap().clear();
+    output::output_repository_no_pending(&repo2, &changes, &txn2, &channel2, "", true, None, 1, 0)
+        .unwrap();
+    let seen = seen.lock().unwrap();
+    assert!(!seen.is_empty());
+    for (p, _) in seen.iter() {
+        assert!(std::fs::symlink_metadata(p).is_ok(), "{:?}", p);
+    }
+    Ok(())
+}
+
+/// Output synchronises the files it writes in a single batch, and
+/// paths that vanished in the meantime are skipped.
+#[test]
+fn output_durability() -> Result<(), anyhow::Error> {
+    use crate::durability::{Durability, SyncBatch};
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), 1);
+    repo.write_file("dir/file")?.write_all(&b"a\nb\n"[..])?;
+    repo.write_file("other")?.write_all(&b"c\n"[..])?;
+
+    let f = tempfile::tempdir()?;
+    let env = pristine::sanakirja::Pristine::new(f.path().join("pristine"))?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0).unwrap();
+    txn.write().add_file("other", 0).unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    record_all(&repo, &changes, &txn, &channel, "").unwrap();
+
+    for durability in [Durability::FsyncData, Durability::FsyncAll].iter() {
+        let r2 = tempfile::tempdir()?;
+        let repo2 =
+            working_copy::filesystem::FileSystem::from_root(r2.path()).with_durability(*durability);
+        output::output_repository_no_pending(
+            &repo2, &changes, &txn, &channel, "", true, None, 1, 0,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(r2.path().join("dir/file"))?, b"a\nb\n");
+        assert_eq!(std::fs::read(r2.path().join("other"))?, b"c\n");
+    }
+
+    let batch = SyncBatch::new(Durability::FsyncAll);
+    batch.push(&r.path().join("other"));
+    batch.push(&r.path().join("vanished"));
+    batch.sync()?;
+
+    // The policy can be changed for a single operation, and is shared
+    // by the clones of a working copy.
+    let batch = SyncBatch::new(Durability::None);
+    batch.push(&r.path().join("other"));
+    batch.set_durability(Durability::FsyncData);
+    assert_eq!(batch.durability(), Durability::FsyncData);
+    batch.push(&r.path().join("dir/file"));
+    batch.sync()?;
+    let repo3 = repo.clone();
+    repo3.set_durability(Durability::FsyncAll);
+    repo.touched("other");
+    repo.sync()?;
+    Ok(())
+}
+
+#[test]
+fn watch_channel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = Arc::new(pristine::sanakirja::Pristine::new_anon()?);
+    {
+        let mut txn = env.mut_txn_begin()?;
+        txn.open_or_create_channel("main")?;
+        txn.commit()?;
+    }
+
+    let env_ = env.clone();
+    let mut w = watch(move || env_.txn_begin(), "main")?;
+    let initial = w.state();
+    assert_eq!(w.wait_timeout(Duration::from_millis(10))?, None);
+
+    let waiter = std::thread::spawn(move || w.wait().unwrap());
+
+    let txn = env.arc_txn_begin()?;
+    let channel = txn.read().load_channel("main")?.unwrap();
+    repo.add_file("a", b"a\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let state = txn.read().current_state(&*channel.read())?;
+    txn.commit()?;
+
+    let seen = waiter.join().unwrap();
+    assert_ne!(seen, initial);
+    assert_eq!(seen, state);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Store(HashMap<String, (ObjectKind, Vec<u8>)>);
+
+impl ObjectStore for Store {
+    type Error = std::io::Error;
+    fn put(
+        &mut self,
+        key: &str,
+        kind: ObjectKind,
+        contents: &[u8],
+        _meta: SinkMetadata,
+    ) -> Result<(), Self::Error> {
+        self.0.insert(key.to_string(), (kind, contents.to_vec()));
+        Ok(())
+    }
+}
+
+/// Write the same channel to different sinks.
+#[test]
+fn sinks() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\n".to_vec());
+    repo.add_file("top", b"c\n".to_vec());
+    repo.add_symlink("dir/link", "file");
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("dir/file", 0)?;
+    txn.write().add_file("top", 0)?;
+    txn.write().add_file("dir/link", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let mut mem = MemorySink::default();
+    let conflicts = txn.read().archive(&changes, &channel, &mut mem)?;
+    assert!(conflicts.is_empty());
+    match mem.entries.get("dir/file") {
+        Some(MemoryEntry::File { contents, .. }) => assert_eq!(contents, b"a\nb\n"),
+        e => panic!("{:?}", e),
+    }
+    match mem.entries.get("top") {
+        Some(MemoryEntry::File { contents, .. }) => assert_eq!(contents, b"c\n"),
+        e => panic!("{:?}", e),
+    }
+    match mem.entries.get("dir/link") {
+        Some(MemoryEntry::Symlink { target, .. }) => assert_eq!(target, "file"),
+        e => panic!("{:?}", e),
+    }
+
+    let mut store = ObjectStoreSink::new(Store::default(), "previews/");
+    txn.read().archive(&changes, &channel, &mut store)?;
+    assert_eq!(
+        store.store.0.get("previews/dir/file"),
+        Some(&(ObjectKind::File, b"a\nb\n".to_vec()))
+    );
+
+    let checkout = working_copy::memory::Memory::new();
+    let mut sink = WorkingCopySink::new(&checkout);
+    txn.read().archive(&changes, &channel, &mut sink)?;
+    let mut buf = Vec::new();
+    checkout.read_file("dir/file", &mut buf)?;
+    assert_eq!(buf, b"a\nb\n");
+    assert!(checkout.file_metadata("dir/link")?.is_symlink());
+    Ok(())
+}
+
+/// Export the tree of a repository, rebuild its pristine from the
+/// changes, and import the tree into the new pristine.
+#[test]
+fn tree_export_import() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/a", b"a\n".to_vec());
+    repo.add_file("b", b"b\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("dir/a", 0)?;
+    txn.write().add_file("b", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+    // Tracked, but not recorded.
+    repo.add_file("c", b"c\n".to_vec());
+    txn.write().add_file("c", 0)?;
+
+    let mut exported = Vec::new();
+    assert_eq!(crate::tree_export::export(&*txn.read(), &mut exported)?, 4);
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    txn2.write()
+        .apply_change(&changes, &mut *channel2.write(), &h)?;
+    let report = crate::tree_export::import(&mut *txn2.write(), &exported[..])?;
+    assert_eq!(report.entries, 4);
+    assert!(report.unassociated.is_empty());
+
+    for path in ["dir", "dir/a", "b", "c"].iter() {
+        let inode = fs::find_inode(&*txn.read(), path)?;
+        assert_eq!(fs::find_inode(&*txn2.read(), path)?, inode);
+        assert_eq!(
+            txn.read().get_inodes(&inode, None)?.cloned(),
+            txn2.read().get_inodes(&inode, None)?.cloned()
+        );
+    }
+    // Importing twice is refused.
+    assert!(crate::tree_export::import(&mut *txn2.write(), &exported[..]).is_err());
+
+    // Only the unrecorded file is left to record.
+    let mut builder = Builder::new();
+    builder.record(
+        txn2.clone(),
+        Algorithm::default(),
+        channel2.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let rec = builder.finish()?;
+    assert_eq!(rec.actions.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn snapshot_verify() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path());
+    let changes_dir = r.path().join(DOT_DIR).join("changes");
+    let changes = changestore::filesystem::FileSystem::from_changes(changes_dir.clone(), 1);
+    repo.write_file("file")?.write_all(&b"a\nb\n"[..])?;
+
+    let db = r.path().join(DOT_DIR).join("pristine").join("db");
+    std::fs::create_dir_all(db.parent().unwrap())?;
+    let env = pristine::sanakirja::Pristine::new(&db)?;
+    {
+        let txn = env.arc_txn_begin().unwrap();
+        txn.write().add_file("file", 0).unwrap();
+        let channel = txn.write().open_or_create_channel("main").unwrap();
+        record_all(&repo, &changes, &txn, &channel, "").unwrap();
+        txn.commit().unwrap();
+    }
+
+    let dest = tempfile::tempdir()?;
+    let manifest = {
+        let txn = env.txn_begin().unwrap();
+        // Writers aren't blocked by the backup.
+        let mut w = env.mut_txn_begin().unwrap();
+        w.open_or_create_channel("other").unwrap();
+        w.commit().unwrap();
+        crate::backup::snapshot(&txn, &db, &changes_dir, dest.path())?
+    };
+    assert_eq!(manifest.files.len(), 2);
+    assert!(crate::backup::verify(dest.path())?.is_empty());
+
+    let change = &manifest.files[1].path;
+    std::fs::remove_file(dest.path().join(change))?;
+    assert_eq!(&crate::backup::verify(dest.path())?, &[change.clone()]);
+    Ok(())
+}
+
+/// Bootstrapping the same template twice yields the same initial
+/// change.
+#[test]
+fn bootstrap_template() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let mut author = std::collections::BTreeMap::new();
+    author.insert("name".to_string(), "Generator".to_string());
+    let template = Template {
+        config: Some("[hooks]\n".to_string()),
+        dirs: vec!["empty".to_string()],
+        header: ChangeHeader {
+            message: "Initial commit".to_string(),
+            description: None,
+            timestamp: chrono::DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z")?.into(),
+            authors: vec![Author(author)],
+        },
+        ..Template::default()
+    }
+    .file("src/main.rs", b"fn main() {}\n")
+    .file("README", b"a\nb\n")
+    .executable("bin/run", b"#!/bin/sh\n");
+
+    let a = tempfile::tempdir()?;
+    let b = tempfile::tempdir()?;
+    let ra = bootstrap(a.path(), &template)?;
+    let rb = bootstrap(b.path(), &template)?;
+    assert!(ra.hash.is_some());
+    assert_eq!(ra.hash, rb.hash);
+
+    let change = ra.changes.get_change(ra.hash.as_ref().unwrap())?;
+    assert_eq!(change.hashed.header, template.header);
+    assert!(change.hashed.dependencies.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(a.path().join(DOT_DIR).join("config"))?,
+        "[hooks]\n"
+    );
+    assert!(ra.working_copy.file_metadata("bin/run")?.0 & 0o100 != 0);
+
+    let txn = ra.pristine.txn_begin()?;
+    let channel = txn.load_channel("main")?.unwrap();
+    assert_eq!(txn.log(&*channel.read(), 0)?.count(), 1);
+    for p in ["README", "bin/run", "empty", "src/main.rs"].iter() {
+        assert!(txn.is_tracked(p)?);
+    }
+
+    assert!(matches!(
+        bootstrap(a.path(), &template),
+        Err(BootstrapError::AlreadyInRepository(_))
+    ));
+    Ok(())
+}
+
+/// Bootstrapping refuses to nest repositories or to overwrite files,
+/// and leaves nothing behind when it fails.
+#[test]
+fn bootstrap_safety() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let template = Template::default().file("README", b"a\n");
+    let a = tempfile::tempdir()?;
+    bootstrap(a.path(), &template)?;
+    assert!(matches!(
+        bootstrap(a.path().join("sub"), &template),
+        Err(BootstrapError::AlreadyInRepository(_))
+    ));
+
+    let b = tempfile::tempdir()?;
+    std::fs::write(b.path().join("README"), "mine\n")?;
+    assert!(matches!(
+        bootstrap(b.path(), &template),
+        Err(BootstrapError::WouldOverwrite(_))
+    ));
+    assert_eq!(std::fs::read_to_string(b.path().join("README"))?, "mine\n");
+    assert!(std::fs::metadata(b.path().join(DOT_DIR)).is_err());
+
+    // "x" can't be both a file and a directory.
+    let c = tempfile::tempdir()?;
+    std::fs::write(c.path().join("kept"), "")?;
+    let template = Template::default().file("x", b"").file("x/y", b"");
+    assert!(bootstrap(c.path(), &template).is_err());
+    let left: Vec<_> = std::fs::read_dir(c.path())?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(left, vec![std::ffi::OsString::from("kept")]);
+    Ok(())
+}
+
+/// Symbolic links are recorded with their targets as contents, and
+/// output as links.
+#[test]
+fn symlink_output() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.add_file("dir/file", b"a\nb\n".to_vec());
+    repo.add_symlink("dir/link", "file");
+    txn.write().add_file("dir/file", 0)?;
+    txn.write().add_file("dir/link", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    assert!(repo2.file_metadata("dir/link")?.is_symlink());
+    assert!(!repo2.file_metadata("dir/file")?.is_symlink());
+    let mut target = Vec::new();
+    repo2.read_file("dir/link", &mut target)?;
+    assert_eq!(target, b"file");
+
+    // Retargetting the link is an edit of its contents.
+    repo.add_symlink("dir/link", "../other");
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(change.changes.len(), 1);
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    assert!(repo2.file_metadata("dir/link")?.is_symlink());
+    target.clear();
+    repo2.read_file("dir/link", &mut target)?;
+    assert_eq!(target, b"../other");
+    Ok(())
+}
+
+/// File system working copies only record and output links if
+/// asked to, and never output links leaving the working copy.
+#[test]
+fn symlinks_opt_in() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let r = tempfile::tempdir()?;
+    let repo = working_copy::filesystem::FileSystem::from_root(r.path()).with_symlinks(true);
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    repo.write_file("dir/file")?.write_all(b"a\n")?;
+    std::os::unix::fs::symlink("file", r.path().join("dir/link"))?;
+    assert!(repo.file_metadata("dir/link")?.is_symlink());
+    let following = working_copy::filesystem::FileSystem::from_root(r.path());
+    assert!(!following.file_metadata("dir/link")?.is_symlink());
+    txn.write().add_file("dir/file", 0)?;
+    txn.write().add_file("dir/link", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let r2 = tempfile::tempdir()?;
+    let repo2 = working_copy::filesystem::FileSystem::from_root(r2.path()).with_symlinks(true);
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    assert_eq!(
+        std::fs::read_link(r2.path().join("dir/link"))?,
+        std::path::Path::new("file")
+    );
+
+    let r3 = tempfile::tempdir()?;
+    let repo3 = working_copy::filesystem::FileSystem::from_root(r3.path());
+    output::output_repository_no_pending(&repo3, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let link = r3.path().join("dir/link");
+    assert!(!std::fs::symlink_metadata(&link)?.file_type().is_symlink());
+    assert_eq!(std::fs::read(&link)?, b"file");
+
+    // Links leaving the working copy are refused.
+    for target in &["/etc", "../../etc", "x/../../.."] {
+        std::fs::remove_file(r.path().join("dir/link"))?;
+        std::os::unix::fs::symlink(target, r.path().join("dir/link"))?;
+        record_all(&repo, &changes, &txn, &channel, "")?;
+        let r4 = tempfile::tempdir()?;
+        let repo4 = working_copy::filesystem::FileSystem::from_root(r4.path()).with_symlinks(true);
+        assert!(output::output_repository_no_pending(
+            &repo4, &changes, &txn, &channel, "", true, None, 1, 0
+        )
+        .is_err());
+        assert!(std::fs::symlink_metadata(r4.path().join("dir/link")).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn journal_recover() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    let state = txn2.read().current_state(&*channel2.read())?;
+
+    // An interrupted apply, rolled back.
+    let intent = begin(&mut *txn2.write(), "main", Operation::Apply, &[h])?.unwrap();
+    assert_eq!(intent.prior_state, state);
+    assert_eq!(
+        touched_paths(&changes, &intent)?,
+        Some(vec!["a".to_string()])
+    );
+    assert!(matches!(
+        begin(&mut *txn2.write(), "main", Operation::Apply, &[h]),
+        Err(JournalError::Unfinished(_))
+    ));
+    assert_eq!(
+        pending(&*txn2.read())?,
+        vec![("main".to_string(), intent.clone())]
+    );
+    let recovered = recover(&mut *txn2.write(), &changes, &channel2, Direction::Back, 0)?;
+    assert_eq!(recovered, Some(intent));
+    assert!(pending(&*txn2.read())?.is_empty());
+    assert_eq!(txn2.read().current_state(&*channel2.read())?, state);
+
+    // An interrupted apply, rolled forward.
+    begin(&mut *txn2.write(), "main", Operation::Apply, &[h])?;
+    recover(
+        &mut *txn2.write(),
+        &changes,
+        &channel2,
+        Direction::Forward,
+        0,
+    )?;
+    assert!(txn2.read().has_change(&channel2, &h)?.is_some());
+    assert!(recover(
+        &mut *txn2.write(),
+        &changes,
+        &channel2,
+        Direction::Forward,
+        0
+    )?
+    .is_none());
+
+    // Changes already applied aren't stored, and rolling back
+    // doesn't unrecord them.
+    assert!(begin(&mut *txn2.write(), "main", Operation::Apply, &[h])?.is_none());
+    assert!(pending(&*txn2.read())?.is_empty());
+
+    // An unrecord interrupted after reaching the pristine, rolled
+    // back.
+    begin(&mut *txn2.write(), "main", Operation::Unrecord, &[h])?;
+    txn2.write().unrecord(&changes, &channel2, &h, 0)?;
+    recover(&mut *txn2.write(), &changes, &channel2, Direction::Back, 0)?;
+    assert!(txn2.read().has_change(&channel2, &h)?.is_some());
+    Ok(())
+}
+
+/// Update a working copy between two states: only the files touched
+/// in between are written, moved or deleted.
+#[test]
+fn update_delta() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo_alice = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo_alice.add_file("a", b"a\nb\n".to_vec());
+    repo_alice.add_file("b", b"c\nd\n".to_vec());
+    repo_alice.add_file("dir/c", b"e\nf\n".to_vec());
+    repo_alice.add_file("untouched", b"g\n".to_vec());
+
+    let env_alice = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_alice = env_alice.arc_txn_begin().unwrap();
+    let channel_alice = txn_alice.write().open_or_create_channel("main")?;
+    for f in &["a", "b", "dir/c", "untouched"] {
+        txn_alice.write().add_file(f, 0)?;
+    }
+    let h0 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    repo_alice.write_file("a")?.write_all(b"a\nx\nb\n")?;
+    repo_alice.remove_path("b", false)?;
+    txn_alice.write().move_file("dir/c", "d", 0)?;
+    repo_alice.rename("dir/c", "d")?;
+    let h1 = record_all(&repo_alice, &changes, &txn_alice, &channel_alice, "")?;
+
+    let repo_bob = working_copy::memory::Memory::new();
+    let env_bob = pristine::sanakirja::Pristine::new_anon()?;
+    let txn_bob = env_bob.arc_txn_begin().unwrap();
+    let channel_bob = txn_bob.write().open_or_create_channel("main")?;
+    apply::apply_change_arc(&changes, &txn_bob, &channel_bob, &h0)?;
+    let s0 = txn_bob.read().current_state(&*channel_bob.read())?;
+    let up = output::update(
+        &repo_bob,
+        &changes,
+        &txn_bob,
+        &channel_bob,
+        &Merkle::zero(),
+        &s0,
+        1,
+    )?;
+    assert!(!up.full);
+    let mut files = repo_bob.list_files();
+    files.sort();
+    assert_eq!(files, &["a", "b", "dir", "dir/c", "untouched"]);
+
+    // Files that aren't touched aren't rewritten.
+    repo_bob.write_file("untouched")?.write_all(b"local\n")?;
+
+    apply::apply_change_arc(&changes, &txn_bob, &channel_bob, &h1)?;
+    let s1 = txn_bob.read().current_state(&*channel_bob.read())?;
+    match output::update(&repo_bob, &changes, &txn_bob, &channel_bob, &s1, &s0, 1) {
+        Err(output::UpdateError::NotCurrent(s)) => assert_eq!(s, s0),
+        r => panic!("{:?}", r),
+    }
+    let up = output::update(&repo_bob, &changes, &txn_bob, &channel_bob, &s0, &s1, 1)?;
+    debug!("{:?}", up);
+    assert!(!up.full);
+    assert!(up.output.iter().any(|p| p == "a"));
+    assert!(up.output.iter().any(|p| p == "d"));
+    assert!(up.output.iter().all(|p| p != "untouched"));
+    assert_eq!(up.removed, &["b"]);
+
+    let mut files = repo_bob.list_files();
+    files.sort();
+    assert_eq!(files, &["a", "d", "dir", "untouched"]);
+    let mut buf = Vec::new();
+    repo_bob.read_file("a", &mut buf)?;
+    assert_eq!(buf, b"a\nx\nb\n");
+    buf.clear();
+    repo_bob.read_file("d", &mut buf)?;
+    assert_eq!(buf, b"e\nf\n");
+    buf.clear();
+    repo_bob.read_file("untouched", &mut buf)?;
+    assert_eq!(buf, b"local\n");
+    Ok(())
+}
+
+#[test]
+fn preflight_issues() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\n".to_vec());
+    repo.add_file("B", b"B\n".to_vec());
+    repo.add_file("b", b"b\n".to_vec());
+    repo.add_file("dir/x", b"x\n".to_vec());
+    for f in ["a", "B", "b", "dir/x"].iter() {
+        txn.write().add_file(f, 0)?;
+    }
+    let h = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let env2 = pristine::sanakirja::Pristine::new_anon()?;
+    let txn2 = env2.arc_txn_begin().unwrap();
+    let channel2 = txn2.write().open_or_create_channel("main")?;
+    apply::apply_change_arc(&changes, &txn2, &channel2, &h)?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    let report = preflight(&*txn2.read(), &channel2, &changes, &repo2, &[])?;
+    assert!(report.is_ok());
+    assert_eq!(report.files, 4);
+    assert_eq!(report.bytes, 8);
+
+    repo2.add_file("a", b"local\n".to_vec());
+    repo2.set_available_space(Some(4));
+    let report = preflight(&*txn2.read(), &channel2, &changes, &repo2, &[])?;
+    assert!(report.issues.contains(&PreflightIssue::CaseConflict {
+        path: "B".to_string(),
+        other: "b".to_string(),
+    }));
+    assert!(report.issues.contains(&PreflightIssue::Untracked {
+        path: "a".to_string()
+    }));
+    assert!(report.issues.contains(&PreflightIssue::NotEnoughSpace {
+        needed: 8,
+        available: 4,
+    }));
+
+    // Only the files under `dir`.
+    repo2.set_readonly(true);
+    let report = preflight(&*txn2.read(), &channel2, &changes, &repo2, &["dir"])?;
+    assert_eq!(report.files, 1);
+    assert_eq!(
+        report.issues,
+        vec![PreflightIssue::ReadOnly {
+            path: "dir".to_string()
+        }]
+    );
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use super::*;
+use crate::fixtures::*;
+use crate::working_copy::WorkingCopy;
+
+#[test]
+fn fixture_conflicts() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let mut f = Fixture::new()?;
+    f.record("main", "init", |e| {
+        e.add_dir("dir")?;
+        e.write("dir/a", b"a\nb\n")
+    })?;
+    let forks = f.channels("main", 3)?;
+    assert_eq!(forks, vec!["main-1", "main-2", "main-3"]);
+
+    f.order_conflict("order", "file")?;
+    f.zombie_conflict("zombie", "file")?;
+    f.name_conflict("name", "file")?;
+    f.multiple_names_conflict("names", "file", "a", "b")?;
+    f.zombie_file_conflict("zombie_file", "file")?;
+    f.binary_file("main", "bin", 10_000, 1)?;
+    f.record("main", "rename", |e| e.rename("dir/a", "dir/b"))?;
+
+    assert!(f.switch("main")?.is_empty());
+    let mut buf = Vec::new();
+    f.working_copy.read_file("bin", &mut buf)?;
+    assert_eq!(buf, binary_contents(10_000, 1));
+    buf.clear();
+    f.working_copy.read_file("dir/b", &mut buf)?;
+    assert_eq!(buf, b"a\nb\n");
+
+    let has = |conflicts: &[Conflict], f: fn(&Conflict) -> bool| conflicts.iter().any(f);
+    let c = f.switch("order")?;
+    assert!(has(&c, |c| matches!(c, Conflict::Order { .. })));
+    let c = f.switch("zombie")?;
+    assert!(has(&c, |c| matches!(c, Conflict::Zombie { .. })));
+    let c = f.switch("name")?;
+    assert!(has(&c, |c| matches!(c, Conflict::Name { .. })));
+    let c = f.switch("names")?;
+    assert!(has(&c, |c| matches!(c, Conflict::MultipleNames { .. })));
+    let c = f.switch("zombie_file")?;
+    assert!(has(&c, |c| matches!(c, Conflict::ZombieFile { .. })));
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use super::*;
+use crate::fuse::*;
+
+/// Browse two channels without mounting them.
+#[test]
+fn browse_channels() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let main = txn.write().open_or_create_channel("main")?;
+    let empty = txn.write().open_or_create_channel("empty")?;
+    txn.write().add_file("dir/file", 0)?;
+    record_all(&repo, &changes, &txn, &main, "")?;
+
+    let views = vec![
+        View {
+            name: "main".to_string(),
+            channel: main,
+        },
+        View {
+            name: "empty".to_string(),
+            channel: empty,
+        },
+    ];
+    let mut fs = ChannelsFs::new(txn, changes, views, 10);
+    let root: Vec<_> = fs.read_dir(1)?.into_iter().map(|(n, _)| n).collect();
+    assert_eq!(root, vec!["empty", "main"]);
+
+    let empty = fs.lookup_name(1, "empty")?;
+    assert!(fs.read_dir(empty)?.is_empty());
+
+    let main = fs.lookup_name(1, "main")?;
+    let dir = fs.lookup_name(main, "dir")?;
+    let file = fs.lookup_name(dir, "file")?;
+    assert_eq!(&fs.read_file(file)?[..], b"a\nb\n");
+    assert!(fs.read_file(dir).is_err());
+    assert!(fs.lookup_name(dir, "missing").is_err());
+    Ok(())
+}
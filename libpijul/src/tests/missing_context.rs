@@ -272,7 +272,7 @@ fn missing_context_newedges() -> Result<(), anyhow::Error> {
             timestamp: chrono::Utc::now(),
         },
         Vec::new(),
-    );
+    ).unwrap();
     let inv_h = changes.save_change(&inv)?;
     // Alice applies Bob's inverse change.
     info!("Applying inverse change");
@@ -10,32 +10,41 @@ mod add_file;
 mod change;
 mod clone;
 mod conflict;
+mod degraded;
 mod diff;
 mod file_conflicts;
 mod filesystem;
+#[cfg(feature = "fixtures")]
+mod fixtures;
+#[cfg(feature = "fuse")]
+mod fuse;
 mod missing_context;
 mod partial;
 mod performance;
+mod resume;
 mod rm_file;
 mod rollback;
 mod text;
 mod unrecord;
 
-fn record_all_change<
-    T: MutTxnT + Send + Sync + 'static,
-    R: WorkingCopy + Clone + Send + Sync + 'static,
-    P: ChangeStore + Clone + Send + Sync + 'static,
->(
+/// Record the changes under `prefix` with `state`, configured by the
+/// caller, and apply them as a single change, even if it is empty.
+/// Returns the hash of the change, the change, and the rest of the
+/// recording, for instance to save its file statistics.
+fn record_with<T, R, P>(
+    mut state: Builder,
     repo: &R,
     store: &P,
     txn: &ArcTxn<T>,
     channel: &ChannelRef<T>,
     prefix: &str,
-) -> Result<(Hash, Change), anyhow::Error>
+) -> Result<(Hash, Change, crate::record::Recorded), anyhow::Error>
 where
+    T: MutTxnT + Send + Sync + 'static,
+    R: WorkingCopy + Clone + Send + Sync + 'static,
+    P: ChangeStore + Clone + Send + Sync + 'static,
     R::Error: Send + Sync + 'static,
 {
-    let mut state = Builder::new();
     state.record(
         txn.clone(),
         Algorithm::default(),
@@ -46,9 +55,8 @@ where
         1,
     )?;
 
-    let rec = state.finish();
-    let changes = rec
-        .actions
+    let mut rec = state.finish()?;
+    let changes = std::mem::take(&mut rec.actions)
         .into_iter()
         .map(|rec| rec.globalize(&*txn.read()).unwrap())
         .collect();
@@ -88,7 +96,30 @@ where
         &hash,
         &rec.updatables,
     )?;
-    Ok((hash, change0))
+    Ok((hash, change0, rec))
+}
+
+fn record_all_change<
+    T: MutTxnT + Send + Sync + 'static,
+    R: WorkingCopy + Clone + Send + Sync + 'static,
+    P: ChangeStore + Clone + Send + Sync + 'static,
+>(
+    repo: &R,
+    store: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    prefix: &str,
+) -> Result<(Hash, Change), anyhow::Error>
+where
+    R::Error: Send + Sync + 'static,
+{
+    let (hash, change, _) = record_with(Builder::new(), repo, store, txn, channel, prefix)?;
+    Ok((hash, change))
+}
+
+/// Path of the debugging dump `name`, outside of the crate.
+fn debug_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(name)
 }
 
 fn record_all<T: MutTxnT, R: WorkingCopy, P: ChangeStore>(
@@ -108,6 +139,26 @@ where
     Ok(hash)
 }
 
+/// Record and apply all the changes under `prefix`, saving the file
+/// statistics of the recording, and return the recorded hunks.
+fn record_all_stats<T, R, P>(
+    repo: &R,
+    store: &P,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    prefix: &str,
+) -> Result<Vec<crate::change::Hunk<Option<Hash>, crate::change::Local>>, anyhow::Error>
+where
+    T: MutTxnT + Send + Sync + 'static,
+    R: WorkingCopy + Clone + Send + Sync + 'static,
+    P: ChangeStore + Clone + Send + Sync + 'static,
+    R::Error: Send + Sync + 'static,
+{
+    let (_, change, rec) = record_with(Builder::new(), repo, store, txn, channel, prefix)?;
+    rec.save_file_stats(&mut *txn.write(), &*channel.read())?;
+    Ok(change.changes.clone())
+}
+
 fn record_all_output<
     T: MutTxnT + Send + Sync + 'static,
     R: WorkingCopy + Clone + Send + Sync + 'static,
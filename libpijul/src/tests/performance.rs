@@ -1,4 +1,5 @@
 use super::*;
+use crate::dirty_cache::{self, CacheEntry};
 use crate::working_copy::WorkingCopy;
 use std::io::Write;
 
@@ -45,8 +46,9 @@ fn quadratic_pseudo_edges() -> Result<(), anyhow::Error> {
     {
         let channel = channel.read();
         let mut m = 0;
-        let mut it = txn.read().iter_graph(&channel.graph, None).unwrap();
-        while let Some(Ok(_)) = txn.read().next_graph(&channel.graph, &mut it) {
+        let txn = txn.read();
+        for x in txn.iter_graph(&channel.graph, None).unwrap() {
+            x.unwrap();
             m += 1
         }
         let m0 = n * 8 + 6;
@@ -109,7 +111,7 @@ fn linear_context_repair() {
             txn.read().graph(&channel.read()),
             txn.read().graph(&channel2.read()),
         ],
-        "debug_sanakirja",
+        debug_path("debug_sanakirja"),
         true,
     );
 
@@ -137,8 +139,9 @@ fn linear_context_repair() {
     {
         let channel = channel.read();
         let mut m = 0;
-        let mut it = txn.read().iter_graph(&channel.graph, None).unwrap();
-        while let Some(Ok(_)) = txn.read().next_graph(&channel.graph, &mut it) {
+        let txn = txn.read();
+        for x in txn.iter_graph(&channel.graph, None).unwrap() {
+            x.unwrap();
             m += 1
         }
         debug!("m (channel, alice) = {:?}", m);
@@ -150,8 +153,9 @@ fn linear_context_repair() {
     {
         let channel = channel2.read();
         let mut m = 0;
-        let mut it = txn.read().iter_graph(&channel.graph, None).unwrap();
-        while let Some(Ok(_)) = txn.read().next_graph(&channel.graph, &mut it) {
+        let txn = txn.read();
+        for x in txn.iter_graph(&channel.graph, None).unwrap() {
+            x.unwrap();
             m += 1
         }
         debug!("m (channel2, bob) = {:?}", m);
@@ -162,3 +166,60 @@ fn linear_context_repair() {
     }
     txn.commit().unwrap();
 }
+
+/// Files found identical to the pristine are cached, and skipped as
+/// long as their size and modification time don't change.
+#[test]
+fn dirty_cache() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\nb\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    // Record as if the channel had never been touched, so that only
+    // the cache can skip files.
+    let record_cached = || {
+        txn.write().touch_channel(&mut *channel.write(), Some(0));
+        record_all_stats(&repo, &changes, &txn, &channel, "")
+    };
+    assert_eq!(record_cached()?.len(), 1);
+
+    let inode = crate::fs::find_inode(&*txn.read(), "a")?;
+    assert!(dirty_cache::load(&*txn.read(), inode)?.is_none());
+    assert!(record_cached()?.is_empty());
+    let entry = dirty_cache::load(&*txn.read(), inode)?.unwrap();
+    let stat = dirty_cache::stat(&repo, "a").unwrap();
+    assert_eq!(entry.size, 4);
+    assert_eq!(entry.mtime, stat.mtime);
+
+    // Edit the file behind the cache's back, keeping its size and
+    // pretending its modification time didn't change: the edit isn't
+    // seen.
+    repo.write_file("a")?.write_all(b"a\nc\n")?;
+    let stat = dirty_cache::stat(&repo, "a").unwrap();
+    let trusted = CacheEntry {
+        mtime: stat.mtime,
+        written: (stat.mtime.0 + 1, 0),
+        ..entry
+    };
+    dirty_cache::save(&mut *txn.write(), inode, &trusted)?;
+    assert!(record_cached()?.is_empty());
+
+    // Entries written in the same second as the modification aren't
+    // trusted.
+    let racy = CacheEntry {
+        written: stat.mtime,
+        ..trusted
+    };
+    dirty_cache::save(&mut *txn.write(), inode, &racy)?;
+    assert_eq!(record_cached()?.len(), 1);
+
+    // Files of another size are always diffed.
+    repo.write_file("a")?.write_all(b"a\nc\nd\n")?;
+    assert_eq!(record_cached()?.len(), 1);
+    Ok(())
+}
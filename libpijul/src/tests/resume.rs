@@ -0,0 +1,173 @@
+use super::*;
+use crate::change::Hunk;
+use crate::record::{CancellationToken, RecordError, RecordObserver, SessionError};
+use std::sync::Arc;
+
+/// A recording saved after its first prefix is resumed in a new
+/// builder, and finished as if it had been recorded at once.
+#[test]
+fn record_session() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a/x", b"x\n".to_vec());
+    repo.add_file("b/y", b"y\ny\n".to_vec());
+    for f in ["a/x", "b/y"].iter() {
+        txn.write().add_file(f, 0)?;
+    }
+    let dir = tempfile::tempdir()?;
+
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "a",
+        1,
+    )?;
+    state.save_session(&*txn.read(), &*channel.read(), dir.path(), b"selection")?;
+    std::mem::drop(state);
+
+    let (mut state, data) = Builder::resume_session(&*txn.read(), &*channel.read(), dir.path())?;
+    assert_eq!(data, b"selection");
+    assert_eq!(state.recorded_prefixes(), &["a".to_string()]);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "b",
+        1,
+    )?;
+    assert_eq!(
+        state.recorded_prefixes(),
+        &["a".to_string(), "b".to_string()]
+    );
+    let rec = state.finish()?;
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = crate::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )?;
+    let hash = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &hash, &rec.updatables)?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut files = repo2.list_files();
+    files.sort();
+    assert_eq!(files, vec!["a", "a/x", "b", "b/y"]);
+    let mut y = Vec::new();
+    repo2.read_file("b/y", &mut y)?;
+    assert_eq!(y, b"y\ny\n");
+
+    // The channel has changed since the session was saved.
+    match Builder::resume_session(&*txn.read(), &*channel.read(), dir.path()) {
+        Err(SessionError::ChannelChanged(c)) => assert_eq!(c, "main"),
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("resumed an outdated session"),
+    }
+    Ok(())
+}
+
+struct CancelOn(&'static str, CancellationToken);
+
+impl RecordObserver for CancelOn {
+    fn file_scanned(&self, path: &str) {
+        if path == self.0 {
+            self.1.cancel()
+        }
+    }
+}
+
+/// A checkpointed recording interrupted in its second directory is
+/// resumed with its options, without recording the first directory
+/// again, and sessions are refused once the tracked files changed.
+#[test]
+fn record_checkpoint() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a/x", b"x\n".to_vec());
+    repo.add_file("b/y", b"y\ny\n".to_vec());
+    for f in ["a/x", "b/y"].iter() {
+        txn.write().add_file(f, 0)?;
+    }
+    let dir = tempfile::tempdir()?;
+
+    let mut state = Builder::new();
+    state.checkpoint = Some(dir.path().to_path_buf());
+    state.word_diff = true;
+    state.observer = Some(Arc::new(CancelOn("b", state.cancellation.clone())));
+    match state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    ) {
+        Err(RecordError::Cancelled) => {}
+        r => panic!("{:?}", r.err()),
+    }
+    std::mem::drop(state);
+
+    let (mut state, _) = Builder::resume_session(&*txn.read(), &*channel.read(), dir.path())?;
+    assert!(state.word_diff);
+    assert_eq!(state.checkpoint.as_deref(), Some(dir.path()));
+    assert_eq!(state.recorded_prefixes(), &["a".to_string()]);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert_eq!(
+        state.recorded_prefixes(),
+        &["a".to_string(), "b".to_string()]
+    );
+    let rec = state.finish()?;
+    let mut added: Vec<_> = rec
+        .actions
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileAdd { path, .. } => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+    added.sort();
+    assert_eq!(added, vec!["a", "a/x", "b", "b/y"]);
+
+    // Sessions refer to inodes, and can't be resumed once a file is
+    // moved.
+    txn.write().move_file("b/y", "b/z", 0)?;
+    match Builder::resume_session(&*txn.read(), &*channel.read(), dir.path()) {
+        Err(SessionError::TreeChanged) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("resumed an outdated session"),
+    }
+    Ok(())
+}
@@ -66,7 +66,7 @@ fn rollback_conflict_resolution_simple() {
             timestamp: chrono::Utc::now(),
         },
         Vec::new(),
-    );
+    ).unwrap();
     let h_inv = changes.save_change(&p_inv).unwrap();
     apply::apply_change_arc(&changes, &txn, &channela, &h_inv).unwrap();
 }
@@ -131,7 +131,7 @@ fn rollback_conflict_resolution_swap() -> Result<(), anyhow::Error> {
             timestamp: chrono::Utc::now(),
         },
         Vec::new(),
-    );
+    ).unwrap();
     let h_inv = changes.save_change(&p_inv)?;
     apply::apply_change_arc(&changes, &txn, &channelb, &h_inv)?;
 
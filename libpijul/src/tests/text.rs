@@ -1,5 +1,14 @@
 use super::*;
+use crate::change::Hunk;
+use crate::channel_settings::*;
+use crate::file_stats::Eol;
+use crate::text_encoding::{Encoding, EncodingDetector, Utf8Only};
 use crate::working_copy::WorkingCopy;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 #[test]
 fn add_non_utf8_file_test() -> Result<(), anyhow::Error> {
@@ -100,3 +109,288 @@ fn change_non_utf8_file_test() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+/// Reads `.txt` files as Windows-1252, and other files as UTF-8.
+struct Latin1Text;
+
+impl EncodingDetector for Latin1Text {
+    fn detect(&self, path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        if path.ends_with(".txt") {
+            Some(encoding_rs::WINDOWS_1252.into())
+        } else {
+            Utf8Only.detect(path, contents, last)
+        }
+    }
+}
+
+/// The encoding detector of the working copy decides which files are
+/// text, and in which encoding.
+#[test]
+fn encoding_detector() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    repo.set_encoding_detector(Latin1Text);
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    let files = [
+        ("a.txt", &b"caf\xe9\n"[..]),
+        ("b.dat", &b"caf\xe9\n"[..]),
+        ("c.dat", "café\n".as_bytes()),
+    ];
+    for (f, contents) in files.iter() {
+        repo.add_file(f, contents.to_vec());
+        txn.write().add_file(f, 0)?;
+    }
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let mut encodings: Vec<_> = change
+        .changes
+        .iter()
+        .filter_map(|h| match h {
+            Hunk::FileAdd { path, encoding, .. } => {
+                Some((path.as_str(), encoding.as_ref().map(|e| e.label())))
+            }
+            _ => None,
+        })
+        .collect();
+    encodings.sort();
+    assert_eq!(
+        encodings,
+        vec![
+            ("a.txt", Some("windows-1252")),
+            ("b.dat", None),
+            ("c.dat", Some("UTF-8"))
+        ]
+    );
+    Ok(())
+}
+
+/// Counts the files whose encoding is detected.
+#[derive(Clone, Default)]
+struct CountingDetector(Arc<AtomicUsize>);
+
+impl EncodingDetector for CountingDetector {
+    fn detect(&self, path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        crate::text_encoding::DefaultEncodingDetector.detect(path, contents, last)
+    }
+}
+
+/// Files still in the encoding stored in their statistics are recorded
+/// without detecting their encoding again.
+#[test]
+fn file_stats_skip_detection() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let detector = CountingDetector::default();
+    repo.set_encoding_detector(detector.clone());
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("text", b"a\nb\n".to_vec());
+    txn.write().add_file("text", 0)?;
+    repo.add_file("bin", b"a\0b".to_vec());
+    txn.write().add_file("bin", 0)?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(detector.0.load(Ordering::SeqCst), 2);
+
+    repo.write_file("text")?.write_all(b"a\nc\n")?;
+    repo.write_file("bin")?.write_all(b"a\0c")?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(detector.0.load(Ordering::SeqCst), 2);
+
+    // The text file is neither ASCII nor UTF-8 anymore, and the binary
+    // file doesn't have NUL bytes.
+    repo.write_file("text")?.write_all(b"a\n\xe9t\xe9\n")?;
+    repo.write_file("bin")?.write_all(b"ac")?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(detector.0.load(Ordering::SeqCst), 4);
+    let bin = crate::file_stats::get(&*txn.read(), "bin")?.unwrap();
+    assert!(!bin.is_binary());
+    Ok(())
+}
+
+/// Recording stores the encoding and line endings of the files it
+/// reads, and forgets those of deleted files.
+#[test]
+fn file_stats() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("crlf", b"a\r\nb\r\nc".to_vec());
+    txn.write().add_file("crlf", 0)?;
+    repo.add_file("lf", b"a\nb\n".to_vec());
+    txn.write().add_file("lf", 0)?;
+    let mut rng = ChaCha20Rng::seed_from_u64(1234);
+    let bin: Vec<u8> = (0..30_000).map(|_| rng.gen()).collect();
+    repo.add_file("bin", bin);
+    txn.write().add_file("bin", 0)?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+
+    let stats = crate::file_stats::list(&*txn.read())?;
+    let paths: Vec<_> = stats.iter().map(|(p, _)| p.as_str()).collect();
+    assert_eq!(paths, vec!["bin", "crlf", "lf"]);
+
+    let bin = &stats[0].1;
+    assert!(bin.is_binary());
+    assert_eq!(bin.size, 30_000);
+    let crlf = &stats[1].1;
+    assert!(!crlf.is_binary());
+    assert_eq!(crlf.eol(), Eol::Crlf);
+    assert_eq!(crlf.lines(), 3);
+    assert!(!crlf.final_eol);
+    let lf = crate::file_stats::get(&*txn.read(), "lf")?.unwrap();
+    assert_eq!(lf.eol(), Eol::Lf);
+    assert_eq!(lf.lines(), 2);
+
+    // Edit and delete files.
+    repo.write_file("lf")?.write_all(b"a\r\nb\n")?;
+    repo.remove_path("bin", false)?;
+    let inode = crate::fs::find_inode(&*txn.read(), "bin")?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    let lf = crate::file_stats::get(&*txn.read(), "lf")?.unwrap();
+    assert_eq!(lf.eol(), Eol::Mixed);
+    assert!(crate::file_stats::load(&*txn.read(), inode)?.is_none());
+    Ok(())
+}
+
+/// Files whose name or permissions changed are not diffed again when
+/// their contents are the ones last found identical to the pristine.
+#[test]
+fn file_stats_metadata_only() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    repo.add_file("a", b"a\nb\nc\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    let stats = crate::file_stats::get(&*txn.read(), "a")?.unwrap();
+    assert!(stats.verified.is_none());
+
+    // Nothing changed: the contents are now known to be in the pristine.
+    assert!(record_all_stats(&repo, &changes, &txn, &channel, "")?.is_empty());
+    let verified = crate::file_stats::get(&*txn.read(), "a")?
+        .unwrap()
+        .verified
+        .unwrap();
+
+    // Renaming the file only records a move.
+    repo.rename("a", "b")?;
+    txn.write().move_file("a", "b", 0)?;
+    let hunks = record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(hunks.len(), 1);
+    assert!(matches!(hunks[0], Hunk::FileMove { .. }));
+    let stats = crate::file_stats::get(&*txn.read(), "b")?.unwrap();
+    assert_eq!(stats.verified.unwrap().contents, verified.contents);
+
+    // Edits made along with a move are still recorded.
+    repo.write_file("b")?.write_all(b"a\nx\nc\n")?;
+    repo.rename("b", "c")?;
+    txn.write().move_file("b", "c", 0)?;
+    let hunks = record_all_stats(&repo, &changes, &txn, &channel, "")?;
+    assert_eq!(hunks.len(), 2);
+    assert!(crate::file_stats::get(&*txn.read(), "c")?
+        .unwrap()
+        .verified
+        .is_none());
+    Ok(())
+}
+
+/// Record with the settings of a channel, unless the builder
+/// overrides them.
+#[test]
+fn channel_settings() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let mut channel = txn.write().open_or_create_channel("main")?;
+    let other = txn.write().open_or_create_channel("other")?;
+
+    assert_eq!(
+        load(&*txn.read(), &*channel.read())?,
+        ChannelSettings::default()
+    );
+    let settings = ChannelSettings {
+        diff_algorithm: Some(Algorithm::Patience),
+        binary: None,
+        eol: Some(EolPolicy::Lf),
+    };
+    save(&mut *txn.write(), &*channel.read(), &settings)?;
+    assert_eq!(load(&*txn.read(), &*channel.read())?, settings);
+    assert_eq!(
+        load(&*txn.read(), &*other.read())?,
+        ChannelSettings::default()
+    );
+
+    // Settings follow the channel when it is renamed.
+    txn.write().rename_channel(&mut channel, "renamed")?;
+    assert_eq!(load(&*txn.read(), &*channel.read())?, settings);
+    let main = txn.write().open_or_create_channel("main")?;
+    assert_eq!(
+        load(&*txn.read(), &*main.read())?,
+        ChannelSettings::default()
+    );
+
+    repo.add_file("a", b"a\r\nb\r\n".to_vec());
+    txn.write().add_file("a", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let repo2 = working_copy::memory::Memory::new();
+    output::output_repository_no_pending(&repo2, &changes, &txn, &channel, "", true, None, 1, 0)?;
+    let mut a = Vec::new();
+    repo2.read_file("a", &mut a)?;
+    assert_eq!(a, b"a\nb\n");
+
+    // Nothing to record, since line endings are normalised.
+    let mut state = Builder::new();
+    state.force_rediff = true;
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish()?.actions.is_empty());
+
+    // Overriding the policy of the channel.
+    let mut state = Builder::new();
+    state.force_rediff = true;
+    state.eol_policy = Some(EolPolicy::Preserve);
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(!state.finish()?.actions.is_empty());
+
+    save(
+        &mut *txn.write(),
+        &*channel.read(),
+        &ChannelSettings::default(),
+    )?;
+    let id = *txn.read().id(&*channel.read());
+    assert!(txn.read().get_channel_settings(&id).unwrap().is_none());
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::channel::*;
 use crate::working_copy::WorkingCopy;
 use std::io::Write;
 
@@ -587,16 +588,18 @@ fn rollback_(delete_file: bool) -> Result<(), anyhow::Error> {
     // Rollback the deletion of -b-
     let p_del = changes.get_change(&h_del)?;
     debug!("p_del = {:#?}", p_del);
-    let p_inv = p_del.inverse(
-        &h_del,
-        crate::change::ChangeHeader {
-            authors: vec![],
-            message: "rollback".to_string(),
-            description: None,
-            timestamp: chrono::Utc::now(),
-        },
-        Vec::new(),
-    );
+    let p_inv = p_del
+        .inverse(
+            &h_del,
+            crate::change::ChangeHeader {
+                authors: vec![],
+                message: "rollback".to_string(),
+                description: None,
+                timestamp: chrono::Utc::now(),
+            },
+            Vec::new(),
+        )
+        .unwrap();
     let h_inv = changes.save_change(&p_inv)?;
     apply::apply_change_arc(&changes, &txn, &channel, &h_inv)?;
     let conflicts = output::output_repository_no_pending(
@@ -738,16 +741,18 @@ fn double_convoluted() -> Result<(), anyhow::Error> {
     apply::apply_change_arc(&changes, &txn, &channel2, &h1)?;
     let rollback = |h| {
         let p = changes.get_change(&h).unwrap();
-        let p_inv = p.inverse(
-            &h,
-            crate::change::ChangeHeader {
-                authors: vec![],
-                message: "rollback".to_string(),
-                description: None,
-                timestamp: chrono::Utc::now(),
-            },
-            Vec::new(),
-        );
+        let p_inv = p
+            .inverse(
+                &h,
+                crate::change::ChangeHeader {
+                    authors: vec![],
+                    message: "rollback".to_string(),
+                    description: None,
+                    timestamp: chrono::Utc::now(),
+                },
+                Vec::new(),
+            )
+            .unwrap();
         let h_inv = changes.save_change(&p_inv).unwrap();
         h_inv
     };
@@ -812,3 +817,155 @@ fn double_file() -> Result<(), anyhow::Error> {
     assert!(inodes.next().is_none());
     Ok(())
 }
+
+/// Soft-delete a channel, restore it, then delete it again and purge
+/// it.
+#[test]
+fn soft_delete_channel() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    let state = txn.read().current_state(&*channel.read())?;
+    std::mem::drop(channel);
+
+    let mut txn = txn.write();
+    let d = soft_delete(&mut *txn, "main")?;
+    assert_eq!(d.name, "main");
+    assert!(crate::channel::load_channel(&*txn, "main")?.is_none());
+    assert_eq!(deleted(&*txn)?, vec![d.clone()]);
+    for c in txn.iter_channels("")? {
+        let (name, channel) = c?;
+        assert_eq!(name.as_str(), "main");
+        assert!(is_deleted(&*txn, &*channel.read())?);
+    }
+    // The channel keeps its name, and isn't deleted twice.
+    assert!(soft_delete(&mut *txn, "main").is_err());
+
+    // A recent deletion isn't purged.
+    assert!(purge(&mut *txn, DEFAULT_RETENTION)?.is_empty());
+
+    let channel = undelete(&mut *txn, "main")?;
+    assert_eq!(txn.current_state(&*channel.read())?, state);
+    assert!(deleted(&*txn)?.is_empty());
+    std::mem::drop(channel);
+
+    soft_delete(&mut *txn, "main")?;
+    let purged = purge(&mut *txn, std::time::Duration::from_secs(0))?;
+    assert_eq!(purged.len(), 1);
+    assert!(deleted(&*txn)?.is_empty());
+    assert!(undelete(&mut *txn, "main").is_err());
+    Ok(())
+}
+
+/// Delete a line, insert another one in its place, and prune the
+/// pseudo-edge left by the deletion.
+#[test]
+fn prune_pseudo_edges() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\nc\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("dir/file")?.write_all(b"a\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("dir/file")?.write_all(b"a\nx\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let report = pristine::prune_pseudo_checked(&mut *txn.write(), &mut *channel.write())?;
+    debug!("{:?}", report);
+    assert!(report.files >= 2);
+
+    // Everything redundant was removed by the first pass.
+    let report = pristine::prune_pseudo_checked(&mut *txn.write(), &mut *channel.write())?;
+    assert_eq!(report.removed, 0);
+
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+    let mut buf = Vec::new();
+    repo.read_file("dir/file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nx\nd\n"));
+    Ok(())
+}
+
+/// Same as above, but remove the redundant edges while recording.
+#[test]
+fn record_redundant_cleanup() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\nc\nd\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("dir/file")?.write_all(b"a\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("dir/file")?.write_all(b"a\nx\nd\n")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("dir/file")?.write_all(b"a\nx\nd\ne\n")?;
+    let mut builder = Builder::new();
+    builder.record(
+        txn.clone(),
+        Algorithm::default(),
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    let mut rec = builder.finish()?;
+    let removed = rec.apply_redundant_cleanup(&mut *txn.write(), &mut *channel.write())?;
+    debug!("removed {:?}", removed);
+    assert!(rec.redundant.is_empty());
+    // Nothing is left to remove.
+    assert_eq!(
+        rec.apply_redundant_cleanup(&mut *txn.write(), &mut *channel.write())?,
+        0
+    );
+
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let change = Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        crate::change::ChangeHeader::default(),
+        Vec::new(),
+    )
+    .unwrap();
+    let h = changes.save_change(&change)?;
+    apply::apply_local_change(&mut *txn.write(), &channel, &change, &h, &rec.updatables)?;
+
+    let report = pristine::prune_pseudo_checked(&mut *txn.write(), &mut *channel.write())?;
+    debug!("{:?}", report);
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+    let mut buf = Vec::new();
+    repo.read_file("dir/file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nx\nd\ne\n"));
+    Ok(())
+}
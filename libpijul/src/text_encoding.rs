@@ -1,10 +1,24 @@
+//! Text encodings, and how they are detected.
+//!
+//! The encoding of each file is guessed when recording it, by the
+//! [`EncodingDetector`] of the working copy (see
+//! [`crate::working_copy::WorkingCopy::detect_encoding`]). Files for
+//! which no encoding is detected are recorded as binary files.
+use crate::chardetng::EncodingDetector as Chardetng;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt;
 
+/// The text encoding of a file.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Encoding(pub(crate) &'static encoding_rs::Encoding);
 
+impl From<&'static encoding_rs::Encoding> for Encoding {
+    fn from(encoding: &'static encoding_rs::Encoding) -> Self {
+        Encoding(encoding)
+    }
+}
+
 impl Encoding {
     pub(crate) fn for_label(label: &str) -> Encoding {
         Encoding(encoding_rs::Encoding::for_label_no_replacement(label.as_bytes()).unwrap())
@@ -23,6 +37,76 @@ impl Encoding {
     }
 }
 
+/// Detects the encoding of files. Embedders can implement this to
+/// force UTF-8, use another detection library, or decide from the
+/// file names, and install it on their working copy.
+pub trait EncodingDetector: Send + Sync {
+    /// The encoding of `contents`, the beginning of file `path`, or
+    /// `None` if it is binary. `last` is `true` if `contents` is the
+    /// whole file.
+    fn detect(&self, path: &str, contents: &[u8], last: bool) -> Option<Encoding>;
+}
+
+/// The default detector, using the heuristics of chardetng. Since
+/// these accept any sequence of bytes as windows-1252, contents with
+/// NUL bytes are considered binary.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultEncodingDetector;
+
+impl EncodingDetector for DefaultEncodingDetector {
+    fn detect(&self, _path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        if memchr::memchr(0, contents).is_some() {
+            return None;
+        }
+        let mut detector = Chardetng::new();
+        detector.feed(contents, last);
+        let (encoding, score) = detector.guess_assess(None, true);
+        if score {
+            Some(Encoding(encoding))
+        } else {
+            None
+        }
+    }
+}
+
+/// A detector treating valid UTF-8 as text, and everything else as
+/// binary.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8Only;
+
+impl EncodingDetector for Utf8Only {
+    fn detect(&self, _path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        match std::str::from_utf8(contents) {
+            Ok(_) => Some(Encoding(encoding_rs::UTF_8)),
+            // A truncated window may end in the middle of a character.
+            Err(e) if !last && e.error_len().is_none() => Some(Encoding(encoding_rs::UTF_8)),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Whether `contents`, found binary by an [`EncodingDetector`], is
+/// valid UTF-8 text with a few NUL bytes (at most one byte in 64),
+/// such as logs or dumps padded with NULs. `last` is `true` if
+/// `contents` is the whole file.
+pub(crate) fn mostly_text(contents: &[u8], last: bool) -> bool {
+    const MAX_NUL_RATIO: usize = 64;
+    let nuls = contents.iter().filter(|&&b| b == 0).count();
+    if nuls * MAX_NUL_RATIO > contents.len() {
+        return false;
+    }
+    match std::str::from_utf8(contents) {
+        Ok(_) => true,
+        Err(e) => !last && e.error_len().is_none(),
+    }
+}
+
+impl<D: EncodingDetector + ?Sized> EncodingDetector for std::sync::Arc<D> {
+    fn detect(&self, path: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        (**self).detect(path, contents, last)
+    }
+}
+
 impl Clone for Encoding {
     fn clone(&self) -> Self {
         Encoding(self.0)
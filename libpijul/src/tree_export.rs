@@ -0,0 +1,165 @@
+//! Export and import of the correspondence between the files of the
+//! working copy and the graph.
+//!
+//! The tree, revtree, inodes and revinodes tables are local to a
+//! pristine: inodes are allocated when files are added or output, and
+//! changes are referred to by internal identifiers. [`export`] writes
+//! them as JSON, with changes referred to by their hash, and
+//! [`import`] writes them back into another pristine with the same
+//! changes, such as one rebuilt after a corruption by applying all
+//! the changes again. The working copy is then recognised as is,
+//! without outputting it again.
+use crate::fs::{is_directory, iter_working_copy};
+use crate::pristine::*;
+use crate::small_string::SmallString;
+use crate::HashMap;
+
+/// A file or directory of the working copy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeEntry {
+    pub path: String,
+    pub inode: u64,
+    pub is_dir: bool,
+    /// Hash of the change that introduced the file, in base32, and
+    /// position of the file in that change. Absent if the file was
+    /// added but not recorded.
+    pub vertex: Option<(String, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeExport {
+    pub deterministic_inodes: bool,
+    /// The entries, parents first.
+    pub entries: Vec<TreeEntry>,
+}
+
+/// The result of an [`import`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub entries: usize,
+    /// Recorded files whose change isn't known to the pristine. They
+    /// are tracked again, but considered unrecorded.
+    pub unassociated: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum TreeExportError<T: std::error::Error + 'static> {
+    #[error(transparent)]
+    Txn(T),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Malformed tree entry: {0}")]
+    Malformed(String),
+    #[error("The tree of the pristine isn't empty")]
+    TreeNotEmpty,
+}
+
+impl<T: std::error::Error + 'static> From<TxnErr<T>> for TreeExportError<T> {
+    fn from(e: TxnErr<T>) -> Self {
+        TreeExportError::Txn(e.0)
+    }
+}
+
+/// Collect the tree of `txn`.
+pub fn export_tree<T: GraphTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+    txn: &T,
+) -> Result<TreeExport, TxnErr<T::GraphError>> {
+    let mut entries = Vec::new();
+    for x in iter_working_copy(txn, Inode::ROOT) {
+        let (inode, path) = x.map_err(TxnErr)?;
+        let vertex = if let Some(pos) = txn.get_inodes(&inode, None)? {
+            let h: Hash = txn.get_external(&pos.change)?.unwrap().into();
+            Some((h.to_base32(), u64::from(pos.pos.0)))
+        } else {
+            None
+        };
+        entries.push(TreeEntry {
+            path,
+            inode: u64::from(inode.0),
+            is_dir: is_directory(txn, inode)?,
+            vertex,
+        })
+    }
+    Ok(TreeExport {
+        deterministic_inodes: txn.inode_allocation() == InodeAllocation::Deterministic,
+        entries,
+    })
+}
+
+/// Write the tree of `txn` to `w`, as JSON.
+pub fn export<
+    T: GraphTxnT + TreeTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+    W: std::io::Write,
+>(
+    txn: &T,
+    w: W,
+) -> Result<usize, TreeExportError<T::GraphError>> {
+    let tree = export_tree(txn)?;
+    serde_json::to_writer_pretty(w, &tree)?;
+    Ok(tree.entries.len())
+}
+
+/// Read a tree written by [`export`] from `r`, and import it into
+/// `txn`, whose tree must be empty.
+pub fn import<
+    T: GraphTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>,
+    R: std::io::Read,
+>(
+    txn: &mut T,
+    r: R,
+) -> Result<ImportReport, TreeExportError<T::GraphError>> {
+    let tree: TreeExport = serde_json::from_reader(r)?;
+    import_tree(txn, &tree)
+}
+
+/// Import `tree` into `txn`, whose tree must be empty.
+pub fn import_tree<T: GraphTxnT + TreeMutTxnT<TreeError = <T as GraphTxnT>::GraphError>>(
+    txn: &mut T,
+    tree: &TreeExport,
+) -> Result<ImportReport, TreeExportError<T::GraphError>> {
+    if let Some(x) = iter_working_copy(txn, Inode::ROOT).next() {
+        x.map_err(TreeExportError::Txn)?;
+        return Err(TreeExportError::TreeNotEmpty);
+    }
+    txn.set_inode_allocation(if tree.deterministic_inodes {
+        InodeAllocation::Deterministic
+    } else {
+        InodeAllocation::Salted
+    });
+    let mut report = ImportReport::default();
+    let mut inodes = HashMap::default();
+    for e in tree.entries.iter() {
+        let malformed = || TreeExportError::Malformed(e.path.clone());
+        let basename = crate::path::file_name(&e.path).ok_or_else(malformed)?;
+        let parent_inode = match crate::path::parent(&e.path) {
+            Some(p) if !p.is_empty() => *inodes.get(p).ok_or_else(malformed)?,
+            _ => Inode::ROOT,
+        };
+        let inode = Inode(L64::from(e.inode));
+        if inode == Inode::ROOT || inodes.insert(e.path.as_str(), inode).is_some() {
+            return Err(malformed());
+        }
+        let id = OwnedPathId {
+            parent_inode,
+            basename: SmallString::from_str(basename),
+        };
+        put_tree_with_rev(txn, &id, &inode)?;
+        if e.is_dir {
+            txn.put_tree(&OwnedPathId::inode(inode), &inode)?;
+        }
+        if let Some((ref h, pos)) = e.vertex {
+            let h = Hash::from_base32(h.as_bytes()).ok_or_else(malformed)?;
+            if let Some(&change) = txn.get_internal(&h.into())? {
+                let pos = Position {
+                    change,
+                    pos: ChangePosition(L64::from(pos)),
+                };
+                put_inodes_with_rev(txn, &inode, &pos)?;
+            } else {
+                report.unassociated.push(e.path.clone())
+            }
+        }
+        report.entries += 1;
+    }
+    Ok(report)
+}
@@ -63,6 +63,7 @@ pub fn unrecord<T: MutTxnT, P: ChangeStore>(
     let mut channel = channel.write();
 
     del_channel_changes::<T, P>(txn, &mut channel, change_id)?;
+    crate::hunk_kind::apply_hunks(txn, &*channel, &change, true)?;
 
     unapply(txn, &mut channel, changes, change_id, &change, salt)?;
 
@@ -126,7 +126,7 @@ fn restore_inode<
             basename,
         };
         if txn.get_tree(&file_id, None)?.is_none() {
-            let inode = crate::fs::create_new_inode(txn, &file_id, salt)?;
+            let inode = crate::fs::create_inode_for(txn, &file_id, salt, dest)?;
             put_tree_with_rev(txn, &file_id, &inode)?;
             put_inodes_with_rev(txn, &inode, &dest)?;
             if metadata.is_dir() {
@@ -1,4 +1,6 @@
+use crate::output::ConflictId;
 use crate::pristine::*;
+use std::borrow::Cow;
 
 pub const START_MARKER: &str = "\n>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>\n";
 
@@ -6,6 +8,32 @@ pub const SEPARATOR: &str = "\n================================\n";
 
 pub const END_MARKER: &str = "\n<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<\n";
 
+/// The start marker of conflict `id`, which includes `id` if `ids`
+/// is set (see [`crate::output::ConflictOptions`]).
+pub fn start_marker(id: ConflictId, ids: bool) -> Cow<'static, str> {
+    if ids {
+        Cow::Owned(format!("{} {}\n", START_MARKER.trim_end(), id))
+    } else {
+        Cow::Borrowed(START_MARKER)
+    }
+}
+
+/// The identifier in start marker line `line`, if any.
+pub fn marker_id(line: &[u8]) -> Option<ConflictId> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let id = line.strip_prefix(START_MARKER.trim().as_bytes())?;
+    let id = id.strip_prefix(b" ")?;
+    std::str::from_utf8(id).ok()?.parse().ok()
+}
+
+/// Whether `line` is a start marker, with or without an identifier.
+pub fn is_start_marker(line: &[u8]) -> bool {
+    let l = line.strip_suffix(b"\n").unwrap_or(line);
+    let l = l.strip_suffix(b"\r").unwrap_or(l);
+    l == START_MARKER.trim().as_bytes() || marker_id(line).is_some()
+}
+
 /// A trait for outputting keys and their contents. This trait allows
 /// to retain more information about conflicts than directly
 /// outputting as bytes to a `Write`. The diff algorithm uses that
@@ -17,14 +45,14 @@ pub trait VertexBuffer {
         F: FnOnce(&mut Vec<u8>) -> Result<(), E>;
 
     fn output_conflict_marker(&mut self, s: &str) -> Result<(), std::io::Error>;
-    fn begin_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_conflict(&mut self, _id: ConflictId) -> Result<(), std::io::Error> {
         self.output_conflict_marker(START_MARKER)
     }
-    fn begin_zombie_conflict(&mut self) -> Result<(), std::io::Error> {
-        self.begin_conflict()
+    fn begin_zombie_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
+        self.begin_conflict(id)
     }
-    fn begin_cyclic_conflict(&mut self) -> Result<(), std::io::Error> {
-        self.begin_conflict()
+    fn begin_cyclic_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
+        self.begin_conflict(id)
     }
     fn conflict_next(&mut self) -> Result<(), std::io::Error> {
         self.output_conflict_marker(SEPARATOR)
@@ -47,6 +75,8 @@ pub(crate) struct ConflictsWriter<'a, 'b, W: std::io::Write> {
     pub path: &'b str,
     pub conflicts: &'a mut Vec<crate::output::Conflict>,
     pub buf: Vec<u8>,
+    /// Embed the identifiers of conflicts in their start markers.
+    pub ids: bool,
 }
 
 impl<'a, 'b, W: std::io::Write> ConflictsWriter<'a, 'b, W> {
@@ -58,6 +88,7 @@ impl<'a, 'b, W: std::io::Write> ConflictsWriter<'a, 'b, W> {
             path,
             conflicts,
             buf: Vec::new(),
+            ids: false,
         }
     }
 }
@@ -109,26 +140,26 @@ impl<'a, 'b, W: std::io::Write> VertexBuffer for ConflictsWriter<'a, 'b, W> {
         Ok(())
     }
 
-    fn begin_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         self.conflicts.push(crate::output::Conflict::Order {
             path: self.path.to_string(),
             line: self.lines,
         });
-        self.output_conflict_marker(START_MARKER)
+        self.output_conflict_marker(&start_marker(id, self.ids))
     }
-    fn begin_zombie_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_zombie_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         self.conflicts.push(crate::output::Conflict::Zombie {
             path: self.path.to_string(),
             line: self.lines,
         });
-        self.output_conflict_marker(START_MARKER)
+        self.output_conflict_marker(&start_marker(id, self.ids))
     }
-    fn begin_cyclic_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_cyclic_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         self.conflicts.push(crate::output::Conflict::Cyclic {
             path: self.path.to_string(),
             line: self.lines,
         });
-        self.output_conflict_marker(START_MARKER)
+        self.output_conflict_marker(&start_marker(id, self.ids))
     }
 }
 
@@ -196,26 +227,26 @@ impl<W: std::io::Write> VertexBuffer for Writer<W> {
         Ok(())
     }
 
-    fn begin_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_conflict(&mut self, _id: ConflictId) -> Result<(), std::io::Error> {
         self.output_conflict_marker(START_MARKER)
     }
     fn end_conflict(&mut self) -> Result<(), std::io::Error> {
         self.is_zombie = false;
         self.output_conflict_marker(END_MARKER)
     }
-    fn begin_zombie_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_zombie_conflict(&mut self, id: ConflictId) -> Result<(), std::io::Error> {
         if self.is_zombie {
             Ok(())
         } else {
             self.is_zombie = true;
-            self.begin_conflict()
+            self.begin_conflict(id)
         }
     }
     fn end_zombie_conflict(&mut self) -> Result<(), std::io::Error> {
         self.is_zombie = false;
         self.output_conflict_marker(END_MARKER)
     }
-    fn begin_cyclic_conflict(&mut self) -> Result<(), std::io::Error> {
+    fn begin_cyclic_conflict(&mut self, _id: ConflictId) -> Result<(), std::io::Error> {
         self.output_conflict_marker(START_MARKER)
     }
 }
@@ -1,13 +1,25 @@
 use super::*;
+use crate::durability::{Durability, SyncBatch};
 use crate::pristine::{ArcTxn, InodeMetadata};
 use canonical_path::{CanonicalPath, CanonicalPathBuf};
 use ignore::WalkBuilder;
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 
+/// A function called on each path written by output, along with
+/// its metadata.
+pub type OutputHook = dyn Fn(&Path, InodeMetadata) -> std::io::Result<()> + Send + Sync;
+
 #[derive(Clone)]
 pub struct FileSystem {
     root: PathBuf,
+    output_hook: Option<std::sync::Arc<OutputHook>>,
+    encoding_detector: Option<std::sync::Arc<dyn EncodingDetector>>,
+    full_permissions: bool,
+    symlinks: bool,
+    graph_limits: crate::alive::retrieve::RetrieveLimits,
+    conflict_options: crate::output::ConflictOptions,
+    sync: std::sync::Arc<SyncBatch>,
 }
 
 pub fn filter_ignore(root_: &CanonicalPath, path: &CanonicalPath, is_dir: bool) -> bool {
@@ -111,9 +123,101 @@ impl FileSystem {
     pub fn from_root<P: AsRef<Path>>(root: P) -> Self {
         FileSystem {
             root: root.as_ref().to_path_buf(),
+            output_hook: None,
+            encoding_detector: None,
+            full_permissions: false,
+            symlinks: false,
+            graph_limits: crate::alive::retrieve::RetrieveLimits::UNLIMITED,
+            conflict_options: crate::output::ConflictOptions::default(),
+            sync: std::sync::Arc::new(SyncBatch::default()),
         }
     }
 
+    /// Set a hook called on each file and directory written by
+    /// output, for instance to set their owner or SELinux context.
+    pub fn with_output_hook<
+        F: Fn(&Path, InodeMetadata) -> std::io::Result<()> + Send + Sync + 'static,
+    >(
+        mut self,
+        hook: F,
+    ) -> Self {
+        self.output_hook = Some(std::sync::Arc::new(hook));
+        self
+    }
+
+    /// Set the detector used to guess the encoding of files when
+    /// recording, instead of [`DefaultEncodingDetector`].
+    pub fn with_encoding_detector<D: EncodingDetector + 'static>(mut self, detector: D) -> Self {
+        self.encoding_detector = Some(std::sync::Arc::new(detector));
+        self
+    }
+
+    /// Record and restore all the Unix permissions of files and
+    /// directories, instead of just the executable bit, so that a
+    /// file readable by its owner only can be told from a file
+    /// readable by everyone. Files recorded without this option are
+    /// output as if it was off.
+    pub fn with_full_permissions(mut self, full: bool) -> Self {
+        self.full_permissions = full;
+        self
+    }
+
+    /// Record symbolic links as links, with their target as contents,
+    /// and output the links recorded as links. Off by default, in
+    /// which case links are followed, and recorded links are output
+    /// as files containing their target. Links to absolute paths or
+    /// to paths outside of the working copy are never output.
+    pub fn with_symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// The metadata of `path`, following symbolic links unless they
+    /// are recorded as links.
+    fn metadata(&self, path: &Path) -> Result<std::fs::Metadata, std::io::Error> {
+        if self.symlinks {
+            link_metadata(path)
+        } else {
+            std::fs::metadata(path)
+        }
+    }
+
+    /// Fail to output the files whose graphs exceed `limits`, see
+    /// [`crate::alive::retrieve::RetrieveLimits`].
+    pub fn with_graph_limits(mut self, limits: crate::alive::retrieve::RetrieveLimits) -> Self {
+        self.graph_limits = limits;
+        self
+    }
+
+    /// Render the conflicts of the files output to this working copy
+    /// with `options`, see [`crate::output::ConflictOptions`].
+    pub fn with_conflict_options(mut self, options: crate::output::ConflictOptions) -> Self {
+        self.conflict_options = options;
+        self
+    }
+
+    /// Make the files written by output durable according to
+    /// `durability`, see [`crate::durability`]. The clones of this
+    /// working copy share their batch of paths to synchronise.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.sync = std::sync::Arc::new(SyncBatch::new(durability));
+        self
+    }
+
+    /// Change the durability policy of this working copy and of its
+    /// clones, for instance for a single operation.
+    pub fn set_durability(&self, durability: Durability) {
+        self.sync.set_durability(durability)
+    }
+
+    /// Remember that `path` (relative to the root) was changed
+    /// outside of this working copy, for instance renamed along with
+    /// an update of the tree tables, so that the next
+    /// [`WorkingCopy::sync`] makes it durable.
+    pub fn touched(&self, path: &str) {
+        self.sync.push(&self.root.join(path))
+    }
+
     pub fn record_prefixes<
         T: crate::MutTxnTExt + crate::TxnTExt + Send + Sync + 'static,
         C: crate::changestore::ChangeStore + Clone + Send + 'static,
@@ -288,15 +392,20 @@ impl FileSystem {
             }
         }
         debug!("recording from prefix {:?}", prefix);
-        state.record(
-            txn.clone(),
-            crate::Algorithm::default(),
-            channel,
-            self,
-            changes,
-            &prefix,
-            1,
-        )?;
+        // Opaque directories are recorded as archives.
+        let working_copy =
+            crate::opaque::Opaque::new(self.clone()).map_err(crate::record::RecordError::Io)?;
+        state
+            .record(
+                txn.clone(),
+                crate::ChannelAlgorithm,
+                channel,
+                &working_copy,
+                changes,
+                &prefix,
+                1,
+            )
+            .map_err(|e| e.map_working_copy(std::io::Error::from))?;
         debug!("recorded");
         Ok(())
     }
@@ -312,23 +421,74 @@ impl WorkingCopy for FileSystem {
     type Error = std::io::Error;
     fn create_dir_all(&self, file: &str) -> Result<(), Self::Error> {
         debug!("create_dir_all {:?}", file);
-        Ok(std::fs::create_dir_all(&self.path(file))?)
+        let path = self.path(file);
+        std::fs::create_dir_all(&path)?;
+        self.sync.push(&path);
+        Ok(())
     }
     fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
         debug!("metadata {:?}", file);
-        let attr = std::fs::metadata(&self.path(file))?;
+        let attr = self.metadata(&self.path(file))?;
+        if attr.file_type().is_symlink() {
+            return Ok(InodeMetadata::new_symlink());
+        }
         let permissions = permissions(&attr).unwrap_or(0o700);
         debug!("permissions = {:?}", permissions);
-        Ok(InodeMetadata::new(permissions & 0o100, attr.is_dir()))
+        Ok(InodeMetadata::new(
+            permissions & self.permission_mask() as usize,
+            attr.is_dir(),
+        ))
+    }
+    fn permission_mask(&self) -> u16 {
+        if self.full_permissions {
+            0o777
+        } else {
+            0o100
+        }
     }
     fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
         use std::io::Read;
         debug!("read_file {:?}", file);
+        if self.symlinks && read_symlink(&self.path(file), buffer)? {
+            return Ok(());
+        }
         let mut f = std::fs::File::open(&self.path(file))?;
         f.read_to_end(buffer)?;
         Ok(())
     }
 
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(Some(self.metadata(&self.path(file))?.len()))
+    }
+
+    fn read_file_windows(
+        &self,
+        file: &str,
+        window: usize,
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), Self::Error> {
+        use std::io::Read;
+        debug!("read_file_windows {:?}", file);
+        let mut target = Vec::new();
+        if self.symlinks && read_symlink(&self.path(file), &mut target)? {
+            f(&target);
+            return Ok(());
+        }
+        let mut file = std::fs::File::open(&self.path(file))?;
+        let mut buf = vec![0; window];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if !f(&buf[..n]) {
+                return Ok(());
+            }
+        }
+    }
+
     #[cfg(not(unix))]
     fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
         debug!("modified_time {:?}", file);
@@ -340,7 +500,7 @@ impl WorkingCopy for FileSystem {
     fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
         debug!("modified_time {:?}", file);
         use std::os::unix::fs::MetadataExt;
-        let attr = std::fs::metadata(&self.path(file))?;
+        let attr = self.metadata(&self.path(file))?;
         let ctime =
             std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(attr.ctime() as u64);
         Ok(attr.modified()?.min(ctime))
@@ -349,7 +509,7 @@ impl WorkingCopy for FileSystem {
     fn remove_path(&self, path: &str, rec: bool) -> Result<(), Self::Error> {
         debug!("remove_path {:?}", path);
         let path = self.path(path);
-        if let Ok(meta) = std::fs::metadata(&path) {
+        if let Ok(meta) = link_metadata(&path) {
             if let Err(e) = if meta.is_dir() {
                 if rec {
                     std::fs::remove_dir_all(&path)
@@ -361,6 +521,7 @@ impl WorkingCopy for FileSystem {
             } {
                 info!("while deleting {:?}: {:?}", path, e);
             }
+            self.sync.push(&path);
         }
         Ok(())
     }
@@ -372,6 +533,8 @@ impl WorkingCopy for FileSystem {
             std::fs::create_dir_all(p)?
         }
         std::fs::rename(&former, &new)?;
+        self.sync.push(&former);
+        self.sync.push(&new);
         Ok(())
     }
     #[cfg(not(windows))]
@@ -379,13 +542,18 @@ impl WorkingCopy for FileSystem {
         use std::os::unix::fs::PermissionsExt;
         let name = self.path(name);
         debug!("set_permissions: {:?}", name);
-        let metadata = std::fs::metadata(&name)?;
+        let metadata = link_metadata(&name)?;
+        if metadata.file_type().is_symlink() {
+            return Ok(());
+        }
         let mut current = metadata.permissions();
         debug!(
             "setting mode for {:?} to {:?} (currently {:?})",
             name, permissions, current
         );
-        if permissions & 0o100 != 0 {
+        if self.full_permissions && InodeMetadata::is_full_permissions(permissions) {
+            current.set_mode((current.mode() & !0o777) | (permissions as u32 & 0o777));
+        } else if permissions & 0o100 != 0 {
             current.set_mode(current.mode() | 0o100);
         } else {
             current.set_mode(current.mode() & ((!0o777) | 0o666));
@@ -400,6 +568,60 @@ impl WorkingCopy for FileSystem {
         Ok(())
     }
 
+    fn output_hook(&self, name: &str, meta: InodeMetadata) -> Result<(), Self::Error> {
+        if let Some(ref hook) = self.output_hook {
+            hook(&self.path(name), meta)?
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Self::Error> {
+        self.sync.sync()
+    }
+
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        self.graph_limits
+    }
+
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.conflict_options
+    }
+
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        if let Some(ref detector) = self.encoding_detector {
+            detector.detect(file, contents, last)
+        } else {
+            DefaultEncodingDetector.detect(file, contents, last)
+        }
+    }
+
+    fn is_writable(&self, name: &str) -> Result<bool, Self::Error> {
+        let mut path = self.path(name);
+        loop {
+            match std::fs::metadata(&path) {
+                Ok(meta) => return Ok(!meta.permissions().readonly()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    if !path.pop() {
+                        return Ok(false);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(self.root.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
     type Writer = std::io::BufWriter<std::fs::File>;
     fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
         let path = self.path(file);
@@ -411,8 +633,86 @@ impl WorkingCopy for FileSystem {
         std::fs::remove_file(&path).unwrap_or(());
         let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
         debug!("file");
+        self.sync.push(&path);
         Ok(file)
     }
+
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.path(path))? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string())
+            }
+        }
+        names.sort();
+        Ok(Some(names))
+    }
+
+    fn subrepo_state(&self, path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        let db = self
+            .path(path)
+            .join(crate::DOT_DIR)
+            .join(crate::repository::PRISTINE_DIR)
+            .join("db");
+        if std::fs::metadata(&db).is_err() {
+            return Ok(None);
+        }
+        crate::subrepo::read_state(&db)
+            .map(Some)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    #[cfg(unix)]
+    fn create_symlink(&self, file: &str, target: &str) -> Result<bool, Self::Error> {
+        if !self.symlinks {
+            return Ok(false);
+        }
+        let path = self.path(file);
+        debug!("create_symlink {:?} -> {:?}", path, target);
+        if !crate::path::is_contained_link(file, target) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "Link {} points outside of the working copy: {}",
+                    file, target
+                ),
+            ));
+        }
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p)?
+        }
+        std::fs::remove_file(&path).unwrap_or(());
+        std::os::unix::fs::symlink(target, &path)?;
+        self.sync.push(&path);
+        Ok(true)
+    }
+}
+
+/// The metadata of `path`, without following symbolic links on Unix.
+#[cfg(unix)]
+fn link_metadata(path: &Path) -> Result<std::fs::Metadata, std::io::Error> {
+    std::fs::symlink_metadata(path)
+}
+#[cfg(not(unix))]
+fn link_metadata(path: &Path) -> Result<std::fs::Metadata, std::io::Error> {
+    std::fs::metadata(path)
+}
+
+/// Append the target of `path` to `buffer` if `path` is a symbolic
+/// link, and return whether it is.
+#[cfg(unix)]
+fn read_symlink(path: &Path, buffer: &mut Vec<u8>) -> Result<bool, std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    if link_metadata(path)?.file_type().is_symlink() {
+        buffer.extend_from_slice(std::fs::read_link(path)?.as_os_str().as_bytes());
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+#[cfg(not(unix))]
+fn read_symlink(_: &Path, _: &mut Vec<u8>) -> Result<bool, std::io::Error> {
+    Ok(false)
 }
 
 #[cfg(not(windows))]
@@ -12,6 +12,20 @@ pub struct Memory(Arc<Mutex<Memory_>>);
 struct Memory_ {
     files: FileTree,
     last_modified: SystemTime,
+    readonly: bool,
+    available_space: Option<u64>,
+    encoding_detector: Option<Detector>,
+    full_permissions: bool,
+    graph_limits: crate::alive::retrieve::RetrieveLimits,
+    conflict_options: crate::output::ConflictOptions,
+}
+
+struct Detector(Arc<dyn EncodingDetector>);
+
+impl std::fmt::Debug for Detector {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str("Detector")
+    }
 }
 
 #[derive(Debug, Default)]
@@ -37,6 +51,12 @@ impl Default for Memory {
         Memory(Arc::new(Mutex::new(Memory_ {
             files: FileTree::default(),
             last_modified: SystemTime::now(),
+            readonly: false,
+            available_space: None,
+            encoding_detector: None,
+            full_permissions: false,
+            graph_limits: crate::alive::retrieve::RetrieveLimits::UNLIMITED,
+            conflict_options: crate::output::ConflictOptions::default(),
         })))
     }
 }
@@ -45,6 +65,40 @@ impl Memory {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Report this working copy as read-only in
+    /// [`WorkingCopy::is_writable`]. Writes still succeed.
+    pub fn set_readonly(&self, readonly: bool) {
+        self.0.lock().readonly = readonly
+    }
+
+    /// Set the space reported by [`WorkingCopy::available_space`].
+    pub fn set_available_space(&self, space: Option<u64>) {
+        self.0.lock().available_space = space
+    }
+
+    /// Set the detector used by [`WorkingCopy::detect_encoding`].
+    pub fn set_encoding_detector<D: EncodingDetector + 'static>(&self, detector: D) {
+        self.0.lock().encoding_detector = Some(Detector(Arc::new(detector)))
+    }
+
+    /// Keep all the Unix permissions given to
+    /// [`WorkingCopy::set_permissions`], instead of just the
+    /// executable bit.
+    pub fn set_full_permissions(&self, full: bool) {
+        self.0.lock().full_permissions = full
+    }
+
+    /// Set the limits returned by [`WorkingCopy::graph_limits`].
+    pub fn set_graph_limits(&self, limits: crate::alive::retrieve::RetrieveLimits) {
+        self.0.lock().graph_limits = limits
+    }
+
+    /// Set the options returned by [`WorkingCopy::conflict_options`].
+    pub fn set_conflict_options(&self, options: crate::output::ConflictOptions) {
+        self.0.lock().conflict_options = options
+    }
+
     pub fn list_files(&self) -> Vec<String> {
         let m = self.0.lock();
         let mut result = Vec::new();
@@ -88,6 +142,18 @@ impl Memory {
         )
     }
 
+    /// Add a symbolic link to `target`.
+    pub fn add_symlink(&self, file: &str, target: &str) {
+        self.add_inode(
+            file,
+            Inode::File {
+                meta: InodeMetadata::new_symlink(),
+                last_modified: SystemTime::now(),
+                contents: Arc::new(Mutex::new(target.as_bytes().to_vec())),
+            },
+        )
+    }
+
     pub fn add_dir(&self, file: &str) {
         let file_meta = InodeMetadata::new(0o100, true);
         let last = SystemTime::now();
@@ -215,6 +281,41 @@ pub enum Error {
 
 impl WorkingCopy for Memory {
     type Error = Error;
+    fn is_writable(&self, _path: &str) -> Result<bool, Self::Error> {
+        Ok(!self.0.lock().readonly)
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        Ok(self.0.lock().available_space)
+    }
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        self.0.lock().graph_limits
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.0.lock().conflict_options
+    }
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        let detector = self
+            .0
+            .lock()
+            .encoding_detector
+            .as_ref()
+            .map(|d| d.0.clone());
+        if let Some(detector) = detector {
+            detector.detect(file, contents, last)
+        } else {
+            DefaultEncodingDetector.detect(file, contents, last)
+        }
+    }
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        let m = self.0.lock();
+        match m.get_file(file) {
+            Some(Inode::File { ref contents, .. }) => Ok(Some(contents.lock().len() as u64)),
+            Some(Inode::Directory { .. }) => Ok(None),
+            None => Err(Error::NotFound {
+                path: file.to_string(),
+            }),
+        }
+    }
     fn create_dir_all(&self, file: &str) -> Result<(), Self::Error> {
         let not_already_exists = {
             let m = self.0.lock();
@@ -279,15 +380,24 @@ impl WorkingCopy for Memory {
         }
         Ok(())
     }
+    fn permission_mask(&self) -> u16 {
+        if self.0.lock().full_permissions {
+            0o777
+        } else {
+            0o100
+        }
+    }
     fn set_permissions(&self, file: &str, permissions: u16) -> Result<(), Self::Error> {
         debug!("set_permissions {:?}", file);
         let mut m = self.0.lock();
+        let mask = if m.full_permissions { 0o777 } else { 0o100 };
         match m.get_file_mut(file) {
+            Some(Inode::File { ref meta, .. }) if meta.is_symlink() => {}
             Some(Inode::File { ref mut meta, .. }) => {
-                *meta = InodeMetadata::new(permissions as usize & 0o100, false);
+                *meta = InodeMetadata::new(permissions as usize & mask, false);
             }
             Some(Inode::Directory { ref mut meta, .. }) => {
-                *meta = InodeMetadata::new(permissions as usize & 0o100, true);
+                *meta = InodeMetadata::new(permissions as usize & mask, true);
             }
             None => panic!("file not found: {:?}", file),
         }
@@ -299,9 +409,14 @@ impl WorkingCopy for Memory {
         let mut m = self.0.lock();
         if let Some(f) = m.get_file_mut(file) {
             if let Inode::File {
-                ref mut contents, ..
+                ref mut contents,
+                ref mut meta,
+                ..
             } = f
             {
+                if meta.is_symlink() {
+                    *meta = InodeMetadata::new(0, false)
+                }
                 contents.lock().clear();
                 return Ok(Writer {
                     w: contents.clone(),
@@ -323,6 +438,28 @@ impl WorkingCopy for Memory {
         );
         Ok(Writer { w: contents })
     }
+
+    fn read_dir(&self, path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        let m = self.0.lock();
+        let tree = if path.split('/').all(|c| c.is_empty()) {
+            &m.files
+        } else if let Some(Inode::Directory { ref children, .. }) = m.get_file(path) {
+            children
+        } else {
+            return Err(Error::NotFound {
+                path: path.to_string(),
+            });
+        };
+        let mut names: Vec<_> = tree.children.keys().cloned().collect();
+        names.sort();
+        Ok(Some(names))
+    }
+
+    fn create_symlink(&self, file: &str, target: &str) -> Result<bool, Self::Error> {
+        self.0.lock().remove_path_(file);
+        self.add_symlink(file, target);
+        Ok(true)
+    }
 }
 
 pub struct Writer {
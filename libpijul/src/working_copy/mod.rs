@@ -1,7 +1,7 @@
-use crate::chardetng::EncodingDetector;
-
+use crate::file_stats::FileStats;
 use crate::pristine::InodeMetadata;
-use crate::text_encoding::Encoding;
+use crate::subrepo::SubrepoState;
+use crate::text_encoding::{DefaultEncodingDetector, Encoding, EncodingDetector};
 
 #[cfg(feature = "ondisk-repos")]
 pub mod filesystem;
@@ -11,6 +11,11 @@ pub use filesystem::FileSystem;
 pub mod memory;
 pub use memory::Memory;
 
+#[cfg(any(test, feature = "simulated"))]
+pub mod simulated;
+#[cfg(any(test, feature = "simulated"))]
+pub use simulated::Simulated;
+
 pub trait WorkingCopy {
     type Error: std::error::Error + Send;
     fn create_dir_all(&self, path: &str) -> Result<(), Self::Error>;
@@ -20,9 +25,93 @@ pub trait WorkingCopy {
     fn remove_path(&self, name: &str, rec: bool) -> Result<(), Self::Error>;
     fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error>;
     fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error>;
+    /// The permission bits reported by [`WorkingCopy::file_metadata`].
+    /// Record compares only these bits with the recorded ones, and
+    /// keeps the others, so that working copies tracking fewer bits
+    /// don't strip the bits recorded by the others.
+    fn permission_mask(&self) -> u16 {
+        0o100
+    }
+    /// Called by output on each file or directory it writes, after
+    /// setting its permissions, so that embedders can set ownership,
+    /// extended attributes and the like. Does nothing by default.
+    fn output_hook(&self, _name: &str, _meta: InodeMetadata) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Make the changes made to this working copy since the last call
+    /// durable, according to its policy (see [`crate::durability`]).
+    /// Called by output once all the files are written, before the
+    /// tree-table updates are committed. Does nothing by default.
+    fn sync(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Limits on the graphs of the files output to this working copy,
+    /// none by default.
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        crate::alive::retrieve::RetrieveLimits::UNLIMITED
+    }
+    /// How conflicts are rendered in the files output to this working
+    /// copy, and in the outputs record compares them with.
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        crate::output::ConflictOptions::default()
+    }
+    /// Can `path` be written to? If `path` doesn't exist, this is
+    /// answered for its closest existing ancestor. Always true by
+    /// default.
+    fn is_writable(&self, _path: &str) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+    /// The number of bytes available to write files, if known.
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+    /// The size of `file` in bytes, if known without reading it.
+    fn file_size(&self, _file: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+    /// Read `file` in windows of at most `window` bytes, calling `f`
+    /// on each of them until it returns `false`. By default, this
+    /// reads the whole file first.
+    fn read_file_windows(
+        &self,
+        file: &str,
+        window: usize,
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), Self::Error> {
+        let mut buf = Vec::new();
+        self.read_file(file, &mut buf)?;
+        for w in buf.chunks(window) {
+            if !f(w) {
+                break;
+            }
+        }
+        Ok(())
+    }
 
     type Writer: std::io::Write;
     fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error>;
+    /// Replace `file` with a symbolic link to `target`, returning
+    /// `false` if this working copy doesn't support symbolic links
+    /// (the default), in which case output writes `target` to a
+    /// regular file instead.
+    fn create_symlink(&self, _file: &str, _target: &str) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+    /// The names of the entries of directory `path`, sorted, or `None`
+    /// if this working copy can't list directories (the default).
+    /// This is only used to record opaque directories (see
+    /// [`crate::opaque`]), which fails if their entries can't be
+    /// listed.
+    fn read_dir(&self, _path: &str) -> Result<Option<Vec<String>>, Self::Error> {
+        Ok(None)
+    }
+    /// The state of the nested repository at `path`, if this working
+    /// copy can read it (see [`crate::subrepo`]). Nested repositories
+    /// whose state can't be read are recorded from their pointer
+    /// file.
+    fn subrepo_state(&self, _path: &str) -> Result<Option<SubrepoState>, Self::Error> {
+        Ok(None)
+    }
     /// Read the file into the buffer
     ///
     /// Returns the file's text encoding or None if it was a binary file
@@ -33,13 +122,35 @@ pub trait WorkingCopy {
     ) -> Result<Option<Encoding>, Self::Error> {
         let init = buffer.len();
         self.read_file(&file, buffer)?;
-        let mut detector = EncodingDetector::new();
-        detector.feed(&buffer[init..], true);
-        let (encoding, score) = detector.guess_assess(None, true);
-        if score {
-            Ok(Some(Encoding(encoding)))
+        Ok(self.detect_encoding(file, &buffer[init..], true))
+    }
+    /// Like [`WorkingCopy::decode_file`], where `stats` are the
+    /// statistics of `file` when it was last recorded, if any. If the
+    /// file is still in the same encoding (see
+    /// [`FileStats::same_encoding`]), the encoding detector isn't run.
+    fn decode_file_with_stats(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+        stats: Option<&FileStats>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        let stats = if let Some(stats) = stats {
+            stats
+        } else {
+            return self.decode_file(file, buffer);
+        };
+        let init = buffer.len();
+        self.read_file(&file, buffer)?;
+        if let Some(encoding) = stats.same_encoding(&buffer[init..]) {
+            Ok(encoding)
         } else {
-            Ok(None)
+            Ok(self.detect_encoding(file, &buffer[init..], true))
         }
     }
+    /// The encoding of `contents`, read from the beginning of `file`,
+    /// or `None` if `file` is binary. `last` is `true` if `contents`
+    /// is the whole file. Uses [`DefaultEncodingDetector`] by default.
+    fn detect_encoding(&self, file: &str, contents: &[u8], last: bool) -> Option<Encoding> {
+        DefaultEncodingDetector.detect(file, contents, last)
+    }
 }
@@ -0,0 +1,239 @@
+//! A working copy simulating the quirks of real filesystems, to
+//! reproduce platform-specific bugs in tests.
+//!
+//! [`Simulated`] wraps a [`Memory`] working copy, and can be
+//! configured to:
+//!
+//! - match names regardless of their case, like the default
+//!   filesystems of macOS and Windows,
+//! - match names regardless of their Unicode normalization, like
+//!   HFS+ and APFS, on which `é` may be stored as `e` followed by a
+//!   combining accent,
+//! - refuse to read or write some paths,
+//! - make a file vanish after its metadata was read, as if another
+//!   process deleted it while it was being recorded.
+//!
+//! Like on these filesystems, a name is kept as it was first
+//! written: writing `readme` when `README` exists writes to `README`.
+//! Setting up the files directly in the underlying [`Memory`] working
+//! copy bypasses the simulation.
+//!
+//! This module is only compiled in tests, or with the `simulated`
+//! feature.
+use super::memory::{self, Memory};
+use super::*;
+use crate::HashSet;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone)]
+pub struct Simulated {
+    repo: Memory,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    case_insensitive: bool,
+    normalization_insensitive: bool,
+    /// Folded paths that can't be read or written.
+    denied: HashSet<String>,
+    /// Folded paths removed when they are next read.
+    vanishing: HashSet<String>,
+}
+
+impl State {
+    /// The key under which this filesystem compares `name`.
+    fn fold(&self, name: &str) -> String {
+        let name: String = if self.normalization_insensitive {
+            name.nfc().collect()
+        } else {
+            name.to_string()
+        };
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    /// Whether `path` or one of its ancestors is denied.
+    fn is_denied(&self, path: &str) -> bool {
+        let mut path = self.fold(path);
+        loop {
+            if self.denied.contains(&path) {
+                return true;
+            }
+            if path.is_empty() {
+                return false;
+            }
+            crate::path::pop(&mut path)
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Memory(#[from] memory::Error),
+    #[error("Permission denied: {path}")]
+    PermissionDenied { path: String },
+}
+
+impl Simulated {
+    /// Simulate a filesystem behaving exactly like `repo`, until
+    /// configured otherwise.
+    pub fn new(repo: Memory) -> Self {
+        Simulated {
+            repo,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// The underlying working copy, with the names actually stored.
+    pub fn memory(&self) -> &Memory {
+        &self.repo
+    }
+
+    pub fn set_case_insensitive(&self, case_insensitive: bool) {
+        self.state.lock().case_insensitive = case_insensitive
+    }
+
+    pub fn set_normalization_insensitive(&self, normalization_insensitive: bool) {
+        self.state.lock().normalization_insensitive = normalization_insensitive
+    }
+
+    /// Refuse to read or write `path` and the paths under it. Their
+    /// metadata can still be read.
+    pub fn deny(&self, path: &str) {
+        let mut state = self.state.lock();
+        let path = state.fold(path);
+        state.denied.insert(path);
+    }
+
+    /// Undo [`Simulated::deny`].
+    pub fn allow(&self, path: &str) {
+        let mut state = self.state.lock();
+        let path = state.fold(path);
+        state.denied.remove(&path);
+    }
+
+    /// Remove `path` the next time it is read, failing that read.
+    pub fn vanish_on_read(&self, path: &str) {
+        let mut state = self.state.lock();
+        let path = state.fold(path);
+        state.vanishing.insert(path);
+    }
+
+    /// The path stored in the underlying working copy for `path`,
+    /// i.e. `path` with the names of its existing components replaced
+    /// by the stored names they match.
+    fn resolve(&self, path: &str) -> String {
+        let state = self.state.lock();
+        let files = self.repo.list_files();
+        let mut resolved = String::new();
+        for c in path.split('/').filter(|c| !c.is_empty()) {
+            let folded = state.fold(c);
+            let existing = files.iter().find(|f| {
+                crate::path::parent(f) == Some(resolved.as_str())
+                    && crate::path::file_name(f).map(|n| state.fold(n)).as_ref() == Some(&folded)
+            });
+            if let Some(f) = existing {
+                resolved = f.clone()
+            } else {
+                crate::path::push(&mut resolved, c)
+            }
+        }
+        resolved
+    }
+
+    /// Resolve `path` for writing, failing if it is denied.
+    fn resolve_writable(&self, path: &str) -> Result<String, Error> {
+        let resolved = self.resolve(path);
+        if self.state.lock().is_denied(&resolved) {
+            return Err(Error::PermissionDenied {
+                path: path.to_string(),
+            });
+        }
+        Ok(resolved)
+    }
+}
+
+impl WorkingCopy for Simulated {
+    type Error = Error;
+    fn is_writable(&self, path: &str) -> Result<bool, Self::Error> {
+        let resolved = self.resolve(path);
+        Ok(!self.state.lock().is_denied(&resolved) && self.repo.is_writable(&resolved)?)
+    }
+    fn available_space(&self) -> Result<Option<u64>, Self::Error> {
+        Ok(self.repo.available_space()?)
+    }
+    fn graph_limits(&self) -> crate::alive::retrieve::RetrieveLimits {
+        self.repo.graph_limits()
+    }
+    fn conflict_options(&self) -> crate::output::ConflictOptions {
+        self.repo.conflict_options()
+    }
+    fn create_dir_all(&self, path: &str) -> Result<(), Self::Error> {
+        let path = self.resolve_writable(path)?;
+        Ok(self.repo.create_dir_all(&path)?)
+    }
+    fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
+        Ok(self.repo.file_metadata(&self.resolve(file))?)
+    }
+    fn file_size(&self, file: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(self.repo.file_size(&self.resolve(file))?)
+    }
+    fn read_file(&self, file: &str, buffer: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let resolved = self.resolve(file);
+        let vanished = {
+            let mut state = self.state.lock();
+            if state.is_denied(&resolved) {
+                return Err(Error::PermissionDenied {
+                    path: file.to_string(),
+                });
+            }
+            let folded = state.fold(&resolved);
+            state.vanishing.remove(&folded)
+        };
+        if vanished {
+            debug!("{:?} vanished", resolved);
+            self.repo.remove_path(&resolved, true)?;
+        }
+        Ok(self.repo.read_file(&resolved, buffer)?)
+    }
+    fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
+        Ok(self.repo.modified_time(&self.resolve(file))?)
+    }
+    fn remove_path(&self, path: &str, rec: bool) -> Result<(), Self::Error> {
+        let path = self.resolve_writable(path)?;
+        Ok(self.repo.remove_path(&path, rec)?)
+    }
+    fn rename(&self, former: &str, new: &str) -> Result<(), Self::Error> {
+        let former = self.resolve_writable(former)?;
+        let existing = self.resolve_writable(new)?;
+        // Renaming to a name differing only by its case (or
+        // normalization) changes the stored name, and renaming to
+        // another existing file replaces it.
+        let mut new_ = crate::path::parent(&existing).unwrap_or("").to_string();
+        crate::path::push(&mut new_, crate::path::file_name(new).unwrap_or(new));
+        if existing != former && self.repo.file_metadata(&existing).is_ok() {
+            self.repo.remove_path(&existing, true)?
+        }
+        Ok(self.repo.rename(&former, &new_)?)
+    }
+    fn permission_mask(&self) -> u16 {
+        self.repo.permission_mask()
+    }
+    fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
+        let name = self.resolve_writable(name)?;
+        Ok(self.repo.set_permissions(&name, permissions)?)
+    }
+
+    type Writer = memory::Writer;
+    fn write_file(&self, file: &str) -> Result<Self::Writer, Self::Error> {
+        let file = self.resolve_writable(file)?;
+        Ok(self.repo.write_file(&file)?)
+    }
+}
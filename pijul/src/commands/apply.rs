@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use anyhow::bail;
 use clap::Clap;
 use libpijul::changestore::ChangeStore;
-use libpijul::{DepsTxnT, GraphTxnT, MutTxnTExt, TxnT};
+use libpijul::{DepsTxnT, GraphTxnT, MutTxnTExt, TxnT, TxnTExt};
 use libpijul::{HashMap, HashSet};
 use log::*;
 
@@ -28,6 +28,8 @@ pub struct Apply {
 impl Apply {
     pub fn run(self) -> Result<(), anyhow::Error> {
         let repo = Repository::find_root(self.repo_path)?;
+        repo.working_copy
+            .set_durability(repo.config.durability_for("apply"));
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -40,11 +42,13 @@ impl Apply {
             cur.as_str()
         };
         let is_current_channel = channel_name == cur;
-        let channel = if let Some(channel) = txn.read().load_channel(&channel_name)? {
-            channel
-        } else {
-            bail!("Channel {:?} not found", channel_name)
-        };
+        let channel =
+            if let Some(channel) = libpijul::channel::load_channel(&*txn.read(), &channel_name)? {
+                channel
+            } else {
+                bail!("Channel {:?} not found", channel_name)
+            };
+        super::recover_journal(&repo, &txn, &channel, is_current_channel)?;
         let mut hashes = Vec::new();
         for ch in self.change.iter() {
             hashes.push(if let Ok(h) = txn.read().hash_from_prefix(ch) {
@@ -70,19 +74,54 @@ impl Apply {
             let change = libpijul::change::Change::read(&mut change, &mut HashMap::default())?;
             hashes.push(repo.changes.save_change(&change)?)
         }
+        if self.deps_only && hashes.len() > 1 {
+            bail!("--deps-only is only applicable to a single change")
+        }
+
+        // Store the intent of this operation in its own transaction,
+        // right before applying, so that it can be rolled back if we
+        // get interrupted. Applying only the dependencies isn't
+        // rolled back.
+        if !self.deps_only {
+            libpijul::journal::begin(
+                &mut *txn.write(),
+                channel_name,
+                libpijul::journal::Operation::Apply,
+                &hashes,
+            )?;
+        }
+        txn.commit()?;
+        let txn = repo.pristine.arc_txn_begin()?;
+        let channel = libpijul::channel::load_channel(&*txn.read(), &channel_name)?.unwrap();
+
         if self.deps_only {
-            if hashes.len() > 1 {
-                bail!("--deps-only is only applicable to a single change")
-            }
             let mut channel = channel.write();
             txn.write()
                 .apply_deps_rec(&repo.changes, &mut channel, hashes.last().unwrap())?;
         } else {
+            let identity = super::audit_identity();
             let mut channel = channel.write();
             let mut txn = txn.write();
             for hash in hashes.iter() {
-                txn.apply_change_rec(&repo.changes, &mut channel, hash)?
+                txn.apply_change_rec(&repo.changes, &mut channel, hash)?;
+                let state = txn.current_state(&*channel)?;
+                libpijul::audit::append_state(
+                    &mut *txn,
+                    &identity,
+                    libpijul::audit::AuditOperation::Apply { hash: *hash },
+                    &channel_name,
+                    state,
+                )?;
+            }
+        }
+        if !self.deps_only {
+            let mut renamed = Vec::new();
+            let txn = txn.read();
+            for hash in hashes.iter() {
+                let report = libpijul::apply_report(&repo.changes, &*txn, &*channel.read(), hash)?;
+                renamed.extend(report.rename_edit_conflicts.into_iter())
             }
+            super::print_rename_edit_conflicts(&renamed)?;
         }
 
         let mut touched = HashSet::default();
@@ -161,6 +200,12 @@ impl Apply {
             PROGRESS.join();
             super::print_conflicts(&conflicts)?;
         }
+        libpijul::maintenance::AutoMaintenance::new(repo.config.maintenance_policy()).after_apply(
+            &mut *txn.write(),
+            &channel,
+            hashes.len() as u64,
+        )?;
+        libpijul::journal::finish(&mut *txn.write(), channel_name)?;
         txn.commit()?;
         Ok(())
     }
@@ -84,6 +84,7 @@ impl Archive {
                 },
                 self.no_cert_check,
                 true,
+                &crate::config::RemoteFeatures::global(),
             )
             .await?;
             if let crate::remote::RemoteRepo::LocalChannel(_) = remote {
@@ -110,6 +111,7 @@ impl Archive {
             }
             let mut f = std::fs::File::create(&p)?;
             let mut tarball = libpijul::output::Tarball::new(&mut f, self.prefix, umask);
+            tarball.graph_limits = repo.config.graph_limits;
             let conflicts = if let Some(state) = state {
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 let channel_name = if let Some(ref c) = self.channel {
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::path::PathBuf;
 
 use clap::Clap;
@@ -15,6 +17,10 @@ pub struct Change {
     /// The hash of the change to show, or an unambiguous prefix thereof
     #[clap(value_name = "HASH")]
     hash: Option<String>,
+    /// Explain why the change depends on change DEP, instead of
+    /// showing the change
+    #[clap(long = "explain", value_name = "DEP")]
+    explain: Option<String>,
 }
 
 impl Change {
@@ -39,8 +45,40 @@ impl Change {
                 return Ok(());
             }
         };
+        if let Some(ref dep) = self.explain {
+            let dep = txn.hash_from_prefix(dep)?.0;
+            let e = libpijul::change::explain_dependency(&txn, &changes, &hash, &dep)?;
+            let mut stdout = std::io::stdout();
+            if !e.via.is_empty() {
+                let via: Vec<_> = e.via.iter().map(|h| h.to_base32()).collect();
+                writeln!(stdout, "Inherited through {}", via.join(" -> "))?;
+            }
+            for c in e.causes.iter() {
+                if let Some(line) = c.line {
+                    write!(stdout, "Hunk {} ({}:{})", c.hunk + 1, c.path, line)?;
+                } else {
+                    write!(stdout, "Hunk {} ({})", c.hunk + 1, c.path)?;
+                }
+                writeln!(stdout, ": {:?}", c.reason)?;
+            }
+            return Ok(());
+        }
         let change = changes.get_change(&hash).unwrap();
-        let file_name = |l: &Local, _| format!("{}:{}", l.path, l.line);
+        let moves: HashMap<_, _> = change
+            .moves()
+            .into_iter()
+            .map(|m| ((m.to.path, m.to.line), m.from))
+            .collect();
+        let file_name = |l: &Local, _| {
+            if let Some(from) = moves.get(&(l.path.clone(), l.line)) {
+                format!(
+                    "{}:{} (moved from {}:{})",
+                    l.path, l.line, from.path, from.line
+                )
+            } else {
+                format!("{}:{}", l.path, l.line)
+            }
+        };
         let colors = super::diff::is_colored();
         change.write(
             &changes,
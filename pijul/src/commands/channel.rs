@@ -19,9 +19,22 @@ pub struct Channel {
 #[derive(Clap, Debug)]
 pub enum SubCommand {
     /// Delete a channel.
-    /// The channel must not be the current channel.
+    /// The channel must not be the current channel. Unless
+    /// `--permanent` is given, it can be restored with `pijul channel
+    /// undelete` until `pijul gc` purges it.
     #[clap(name = "delete")]
-    Delete { delete: String },
+    Delete {
+        delete: String,
+        /// Drop the channel immediately, without a way to restore it
+        #[clap(long = "permanent")]
+        permanent: bool,
+    },
+    /// Restore a deleted channel.
+    #[clap(name = "undelete")]
+    Undelete { name: String },
+    /// List the deleted channels that can still be restored.
+    #[clap(name = "deleted")]
+    Deleted,
     /// Rename a channel.
     #[clap(name = "rename")]
     Rename { from: String, to: Option<String> },
@@ -32,6 +45,24 @@ pub enum SubCommand {
     /// Create a new, empty channel.
     #[clap(name = "new")]
     New { name: String },
+    /// Show or change the record settings of a channel (defaults to
+    /// the current channel).
+    #[clap(name = "settings")]
+    Settings {
+        name: Option<String>,
+        /// Diff algorithm ("myers" or "patience")
+        #[clap(long = "diff-algorithm")]
+        diff_algorithm: Option<libpijul::Algorithm>,
+        /// How binary files are recorded ("byte-ranges", "chunks" or "lines")
+        #[clap(long = "binary")]
+        binary: Option<libpijul::channel_settings::BinaryPolicy>,
+        /// How line endings are recorded ("preserve" or "lf")
+        #[clap(long = "eol")]
+        eol: Option<libpijul::channel_settings::EolPolicy>,
+        /// Reset all settings to their defaults
+        #[clap(long = "clear")]
+        clear: bool,
+    },
 }
 
 impl Channel {
@@ -45,6 +76,9 @@ impl Channel {
                 for channel in txn.iter_channels("")? {
                     let (_, channel) = channel?;
                     let channel = channel.read();
+                    if libpijul::channel::is_deleted(&txn, &*channel)? {
+                        continue;
+                    }
                     let name = txn.name(&*channel);
                     if current == Some(name) {
                         writeln!(stdout, "* {}", name)?;
@@ -53,18 +87,58 @@ impl Channel {
                     }
                 }
             }
-            Some(SubCommand::Delete { ref delete }) => {
+            Some(SubCommand::Delete {
+                ref delete,
+                permanent,
+            }) => {
                 let repo = Repository::find_root(self.repo_path)?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 let current = txn.current_channel().ok();
                 if Some(delete.as_str()) == current {
                     bail!("Cannot delete current channel")
                 }
-                if !txn.drop_channel(delete)? {
-                    return Err(anyhow!("Channel {} not found", delete));
+                if permanent {
+                    if !txn.drop_channel(delete)? {
+                        return Err(anyhow!("Channel {} not found", delete));
+                    }
+                    libpijul::audit::append(
+                        &mut txn,
+                        &super::audit_identity(),
+                        libpijul::audit::AuditOperation::DropChannel,
+                        delete,
+                    )?;
+                } else {
+                    // Audit first, while the channel still has a state.
+                    libpijul::audit::append(
+                        &mut txn,
+                        &super::audit_identity(),
+                        libpijul::audit::AuditOperation::SoftDeleteChannel,
+                        delete,
+                    )?;
+                    libpijul::channel::soft_delete(&mut txn, delete)?;
                 }
                 txn.commit()?;
             }
+            Some(SubCommand::Undelete { ref name }) => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                libpijul::channel::undelete(&mut txn, name)?;
+                libpijul::audit::append(
+                    &mut txn,
+                    &super::audit_identity(),
+                    libpijul::audit::AuditOperation::UndeleteChannel,
+                    name,
+                )?;
+                txn.commit()?;
+            }
+            Some(SubCommand::Deleted) => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let txn = repo.pristine.txn_begin()?;
+                for d in libpijul::channel::deleted(&txn)? {
+                    let t = chrono::NaiveDateTime::from_timestamp(d.deleted_at as i64, 0);
+                    writeln!(stdout, "{} (deleted {} UTC)", d.name, t)?;
+                }
+            }
             Some(SubCommand::Switch { to }) => {
                 (crate::commands::reset::Reset {
                     repo_path: self.repo_path,
@@ -85,13 +159,21 @@ impl Channel {
                 } else {
                     bail!("No current channel")
                 };
-                let mut channel = if let Some(channel) = txn.load_channel(from)? {
-                    channel
-                } else {
-                    bail!("No such channel: {:?}", from)
-                };
+                let mut channel =
+                    if let Some(channel) = libpijul::channel::load_channel(&txn, from)? {
+                        channel
+                    } else {
+                        bail!("No such channel: {:?}", from)
+                    };
+                let from = from.to_string();
                 txn.rename_channel(&mut channel, to)?;
                 txn.set_current_channel(&to)?;
+                libpijul::audit::append(
+                    &mut txn,
+                    &super::audit_identity(),
+                    libpijul::audit::AuditOperation::RenameChannel { from },
+                    to,
+                )?;
                 txn.commit()?;
             }
             Some(SubCommand::New { name }) => {
@@ -103,6 +185,49 @@ impl Channel {
                 txn.open_or_create_channel(&name)?;
                 txn.commit()?;
             }
+            Some(SubCommand::Settings {
+                name,
+                diff_algorithm,
+                binary,
+                eol,
+                clear,
+            }) => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let mut txn = repo.pristine.mut_txn_begin()?;
+                let name = if let Some(name) = name {
+                    name
+                } else if let Ok(current) = txn.current_channel() {
+                    current.to_string()
+                } else {
+                    bail!("No current channel")
+                };
+                let channel = if let Some(channel) = libpijul::channel::load_channel(&txn, &name)? {
+                    channel
+                } else {
+                    bail!("No such channel: {:?}", name)
+                };
+                let mut settings = if clear {
+                    Default::default()
+                } else {
+                    libpijul::channel_settings::load(&txn, &*channel.read())?
+                };
+                if diff_algorithm.is_some() || binary.is_some() || eol.is_some() || clear {
+                    settings.diff_algorithm = diff_algorithm.or(settings.diff_algorithm);
+                    settings.binary = binary.or(settings.binary);
+                    settings.eol = eol.or(settings.eol);
+                    libpijul::channel_settings::save(&mut txn, &*channel.read(), &settings)?;
+                    txn.commit()?;
+                }
+                if let Some(a) = settings.diff_algorithm {
+                    writeln!(stdout, "diff-algorithm: {:?}", a)?;
+                }
+                if let Some(b) = settings.binary {
+                    writeln!(stdout, "binary: {:?}", b)?;
+                }
+                if let Some(e) = settings.eol {
+                    writeln!(stdout, "eol: {:?}", e)?;
+                }
+            }
         }
         Ok(())
     }
@@ -40,6 +40,7 @@ impl Clone {
             &self.channel,
             self.no_cert_check,
             true,
+            &crate::config::RemoteFeatures::global(),
         )
         .await?;
 
@@ -73,6 +74,8 @@ impl Clone {
         .unwrap_or(());
 
         let mut repo = Repository::init(Some(path), None)?;
+        repo.working_copy
+            .set_durability(repo.config.durability_for("clone"));
         let txn = repo.pristine.arc_txn_begin()?;
         let mut channel = txn.write().open_or_create_channel(&self.channel)?;
         if let Some(ref change) = self.change {
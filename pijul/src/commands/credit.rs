@@ -1,4 +1,3 @@
-use std::collections::HashSet;
 use std::path::PathBuf;
 
 use anyhow::bail;
@@ -51,12 +50,21 @@ impl Credit {
         };
         super::pager();
         let channel = channel.read();
+        let mut creditor = Creditor::new(std::io::stdout(), &txn, &channel);
+        creditor.copied = libpijul::search::copy_credits(
+            &repo.changes,
+            &txn,
+            &channel,
+            pos,
+            repo.config.graph_limits,
+        )?;
         match libpijul::output::output_file(
             &repo.changes,
             &txn,
             &channel,
             pos,
-            &mut Creditor::new(std::io::stdout(), &txn, &channel),
+            &mut creditor,
+            repo.config.graph_limits,
         ) {
             Ok(_) => {}
             Err(libpijul::output::FileError::Io(io)) => {
@@ -75,9 +83,10 @@ pub struct Creditor<'a, W: std::io::Write, T: ChannelTxnT> {
     w: W,
     buf: Vec<u8>,
     new_line: bool,
-    changes: HashSet<Hash>,
     txn: &'a T,
     channel: &'a T::Channel,
+    /// If the file is a copy, the credits of the lines of its source.
+    pub copied: Option<libpijul::search::CopyCredits>,
 }
 
 impl<'a, W: std::io::Write, T: ChannelTxnT> Creditor<'a, W, T> {
@@ -88,11 +97,34 @@ impl<'a, W: std::io::Write, T: ChannelTxnT> Creditor<'a, W, T> {
             buf: Vec::new(),
             txn,
             channel,
-            changes: HashSet::new(),
+            copied: None,
         }
     }
 }
 
+fn write_credit<W: std::io::Write>(
+    w: &mut W,
+    new_line: bool,
+    changes: &[libpijul::Hash],
+) -> Result<(), std::io::Error> {
+    if !new_line {
+        writeln!(w)?;
+    }
+    writeln!(w)?;
+    let mut is_first = true;
+    for c in changes {
+        let c = c.to_base32();
+        write!(
+            w,
+            "{}{}",
+            if is_first { "" } else { ", " },
+            c.split_at(12).0,
+        )?;
+        is_first = false;
+    }
+    writeln!(w, "\n")
+}
+
 impl<'a, W: std::io::Write, T: TxnTExt> VertexBuffer for Creditor<'a, W, T> {
     fn output_line<E, C: FnOnce(&mut Vec<u8>) -> Result<(), E>>(
         &mut self,
@@ -106,41 +138,30 @@ impl<'a, W: std::io::Write, T: TxnTExt> VertexBuffer for Creditor<'a, W, T> {
         self.buf.clear();
         c(&mut self.buf)?;
 
+        let mut changes = Vec::new();
         if !v.change.is_root() {
-            self.changes.clear();
-            for e in self
-                .txn
-                .iter_adjacent(self.channel, v, EdgeFlags::PARENT, EdgeFlags::all())
-                .unwrap()
-            {
-                let e = e.unwrap();
-                if e.introduced_by().is_root() {
-                    continue;
-                }
-                if let Ok(Some(intro)) = self.txn.get_external(&e.introduced_by()) {
-                    self.changes.insert(intro.into());
-                }
-            }
-            if !self.new_line {
-                writeln!(self.w)?;
-            }
-            writeln!(self.w)?;
-            let mut is_first = true;
-            for c in self.changes.drain() {
-                let c = c.to_base32();
-                write!(
-                    self.w,
-                    "{}{}",
-                    if is_first { "" } else { ", " },
-                    c.split_at(12).0,
-                )?;
-                is_first = false;
-            }
-            writeln!(self.w, "\n")?;
+            changes = libpijul::search::credit(self.txn, self.txn.graph(self.channel), v).unwrap();
+        }
+        // Lines added by copying a file are credited to the changes
+        // that wrote them in the source.
+        let copied = self
+            .copied
+            .as_ref()
+            .filter(|c| !v.change.is_root() && changes == [c.change]);
+        if copied.is_none() && !v.change.is_root() {
+            write_credit(&mut self.w, self.new_line, &changes)?;
         }
         let ends_with_newline = self.buf.ends_with(b"\n");
         if let Ok(s) = std::str::from_utf8(&self.buf[..]) {
+            let mut last = None;
             for l in s.lines() {
+                if let Some(copied) = copied {
+                    let credit = copied.credit(l.as_bytes(), &changes);
+                    if last != Some(credit) {
+                        write_credit(&mut self.w, last.is_some() || self.new_line, credit)?;
+                        last = Some(credit)
+                    }
+                }
                 self.w.write_all(b"> ")?;
                 self.w.write_all(l.as_bytes())?;
                 self.w.write_all(b"\n")?;
@@ -63,12 +63,13 @@ impl Diff {
         let channel = txn.write().open_or_create_channel(&channel)?;
 
         let mut state = libpijul::RecordBuilder::new();
+        state.graph_limits = repo.config.graph_limits;
         if self.prefixes.is_empty() {
             state.record(
                 txn.clone(),
-                libpijul::Algorithm::default(),
+                libpijul::ChannelAlgorithm,
                 channel.clone(),
-                &repo.working_copy,
+                &libpijul::opaque::Opaque::new(repo.working_copy.clone())?,
                 &repo.changes,
                 "",
                 num_cpus::get(),
@@ -86,7 +87,7 @@ impl Diff {
                 0,
             )?;
         }
-        let rec = state.finish();
+        let rec = state.finish()?;
         if rec.actions.is_empty() {
             let txn = txn.read();
             if self.short {
@@ -138,16 +139,21 @@ impl Diff {
                     .push(Status {
                         operation: match ch {
                             Hunk::FileMove { .. } => "file move",
+                            Hunk::FileMeta { .. } => "permissions",
                             Hunk::FileDel { .. } => "file del",
                             Hunk::FileUndel { .. } => "file undel",
                             Hunk::SolveNameConflict { .. } => "solve name conflict",
                             Hunk::UnsolveNameConflict { .. } => "unsolve name conflict",
                             Hunk::FileAdd { .. } => "file add",
+                            Hunk::FileCopy { .. } => "file copy",
+                            Hunk::SubrepoPointer { .. } => "subrepository",
                             Hunk::Edit { .. } => "edit",
                             Hunk::Replacement { .. } => "replacement",
                             Hunk::SolveOrderConflict { .. } => "solve order conflict",
                             Hunk::UnsolveOrderConflict { .. } => "unsolve order conflict",
                             Hunk::ResurrectZombies { .. } => "resurrect zombies",
+                            Hunk::BinaryEdit { .. } => "binary edit",
+                            Hunk::Custom { .. } => "custom",
                         },
                         line: ch.line(),
                     });
@@ -161,6 +167,9 @@ impl Diff {
                     Hunk::FileMove { path, .. } => {
                         changes.entry(path).or_insert(BTreeSet::new()).insert("MV")
                     }
+                    Hunk::FileMeta { path, .. } => {
+                        changes.entry(path).or_insert(BTreeSet::new()).insert("P")
+                    }
                     Hunk::FileDel { path, .. } => {
                         changes.entry(path).or_insert(BTreeSet::new()).insert("D")
                     }
@@ -170,6 +179,12 @@ impl Diff {
                     Hunk::FileAdd { path, .. } => {
                         changes.entry(path).or_insert(BTreeSet::new()).insert("A")
                     }
+                    Hunk::FileCopy { path, .. } => {
+                        changes.entry(path).or_insert(BTreeSet::new()).insert("C")
+                    }
+                    Hunk::SubrepoPointer { path, .. } => {
+                        changes.entry(path).or_insert(BTreeSet::new()).insert("S")
+                    }
                     Hunk::SolveNameConflict { path, .. } => {
                         changes.entry(path).or_insert(BTreeSet::new()).insert("SC")
                     }
@@ -196,6 +211,14 @@ impl Diff {
                         local: Local { path, .. },
                         ..
                     } => changes.entry(path).or_insert(BTreeSet::new()).insert("RZ"),
+                    Hunk::BinaryEdit {
+                        local: Local { path, .. },
+                        ..
+                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("M"),
+                    Hunk::Custom {
+                        local: Local { path, .. },
+                        ..
+                    } => changes.entry(path).or_insert(BTreeSet::new()).insert("X"),
                 };
             }
             let al = changes
@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Clap;
+use libpijul::doctor::*;
+use libpijul::TxnT;
+
+use crate::repository::Repository;
+
+#[derive(Clap, Debug)]
+pub struct Doctor {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Check the working copy against this channel instead of the current channel
+    #[clap(long = "channel")]
+    channel: Option<String>,
+    /// Output the report in JSON format, e.g. to attach it to a bug report
+    #[clap(long = "json")]
+    json: bool,
+}
+
+impl Doctor {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let mut report = Report::default();
+        check_tables(&repo.pristine, &mut report)?;
+        if report.is_healthy() {
+            let txn = repo.pristine.txn_begin()?;
+            let channel_name = if let Some(ref c) = self.channel {
+                c
+            } else {
+                txn.current_channel().unwrap_or(crate::DEFAULT_CHANNEL)
+            }
+            .to_string();
+            let channel = if let Some(channel) = txn.load_channel(&channel_name)? {
+                channel
+            } else {
+                bail!("No such channel: {:?}", channel_name)
+            };
+            let r = diagnose(
+                &txn,
+                &repo.changes,
+                &repo.working_copy,
+                &channel,
+                &DoctorOptions::default(),
+            )?;
+            for check in r.checks {
+                if !report.checks.contains(&check) {
+                    report.checks.push(check)
+                }
+            }
+            report.findings.extend(r.findings);
+        }
+        let mut stdout = std::io::stdout();
+        if self.json {
+            report.to_json(&mut stdout)?;
+            writeln!(stdout)?;
+            return Ok(());
+        }
+        if report.findings.is_empty() {
+            writeln!(stdout, "No problem found")?;
+        }
+        for f in report.findings.iter() {
+            write!(stdout, "{:?} ({:?})", f.severity, f.check)?;
+            if let Some(ref c) = f.channel {
+                write!(stdout, " [{}]", c)?;
+            }
+            if let Some(ref p) = f.path {
+                write!(stdout, " {}:", p)?;
+            }
+            writeln!(stdout, " {}", f.message)?;
+            if let Some(ref fix) = f.fix {
+                writeln!(stdout, "    Suggested fix: {}", fix)?;
+            }
+        }
+        Ok(())
+    }
+}
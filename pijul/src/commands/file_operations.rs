@@ -36,6 +36,7 @@ impl Move {
             return Ok(());
         }
 
+        repo.working_copy.set_durability(repo.config.durability_for("mv"));
         let mut txn = repo.pristine.mut_txn_begin()?;
         let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
         for p in self.paths {
@@ -64,9 +65,15 @@ impl Move {
                 let target = target.to_slash_lossy();
                 debug!("moving {:?} -> {:?}", source, target);
                 txn.move_file(&source, &target, self.salt.unwrap_or(0))?;
+                repo.working_copy.touched(&source);
+                repo.working_copy.touched(&target);
             }
             std::mem::forget(r);
         }
+        // The renames must be durable before the tree tables
+        // describing them are.
+        use libpijul::working_copy::WorkingCopy;
+        repo.working_copy.sync()?;
         txn.commit()?;
         Ok(())
     }
@@ -28,7 +28,7 @@ impl Fork {
         let mut txn = repo.pristine.mut_txn_begin()?;
         if let Some(ref ch) = self.change {
             let (hash, _) = txn.hash_from_prefix(ch)?;
-            let channel = txn.open_or_create_channel(&self.to)?;
+            let channel = libpijul::channel::open_or_create_channel(&mut txn, &self.to)?;
             let mut channel = channel.write();
             txn.apply_change_rec(&repo.changes, &mut channel, &hash)?
         } else {
@@ -41,8 +41,16 @@ impl Fork {
             } else {
                 cur.as_str()
             };
-            if let Some(channel) = txn.load_channel(&channel_name)? {
+            if let Some(channel) = libpijul::channel::load_channel(&txn, &channel_name)? {
                 txn.fork(&channel, &self.to)?;
+                libpijul::audit::append(
+                    &mut txn,
+                    &super::audit_identity(),
+                    libpijul::audit::AuditOperation::ForkChannel {
+                        from: channel_name.to_string(),
+                    },
+                    &self.to,
+                )?;
             }
         }
         txn.commit()?;
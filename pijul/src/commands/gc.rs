@@ -0,0 +1,36 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Clap;
+use libpijul::MutTxnT;
+
+use crate::repository::Repository;
+
+#[derive(Clap, Debug)]
+pub struct Gc {
+    /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.pijul` directory.
+    #[clap(long = "repository")]
+    repo_path: Option<PathBuf>,
+    /// Purge all deleted channels, regardless of when they were deleted
+    #[clap(long = "all")]
+    all: bool,
+}
+
+impl Gc {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let retention = if self.all {
+            std::time::Duration::from_secs(0)
+        } else {
+            repo.config.channel_retention()
+        };
+        let mut txn = repo.pristine.mut_txn_begin()?;
+        let purged = libpijul::channel::purge(&mut txn, retention)?;
+        txn.commit()?;
+        let mut stdout = std::io::stdout();
+        for d in purged {
+            writeln!(stdout, "Purged deleted channel {}", d.name)?;
+        }
+        Ok(())
+    }
+}
@@ -253,7 +253,7 @@ fn import(
     for &(oid, merkle) in dag.root.iter() {
         if let Some(merkle) = merkle {
             let oid_ = format!("{}", oid);
-            let channel = if let Some(c) = txn.read().load_channel(&oid_)? {
+            let channel = if let Some(c) = libpijul::channel::load_channel(&*txn.read(), &oid_)? {
                 c
             } else {
                 bail!("Channel not found: {:?}", oid);
@@ -301,7 +301,8 @@ fn import(
                     let first_parent = parents.iter().next().unwrap();
                     let parent_name = format!("{}", first_parent);
                     let mut txn = txn.write();
-                    let parent_channel = txn.load_channel(&parent_name)?.unwrap();
+                    let parent_channel =
+                        libpijul::channel::load_channel(&*txn, &parent_name)?.unwrap();
 
                     let name = format!("{}", oid);
                     let channel = txn.fork(&parent_channel, &name)?;
@@ -311,7 +312,7 @@ fn import(
                     // Create a new channel for this commit.
                     let name = format!("{}", oid);
                     let mut txn = txn.write();
-                    let channel = txn.open_or_create_channel(&name)?;
+                    let channel = libpijul::channel::open_or_create_channel(&mut *txn, &name)?;
                     channel
                 };
 
@@ -382,7 +383,7 @@ fn make_apply_plan<T: TxnTExt>(
                 }
             }
             let p_name = format!("{}", p);
-            let p_channel = txn.load_channel(&p_name)?.unwrap();
+            let p_channel = libpijul::channel::load_channel(&*txn, &p_name)?.unwrap();
             for x in txn.log(&*p_channel.read(), 0)? {
                 let (n, (h, _)) = x?;
                 let h: libpijul::Hash = h.into();
@@ -686,6 +687,7 @@ fn import_commit<T: TxnTExt + MutTxnTExt + GraphIter + Send + Sync + 'static>(
                 chrono::Utc,
             ),
         },
+        chrono::FixedOffset::east(signature.when().offset_minutes() * 60),
     );
     {
         let mut txn = txn.write();
@@ -728,11 +730,14 @@ fn record_apply<
     repo_path: &CanonicalPathBuf,
     prefixes: &[PathBuf],
     header: libpijul::change::ChangeHeader,
+    timezone: chrono::FixedOffset,
 ) -> Result<
     (usize, Option<libpijul::Hash>, libpijul::Merkle),
     libpijul::LocalApplyError<T::GraphError>,
 > {
+    use libpijul::working_copy::WorkingCopy;
     let mut state = libpijul::RecordBuilder::new();
+    state.graph_limits = working_copy.graph_limits();
     let num_cpus = num_cpus::get();
     for p in prefixes.iter() {
         use libpijul::working_copy::filesystem::*;
@@ -776,7 +781,7 @@ fn record_apply<
             }
         }
     }
-    let rec = state.finish();
+    let rec = state.finish()?;
     let mut txn = txn.write();
     if rec.actions.is_empty() {
         return Ok((0, None, txn.current_state(&channel.read()).map_err(TxnErr)?));
@@ -797,6 +802,7 @@ fn record_apply<
         header,
         Vec::new(),
     )?;
+    change.set_timezone(timezone);
     change.dependencies = dependencies;
     change.extra_known = extra_known;
     debug!("saving change");
@@ -107,7 +107,12 @@ impl Key {
                         bail!("No such remote: {}", remote)
                     }
                 } else if let Some(mut ssh) = crate::remote::ssh::ssh_remote(&remote, false) {
-                    ssh.connect(&remote, crate::DEFAULT_CHANNEL).await?
+                    ssh.connect(
+                        &remote,
+                        crate::DEFAULT_CHANNEL,
+                        &crate::config::RemoteFeatures::global(),
+                    )
+                    .await?
                 } else {
                     bail!("No such remote: {}", remote)
                 };
@@ -1,9 +1,10 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::PathBuf;
 
 use anyhow::bail;
+use canonical_path::CanonicalPathBuf;
 use clap::Clap;
 use libpijul::changestore::*;
 use libpijul::{Base32, TxnT, TxnTExt};
@@ -27,12 +28,23 @@ pub struct Log {
     /// Include full change description in the output
     #[clap(long = "description")]
     descriptions: bool,
+    /// Include the changes rewritten into each change (by amending,
+    /// splitting or filtering) in the output
+    #[clap(long = "provenance")]
+    provenance: bool,
+    /// Include the CI statuses of each change in the output
+    #[clap(long = "ci")]
+    ci: bool,
     /// Start after this many changes
     #[clap(long = "offset")]
     offset: Option<usize>,
     /// Output at most this many changes
     #[clap(long = "limit")]
     limit: Option<usize>,
+    /// Only show the changes touching these files, including the
+    /// changes to the files they were copied from
+    #[clap(last = true)]
+    files: Vec<PathBuf>,
 }
 
 impl Log {
@@ -49,17 +61,37 @@ impl Log {
         } else {
             bail!("No such channel: {:?}", channel_name)
         };
+        let mut filter = None;
+        if !self.files.is_empty() {
+            let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
+            let mut touched = HashSet::new();
+            for file in self.files.iter() {
+                let path = std::fs::canonicalize(crate::current_dir()?.join(file))?;
+                let path = path.strip_prefix(&repo_path.as_path())?.to_str().unwrap();
+                let (pos, _) = txn.follow_oldest_path(&repo.changes, &channel, path)?;
+                touched.extend(libpijul::fs::file_log(
+                    &repo.changes,
+                    &txn,
+                    &*channel.read(),
+                    pos,
+                )?);
+            }
+            filter = Some(touched)
+        }
         super::pager();
         let changes = repo.changes;
         let mut stdout = std::io::stdout();
         let limit = self.limit.unwrap_or(std::usize::MAX);
         let offset = self.offset.unwrap_or(0);
+        let log = txn.reverse_log(&*channel.read(), None)?.filter(|h| {
+            if let (Ok((_, (h, _))), Some(ref filter)) = (h, &filter) {
+                filter.contains(&(*h).into())
+            } else {
+                true
+            }
+        });
         if self.hash_only {
-            for h in txn
-                .reverse_log(&*channel.read(), None)?
-                .skip(offset)
-                .take(limit)
-            {
+            for h in log.skip(offset).take(limit) {
                 let h: libpijul::Hash = (h?.1).0.into();
                 writeln!(stdout, "{}", h.to_base32())?
             }
@@ -69,11 +101,7 @@ impl Log {
             let mut id_path = repo.path.join(libpijul::DOT_DIR);
             id_path.push("identities");
 
-            for h in txn
-                .reverse_log(&*channel.read(), None)?
-                .skip(offset)
-                .take(limit)
-            {
+            for h in log.skip(offset).take(limit) {
                 let (h, mrk) = h?.1;
                 let h: libpijul::Hash = h.into();
                 let mrk: libpijul::Merkle = mrk.into();
@@ -119,6 +147,29 @@ impl Log {
                 if states {
                     writeln!(stdout, "State: {}", mrk.to_base32())?;
                 }
+                if self.provenance {
+                    for l in libpijul::provenance::ancestry(&txn, &h)? {
+                        writeln!(
+                            stdout,
+                            "Rewrites: {} ({} by {}, {})",
+                            l.old.to_base32(),
+                            l.operation,
+                            l.actor,
+                            l.timestamp
+                        )?;
+                    }
+                }
+                if self.ci {
+                    let statuses = libpijul::ci_status::get(&txn, &h)?;
+                    if let Some(state) = statuses.iter().map(|s| s.state).max() {
+                        write!(stdout, "CI: {}", state)?;
+                        for (i, s) in statuses.iter().enumerate() {
+                            let sep = if i == 0 { " (" } else { ", " };
+                            write!(stdout, "{}{}: {}", sep, s.name, s.state)?;
+                        }
+                        writeln!(stdout, ")")?;
+                    }
+                }
                 writeln!(stdout, "\n    {}\n", header.message)?;
                 if self.descriptions {
                     if let Some(ref descr) = header.description {
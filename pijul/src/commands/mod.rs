@@ -38,6 +38,12 @@ pub use reset::*;
 mod fork;
 pub use fork::*;
 
+mod gc;
+pub use gc::*;
+
+mod doctor;
+pub use doctor::*;
+
 mod unrecord;
 pub use unrecord::*;
 
@@ -74,16 +80,17 @@ fn pending<T: libpijul::MutTxnTExt + libpijul::TxnT + Send + Sync + 'static>(
     use libpijul::changestore::ChangeStore;
 
     let mut builder = libpijul::record::Builder::new();
+    builder.graph_limits = repo.config.graph_limits;
     builder.record(
         txn.clone(),
-        libpijul::Algorithm::default(),
+        libpijul::ChannelAlgorithm,
         channel.clone(),
-        &repo.working_copy,
+        &libpijul::opaque::Opaque::new(repo.working_copy.clone())?,
         &repo.changes,
         "",
         num_cpus::get(),
     )?;
-    let recorded = builder.finish();
+    let recorded = builder.finish()?;
     if recorded.actions.is_empty() {
         return Ok(None);
     }
@@ -259,6 +266,77 @@ fn load_key() -> Result<libpijul::key::SKey, anyhow::Error> {
     }
 }
 
+/// Roll back the operation on `channel` left unfinished by an
+/// interrupted command, if any, and output the paths it touched again
+/// if `channel` is the current one. The other files of the working
+/// copy are left as they are.
+fn recover_journal(
+    repo: &crate::repository::Repository,
+    txn: &libpijul::ArcTxn<libpijul::pristine::sanakirja::MutTxn<()>>,
+    channel: &libpijul::ChannelRef<libpijul::pristine::sanakirja::MutTxn<()>>,
+    is_current_channel: bool,
+) -> Result<(), anyhow::Error> {
+    use libpijul::ChannelTxnT;
+    let name = txn.read().name(&*channel.read()).to_string();
+    let touched = if let Some(intent) = libpijul::journal::load(&*txn.read(), &name)? {
+        libpijul::journal::touched_paths(&repo.changes, &intent)?
+    } else {
+        return Ok(());
+    };
+    let intent = libpijul::journal::recover(
+        &mut *txn.write(),
+        &repo.changes,
+        channel,
+        libpijul::journal::Direction::Back,
+        0,
+    )?;
+    if let Some(intent) = intent {
+        eprintln!(
+            "Rolled back an interrupted {} of {} change(s)",
+            match intent.operation {
+                libpijul::journal::Operation::Apply => "apply",
+                libpijul::journal::Operation::Unrecord => "unrecord",
+            },
+            intent.hashes.len()
+        );
+        if is_current_channel {
+            let touched = touched.unwrap_or_else(|| vec![String::new()]);
+            for path in touched.iter() {
+                libpijul::output::output_repository_no_pending(
+                    &repo.working_copy,
+                    &repo.changes,
+                    txn,
+                    channel,
+                    path,
+                    true,
+                    None,
+                    num_cpus::get(),
+                    0,
+                )?;
+            }
+            // The restored files must be durable before the rollback
+            // is committed.
+            use libpijul::working_copy::WorkingCopy;
+            repo.working_copy.sync()?;
+        }
+    }
+    Ok(())
+}
+
+/// The identity recorded in the audit log: the user's public key if
+/// there is one, else an empty string.
+fn audit_identity() -> String {
+    if let Some(mut dir) = crate::config::global_config_dir() {
+        dir.push("publickey.json");
+        if let Ok(key) = std::fs::File::open(&dir) {
+            if let Ok(k) = serde_json::from_reader::<_, libpijul::key::PublicKey>(key) {
+                return k.key;
+            }
+        }
+    }
+    String::new()
+}
+
 fn find_hash(path: &mut std::path::PathBuf, hash: &str) -> Result<libpijul::Hash, anyhow::Error> {
     use libpijul::Base32;
     if hash.len() < 2 {
@@ -295,7 +373,7 @@ fn find_hash(path: &mut std::path::PathBuf, hash: &str) -> Result<libpijul::Hash
     bail!("Hash not found")
 }
 
-use libpijul::Conflict;
+use libpijul::{Base32, Conflict};
 fn print_conflicts(conflicts: &[Conflict]) -> Result<(), std::io::Error> {
     if conflicts.is_empty() {
         return Ok(());
@@ -334,3 +412,28 @@ fn print_conflicts(conflicts: &[Conflict]) -> Result<(), std::io::Error> {
     }
     Ok(())
 }
+
+fn print_rename_edit_conflicts(
+    conflicts: &[libpijul::RenameEditConflict],
+) -> Result<(), std::io::Error> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let mut w = termcolor::StandardStream::stderr(termcolor::ColorChoice::Auto);
+    use std::io::Write;
+    use termcolor::*;
+    w.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+    writeln!(w, "\nSome files were edited and renamed concurrently:\n")?;
+    w.set_color(ColorSpec::new().set_fg(None))?;
+    for c in conflicts.iter() {
+        writeln!(
+            w,
+            "  - \"{}\" was edited by {} and renamed to \"{}\" by {}",
+            c.edited_path,
+            c.edit.to_base32(),
+            c.current_path,
+            c.rename.to_base32()
+        )?
+    }
+    Ok(())
+}
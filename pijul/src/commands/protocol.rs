@@ -8,6 +8,7 @@ use anyhow::bail;
 use byteorder::{BigEndian, WriteBytesExt};
 use clap::Clap;
 use lazy_static::lazy_static;
+use libpijul::redact::{Redactor, Visibility};
 use libpijul::*;
 use log::{debug, error};
 use regex::Regex;
@@ -22,6 +23,16 @@ pub struct Protocol {
     /// Use this protocol version
     #[clap(long = "version")]
     version: usize,
+    /// Reject changes that would make the changes of this repository larger than this number of bytes
+    #[clap(long = "quota")]
+    quota: Option<u64>,
+    /// Reject the changes whose contents are larger than this number of bytes once loaded
+    #[clap(long = "max-memory")]
+    max_memory: Option<u64>,
+    /// Name of the requester, used to hide redacted changes from
+    /// unauthorized requesters (usually set by the SSH forced command)
+    #[clap(long = "requester")]
+    requester: Option<String>,
 }
 
 lazy_static! {
@@ -29,8 +40,14 @@ lazy_static! {
     static ref ID: Regex = Regex::new(r#"id\s+(\S+)\s+"#).unwrap();
     static ref IDENTITIES: Regex = Regex::new(r#"identities(\s+([0-9]+))?\s+"#).unwrap();
     static ref CHANGELIST: Regex = Regex::new(r#"changelist\s+(\S+)\s+([0-9]+)(.*)\s+"#).unwrap();
+    static ref CHANGELIST_SINCE: Regex = Regex::new(r#"^\s*since\s+(\S+)"#).unwrap();
     static ref CHANGELIST_PATHS: Regex = Regex::new(r#""(((\\")|[^"])+)""#).unwrap();
     static ref CHANGE: Regex = Regex::new(r#"((change)|(partial))\s+([^ ]*)\s+"#).unwrap();
+    static ref CHUNKS: Regex = Regex::new(r#"chunks\s+(\S+)\s+"#).unwrap();
+    static ref PROVENANCE: Regex = Regex::new(r#"provenance\s+"#).unwrap();
+    static ref CI_STATUSES: Regex = Regex::new(r#"ci-statuses\s+"#).unwrap();
+    static ref NOTES: Regex = Regex::new(r#"notes\s+(\S+)\s+"#).unwrap();
+    static ref WANT: Regex = Regex::new(r#"want\s+(\S+)([0-9 ]*)\n"#).unwrap();
     static ref APPLY: Regex = Regex::new(r#"apply\s+(\S+)\s+([^ ]*) ([0-9]+)\s+"#).unwrap();
     static ref CHANNEL: Regex = Regex::new(r#"channel\s+(\S+)\s+"#).unwrap();
     static ref ARCHIVE: Regex =
@@ -38,20 +55,75 @@ lazy_static! {
 }
 
 fn load_channel<T: MutTxnTExt>(txn: &T, name: &str) -> Result<ChannelRef<T>, anyhow::Error> {
-    if let Some(c) = txn.load_channel(name)? {
+    if let Some(c) = libpijul::channel::load_channel(txn, name)? {
         Ok(c)
     } else {
         bail!("No such channel: {:?}", name)
     }
 }
 
+/// The state of `channel` after its `n`th change.
+fn state_at<T: TxnTExt>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    n: u64,
+) -> Result<Option<Merkle>, anyhow::Error> {
+    if let Some(x) = txn.log(&*channel.read(), n)?.next() {
+        let (n_, (_, m)) = x?;
+        if n_ == n {
+            return Ok(Some(m.into()));
+        }
+    }
+    Ok(None)
+}
+
 const PARTIAL_CHANGE_SIZE: u64 = 1 << 20;
 
+fn parse_hash(h: &str, buf: &str) -> Result<Hash, anyhow::Error> {
+    if let Some(h) = Hash::from_base32(h.as_bytes()) {
+        Ok(h)
+    } else {
+        debug!("protocol error: {:?}", buf);
+        bail!("Protocol error")
+    }
+}
+
+fn read_change(changes_dir: &mut PathBuf, h: &Hash) -> Result<Vec<u8>, std::io::Error> {
+    libpijul::changestore::filesystem::push_filename(changes_dir, h);
+    let r = std::fs::read(&changes_dir);
+    libpijul::changestore::filesystem::pop_filename(changes_dir);
+    r
+}
+
+/// The part of file `change` of change `h` that `requester` may see.
+fn visible_part(
+    repo: &mut Repository,
+    requester: Option<&str>,
+    h: &Hash,
+    mut change: Vec<u8>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let visibility = repo.config.redaction.visibility(requester, h);
+    let mut r = std::io::Cursor::new(&change);
+    if let Some(len) = libpijul::redact::visible_len(visibility, &mut r)? {
+        change.truncate(len as usize);
+        Ok(change)
+    } else {
+        libpijul::changestore::filesystem::push_filename(&mut repo.changes_dir, h);
+        let redacted = libpijul::redact::redact_file(&repo.changes_dir.to_string_lossy(), h);
+        libpijul::changestore::filesystem::pop_filename(&mut repo.changes_dir);
+        Ok(redacted?)
+    }
+}
+
 impl Protocol {
     pub fn run(self) -> Result<(), anyhow::Error> {
         let mut repo = Repository::find_root(self.repo_path)?;
         let txn = repo.pristine.arc_txn_begin()?;
-        let mut ws = libpijul::ApplyWorkspace::new();
+        let apply_options = libpijul::ApplyOptions {
+            max_memory: self.max_memory,
+            headers: repo.config.change_header.clone(),
+            ..libpijul::ApplyOptions::default()
+        };
         let mut buf = String::new();
         let mut buf2 = vec![0; 4096 * 10];
         let s = std::io::stdin();
@@ -59,6 +131,9 @@ impl Protocol {
         let o = std::io::stdout();
         let mut o = BufWriter::new(o.lock());
         let mut applied = HashMap::new();
+        let mut usage = None;
+        let mut pushed = 0u64;
+        let requester = self.requester.as_deref();
 
         debug!("reading");
         while s.read_line(&mut buf)? > 0 {
@@ -105,38 +180,60 @@ impl Protocol {
                 let mut paths = HashSet::new();
                 debug!("cap[3] = {:?}", &cap[3]);
                 let txn = txn.read();
-                for r in CHANGELIST_PATHS.captures_iter(&cap[3]) {
-                    let s: String = r[1].replace("\\\"", "\"");
-                    if let Ok((p, ambiguous)) = txn.follow_oldest_path(&repo.changes, &channel, &s)
-                    {
-                        if ambiguous {
-                            bail!("Ambiguous path")
-                        }
-                        let h: libpijul::Hash = txn.get_external(&p.change)?.unwrap().into();
-                        writeln!(o, "{}.{}", h.to_base32(), p.pos.0)?;
-                        paths.insert(p);
-                        paths.extend(
-                            libpijul::fs::iter_graph_descendants(&*txn, &channel.read().graph, p)?
+                // Clients polling the channel send the last state of
+                // their cached copy of it, at position `from - 1`. If
+                // the channel has another state there (because changes
+                // were unrecorded), the client has to look for the last
+                // state in common instead.
+                let outdated = if let Some(since) = CHANGELIST_SINCE.captures(&cap[3]) {
+                    let state = Merkle::from_base32(since[1].as_bytes());
+                    let ok = from > 0
+                        && state.is_some()
+                        && state_at(&*txn, &channel, from - 1)? == state;
+                    writeln!(o, "since: {}", if ok { "ok" } else { "outdated" })?;
+                    !ok
+                } else {
+                    false
+                };
+                if !outdated {
+                    for r in CHANGELIST_PATHS.captures_iter(&cap[3]) {
+                        let s: String = r[1].replace("\\\"", "\"");
+                        if let Ok((p, ambiguous)) =
+                            txn.follow_oldest_path(&repo.changes, &channel, &s)
+                        {
+                            if ambiguous {
+                                bail!("Ambiguous path")
+                            }
+                            let h: libpijul::Hash = txn.get_external(&p.change)?.unwrap().into();
+                            writeln!(o, "{}.{}", h.to_base32(), p.pos.0)?;
+                            paths.insert(p);
+                            paths.extend(
+                                libpijul::fs::iter_graph_descendants(
+                                    &*txn,
+                                    &channel.read().graph,
+                                    p,
+                                )?
                                 .map(|x| x.unwrap()),
-                        );
-                    } else {
-                        debug!("protocol line: {:?}", buf);
-                        bail!("Protocol error")
+                            );
+                        } else {
+                            debug!("protocol line: {:?}", buf);
+                            bail!("Protocol error")
+                        }
                     }
-                }
-                debug!("paths = {:?}", paths);
-                for x in txn.log(&*channel.read(), from)? {
-                    let (n, (h, m)) = x?;
-                    let h_int = txn.get_internal(h)?.unwrap();
-                    if paths.is_empty()
-                        || paths.iter().any(|x| {
-                            x.change == *h_int
-                                || txn.get_touched_files(x, Some(h_int)).unwrap().is_some()
-                        })
-                    {
-                        let h: Hash = h.into();
-                        let m: Merkle = m.into();
-                        writeln!(o, "{}.{}.{}", n, h.to_base32(), m.to_base32())?
+                    debug!("paths = {:?}", paths);
+                    for x in txn.log(&*channel.read(), from)? {
+                        let (n, (h, m)) = x?;
+                        let h_int = txn.get_internal(h)?.unwrap();
+                        if paths.is_empty()
+                            || paths.iter().any(|x| {
+                                x.change == *h_int
+                                    || txn.get_touched_files(x, Some(h_int)).unwrap().is_some()
+                            })
+                        {
+                            let h: Hash = h.into();
+                            let m: Merkle = m.into();
+                            writeln!(o, "{}.{}.{}", n, h.to_base32(), m.to_base32())?
+                        }
                     }
                 }
                 writeln!(o)?;
@@ -152,27 +249,98 @@ impl Protocol {
                 libpijul::changestore::filesystem::push_filename(&mut repo.changes_dir, &h);
                 debug!("repo = {:?}", repo.changes_dir);
                 let mut f = std::fs::File::open(&repo.changes_dir)?;
-                let size = std::fs::metadata(&repo.changes_dir)?.len();
-                let size = if &cap[1] == "change" || size <= PARTIAL_CHANGE_SIZE {
-                    size
-                } else {
-                    libpijul::change::Change::size_no_contents(&mut f)?
-                };
-                o.write_u64::<BigEndian>(size)?;
-                let mut size = size as usize;
-                while size > 0 {
-                    if size < buf2.len() {
-                        buf2.truncate(size as usize);
-                    }
-                    let n = f.read(&mut buf2[..])?;
-                    if n == 0 {
-                        break;
+                let visibility = repo.config.redaction.visibility(requester, &h);
+                if let Some(visible) = libpijul::redact::visible_len(visibility, &mut f)? {
+                    let size = std::fs::metadata(&repo.changes_dir)?.len();
+                    let size = if &cap[1] == "change" || size <= PARTIAL_CHANGE_SIZE {
+                        size
+                    } else {
+                        libpijul::change::Change::size_no_contents(&mut f)?
+                    };
+                    let size = size.min(visible);
+                    o.write_u64::<BigEndian>(size)?;
+                    let mut size = size as usize;
+                    while size > 0 {
+                        if size < buf2.len() {
+                            buf2.truncate(size as usize);
+                        }
+                        let n = f.read(&mut buf2[..])?;
+                        if n == 0 {
+                            break;
+                        }
+                        size -= n;
+                        o.write_all(&buf2[..n])?;
                     }
-                    size -= n;
-                    o.write_all(&buf2[..n])?;
+                } else {
+                    let redacted =
+                        libpijul::redact::redact_file(&repo.changes_dir.to_string_lossy(), &h)?;
+                    o.write_u64::<BigEndian>(redacted.len() as u64)?;
+                    o.write_all(&redacted)?;
                 }
                 o.flush()?;
                 libpijul::changestore::filesystem::pop_filename(&mut repo.changes_dir);
+            } else if let Some(cap) = CHUNKS.captures(&buf) {
+                // List the chunks of a change, so that the client can
+                // ask for the ones it doesn't have with `want`.
+                let h = parse_hash(&cap[1], &buf)?;
+                let change = read_change(&mut repo.changes_dir, &h)?;
+                let change = visible_part(&mut repo, requester, &h, change)?;
+                let mut list = Vec::new();
+                libpijul::chunks::write_list(&libpijul::chunks::split(&change), &mut list)?;
+                o.write_u64::<BigEndian>(list.len() as u64)?;
+                o.write_all(&list)?;
+                o.flush()?;
+            } else if PROVENANCE.is_match(&buf) {
+                // The provenance links of this repository, for the
+                // client to import.
+                let mut list = Vec::new();
+                libpijul::provenance::write_list(
+                    &libpijul::provenance::list(&*txn.read())?,
+                    &mut list,
+                )?;
+                o.write_u64::<BigEndian>(list.len() as u64)?;
+                o.write_all(&list)?;
+                o.flush()?;
+            } else if CI_STATUSES.is_match(&buf) {
+                let mut list = Vec::new();
+                libpijul::ci_status::write_list(
+                    &libpijul::ci_status::list(&*txn.read())?,
+                    &mut list,
+                )?;
+                o.write_u64::<BigEndian>(list.len() as u64)?;
+                o.write_all(&list)?;
+                o.flush()?;
+            } else if let Some(cap) = NOTES.captures(&buf) {
+                let mut list = Vec::new();
+                libpijul::notes::write_list(
+                    &libpijul::notes::list(&*txn.read(), &cap[1])?,
+                    &mut list,
+                )?;
+                o.write_u64::<BigEndian>(list.len() as u64)?;
+                o.write_all(&list)?;
+                o.flush()?;
+            } else if let Some(cap) = WANT.captures(&buf) {
+                let h = parse_hash(&cap[1], &buf)?;
+                let change = read_change(&mut repo.changes_dir, &h)?;
+                let change = visible_part(&mut repo, requester, &h, change)?;
+                let mut starts = Vec::new();
+                let mut start = 0;
+                for c in libpijul::chunks::split(&change) {
+                    starts.push((start, c.len));
+                    start += c.len
+                }
+                let mut wanted = Vec::new();
+                for i in cap[2].split_whitespace() {
+                    if let Some(&(start, len)) = i.parse().ok().and_then(|i: usize| starts.get(i)) {
+                        wanted.extend_from_slice(&change[start..start + len])
+                    } else {
+                        debug!("protocol error: {:?}", buf);
+                        bail!("Protocol error")
+                    }
+                }
+                o.write_u64::<BigEndian>(wanted.len() as u64)?;
+                o.write_all(&wanted)?;
+                o.flush()?;
             } else if let Some(cap) = APPLY.captures(&buf) {
                 let h = if let Some(h) = Hash::from_base32(cap[2].as_bytes()) {
                     h
@@ -184,15 +352,43 @@ impl Protocol {
                 libpijul::changestore::filesystem::push_filename(&mut path, &h);
                 std::fs::create_dir_all(path.parent().unwrap())?;
                 let size: usize = cap[3].parse().unwrap();
+                if let Some(ref quota) = self.quota {
+                    if usage.is_none() {
+                        usage = Some(libpijul::quota::usage(&*txn.read(), &repo.changes)?);
+                    }
+                    let usage = usage.as_ref().unwrap();
+                    pushed += size as u64;
+                    if !libpijul::quota::Quota::allows(quota, usage, pushed) {
+                        bail!("Quota exceeded")
+                    }
+                }
                 buf2.resize(size, 0);
                 s.read_exact(&mut buf2)?;
+                if libpijul::redact::is_redacted_file(&buf2, &h)? {
+                    bail!("Change {} is redacted", &cap[2])
+                }
                 std::fs::write(&path, &buf2)?;
-                libpijul::change::Change::deserialize(&path.to_string_lossy(), Some(&h))?;
+                if let Some(max) = self.max_memory {
+                    let len =
+                        libpijul::change::Change::contents_len(&mut std::fs::File::open(&path)?)?;
+                    if len > max {
+                        std::fs::remove_file(&path)?;
+                        bail!("Change {} is larger than {} bytes", &cap[2], max)
+                    }
+                }
                 let channel = load_channel(&*txn.read(), &cap[1])?;
                 {
                     let mut channel_ = channel.write();
-                    txn.write()
-                        .apply_change_ws(&repo.changes, &mut channel_, &h, &mut ws)?;
+                    let result = txn.write().apply_change_rec_opt(
+                        &repo.changes,
+                        &mut channel_,
+                        &h,
+                        &apply_options,
+                    );
+                    if let Err(libpijul::ApplyError::InvalidHeader { .. }) = result {
+                        std::fs::remove_file(&path)?;
+                    }
+                    result?;
                 }
                 applied.insert(cap[1].to_string(), channel);
             } else if let Some(cap) = ARCHIVE.captures(&buf) {
@@ -202,7 +398,15 @@ impl Protocol {
                     cap.get(6).map(|x| x.as_str().to_string()),
                     0,
                 );
+                tarball.graph_limits = repo.config.graph_limits;
                 let channel = load_channel(&*txn.read(), &cap[1])?;
+                for x in txn.read().log(&*channel.read(), 0)? {
+                    let (_, (h, _)) = x?;
+                    let h: Hash = h.into();
+                    if repo.config.redaction.visibility(requester, &h) != Visibility::Full {
+                        bail!("Channel {} contains redacted changes", &cap[1])
+                    }
+                }
                 let conflicts = if let Some(caps) = cap.get(2) {
                     debug!("caps = {:?}", caps.as_str());
                     let mut hashes = caps.as_str().split(' ').filter(|x| !x.is_empty());
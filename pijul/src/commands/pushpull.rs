@@ -215,7 +215,8 @@ impl Push {
             )
             .await?;
 
-        let mut channel = txn.write().open_or_create_channel(&channel_name)?;
+        let mut channel =
+            libpijul::channel::open_or_create_channel(&mut *txn.write(), &channel_name)?;
 
         let PushDelta {
             remote_ref,
@@ -267,7 +268,7 @@ impl Push {
         } else {
             let mut o = make_changelist(&repo.changes, &to_upload, "push")?;
             loop {
-                let d = parse_changelist(&edit::edit_bytes(&o[..])?);
+                let d = parse_changelist(&::edit::edit_bytes(&o[..])?);
                 let comp = complete_deps(&*txn.read(), &remote_ref, &repo.changes, &to_upload, &d)?;
                 if comp.len() == d.len() {
                     break comp;
@@ -342,6 +343,7 @@ impl Pull {
 
     pub async fn run(self) -> Result<(), anyhow::Error> {
         let mut repo = Repository::find_root(self.repo_path.clone())?;
+        repo.working_copy.set_durability(repo.config.durability_for("pull"));
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -354,7 +356,8 @@ impl Pull {
             cur.as_str()
         };
         let is_current_channel = channel_name == cur;
-        let mut channel = txn.write().open_or_create_channel(&channel_name)?;
+        let mut channel =
+            libpijul::channel::open_or_create_channel(&mut *txn.write(), &channel_name)?;
         debug!("{:?}", repo.config);
         let remote_name = if let Some(ref rem) = self.from {
             rem
@@ -395,6 +398,12 @@ impl Pull {
         if let Some(ref r) = remote_ref {
             remote.update_identities(&mut repo, r).await?;
         }
+        let links = remote.update_provenance(&mut *txn.write()).await?;
+        debug!("{:?} new provenance links", links);
+        let statuses = remote.update_ci_statuses(&mut *txn.write()).await?;
+        debug!("{:?} CI statuses updated", statuses);
+        let reviews = remote.update_reviews(&mut *txn.write()).await?;
+        debug!("{:?} review annotations updated", reviews);
 
         notify_remote_unrecords(&repo, remote_unrecs.as_slice());
 
@@ -411,7 +420,7 @@ impl Pull {
         if !self.all {
             let mut o = make_changelist(&repo.changes, &to_download, "pull")?;
             to_download = loop {
-                let d = parse_changelist(&edit::edit_bytes(&o[..])?);
+                let d = parse_changelist(&::edit::edit_bytes(&o[..])?);
                 let comp = complete_deps(&*txn.read(), &None, &repo.changes, &to_download, &d)?;
                 if comp.len() == d.len() {
                     break comp;
@@ -445,6 +454,13 @@ impl Pull {
             .await?;
         remote.finish().await?;
 
+        let migrated = if repo.config.migrate_rewritten {
+            libpijul::provenance::migrate(&mut *txn.write(), &repo.changes, &channel)?
+        } else {
+            Vec::new()
+        };
+        debug!("migrated = {:?}", migrated);
+
         debug!("inodes = {:?}", inodes);
         debug!("to_download: {:?}", to_download.len());
         let mut touched = HashSet::new();
@@ -484,6 +500,11 @@ impl Pull {
                     }
                 }
             }
+            if !migrated.is_empty() {
+                // The rewritten changes unrecorded may have touched
+                // any file, output everything.
+                touched_paths.clear()
+            }
             touched_paths.sort();
             let mut last = "";
             PROGRESS
@@ -615,7 +636,7 @@ fn notify_remote_unrecords(repo: &Repository, remote_unrecs: &[(u64, Hash)]) {
             writeln!(&mut s, "#    {}", header.timestamp).expect("Infallible write to String");
             writeln!(&mut s, "#    {}", hash.to_base32()).expect("Infallible write to String");
         }
-        if let Err(e) = edit::edit(s.as_str()) {
+        if let Err(e) = ::edit::edit(s.as_str()) {
             log::error!(
                 "Notification of remote unrecords experienced an error: {}",
                 e
@@ -641,7 +662,7 @@ fn notify_unknown_changes(unknown_changes: &[Hash]) {
             writeln!(&mut s, "#     ... plus {} more change{}", rest_len, plural)
                 .expect("Infallible write to String");
         }
-        if let Err(e) = edit::edit(s.as_str()) {
+        if let Err(e) = ::edit::edit(s.as_str()) {
             log::error!(
                 "Notification of unknown changes experienced an error: {}",
                 e
@@ -1,6 +1,5 @@
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::Arc;
 
 use anyhow::bail;
 use canonical_path::{CanonicalPath, CanonicalPathBuf};
@@ -8,10 +7,11 @@ use chrono::Utc;
 use clap::Clap;
 use libpijul::change::*;
 use libpijul::changestore::*;
+use libpijul::record::SESSION_FILE;
+use libpijul::HashSet;
 use libpijul::{
     ArcTxn, Base32, ChannelMutTxnT, ChannelRef, ChannelTxnT, MutTxnTExt, TxnT, TxnTExt,
 };
-use libpijul::{HashMap, HashSet};
 use log::debug;
 
 use crate::repository::*;
@@ -45,6 +45,39 @@ pub struct Record {
     #[clap(long = "amend")]
     #[allow(clippy::option_option)]
     pub amend: Option<Option<String>>,
+    /// Reorder the hunks of the change, by "path" or by "kind"
+    #[clap(long = "sort-hunks")]
+    pub sort_hunks: Option<libpijul::record::SortOrder>,
+    /// Number of threads reading new files, to speed up the first record of large trees
+    #[clap(long = "io-concurrency", default_value = "1")]
+    pub io_concurrency: usize,
+    /// Record new files identical to a tracked file as copies of that file
+    #[clap(long = "detect-copies")]
+    pub detect_copies: bool,
+    /// Record the words that changed in modified lines, instead of whole lines
+    #[clap(long = "word-diff")]
+    pub word_diff: bool,
+    /// Join the edits separated by at most this many unchanged lines into a single edit
+    #[clap(long = "join-distance")]
+    pub join_distance: Option<usize>,
+    /// Only record the paths matching these glob patterns, and not matching the patterns starting with "!"
+    #[clap(long = "filter")]
+    pub filter: Vec<String>,
+    /// Diff files larger than this many bytes without reading them entirely, keeping only the parts not found in the recorded version (text files are read entirely with word diffs or when normalising line endings)
+    #[clap(long = "stream-threshold")]
+    pub stream_threshold: Option<u64>,
+    /// Record the missing files similar to an added file as moved to that file, if their similarity (between 0 and 1) is at least this
+    #[clap(long = "detect-renames", conflicts_with = "prefixes")]
+    pub detect_renames: Option<f64>,
+    /// Annotate the blocks of lines moved within the change with their origin
+    #[clap(long = "detect-moves")]
+    pub detect_moves: bool,
+    /// Skip files that can't be read instead of failing, and list them
+    #[clap(long = "skip-unreadable")]
+    pub skip_unreadable: bool,
+    /// Save the recording in progress under .pijul, and resume it if a previous recording was interrupted
+    #[clap(long = "checkpoint")]
+    pub checkpoint: bool,
     /// Paths in which to record the changes
     pub prefixes: Vec<PathBuf>,
 }
@@ -78,11 +111,14 @@ impl Record {
         } else {
             cur.as_str()
         };
-        let mut channel = if let Some(channel) = txn.read().load_channel(&channel)? {
-            channel
-        } else {
-            bail!("Channel {:?} not found", channel);
-        };
+        // Refuse to record in the middle of a conflict resolution.
+        libpijul::resolution::finish(&mut *txn.write(), channel)?;
+        let mut channel =
+            if let Some(channel) = libpijul::channel::load_channel(&*txn.read(), &channel)? {
+                channel
+            } else {
+                bail!("Channel {:?} not found", channel);
+            };
 
         let mut extra = Vec::new();
         for h in repo.config.extra_dependencies.iter() {
@@ -101,6 +137,7 @@ impl Record {
             extra.push(h)
         }
 
+        let mut amended = None;
         let header = if let Some(ref amend) = self.amend {
             let h = if let Some(ref hash) = amend {
                 txn.read().hash_from_prefix(hash)?.0
@@ -124,12 +161,15 @@ impl Record {
                 &h,
                 self.timestamp.unwrap_or(0) as u64,
             )?;
+            amended = Some(h);
             header
         } else {
             self.header()?
         };
-        let no_prefixes =
-            self.prefixes.is_empty() && !self.ignore_missing && self.working_copy.is_none();
+        let no_prefixes = self.prefixes.is_empty()
+            && self.filter.is_empty()
+            && !self.ignore_missing
+            && self.working_copy.is_none();
         let (repo_path, working_copy) = if let Some(ref w) = self.working_copy {
             (
                 CanonicalPathBuf::canonicalize(w)?,
@@ -141,6 +181,11 @@ impl Record {
 
         let key = super::load_key()?;
 
+        let session = if self.checkpoint {
+            Some(repo.path.join(libpijul::DOT_DIR).join(RECORD_SESSION))
+        } else {
+            None
+        };
         let result = self.record(
             txn,
             channel.clone(),
@@ -149,15 +194,36 @@ impl Record {
             repo_path,
             header,
             &extra,
+            &repo.config.change_header,
+            session.as_deref(),
         )?;
         match result {
-            Either::A((txn, mut change, updates, hash, oldest)) => {
+            Either::A((txn, mut change, rec, hash)) => {
                 let hash = hash.unwrap();
                 change.unhashed = Some(serde_json::json!({
                     "signature": key.sign_raw(&hash.to_bytes())?,
                 }));
                 let mut txn_ = txn.write();
-                txn_.apply_local_change(&mut channel, &change, &hash, &updates)?;
+                txn_.apply_local_change(&mut channel, &change, &hash, &rec.updatables)?;
+                rec.save_file_stats(&mut *txn_, &*channel.read())?;
+                let channel_name = txn_.name(&*channel.read()).to_string();
+                libpijul::audit::append(
+                    &mut *txn_,
+                    &super::audit_identity(),
+                    libpijul::audit::AuditOperation::Record { hash },
+                    &channel_name,
+                )?;
+                if let Some(old) = amended {
+                    libpijul::provenance::add(
+                        &mut *txn_,
+                        libpijul::provenance::ProvenanceLink::new(
+                            old,
+                            hash,
+                            libpijul::provenance::ProvenanceOperation::Amend,
+                            &super::audit_identity(),
+                        ),
+                    )?;
+                }
                 let mut path = repo.path.join(libpijul::DOT_DIR);
                 path.push("identities");
                 std::fs::create_dir_all(&path)?;
@@ -165,9 +231,10 @@ impl Record {
                 std::fs::File::create(&path)?;
 
                 writeln!(stdout, "Hash: {}", hash.to_base32())?;
-                debug!("oldest = {:?}", oldest);
+                debug!("oldest = {:?}", rec.oldest_change);
                 if no_prefixes {
-                    let mut oldest = oldest
+                    let mut oldest = rec
+                        .oldest_change
                         .duration_since(std::time::SystemTime::UNIX_EPOCH)
                         .unwrap()
                         .as_secs() as u64;
@@ -186,14 +253,22 @@ impl Record {
                 std::mem::drop(txn_);
                 txn.commit()?;
             }
-            Either::B(txn) => {
+            Either::B((txn, rec)) => {
                 if no_prefixes {
-                    txn.write().touch_channel(&mut *channel.write(), None);
+                    let mut txn_ = txn.write();
+                    rec.save_file_stats(&mut *txn_, &*channel.read())?;
+                    txn_.touch_channel(&mut *channel.write(), None);
+                    std::mem::drop(txn_);
                     txn.commit()?;
                 }
                 writeln!(stderr, "Nothing to record")?;
             }
         }
+        if let Some(session) = session {
+            if session.exists() {
+                std::fs::remove_dir_all(&session)?
+            }
+        }
         Ok(())
     }
 
@@ -262,6 +337,7 @@ impl Record {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn record<
         T: TxnTExt + MutTxnTExt + Sync + Send + 'static,
         C: ChangeStore + Send + Clone + 'static,
@@ -274,23 +350,71 @@ impl Record {
         repo_path: CanonicalPathBuf,
         header: ChangeHeader,
         extra_deps: &[libpijul::Hash],
+        header_policy: &libpijul::header::HeaderPolicy,
+        session: Option<&std::path::Path>,
     ) -> Result<
         Either<
             (
                 ArcTxn<T>,
                 Change,
-                HashMap<usize, libpijul::InodeUpdate>,
+                libpijul::record::Recorded,
                 Option<libpijul::Hash>,
-                std::time::SystemTime,
             ),
-            ArcTxn<T>,
+            (ArcTxn<T>, libpijul::record::Recorded),
         >,
         anyhow::Error,
     > {
-        let mut state = libpijul::RecordBuilder::new();
-        if self.ignore_missing {
-            state.ignore_missing = true;
-        }
+        use libpijul::working_copy::WorkingCopy;
+        let resumed = if let Some(dir) = session.filter(|d| d.join(SESSION_FILE).exists()) {
+            match libpijul::RecordBuilder::resume_session(&*txn.read(), &*channel.read(), dir) {
+                Ok((state, _)) => Some(state),
+                Err(e) => {
+                    writeln!(
+                        std::io::stderr(),
+                        "Discarding the interrupted recording: {}",
+                        e
+                    )?;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut state = if let Some(state) = resumed {
+            state
+        } else {
+            let mut state = libpijul::RecordBuilder::new();
+            if self.ignore_missing {
+                state.ignore_missing = true;
+            }
+            state.io_concurrency = self.io_concurrency;
+            state.detect_copies = self.detect_copies;
+            state.word_diff = self.word_diff;
+            state.join_distance = self.join_distance;
+            if !self.filter.is_empty() {
+                state.filter = Some(libpijul::record::PathFilter::new(&self.filter));
+            }
+            state.stream_threshold = self.stream_threshold;
+            if self.skip_unreadable {
+                state.read_error_policy = libpijul::record::ReadErrorPolicy::Skip;
+            }
+            state.detect_renames = self.detect_renames;
+            state.checkpoint = session.map(|s| s.to_path_buf());
+            state
+        };
+        state.graph_limits = working_copy.graph_limits();
+        // Opaque directories are recorded as archives.
+        let opaque = libpijul::opaque::Opaque::new(working_copy.clone())?;
+        let scanning =
+            crate::progress::PROGRESS
+                .borrow_mut()
+                .unwrap()
+                .push(crate::progress::Cursor::Bar {
+                    i: 0,
+                    n: 0,
+                    pre: "Scanning files".into(),
+                });
+        state.observer = Some(std::sync::Arc::new(Scanning(scanning)));
         if self.prefixes.is_empty() {
             if self.ignore_missing {
                 for f in ignore::Walk::new(&repo_path) {
@@ -300,9 +424,9 @@ impl Record {
                         let p = p.as_path().strip_prefix(&repo_path).unwrap();
                         state.record(
                             txn.clone(),
-                            libpijul::Algorithm::default(),
+                            libpijul::ChannelAlgorithm,
                             channel.clone(),
-                            working_copy,
+                            &opaque,
                             changes,
                             p.to_str().unwrap(),
                             1, // num_cpus::get(),
@@ -312,12 +436,12 @@ impl Record {
             } else {
                 state.record(
                     txn.clone(),
-                    libpijul::Algorithm::default(),
+                    libpijul::ChannelAlgorithm,
                     channel.clone(),
-                    working_copy,
+                    &opaque,
                     changes,
                     "",
-                    1, // num_cpus::get(),
+                    num_cpus::get(),
                 )?
             }
         } else {
@@ -329,29 +453,41 @@ impl Record {
                 &mut state,
                 repo_path,
                 &self.prefixes,
-                1, // num_cpus::get(),
+                num_cpus::get(),
                 self.timestamp.unwrap_or(0) as u64,
             )?;
         }
 
-        let mut rec = state.finish();
+        crate::progress::PROGRESS.join();
+
+        for r in state.renames.iter() {
+            eprintln!(
+                "Detected rename {:?} -> {:?} ({:.0}% similar)",
+                r.from,
+                r.to,
+                r.similarity * 100.
+            );
+        }
+        let (mut rec, report) = state.finish_with_report()?;
+        for skipped in report.skipped.iter() {
+            eprintln!("Skipped {:?}: {}", skipped.path, skipped.error);
+        }
         if rec.actions.is_empty() {
-            return Ok(Either::B(txn));
+            return Ok(Either::B((txn, rec)));
+        }
+        if let Some(order) = self.sort_hunks {
+            rec.sort_hunks(order)
         }
         debug!("TAKING LOCK {}", line!());
         let txn_ = txn.write();
-        let actions = rec
-            .actions
+        let actions = std::mem::take(&mut rec.actions)
             .into_iter()
             .map(|rec| rec.globalize(&*txn_).unwrap())
             .collect();
-        let contents = if let Ok(c) = Arc::try_unwrap(rec.contents) {
-            c.into_inner()
-        } else {
-            unreachable!()
-        };
+        let contents = std::mem::take(&mut *rec.contents.lock());
         let mut change =
             LocalChange::make_change(&*txn_, &channel, actions, contents, header, Vec::new())?;
+        change.set_timezone(*chrono::Local::now().offset());
 
         let current: HashSet<_> = change.dependencies.iter().cloned().collect();
         for dep in extra_deps.iter() {
@@ -409,17 +545,16 @@ impl Record {
         if change.header.message.trim().is_empty() {
             bail!("No change message")
         }
+        libpijul::header::check(header_policy, &change.header)?;
+        if self.detect_moves {
+            let moves = change.detect_moves(changes, &Default::default())?;
+            change.annotate_moves(&moves);
+        }
         debug!("saving change");
         let hash = changes.save_change(&change)?;
         debug!("saved");
         std::mem::drop(txn_);
-        Ok(Either::A((
-            txn,
-            change,
-            rec.updatables,
-            Some(hash),
-            rec.oldest_change,
-        )))
+        Ok(Either::A((txn, change, rec, Some(hash))))
     }
 }
 
@@ -428,7 +563,26 @@ enum Either<A, B> {
     B(B),
 }
 
+/// Name of the directory of the recording in progress with
+/// `--checkpoint`, in the `.pijul` directory.
+const RECORD_SESSION: &str = "record-session";
+
 const SYNTAX_ERROR: &str = "# Syntax errors, please try again.
 # Alternatively, you may delete the entire file (including this
 # comment) to abort.
 ";
+
+/// Shows the paths scanned by record in a progress bar.
+struct Scanning(usize);
+
+impl libpijul::record::RecordObserver for Scanning {
+    fn started(&self, files: usize) {
+        let mut progress = crate::progress::PROGRESS.borrow_mut().unwrap();
+        if let crate::progress::Cursor::Bar { ref mut n, .. } = progress[self.0] {
+            *n += files
+        }
+    }
+    fn file_scanned(&self, _path: &str) {
+        crate::progress::PROGRESS.borrow_mut().unwrap()[self.0].incr()
+    }
+}
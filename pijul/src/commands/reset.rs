@@ -38,6 +38,8 @@ impl Reset {
     fn reset(self, overwrite_changes: bool) -> Result<(), anyhow::Error> {
         let has_repo_path = self.repo_path.is_some();
         let repo = Repository::find_root(self.repo_path)?;
+        repo.working_copy
+            .set_durability(repo.config.durability_for("reset"));
         let txn = repo.pristine.arc_txn_begin()?;
 
         let cur = txn
@@ -51,11 +53,12 @@ impl Reset {
             cur.as_str()
         };
         let repo_path = CanonicalPathBuf::canonicalize(&repo.path)?;
-        let channel = if let Some(channel) = txn.read().load_channel(&channel_name)? {
-            channel
-        } else {
-            bail!("No such channel: {:?}", channel_name)
-        };
+        let channel =
+            if let Some(channel) = libpijul::channel::load_channel(&*txn.read(), &channel_name)? {
+                channel
+            } else {
+                bail!("No such channel: {:?}", channel_name)
+            };
 
         if self.dry_run {
             if self.files.len() != 1 {
@@ -84,6 +87,7 @@ impl Reset {
                 &channel.read(),
                 pos,
                 &mut libpijul::vertex_buffer::Writer::new(std::io::stdout()),
+                repo.config.graph_limits,
             )?;
             return Ok(());
         }
@@ -103,20 +107,21 @@ impl Reset {
             }
             let channel = {
                 let txn = txn.read();
-                txn.load_channel(&current_channel)?
+                libpijul::channel::load_channel(&*txn, &current_channel)?
             };
             if let Some(channel) = channel {
                 let mut state = libpijul::RecordBuilder::new();
+                state.graph_limits = repo.config.graph_limits;
                 state.record(
                     txn.clone(),
-                    libpijul::Algorithm::default(),
+                    libpijul::ChannelAlgorithm,
                     channel.clone(),
-                    &repo.working_copy,
+                    &libpijul::opaque::Opaque::new(repo.working_copy.clone())?,
                     &repo.changes,
                     "",
                     num_cpus::get(),
                 )?;
-                let rec = state.finish();
+                let rec = state.finish()?;
                 debug!("actions = {:?}", rec.actions);
                 if !rec.actions.is_empty() {
                     bail!("Cannot change channel, as there are unrecorded changes.")
@@ -146,7 +151,7 @@ impl Reset {
             }
             let mut inodes = HashSet::new();
             let mut txn_ = txn.write();
-            if let Some(cur) = txn_.load_channel(&current_channel)? {
+            if let Some(cur) = libpijul::channel::load_channel(&*txn_, &current_channel)? {
                 let mut changediff = HashSet::new();
                 let (a, b, s) =
                     libpijul::pristine::last_common_state(&*txn_, &*cur.read(), &*channel.read())?;
@@ -69,7 +69,8 @@ impl Tag {
                 };
                 debug!("channel_name = {:?}", channel_name);
                 try_record(&mut repo, txn.clone(), &channel_name)?;
-                let channel = txn.read().load_channel(&channel_name)?.unwrap();
+                let channel =
+                    libpijul::channel::load_channel(&*txn.read(), &channel_name)?.unwrap();
                 let last_t = if let Some(n) = txn.read().reverse_log(&*channel.read(), None)?.next()
                 {
                     n?.0.into()
@@ -137,7 +138,8 @@ impl Tag {
                     .current_channel()
                     .unwrap_or(crate::DEFAULT_CHANNEL)
                     .to_string();
-                let channel = if let Some(c) = txn.load_channel(&channel_name)? {
+                let channel = if let Some(c) = libpijul::channel::load_channel(&txn, &channel_name)?
+                {
                     c
                 } else {
                     bail!("Channel {:?} not found", channel_name)
@@ -207,27 +209,28 @@ fn header(
     }
 }
 
-fn try_record<T: ChannelMutTxnT + TxnT + Send + Sync + 'static>(
+fn try_record<T: MutTxnT + TxnT + Send + Sync + 'static>(
     repo: &mut Repository,
     txn: ArcTxn<T>,
     channel: &str,
 ) -> Result<(), anyhow::Error> {
-    let channel = if let Some(channel) = txn.read().load_channel(channel)? {
+    let channel = if let Some(channel) = libpijul::channel::load_channel(&*txn.read(), channel)? {
         channel
     } else {
         bail!("Channel not found: {}", channel)
     };
     let mut state = libpijul::RecordBuilder::new();
+    state.graph_limits = repo.config.graph_limits;
     state.record(
         txn,
-        libpijul::Algorithm::default(),
+        libpijul::ChannelAlgorithm,
         channel,
-        &repo.working_copy,
+        &libpijul::opaque::Opaque::new(repo.working_copy.clone())?,
         &repo.changes,
         "",
         num_cpus::get(),
     )?;
-    let rec = state.finish();
+    let rec = state.finish()?;
     if !rec.actions.is_empty() {
         bail!("Cannot change channel, as there are unrecorded changes.")
     }
@@ -32,6 +32,8 @@ pub struct Unrecord {
 impl Unrecord {
     pub fn run(self) -> Result<(), anyhow::Error> {
         let mut repo = Repository::find_root(self.repo_path)?;
+        repo.working_copy
+            .set_durability(repo.config.durability_for("unrecord"));
         debug!("{:?}", repo.config);
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
@@ -45,11 +47,13 @@ impl Unrecord {
             cur.as_str()
         };
         let is_current_channel = cur == channel_name;
-        let channel = if let Some(channel) = txn.read().load_channel(&channel_name)? {
-            channel
-        } else {
-            bail!("No such channel: {:?}", channel_name);
-        };
+        let channel =
+            if let Some(channel) = libpijul::channel::load_channel(&*txn.read(), &channel_name)? {
+                channel
+            } else {
+                bail!("No such channel: {:?}", channel_name);
+            };
+        super::recover_journal(&repo, &txn, &channel, is_current_channel)?;
         let mut hashes = Vec::new();
 
         if self.change_id.is_empty() {
@@ -75,7 +79,7 @@ impl Unrecord {
                 .take(number_of_changes)
                 .collect::<Vec<_>>();
             let o = make_changelist(&repo.changes, &hashes_, "unrecord")?;
-            for h in parse_changelist(&edit::edit_bytes(&o[..])?).iter() {
+            for h in parse_changelist(&::edit::edit_bytes(&o[..])?).iter() {
                 hashes.push((*h, *txn.get_internal(&h.into())?.unwrap()))
             }
         } else {
@@ -101,12 +105,55 @@ impl Unrecord {
         }
         debug!("changes: {:?}", changes);
         std::mem::drop(channel_);
+        changes.sort_by(|a, b| b.2.cmp(&a.2));
+
+        // Fail before storing the intent if other changes depend on
+        // the ones to unrecord. The unrecorded changes the pending
+        // change depends on are only known once it is recorded, below.
+        let hashes: Vec<Hash> = changes.iter().map(|(h, _, _)| *h).collect();
+        {
+            let txn_ = txn.read();
+            let channel_ = channel.read();
+            for (hash, change_id, _) in changes.iter() {
+                for p in txn_.iter_revdep(change_id)? {
+                    let (p, d) = p?;
+                    if p < change_id {
+                        continue;
+                    } else if p > change_id {
+                        break;
+                    }
+                    if txn_.get_changeset(txn_.changes(&channel_), d)?.is_some() {
+                        let dep: Hash = txn_.get_external(d)?.unwrap().into();
+                        if !hashes.contains(&dep) {
+                            bail!(
+                                "Cannot unrecord change {} because {} depend on it",
+                                hash.to_base32(),
+                                dep.to_base32()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        // Store the intent of this operation in its own transaction,
+        // right before unrecording, so that it can be rolled back if
+        // we get interrupted.
+        libpijul::journal::begin(
+            &mut *txn.write(),
+            channel_name,
+            libpijul::journal::Operation::Unrecord,
+            &hashes,
+        )?;
+        txn.commit()?;
+        let txn = repo.pristine.arc_txn_begin()?;
+        let channel = libpijul::channel::load_channel(&*txn.read(), &channel_name)?.unwrap();
+
         let pending_hash = if self.reset {
             super::pending(txn.clone(), &channel, &mut repo)?
         } else {
             None
         };
-        changes.sort_by(|a, b| b.2.cmp(&a.2));
         for (hash, change_id, _) in changes {
             let channel_ = channel.read();
             let txn_ = txn.read();
@@ -136,6 +183,12 @@ impl Unrecord {
             std::mem::drop(channel_);
             std::mem::drop(txn_);
             txn.write().unrecord(&repo.changes, &channel, &hash, 0)?;
+            libpijul::audit::append(
+                &mut *txn.write(),
+                &super::audit_identity(),
+                libpijul::audit::AuditOperation::Unrecord { hash },
+                &channel_name,
+            )?;
         }
 
         if self.reset && is_current_channel {
@@ -157,6 +210,9 @@ impl Unrecord {
                 repo.changes.del_change(&h)?;
             }
         }
+        libpijul::maintenance::AutoMaintenance::new(repo.config.maintenance_policy())
+            .after_unrecord(&mut *txn.write(), &channel, hashes.len() as u64)?;
+        libpijul::journal::finish(&mut *txn.write(), channel_name)?;
         txn.commit()?;
         Ok(())
     }
@@ -14,6 +14,10 @@ pub struct Global {
     pub pager: Option<Choice>,
     pub template: Option<Templates>,
     pub ignore_kinds: Option<HashMap<String, Vec<String>>>,
+    /// Protocol features used with the remotes of repositories
+    /// cloned from the command line.
+    #[serde(default)]
+    pub remote_features: RemoteFeatures,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +44,44 @@ pub struct Templates {
     pub description: Option<PathBuf>,
 }
 
+/// Optional features of the SSH protocol. Servers older than these
+/// don't understand the commands they use, hence they are opt-in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RemoteFeatures {
+    /// Download full changes chunk by chunk, fetching only the chunks
+    /// not found locally, with the `chunks` and `want` commands.
+    #[serde(default)]
+    pub dedup: bool,
+    /// Download the provenance links of the remote when pulling,
+    /// with the `provenance` command.
+    #[serde(default)]
+    pub provenance: bool,
+    /// Download the CI statuses of the remote when pulling, with the
+    /// `ci-statuses` command.
+    #[serde(default)]
+    pub ci_statuses: bool,
+    /// Download the notes of the remote on changes when pulling, with
+    /// the `notes` command. Only the namespaces known to this client,
+    /// such as review annotations, are downloaded.
+    #[serde(default)]
+    pub notes: bool,
+    /// Accept the redacted versions of the changes the remote
+    /// doesn't show us in full, which are stored apart from the
+    /// changes, and whose contents can't be checked.
+    #[serde(default)]
+    pub accept_redacted: bool,
+}
+
+impl RemoteFeatures {
+    /// The features of the global configuration file, used when
+    /// there is no repository yet.
+    pub fn global() -> Self {
+        Global::load()
+            .map(|(g, _)| g.remote_features)
+            .unwrap_or_default()
+    }
+}
+
 pub const GLOBAL_CONFIG_DIR: &str = ".pijulconfig";
 const CONFIG_DIR: &str = "pijul";
 
@@ -106,6 +148,100 @@ pub struct Config {
     pub hooks: Hooks,
     pub colors: Option<Choice>,
     pub pager: Option<Choice>,
+    /// Order of the sides of conflicts, "application" (the default) or
+    /// "timestamp".
+    pub conflict_order: Option<libpijul::output::ConflictOrder>,
+    /// Write the identifiers of conflicts in their start markers.
+    #[serde(default)]
+    pub conflict_ids: bool,
+    /// Number of days during which deleted channels can be restored,
+    /// before `pijul gc` purges them.
+    pub channel_retention_days: Option<u64>,
+    /// Automatic maintenance after `pijul apply` and `pijul unrecord`.
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    /// Rules on the headers of the changes recorded, and of the
+    /// changes pushed to this repository.
+    #[serde(default)]
+    pub change_header: libpijul::header::HeaderPolicy,
+    /// Limits on the size of the graphs of the files output or
+    /// recorded.
+    #[serde(default)]
+    pub graph_limits: libpijul::alive::retrieve::RetrieveLimits,
+    /// Changes that `pijul protocol` only serves in part, except to
+    /// their readers.
+    #[serde(default)]
+    pub redaction: libpijul::redact::RedactionPolicy,
+    /// Record and restore all the Unix permissions of files, instead
+    /// of just the executable bit.
+    #[serde(default)]
+    pub full_permissions: bool,
+    /// Record symbolic links as links, and output them as links,
+    /// instead of following them.
+    #[serde(default)]
+    pub symlinks: bool,
+    /// Protocol features used with the remotes of this repository.
+    #[serde(default)]
+    pub remote_features: RemoteFeatures,
+    /// After pulling, unrecord the changes rewritten on the remote
+    /// once all the changes replacing them have been pulled.
+    #[serde(default)]
+    pub migrate_rewritten: bool,
+    /// Durability of the files written to the working copy, and of
+    /// the renames recorded in the tree tables, "none" (the default),
+    /// "fsync-data" or "fsync-all".
+    #[serde(default)]
+    pub durability: libpijul::durability::Durability,
+    /// Durability policies for single commands ("apply", "pull",
+    /// "reset", "unrecord", "mv"…), overriding `durability`.
+    #[serde(default)]
+    pub command_durability: std::collections::HashMap<String, libpijul::durability::Durability>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Maintenance {
+    /// Prune the pseudo-edges of a channel after this many changes
+    /// have been applied to it or unrecorded from it.
+    pub every: Option<u64>,
+    /// Prune the pseudo-edges of a channel when they make up more
+    /// than this proportion of its graph.
+    pub max_pseudo_ratio: Option<f64>,
+    pub min_pseudo_edges: Option<usize>,
+    /// Also purge the deleted channels past their retention period.
+    #[serde(default)]
+    pub purge: bool,
+}
+
+impl Config {
+    /// The durability policy of command `command`.
+    pub fn durability_for(&self, command: &str) -> libpijul::durability::Durability {
+        self.command_durability
+            .get(command)
+            .cloned()
+            .unwrap_or(self.durability)
+    }
+
+    /// The time during which deleted channels can be restored.
+    pub fn channel_retention(&self) -> std::time::Duration {
+        if let Some(days) = self.channel_retention_days {
+            std::time::Duration::from_secs(days * 24 * 3600)
+        } else {
+            libpijul::channel::DEFAULT_RETENTION
+        }
+    }
+
+    pub fn maintenance_policy(&self) -> libpijul::maintenance::MaintenancePolicy {
+        libpijul::maintenance::MaintenancePolicy {
+            every: self.maintenance.every,
+            max_pseudo_ratio: self.maintenance.max_pseudo_ratio,
+            min_pseudo_edges: self.maintenance.min_pseudo_edges.unwrap_or(1000),
+            purge_retention: if self.maintenance.purge {
+                Some(self.channel_retention())
+            } else {
+                None
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
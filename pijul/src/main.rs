@@ -96,6 +96,15 @@ pub enum SubCommand {
     /// Create a new channel
     Fork(Fork),
 
+    /// Purges the channels deleted for longer than the retention
+    /// period (`channel_retention_days` in the repository config, 30
+    /// days by default)
+    Gc(Gc),
+
+    /// Runs a battery of checks on the repository, and reports the
+    /// problems found along with suggested fixes
+    Doctor(Doctor),
+
     /// Unrecords a list of changes.
     ///
     /// The changes will be removed from your log, but your working
@@ -248,6 +257,8 @@ async fn run(opts: Opts) -> Result<(), anyhow::Error> {
         // #[cfg(debug_assertions)]
         SubCommand::Debug(debug) => debug.run(),
         SubCommand::Fork(fork) => fork.run(),
+        SubCommand::Gc(gc) => gc.run(),
+        SubCommand::Doctor(doctor) => doctor.run(),
         SubCommand::Unrecord(unrecord) => unrecord.run(),
         SubCommand::Apply(apply) => apply.run(),
         SubCommand::Remote(remote) => remote.run(),
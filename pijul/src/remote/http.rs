@@ -244,6 +244,7 @@ impl Http {
                             let mut stderr = std::io::stderr();
                             writeln!(stderr, "{}", e)?;
                         }
+                        super::ListLine::Since(_) => {}
                     }
                 } else {
                     break;
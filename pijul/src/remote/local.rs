@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::bail;
-use libpijul::pristine::{Hash, Merkle, MutTxnT, Position, TxnT};
+use libpijul::pristine::{Hash, Merkle, Position};
 use libpijul::*;
 use log::debug;
 
@@ -34,13 +34,16 @@ pub fn get_state<T: TxnTExt>(
 impl Local {
     pub fn get_state(&mut self, mid: Option<u64>) -> Result<Option<(u64, Merkle)>, anyhow::Error> {
         let txn = self.pristine.txn_begin()?;
-        let channel = txn.load_channel(&self.channel)?.unwrap();
-        Ok(get_state(&txn, &channel, mid)?)
+        if let Some(channel) = libpijul::channel::load_channel(&txn, &self.channel)? {
+            Ok(get_state(&txn, &channel, mid)?)
+        } else {
+            Ok(None)
+        }
     }
 
     pub fn get_id(&self) -> Result<libpijul::pristine::RemoteId, anyhow::Error> {
         let txn = self.pristine.txn_begin()?;
-        if let Some(channel) = txn.load_channel(&self.channel)? {
+        if let Some(channel) = libpijul::channel::load_channel(&txn, &self.channel)? {
             Ok(*txn.id(&*channel.read()))
         } else {
             Err(anyhow::anyhow!(
@@ -64,15 +67,16 @@ impl Local {
             crate::repository::max_files(),
         );
         let remote_txn = self.pristine.txn_begin()?;
-        let remote_channel = if let Some(channel) = remote_txn.load_channel(&self.channel)? {
-            channel
-        } else {
-            debug!(
-                "Local::download_changelist found no channel named {:?}",
-                self.channel
-            );
-            bail!("No channel {} found for remote {}", self.name, self.channel)
-        };
+        let remote_channel =
+            if let Some(channel) = libpijul::channel::load_channel(&remote_txn, &self.channel)? {
+                channel
+            } else {
+                debug!(
+                    "Local::download_changelist found no channel named {:?}",
+                    self.channel
+                );
+                bail!("No channel {} found for remote {}", self.name, self.channel)
+            };
         let mut paths_ = HashSet::new();
         let mut result = HashSet::new();
         for s in paths {
@@ -126,9 +130,10 @@ impl Local {
             crate::repository::max_files(),
         );
         let txn = self.pristine.arc_txn_begin()?;
-        let channel = txn
-            .write()
-            .open_or_create_channel(to_channel.unwrap_or(&self.channel))?;
+        let channel = libpijul::channel::open_or_create_channel(
+            &mut *txn.write(),
+            to_channel.unwrap_or(&self.channel),
+        )?;
         for c in changes {
             libpijul::changestore::filesystem::push_filename(&mut local, &c);
             libpijul::changestore::filesystem::push_filename(&mut self.changes_dir, &c);
@@ -189,6 +194,28 @@ impl Local {
         Ok(())
     }
 
+    pub fn download_provenance(
+        &mut self,
+    ) -> Result<Vec<libpijul::provenance::ProvenanceLink>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        Ok(libpijul::provenance::list(&txn)?)
+    }
+
+    pub fn download_ci_statuses(
+        &mut self,
+    ) -> Result<Vec<libpijul::ci_status::ChangeStatuses>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        Ok(libpijul::ci_status::list(&txn)?)
+    }
+
+    pub fn download_notes(
+        &mut self,
+        namespace: &str,
+    ) -> Result<Vec<libpijul::notes::ChangeNote>, anyhow::Error> {
+        let txn = self.pristine.txn_begin()?;
+        Ok(libpijul::notes::list(&txn, namespace)?)
+    }
+
     pub async fn update_identities(
         &mut self,
         _rev: Option<u64>,
@@ -51,10 +51,19 @@ impl Repository {
                 channel,
                 no_cert_check,
                 with_path,
+                &self.config.remote_features,
             )
             .await
         } else {
-            unknown_remote(self_path, name, channel, no_cert_check, with_path).await
+            unknown_remote(
+                self_path,
+                name,
+                channel,
+                no_cert_check,
+                with_path,
+                &self.config.remote_features,
+            )
+            .await
         }
     }
 }
@@ -65,6 +74,7 @@ pub async fn unknown_remote(
     channel: &str,
     no_cert_check: bool,
     with_path: bool,
+    features: &RemoteFeatures,
 ) -> Result<RemoteRepo, anyhow::Error> {
     if let Ok(url) = url::Url::parse(name) {
         let scheme = url.scheme();
@@ -81,7 +91,7 @@ pub async fn unknown_remote(
         } else if scheme == "ssh" {
             return if let Some(mut ssh) = ssh_remote(name, with_path) {
                 debug!("unknown_remote, ssh = {:?}", ssh);
-                Ok(RemoteRepo::Ssh(ssh.connect(name, channel).await?))
+                Ok(RemoteRepo::Ssh(ssh.connect(name, channel, features).await?))
             } else {
                 bail!("Remote not found: {:?}", name)
             };
@@ -115,7 +125,7 @@ pub async fn unknown_remote(
     }
     if let Some(mut ssh) = ssh_remote(name, with_path) {
         debug!("unknown_remote, ssh = {:?}", ssh);
-        Ok(RemoteRepo::Ssh(ssh.connect(name, channel).await?))
+        Ok(RemoteRepo::Ssh(ssh.connect(name, channel, features).await?))
     } else {
         bail!("Remote not found: {:?}", name)
     }
@@ -204,7 +214,7 @@ impl RemoteDelta<MutTxn<()>> {
 
         for x in txn.reverse_log(&*channel.read(), None)? {
             let (_, (h, _)) = x?;
-            if let Some(channel) = txn.load_channel(remote_channel)? {
+            if let Some(channel) = libpijul::channel::load_channel(txn, remote_channel)? {
                 let channel = channel.read();
                 let h_int = txn.get_internal(h)?.unwrap();
                 if txn.get_changeset(txn.changes(&channel), h_int)?.is_none() {
@@ -331,7 +341,7 @@ pub(crate) fn update_changelist_local_channel(
             change: txn.get_external(&x.change).unwrap().unwrap().into(),
             pos: x.pos,
         }));
-        if let Some(remote_channel) = txn.load_channel(remote_channel)? {
+        if let Some(remote_channel) = libpijul::channel::load_channel(txn, remote_channel)? {
             let remote_channel = remote_channel.read();
             for x in txn.reverse_log(&remote_channel, None)? {
                 let (h, m) = x?.1;
@@ -520,9 +530,22 @@ impl RemoteRepo {
         } else {
             unreachable!()
         };
-        let dichotomy_n = self
-            .dichotomy_changelist(txn, &remote_ref.lock().remote)
-            .await?;
+        // Frequently polling clients usually have an up-to-date
+        // cache, ask for the changes after it first.
+        let last = txn
+            .last_remote(&remote_ref.lock().remote)?
+            .map(|(n, m)| (n, Merkle::from(&m.b)));
+        let since = if let Some(last) = last {
+            self.changelist_since(last, path).await?
+        } else {
+            None
+        };
+        let dichotomy_n = if let (Some((n, _)), Some(_)) = (last, &since) {
+            n + 1
+        } else {
+            self.dichotomy_changelist(txn, &remote_ref.lock().remote)
+                .await?
+        };
         let ours_ge_dichotomy: Vec<(u64, Hash)> = txn
             .iter_remote(&remote_ref.lock().remote, dichotomy_n)?
             .filter_map(|k| {
@@ -539,8 +562,11 @@ impl RemoteRepo {
                 }
             })
             .collect();
-        let (inodes, theirs_ge_dichotomy) =
-            self.download_changelist_nocache(dichotomy_n, path).await?;
+        let (inodes, theirs_ge_dichotomy) = if let Some(since) = since {
+            since
+        } else {
+            self.download_changelist_nocache(dichotomy_n, path).await?
+        };
         let ours_ge_dichotomy_set = ours_ge_dichotomy
             .iter()
             .map(|(_, h)| h)
@@ -683,6 +709,22 @@ impl RemoteRepo {
         Ok((r, v))
     }
 
+    /// Get the list of the remote's changes that come after the last
+    /// change in our cache of the remote, `last`, in a single round
+    /// trip. Returns `None` if this isn't supported by the remote, or
+    /// if the remote doesn't have the state of `last` anymore, in
+    /// which case [`Self::dichotomy_changelist`] must be used instead.
+    async fn changelist_since(
+        &mut self,
+        last: (u64, Merkle),
+        paths: &[String],
+    ) -> Result<Option<(HashSet<Position<Hash>>, Vec<(u64, Hash, Merkle)>)>, anyhow::Error> {
+        match *self {
+            RemoteRepo::Ssh(ref mut s) => s.changelist_since(last.0 + 1, last.1, paths).await,
+            _ => Ok(None),
+        }
+    }
+
     /// Uses a binary search to find the integer identifier of the last point
     /// at which our locally cached version of the remote was the same as the 'actual'
     /// state of the remote.
@@ -742,7 +784,7 @@ impl RemoteRepo {
             RemoteRepo::Ssh(ref mut s) => s.get_state(mid).await,
             RemoteRepo::Http(ref mut h) => h.get_state(mid).await,
             RemoteRepo::LocalChannel(ref channel) => {
-                if let Some(channel) = txn.load_channel(&channel)? {
+                if let Some(channel) = libpijul::channel::load_channel(txn, &channel)? {
                     local::get_state(txn, &channel, mid)
                 } else {
                     Ok(None)
@@ -764,7 +806,7 @@ impl RemoteRepo {
             RemoteRepo::Ssh(ref mut s) => s.get_id().await,
             RemoteRepo::Http(ref h) => h.get_id().await,
             RemoteRepo::LocalChannel(ref channel) => {
-                if let Some(channel) = txn.load_channel(&channel)? {
+                if let Some(channel) = libpijul::channel::load_channel(txn, &channel)? {
                     Ok(Some(*txn.id(&*channel.read())))
                 } else {
                     Err(anyhow::anyhow!(
@@ -861,7 +903,7 @@ impl RemoteRepo {
             }
             RemoteRepo::Http(ref h) => h.upload_changes(pro_n, local, to_channel, changes).await?,
             RemoteRepo::LocalChannel(ref channel) => {
-                let mut channel = txn.open_or_create_channel(channel)?;
+                let mut channel = libpijul::channel::open_or_create_channel(txn, channel)?;
                 let store = libpijul::changestore::filesystem::FileSystem::from_changes(
                     local,
                     crate::repository::max_files(),
@@ -919,6 +961,57 @@ impl RemoteRepo {
         Ok(())
     }
 
+    /// Import the provenance links of the remote, returning the
+    /// number of new links. HTTP remotes don't serve them.
+    pub async fn update_provenance<T: MutTxnT>(
+        &mut self,
+        txn: &mut T,
+    ) -> Result<usize, anyhow::Error> {
+        debug!("Downloading provenance links");
+        let links = match *self {
+            RemoteRepo::Local(ref mut l) => l.download_provenance()?,
+            RemoteRepo::Ssh(ref mut s) => s.download_provenance().await?,
+            RemoteRepo::Http(_) | RemoteRepo::LocalChannel(_) => Vec::new(),
+            RemoteRepo::None => unreachable!(),
+        };
+        Ok(libpijul::provenance::import(txn, links)?)
+    }
+
+    /// Merge the CI statuses of the remote, returning the number of
+    /// statuses that changed. HTTP remotes don't serve them.
+    pub async fn update_ci_statuses<T: MutTxnT>(
+        &mut self,
+        txn: &mut T,
+    ) -> Result<usize, anyhow::Error> {
+        debug!("Downloading CI statuses");
+        let statuses = match *self {
+            RemoteRepo::Local(ref mut l) => l.download_ci_statuses()?,
+            RemoteRepo::Ssh(ref mut s) => s.download_ci_statuses().await?,
+            RemoteRepo::Http(_) | RemoteRepo::LocalChannel(_) => Vec::new(),
+            RemoteRepo::None => unreachable!(),
+        };
+        Ok(libpijul::ci_status::import(txn, statuses)?)
+    }
+
+    /// Merge the review annotations of the remote, stored in its
+    /// notes, returning the number of annotations that changed. HTTP
+    /// remotes don't serve notes.
+    pub async fn update_reviews<T: MutTxnT>(
+        &mut self,
+        txn: &mut T,
+    ) -> Result<usize, anyhow::Error> {
+        debug!("Downloading reviews");
+        let namespace = libpijul::review::NAMESPACE;
+        let notes = match *self {
+            RemoteRepo::Local(ref mut l) => l.download_notes(namespace)?,
+            RemoteRepo::Ssh(ref mut s) => s.download_notes(namespace).await?,
+            RemoteRepo::Http(_) | RemoteRepo::LocalChannel(_) => Vec::new(),
+            RemoteRepo::None => unreachable!(),
+        };
+        let review = libpijul::review::Review::from_notes(&notes)?;
+        Ok(libpijul::review::import(txn, &review)?)
+    }
+
     pub async fn pull<T: MutTxnTExt + TxnTExt + GraphIter>(
         &mut self,
         repo: &mut Repository,
@@ -1257,9 +1350,16 @@ lazy_static! {
 }
 
 enum ListLine {
-    Change { n: u64, h: Hash, m: Merkle },
+    Change {
+        n: u64,
+        h: Hash,
+        m: Merkle,
+    },
     Position(Position<Hash>),
     Error(String),
+    /// Whether the state sent with `changelist ... since` is still
+    /// the state of the remote at that position.
+    Since(bool),
 }
 
 fn parse_line(data: &str) -> Result<ListLine, anyhow::Error> {
@@ -1276,6 +1376,11 @@ fn parse_line(data: &str) -> Result<ListLine, anyhow::Error> {
             });
         }
     }
+    if data == "since: ok" {
+        return Ok(ListLine::Since(true));
+    } else if data == "since: outdated" {
+        return Ok(ListLine::Since(false));
+    }
     if data.starts_with("error:") {
         return Ok(ListLine::Error(data.split_at(6).1.to_string()));
     }
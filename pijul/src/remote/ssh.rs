@@ -25,6 +25,22 @@ pub struct Ssh {
     pub path: String,
     pub is_running: bool,
     pub name: String,
+    /// Download full changes chunk by chunk, fetching only the chunks
+    /// not found locally. Servers older than this don't understand
+    /// the `chunks` and `want` commands, hence this is opt-in.
+    pub dedup: bool,
+    /// Download the provenance links of the remote when pulling.
+    /// Servers older than this don't understand the `provenance`
+    /// command, hence this is opt-in too.
+    pub provenance: bool,
+    /// Download the CI statuses of the remote when pulling, with the
+    /// `ci-statuses` command.
+    pub ci_statuses: bool,
+    /// Download the notes of the remote when pulling, with the
+    /// `notes` command.
+    pub notes: bool,
+    /// Accept redacted changes, see [`libpijul::redact`].
+    pub accept_redacted: bool,
     state: Arc<Mutex<State>>,
     has_errors: Arc<Mutex<bool>>,
 }
@@ -92,7 +108,12 @@ pub fn ssh_remote(addr: &str, with_path: bool) -> Option<Remote> {
 }
 
 impl<'a> Remote<'a> {
-    pub async fn connect(&mut self, name: &str, channel: &str) -> Result<Ssh, anyhow::Error> {
+    pub async fn connect(
+        &mut self,
+        name: &str,
+        channel: &str,
+        features: &crate::config::RemoteFeatures,
+    ) -> Result<Ssh, anyhow::Error> {
         let mut home = dirs_next::home_dir().unwrap();
         home.push(".ssh");
         home.push("known_hosts");
@@ -139,6 +160,11 @@ impl<'a> Remote<'a> {
             path: self.path.to_string(),
             is_running: false,
             name: name.to_string(),
+            dedup: features.dedup,
+            provenance: features.provenance,
+            ci_statuses: features.ci_statuses,
+            notes: features.notes,
+            accept_redacted: features.accept_redacted,
             state,
             has_errors,
         })
@@ -304,6 +330,7 @@ enum State {
         final_path: PathBuf,
         hashes: Vec<libpijul::pristine::Hash>,
         current: usize,
+        accept_redacted: bool,
     },
     Changelist {
         sender: tokio::sync::mpsc::Sender<Option<super::ListLine>>,
@@ -325,6 +352,11 @@ enum State {
         sender: Option<tokio::sync::mpsc::Sender<crate::Identity>>,
         buf: Vec<u8>,
     },
+    Reply {
+        sender: Option<tokio::sync::oneshot::Sender<Vec<u8>>>,
+        len: Option<usize>,
+        buf: Vec<u8>,
+    },
 }
 
 type BoxFuture<T> = Pin<Box<dyn futures::future::Future<Output = T> + Send>>;
@@ -482,6 +514,7 @@ impl thrussh::client::Handler for SshClient {
                     ref mut final_path,
                     ref hashes,
                     ref mut current,
+                    accept_redacted,
                 } => {
                     trace!("state changes");
                     let mut p = 0;
@@ -501,11 +534,17 @@ impl thrussh::client::Handler for SshClient {
                             *remaining_len = 0;
                             file.flush()?;
 
-                            libpijul::changestore::filesystem::push_filename(
-                                final_path,
-                                &hashes[*current],
-                            );
-                            final_path.set_extension("change");
+                            if redacted(&path, &hashes[*current], accept_redacted)? {
+                                libpijul::changestore::filesystem::push_redacted_filename(
+                                    final_path,
+                                    &hashes[*current],
+                                );
+                            } else {
+                                libpijul::changestore::filesystem::push_filename(
+                                    final_path,
+                                    &hashes[*current],
+                                );
+                            }
                             debug!("moving {:?} to {:?}", path, final_path);
                             std::fs::create_dir_all(&final_path.parent().unwrap())?;
                             let r = std::fs::rename(&path, &final_path);
@@ -658,6 +697,24 @@ impl thrussh::client::Handler for SshClient {
                         buf.extend(&data);
                     }
                 }
+                State::Reply {
+                    ref mut sender,
+                    ref mut len,
+                    ref mut buf,
+                } => {
+                    buf.extend(&data);
+                    if len.is_none() && buf.len() >= 8 {
+                        *len = Some((&buf[..8]).read_u64::<BigEndian>()? as usize);
+                        buf.drain(..8);
+                    }
+                    if let Some(len) = *len {
+                        if buf.len() >= len {
+                            if let Some(sender) = sender.take() {
+                                sender.send(std::mem::take(buf)).unwrap_or(());
+                            }
+                        }
+                    }
+                }
                 State::None => {
                     debug!("None state");
                 }
@@ -694,6 +751,24 @@ fn learn(addr: &str, port: u16, pk: &thrussh_keys::key::PublicKey) -> Result<boo
     }
 }
 
+/// Whether the downloaded file `path` is the redacted version of
+/// change `hash`, in which case it is checked, and must be stored
+/// apart from the changes. Fails unless `accept_redacted` is set.
+fn redacted(path: &Path, hash: &Hash, accept_redacted: bool) -> Result<bool, anyhow::Error> {
+    let buf = std::fs::read(path)?;
+    if !libpijul::redact::is_redacted_file(&buf, hash)? {
+        return Ok(false);
+    }
+    if !accept_redacted {
+        bail!(
+            "Change {} is redacted by the remote, set remote_features.accept_redacted to accept it",
+            hash.to_base32()
+        )
+    }
+    libpijul::redact::check_redacted(&buf, hash)?;
+    Ok(true)
+}
+
 impl Ssh {
     pub async fn finish(&mut self) -> Result<(), anyhow::Error> {
         self.c.eof().await?;
@@ -875,6 +950,7 @@ impl Ssh {
                 super::ListLine::Error(err) => {
                     bail!(err)
                 }
+                super::ListLine::Since(_) => {}
             }
         }
         if *self.has_errors.lock().await {
@@ -884,6 +960,63 @@ impl Ssh {
         Ok(result)
     }
 
+    /// Download the changelist from `from`, if the state of the
+    /// remote at `from - 1` is still `state`. Servers that don't know
+    /// about this answer with the changelist without checking the
+    /// state, which is then ignored.
+    pub async fn changelist_since(
+        &mut self,
+        from: u64,
+        state: Merkle,
+        paths: &[String],
+    ) -> Result<Option<(HashSet<Position<Hash>>, Vec<(u64, Hash, Merkle)>)>, anyhow::Error> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        *self.state.lock().await = State::Changelist {
+            sender,
+            pending: Vec::new(),
+        };
+        self.run_protocol().await?;
+        debug!("changelist_since");
+        let mut command = Vec::new();
+        write!(
+            command,
+            "changelist {} {} since {}",
+            self.channel,
+            from,
+            state.to_base32()
+        )
+        .unwrap();
+        for p in paths {
+            write!(command, " {:?}", p).unwrap()
+        }
+        command.push(b'\n');
+        self.c.data(&command[..]).await?;
+        let mut since = None;
+        let mut positions = HashSet::new();
+        let mut changes = Vec::new();
+        while let Some(Some(m)) = receiver.recv().await {
+            match m {
+                super::ListLine::Since(ok) => since = Some(ok),
+                super::ListLine::Change { n, h, m } => changes.push((n, h, m)),
+                super::ListLine::Position(pos) => {
+                    positions.insert(pos);
+                }
+                super::ListLine::Error(err) => {
+                    bail!(err)
+                }
+            }
+        }
+        if *self.has_errors.lock().await {
+            bail!("Remote sent an error")
+        }
+        debug!("since = {:?}", since);
+        if since == Some(true) {
+            Ok(Some((positions, changes)))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub async fn upload_changes(
         &mut self,
         pro_n: usize,
@@ -936,6 +1069,11 @@ impl Ssh {
         changes_dir: &mut PathBuf,
         full: bool,
     ) -> Result<(), anyhow::Error> {
+        if full && self.dedup {
+            return self
+                .download_changes_dedup(pro_n, c, sender, changes_dir)
+                .await;
+        }
         let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
         let path = changes_dir.join("tmp");
         std::fs::create_dir_all(&changes_dir)?;
@@ -948,6 +1086,7 @@ impl Ssh {
             file,
             hashes: Vec::new(),
             current: 0,
+            accept_redacted: self.accept_redacted,
         };
         self.run_protocol().await?;
         let mut sender = sender.map(|x| x.clone());
@@ -986,6 +1125,105 @@ impl Ssh {
         Ok(())
     }
 
+    /// Send `command`, and wait for a reply made of a length (u64,
+    /// big-endian) followed by that many bytes.
+    async fn request(&mut self, command: String) -> Result<Vec<u8>, anyhow::Error> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.state.lock().await = State::Reply {
+            sender: Some(sender),
+            len: None,
+            buf: Vec::new(),
+        };
+        self.c.data(command.as_bytes()).await?;
+        let reply = receiver.await?;
+        *self.state.lock().await = State::None;
+        Ok(reply)
+    }
+
+    /// Download full changes with the `chunks` and `want` commands,
+    /// reusing the chunks of the partial versions of the changes
+    /// already present, and of the changes downloaded before.
+    async fn download_changes_dedup(
+        &mut self,
+        pro_n: usize,
+        c: &mut tokio::sync::mpsc::UnboundedReceiver<libpijul::pristine::Hash>,
+        mut sender: Option<&mut tokio::sync::mpsc::Sender<libpijul::pristine::Hash>>,
+        changes_dir: &mut PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        self.run_protocol().await?;
+        let mut index = libpijul::chunks::ChunkIndex::new();
+        let tmp = changes_dir.join("tmp");
+        std::fs::create_dir_all(&changes_dir)?;
+        while let Some(h) = c.recv().await {
+            debug!("download_change_dedup {:?}", h);
+            let list = self.request(format!("chunks {}\n", h.to_base32())).await?;
+            let chunks = libpijul::chunks::read_list(&list)?;
+            libpijul::changestore::filesystem::push_filename(changes_dir, &h);
+            if let Ok(partial) = std::fs::read(&changes_dir) {
+                index.add(&partial)
+            }
+            libpijul::changestore::filesystem::pop_filename(changes_dir);
+            let missing = index.missing(&chunks);
+            debug!("{:?} chunks, {:?} missing", chunks.len(), missing.len());
+            let mut want = format!("want {}", h.to_base32());
+            for i in missing {
+                want.push_str(&format!(" {}", i));
+            }
+            want.push('\n');
+            let received = self.request(want).await?;
+            std::fs::write(&tmp, index.reassemble(&chunks, &received)?)?;
+            libpijul::change::Change::deserialize(&tmp.to_string_lossy(), Some(&h))?;
+            if redacted(&tmp, &h, self.accept_redacted)? {
+                libpijul::changestore::filesystem::push_redacted_filename(changes_dir, &h);
+            } else {
+                libpijul::changestore::filesystem::push_filename(changes_dir, &h);
+            }
+            std::fs::create_dir_all(&changes_dir.parent().unwrap())?;
+            let r = std::fs::rename(&tmp, &changes_dir);
+            libpijul::changestore::filesystem::pop_filename(changes_dir);
+            r?;
+            super::PROGRESS.borrow_mut().unwrap()[pro_n].incr();
+            if let Some(ref mut sender) = sender {
+                sender.send(h).await.unwrap_or(());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn download_provenance(
+        &mut self,
+    ) -> Result<Vec<libpijul::provenance::ProvenanceLink>, anyhow::Error> {
+        if !self.provenance {
+            return Ok(Vec::new());
+        }
+        self.run_protocol().await?;
+        let list = self.request("provenance\n".to_string()).await?;
+        Ok(libpijul::provenance::read_list(&list)?)
+    }
+
+    pub async fn download_ci_statuses(
+        &mut self,
+    ) -> Result<Vec<libpijul::ci_status::ChangeStatuses>, anyhow::Error> {
+        if !self.ci_statuses {
+            return Ok(Vec::new());
+        }
+        self.run_protocol().await?;
+        let list = self.request("ci-statuses\n".to_string()).await?;
+        Ok(libpijul::ci_status::read_list(&list)?)
+    }
+
+    pub async fn download_notes(
+        &mut self,
+        namespace: &str,
+    ) -> Result<Vec<libpijul::notes::ChangeNote>, anyhow::Error> {
+        if !self.notes {
+            return Ok(Vec::new());
+        }
+        self.run_protocol().await?;
+        let list = self.request(format!("notes {}\n", namespace)).await?;
+        Ok(libpijul::notes::read_list(&list)?)
+    }
+
     pub async fn update_identities(
         &mut self,
         rev: Option<u64>,
@@ -91,11 +91,22 @@ impl Repository {
         } else {
             config::Config::default()
         };
+        let durability = config.durability;
+        let pristine = libpijul::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?;
+        libpijul::hunk_kind::validate(&pristine.txn_begin()?)?;
         Ok(Repository {
-            pristine: libpijul::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?,
+            pristine,
             working_copy: libpijul::working_copy::filesystem::FileSystem::from_root(
                 &working_copy_dir,
-            ),
+            )
+            .with_full_permissions(config.full_permissions)
+            .with_symlinks(config.symlinks)
+            .with_graph_limits(config.graph_limits)
+            .with_conflict_options(libpijul::output::ConflictOptions {
+                order: config.conflict_order.unwrap_or_default(),
+                ids: config.conflict_ids,
+            })
+            .with_durability(durability),
             changes: libpijul::changestore::filesystem::FileSystem::from_root(
                 &working_copy_dir,
                 crate::repository::max_files(),